@@ -0,0 +1,47 @@
+//! Client for the compressed echo server
+//!
+//! Offers the codecs given on the command line, sends every line of stdin
+//! as a message and prints the echoes along with their compressed sizes.
+//! Start the `server` example first, then run
+//! `cargo run --example client [address] [codec...]`, for instance
+//! `cargo run --example client 127.0.0.1:7878 zlib lz4 < src/data/test.txt`.
+
+#![allow(deprecated)]
+
+extern crate compress;
+
+use std::env;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+
+mod rpc;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:7878".to_string());
+    let mut names = args.collect::<Vec<_>>();
+    if names.is_empty() {
+        names = vec!["lz4".to_string(), "flate".to_string()];
+    }
+    let names = names.iter().map(|name| &name[..]).collect::<Vec<_>>();
+
+    let stream = TcpStream::connect(&addr[..]).unwrap();
+    let mut r = BufReader::new(stream.try_clone().unwrap());
+    let mut w = BufWriter::new(stream);
+    let codec = rpc::offer(&mut r, &mut w, &names).unwrap();
+    println!("using {}", codec.name());
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let (mut plain, mut compressed) = (0, 0);
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
+        compressed += rpc::write_frame(&mut w, &*codec, line.as_bytes()).unwrap();
+        let echo = rpc::read_frame(&mut r, &*codec).unwrap().expect("server hung up");
+        assert_eq!(echo, line.as_bytes());
+        plain += line.len();
+        writeln!(out, "{}", String::from_utf8_lossy(&echo)).unwrap();
+    }
+    writeln!(out, "sent {} bytes of messages as {} bytes", plain, compressed).unwrap();
+}
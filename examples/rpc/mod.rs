@@ -0,0 +1,123 @@
+//! The wire format shared by the `server` and `client` examples
+//!
+//! A connection starts with the client offering the codecs it wants to use,
+//! most preferred first, as a count byte followed by length-prefixed names.
+//! The server answers with the first name it has compiled in, or an empty
+//! name if there is none, and closes the connection in that case. After that
+//! both sides exchange frames: a u32 little endian length followed by one
+//! complete stream of the negotiated codec.
+
+// each example only uses one side of the protocol
+#![allow(dead_code)]
+
+use std::io::{self, Read, Write};
+
+use compress::io::ReadMax;
+use compress::{codec_by_name, Codec};
+
+/// Frames, and the messages they decompress to, larger than this are
+/// rejected instead of allocated
+pub const MAX_FRAME: usize = 16 << 20;
+
+fn invalid<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn too_large<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+fn write_name<W: Write>(w: &mut W, name: &str) -> io::Result<()> {
+    if name.len() > u8::MAX as usize {
+        return too_large("codec name too long")
+    }
+    try!(w.write_all(&[name.len() as u8]));
+    w.write_all(name.as_bytes())
+}
+
+fn read_name<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut len = [0];
+    try!(r.read_exact(&mut len));
+    let mut name = vec![0; len[0] as usize];
+    try!(r.read_exact(&mut name));
+    match String::from_utf8(name) {
+        Ok(name) => Ok(name),
+        Err(_) => invalid("codec name is not UTF-8"),
+    }
+}
+
+/// Client side: offers `names` and returns the codec the server picked
+pub fn offer<R: Read, W: Write>(r: &mut R, w: &mut W, names: &[&str])
+                                -> io::Result<Box<dyn Codec>> {
+    if names.len() > u8::MAX as usize {
+        return too_large("too many codecs offered")
+    }
+    try!(w.write_all(&[names.len() as u8]));
+    for name in names {
+        try!(write_name(w, name));
+    }
+    try!(w.flush());
+    let picked = try!(read_name(r));
+    match codec_by_name(&picked) {
+        Some(codec) if names.contains(&&picked[..]) => Ok(codec),
+        _ if picked.is_empty() => invalid("the server supports none of the offered codecs"),
+        _ => invalid("the server picked a codec that wasn't offered"),
+    }
+}
+
+/// Server side: reads the offer and answers with the codec to use
+pub fn accept<R: Read, W: Write>(r: &mut R, w: &mut W) -> io::Result<Box<dyn Codec>> {
+    let mut count = [0];
+    try!(r.read_exact(&mut count));
+    let mut picked = None;
+    for _ in 0..count[0] {
+        let name = try!(read_name(r));
+        if picked.is_none() {
+            picked = codec_by_name(&name);
+        }
+    }
+    try!(write_name(w, picked.as_ref().map_or("", |codec| codec.name())));
+    try!(w.flush());
+    match picked {
+        Some(codec) => Ok(codec),
+        None => invalid("none of the offered codecs is supported"),
+    }
+}
+
+/// Compresses `data` with `codec` and writes it as one frame, returning the
+/// size of the frame
+pub fn write_frame<W: Write>(w: &mut W, codec: &dyn Codec, data: &[u8]) -> io::Result<usize> {
+    if data.len() > MAX_FRAME {
+        return too_large("message too large")
+    }
+    let mut frame = Vec::new();
+    let mut e = try!(codec.encoder(Box::new(&mut frame)));
+    try!(e.write_all(data));
+    try!(e.finish());
+    if frame.len() > MAX_FRAME {
+        return too_large("message too large")
+    }
+    try!(w.write_all(&(frame.len() as u32).to_le_bytes()));
+    try!(w.write_all(&frame));
+    try!(w.flush());
+    Ok(4 + frame.len())
+}
+
+/// Reads one frame and decompresses it, returning `None` if the peer closed
+/// the connection between frames
+pub fn read_frame<R: Read>(r: &mut R, codec: &dyn Codec) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match try!(r.read(&mut len[..1])) {
+        0 => return Ok(None),
+        _ => try!(r.read_exact(&mut len[1..])),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return invalid("frame too large")
+    }
+    let mut frame = vec![0; len];
+    try!(r.read_exact(&mut frame));
+    // a small frame can still decompress to far more than a message
+    let data = try!(try!(codec.decoder(Box::new(&frame[..]))).read_max(MAX_FRAME));
+    Ok(Some(data))
+}
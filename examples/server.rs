@@ -0,0 +1,51 @@
+//! Compressed echo server
+//!
+//! Negotiates a codec with every client and sends each message back
+//! compressed the same way, see `rpc/mod.rs` for the wire format. Run it
+//! with `cargo run --example server [address]` and talk to it with the
+//! `client` example.
+
+#![allow(deprecated)]
+
+extern crate compress;
+
+use std::env;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+mod rpc;
+
+fn serve(stream: TcpStream) -> io::Result<()> {
+    let mut r = BufReader::new(try!(stream.try_clone()));
+    let mut w = BufWriter::new(stream);
+    let codec = try!(rpc::accept(&mut r, &mut w));
+    let mut messages = 0;
+    while let Some(message) = try!(rpc::read_frame(&mut r, &*codec)) {
+        try!(rpc::write_frame(&mut w, &*codec, &message));
+        messages += 1;
+    }
+    println!("echoed {} messages using {}", messages, codec.name());
+    Ok(())
+}
+
+fn main() {
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:7878".to_string());
+    let listener = TcpListener::bind(&addr[..]).unwrap();
+    println!("listening on {}, codecs: {:?}", addr,
+             compress::capabilities().iter().map(|c| c.name)
+                                     .filter(|name| compress::codec_by_name(name).is_some())
+                                     .collect::<Vec<_>>());
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => { println!("accept failed: {}", e); continue }
+        };
+        let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+        thread::spawn(move || {
+            if let Err(e) = serve(stream) {
+                println!("{}: {}", peer, e);
+            }
+        });
+    }
+}
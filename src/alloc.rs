@@ -0,0 +1,296 @@
+/*!
+
+Allocation hooks for the large internal buffers of the codecs
+
+Some stages work on buffers of many megabytes, like the block and suffix
+array of the BWT, along with the buffers used to sort the suffixes. An `Allocator` lets the application decide where those come
+from, for instance an arena or a pool of huge pages, by handing over a pair
+of allocation functions. It can also ask for a larger alignment than the
+element type needs. `Buffer` is the growable array the codecs keep in such
+memory; everything else still goes through the global allocator.
+
+# Huge pages
+
+Sorting or inverting a block of many megabytes touches memory all over the
+suffix array, and with 4 KiB pages most of those accesses miss the TLB.
+`Allocator::huge_pages` aligns the buffers to `HUGE_PAGE_SIZE`, 2 MiB on
+x86-64 and aarch64, so the kernel can back them with huge pages: on Linux
+that happens by itself when transparent huge pages are set to `always`.
+With the `madvise` setting the memory has to be flagged with
+`madvise(MADV_HUGEPAGE)`, which an `AllocFn` of the application can do before
+returning it. Aligning small buffers that way only wastes address space, so
+it pays off for blocks of several megabytes.
+
+# Example
+
+```rust
+use std::alloc::{self, Layout};
+use std::io::Write;
+use compress::alloc::Allocator;
+use compress::bwt;
+
+unsafe fn arena_alloc(layout: Layout) -> *mut u8 {
+    // hand out memory from the application's arena here
+    alloc::alloc(layout)
+}
+
+unsafe fn arena_dealloc(ptr: *mut u8, layout: Layout) {
+    alloc::dealloc(ptr, layout)
+}
+
+let arena = unsafe { Allocator::new(arena_alloc, arena_dealloc) };
+let mut e = bwt::Encoder::new(Vec::new(), 4 << 20).unwrap();
+e.set_allocator(arena);
+e.write_all(b"some text").unwrap();
+let (_, result) = e.finish();
+result.unwrap();
+```
+
+*/
+
+use std::alloc::{self, Layout};
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::slice;
+
+/// The size of a huge page on x86-64 and aarch64, the alignment used by
+/// `Allocator::huge_pages`
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Allocates memory for a layout, returning null on failure, with the
+/// contract of `std::alloc::alloc`
+pub type AllocFn = unsafe fn(Layout) -> *mut u8;
+
+/// Frees memory obtained from the matching `AllocFn` with the same layout
+pub type DeallocFn = unsafe fn(*mut u8, Layout);
+
+/// A pair of allocation functions and the alignment to ask them for
+#[derive(Clone, Copy)]
+pub struct Allocator {
+    alloc: AllocFn,
+    dealloc: DeallocFn,
+    align: usize,
+}
+
+impl Allocator {
+    /// Creates an allocator from a pair of functions
+    ///
+    /// # Safety
+    ///
+    /// The functions have to behave like `std::alloc::alloc` and
+    /// `std::alloc::dealloc`: return memory fitting the layout or null, and
+    /// free exactly what was returned.
+    pub unsafe fn new(alloc: AllocFn, dealloc: DeallocFn) -> Allocator {
+        Allocator { alloc, dealloc, align: 1 }
+    }
+
+    /// Returns the global allocator
+    pub fn global() -> Allocator {
+        Allocator { alloc: alloc::alloc, dealloc: alloc::dealloc, align: 1 }
+    }
+
+    /// Returns the global allocator with buffers aligned to `HUGE_PAGE_SIZE`,
+    /// see the module documentation
+    pub fn huge_pages() -> Allocator {
+        Allocator::global().with_align(HUGE_PAGE_SIZE)
+    }
+
+    /// Returns this allocator asking for at least `align` bytes of
+    /// alignment, which has to be a power of two
+    pub fn with_align(self, align: usize) -> Allocator {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Allocator { align, ..self }
+    }
+
+    /// Returns the alignment asked for, beyond the one of the element type
+    pub fn align(&self) -> usize {
+        self.align
+    }
+}
+
+impl Default for Allocator {
+    fn default() -> Allocator {
+        Allocator::global()
+    }
+}
+
+impl fmt::Debug for Allocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Allocator").field("align", &self.align).finish()
+    }
+}
+
+/// A growable array of plain values in memory of an `Allocator`
+pub struct Buffer<T: Copy> {
+    ptr: NonNull<T>,
+    len: usize,
+    cap: usize,
+    alloc: Allocator,
+}
+
+unsafe impl<T: Copy + Send> Send for Buffer<T> {}
+unsafe impl<T: Copy + Sync> Sync for Buffer<T> {}
+
+impl<T: Copy> Buffer<T> {
+    /// Creates an empty buffer, which allocates nothing until it grows
+    pub fn new(alloc: Allocator) -> Buffer<T> {
+        assert!(mem::size_of::<T>() > 0, "zero-sized elements aren't supported");
+        Buffer { ptr: NonNull::dangling(), len: 0, cap: 0, alloc }
+    }
+
+    /// Returns the allocator of this buffer
+    pub fn allocator(&self) -> Allocator {
+        self.alloc
+    }
+
+    /// Returns how many elements fit without reallocating
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    fn layout(&self, cap: usize) -> Layout {
+        let align = cmp::max(mem::align_of::<T>(), self.alloc.align);
+        let size = cap.checked_mul(mem::size_of::<T>()).expect("buffer size overflow");
+        Layout::from_size_align(size, align).expect("buffer size overflow")
+    }
+
+    /// Makes room for at least `additional` more elements
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self.len.checked_add(additional).expect("buffer size overflow");
+        if needed <= self.cap {
+            return
+        }
+        let cap = cmp::max(needed, self.cap * 2);
+        let layout = self.layout(cap);
+        let ptr = unsafe { (self.alloc.alloc)(layout) } as *mut T;
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+        if self.cap > 0 {
+            unsafe {
+                ptr::copy_nonoverlapping(self.ptr.as_ptr(), ptr.as_ptr(), self.len);
+                (self.alloc.dealloc)(self.ptr.as_ptr() as *mut u8, self.layout(self.cap));
+            }
+        }
+        self.ptr = ptr;
+        self.cap = cap;
+    }
+
+    /// Appends an element
+    pub fn push(&mut self, value: T) {
+        self.reserve(1);
+        unsafe { ptr::write(self.ptr.as_ptr().add(self.len), value) };
+        self.len += 1;
+    }
+
+    /// Appends the elements of a slice
+    pub fn extend_from_slice(&mut self, values: &[T]) {
+        self.reserve(values.len());
+        unsafe {
+            ptr::copy_nonoverlapping(values.as_ptr(), self.ptr.as_ptr().add(self.len), values.len());
+        }
+        self.len += values.len();
+    }
+
+    /// Sets the length, filling new elements with `value`
+    pub fn resize(&mut self, len: usize, value: T) {
+        if len > self.len {
+            self.reserve(len - self.len);
+            for i in self.len..len {
+                unsafe { ptr::write(self.ptr.as_ptr().add(i), value) };
+            }
+        }
+        self.len = len;
+    }
+
+    /// Shortens the buffer to `len` elements, keeping the memory
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.len = len;
+        }
+    }
+
+    /// Removes all elements, keeping the memory
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<T: Copy> Deref for Buffer<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy> DerefMut for Buffer<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T: Copy> Drop for Buffer<T> {
+    fn drop(&mut self) {
+        if self.cap > 0 {
+            unsafe { (self.alloc.dealloc)(self.ptr.as_ptr() as *mut u8, self.layout(self.cap)) };
+        }
+    }
+}
+
+impl<T: Copy> Default for Buffer<T> {
+    fn default() -> Buffer<T> {
+        Buffer::new(Allocator::global())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Buffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::alloc::{self, Layout};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::{Allocator, Buffer};
+
+    static LIVE: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe fn counting_alloc(layout: Layout) -> *mut u8 {
+        LIVE.fetch_add(layout.size(), Ordering::SeqCst);
+        alloc::alloc(layout)
+    }
+
+    unsafe fn counting_dealloc(ptr: *mut u8, layout: Layout) {
+        LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+        alloc::dealloc(ptr, layout)
+    }
+
+    #[test]
+    fn buffers() {
+        let counting = unsafe { Allocator::new(counting_alloc, counting_dealloc) }.with_align(4096);
+        {
+            let mut b: Buffer<usize> = Buffer::new(counting);
+            assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+            b.resize(1000, 7);
+            b.extend_from_slice(&[1, 2, 3]);
+            for i in 0..5000 {
+                b.push(i);
+            }
+            assert_eq!(b.len(), 6003);
+            assert_eq!((b[999], b[1000], b[6002]), (7, 1, 4999));
+            assert_eq!(b.as_ptr() as usize % 4096, 0);
+            assert!(LIVE.load(Ordering::SeqCst) >= 6003 * 8);
+            b.truncate(10);
+            b.resize(12, 0);
+            assert_eq!(&b[8..], &[7, 7, 0, 0]);
+        }
+        assert_eq!(LIVE.load(Ordering::SeqCst), 0);
+    }
+}
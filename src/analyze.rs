@@ -0,0 +1,172 @@
+/*!
+
+Corpus statistics gathered on the way through
+
+A `HistogramWriter` sits in front of any writer, usually an encoder, passes
+every byte on untouched and counts bytes and digrams (pairs of adjacent
+bytes) as they go by. Statistics of a corpus thus come for free during the
+compression pass instead of needing a second read of the data.
+
+# Example
+
+```rust
+use std::io::Write;
+use compress::analyze::HistogramWriter;
+
+let mut w = HistogramWriter::new(Vec::new());
+w.write_all(b"abra").unwrap();
+w.write_all(b"cadabra").unwrap();
+let (data, histogram) = w.finish();
+assert_eq!(&data[..], b"abracadabra");
+assert_eq!(histogram.count(b'a'), 5);
+assert_eq!(histogram.digram(b'b', b'r'), 2);
+// pairs spanning two writes are counted too
+assert_eq!(histogram.digram(b'a', b'c'), 1);
+```
+
+*/
+
+use std::io::{self, Write};
+
+/// Byte and digram counts of a stream
+#[derive(Clone)]
+pub struct Histogram {
+    bytes: [u64; 256],
+    digrams: Vec<u64>,
+}
+
+impl Histogram {
+    /// Creates an empty histogram
+    pub fn new() -> Histogram {
+        Histogram {
+            bytes: [0; 256],
+            digrams: vec![0; 256 * 256],
+        }
+    }
+
+    /// Returns how many times `byte` was seen
+    pub fn count(&self, byte: u8) -> u64 {
+        self.bytes[byte as usize]
+    }
+
+    /// Returns how many times `first` was directly followed by `second`
+    pub fn digram(&self, first: u8, second: u8) -> u64 {
+        self.digrams[(first as usize) << 8 | second as usize]
+    }
+
+    /// Returns the counts of all byte values, indexed by the byte
+    pub fn bytes(&self) -> &[u64; 256] {
+        &self.bytes
+    }
+
+    /// Returns the counts of all digrams, indexed by `first << 8 | second`
+    pub fn digrams(&self) -> &[u64] {
+        &self.digrams
+    }
+
+    /// Returns the number of bytes seen
+    pub fn total(&self) -> u64 {
+        self.bytes.iter().sum()
+    }
+
+    /// Returns the number of distinct byte values seen
+    pub fn distinct(&self) -> usize {
+        self.bytes.iter().filter(|&&c| c != 0).count()
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+/// A writer counting what passes through it, see the module documentation
+pub struct HistogramWriter<W> {
+    w: W,
+    histogram: Histogram,
+    last: Option<u8>,
+}
+
+impl<W: Write> HistogramWriter<W> {
+    /// Creates a counting writer in front of `w`
+    pub fn new(w: W) -> HistogramWriter<W> {
+        HistogramWriter {
+            w,
+            histogram: Histogram::new(),
+            last: None,
+        }
+    }
+
+    /// Returns the statistics of everything written so far
+    pub fn histogram(&self) -> &Histogram {
+        &self.histogram
+    }
+
+    /// Returns a reference to the wrapped writer
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Returns the wrapped writer along with the final statistics
+    pub fn finish(self) -> (W, Histogram) {
+        (self.w, self.histogram)
+    }
+}
+
+impl<W: Write> Write for HistogramWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // only what the wrapped writer accepted is counted
+        let n = try!(self.w.write(buf));
+        for &byte in buf[..n].iter() {
+            self.histogram.bytes[byte as usize] += 1;
+            if let Some(last) = self.last {
+                self.histogram.digrams[(last as usize) << 8 | byte as usize] += 1;
+            }
+            self.last = Some(byte);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use super::HistogramWriter;
+
+    #[test]
+    fn counts_text() {
+        let input = include_bytes!("data/test.txt");
+        let mut w = HistogramWriter::new(Vec::new());
+        for chunk in input.chunks(13) {
+            w.write_all(chunk).unwrap();
+        }
+        let (data, histogram) = w.finish();
+        assert_eq!(&data[..], &input[..]);
+        assert_eq!(histogram.total(), input.len() as u64);
+        assert_eq!(histogram.digrams().iter().sum::<u64>(), input.len() as u64 - 1);
+
+        let spaces = input.iter().filter(|&&b| b == b' ').count() as u64;
+        assert_eq!(histogram.count(b' '), spaces);
+        let the = input.windows(2).filter(|w| w == b"th").count() as u64;
+        assert_eq!(histogram.digram(b't', b'h'), the);
+    }
+
+    #[cfg(feature="lz4")]
+    #[test]
+    fn through_encoder() {
+        use lz4;
+        let input = include_bytes!("data/test.txt");
+        let mut w = HistogramWriter::new(lz4::Encoder::new(Vec::new()));
+        w.write_all(input).unwrap();
+        assert_eq!(w.histogram().total(), input.len() as u64);
+        let (e, histogram) = w.finish();
+        let (_, result) = e.finish();
+        result.unwrap();
+        assert!(histogram.distinct() > 26);
+    }
+}
@@ -0,0 +1,330 @@
+/*!
+
+A snapshot of the public API, checked by the `public_api` test
+
+Everything reachable from the crate root is covered by semver, except for
+items marked `#[doc(hidden)]`, which other modules of the crate and the
+command line tool share but downstream code shouldn't rely on. The test
+walks the sources from `lib.rs`, following public modules whatever features
+they need, and lists every public item along with its signature, up to the
+brace opening its body: functions, types and their public fields and
+variants, trait items, methods of inherent impls, re-exports and exported
+macros. The list has to match `data/public-api.txt`, so that a change to the
+API shows up in review as a change to that file. After a deliberate change,
+rewrite it with
+
+```text
+COMPRESS_BLESS_API=1 cargo test public_api
+```
+
+This is a line based scan of the sources rather than the compiler's view of
+the crate, since the rustdoc JSON output needs a nightly toolchain. It is
+good enough for code formatted like this crate's, but it can't see:
+
+* `cfg` attributes: items are listed whatever they are gated on, and an item
+  defined once per configuration is listed once
+* items generated by macros, or brought in by `include!`
+* what a re-export refers to: `pub use` declarations are listed as written
+* the items of private modules, even when they are re-exported
+* trait impls, and methods of types declared in another file
+* raw strings holding quotes or braces, which throw off the brace matching
+
+*/
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// What a pair of braces encloses
+#[derive(Clone)]
+enum Frame {
+    // a module with its path and the directory of its child module files
+    Module(String, PathBuf),
+    // an inherent impl, a struct or an enum, a trait, with their path
+    Impl(String),
+    Struct(String),
+    Enum(String),
+    Trait(String),
+    // anything else, whose contents aren't part of the API
+    Other,
+}
+
+// Attributes seen ahead of the next item
+#[derive(Default)]
+struct Attributes {
+    hidden: bool,
+    test: bool,
+    exported: bool,
+}
+
+// Removes comments from `line` while keeping strings, returns the rest along
+// with the braces that aren't in strings or characters
+fn strip(line: &str, comment: &mut u32, string: &mut bool) -> (String, Vec<char>) {
+    let chars: Vec<char> = line.chars().collect();
+    let mut code = String::new();
+    let mut braces = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (c, next) = (chars[i], chars.get(i + 1).cloned());
+        if *comment > 0 {
+            match (c, next) {
+                ('*', Some('/')) => { *comment -= 1; i += 1 }
+                ('/', Some('*')) => { *comment += 1; i += 1 }
+                _ => {}
+            }
+        } else if *string {
+            code.push(c);
+            match c {
+                '\\' => if let Some(next) = next { code.push(next); i += 1 },
+                '"' => *string = false,
+                _ => {}
+            }
+        } else {
+            match (c, next) {
+                ('/', Some('/')) => break,
+                ('/', Some('*')) => { *comment += 1; i += 1 }
+                ('"', _) => { *string = true; code.push(c) }
+                ('\'', Some('\\')) => {
+                    // an escaped character, up to the closing quote
+                    let end = chars[i + 2..].iter().position(|&c| c == '\'')
+                                            .map_or(chars.len() - 1, |p| i + 2 + p);
+                    code.extend(&chars[i..end + 1]);
+                    i = end;
+                }
+                ('\'', Some(_)) if chars.get(i + 2) == Some(&'\'') => {
+                    code.extend(&chars[i..i + 3]);
+                    i += 2;
+                }
+                ('{', _) | ('}', _) => { braces.push(c); code.push(c) }
+                _ => code.push(c),
+            }
+        }
+        i += 1;
+    }
+    (code, braces)
+}
+
+// Returns the type an inherent impl is for, or None for a trait impl
+fn impl_target(header: &str) -> Option<String> {
+    let mut rest = header["impl".len()..].trim_start();
+    if rest.starts_with('<') {
+        let mut depth = 0;
+        let mut prev = ' ';
+        for (i, c) in rest.char_indices() {
+            match c {
+                '<' => depth += 1,
+                // the arrow of a closure bound doesn't close anything
+                '>' if prev != '-' => depth -= 1,
+                _ => {}
+            }
+            prev = c;
+            if depth == 0 {
+                rest = rest[i + 1..].trim_start();
+                break
+            }
+        }
+    }
+    if rest.contains(" for ") {
+        return None
+    }
+    let name: String = rest.chars().take_while(|&c| c.is_alphanumeric() || c == '_').collect();
+    if name.is_empty() { None } else { Some(name) }
+}
+
+// Returns the name following the keyword `kind` in a signature
+fn item_name(sig: &str, kind: &str) -> Option<String> {
+    sig.split_whitespace().skip_while(|&word| word != kind).nth(1)
+       .map(|name| name.chars().take_while(|&c| c.is_alphanumeric() || c == '_').collect())
+}
+
+fn starts_item(code: &str) -> bool {
+    ["pub ", "impl", "mod ", "fn ", "struct ", "enum ", "trait ", "type ", "const ", "static ",
+     "use ", "macro_rules!", "extern ", "unsafe "].iter().any(|&k| code.starts_with(k))
+}
+
+// Squeezes the whitespace in a signature and drops what ends it
+fn normalize(sig: &str) -> String {
+    let sig = sig.split_whitespace().collect::<Vec<_>>().join(" ");
+    sig.trim_end_matches(&['{', ';', ','][..]).trim_end().to_string()
+}
+
+#[derive(Default)]
+struct Scanner {
+    items: Vec<String>,
+}
+
+impl Scanner {
+    fn push(&mut self, path: &str, sig: &str) {
+        let item = format!("{}: {}", path, normalize(sig));
+        if !self.items.contains(&item) {
+            self.items.push(item);
+        }
+    }
+
+    fn module(&mut self, file: &Path, path: &str, dir: &Path) {
+        let text = fs::read_to_string(file).unwrap();
+        // methods only count for the types other modules can name
+        let public: Vec<String> = text.lines().map(str::trim)
+            .filter(|line| line.starts_with("pub struct ") || line.starts_with("pub enum "))
+            .filter_map(|line| item_name(line, "struct").or_else(|| item_name(line, "enum")))
+            .collect();
+        let mut frames = vec![Frame::Module(path.to_string(), dir.to_path_buf())];
+        let mut hidden_types = Vec::new();
+        let mut attrs = Attributes::default();
+        // the frame opened by the next brace
+        let mut pending = None;
+        // a use declaration spanning lines, with the module re-exporting
+        let mut imports: Option<(Option<String>, String)> = None;
+        // a signature spanning lines, with the braces seen so far
+        let mut continued: Option<(String, Vec<char>)> = None;
+        let (mut comment, mut string) = (0, false);
+        for line in text.lines() {
+            let in_string = string;
+            let (code, mut braces) = strip(line, &mut comment, &mut string);
+            let mut code = code.trim().to_string();
+            if let Some((mut sig, mut before)) = continued.take() {
+                sig.push(' ');
+                sig.push_str(&code);
+                before.extend(braces);
+                code = sig;
+                braces = before;
+            }
+            let code = &code[..];
+            let frame = frames.last().unwrap().clone();
+            let member = matches!(frame, Frame::Struct(_) | Frame::Enum(_));
+            let ends = code.contains('{') || code.ends_with(';') || (member && code.ends_with(','));
+            if !in_string && !ends && starts_item(code) && !code.starts_with("use ")
+                && !code.starts_with("pub use ") && !code.starts_with("macro_rules!") {
+                continued = Some((code.to_string(), braces));
+                continue
+            }
+            if let Some((module, mut sig)) = imports.take() {
+                sig.push(' ');
+                sig.push_str(code);
+                match module {
+                    Some(module) if code.ends_with(';') => self.push(&module, &sig),
+                    None if code.ends_with(';') => {}
+                    module => imports = Some((module, sig)),
+                }
+                continue
+            }
+            let head = match code.find('{') {
+                // a value built from a struct is cut at the type
+                Some(i) if code.starts_with("pub const ") || code.starts_with("pub static ") => {
+                    &code[..code.find(" = ").unwrap_or(i)]
+                }
+                Some(i) if !code.starts_with("pub use ") => &code[..i],
+                _ => code,
+            };
+            let sig = normalize(head);
+            // the braces of a use declaration group its paths
+            if !in_string && (code.starts_with("use ") || code.starts_with("pub use ")) && !code.ends_with(';') {
+                let module = match frame {
+                    Frame::Module(ref module, _) if code.starts_with("pub ") && !attrs.hidden && !attrs.test => {
+                        Some(module.clone())
+                    }
+                    _ => None,
+                };
+                imports = Some((module, code.to_string()));
+                attrs = Attributes::default();
+                continue
+            }
+            if in_string || code.is_empty() {
+            } else if code.starts_with("#[") {
+                attrs.hidden |= code.contains("doc(hidden)");
+                attrs.test |= code.contains("cfg(test)");
+                attrs.exported |= code.contains("macro_export");
+            } else if starts_item(code) || matches!(frame, Frame::Enum(_)) {
+                let skip = attrs.hidden || attrs.test;
+                match frame {
+                    Frame::Module(ref module, _) if skip => {
+                        if let Some(name) = item_name(&sig, "struct").or_else(|| item_name(&sig, "enum")) {
+                            hidden_types.push(format!("{}::{}", module, name));
+                        }
+                    }
+                    Frame::Module(ref module, ref dir) => {
+                        if code.starts_with("pub mod ") {
+                            self.push(module, &sig);
+                            let name = item_name(&sig, "mod").unwrap();
+                            let child = format!("{}::{}", module, name);
+                            if code.ends_with(';') {
+                                let file = dir.join(format!("{}.rs", name));
+                                let file = if file.exists() { file } else { dir.join(&name).join("mod.rs") };
+                                self.module(&file, &child, &dir.join(&name));
+                            } else {
+                                pending = Some(Frame::Module(child, dir.join(&name)));
+                            }
+                        } else if code.starts_with("pub ") {
+                            self.push(module, &sig);
+                            let named = |kind| item_name(&sig, kind).map(|name| format!("{}::{}", module, name));
+                            pending = named("struct").map(Frame::Struct)
+                                .or_else(|| named("enum").map(Frame::Enum))
+                                .or_else(|| named("trait").map(Frame::Trait));
+                        } else if code.starts_with("impl") {
+                            pending = impl_target(code).filter(|name| public.contains(name))
+                                                       .map(|name| format!("{}::{}", module, name))
+                                                       .filter(|path| !hidden_types.contains(path))
+                                                       .map(Frame::Impl);
+                        } else if code.starts_with("macro_rules!") && attrs.exported {
+                            self.push(module, &sig);
+                        }
+                    }
+                    Frame::Impl(ref path) | Frame::Struct(ref path) => {
+                        if code.starts_with("pub ") && !skip {
+                            self.push(path, &sig);
+                        }
+                    }
+                    Frame::Enum(ref path) => {
+                        if code.starts_with(|c: char| c.is_uppercase()) && !skip {
+                            self.push(path, &sig);
+                        }
+                    }
+                    Frame::Trait(ref path) => {
+                        let trait_item = ["fn ", "unsafe fn ", "type ", "const "].iter().any(|&k| code.starts_with(k));
+                        if trait_item && !skip {
+                            self.push(path, &sig);
+                        }
+                    }
+                    Frame::Other => {}
+                }
+                attrs = Attributes::default();
+            }
+            for &brace in braces.iter() {
+                if brace == '{' {
+                    frames.push(pending.take().unwrap_or(Frame::Other));
+                } else {
+                    frames.pop();
+                }
+            }
+            if code.ends_with(';') {
+                pending = None;
+            }
+        }
+    }
+}
+
+#[test]
+fn public_api() {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+    let mut scanner = Scanner::default();
+    scanner.module(&src.join("lib.rs"), "compress", &src);
+    let mut current = scanner.items.join("\n");
+    current.push('\n');
+
+    let snapshot = src.join("data").join("public-api.txt");
+    if env::var_os("COMPRESS_BLESS_API").is_some() {
+        fs::write(&snapshot, &current).unwrap();
+        return
+    }
+    let expected = fs::read_to_string(&snapshot).unwrap_or_default();
+    if current != expected {
+        let mut diff = String::new();
+        for line in expected.lines().filter(|line| !scanner.items.iter().any(|item| item == line)) {
+            diff.push_str(&format!("\n- {}", line));
+        }
+        for item in scanner.items.iter().filter(|item| !expected.lines().any(|line| line == *item)) {
+            diff.push_str(&format!("\n+ {}", item));
+        }
+        panic!("the public API changed, rerun with COMPRESS_BLESS_API=1 if that's intended:{}", diff);
+    }
+}
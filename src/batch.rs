@@ -0,0 +1,172 @@
+/*!
+
+Latency-bounded batching of small writes
+
+Encoders compress best when they see large blocks, but an RPC message has
+to go out soon after it is written. A `Batcher` sits in front of an encoder,
+coalesces small writes and flushes the encoder once a batch is big enough
+or its oldest byte has waited for the configured delay.
+
+There is no timer thread: the caller checks `deadline` and calls `tick`,
+which fits into whatever event loop already drives the connection.
+
+# Example
+
+```rust
+use std::io::Write;
+use std::time::Duration;
+use compress::batch::Batcher;
+
+let mut b = Batcher::new(Vec::new(), 4096, Duration::from_millis(5));
+b.write_all(b"small message").unwrap();
+assert!(b.get_ref().is_empty());
+// in an event loop: sleep until b.deadline(), then
+b.flush().unwrap();
+assert_eq!(&b.get_ref()[..], b"small message");
+```
+
+*/
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// A writer coalescing small writes, see the module documentation
+pub struct Batcher<W: Write> {
+    w: W,
+    buf: Vec<u8>,
+    max_bytes: usize,
+    max_delay: Duration,
+    oldest: Option<Instant>,
+}
+
+impl<W: Write> Batcher<W> {
+    /// Creates a batcher in front of `w`, passing data on once `max_bytes`
+    /// are pending or the oldest of them is `max_delay` old
+    pub fn new(w: W, max_bytes: usize, max_delay: Duration) -> Batcher<W> {
+        Batcher {
+            w,
+            buf: Vec::with_capacity(max_bytes),
+            max_bytes,
+            max_delay,
+            oldest: None,
+        }
+    }
+
+    /// Returns the time by which `tick` has to be called, if anything is
+    /// pending
+    pub fn deadline(&self) -> Option<Instant> {
+        self.oldest.map(|t| t + self.max_delay)
+    }
+
+    /// Flushes the pending batch if its deadline has passed, returning
+    /// whether it did
+    pub fn tick(&mut self) -> io::Result<bool> {
+        self.tick_at(Instant::now())
+    }
+
+    /// Like `tick`, with the current time supplied by the caller
+    pub fn tick_at(&mut self, now: Instant) -> io::Result<bool> {
+        match self.deadline() {
+            Some(deadline) if deadline <= now => {
+                try!(self.flush());
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns the number of bytes waiting to be passed on
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns a reference to the wrapped writer
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Passes on and flushes everything pending, then returns the wrapped
+    /// writer
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.flush());
+        Ok(self.w)
+    }
+}
+
+impl<W: Write> Write for Batcher<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.oldest.is_none() && !buf.is_empty() {
+            self.oldest = Some(Instant::now());
+        }
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= self.max_bytes {
+            try!(self.flush());
+        }
+        Ok(buf.len())
+    }
+
+    /// Passes the pending batch on and flushes the wrapped writer, which
+    /// makes an encoder emit everything it has buffered
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            try!(self.w.write_all(&self.buf));
+            self.buf.clear();
+        }
+        self.oldest = None;
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::time::Duration;
+    use super::Batcher;
+
+    #[test]
+    fn size_limit() {
+        let mut b = Batcher::new(Vec::new(), 10, Duration::from_secs(3600));
+        b.write_all(b"12345").unwrap();
+        assert_eq!(b.pending(), 5);
+        assert!(b.get_ref().is_empty());
+        b.write_all(b"67890ab").unwrap();
+        assert_eq!(b.pending(), 0);
+        assert_eq!(b.deadline(), None);
+        assert_eq!(&b.finish().unwrap()[..], b"1234567890ab");
+    }
+
+    #[test]
+    fn deadline() {
+        let mut b = Batcher::new(Vec::new(), 1000, Duration::from_millis(5));
+        assert!(!b.tick().unwrap());
+        b.write_all(b"ping").unwrap();
+        let deadline = b.deadline().unwrap();
+        b.write_all(b"pong").unwrap();
+        assert_eq!(b.deadline(), Some(deadline));
+
+        assert!(!b.tick_at(deadline - Duration::from_millis(1)).unwrap());
+        assert!(b.get_ref().is_empty());
+        assert!(b.tick_at(deadline).unwrap());
+        assert_eq!(&b.get_ref()[..], b"pingpong");
+        assert_eq!(b.deadline(), None);
+    }
+
+    #[cfg(feature="lz4")]
+    #[test]
+    fn through_encoder() {
+        use std::io::Read;
+        use lz4;
+        let mut b = Batcher::new(lz4::Encoder::new(Vec::new()), 1 << 16,
+                                 Duration::from_millis(5));
+        for _ in 0..100 {
+            b.write_all(b"short message ").unwrap();
+        }
+        b.flush().unwrap();
+        let (encoded, result) = b.finish().unwrap().finish();
+        result.unwrap();
+
+        let mut decoded = Vec::new();
+        lz4::Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded.len(), 1400);
+    }
+}
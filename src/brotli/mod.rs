@@ -0,0 +1,1071 @@
+/*!
+
+Brotli decompression. Requires `brotli` feature, enabled by default
+
+This is a pure Rust decoder of the format of RFC 7932, the `br` content
+encoding of the web. A stream is made of meta-blocks, each either stored or
+coded as commands inserting literals and copying earlier data. Literals,
+commands and distances are coded with several Huffman codes each, picked by
+block switches in the stream and, for literals and distances, by a context
+map from the preceding bytes or the copy length. Distances beyond the
+window refer to words of the built-in static dictionary, optionally
+transformed.
+
+The decoder allocates the window the stream declares, 16 MiB at most, when
+it reads the stream header. There is no encoder.
+
+# Example
+
+```rust
+use std::io::Read;
+use compress::brotli;
+
+// "hello, hello, hello" compressed with `brotli -q 11`
+let compressed = [0x1b, 0x12, 0x00, 0xf8, 0x8d, 0x94, 0x6e, 0xde, 0x44, 0x09, 0x52,
+                  0x86, 0x96, 0x6c, 0x2c, 0x6f, 0x11, 0x4f, 0x24, 0xd0, 0x71, 0x00];
+let mut d = brotli::Decoder::new(&compressed[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], b"hello, hello, hello");
+```
+
+# Links
+
+* https://tools.ietf.org/html/rfc7932
+
+*/
+
+use std::cmp;
+use std::io::{self, Read};
+
+use super::byteorder::ReadBytesExt;
+
+static DICTIONARY: &[u8; 122784] = include_bytes!("dictionary.bin");
+
+// the dictionary holds words of 4 to 24 bytes, `1 << DICT_BITS[len]` of
+// each length starting at `DICT_OFFSET[len]`
+static DICT_BITS: [u8; 25] = [
+    0, 0, 0, 0, 10, 10, 11, 11, 10, 10, 10, 10, 10, 9, 9, 8, 7, 7, 8, 7, 7, 6, 6, 5, 5,
+];
+static DICT_OFFSET: [u32; 25] = [
+    0, 0, 0, 0, 0, 4096, 9216, 21504, 35840, 44032, 53248, 63488, 74752, 87040, 93696, 100864,
+    104704, 106752, 108928, 113536, 115968, 118528, 119872, 121280, 122016,
+];
+
+#[derive(Clone, Copy)]
+enum Transform {
+    Identity,
+    OmitLast(usize),
+    UppercaseFirst,
+    UppercaseAll,
+    OmitFirst(usize),
+}
+
+use self::Transform::*;
+
+// prefix, transform and suffix of the dictionary word transforms, Appendix B
+static TRANSFORMS: [(&[u8], Transform, &[u8]); 121] = [
+    (b"", Identity, b""),
+    (b"", Identity, b" "),
+    (b" ", Identity, b" "),
+    (b"", OmitFirst(1), b""),
+    (b"", UppercaseFirst, b" "),
+    (b"", Identity, b" the "),
+    (b" ", Identity, b""),
+    (b"s ", Identity, b" "),
+    (b"", Identity, b" of "),
+    (b"", UppercaseFirst, b""),
+    (b"", Identity, b" and "),
+    (b"", OmitFirst(2), b""),
+    (b"", OmitLast(1), b""),
+    (b", ", Identity, b" "),
+    (b"", Identity, b", "),
+    (b" ", UppercaseFirst, b" "),
+    (b"", Identity, b" in "),
+    (b"", Identity, b" to "),
+    (b"e ", Identity, b" "),
+    (b"", Identity, b"\""),
+    (b"", Identity, b"."),
+    (b"", Identity, b"\">"),
+    (b"", Identity, b"\n"),
+    (b"", OmitLast(3), b""),
+    (b"", Identity, b"]"),
+    (b"", Identity, b" for "),
+    (b"", OmitFirst(3), b""),
+    (b"", OmitLast(2), b""),
+    (b"", Identity, b" a "),
+    (b"", Identity, b" that "),
+    (b" ", UppercaseFirst, b""),
+    (b"", Identity, b". "),
+    (b".", Identity, b""),
+    (b" ", Identity, b", "),
+    (b"", OmitFirst(4), b""),
+    (b"", Identity, b" with "),
+    (b"", Identity, b"'"),
+    (b"", Identity, b" from "),
+    (b"", Identity, b" by "),
+    (b"", OmitFirst(5), b""),
+    (b"", OmitFirst(6), b""),
+    (b" the ", Identity, b""),
+    (b"", OmitLast(4), b""),
+    (b"", Identity, b". The "),
+    (b"", UppercaseAll, b""),
+    (b"", Identity, b" on "),
+    (b"", Identity, b" as "),
+    (b"", Identity, b" is "),
+    (b"", OmitLast(7), b""),
+    (b"", OmitLast(1), b"ing "),
+    (b"", Identity, b"\n\t"),
+    (b"", Identity, b":"),
+    (b" ", Identity, b". "),
+    (b"", Identity, b"ed "),
+    (b"", OmitFirst(9), b""),
+    (b"", OmitFirst(7), b""),
+    (b"", OmitLast(6), b""),
+    (b"", Identity, b"("),
+    (b"", UppercaseFirst, b", "),
+    (b"", OmitLast(8), b""),
+    (b"", Identity, b" at "),
+    (b"", Identity, b"ly "),
+    (b" the ", Identity, b" of "),
+    (b"", OmitLast(5), b""),
+    (b"", OmitLast(9), b""),
+    (b" ", UppercaseFirst, b", "),
+    (b"", UppercaseFirst, b"\""),
+    (b".", Identity, b"("),
+    (b"", UppercaseAll, b" "),
+    (b"", UppercaseFirst, b"\">"),
+    (b"", Identity, b"=\""),
+    (b" ", Identity, b"."),
+    (b".com/", Identity, b""),
+    (b" the ", Identity, b" of the "),
+    (b"", UppercaseFirst, b"'"),
+    (b"", Identity, b". This "),
+    (b"", Identity, b","),
+    (b".", Identity, b" "),
+    (b"", UppercaseFirst, b"("),
+    (b"", UppercaseFirst, b"."),
+    (b"", Identity, b" not "),
+    (b" ", Identity, b"=\""),
+    (b"", Identity, b"er "),
+    (b" ", UppercaseAll, b" "),
+    (b"", Identity, b"al "),
+    (b" ", UppercaseAll, b""),
+    (b"", Identity, b"='"),
+    (b"", UppercaseAll, b"\""),
+    (b"", UppercaseFirst, b". "),
+    (b" ", Identity, b"("),
+    (b"", Identity, b"ful "),
+    (b" ", UppercaseFirst, b". "),
+    (b"", Identity, b"ive "),
+    (b"", Identity, b"less "),
+    (b"", UppercaseAll, b"'"),
+    (b"", Identity, b"est "),
+    (b" ", UppercaseFirst, b"."),
+    (b"", UppercaseAll, b"\">"),
+    (b" ", Identity, b"='"),
+    (b"", UppercaseFirst, b","),
+    (b"", Identity, b"ize "),
+    (b"", UppercaseAll, b"."),
+    (b"\xc2\xa0", Identity, b""),
+    (b" ", Identity, b","),
+    (b"", UppercaseFirst, b"=\""),
+    (b"", UppercaseAll, b"=\""),
+    (b"", Identity, b"ous "),
+    (b"", UppercaseAll, b", "),
+    (b"", UppercaseFirst, b"='"),
+    (b" ", UppercaseFirst, b","),
+    (b" ", UppercaseAll, b"=\""),
+    (b" ", UppercaseAll, b", "),
+    (b"", UppercaseAll, b","),
+    (b"", UppercaseAll, b"("),
+    (b"", UppercaseAll, b". "),
+    (b" ", UppercaseAll, b"."),
+    (b"", UppercaseAll, b"='"),
+    (b" ", UppercaseAll, b". "),
+    (b" ", UppercaseFirst, b"=\""),
+    (b" ", UppercaseAll, b"='"),
+    (b" ", UppercaseFirst, b"='"),
+];
+
+// literal context of the UTF8 mode, from the last and second to last bytes
+static UTF8_LAST: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 4, 0, 0, 4, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    8, 12, 16, 12, 12, 20, 12, 16, 24, 28, 12, 12, 32, 12, 36, 12,
+    44, 44, 44, 44, 44, 44, 44, 44, 44, 44, 32, 32, 24, 40, 28, 12,
+    12, 48, 52, 52, 52, 48, 52, 52, 52, 48, 52, 52, 52, 52, 52, 48,
+    52, 52, 52, 52, 52, 48, 52, 52, 52, 52, 52, 24, 12, 28, 12, 12,
+    12, 56, 60, 60, 60, 56, 60, 60, 60, 56, 60, 60, 60, 60, 60, 56,
+    60, 60, 60, 60, 60, 56, 60, 60, 60, 60, 60, 24, 12, 28, 12, 0,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+    2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3,
+];
+static UTF8_SECOND: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1,
+    1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1,
+    1, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3,
+    3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 3, 1, 1, 1, 1, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+];
+
+// literal context of the signed mode, for either of the last two bytes
+fn signed_context(b: u8) -> usize {
+    match b {
+        0 => 0,
+        1..=15 => 1,
+        16..=63 => 2,
+        64..=127 => 3,
+        128..=191 => 4,
+        192..=239 => 5,
+        240..=254 => 6,
+        255 => 7,
+    }
+}
+
+// base value and extra bits of the block counts, insert lengths and copy
+// lengths
+static BLOCK_COUNT: [(u32, u32); 26] = [
+    (1, 2), (5, 2), (9, 2), (13, 2), (17, 3), (25, 3), (33, 3), (41, 3), (49, 4), (65, 4),
+    (81, 4), (97, 4), (113, 5), (145, 5), (177, 5), (209, 5), (241, 6), (305, 6), (369, 7),
+    (497, 8), (753, 9), (1265, 10), (2289, 11), (4337, 12), (8433, 13), (16625, 24),
+];
+static INSERT_LEN: [(u32, u32); 24] = [
+    (0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0), (6, 1), (8, 1), (10, 2), (14, 2), (18, 3),
+    (26, 3), (34, 4), (50, 4), (66, 5), (98, 5), (130, 6), (194, 7), (322, 8), (578, 9),
+    (1090, 10), (2114, 12), (6210, 14), (22594, 24),
+];
+static COPY_LEN: [(u32, u32); 24] = [
+    (2, 0), (3, 0), (4, 0), (5, 0), (6, 0), (7, 0), (8, 0), (9, 0), (10, 1), (12, 1), (14, 2),
+    (18, 2), (22, 3), (30, 3), (38, 4), (54, 4), (70, 5), (102, 5), (134, 6), (198, 7),
+    (326, 8), (582, 9), (1094, 10), (2118, 24),
+];
+// the first insert and copy length codes of each 64 command codes, the
+// first two cells reusing the last distance
+static COMMAND_CELLS: [(u32, u32); 11] = [
+    (0, 0), (0, 8), (0, 0), (0, 8), (8, 0), (8, 8), (0, 16), (16, 0), (8, 16), (16, 8), (16, 16),
+];
+static CODE_LENGTH_ORDER: [usize; 18] = [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+const MAX_CODE_LEN: usize = 15;
+const LITERALS: usize = 256;
+const COMMANDS: usize = 704;
+const BLOCK_COUNTS: usize = 26;
+
+fn error<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+struct BitReader<R> {
+    r: R,
+    buf: u64,
+    cnt: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    // Bytes are read LSB first
+    fn bits(&mut self, cnt: u32) -> io::Result<u32> {
+        while self.cnt < cnt {
+            let byte = try!(self.r.read_u8());
+            self.buf |= (byte as u64) << self.cnt;
+            self.cnt += 8;
+        }
+        let ret = self.buf & ((1 << cnt) - 1);
+        self.buf >>= cnt;
+        self.cnt -= cnt;
+        Ok(ret as u32)
+    }
+
+    fn bit(&mut self) -> io::Result<bool> {
+        self.bits(1).map(|b| b == 1)
+    }
+
+    // Skips to the byte boundary, the padding has to be zero
+    fn align(&mut self) -> io::Result<()> {
+        let pad = self.cnt % 8;
+        if try!(self.bits(pad)) != 0 {
+            return error("non-zero brotli padding")
+        }
+        Ok(())
+    }
+
+    // Whole bytes after `align`
+    fn bytes(&mut self, out: &mut [u8]) -> io::Result<()> {
+        let mut n = 0;
+        while self.cnt > 0 && n < out.len() {
+            out[n] = try!(self.bits(8)) as u8;
+            n += 1;
+        }
+        self.r.read_exact(&mut out[n..])
+    }
+
+    // VarLenUint8, a number from 0 to 255
+    fn var_u8(&mut self) -> io::Result<usize> {
+        if !try!(self.bit()) {
+            return Ok(0)
+        }
+        match try!(self.bits(3)) {
+            0 => Ok(1),
+            n => self.bits(n).map(|extra| (1 << n) + extra as usize),
+        }
+    }
+}
+
+// A canonical prefix code, decoded a bit at a time like `flate` does
+struct Huffman {
+    // number of codes of each length
+    count: [u16; MAX_CODE_LEN + 1],
+    // symbols ordered by their code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lens: &[u8]) -> Huffman {
+        let mut count = [0; MAX_CODE_LEN + 1];
+        for &len in lens {
+            count[len as usize] += 1;
+        }
+        count[0] = 0;
+        let mut symbols = Vec::new();
+        for len in 1..MAX_CODE_LEN + 1 {
+            symbols.extend((0..lens.len()).filter(|&s| lens[s] as usize == len).map(|s| s as u16));
+        }
+        Huffman { count, symbols }
+    }
+
+    // A code of a lone symbol, which takes no bits
+    fn single(symbol: usize) -> Huffman {
+        Huffman { count: [0; MAX_CODE_LEN + 1], symbols: vec![symbol as u16] }
+    }
+
+    fn decode<R: Read>(&self, r: &mut BitReader<R>) -> io::Result<usize> {
+        if self.count[1..].iter().all(|&c| c == 0) {
+            return Ok(self.symbols[0] as usize)
+        }
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..MAX_CODE_LEN + 1 {
+            code |= try!(r.bits(1)) as usize;
+            let count = self.count[len] as usize;
+            if code < first + count {
+                return Ok(self.symbols[index + code - first] as usize)
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        error("invalid brotli prefix code")
+    }
+
+    // Reads the description of a code over `size` symbols, section 3
+    fn read<R: Read>(r: &mut BitReader<R>, size: usize) -> io::Result<Huffman> {
+        match try!(r.bits(2)) {
+            1 => Huffman::read_simple(r, size),
+            skip => Huffman::read_complex(r, size, skip as usize),
+        }
+    }
+
+    fn read_simple<R: Read>(r: &mut BitReader<R>, size: usize) -> io::Result<Huffman> {
+        let bits = usize::BITS - (size - 1).leading_zeros();
+        let count = try!(r.bits(2)) as usize + 1;
+        let mut symbols = [0; 4];
+        for i in 0..count {
+            symbols[i] = try!(r.bits(bits)) as usize;
+            if symbols[i] >= size || symbols[..i].contains(&symbols[i]) {
+                return error("invalid brotli simple prefix code")
+            }
+        }
+        let lens: &[u8] = match count {
+            1 => return Ok(Huffman::single(symbols[0])),
+            2 => &[1, 1],
+            3 => &[1, 2, 2],
+            _ if try!(r.bit()) => &[1, 2, 3, 3],
+            _ => &[2, 2, 2, 2],
+        };
+        let mut all = vec![0; size];
+        for (&s, &len) in symbols.iter().zip(lens) {
+            all[s] = len;
+        }
+        Ok(Huffman::new(&all))
+    }
+
+    fn read_complex<R: Read>(r: &mut BitReader<R>, size: usize, skip: usize)
+                             -> io::Result<Huffman> {
+        // the lengths of the code length code have a fixed code of their own
+        let mut lens = [0; 18];
+        let mut space = 32;
+        let mut used = 0;
+        for &sym in &CODE_LENGTH_ORDER[skip..] {
+            let len = match try!(r.bits(2)) {
+                0 => 0,
+                1 => 4,
+                2 => 3,
+                _ => match try!(r.bits(1)) {
+                    0 => 2,
+                    _ => if try!(r.bit()) { 5 } else { 1 },
+                },
+            };
+            lens[sym] = len;
+            if len != 0 {
+                space -= 32 >> len;
+                used += 1;
+                if space <= 0 {
+                    break
+                }
+            }
+        }
+        if used != 1 && space != 0 {
+            return error("invalid brotli code length code")
+        }
+        let code_lengths = if used == 1 {
+            Huffman::single(lens.iter().position(|&l| l != 0).unwrap())
+        } else {
+            Huffman::new(&lens)
+        };
+
+        let mut lens = vec![0; size];
+        let mut space = 1 << MAX_CODE_LEN;
+        let (mut sym, mut prev, mut repeat, mut repeat_len) = (0, 8, 0, 0);
+        while sym < size && space > 0 {
+            let len = try!(code_lengths.decode(r));
+            if len < 16 {
+                repeat = 0;
+                lens[sym] = len as u8;
+                sym += 1;
+                if len != 0 {
+                    prev = len;
+                    space -= (1 << MAX_CODE_LEN) >> len;
+                }
+                continue
+            }
+            // 16 repeats the previous non-zero length, 17 repeats zeros,
+            // and consecutive repeats multiply
+            let (extra, new_len) = if len == 16 { (2, prev) } else { (3, 0) };
+            if repeat_len != new_len {
+                repeat = 0;
+                repeat_len = new_len;
+            }
+            let old = repeat;
+            if repeat > 0 {
+                repeat = (repeat - 2) << extra;
+            }
+            repeat += try!(r.bits(extra)) as usize + 3;
+            let delta = repeat - old;
+            if sym + delta > size {
+                return error("invalid brotli code lengths")
+            }
+            for len in &mut lens[sym..sym + delta] {
+                *len = repeat_len as u8;
+            }
+            sym += delta;
+            if repeat_len != 0 {
+                space -= (delta as i64) << (MAX_CODE_LEN - repeat_len);
+            }
+        }
+        if space != 0 {
+            return error("invalid brotli code lengths")
+        }
+        Ok(Huffman::new(&lens))
+    }
+}
+
+// Block types and counts of one of the three categories
+struct BlockSwitch {
+    types: usize,
+    type_code: Option<Huffman>,
+    count_code: Option<Huffman>,
+    current: usize,
+    prev: usize,
+    left: u32,
+}
+
+impl BlockSwitch {
+    fn read<R: Read>(r: &mut BitReader<R>) -> io::Result<BlockSwitch> {
+        let types = try!(r.var_u8()) + 1;
+        let mut switch = BlockSwitch {
+            types,
+            type_code: None,
+            count_code: None,
+            current: 0,
+            prev: 1,
+            left: 1 << 24,
+        };
+        if types >= 2 {
+            switch.type_code = Some(try!(Huffman::read(r, types + 2)));
+            let count_code = try!(Huffman::read(r, BLOCK_COUNTS));
+            switch.left = try!(read_count(r, &count_code));
+            switch.count_code = Some(count_code);
+        }
+        Ok(switch)
+    }
+
+    // Counts one more item, switching to the next block first if the
+    // current one is over
+    fn next<R: Read>(&mut self, r: &mut BitReader<R>) -> io::Result<usize> {
+        if self.left == 0 {
+            let (type_code, count_code) = match (&self.type_code, &self.count_code) {
+                (Some(t), Some(c)) => (t, c),
+                _ => return error("brotli block count exceeded"),
+            };
+            let next = match try!(type_code.decode(r)) {
+                0 => self.prev,
+                1 => (self.current + 1) % self.types,
+                code => code - 2,
+            };
+            self.prev = self.current;
+            self.current = next;
+            self.left = try!(read_count(r, count_code));
+        }
+        self.left -= 1;
+        Ok(self.current)
+    }
+}
+
+fn read_count<R: Read>(r: &mut BitReader<R>, code: &Huffman) -> io::Result<u32> {
+    let (base, extra) = BLOCK_COUNT[try!(code.decode(r))];
+    r.bits(extra).map(|bits| base + bits)
+}
+
+fn read_context_map<R: Read>(r: &mut BitReader<R>, size: usize, trees: usize)
+                             -> io::Result<Vec<u8>> {
+    let mut map = vec![0; size];
+    if trees < 2 {
+        return Ok(map)
+    }
+    let rle_max = if try!(r.bit()) { try!(r.bits(4)) as usize + 1 } else { 0 };
+    let code = try!(Huffman::read(r, trees + rle_max));
+    let mut i = 0;
+    while i < size {
+        match try!(code.decode(r)) {
+            0 => i += 1,
+            sym if sym <= rle_max => {
+                let zeros = (1 << sym) + try!(r.bits(sym as u32)) as usize;
+                if i + zeros > size {
+                    return error("invalid brotli context map")
+                }
+                i += zeros;
+            }
+            sym => {
+                map[i] = (sym - rle_max) as u8;
+                i += 1;
+            }
+        }
+    }
+    if try!(r.bit()) {
+        // inverse move-to-front
+        let mut mtf: Vec<u8> = (0..=255).collect();
+        for v in &mut map {
+            let index = *v as usize;
+            *v = mtf[index];
+            mtf[..index + 1].rotate_right(1);
+        }
+    }
+    Ok(map)
+}
+
+// The codes of a compressed meta-block
+struct MetaBlock {
+    literal: BlockSwitch,
+    command: BlockSwitch,
+    distance: BlockSwitch,
+    postfix: u32,
+    direct: usize,
+    modes: Vec<u8>,
+    literal_map: Vec<u8>,
+    distance_map: Vec<u8>,
+    literal_codes: Vec<Huffman>,
+    command_codes: Vec<Huffman>,
+    distance_codes: Vec<Huffman>,
+}
+
+impl MetaBlock {
+    fn read<R: Read>(r: &mut BitReader<R>) -> io::Result<MetaBlock> {
+        let literal = try!(BlockSwitch::read(r));
+        let command = try!(BlockSwitch::read(r));
+        let distance = try!(BlockSwitch::read(r));
+        let postfix = try!(r.bits(2));
+        let direct = (try!(r.bits(4)) as usize) << postfix;
+        let mut modes = Vec::with_capacity(literal.types);
+        for _ in 0..literal.types {
+            modes.push(try!(r.bits(2)) as u8);
+        }
+        let literal_trees = try!(r.var_u8()) + 1;
+        let literal_map = try!(read_context_map(r, 64 * literal.types, literal_trees));
+        let distance_trees = try!(r.var_u8()) + 1;
+        let distance_map = try!(read_context_map(r, 4 * distance.types, distance_trees));
+
+        let read_codes = |r: &mut BitReader<R>, count, size| {
+            (0..count).map(|_| Huffman::read(r, size)).collect::<io::Result<Vec<_>>>()
+        };
+        let literal_codes = try!(read_codes(r, literal_trees, LITERALS));
+        let command_codes = try!(read_codes(r, command.types, COMMANDS));
+        let distance_size = 16 + direct + (48 << postfix);
+        let distance_codes = try!(read_codes(r, distance_trees, distance_size));
+        Ok(MetaBlock {
+            literal, command, distance, postfix, direct, modes, literal_map, distance_map,
+            literal_codes, command_codes, distance_codes,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    StreamHeader,
+    MetaHeader,
+    Uncompressed,
+    Command,
+    Insert,
+    Distance,
+    Copy,
+    Word,
+    Done,
+}
+
+/// Decompresses a brotli stream from the reader it wraps
+pub struct Decoder<R> {
+    r: BitReader<R>,
+    step: Step,
+    // the ring buffer of the window, a power of two in size
+    window: Vec<u8>,
+    max_distance: usize,
+    total: usize,
+    is_last: bool,
+    // bytes left in the meta-block
+    meta_left: usize,
+    meta: Option<MetaBlock>,
+    // the last four distances, most recent first
+    distances: [usize; 4],
+    insert_left: usize,
+    copy_len: usize,
+    last_distance: bool,
+    copy_left: usize,
+    copy_distance: usize,
+    word: [u8; 64],
+    word_pos: usize,
+    word_len: usize,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading a brotli stream from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            r: BitReader { r, buf: 0, cnt: 0 },
+            step: Step::StreamHeader,
+            window: Vec::new(),
+            max_distance: 0,
+            total: 0,
+            is_last: false,
+            meta_left: 0,
+            meta: None,
+            distances: [4, 11, 15, 16],
+            insert_left: 0,
+            copy_len: 0,
+            last_distance: false,
+            copy_left: 0,
+            copy_distance: 0,
+            word: [0; 64],
+            word_pos: 0,
+            word_len: 0,
+        }
+    }
+
+    /// Returns the wrapped reader. Bytes read ahead of the end of the stream
+    /// are lost, which are at most the last byte.
+    pub fn into_inner(self) -> R {
+        self.r.r
+    }
+
+    fn stream_header(&mut self) -> io::Result<()> {
+        let bits = if !try!(self.r.bit()) {
+            16
+        } else {
+            match try!(self.r.bits(3)) {
+                0 => match try!(self.r.bits(3)) {
+                    0 => 17,
+                    1 => return error("large brotli windows are not supported"),
+                    n => 8 + n,
+                },
+                n => 17 + n,
+            }
+        };
+        self.window = vec![0; 1 << bits];
+        self.max_distance = (1 << bits) - 16;
+        Ok(())
+    }
+
+    fn meta_header(&mut self) -> io::Result<Step> {
+        self.is_last = try!(self.r.bit());
+        if self.is_last && try!(self.r.bit()) {
+            return self.end_stream()
+        }
+        let nibbles = match try!(self.r.bits(2)) {
+            3 => 0,
+            n => n + 4,
+        };
+        if nibbles == 0 {
+            // metadata, skipped
+            if try!(self.r.bit()) {
+                return error("reserved brotli bit set")
+            }
+            let len_bytes = try!(self.r.bits(2));
+            let mut skip = 0;
+            for i in 0..len_bytes {
+                let byte = try!(self.r.bits(8)) as u64;
+                if i > 0 && i + 1 == len_bytes && byte == 0 {
+                    return error("invalid brotli metadata length")
+                }
+                skip |= byte << (8 * i);
+            }
+            if len_bytes > 0 {
+                skip += 1;
+            }
+            try!(self.r.align());
+            let mut buf = [0; 256];
+            while skip > 0 {
+                let n = cmp::min(skip, buf.len() as u64) as usize;
+                try!(self.r.bytes(&mut buf[..n]));
+                skip -= n as u64;
+            }
+            return if self.is_last { self.end_stream() } else { Ok(Step::MetaHeader) }
+        }
+
+        let mut len = 0;
+        for i in 0..nibbles {
+            let nibble = try!(self.r.bits(4)) as usize;
+            if i > 3 && i + 1 == nibbles && nibble == 0 {
+                return error("invalid brotli meta-block length")
+            }
+            len |= nibble << (4 * i);
+        }
+        self.meta_left = len + 1;
+        if !self.is_last && try!(self.r.bit()) {
+            try!(self.r.align());
+            return Ok(Step::Uncompressed)
+        }
+        self.meta = Some(try!(MetaBlock::read(&mut self.r)));
+        Ok(Step::Command)
+    }
+
+    fn end_stream(&mut self) -> io::Result<Step> {
+        try!(self.r.align());
+        Ok(Step::Done)
+    }
+
+    fn end_meta(&mut self) -> io::Result<Step> {
+        if self.is_last { self.end_stream() } else { Ok(Step::MetaHeader) }
+    }
+
+    fn byte_back(&self, distance: usize) -> u8 {
+        if distance > self.total {
+            return 0
+        }
+        self.window[(self.total - distance) & (self.window.len() - 1)]
+    }
+
+    fn put(&mut self, out: &mut [u8], n: &mut usize, b: u8) {
+        let mask = self.window.len() - 1;
+        self.window[self.total & mask] = b;
+        self.total += 1;
+        out[*n] = b;
+        *n += 1;
+    }
+
+    fn command(&mut self) -> io::Result<Step> {
+        let meta = self.meta.as_mut().unwrap();
+        let block = try!(meta.command.next(&mut self.r));
+        let code = try!(meta.command_codes[block].decode(&mut self.r));
+        let (insert_base, copy_base) = COMMAND_CELLS[code >> 6];
+        let (insert, insert_extra) = INSERT_LEN[insert_base as usize + ((code >> 3) & 7)];
+        let (copy, copy_extra) = COPY_LEN[copy_base as usize + (code & 7)];
+        self.insert_left = (insert + try!(self.r.bits(insert_extra))) as usize;
+        self.copy_len = (copy + try!(self.r.bits(copy_extra))) as usize;
+        self.last_distance = code < 128;
+        Ok(Step::Insert)
+    }
+
+    fn literal(&mut self) -> io::Result<u8> {
+        let (p1, p2) = (self.byte_back(1), self.byte_back(2));
+        let meta = self.meta.as_mut().unwrap();
+        let block = try!(meta.literal.next(&mut self.r));
+        let context = match meta.modes[block] {
+            0 => p1 as usize & 0x3F,
+            1 => p1 as usize >> 2,
+            2 => (UTF8_LAST[p1 as usize] | UTF8_SECOND[p2 as usize]) as usize,
+            _ => signed_context(p1) << 3 | signed_context(p2),
+        };
+        let tree = meta.literal_map[64 * block + context] as usize;
+        meta.literal_codes[tree].decode(&mut self.r).map(|b| b as u8)
+    }
+
+    fn distance(&mut self) -> io::Result<Step> {
+        let copy_len = self.copy_len;
+        let distance = if self.last_distance {
+            self.distances[0]
+        } else {
+            let meta = self.meta.as_mut().unwrap();
+            let block = try!(meta.distance.next(&mut self.r));
+            let context = cmp::min(copy_len, 5) - 2;
+            let tree = meta.distance_map[4 * block + context] as usize;
+            let code = try!(meta.distance_codes[tree].decode(&mut self.r));
+            let distance = if code < 16 {
+                // the last distances, some of them adjusted
+                const INDEX: [usize; 16] = [0, 1, 2, 3, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1];
+                const DELTA: [isize; 16] = [0, 0, 0, 0, -1, 1, -2, 2, -3, 3, -1, 1, -2, 2, -3, 3];
+                let distance = self.distances[INDEX[code]] as isize + DELTA[code];
+                if distance <= 0 {
+                    return error("invalid brotli distance")
+                }
+                distance as usize
+            } else if code < 16 + meta.direct {
+                code - 15
+            } else {
+                let code = code - meta.direct - 16;
+                let bits = 1 + (code >> (meta.postfix + 1)) as u32;
+                let high = code >> meta.postfix;
+                let low = code & ((1 << meta.postfix) - 1);
+                let offset = ((2 + (high & 1)) << bits) - 4;
+                let extra = try!(self.r.bits(bits)) as usize;
+                ((offset + extra) << meta.postfix) + low + meta.direct + 1
+            };
+            if code != 0 && distance <= cmp::min(self.max_distance, self.total) {
+                self.distances = [distance, self.distances[0], self.distances[1], self.distances[2]];
+            }
+            distance
+        };
+
+        if distance <= cmp::min(self.max_distance, self.total) {
+            if copy_len > self.meta_left {
+                return error("brotli copy past the end of the meta-block")
+            }
+            self.copy_left = copy_len;
+            self.copy_distance = distance;
+            return Ok(Step::Copy)
+        }
+
+        // a word of the static dictionary
+        let len = copy_len;
+        if !(4..=24).contains(&len) {
+            return error("invalid brotli dictionary reference")
+        }
+        let id = distance - cmp::min(self.max_distance, self.total) - 1;
+        let bits = DICT_BITS[len];
+        let transform = id >> bits;
+        if transform >= TRANSFORMS.len() {
+            return error("invalid brotli dictionary reference")
+        }
+        let start = DICT_OFFSET[len] as usize + (id & ((1 << bits) - 1)) * len;
+        self.word_len = transform_word(&mut self.word, &DICTIONARY[start..start + len], transform);
+        self.word_pos = 0;
+        if self.word_len > self.meta_left {
+            return error("brotli copy past the end of the meta-block")
+        }
+        Ok(Step::Word)
+    }
+}
+
+// Upper-cases the UTF-8 sequence at the start of `word` the way brotli
+// does, returning its length
+fn uppercase(word: &mut [u8]) -> usize {
+    if word[0] < 0xC0 {
+        if word[0].is_ascii_lowercase() {
+            word[0] ^= 0x20;
+        }
+        1
+    } else if word[0] < 0xE0 {
+        if word.len() > 1 {
+            word[1] ^= 0x20;
+        }
+        2
+    } else {
+        if word.len() > 2 {
+            word[2] ^= 0x05;
+        }
+        3
+    }
+}
+
+fn transform_word(out: &mut [u8; 64], word: &[u8], transform: usize) -> usize {
+    let (prefix, kind, suffix) = TRANSFORMS[transform];
+    out[..prefix.len()].copy_from_slice(prefix);
+    let mut len = prefix.len();
+    let word = match kind {
+        OmitFirst(n) => &word[cmp::min(n, word.len())..],
+        OmitLast(n) => &word[..word.len().saturating_sub(n)],
+        _ => word,
+    };
+    out[len..len + word.len()].copy_from_slice(word);
+    let body = len..len + word.len();
+    len += word.len();
+    match kind {
+        UppercaseFirst if !word.is_empty() => { uppercase(&mut out[body]); }
+        UppercaseAll => {
+            let mut i = body.start;
+            while i < body.end {
+                i += uppercase(&mut out[i..body.end]);
+            }
+        }
+        _ => {}
+    }
+    out[len..len + suffix.len()].copy_from_slice(suffix);
+    len + suffix.len()
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            self.step = match self.step {
+                Step::StreamHeader => {
+                    try!(self.stream_header());
+                    Step::MetaHeader
+                }
+                Step::MetaHeader => try!(self.meta_header()),
+                Step::Uncompressed => {
+                    let len = cmp::min(buf.len() - n, self.meta_left);
+                    try!(self.r.bytes(&mut buf[n..n + len]));
+                    let mask = self.window.len() - 1;
+                    for &b in &buf[n..n + len] {
+                        self.window[self.total & mask] = b;
+                        self.total += 1;
+                    }
+                    n += len;
+                    self.meta_left -= len;
+                    if self.meta_left == 0 { try!(self.end_meta()) } else { Step::Uncompressed }
+                }
+                Step::Command => try!(self.command()),
+                Step::Insert => {
+                    while self.insert_left > 0 && n < buf.len() {
+                        if self.meta_left == 0 {
+                            return error("brotli insert past the end of the meta-block")
+                        }
+                        let b = try!(self.literal());
+                        self.put(buf, &mut n, b);
+                        self.insert_left -= 1;
+                        self.meta_left -= 1;
+                    }
+                    if self.insert_left > 0 {
+                        Step::Insert
+                    } else if self.meta_left == 0 {
+                        // the copy of the last command is ignored
+                        try!(self.end_meta())
+                    } else {
+                        Step::Distance
+                    }
+                }
+                Step::Distance => try!(self.distance()),
+                Step::Copy => {
+                    let len = cmp::min(self.copy_left, buf.len() - n);
+                    for _ in 0..len {
+                        let b = self.byte_back(self.copy_distance);
+                        self.put(buf, &mut n, b);
+                    }
+                    self.copy_left -= len;
+                    self.meta_left -= len;
+                    if self.copy_left > 0 {
+                        Step::Copy
+                    } else if self.meta_left == 0 {
+                        try!(self.end_meta())
+                    } else {
+                        Step::Command
+                    }
+                }
+                Step::Word => {
+                    let len = cmp::min(self.word_len - self.word_pos, buf.len() - n);
+                    for i in 0..len {
+                        let b = self.word[self.word_pos + i];
+                        self.put(buf, &mut n, b);
+                    }
+                    self.word_pos += len;
+                    self.meta_left -= len;
+                    if self.word_pos < self.word_len {
+                        Step::Word
+                    } else if self.meta_left == 0 {
+                        try!(self.end_meta())
+                    } else {
+                        Step::Command
+                    }
+                }
+                Step::Done => break,
+            };
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+    use super::Decoder;
+
+    fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Decoder::new(data).read_to_end(&mut out).map(|_| out)
+    }
+
+    #[test]
+    fn empty() {
+        // a last empty meta-block, with the default and the smallest window
+        assert_eq!(decode(&[0x3b]).unwrap(), b"");
+        assert_eq!(decode(&[0xa1, 0x01]).unwrap(), b"");
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn decode_files() {
+        let text = &include_bytes!("../data/test.txt")[..];
+        for &data in &[&include_bytes!("../data/test.txt.br.0")[..],
+                       &include_bytes!("../data/test.txt.br.5")[..],
+                       &include_bytes!("../data/test.txt.br.11")[..]] {
+            assert_eq!(&decode(data).unwrap()[..], text);
+
+            let mut d = Decoder::new(data);
+            let mut out = Vec::new();
+            let mut buf = [0; 7];
+            loop {
+                match d.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => out.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert_eq!(&out[..], text);
+        }
+    }
+
+    #[test]
+    fn dictionary_words() {
+        // the encoder finds these in the static dictionary, with transforms
+        let text = b"The Free Software, the free software. THE FREE SOFTWARE! \
+                     information; Information; INFORMATION";
+        assert_eq!(&decode(include_bytes!("../data/words.br")).unwrap()[..], &text[..]);
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = include_bytes!("../data/test.txt.br.11");
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        for at in 0..data.len() {
+            let mut data = data.to_vec();
+            data[at] ^= 0x20;
+            // errors or wrong data, never a panic
+            if let Ok(out) = decode(&data) {
+                assert!(out[..] != include_bytes!("../data/test.txt")[..]);
+            }
+        }
+    }
+}
@@ -7,6 +7,13 @@ Designed to be used on BWT block output for compression.
 
 http://www.data-compression.info/Algorithms/DC/
 
+`Encoder` and `Decoder` apply the transformation to a stream, cut into
+blocks. Each block is written as its length, the number of symbols present
+with the first position of each, and the distances; lengths, positions and
+distances are u32 little endian. The stream has no header, so empty input
+encodes to no bytes and no bytes decode to empty output; the DC stage is
+meant to sit inside a framed format such as the `bwt` one.
+
 # Example
 
 ```rust
@@ -17,6 +24,19 @@ let distances = dc::encode_simple::<usize>(bytes);
 let decoded = dc::decode_simple(bytes.len(), &distances[..]);
 ```
 
+```rust
+use std::io::{Read, Write};
+use compress::bwt::dc;
+
+let mut e = dc::Encoder::new(Vec::new(), 1 << 16).unwrap();
+e.write_all(b"abracadabra").unwrap();
+let (encoded, _) = e.finish();
+
+let mut decoded = Vec::new();
+dc::Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], b"abracadabra");
+```
+
 # Credit
 
 This is an original implementation.
@@ -24,11 +44,14 @@ Thanks to Edgar Binder for inventing DC!
 
 */
 
-use std::io;
+use std::{cmp, io};
+use std::io::{Read, Write};
 use std::iter::{self, repeat};
 use std::slice as vec;
 use super::num::traits::{NumCast, ToPrimitive};
 use super::mtf::MTF;
+use super::super::byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
+use super::super::byteorder_err_to_io;
 
 pub type Symbol = u8;
 pub type Rank = u8;
@@ -38,23 +61,34 @@ pub const TOTAL_SYMBOLS: usize = 0x100;
 /// Has all the information potentially needed by the underlying coding model
 #[derive(PartialEq, Eq, Debug)]
 pub struct Context {
-    /// current symbol
-    pub symbol: Symbol,
-    /// last known MTF rank
-    pub last_rank: Rank,
-    /// maximum possible distance
-    pub distance_limit: usize,
+    symbol: Symbol,
+    last_rank: Rank,
+    distance_limit: usize,
 }
 
 impl Context {
-    /// create a new distance context
-    pub fn new(s: Symbol, r: Rank, dmax: usize) -> Context {
+    pub(crate) fn new(s: Symbol, r: Rank, dmax: usize) -> Context {
         Context {
             symbol: s,
             last_rank: r,
             distance_limit: dmax,
         }
     }
+
+    /// current symbol
+    pub fn symbol(&self) -> Symbol {
+        self.symbol
+    }
+
+    /// last known MTF rank
+    pub fn last_rank(&self) -> Rank {
+        self.last_rank
+    }
+
+    /// maximum possible distance
+    pub fn distance_limit(&self) -> usize {
+        self.distance_limit
+    }
 }
 
 
@@ -96,7 +130,7 @@ impl<'a, 'b, D> Iterator for EncodeIterator<'a,'b,D>
             assert!(rank < TOTAL_SYMBOLS);
             self.last_active = i+1;
             self.pos[*sym as usize] = i + 1 + d.to_usize().unwrap();
-            debug!("Encoding distance {} at pos {} for symbol {}, computed rank {}, predicting next at {}",
+            trace!(target: "compress::bwt::dc", "Encoding distance {} at pos {} for symbol {}, computed rank {}, predicting next at {}",
                 d.to_usize().unwrap(), i, *sym, rank, self.pos[*sym as usize]);
             (d.clone(), Context::new(*sym, rank as Rank, self.size-i))
         })
@@ -118,19 +152,19 @@ pub fn encode<'a, 'b, D: Clone + Copy + Eq + NumCast>(input: &'a [Symbol], dista
         distances[i] = filler.clone();
         let base = last[sym as usize];
         last[sym as usize] = i;
-        debug!("\tProcessing symbol {} at position {}, last known at {}", sym, i, base);
+        trace!(target: "compress::bwt::dc", "\tProcessing symbol {} at position {}, last known at {}", sym, i, base);
         if base == n {
             let rank = num_unique;
             mtf.symbols[rank] = sym;
             mtf.encode(sym);    //==rank
             // initial distances are not ordered to support re-shuffle
-            debug!("\t\tUnique => assigning rank {}, encoding {}", rank, i);
+            trace!(target: "compress::bwt::dc", "\t\tUnique => assigning rank {}, encoding {}", rank, i);
             init[sym as usize] = i;
             num_unique += 1;
         }else {
             let rank = mtf.encode(sym) as usize;
             if rank > 0 {
-                debug!("\t\tRegular at rank {}, encoding {}", rank, i-base-rank-1);
+                trace!(target: "compress::bwt::dc", "\t\tRegular at rank {}, encoding {}", rank, i-base-rank-1);
                 assert!(i >= base+rank+1);
                 distances[base] = NumCast::from(i-base-rank-1).unwrap();
             }
@@ -138,7 +172,7 @@ pub fn encode<'a, 'b, D: Clone + Copy + Eq + NumCast>(input: &'a [Symbol], dista
     }
     for (rank,&sym) in mtf.symbols[..num_unique].iter().enumerate() {
         let base = last[sym as usize];
-        debug!("\tSweep symbol {} of rank {}, last known at {}, encoding {}", sym, rank, base, n-base-rank-1);
+        trace!(target: "compress::bwt::dc", "\tSweep symbol {} of rank {}, last known at {}, encoding {}", sym, rank, base, n-base-rank-1);
         assert!(n >= base+rank+1);
         distances[base] = NumCast::from(n-base-rank-1).unwrap();
     }
@@ -190,7 +224,7 @@ pub fn decode<F>(mut next: [usize; TOTAL_SYMBOLS], output: &mut [Symbol], mtf: &
     let mut ranks = [0 as Rank; TOTAL_SYMBOLS];
     for rank in 0..i {
         let sym = mtf.symbols[rank];
-        debug!("\tRegistering symbol {} of rank {} at position {}",
+        trace!(target: "compress::bwt::dc", "\tRegistering symbol {} of rank {} at position {}",
             sym, rank, next[sym as usize]);
         ranks[sym as usize] = 0; //could use 'rank' but don't know how to derive it during encoding
     }
@@ -199,7 +233,10 @@ pub fn decode<F>(mut next: [usize; TOTAL_SYMBOLS], output: &mut [Symbol], mtf: &
     while i<n {
         let sym = mtf.symbols[0];
         let stop = next[mtf.symbols[1] as usize];
-        debug!("\tFilling region [{}-{}) with symbol {}", i, stop, sym);
+        if stop > n {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "DC symbol position out of range"))
+        }
+        trace!(target: "compress::bwt::dc", "\tFilling region [{}-{}) with symbol {}", i, stop, sym);
         while i<stop    {
             output[i] = sym;
             i += 1;
@@ -209,25 +246,29 @@ pub fn decode<F>(mut next: [usize; TOTAL_SYMBOLS], output: &mut [Symbol], mtf: &
             Ok(d) => stop + d,
             Err(e) => return Err(e)
         };
-        debug!("\t\tLooking for future position {}", future);
-        assert!(future <= n);
+        trace!(target: "compress::bwt::dc", "\t\tLooking for future position {}", future);
+        if future > n {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "DC distance out of range"))
+        }
         let mut rank = 1;
         while rank < alphabet_size && future+rank > next[mtf.symbols[rank] as usize] {
             mtf.symbols[rank-1] = mtf.symbols[rank];
             rank += 1;
         }
         if rank < alphabet_size {
-            debug!("\t\tFound sym {} of rank {} at position {}", mtf.symbols[rank],
+            trace!(target: "compress::bwt::dc", "\t\tFound sym {} of rank {} at position {}", mtf.symbols[rank],
                 rank, next[mtf.symbols[rank] as usize]);
         }else {
-            debug!("\t\tNot found");
+            trace!(target: "compress::bwt::dc", "\t\tNot found");
         }
         mtf.symbols[rank-1] = sym;
-        debug!("\t\tAssigning future pos {} for symbol {}", future+rank-1, sym);
+        trace!(target: "compress::bwt::dc", "\t\tAssigning future pos {} for symbol {}", future+rank-1, sym);
         next[sym as usize] = future+rank-1;
         ranks[sym as usize] = (rank-1) as Rank;
     }
-    assert_eq!(next.iter().position(|&d| d<n || d>=n+alphabet_size), None);
+    if next.iter().any(|&d| d<n || d>=n+alphabet_size) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "DC distances don't cover the block"))
+    }
     assert_eq!(i, n);
     Ok(())
 }
@@ -252,14 +293,176 @@ pub fn decode_simple<D: ToPrimitive>(n: usize, distances: &[D]) -> Vec<Symbol> {
 }
 
 
+/// A DC stream encoder, transforming blocks of BWT output
+pub struct Encoder<W> {
+    w: W,
+    buf: Vec<Symbol>,
+    distances: Vec<u32>,
+    block_size: usize,
+}
+
+impl<W: Write> Encoder<W> {
+    /// start encoding into the given writer, in blocks of 'block_size'
+    /// symbols, which can't be zero or exceed `bwt::MAX_BLOCK_SIZE`.
+    /// Transformed blocks should match the blocks of the BWT stage. Other
+    /// block sizes are an error of kind `InvalidInput`.
+    pub fn new(w: W, block_size: usize) -> io::Result<Encoder<W>> {
+        if block_size == 0 || block_size > super::MAX_BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("DC block size must be within 1 and {} bytes", super::MAX_BLOCK_SIZE)))
+        }
+        Ok(Encoder {
+            w,
+            buf: Vec::new(),
+            distances: Vec::new(),
+            block_size,
+        })
+    }
+
+    fn encode_block(&mut self) -> io::Result<()> {
+        let n = self.buf.len();
+        self.distances.truncate(0);
+        self.distances.extend((0..n).map(|_| 0));
+        let mut block = Vec::new();
+        {
+            let mut eniter = encode(&self.buf[..], &mut self.distances[..], &mut MTF::new());
+            let present: Vec<(usize, usize)> = eniter.get_init().iter().cloned()
+                .enumerate().filter(|&(_, pos)| pos < n).collect();
+            try!(block.write_u32::<LittleEndian>(n as u32));
+            try!(block.write_u16::<LittleEndian>(present.len() as u16));
+            for &(sym, pos) in present.iter() {
+                try!(block.write_u8(sym as Symbol));
+                try!(block.write_u32::<LittleEndian>(pos as u32));
+            }
+            let distances: Vec<u32> = eniter.by_ref().map(|(d, _)| d).collect();
+            try!(block.write_u32::<LittleEndian>(distances.len() as u32));
+            for &d in distances.iter() {
+                try!(block.write_u32::<LittleEndian>(d));
+            }
+        }
+        self.buf.truncate(0);
+        self.w.write_all(&block)
+    }
+
+    /// finish encoding and return the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.flush();
+        (self.w, result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while !buf.is_empty() {
+            let amt = cmp::min(self.block_size - self.buf.len(), buf.len());
+            self.buf.extend_from_slice(&buf[..amt]);
+            if self.buf.len() == self.block_size {
+                try!(self.encode_block());
+            }
+            buf = &buf[amt..];
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let ret = if !self.buf.is_empty() {
+            self.encode_block()
+        } else {
+            Ok(())
+        };
+        ret.and(self.w.flush())
+    }
+}
+
+
+/// A DC stream decoder
+pub struct Decoder<R> {
+    r: R,
+    output: Vec<Symbol>,
+    start: usize,
+}
+
+impl<R: Read> Decoder<R> {
+    /// start decoding the given reader
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            r,
+            output: Vec::new(),
+            start: 0,
+        }
+    }
+
+    /// finish decoding and return the wrapped reader
+    pub fn finish(self) -> R {
+        self.r
+    }
+
+    // returns false at the end of the stream
+    fn decode_block(&mut self) -> io::Result<bool> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let n = match self.r.read_u32::<LittleEndian>() {
+            Ok(n) => n as usize,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if n == 0 || n > super::MAX_BLOCK_SIZE {
+            return Err(invalid("invalid DC block size"))
+        }
+        let mut init = [n; TOTAL_SYMBOLS];
+        let present = try!(self.r.read_u16::<LittleEndian>().map_err(byteorder_err_to_io)) as usize;
+        if present == 0 || present > TOTAL_SYMBOLS {
+            return Err(invalid("invalid DC alphabet size"))
+        }
+        for _ in 0..present {
+            let sym = try!(self.r.read_u8().map_err(byteorder_err_to_io)) as usize;
+            let pos = try!(self.r.read_u32::<LittleEndian>().map_err(byteorder_err_to_io)) as usize;
+            if pos >= n || init[sym] != n {
+                return Err(invalid("invalid DC symbol position"))
+            }
+            init[sym] = pos;
+        }
+        let count = try!(self.r.read_u32::<LittleEndian>().map_err(byteorder_err_to_io)) as usize;
+        if count > n {
+            return Err(invalid("too many DC distances"))
+        }
+        let mut distances = Vec::with_capacity(count);
+        for _ in 0..count {
+            distances.push(try!(self.r.read_u32::<LittleEndian>().map_err(byteorder_err_to_io)) as usize);
+        }
+
+        self.output.truncate(0);
+        self.output.extend((0..n).map(|_| 0));
+        self.start = 0;
+        let mut di = distances.iter();
+        try!(decode(init, &mut self.output[..], &mut MTF::new(), |_ctx| {
+            di.next().cloned().ok_or_else(|| invalid("missing DC distances"))
+        }));
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.output.len() && !try!(self.decode_block()) {
+            return Ok(0)
+        }
+        let amt = cmp::min(self.output.len() - self.start, dst.len());
+        dst[..amt].copy_from_slice(&self.output[self.start..self.start + amt]);
+        self.start += amt;
+        Ok(amt)
+    }
+}
+
+
 #[cfg(test)]
 mod test {
     use std::iter::repeat;
 
     fn roundtrip(bytes: &[u8]) {
-        info!("Roundtrip DC of size {}", bytes.len());
+        info!(target: "compress::bwt::dc", "Roundtrip DC of size {}", bytes.len());
         let distances = super::encode_simple::<usize>(bytes);
-        debug!("Roundtrip DC input: {:?}, distances: {:?}", bytes, distances);
+        debug!(target: "compress::bwt::dc", "Roundtrip DC input: {:?}, distances: {:?}", bytes, distances);
         let decoded = super::decode_simple(bytes.len(), &distances[..]);
         assert_eq!(&decoded[..], bytes);
     }
@@ -267,7 +470,7 @@ mod test {
     /// rountrip version that compares the coding contexts on the way
     fn roundtrip_ctx(bytes: &[u8]) {
         let n = bytes.len();
-        info!("Roundtrip DC context of size {}", n);
+        info!(target: "compress::bwt::dc", "Roundtrip DC context of size {}", n);
         let mut mtf = super::super::mtf::MTF::new();
         let mut raw_dist: Vec<u16> = repeat(0).take(n).collect();
         let eniter = super::encode(bytes, &mut raw_dist[..], &mut mtf);
@@ -295,6 +498,47 @@ mod test {
         roundtrip(include_bytes!("../data/test.txt"));
     }
 
+    #[test]
+    fn streams() {
+        use std::io::{Read, Write};
+        use super::{Decoder, Encoder};
+        let input = include_bytes!("../data/test.txt");
+        for &block_size in [1, 7, 1000, 1 << 20].iter() {
+            let mut e = Encoder::new(Vec::new(), block_size).unwrap();
+            e.write_all(input).unwrap();
+            let (encoded, result) = e.finish();
+            result.unwrap();
+            let mut decoded = Vec::new();
+            Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], &input[..]);
+        }
+
+        // a single block carries the distances of `encode_simple`
+        let bytes = b"teeesst_dc";
+        let mut e = Encoder::new(Vec::new(), 100).unwrap();
+        e.write_all(bytes).unwrap();
+        let encoded = e.finish().0;
+        let distances = super::encode_simple::<u32>(bytes);
+        let tail: Vec<u32> = encoded[encoded.len() - 4 * (distances.len() - 256)..]
+            .chunks(4).map(|c| c[0] as u32 | (c[1] as u32) << 8 | (c[2] as u32) << 16 | (c[3] as u32) << 24)
+            .collect();
+        assert_eq!(&tail[..], &distances[256..]);
+
+        // corrupt distances are reported, not trusted
+        let mut bad = encoded.clone();
+        let at = bad.len() - 4;
+        bad[at] = 0xff;
+        assert!(Decoder::new(&bad[..]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn invalid_block_size() {
+        for &size in [0, super::super::MAX_BLOCK_SIZE + 1].iter() {
+            let err = super::Encoder::new(Vec::new(), size).err().unwrap();
+            assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidInput);
+        }
+    }
+
     #[test]
     fn roundtrips_context() {
         roundtrip_ctx(b"teeesst_dc");
@@ -2,8 +2,30 @@
 
 BWT (Burrows-Wheeler Transform) forward and backward transformation. Requires `bwt` feature, enabled by default
 
-This module contains a bruteforce implementation of BWT encoding in Rust as well as standard decoding.
+This module contains a linear time implementation of BWT encoding in Rust, based on the SA-IS suffix
+array construction, as well as standard decoding.
 These are exposed as a standard `Reader` and `Writer` interfaces wrapping an underlying stream.
+`Encoder` and `Decoder` are thin adapters around `EncoderCore` and `DecoderCore`, which do the work
+on slices without any I/O, see the `io` module.
+
+The stream starts with the `MAGIC` bytes and the block size as a u32 little endian. Input is cut into
+blocks of that size, the last one possibly shorter, and every block is transformed on its own and
+written as its length, the transformed bytes and the index of the original string among the sorted
+rotations (the primary index), all lengths u32 little endian. Blocks are limited to `MAX_BLOCK_SIZE`
+bytes. Empty input encodes to the header alone, and a stream missing even that is reported as
+`UnexpectedEof`.
+
+Streams written by earlier versions of this crate lack the magic bytes and start right away with the
+block size. `Decoder::legacy` reads those as well as current streams, and `is_legacy` recognizes them.
+
+Large blocks spend much of their time on TLB misses in the suffix array. `Encoder::set_huge_pages` and
+`Decoder::set_huge_pages` align the block buffers, the suffix array and the buffers used to sort it to
+2 MiB so they can be backed by huge pages, see the `alloc` module for when the kernel does that. Any other allocator can be
+plugged in with `set_allocator`.
+
+Blocks are transformed independently, so `Encoder::threads` lets the encoder work on several of them at
+once, for instance `try!(bwt::Encoder::new(w, 4 << 20)).threads(4)`. The stream is the same whatever the
+number of threads.
 
 BWT output stream places together symbols with similar leading contexts. This reshaping of the entropy
 allows further stages to deal with repeated sequences of symbols for better compression.
@@ -29,7 +51,7 @@ use compress::bwt;
 
 // Encode some text
 let text = "some text";
-let mut e = bwt::Encoder::new(BufWriter::new(Vec::new()), 4 << 20);
+let mut e = bwt::Encoder::new(BufWriter::new(Vec::new()), 4 << 20).unwrap();
 e.write(text.as_bytes()).unwrap();
 let (encoded, _) = e.finish();
 let inner = encoded.into_inner().unwrap();
@@ -44,7 +66,8 @@ assert_eq!(&decoded[..], text.as_bytes());
 
 # Credit
 
-This is an original (mostly trivial) implementation.
+This is an original implementation, the suffix array construction follows SA-IS by
+Nong, Zhang and Chan.
 
 */
 
@@ -52,24 +75,55 @@ This is an original (mostly trivial) implementation.
 
 extern crate num;
 
-use std::{cmp, fmt, slice};
-use std::ptr;
-use std::iter::{self, Extend, repeat};
+use std::{cmp, fmt, slice, thread};
+use std::iter::{self, repeat};
 use std::io::{self, Read, Write};
 use self::num::traits::{NumCast, ToPrimitive};
 
-use super::byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
-use super::{byteorder_err_to_io, ReadExact};
+use super::alloc::{Allocator, Buffer};
+use super::byteorder::{LittleEndian, WriteBytesExt};
+use codecs::{Codec, Finish};
+use io::{Core, CoreReader, CoreWriter, Status};
+use metrics;
 
 pub mod dc;
 pub mod mtf;
+pub mod preset;
+pub mod rle0;
+#[cfg(feature="entropy")]
+pub mod zrle;
 
 /// A base element for the transformation
 pub type Symbol = u8;
 
 pub const ALPHABET_SIZE: usize = 0x100;
 
+/// The largest block size accepted by `Encoder` and `Decoder`
+pub const MAX_BLOCK_SIZE: usize = 64 << 20;
+
+/// The bytes a stream starts with. Read as a block size they exceed
+/// `MAX_BLOCK_SIZE`, so they can't be mistaken for the start of a legacy
+/// stream.
+pub const MAGIC: [u8; 4] = *b"rBWT";
+
+/// Returns true if `prefix`, the first bytes of a stream, looks like the
+/// headerless layout of earlier versions: a valid block size followed by a
+/// block no longer than it. At least 8 bytes are needed to tell.
+pub fn is_legacy(prefix: &[u8]) -> bool {
+    if prefix.len() < 8 {
+        return false
+    }
+    let word = |i: usize| u32::from_le_bytes([prefix[i], prefix[i+1], prefix[i+2], prefix[i+3]]) as usize;
+    let (size, first) = (word(0), word(4));
+    size > 0 && size <= MAX_BLOCK_SIZE && first <= size
+}
+
+fn invalid_data<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
 /// Radix sorting primitive
+#[doc(hidden)]
 pub struct Radix    {
     /// number of occurancies (frequency) per symbox
     pub freq    : [usize; ALPHABET_SIZE+1],
@@ -130,39 +184,167 @@ impl Radix  {
 }
 
 
-/// Compute a suffix array from a given input string
-/// Resulting suffixes are guaranteed to be alphabetically sorted
-/// Run time: O(N^3), memory: N words (suf_array) + ALPHABET_SIZE words (Radix)
-pub fn compute_suffixes<SUF: NumCast + ToPrimitive + fmt::Debug>(input: &[Symbol], suf_array: &mut [SUF]) {
-    let mut radix = Radix::new();
-    radix.gather(input);
-    radix.accumulate();
+const EMPTY: usize = usize::MAX;
+
+/// Compute the suffix array of a given input string with the SA-IS algorithm
+/// (Nong, Zhang and Chan, "Two Efficient Algorithms for Linear Time Suffix
+/// Array Construction"). A suffix sorts before all the longer suffixes it is
+/// a prefix of.
+/// Run time: O(N), memory: about 4 to 5 words per input symbol at the peak,
+/// the N words of the suffix array included. The sort works on a copy of
+/// the input widened to words, and names the LMS substrings in another N
+/// words while their positions are gathered, along with a byte per symbol
+/// for the suffix types.
+pub fn suffix_array(input: &[Symbol]) -> Vec<usize> {
+    let mut sa = vec![EMPTY; input.len()];
+    sort_suffixes(input, &mut sa, Allocator::global());
+    sa
+}
+
+// Computes the suffix array of `input` into `sa`, with the temporary
+// buffers coming from `alloc`
+fn sort_suffixes(input: &[Symbol], sa: &mut [usize], alloc: Allocator) {
+    let mut text = Buffer::new(alloc);
+    text.reserve(input.len());
+    for &b in input.iter() {
+        text.push(b as usize);
+    }
+    sais(&text, ALPHABET_SIZE, sa, alloc);
+}
+
+fn filled<T: Copy>(len: usize, value: T, alloc: Allocator) -> Buffer<T> {
+    let mut buffer = Buffer::new(alloc);
+    buffer.resize(len, value);
+    buffer
+}
 
-    debug!("SA compute input: {:?}", input);
-    debug!("radix offsets: {:?}", &radix.freq[..]);
+// Start (or end) offsets of every symbol bucket in the suffix array
+fn buckets(text: &[usize], k: usize, ends: bool, alloc: Allocator) -> Buffer<usize> {
+    let mut bucket = filled(k, 0, alloc);
+    for &c in text.iter() {
+        bucket[c] += 1;
+    }
+    let mut sum = 0;
+    for b in bucket.iter_mut() {
+        sum += *b;
+        *b = if ends { sum } else { sum - *b };
+    }
+    bucket
+}
 
-    for (i,&ch) in input.iter().enumerate() {
-        let p = radix.place(ch);
-        suf_array[p] = NumCast::from(i).unwrap();
+// Sorts the L-type and then the S-type suffixes, given the LMS suffixes
+// placed at the ends of their buckets
+fn induce(text: &[usize], k: usize, stype: &[bool], sa: &mut [usize], alloc: Allocator) {
+    let n = text.len();
+    let mut heads = buckets(text, k, false, alloc);
+    // the suffix before the virtual sentinel is always L-type and comes first
+    sa[heads[text[n - 1]]] = n - 1;
+    heads[text[n - 1]] += 1;
+    for i in 0..n {
+        let j = sa[i];
+        if j != EMPTY && j > 0 && !stype[j - 1] {
+            sa[heads[text[j - 1]]] = j - 1;
+            heads[text[j - 1]] += 1;
+        }
     }
+    let mut tails = buckets(text, k, true, alloc);
+    for i in (0..n).rev() {
+        let j = sa[i];
+        if j != EMPTY && j > 0 && stype[j - 1] {
+            tails[text[j - 1]] -= 1;
+            sa[tails[text[j - 1]]] = j - 1;
+        }
+    }
+}
 
-    // bring the original offsets back
-    radix.shift();
+fn sais(text: &[usize], k: usize, sa: &mut [usize], alloc: Allocator) {
+    let n = text.len();
+    if n == 0 {
+        return
+    }
+    // classify the suffixes, the last one is L-type against the sentinel
+    let mut stype = filled(n, false, alloc);
+    for i in (0..n - 1).rev() {
+        stype[i] = text[i] < text[i + 1] || (text[i] == text[i + 1] && stype[i + 1]);
+    }
+    let stype = &stype[..];
+    let is_lms = |i: usize| i > 0 && i < n && stype[i] && !stype[i - 1];
 
-    for i in 0..ALPHABET_SIZE {
-        let lo = radix.freq[i];
-        let hi = radix.freq[i+1];
-        if lo == hi {
-            continue;
+    // sort the LMS substrings by inducing from their unsorted positions
+    let mut tails = buckets(text, k, true, alloc);
+    for x in sa.iter_mut() {
+        *x = EMPTY;
+    }
+    for i in (1..n).rev().filter(|&i| is_lms(i)) {
+        tails[text[i]] -= 1;
+        sa[tails[text[i]]] = i;
+    }
+    drop(tails);
+    induce(text, k, stype, sa, alloc);
+
+    // name the LMS substrings in sorted order, equal ones get the same name
+    let mut names = filled(n, EMPTY, alloc);
+    let mut name = 0;
+    let mut prev = EMPTY;
+    for &pos in sa.iter().filter(|&&i| is_lms(i)) {
+        let mut same = prev != EMPTY;
+        let mut d = 0;
+        while same {
+            if pos + d == n || prev + d == n ||
+                    text[pos + d] != text[prev + d] || stype[pos + d] != stype[prev + d] {
+                same = false;
+            } else if d > 0 && (is_lms(pos + d) || is_lms(prev + d)) {
+                break
+            }
+            d += 1;
+        }
+        if !same {
+            name += 1;
+        }
+        names[pos] = name - 1;
+        prev = pos;
+    }
+
+    // sort the LMS suffixes, recursing when names are not unique yet
+    let mut positions = Buffer::new(alloc);
+    let mut reduced = Buffer::new(alloc);
+    for i in (1..n).filter(|&i| is_lms(i)) {
+        positions.push(i);
+        reduced.push(names[i]);
+    }
+    drop(names);
+    let mut reduced_sa = filled(reduced.len(), EMPTY, alloc);
+    if name < reduced.len() {
+        sais(&reduced, name, &mut reduced_sa, alloc);
+    } else {
+        for (i, &c) in reduced.iter().enumerate() {
+            reduced_sa[c] = i;
         }
-        let slice = &mut suf_array[lo..hi];
-        debug!("\tsorting group [{}-{}) for symbol {}", lo, hi, i);
-        slice.sort_by(|a,b| {
-            input[(a.to_usize().unwrap())..].cmp(&input[(b.to_usize().unwrap())..])
-        });
     }
+    drop(reduced);
 
-    debug!("sorted SA: {:?}", suf_array);
+    // induce the whole suffix array from the sorted LMS suffixes
+    let mut tails = buckets(text, k, true, alloc);
+    for x in sa.iter_mut() {
+        *x = EMPTY;
+    }
+    for &r in reduced_sa.iter().rev() {
+        let i = positions[r];
+        tails[text[i]] -= 1;
+        sa[tails[text[i]]] = i;
+    }
+    drop(tails);
+    induce(text, k, stype, sa, alloc);
+}
+
+// Compute a suffix array from a given input string into 'suf_array'
+// Resulting suffixes are guaranteed to be alphabetically sorted
+fn compute_suffixes<SUF: NumCast + ToPrimitive + fmt::Debug>(input: &[Symbol], suf_array: &mut [SUF]) {
+    debug!(target: "compress::bwt", "SA compute input: {:?}", input);
+    for (suf, i) in suf_array.iter_mut().zip(suffix_array(input)) {
+        *suf = NumCast::from(i).unwrap();
+    }
+    debug!(target: "compress::bwt", "sorted SA: {:?}", suf_array);
 }
 
 /// An iterator over BWT output
@@ -235,7 +417,7 @@ pub fn compute_inversion_table<SUF: NumCast + fmt::Debug>(input: &[Symbol], orig
         table[radix.place(ch)] = NumCast::from(origin+2+i).unwrap();
     }
     //table[-1] = origin;
-    debug!("inverse table: {:?}", table)
+    debug!(target: "compress::bwt", "inverse table: {:?}", table)
 }
 
 /// An iterator over inverse BWT
@@ -250,7 +432,7 @@ pub struct InverseIterator<'a, SUF: 'a> {
 impl<'a, SUF> InverseIterator<'a, SUF> {
     /// create a new inverse BWT iterator with a given input, origin, and a jump table
     pub fn new(input: &'a [Symbol], origin: usize, table: &'a [SUF]) -> InverseIterator<'a, SUF> {
-        debug!("inverse origin={:?}, input: {:?}", origin, input);
+        debug!(target: "compress::bwt", "inverse origin={:?}, input: {:?}", origin, input);
         InverseIterator {
             input: input,
             table: table,
@@ -268,7 +450,7 @@ impl<'a, SUF: ToPrimitive> Iterator for InverseIterator<'a, SUF> {
             None
         } else {
             self.current = self.table[self.current].to_usize().unwrap().wrapping_sub(1);
-            debug!("\tjumped to {}", self.current);
+            trace!(target: "compress::bwt", "\tjumped to {}", self.current);
 
             let p = if self.current != usize::max_value() {
                 self.current
@@ -305,228 +487,576 @@ fn decode_minimal(input: &[Symbol], origin: usize, output: &mut [Symbol]) {
     radix.gather(input);
     radix.accumulate();
 
+    // Walks the rows of the sorted suffixes backwards through the text. Row
+    // 0 is the empty suffix, which sorts first and which the encoder leaves
+    // out; it ends with the last symbol, stored at the origin instead of the
+    // start of the text. The other rows are shifted down by one.
     let n = input.len();
-    (0..n).fold(origin, |i,j| {
-        let ch = input[i];
+    (0..n).fold(0, |row,j| {
+        let ch = if row == 0 { input[origin] } else { input[row - 1] };
         output[n-j-1] = ch;
-        let offset = &input[..i].iter().filter(|&k| *k==ch).count();
-        radix.freq[ch as usize] + offset
+        let offset = match row {
+            0 => 0,
+            _ => input[..row - 1].iter().filter(|&k| *k==ch).count() +
+                 (ch == input[origin] && origin >= row - 1) as usize,
+        };
+        1 + radix.freq[ch as usize] + offset
     });
 }
 
 
-/// This structure is used to decode a stream of BWT blocks. This wraps an
-/// internal reader which is read from when this decoder's read method is
-/// called.
-pub struct Decoder<R> {
-    /// The internally wrapped reader. This is exposed so it may be moved out
-    /// of. Note that if data is read from the reader while decoding is in
-    /// progress the output stream will get corrupted.
-    pub r: R,
-    start  : usize,
+// What the decoder core gathers the bytes of next
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Magic,
+    BlockSize,
+    Length,
+    // a block of this length, followed by its primary index
+    Block(usize),
+}
 
-    temp   : Vec<u8>,
-    output : Vec<u8>,
-    table  : Vec<usize>,
+/// The I/O free core of `Decoder`, see `io::Core`
+pub struct DecoderCore {
+    step   : Step,
+    // the bytes of the current step gathered so far
+    temp   : Buffer<u8>,
+    output : Buffer<u8>,
+    start  : usize,
+    table  : Buffer<usize>,
 
-    header         : bool,
     max_block_size : usize,
     extra_memory   : bool,
+    legacy         : bool,
+    ended          : bool,
 }
 
-impl<R: Read> Decoder<R> {
-    /// Creates a new decoder which will read data from the given stream. The
-    /// inner stream can be re-acquired by moving out of the `r` field of this
-    /// structure.
-    /// 'extra_mem' switch allows allocating extra N words of memory for better performance
-    pub fn new(r: R, extra_mem: bool) -> Decoder<R> {
-        Decoder {
-            r: r,
+impl DecoderCore {
+    /// Creates a core decoding like `Decoder::new`
+    pub fn new(extra_mem: bool) -> DecoderCore {
+        DecoderCore {
+            step: Step::Magic,
+            temp: Buffer::default(),
+            output: Buffer::default(),
             start: 0,
-            temp: Vec::new(),
-            output: Vec::new(),
-            table: Vec::new(),
-            header: false,
+            table: Buffer::default(),
             max_block_size: 0,
             extra_memory: extra_mem,
+            legacy: false,
+            ended: false,
         }
     }
 
-    /// Resets this decoder back to its initial state. Note that the underlying
-    /// stream is not seeked on or has any alterations performed on it.
+    /// Creates a core decoding like `Decoder::legacy`
+    pub fn legacy(extra_mem: bool) -> DecoderCore {
+        DecoderCore {
+            legacy: true,
+            .. DecoderCore::new(extra_mem)
+        }
+    }
+
+    /// Makes the block buffers and the decoding table come from `alloc`,
+    /// the block being gathered and the decoded block of the moment are kept
+    pub fn set_allocator(&mut self, alloc: Allocator) {
+        let mut temp = Buffer::new(alloc);
+        temp.extend_from_slice(&self.temp);
+        self.temp = temp;
+        self.table = Buffer::new(alloc);
+        let mut output = Buffer::new(alloc);
+        output.extend_from_slice(&self.output);
+        self.output = output;
+    }
+
+    /// Goes back to the start of a stream, dropping the decoded data not
+    /// taken out yet
     pub fn reset(&mut self) {
-        self.header = false;
+        self.step = Step::Magic;
+        self.temp.clear();
+        self.output.clear();
         self.start = 0;
+        self.ended = false;
     }
 
-    fn read_header(&mut self) -> io::Result<()> {
-        match self.r.read_u32::<LittleEndian>() {
-            Ok(size) => {
-                self.max_block_size = size as usize;
-                debug!("max size: {}", self.max_block_size);
-                Ok(())
-            },
-            Err(e) => Err(byteorder_err_to_io(e)),
+    // the bytes the current step needs in all
+    fn wanted(&self) -> usize {
+        match self.step {
+            Step::Block(n) => n + 4,
+            _ => 4,
         }
     }
 
-    fn decode_block(&mut self) -> io::Result<bool> {
-        let n = match self.r.read_u32::<LittleEndian>() {
-            Ok(n) => n as usize,
-            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false), // EOF
-            Err(e) => return Err(e),
+    // Acts on the bytes of the current step, all gathered in `temp`
+    fn step(&mut self) -> io::Result<()> {
+        let word = |at: usize, temp: &[u8]| {
+            u32::from_le_bytes([temp[at], temp[at + 1], temp[at + 2], temp[at + 3]]) as usize
         };
+        match self.step {
+            Step::Magic if self.temp[..] == MAGIC[..] => self.step = Step::BlockSize,
+            Step::Magic if !self.legacy => return invalid_data("not a BWT stream"),
+            Step::Magic | Step::BlockSize => {
+                let size = word(0, &self.temp);
+                debug!(target: "compress::bwt", "max size: {}", size);
+                if size == 0 || size > MAX_BLOCK_SIZE {
+                    return invalid_data("invalid BWT block size")
+                }
+                self.max_block_size = size;
+                self.step = Step::Length;
+            }
+            Step::Length => {
+                let n = word(0, &self.temp);
+                if n > self.max_block_size {
+                    return invalid_data("BWT block larger than the block size")
+                }
+                // the encoder never writes an empty block, there would be no
+                // primary index to point at
+                if n == 0 {
+                    return invalid_data("empty BWT block")
+                }
+                self.step = Step::Block(n);
+            }
+            Step::Block(n) => {
+                let origin = word(n, &self.temp);
+                if origin >= n {
+                    return invalid_data("BWT primary index out of the block")
+                }
+                self.decode_block(n, origin);
+                self.step = Step::Length;
+            }
+        }
+        self.temp.clear();
+        Ok(())
+    }
 
-        self.temp.truncate(0);
-        self.temp.reserve(n);
-        try!(self.r.push_exactly(n as u64, &mut self.temp));
-
-        let origin = try!(self.r.read_u32::<LittleEndian>()) as usize;
-        self.output.truncate(0);
-        self.output.reserve(n);
+    fn decode_block(&mut self, n: usize, origin: usize) {
+        self.output.clear();
+        self.output.resize(n, 0);
 
         if self.extra_memory    {
-            self.table.truncate(0);
-            self.table.extend((0..n).map(|_| 0));
-            for ch in decode(&self.temp[..], origin, &mut self.table[..]) {
-                self.output.push(ch);
+            self.table.clear();
+            self.table.resize(n, 0);
+            for (out, ch) in self.output.iter_mut().zip(decode(&self.temp[..n], origin, &mut self.table[..])) {
+                *out = ch;
             }
         }else   {
-            self.output.extend((0..n).map(|_| 0));
-            decode_minimal(&self.temp[..], origin, &mut self.output[..]);
+            decode_minimal(&self.temp[..n], origin, &mut self.output[..]);
         }
 
         self.start = 0;
-        return Ok(true);
+    }
+
+    // drops up to `n` bytes of the decoded block without copying them
+    fn skip_output(&mut self, n: u64) -> u64 {
+        let k = cmp::min(n, (self.output.len() - self.start) as u64);
+        self.start += k as usize;
+        k
     }
 }
 
-impl<R: Read> Read for Decoder<R> {
-    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
-        if !self.header {
-            try!(self.read_header());
-            self.header = true;
-        }
-        let mut amt = dst.len();
-        let dst_len = amt;
-
-        while amt > 0 {
-            if self.output.len() == self.start {
-                let keep_going = try!(self.decode_block());
-                if !keep_going {
-                   break
+impl Core for DecoderCore {
+    fn process(&mut self, input: &[u8], output: &mut [u8], finish: bool)
+               -> io::Result<(usize, usize, Status)> {
+        let (mut used, mut produced) = (0, 0);
+        loop {
+            let n = cmp::min(self.output.len() - self.start, output.len() - produced);
+            output[produced..produced + n].copy_from_slice(&self.output[self.start..self.start + n]);
+            self.start += n;
+            produced += n;
+            if self.start < self.output.len() {
+                break
+            }
+            if used == input.len() {
+                if finish && !self.ended {
+                    // the stream may only end between blocks
+                    if self.step != Step::Length || !self.temp.is_empty() {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                  "truncated BWT stream"))
+                    }
+                    self.ended = true;
                 }
+                break
+            }
+            let amt = cmp::min(self.wanted() - self.temp.len(), input.len() - used);
+            self.temp.extend_from_slice(&input[used..used + amt]);
+            used += amt;
+            if self.temp.len() == self.wanted() {
+                try!(self.step());
+            }
+        }
+        let status = if self.ended { Status::StreamEnd } else { Status::Ok };
+        Ok((used, produced, status))
+    }
+}
+
+/// This structure is used to decode a stream of BWT blocks. This wraps an
+/// internal reader which is read from when this decoder's read method is
+/// called.
+pub struct Decoder<R> {
+    inner: CoreReader<R, DecoderCore>,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a new decoder which will read data from the given stream. The
+    /// inner stream can be re-acquired with `finish`.
+    /// 'extra_mem' switch allows allocating extra N words of memory for better performance
+    pub fn new(r: R, extra_mem: bool) -> Decoder<R> {
+        Decoder { inner: CoreReader::new(r, DecoderCore::new(extra_mem)) }
+    }
+
+    /// Creates a decoder which also accepts the headerless streams written
+    /// by earlier versions of this crate
+    pub fn legacy(r: R, extra_mem: bool) -> Decoder<R> {
+        Decoder { inner: CoreReader::new(r, DecoderCore::legacy(extra_mem)) }
+    }
+
+    /// Makes the block buffers and the decoding table come from `alloc`,
+    /// the decoded block of the moment is kept
+    pub fn set_allocator(&mut self, alloc: Allocator) {
+        self.inner.core_mut().set_allocator(alloc);
+    }
+
+    /// Aligns the block buffers and the decoding table to huge pages, or
+    /// goes back to the global allocator
+    pub fn set_huge_pages(&mut self, enabled: bool) {
+        self.set_allocator(if enabled { Allocator::huge_pages() } else { Allocator::global() });
+    }
+
+    /// Resets this decoder back to its initial state. Note that the underlying
+    /// stream is not seeked on or has any alterations performed on it.
+    pub fn reset(&mut self) {
+        self.inner.core_mut().reset();
+    }
+
+    /// Destroys this decoder, returning the wrapped reader. Input is read
+    /// ahead, so it may be positioned anywhere after what was decoded.
+    pub fn finish(self) -> R {
+        self.inner.into_inner().0
+    }
+
+    /// Decodes and throws away the next `n` bytes of output, returning how
+    /// many were skipped, which is less than `n` only at the end of the
+    /// stream.
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let mut left = n;
+        while left > 0 {
+            left -= self.inner.core_mut().skip_output(left);
+            // reading one byte has the core decode the next block
+            if left > 0 {
+                if try!(self.inner.read(&mut [0])) == 0 {
+                    break
+                }
+                left -= 1;
             }
-            let n = cmp::min(amt, self.output.len() - self.start);
-            unsafe { ptr::copy_nonoverlapping(
-                &self.output[self.start],
-                &mut dst[dst_len - amt],
-                n,
-            )};
-            self.start += n;
-            amt -= n;
         }
+        Ok(n - left)
+    }
+}
 
-        Ok(dst_len - amt)
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(dst)
     }
 }
 
 
-/// This structure is used to compress a stream of bytes using the BWT.
-/// This is a wrapper around an internal writer which bytes will be written to.
-pub struct Encoder<W> {
-    w: W,
-    buf: Vec<u8>,
-    suf: Vec<usize>,
+// The suffix array and the output of one block in flight
+#[derive(Default)]
+struct Worker {
+    suf: Buffer<usize>,
+    out: Vec<u8>,
+}
+
+impl Worker {
+    fn transform(&mut self, block: &[u8]) {
+        let n = block.len();
+        self.suf.clear();
+        self.suf.resize(n, n);
+        self.out.clear();
+        // the suffixes are sorted straight into `suf`, with the temporaries
+        // in the same memory
+        let alloc = self.suf.allocator();
+        sort_suffixes(block, &mut self.suf, alloc);
+        let mut iter = TransformIterator::new(block, &self.suf);
+        self.out.extend(iter.by_ref());
+        let origin = iter.get_origin() as u32;
+        self.out.extend_from_slice(&origin.to_le_bytes());
+    }
+}
+
+/// The I/O free core of `Encoder`, see `io::Core`
+pub struct EncoderCore {
+    buf: Buffer<u8>,
+    // one per thread
+    workers: Vec<Worker>,
     wrote_header: bool,
     block_size: usize,
+    // encoded bytes the output had no room for yet
+    out: Vec<u8>,
+    out_pos: usize,
+    finished: bool,
 }
 
-impl<W: Write> Encoder<W> {
-    /// Creates a new encoder which will have its output written to the given
-    /// output stream. The output stream can be re-acquired by calling
-    /// `finish()`
-    /// 'block_size' is idealy as big as your input, unless you know for sure that
-    /// the input consists of multiple parts of different nature. Often set as 4Mb.
-    pub fn new(w: W, block_size: usize) -> Encoder<W> {
-        Encoder {
-            w: w,
-            buf: Vec::new(),
-            suf: Vec::new(),
+impl EncoderCore {
+    /// Creates a core encoding like `Encoder::new`, with the same limits on
+    /// the block size
+    pub fn new(block_size: usize) -> io::Result<EncoderCore> {
+        if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("BWT block size must be within 1 and {} bytes", MAX_BLOCK_SIZE)))
+        }
+        Ok(EncoderCore {
+            buf: Buffer::default(),
+            workers: vec![Worker::default()],
             wrote_header: false,
             block_size: block_size,
+            out: Vec::new(),
+            out_pos: 0,
+            finished: false,
+        })
+    }
+
+    /// Transforms up to `threads` blocks at once, see `Encoder::threads`
+    pub fn threads(mut self, threads: usize) -> EncoderCore {
+        self.set_threads(threads);
+        self
+    }
+
+    fn set_threads(&mut self, threads: usize) {
+        let alloc = self.buf.allocator();
+        self.workers.resize_with(cmp::max(threads, 1), || {
+            Worker { suf: Buffer::new(alloc), out: Vec::new() }
+        });
+    }
+
+    /// Makes the block buffer and the suffix array come from `alloc`, along
+    /// with the temporary buffers used to sort the suffixes. The bytes
+    /// written so far are kept.
+    pub fn set_allocator(&mut self, alloc: Allocator) {
+        for worker in self.workers.iter_mut() {
+            worker.suf = Buffer::new(alloc);
         }
+        let mut buf = Buffer::new(alloc);
+        buf.extend_from_slice(&self.buf);
+        self.buf = buf;
     }
 
-    fn encode_block(&mut self) -> io::Result<()> {
-        let n = self.buf.len();
-        try!(self.w.write_u32::<LittleEndian>(n as u32));
+    fn drain(&mut self, output: &mut [u8]) -> usize {
+        let n = cmp::min(self.out.len() - self.out_pos, output.len());
+        output[..n].copy_from_slice(&self.out[self.out_pos..self.out_pos + n]);
+        self.out_pos += n;
+        if self.out_pos == self.out.len() {
+            self.out.clear();
+            self.out_pos = 0;
+        }
+        n
+    }
 
-        self.suf.truncate(0);
-        self.suf.extend((0..n).map(|_| n));
-        let w = &mut self.w;
+    fn write_header(&mut self) {
+        if !self.wrote_header {
+            self.out.extend_from_slice(&MAGIC);
+            self.out.extend_from_slice(&(self.block_size as u32).to_le_bytes());
+            self.wrote_header = true;
+        }
+    }
+
+    // Transforms all buffered input, the last block possibly short
+    fn encode_buffered(&mut self) {
+        encode_blocks(&mut self.out, &mut self.workers, &self.buf, self.block_size)
+            .expect("writing to a Vec can't fail");
+        self.buf.truncate(0);
+    }
+}
 
-        {
-            let mut iter = encode(&self.buf[..], &mut self.suf[..]);
-            for ch in iter.by_ref() {
-                try!(w.write_u8(ch));
+impl Core for EncoderCore {
+    fn process(&mut self, input: &[u8], output: &mut [u8], finish: bool)
+               -> io::Result<(usize, usize, Status)> {
+        // even an empty stream gets its header
+        self.write_header();
+        let mut produced = self.drain(output);
+        let mut used = 0;
+
+        let batch = self.workers.len() * self.block_size;
+        while used < input.len() && self.out.is_empty() {
+            let rest = &input[used..];
+            // whole batches are transformed straight from the caller's slice
+            if self.buf.is_empty() && rest.len() >= batch {
+                encode_blocks(&mut self.out, &mut self.workers, &rest[..batch], self.block_size)
+                    .expect("writing to a Vec can't fail");
+                used += batch;
+            } else {
+                let amt = cmp::min(batch - self.buf.len(), rest.len());
+                self.buf.extend_from_slice(&rest[..amt]);
+                used += amt;
+                if self.buf.len() == batch {
+                    self.encode_buffered();
+                }
             }
+            produced += self.drain(&mut output[produced..]);
+        }
 
-            try!(w.write_u32::<LittleEndian>(iter.get_origin() as u32));
+        if finish && used == input.len() && !self.finished {
+            self.flush();
+            self.finished = true;
+            produced += self.drain(&mut output[produced..]);
         }
-        self.buf.truncate(0);
+        let status = if self.finished && self.out.is_empty() { Status::StreamEnd } else { Status::Ok };
+        Ok((used, produced, status))
+    }
 
-        Ok(())
+    // whatever is buffered becomes a short block
+    fn flush(&mut self) {
+        if !self.buf.is_empty() {
+            self.encode_buffered();
+        }
+    }
+}
+
+/// This structure is used to compress a stream of bytes using the BWT.
+/// This is a wrapper around an internal writer which bytes will be written to.
+pub struct Encoder<W> {
+    inner: CoreWriter<W, EncoderCore>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates a new encoder which will have its output written to the given
+    /// output stream. The output stream can be re-acquired by calling
+    /// `finish()`
+    /// 'block_size' is idealy as big as your input, unless you know for sure that
+    /// the input consists of multiple parts of different nature. Often set as 4Mb,
+    /// it can't be zero or exceed `MAX_BLOCK_SIZE`. Longer inputs are encoded as
+    /// a sequence of independent blocks, cut as the input arrives: writes can be
+    /// of any size, and whatever is left over becomes a short last block when
+    /// the encoder is flushed or finished. Other block sizes are an error of
+    /// kind `InvalidInput`.
+    pub fn new(w: W, block_size: usize) -> io::Result<Encoder<W>> {
+        let core = try!(EncoderCore::new(block_size));
+        Ok(Encoder { inner: CoreWriter::new(w, core) })
+    }
+
+    /// Transforms up to `threads` blocks at once, each on its own thread.
+    /// The output is the same as with a single thread, but up to that many
+    /// blocks of input are buffered, along with a suffix array for each.
+    pub fn threads(mut self, threads: usize) -> Encoder<W> {
+        self.inner.core_mut().set_threads(threads);
+        self
+    }
+
+    /// Makes the block buffer and the suffix array come from `alloc`, along
+    /// with the temporary buffers used to sort the suffixes. The bytes
+    /// written so far are kept.
+    pub fn set_allocator(&mut self, alloc: Allocator) {
+        self.inner.core_mut().set_allocator(alloc);
+    }
+
+    /// Aligns the block buffer, the suffix array and the buffers used to sort
+    /// it to huge pages, or goes back to the global allocator
+    pub fn set_huge_pages(&mut self, enabled: bool) {
+        self.set_allocator(if enabled { Allocator::huge_pages() } else { Allocator::global() });
     }
 
     /// This function is used to flag that this session of compression is done
     /// with. The stream is finished up (final bytes are written), and then the
     /// wrapped writer is returned.
-    pub fn finish(mut self) -> (W, io::Result<()>) {
-        let result = self.flush();
-        (self.w, result)
+    pub fn finish(self) -> (W, io::Result<()>) {
+        self.inner.finish()
     }
 }
 
 impl<W: Write> Write for Encoder<W> {
-    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
-        if !self.wrote_header {
-            try!(self.w.write_u32::<LittleEndian>(self.block_size as u32));
-            self.wrote_header = true;
-        }
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
 
-        while buf.len() > 0 {
-            let amt = cmp::min( self.block_size - self.buf.len(), buf.len() );
-            self.buf.extend(buf[..amt].iter().map(|b| *b));
+    /// Writes out whatever is buffered as a short block, and flushes the
+    /// wrapped writer
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-            if self.buf.len() == self.block_size {
-                try!(self.encode_block());
+
+// Transforms `data` as blocks of `block_size` bytes, the last one possibly
+// short, one per worker, and writes them out in order
+fn encode_blocks<W: Write>(w: &mut W, workers: &mut [Worker], data: &[u8], block_size: usize)
+                           -> io::Result<()> {
+    let blocks = data.chunks(block_size).count();
+    if blocks == 1 {
+        workers[0].transform(data);
+    } else {
+        thread::scope(|s| {
+            for (block, worker) in data.chunks(block_size).zip(workers.iter_mut()) {
+                s.spawn(move || worker.transform(block));
             }
-            buf = &buf[amt..];
-        }
-        Ok(buf.len())
+        });
     }
+    for (block, worker) in data.chunks(block_size).zip(workers.iter()) {
+        try!(w.write_u32::<LittleEndian>(block.len() as u32));
+        try!(w.write_all(&worker.out));
+    }
+    Ok(())
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        let ret = if self.buf.len() > 0 {
-            self.encode_block()
-        } else {
-            Ok(())
-        };
-        ret.and(self.w.flush())
+/// Transforms `data` in one go, as a single block if it fits in
+/// `MAX_BLOCK_SIZE`
+pub fn compress_to_vec(data: &[u8]) -> Vec<u8> {
+    let block_size = data.len().clamp(1, MAX_BLOCK_SIZE);
+    let mut e = Encoder::new(Vec::new(), block_size).expect("the block size is in range");
+    e.write_all(data).expect("writing to a Vec can't fail");
+    let (out, result) = e.finish();
+    result.expect("writing to a Vec can't fail");
+    out
+}
+
+/// Reverts the transform of a stream held in memory in one go
+pub fn decompress_to_vec(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    try!(Decoder::new(data, true).read_to_end(&mut out));
+    Ok(out)
+}
+
+/// The bare BWT stream as a runtime selectable `Codec`, using the faster
+/// decoder which needs more memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bwt {
+    /// The block size encoders use, 1 MiB by default
+    pub block_size: usize,
+}
+
+impl Default for Bwt {
+    fn default() -> Bwt {
+        Bwt { block_size: 1 << 20 }
     }
 }
 
+impl Codec for Bwt {
+    fn name(&self) -> &'static str { "bwt" }
+
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>> {
+        metrics::encoder("bwt", w, |w| Ok(Box::new(try!(Encoder::new(w, self.block_size)))))
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        metrics::decoder("bwt", r, |r| Ok(Box::new(Decoder::new(r, true))))
+    }
+}
+
+impl<W: Write> Finish for Encoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (*self).finish().1
+    }
+}
 
 #[cfg(test)]
 mod test {
     use std::io::{BufReader, BufWriter, Read, Write};
     #[cfg(feature="unstable")]
+    use std::io;
+    #[cfg(feature="unstable")]
     use test::Bencher;
-    use super::{Decoder, Encoder};
+    use std::cmp;
+    use io::{Core, Status};
+    use super::{Decoder, DecoderCore, Encoder, EncoderCore};
 
     fn roundtrip(bytes: &[u8], extra_mem: bool) {
-        let mut e = Encoder::new(BufWriter::new(Vec::new()), 1<<10);
+        let mut e = Encoder::new(BufWriter::new(Vec::new()), 1<<10).unwrap();
         e.write(bytes).unwrap();
         let (e, err) = e.finish();
         err.unwrap();
@@ -545,9 +1075,301 @@ mod test {
         roundtrip(include_bytes!("../data/test.txt"), true);
     }
 
+    #[test]
+    fn suffix_arrays() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+        use super::suffix_array;
+        let mut rng = StdRng::seed_from_u64(508);
+        let mut inputs: Vec<Vec<u8>> = vec![
+            b"".to_vec(), b"a".to_vec(), b"banana".to_vec(), b"mmiissiissiippii".to_vec(),
+            vec![b'a'; 100], b"ab".iter().cycle().take(101).cloned().collect(),
+            include_bytes!("../data/test.txt").to_vec(),
+        ];
+        for _ in 0..50 {
+            let len = rng.gen_range(0, 300);
+            let alphabet = rng.gen_range(1, 5);
+            inputs.push((0..len).map(|_| rng.gen_range(0, alphabet)).collect());
+        }
+        for input in inputs.iter() {
+            let mut expected: Vec<usize> = (0..input.len()).collect();
+            expected.sort_by(|&a, &b| input[a..].cmp(&input[b..]));
+            assert_eq!(suffix_array(input), expected);
+        }
+    }
+
+    #[test]
+    fn large_repetitive_block() {
+        let input: Vec<u8> = b"abcabcabd".iter().cycle().take(3 << 20).cloned().collect();
+        let mut e = Encoder::new(Vec::new(), 4 << 20).unwrap();
+        e.write_all(&input).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        let mut d = Decoder::new(&encoded[..], true);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert!(decoded == input);
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        use super::MAX_BLOCK_SIZE;
+        let text = include_bytes!("../data/test.txt");
+        let input: Vec<u8> = text.iter().cycle().take((1 << 20) * 5 / 2).cloned().collect();
+        let mut e = Encoder::new(Vec::new(), 1 << 20).unwrap();
+        for chunk in input.chunks(100_000) {
+            e.write_all(chunk).unwrap();
+        }
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        // header, then three blocks with a length and a primary index each
+        assert_eq!(encoded.len(), 8 + input.len() + 3 * 8);
+
+        let mut d = Decoder::new(&encoded[..], true);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert!(decoded == input);
+
+        // blocks can't be larger than the header says
+        let mut bad = encoded.clone();
+        bad[4..8].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(Decoder::new(&bad[..], true).read_to_end(&mut Vec::new()).is_err());
+        bad[4..8].copy_from_slice(&(MAX_BLOCK_SIZE as u32 + 1).to_le_bytes());
+        assert!(Decoder::new(&bad[..], true).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn any_write_size() {
+        let text = include_bytes!("../data/test.txt");
+        let input: Vec<u8> = text.iter().cycle().take(10_000).cloned().collect();
+        let encode = |size: usize, threads| {
+            let mut e = Encoder::new(Vec::new(), 4096).unwrap().threads(threads);
+            for chunk in input.chunks(size) {
+                e.write_all(chunk).unwrap();
+            }
+            let (encoded, err) = e.finish();
+            err.unwrap();
+            encoded
+        };
+        // two whole blocks and a short one, however the input is cut up
+        let expected = encode(input.len(), 1);
+        assert_eq!(expected.len(), 8 + input.len() + 3 * 8);
+        for &size in [1, 1000, 4095, 4097, 8192, 9999].iter() {
+            assert!(encode(size, 1) == expected, "writes of {} bytes", size);
+            assert!(encode(size, 2) == expected, "writes of {} bytes on 2 threads", size);
+        }
+    }
+
+    #[test]
+    fn threads() {
+        let text = include_bytes!("../data/test.txt");
+        let input: Vec<u8> = text.iter().cycle().take(100_000).cloned().collect();
+        let encode = |threads| {
+            let mut e = Encoder::new(Vec::new(), 7_000).unwrap().threads(threads);
+            e.write_all(&input[..30_000]).unwrap();
+            e.flush().unwrap();
+            for chunk in input[30_000..].chunks(9_999) {
+                e.write_all(chunk).unwrap();
+            }
+            let (encoded, err) = e.finish();
+            err.unwrap();
+            encoded
+        };
+        let serial = encode(1);
+        for &threads in [2, 4, 20].iter() {
+            assert!(encode(threads) == serial, "differs with {} threads", threads);
+        }
+        let mut decoded = Vec::new();
+        Decoder::new(&serial[..], true).read_to_end(&mut decoded).unwrap();
+        assert!(decoded == input);
+    }
+
+    // feeds the core one byte at a time with one byte of room for output
+    fn trickle<C: Core>(core: &mut C, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut byte = [0];
+        for i in 0..input.len() + 1 {
+            loop {
+                let (used, produced, status) =
+                    core.process(&input[i..cmp::min(i + 1, input.len())], &mut byte,
+                                 i == input.len()).unwrap();
+                out.extend_from_slice(&byte[..produced]);
+                if used == 1 || status == Status::StreamEnd {
+                    break
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn cores_byte_at_a_time() {
+        let input = &include_bytes!("../data/test.txt")[..100];
+        let mut e = Encoder::new(Vec::new(), 16).unwrap();
+        e.write_all(input).unwrap();
+        let encoded = e.finish().0;
+        assert_eq!(trickle(&mut EncoderCore::new(16).unwrap(), input), encoded);
+        assert_eq!(trickle(&mut EncoderCore::new(16).unwrap().threads(3), input), encoded);
+        for &extra_mem in [true, false].iter() {
+            assert_eq!(&trickle(&mut DecoderCore::new(extra_mem), &encoded)[..], input);
+        }
+
+        // the stream may only end between blocks
+        for &cut in [2, 10, 13, 20].iter() {
+            let mut core = DecoderCore::new(true);
+            let mut out = [0; 128];
+            let err = core.process(&encoded[..cut], &mut out, true).unwrap_err();
+            assert_eq!(err.kind(), ::std::io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn legacy_streams() {
+        use super::is_legacy;
+        let input = include_bytes!("../data/test.txt");
+        let mut e = Encoder::new(Vec::new(), 1 << 10).unwrap();
+        e.write_all(input).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        // the layout of earlier versions, without the magic bytes
+        let legacy = &encoded[4..];
+        assert!(is_legacy(legacy));
+        assert!(!is_legacy(&encoded));
+
+        assert!(Decoder::new(legacy, true).read_to_end(&mut Vec::new()).is_err());
+        for stream in [legacy, &encoded[..]].iter() {
+            let mut decoded = Vec::new();
+            Decoder::legacy(*stream, true).read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn invalid_block_size() {
+        for &size in [0, super::MAX_BLOCK_SIZE + 1].iter() {
+            let err = Encoder::new(Vec::new(), size).err().unwrap();
+            assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn empty_block() {
+        for &origin in [0u32, 5].iter() {
+            let mut stream = super::MAGIC.to_vec();
+            stream.extend_from_slice(&16u32.to_le_bytes());
+            stream.extend_from_slice(&0u32.to_le_bytes());
+            stream.extend_from_slice(&origin.to_le_bytes());
+            for &extra_mem in [true, false].iter() {
+                let err = Decoder::new(&stream[..], extra_mem).read_to_end(&mut Vec::new()).unwrap_err();
+                assert_eq!(err.kind(), ::std::io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
     #[test]
     fn decode_minimal() {
         roundtrip(b"abracadabra", false);
+        roundtrip(b"", false);
+        roundtrip(b"2.........g.\n000", false);
+
+        // it inverts the transform like the decoder using a table
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+        let mut rng = StdRng::seed_from_u64(528);
+        for _ in 0..200 {
+            let len = rng.gen_range(1, 40);
+            let alphabet = rng.gen_range(1, 5);
+            let input: Vec<u8> = (0..len).map(|_| rng.gen_range(0, alphabet)).collect();
+            let (encoded, origin) = super::encode_simple(&input);
+            let mut output = vec![0; len];
+            super::decode_minimal(&encoded, origin, &mut output);
+            assert_eq!(output, input);
+        }
+    }
+
+    #[test]
+    fn skip() {
+        let input = include_bytes!("../data/test.txt");
+        let mut e = Encoder::new(Vec::new(), 1<<10).unwrap();
+        e.write_all(input).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+
+        let mut d = Decoder::new(&encoded[..], true);
+        assert_eq!(d.skip(2500).unwrap(), 2500);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], &input[2500..]);
+    }
+
+    #[test]
+    fn huge_pages() {
+        let input = include_bytes!("../data/test.txt");
+        let mut e = Encoder::new(Vec::new(), 1 << 20).unwrap();
+        e.set_huge_pages(true);
+        e.write_all(input).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        let mut d = Decoder::new(&encoded[..], true);
+        d.set_huge_pages(true);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], &input[..]);
+    }
+
+    #[test]
+    fn allocator() {
+        use std::alloc::{self, Layout};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use alloc::Allocator;
+
+        static LIVE: AtomicUsize = AtomicUsize::new(0);
+        static PEAK: AtomicUsize = AtomicUsize::new(0);
+        unsafe fn counting_alloc(layout: Layout) -> *mut u8 {
+            let live = LIVE.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(live, Ordering::SeqCst);
+            alloc::alloc(layout)
+        }
+        unsafe fn counting_dealloc(ptr: *mut u8, layout: Layout) {
+            LIVE.fetch_sub(layout.size(), Ordering::SeqCst);
+            alloc::dealloc(ptr, layout)
+        }
+
+        let input = include_bytes!("../data/test.txt");
+        let mut e = Encoder::new(Vec::new(), 1 << 20).unwrap();
+        e.set_allocator(unsafe { Allocator::new(counting_alloc, counting_dealloc) });
+        e.write_all(input).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        // the copy of the text and the names live alongside the suffix array
+        assert!(PEAK.load(Ordering::SeqCst) >= 3 * ::std::mem::size_of::<usize>() * input.len());
+        let mut decoded = Vec::new();
+        Decoder::new(&encoded[..], true).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], &input[..]);
+    }
+
+    // sorting a block of 16 MiB, with and without huge page alignment
+    #[cfg(feature="unstable")]
+    fn encode_large(bh: &mut Bencher, huge_pages: bool) {
+        let text = include_bytes!("../data/test.txt");
+        let input: Vec<u8> = text.iter().cycle().take(16 << 20).cloned().collect();
+        bh.iter(|| {
+            let mut e = Encoder::new(io::sink(), 16 << 20).unwrap();
+            e.set_huge_pages(huge_pages);
+            e.write_all(&input).unwrap();
+            e.finish().1.unwrap();
+        });
+        bh.bytes = input.len() as u64;
+    }
+
+    #[cfg(feature="unstable")]
+    #[bench]
+    fn encode_large_block(bh: &mut Bencher) {
+        encode_large(bh, false);
+    }
+
+    #[cfg(feature="unstable")]
+    #[bench]
+    fn encode_large_block_huge_pages(bh: &mut Bencher) {
+        encode_large(bh, true);
     }
 
     #[cfg(feature="unstable")]
@@ -571,4 +1393,15 @@ mod test {
         });
         bh.bytes = n as u64;
     }
+
+    #[test]
+    fn one_shot() {
+        let input = include_bytes!("../data/test.txt");
+        let compressed = super::compress_to_vec(input);
+        // a single block
+        assert_eq!(compressed.len(), 8 + 4 + input.len() + 4);
+        assert_eq!(&super::decompress_to_vec(&compressed).unwrap()[..], &input[..]);
+        assert!(super::decompress_to_vec(&compressed[..100]).is_err());
+        assert!(super::decompress_to_vec(&super::compress_to_vec(b"")).unwrap().is_empty());
+    }
 }
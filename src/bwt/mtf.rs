@@ -43,7 +43,7 @@ pub const TOTAL_SYMBOLS: usize = 0x100;
 /// MoveToFront encoder/decoder
 pub struct MTF {
     /// rank-ordered list of unique Symbols
-    pub symbols: [Symbol; TOTAL_SYMBOLS],
+    pub(crate) symbols: [Symbol; TOTAL_SYMBOLS],
 }
 
 impl MTF {
@@ -81,7 +81,7 @@ impl MTF {
     /// decode a rank into its symbol
     pub fn decode(&mut self, rank: Rank) -> Symbol {
         let sym = self.symbols[rank as usize];
-        debug!("\tDecoding rank {} with symbol {}", rank, sym);
+        trace!(target: "compress::bwt::mtf", "\tDecoding rank {} with symbol {}", rank, sym);
         for i in (0 .. rank as usize).rev() {
             self.symbols[i+1] = self.symbols[i];
         }
@@ -152,6 +152,13 @@ impl<R> Decoder<R> {
     }
 }
 
+impl<R: Read> Decoder<R> {
+    /// decode and throw away the next `n` symbols, return how many were skipped
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        io::copy(&mut self.by_ref().take(n), &mut io::sink())
+    }
+}
+
 impl<R: Read> Read for Decoder<R> {
     fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
         let mut bytes_read = 0;
@@ -177,12 +184,12 @@ mod test {
     use super::{Encoder, Decoder};
 
     fn roundtrip(bytes: &[u8]) {
-        info!("Roundtrip MTF of size {}", bytes.len());
+        info!(target: "compress::bwt::mtf", "Roundtrip MTF of size {}", bytes.len());
         let buf = Vec::new();
         let mut e = Encoder::new(io::BufWriter::new(buf));
         e.write_all(bytes).unwrap();
         let encoded = e.finish().into_inner().unwrap();
-        debug!("Roundtrip MTF input: {:?}, ranks: {:?}", bytes, encoded);
+        debug!(target: "compress::bwt::mtf", "Roundtrip MTF input: {:?}, ranks: {:?}", bytes, encoded);
         let mut d = Decoder::new(io::BufReader::new(&encoded[..]));
         let mut decoded = Vec::new();
         d.read_to_end(&mut decoded).unwrap();
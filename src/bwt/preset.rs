@@ -0,0 +1,87 @@
+/*!
+
+Ready-made BWT pipelines with tuned stages
+
+Building a good BWT compressor out of the stages of `pipeline` takes some research: the block size,
+what comes after the transform and how the entropy coder adapts all matter. The presets here are such
+choices made once, and return a `Pipeline` to encode and decode with, or to print as a spec and store
+next to the data.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::bwt::preset;
+
+let pipeline = preset::bzip2_like();
+let mut e = pipeline.encoder(Vec::new()).unwrap();
+e.write_all(b"abracadabra abracadabra").unwrap();
+let (compressed, result) = e.finish();
+result.unwrap();
+
+let mut decoded = Vec::new();
+pipeline.decoder(&compressed[..]).unwrap().read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], b"abracadabra abracadabra");
+assert_eq!(pipeline.to_string(), "bwt:900k|mtf|zrle");
+```
+
+*/
+
+use pipeline::{Pipeline, Stage};
+
+/// Block size of `bzip2_like`, about the one of `bzip2 -9`
+pub const BZIP2_BLOCK: usize = 900 << 10;
+
+/// A pipeline modelled on `bzip2 -9`: 900k BWT blocks, move-to-front, and
+/// zero runs of the ranks coded with RUNA/RUNB by an adaptive order-0
+/// arithmetic coder, see `zrle`. It is spelled `bwt:900k|mtf|zrle` and needs
+/// the `bwt` and `entropy` features.
+///
+/// Its ratio is only checked against the `bzip2` module at level 9 on the
+/// test data of this crate, where it comes within a few percent; it has not
+/// been measured on the Canterbury or Silesia corpora.
+pub fn bzip2_like() -> Pipeline {
+    Pipeline::new(vec![Stage::Bwt(BZIP2_BLOCK), Stage::Mtf, Stage::Zrle])
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::bzip2_like;
+
+    #[test]
+    #[cfg(feature="entropy")]
+    fn flipped() {
+        let pipeline = bzip2_like();
+        let mut e = pipeline.encoder(Vec::new()).unwrap();
+        e.write_all(include_bytes!("../data/test.txt")).unwrap();
+        let (compressed, result) = e.finish();
+        result.unwrap();
+        for i in (0..compressed.len()).step_by(3) {
+            let mut corrupt = compressed.clone();
+            corrupt[i] ^= 0x5a;
+            let _ = pipeline.decoder(&corrupt[..]).unwrap().take(1 << 20).read_to_end(&mut Vec::new());
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature="entropy", feature="bzip2"))]
+    fn close_to_bzip2() {
+        let input = &include_bytes!("../data/test.large")[..1 << 20];
+        let pipeline = bzip2_like();
+        let mut e = pipeline.encoder(Vec::new()).unwrap();
+        e.write_all(input).unwrap();
+        let (compressed, result) = e.finish();
+        result.unwrap();
+        let mut decoded = Vec::new();
+        pipeline.decoder(&compressed[..]).unwrap().read_to_end(&mut decoded).unwrap();
+        assert!(decoded[..] == input[..]);
+
+        let mut e = ::bzip2::Encoder::with_level(Vec::new(), 9);
+        e.write_all(input).unwrap();
+        let (reference, result) = e.finish();
+        result.unwrap();
+        assert!(compressed.len() * 100 < reference.len() * 103,
+                "{} bytes against {} for bzip2", compressed.len(), reference.len());
+    }
+}
@@ -0,0 +1,289 @@
+/*!
+
+Zero run coding of MTF ranks into bytes, as in bzip2. Requires `bwt` feature, enabled by default
+
+After the BWT and MTF stages most ranks are zero, in long runs. This stage writes a run of zero ranks
+as its length in bijective base 2, least significant digit first, with the digits RUNA (a byte 0) for 1
+and RUNB (a byte 1) for 2, so that a run of `n` ranks takes about `log2(n)` bytes. Ranks 1 to 253 are
+written as themselves plus one, and the rare ranks 254 and 255 as the byte 255 followed by a 0 or a 1.
+The output is meant for an entropy coder, like the `ari` stage of a pipeline spelled `bwt|mtf|rle0|ari`.
+`zrle` does the same with an arithmetic coder built in.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::bwt::rle0;
+
+// Encode some ranks
+let ranks = [0u8, 0, 0, 3, 1, 0, 0, 255];
+let mut e = rle0::Encoder::new(Vec::new());
+e.write_all(&ranks).unwrap();
+let (encoded, result) = e.finish();
+result.unwrap();
+assert_eq!(&encoded[..], &[rle0::RUNA, rle0::RUNA, 4, 2, rle0::RUNB, 255, 1]);
+
+// Decode them back
+let mut d = rle0::Decoder::new(&encoded[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], &ranks[..]);
+```
+
+# Credit
+
+The RUNA/RUNB coding of zero runs comes from bzip2 by Julian Seward.
+
+*/
+
+use std::io::{self, Read, Write};
+
+/// The digit 1 of a zero run
+pub const RUNA: u8 = 0;
+/// The digit 2 of a zero run
+pub const RUNB: u8 = 1;
+// followed by a byte telling rank 254 or 255
+const ESCAPE: u8 = 255;
+
+// Calls `put` with the digits of a run of `n` zero ranks in bijective base
+// 2, RUNA or RUNB, least significant first
+pub(crate) fn run_digits<F: FnMut(u8) -> io::Result<()>>(mut n: u64, mut put: F) -> io::Result<()> {
+    while n > 0 {
+        n -= 1;
+        try!(put(if n & 1 == 0 { RUNA } else { RUNB }));
+        n >>= 1;
+    }
+    Ok(())
+}
+
+// The length of a zero run, added up from its digits
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Run {
+    len: u64,
+    weight: u64,
+}
+
+impl Run {
+    // Adds the next digit, RUNA or RUNB
+    pub(crate) fn push(&mut self, digit: u8) -> io::Result<()> {
+        let weight = if self.weight == 0 { 1 } else { self.weight };
+        self.len = match (weight << (digit == RUNB) as usize).checked_add(self.len) {
+            Some(len) if weight < 1 << 62 => len,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "zero run is too long")),
+        };
+        self.weight = weight << 1;
+        Ok(())
+    }
+
+    // Returns the length of the run and starts the next one
+    pub(crate) fn take(&mut self) -> u64 {
+        let len = self.len;
+        *self = Run::default();
+        len
+    }
+}
+
+/// Zero run encoder of MTF ranks
+pub struct Encoder<W> {
+    w: W,
+    // zero ranks not written yet
+    run: u64,
+    out: Vec<u8>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder writing into `w`
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder {
+            w,
+            run: 0,
+            out: Vec::new(),
+        }
+    }
+
+    fn end_run(&mut self) {
+        let out = &mut self.out;
+        run_digits(self.run, |digit| { out.push(digit); Ok(()) }).unwrap();
+        self.run = 0;
+    }
+
+    /// Writes out the pending run and returns the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        self.out.clear();
+        self.end_run();
+        let result = self.w.write_all(&self.out);
+        (self.w, result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.clear();
+        for &rank in buf.iter() {
+            match rank {
+                0 => self.run += 1,
+                1 ..= 253 => {
+                    self.end_run();
+                    self.out.push(rank + 1);
+                }
+                _ => {
+                    self.end_run();
+                    self.out.push(ESCAPE);
+                    self.out.push(rank - 254);
+                }
+            }
+        }
+        try!(self.w.write_all(&self.out));
+        Ok(buf.len())
+    }
+
+    // a run can't be cut short, as the next one would continue its digits
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Zero run decoder of MTF ranks
+pub struct Decoder<R> {
+    r: R,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    // zero ranks decoded but not read out yet
+    zeros: u64,
+    run: Run,
+    // a rank to read out after the run
+    next: Option<u8>,
+    escaped: bool,
+    eof: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            r,
+            buf: vec![0; 4096],
+            pos: 0,
+            len: 0,
+            zeros: 0,
+            run: Run::default(),
+            next: None,
+            escaped: false,
+            eof: false,
+        }
+    }
+
+    /// Returns the wrapped reader
+    pub fn finish(self) -> R {
+        self.r
+    }
+
+    // Decodes up to the next rank which isn't zero, or the end
+    fn fill(&mut self) -> io::Result<()> {
+        loop {
+            if self.pos == self.len {
+                let n = try!(self.r.read(&mut self.buf));
+                if n == 0 {
+                    if self.escaped {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "zero run stream ends after an escape"))
+                    }
+                    self.zeros = self.run.take();
+                    self.eof = true;
+                    return Ok(())
+                }
+                self.len = n;
+                self.pos = 0;
+            }
+            let byte = self.buf[self.pos];
+            self.pos += 1;
+            let rank = match byte {
+                _ if self.escaped => match byte {
+                    0 | 1 => 254 + byte,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid escaped rank")),
+                },
+                RUNA | RUNB => {
+                    try!(self.run.push(byte));
+                    continue
+                }
+                ESCAPE => {
+                    self.escaped = true;
+                    continue
+                }
+                _ => byte - 1,
+            };
+            self.escaped = false;
+            self.zeros = self.run.take();
+            self.next = Some(rank);
+            return Ok(())
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut amount = 0;
+        while amount < buf.len() {
+            if self.zeros > 0 {
+                let n = self.zeros.min((buf.len() - amount) as u64) as usize;
+                for b in buf[amount..amount + n].iter_mut() {
+                    *b = 0;
+                }
+                self.zeros -= n as u64;
+                amount += n;
+            } else if let Some(rank) = self.next.take() {
+                buf[amount] = rank;
+                amount += 1;
+            } else if self.eof {
+                break
+            } else {
+                try!(self.fill());
+            }
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Encoder, Decoder};
+
+    fn roundtrip(ranks: &[u8]) -> usize {
+        let mut e = Encoder::new(Vec::new());
+        for chunk in ranks.chunks(1000) {
+            e.write_all(chunk).unwrap();
+        }
+        let (encoded, result) = e.finish();
+        result.unwrap();
+        let mut decoded = Vec::new();
+        Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert!(decoded[..] == ranks[..]);
+        encoded.len()
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        assert_eq!(roundtrip(b""), 0);
+        assert_eq!(roundtrip(&[0]), 1);
+        roundtrip(&[255, 0, 0, 254, 253, 1]);
+        let mut runs = Vec::new();
+        for n in 0..300 {
+            runs.extend((0..n).map(|_| 0));
+            runs.push((n % 256) as u8);
+        }
+        roundtrip(&runs);
+        roundtrip(include_bytes!("../data/test.txt"));
+        // a long run takes a few bytes, however it is written
+        assert_eq!(roundtrip(&vec![0; 1 << 20]), 20);
+    }
+
+    #[test]
+    fn corrupt() {
+        for bad in [&[255][..], &[255, 2], &[2, 255]].iter() {
+            assert!(Decoder::new(&bad[..]).read_to_end(&mut Vec::new()).is_err());
+        }
+        // more digits than any run could have
+        assert!(Decoder::new(&[1; 64][..]).read_to_end(&mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,248 @@
+/*!
+
+Zero run coding of MTF ranks with an adaptive arithmetic coder. Requires `bwt` and `entropy`
+features, enabled by default
+
+This is the last stage of a bzip2-style compressor, taking the ranks produced by `mtf`. A run of zero
+ranks is written as its length in bijective base 2, with the digits RUNA and RUNB as in bzip2, and any
+other rank as itself plus one. The resulting symbols, together with an end of stream symbol, are coded
+by an order-0 adaptive model whose increments grow with its total, so that it follows the statistics
+of the current part of the data while keeping a long memory of the rest.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::bwt::zrle;
+
+// Encode some ranks
+let ranks = [0u8, 0, 0, 3, 1, 0, 0, 7];
+let mut e = zrle::Encoder::new(Vec::new());
+e.write_all(&ranks).unwrap();
+let (encoded, result) = e.finish();
+result.unwrap();
+
+// Decode them back
+let mut d = zrle::Decoder::new(&encoded[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], &ranks[..]);
+```
+
+# Credit
+
+The RUNA/RUNB coding of zero runs comes from bzip2 by Julian Seward.
+
+*/
+
+use std::io::{self, Read, Write};
+
+use entropy::ari::{self, Border};
+use entropy::ari::table::Model;
+use super::rle0::{self, Run, RUNB};
+
+// RUNA and RUNB come first, then ranks 1 to 255, then the end of the stream
+const END: usize = 257;
+const SYMBOLS: usize = END + 1;
+
+// the model is halved when its total reaches this, the range coder keeps
+// the range above it
+const THRESHOLD: Border = 1 << 16;
+// an update adds `(total >> ADD_LOG) + ADD_CONST` to the symbol
+const ADD_LOG: usize = 12;
+const ADD_CONST: Border = 32;
+
+fn new_model() -> Model {
+    Model::new_flat(SYMBOLS, THRESHOLD)
+}
+
+/// Zero run encoder of MTF ranks
+pub struct Encoder<W> {
+    encoder: ari::Encoder<W>,
+    model: Model,
+    // zero ranks not written yet
+    run: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder writing into `w`
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder {
+            encoder: ari::Encoder::with_threshold(w, THRESHOLD),
+            model: new_model(),
+            run: 0,
+        }
+    }
+
+    fn put(&mut self, symbol: usize) -> io::Result<()> {
+        try!(self.encoder.encode(symbol, &self.model));
+        self.model.update(symbol, ADD_LOG, ADD_CONST);
+        Ok(())
+    }
+
+    // Writes the pending run, see `rle0`
+    fn end_run(&mut self) -> io::Result<()> {
+        let n = self.run;
+        self.run = 0;
+        rle0::run_digits(n, |digit| self.put(digit as usize))
+    }
+
+    /// Writes the end of the stream and returns the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.end_run().and_then(|_| {
+            self.encoder.encode(END, &self.model)
+        });
+        let (w, rest) = self.encoder.finish();
+        (w, result.and(rest))
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &rank in buf.iter() {
+            if rank == 0 {
+                self.run += 1;
+            } else {
+                try!(self.end_run());
+                try!(self.put(rank as usize + 1));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    // a run can't be cut short, as the next one would continue its digits
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// Zero run decoder of MTF ranks
+pub struct Decoder<R> {
+    decoder: ari::Decoder<R>,
+    model: Model,
+    // zero ranks decoded but not read out yet
+    zeros: u64,
+    run: Run,
+    // a rank to read out after the run
+    next: Option<u8>,
+    eof: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            decoder: ari::Decoder::with_threshold(r, THRESHOLD),
+            model: new_model(),
+            zeros: 0,
+            run: Run::default(),
+            next: None,
+            eof: false,
+        }
+    }
+
+    /// Returns the wrapped reader
+    pub fn finish(self) -> (R, io::Result<()>) {
+        self.decoder.finish()
+    }
+
+    // Decodes up to the next rank which isn't zero, or the end
+    fn fill(&mut self) -> io::Result<()> {
+        loop {
+            let symbol = try!(self.decoder.decode(&self.model));
+            if symbol == END {
+                self.zeros = self.run.take();
+                self.eof = true;
+                return Ok(())
+            }
+            self.model.update(symbol, ADD_LOG, ADD_CONST);
+            if symbol > RUNB as usize {
+                self.zeros = self.run.take();
+                self.next = Some((symbol - 1) as u8);
+                return Ok(())
+            }
+            try!(self.run.push(symbol as u8));
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut amount = 0;
+        while amount < buf.len() {
+            if self.zeros > 0 {
+                let n = self.zeros.min((buf.len() - amount) as u64) as usize;
+                for b in buf[amount..amount + n].iter_mut() {
+                    *b = 0;
+                }
+                self.zeros -= n as u64;
+                amount += n;
+            } else if let Some(rank) = self.next.take() {
+                buf[amount] = rank;
+                amount += 1;
+            } else if self.eof {
+                break
+            } else {
+                try!(self.fill());
+            }
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Encoder, Decoder};
+
+    fn roundtrip(ranks: &[u8]) -> usize {
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(ranks).unwrap();
+        let (encoded, result) = e.finish();
+        result.unwrap();
+        let mut decoded = Vec::new();
+        Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert!(decoded[..] == ranks[..]);
+        encoded.len()
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        roundtrip(b"");
+        roundtrip(&[0]);
+        roundtrip(&[255, 0, 0, 255, 1]);
+        let mut runs = Vec::new();
+        for n in 0..300 {
+            runs.extend((0..n).map(|_| 0));
+            runs.push((n % 256) as u8);
+        }
+        roundtrip(&runs);
+        roundtrip(include_bytes!("../data/test.txt"));
+        // a long run takes a few symbols
+        assert!(roundtrip(&vec![0; 1 << 20]) < 16);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(include_bytes!("../data/test.txt")).unwrap();
+        let (encoded, _) = e.finish();
+        let mut d = Decoder::new(&encoded[..encoded.len() / 2]);
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn flipped() {
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(include_bytes!("../data/test.txt")).unwrap();
+        let (encoded, _) = e.finish();
+        for i in 0..encoded.len() {
+            for &mask in [0x01u8, 0x80, 0xff].iter() {
+                let mut corrupt = encoded.clone();
+                corrupt[i] ^= mask;
+                // garbage or an error, but no panic; runs may decode huge
+                let _ = Decoder::new(&corrupt[..]).take(1 << 20).read_to_end(&mut Vec::new());
+            }
+        }
+    }
+}
@@ -0,0 +1,856 @@
+/*!
+
+bzip2 compression and decompression. Requires `bzip2` feature, enabled by
+default
+
+This is a pure Rust implementation of the `.bz2` format, assembled from the
+stages the crate already has: the input goes through a first run-length
+pass, is cut into blocks of 100k to 900k bytes, every block is transformed
+with the BWT (sorting its rotations with the `bwt` suffix array
+construction), its ranks are computed with `bwt::mtf`, runs of zero ranks
+are written with the RUNA/RUNB symbols and the result is Huffman coded with
+up to six tables, switching tables every 50 symbols. Blocks and the whole
+stream are protected by CRC-32s.
+
+The output is understood by the standard `bzip2` tool and this decoder reads
+the tool's output, including files made of several concatenated streams.
+Blocks in the obsolete randomised mode are rejected.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::bzip2;
+
+let mut e = bzip2::Encoder::new(Vec::new());
+e.write_all(b"hello, hello, hello").unwrap();
+let (compressed, result) = e.finish();
+result.unwrap();
+assert_eq!(&compressed[..4], b"BZh9");
+
+let mut d = bzip2::Decoder::new(&compressed[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], b"hello, hello, hello");
+```
+
+*/
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use super::byteorder::ReadBytesExt;
+use bwt::{self, mtf::MTF};
+use entropy::huff;
+
+/// The bytes every bzip2 stream starts with, followed by the level digit
+pub const MAGIC: [u8; 3] = *b"BZh";
+
+const BLOCK_MAGIC: u64 = 0x314159265359;
+const END_MAGIC: u64 = 0x177245385090;
+
+// uncompressed block size, in bytes, per level
+const BLOCK_UNIT: usize = 100000;
+// symbols coded with the same table
+const GROUP_SIZE: usize = 50;
+const MIN_GROUPS: usize = 2;
+const MAX_GROUPS: usize = 6;
+const MAX_ALPHA: usize = 258;
+const MAX_SELECTORS: usize = 2 + 900000 / GROUP_SIZE;
+// longest code the decoder accepts and the encoder produces
+const MAX_CODE_LEN: usize = 20;
+const MAX_ENCODE_LEN: usize = 17;
+const RUNA: u16 = 0;
+const RUNB: u16 = 1;
+
+fn error<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// bzip2 uses the CRC-32 polynomial without reflecting the bits
+const POLY: u32 = 0x04c11db7;
+
+static CRC_TABLE: [u32; 256] = make_crc_table();
+
+const fn make_crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80000000 != 0 { (crc << 1) ^ POLY } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+struct Crc(u32);
+
+impl Crc {
+    fn new() -> Crc {
+        Crc(!0)
+    }
+
+    fn feed(&mut self, byte: u8, count: usize) {
+        for _ in 0..count {
+            self.0 = (self.0 << 8) ^ CRC_TABLE[((self.0 >> 24) ^ byte as u32) as usize];
+        }
+    }
+
+    fn result(&self) -> u32 {
+        !self.0
+    }
+}
+
+fn combine(stream: u32, block: u32) -> u32 {
+    stream.rotate_left(1) ^ block
+}
+
+struct BitWriter<W> {
+    w: W,
+    buf: u64,
+    cnt: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    // Writes the `cnt` low bits of `bits`, most significant first
+    fn put(&mut self, bits: u64, cnt: u32) -> io::Result<()> {
+        self.buf = self.buf << cnt | (bits & ((1 << cnt) - 1));
+        self.cnt += cnt;
+        while self.cnt >= 8 {
+            self.cnt -= 8;
+            try!(self.w.write_all(&[(self.buf >> self.cnt) as u8]));
+        }
+        Ok(())
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        match self.cnt {
+            0 => Ok(()),
+            n => self.put(0, 8 - n),
+        }
+    }
+}
+
+struct BitReader<R> {
+    r: R,
+    buf: u64,
+    cnt: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    fn bits(&mut self, cnt: u32) -> io::Result<u64> {
+        while self.cnt < cnt {
+            self.buf = self.buf << 8 | try!(self.r.read_u8()) as u64;
+            self.cnt += 8;
+        }
+        self.cnt -= cnt;
+        Ok((self.buf >> self.cnt) & ((1 << cnt) - 1))
+    }
+
+    fn bit(&mut self) -> io::Result<bool> {
+        self.bits(1).map(|b| b == 1)
+    }
+
+    fn align(&mut self) {
+        self.cnt -= self.cnt % 8;
+    }
+}
+
+// Huffman code lengths of the given frequencies, no longer than `max_len`.
+// Unused symbols still get a code, as every table has to cover the whole
+// alphabet.
+fn code_lengths(freqs: &[u32], max_len: usize, lens: &mut [u8]) {
+    let weights: Vec<u32> = freqs.iter().map(|&f| cmp::max(f, 1)).collect();
+    lens.copy_from_slice(&huff::code_lengths(&weights, max_len as u8));
+}
+
+/// Writes a bzip2 stream. The input is gathered into blocks, which are
+/// compressed as they fill up, the rest of it on `finish`.
+pub struct Encoder<W: Write> {
+    w: BitWriter<W>,
+    level: u8,
+    max_block: usize,
+    block: Vec<u8>,
+    crc: Crc,
+    stream_crc: u32,
+    run_byte: u8,
+    run_len: usize,
+    wrote_header: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder with the largest block size, like `bzip2 -9`
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder::with_level(w, 9)
+    }
+
+    /// Creates an encoder with blocks of `level` times 100k bytes, the level
+    /// being clamped to 1 to 9
+    pub fn with_level(w: W, level: u8) -> Encoder<W> {
+        let level = level.clamp(1, 9);
+        Encoder {
+            w: BitWriter { w, buf: 0, cnt: 0 },
+            level,
+            // leaves room for the longest run written after the block filled up
+            max_block: level as usize * BLOCK_UNIT - 19,
+            block: Vec::new(),
+            crc: Crc::new(),
+            stream_crc: 0,
+            run_byte: 0,
+            run_len: 0,
+            wrote_header: false,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.wrote_header {
+            try!(self.w.put(u32::from_be_bytes([0, b'B', b'Z', b'h']) as u64, 24));
+            try!(self.w.put((b'0' + self.level) as u64, 8));
+            self.wrote_header = true;
+        }
+        Ok(())
+    }
+
+    // Moves the pending run into the block: up to 4 bytes as is, the rest as
+    // a count
+    fn end_run(&mut self) -> io::Result<()> {
+        if self.run_len == 0 {
+            return Ok(())
+        }
+        self.crc.feed(self.run_byte, self.run_len);
+        let literal = cmp::min(self.run_len, 4);
+        for _ in 0..literal {
+            self.block.push(self.run_byte);
+        }
+        if self.run_len >= 4 {
+            self.block.push((self.run_len - 4) as u8);
+        }
+        self.run_len = 0;
+        if self.block.len() >= self.max_block {
+            try!(self.write_block());
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(())
+        }
+        try!(self.write_header());
+        let crc = self.crc.result();
+        self.stream_crc = combine(self.stream_crc, crc);
+        try!(encode_block(&mut self.w, &self.block, crc));
+        self.block.clear();
+        self.crc = Crc::new();
+        Ok(())
+    }
+
+    /// This function is used to flag that this session of compression is done
+    /// with. The stream is finished up (final bytes are written), and then the
+    /// wrapped writer is returned.
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.end_run()
+                         .and_then(|_| self.write_block())
+                         .and_then(|_| self.write_header())
+                         .and_then(|_| self.w.put(END_MAGIC, 48))
+                         .and_then(|_| self.w.put(self.stream_crc as u64, 32))
+                         .and_then(|_| self.w.align())
+                         .and_then(|_| self.w.w.flush());
+        (self.w.w, result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf.iter() {
+            if self.run_len > 0 && (byte != self.run_byte || self.run_len == 255) {
+                try!(self.end_run());
+            }
+            self.run_byte = byte;
+            self.run_len += 1;
+        }
+        Ok(buf.len())
+    }
+
+    /// Flushes the wrapped writer. Pending input stays in the current block,
+    /// as a bzip2 block can't be ended early without hurting the ratio.
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.w.flush()
+    }
+}
+
+// Sorts the rotations of `block`, returning the last column of the sorted
+// matrix and the row of the block itself
+fn rotations(block: &[u8]) -> (Vec<u8>, usize) {
+    let n = block.len();
+    let mut doubled = Vec::with_capacity(2 * n);
+    doubled.extend_from_slice(block);
+    doubled.extend_from_slice(block);
+    // the suffixes of the doubled block starting in its first half sort like
+    // the rotations
+    let mut origin = 0;
+    let mut last = Vec::with_capacity(n);
+    for i in bwt::suffix_array(&doubled).into_iter().filter(|&i| i < n) {
+        if i == 0 {
+            origin = last.len();
+        }
+        last.push(block[(i + n - 1) % n]);
+    }
+    (last, origin)
+}
+
+fn encode_block<W: Write>(w: &mut BitWriter<W>, block: &[u8], crc: u32) -> io::Result<()> {
+    let (last, origin) = rotations(block);
+
+    let mut in_use = [false; 256];
+    for &b in block.iter() {
+        in_use[b as usize] = true;
+    }
+    let mut seq = [0u8; 256];
+    let mut used = 0;
+    for (b, &u) in in_use.iter().enumerate() {
+        if u {
+            seq[b] = used as u8;
+            used += 1;
+        }
+    }
+    let alpha = used + 2;
+    let eob = (used + 1) as u16;
+
+    // ranks, with runs of zeros as RUNA/RUNB
+    let mut mtf = MTF::new();
+    mtf.reset_alphabetical();
+    let mut syms: Vec<u16> = Vec::with_capacity(block.len() + 1);
+    let mut zeros = 0;
+    for &b in last.iter() {
+        let rank = mtf.encode(seq[b as usize]);
+        if rank == 0 {
+            zeros += 1;
+            continue
+        }
+        write_zero_run(&mut syms, zeros);
+        zeros = 0;
+        syms.push(rank as u16 + 1);
+    }
+    write_zero_run(&mut syms, zeros);
+    syms.push(eob);
+
+    let mut freqs = [0u32; MAX_ALPHA];
+    for &s in syms.iter() {
+        freqs[s as usize] += 1;
+    }
+    let groups = match syms.len() {
+        0..=199 => 2,
+        200..=599 => 3,
+        600..=1199 => 4,
+        1200..=2399 => 5,
+        _ => 6,
+    };
+
+    // start with tables covering slices of the alphabet of equal weight
+    let mut lens = [[0u8; MAX_ALPHA]; MAX_GROUPS];
+    let mut left = syms.len() as u32;
+    let mut start = 0;
+    for part in (1..groups + 1).rev() {
+        let target = left / part as u32;
+        let mut end = start;
+        let mut weight = 0;
+        while end < alpha && (weight < target || end == start) {
+            weight += freqs[end];
+            end += 1;
+        }
+        if end > start + 1 && part != groups && part != 1 && (groups - part) % 2 == 1 {
+            end -= 1;
+            weight -= freqs[end];
+        }
+        for (s, len) in lens[part - 1].iter_mut().enumerate().take(alpha) {
+            *len = if s >= start && s < end { 0 } else { 15 };
+        }
+        left -= weight;
+        start = end;
+    }
+
+    // refine: code every group with its cheapest table, then fit the tables
+    // to the groups they got
+    let mut selectors = Vec::with_capacity(syms.len().div_ceil(GROUP_SIZE));
+    for _ in 0..4 {
+        selectors.clear();
+        let mut table_freqs = [[0u32; MAX_ALPHA]; MAX_GROUPS];
+        for group in syms.chunks(GROUP_SIZE) {
+            let best = (0..groups).min_by_key(|&t| {
+                group.iter().map(|&s| lens[t][s as usize] as u32).sum::<u32>()
+            }).unwrap();
+            selectors.push(best as u8);
+            for &s in group.iter() {
+                table_freqs[best][s as usize] += 1;
+            }
+        }
+        for t in 0..groups {
+            code_lengths(&table_freqs[t][..alpha], MAX_ENCODE_LEN, &mut lens[t][..alpha]);
+        }
+    }
+    let mut codes = [[0u32; MAX_ALPHA]; MAX_GROUPS];
+    for t in 0..groups {
+        let canonical = try!(huff::canonical_codes(&lens[t][..alpha]));
+        codes[t][..alpha].copy_from_slice(&canonical);
+    }
+
+    try!(w.put(BLOCK_MAGIC, 48));
+    try!(w.put(crc as u64, 32));
+    // not randomised
+    try!(w.put(0, 1));
+    try!(w.put(origin as u64, 24));
+
+    let mut ranges = 0u64;
+    for (i, range) in in_use.chunks(16).enumerate() {
+        if range.iter().any(|&u| u) {
+            ranges |= 1 << (15 - i);
+        }
+    }
+    try!(w.put(ranges, 16));
+    for range in in_use.chunks(16).filter(|r| r.iter().any(|&u| u)) {
+        let bits = range.iter().fold(0, |acc, &u| acc << 1 | u as u64);
+        try!(w.put(bits, 16));
+    }
+
+    try!(w.put(groups as u64, 3));
+    try!(w.put(selectors.len() as u64, 15));
+    let mut mtf = MTF::new();
+    mtf.reset_alphabetical();
+    for &sel in selectors.iter() {
+        let rank = mtf.encode(sel);
+        for _ in 0..rank {
+            try!(w.put(1, 1));
+        }
+        try!(w.put(0, 1));
+    }
+
+    for table in lens.iter().take(groups) {
+        let mut cur = table[0];
+        try!(w.put(cur as u64, 5));
+        for &len in table[..alpha].iter() {
+            while cur < len {
+                try!(w.put(2, 2));
+                cur += 1;
+            }
+            while cur > len {
+                try!(w.put(3, 2));
+                cur -= 1;
+            }
+            try!(w.put(0, 1));
+        }
+    }
+
+    for (group, &sel) in syms.chunks(GROUP_SIZE).zip(selectors.iter()) {
+        let (lens, codes) = (&lens[sel as usize], &codes[sel as usize]);
+        for &s in group.iter() {
+            try!(w.put(codes[s as usize] as u64, lens[s as usize] as u32));
+        }
+    }
+    Ok(())
+}
+
+// Writes a run of `n` zero ranks in bijective base 2, RUNA being a 1 digit
+// and RUNB a 2 digit
+fn write_zero_run(syms: &mut Vec<u16>, mut n: usize) {
+    while n > 0 {
+        n -= 1;
+        syms.push(if n & 1 == 0 { RUNA } else { RUNB });
+        n >>= 1;
+    }
+}
+
+// Canonical Huffman decoding tables of one coding table
+struct DecodeTable {
+    min_len: usize,
+    limit: [i32; MAX_CODE_LEN + 1],
+    base: [i32; MAX_CODE_LEN + 2],
+    perm: [u16; MAX_ALPHA],
+}
+
+impl DecodeTable {
+    fn new(lens: &[u8]) -> DecodeTable {
+        let min_len = *lens.iter().min().unwrap() as usize;
+        let max_len = *lens.iter().max().unwrap() as usize;
+        let mut table = DecodeTable {
+            min_len,
+            limit: [-1; MAX_CODE_LEN + 1],
+            base: [0; MAX_CODE_LEN + 2],
+            perm: [0; MAX_ALPHA],
+        };
+        let mut p = 0;
+        for len in min_len..max_len + 1 {
+            for (s, &l) in lens.iter().enumerate() {
+                if l as usize == len {
+                    table.perm[p] = s as u16;
+                    p += 1;
+                }
+            }
+        }
+        // base[len] starts as the number of codes shorter than len
+        for &l in lens.iter() {
+            table.base[l as usize + 1] += 1;
+        }
+        for i in 1..table.base.len() {
+            table.base[i] += table.base[i - 1];
+        }
+        let mut code = 0;
+        for len in min_len..max_len + 1 {
+            code += table.base[len + 1] - table.base[len];
+            table.limit[len] = code - 1;
+            code <<= 1;
+        }
+        for len in (min_len + 1..max_len + 1).rev() {
+            table.base[len] = ((table.limit[len - 1] + 1) << 1) - table.base[len];
+        }
+        table
+    }
+
+    fn decode<R: Read>(&self, r: &mut BitReader<R>, alpha: usize) -> io::Result<u16> {
+        let mut len = self.min_len;
+        let mut code = try!(r.bits(len as u32)) as i32;
+        while code > self.limit[len] {
+            len += 1;
+            if len > MAX_CODE_LEN {
+                return error("invalid bzip2 Huffman code")
+            }
+            code = code << 1 | try!(r.bit()) as i32;
+        }
+        let index = code - self.base[len];
+        if index < 0 || index as usize >= alpha {
+            return error("invalid bzip2 Huffman code")
+        }
+        Ok(self.perm[index as usize])
+    }
+}
+
+/// Reads a bzip2 stream, or several concatenated ones, decompressing a block
+/// at a time
+pub struct Decoder<R> {
+    r: BitReader<R>,
+    max_block: usize,
+    // before the first stream header, or after the end of a stream
+    in_stream: bool,
+    stream_crc: u32,
+    tt: Vec<u32>,
+    output: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading compressed data from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            r: BitReader { r, buf: 0, cnt: 0 },
+            max_block: 0,
+            in_stream: false,
+            stream_crc: 0,
+            tt: Vec::new(),
+            output: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Returns the wrapped reader. Bytes after the end of the last stream
+    /// may have been consumed.
+    pub fn into_inner(self) -> R {
+        self.r.r
+    }
+
+    // Reads a stream header, returning false at the end of the input
+    fn read_header(&mut self, first: bool) -> io::Result<bool> {
+        let mut magic = [0; 4];
+        let mut got = 0;
+        while got < 4 {
+            match try!(self.r.r.read(&mut magic[got..])) {
+                0 => break,
+                n => got += n,
+            }
+        }
+        if got == 0 && !first {
+            return Ok(false)
+        }
+        if got == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty bzip2 file"))
+        }
+        if got < 4 || magic[..3] != MAGIC || !(b'1'..=b'9').contains(&magic[3]) {
+            return error("not a bzip2 stream")
+        }
+        self.max_block = (magic[3] - b'0') as usize * BLOCK_UNIT;
+        self.stream_crc = 0;
+        self.in_stream = true;
+        Ok(true)
+    }
+
+    // Decodes the next block into `output`, returning false at the end of
+    // the input
+    fn next_block(&mut self) -> io::Result<bool> {
+        loop {
+            if !self.in_stream {
+                let first = self.max_block == 0;
+                if !try!(self.read_header(first)) {
+                    return Ok(false)
+                }
+            }
+            match try!(self.r.bits(48)) {
+                BLOCK_MAGIC => break,
+                END_MAGIC => {
+                    let crc = try!(self.r.bits(32)) as u32;
+                    if crc != self.stream_crc {
+                        return error("invalid bzip2 stream checksum")
+                    }
+                    self.r.align();
+                    self.in_stream = false;
+                }
+                _ => return error("invalid bzip2 block header"),
+            }
+        }
+
+        let crc = try!(self.r.bits(32)) as u32;
+        if try!(self.r.bit()) {
+            return error("randomised bzip2 blocks are not supported")
+        }
+        let origin = try!(self.r.bits(24)) as usize;
+
+        let ranges = try!(self.r.bits(16));
+        let mut unseq = Vec::with_capacity(256);
+        for i in 0..16 {
+            if ranges & (1 << (15 - i)) != 0 {
+                let bits = try!(self.r.bits(16));
+                for j in 0..16 {
+                    if bits & (1 << (15 - j)) != 0 {
+                        unseq.push((i * 16 + j) as u8);
+                    }
+                }
+            }
+        }
+        if unseq.is_empty() {
+            return error("bzip2 block uses no symbols")
+        }
+        let alpha = unseq.len() + 2;
+        let eob = (unseq.len() + 1) as u16;
+
+        let groups = try!(self.r.bits(3)) as usize;
+        let count = try!(self.r.bits(15)) as usize;
+        if !(MIN_GROUPS..=MAX_GROUPS).contains(&groups) || count == 0 {
+            return error("invalid bzip2 table selectors")
+        }
+        let mut mtf = MTF::new();
+        mtf.reset_alphabetical();
+        let mut selectors = Vec::with_capacity(cmp::min(count, MAX_SELECTORS));
+        for _ in 0..count {
+            let mut rank = 0;
+            while try!(self.r.bit()) {
+                rank += 1;
+                if rank >= groups {
+                    return error("invalid bzip2 table selectors")
+                }
+            }
+            // later bzip2 versions tolerate excess selectors by ignoring them
+            if selectors.len() < MAX_SELECTORS {
+                selectors.push(mtf.decode(rank as u8));
+            }
+        }
+
+        let mut tables = Vec::with_capacity(groups);
+        let mut lens = [0u8; MAX_ALPHA];
+        for _ in 0..groups {
+            let mut cur = try!(self.r.bits(5)) as i32;
+            for len in lens[..alpha].iter_mut() {
+                loop {
+                    if cur < 1 || cur > MAX_CODE_LEN as i32 {
+                        return error("invalid bzip2 code length")
+                    }
+                    if !try!(self.r.bit()) {
+                        break
+                    }
+                    cur += if try!(self.r.bit()) { -1 } else { 1 };
+                }
+                *len = cur as u8;
+            }
+            tables.push(DecodeTable::new(&lens[..alpha]));
+        }
+
+        // Huffman, RUNA/RUNB and MTF decoding
+        let mut mtf = MTF::new();
+        mtf.reset_alphabetical();
+        let mut counts = [0usize; 256];
+        let mut block: Vec<u8> = Vec::with_capacity(self.max_block);
+        let mut run = 0;
+        let mut run_weight = 1;
+        let mut decoded = 0;
+        loop {
+            let selector = match selectors.get(decoded / GROUP_SIZE) {
+                Some(&s) => s as usize,
+                None => return error("bzip2 block runs out of selectors"),
+            };
+            let sym = try!(tables[selector].decode(&mut self.r, alpha));
+            decoded += 1;
+            if sym == RUNA || sym == RUNB {
+                if run_weight > self.max_block {
+                    return error("bzip2 block is too large")
+                }
+                run += run_weight << (sym == RUNB) as usize;
+                run_weight <<= 1;
+                continue
+            }
+            if run > 0 {
+                if block.len() + run > self.max_block {
+                    return error("bzip2 block is too large")
+                }
+                let byte = unseq[mtf.symbols[0] as usize];
+                counts[byte as usize] += run;
+                block.extend((0..run).map(|_| byte));
+                run = 0;
+                run_weight = 1;
+            }
+            if sym == eob {
+                break
+            }
+            if block.len() == self.max_block {
+                return error("bzip2 block is too large")
+            }
+            let byte = unseq[mtf.decode((sym - 1) as u8) as usize];
+            counts[byte as usize] += 1;
+            block.push(byte);
+        }
+        if origin >= block.len() {
+            return error("invalid bzip2 block origin")
+        }
+
+        // inverse BWT: link every row to the one starting where it ends
+        let mut starts = [0usize; 256];
+        let mut sum = 0;
+        for (start, &count) in starts.iter_mut().zip(counts.iter()) {
+            *start = sum;
+            sum += count;
+        }
+        self.tt.clear();
+        self.tt.resize(block.len(), 0);
+        for (i, &b) in block.iter().enumerate() {
+            self.tt[starts[b as usize]] = i as u32;
+            starts[b as usize] += 1;
+        }
+
+        // and undo the initial run-length pass on the way out
+        self.output.clear();
+        self.pos = 0;
+        let mut block_crc = Crc::new();
+        let mut pos = self.tt[origin] as usize;
+        let mut last = None;
+        let mut same = 0;
+        for _ in 0..block.len() {
+            let byte = block[pos];
+            pos = self.tt[pos] as usize;
+            if same == 4 {
+                block_crc.feed(last.unwrap(), byte as usize);
+                self.output.extend((0..byte).map(|_| last.unwrap()));
+                same = 0;
+                last = None;
+                continue
+            }
+            if last == Some(byte) {
+                same += 1;
+            } else {
+                same = 1;
+                last = Some(byte);
+            }
+            block_crc.feed(byte, 1);
+            self.output.push(byte);
+        }
+        if block_crc.result() != crc {
+            return error("invalid bzip2 block checksum")
+        }
+        self.stream_crc = combine(self.stream_crc, crc);
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.output.len() {
+            if self.eof || !try!(self.next_block()) {
+                self.eof = true;
+                return Ok(0)
+            }
+        }
+        let n = cmp::min(buf.len(), self.output.len() - self.pos);
+        buf[..n].copy_from_slice(&self.output[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use super::{Decoder, Encoder};
+
+    fn roundtrip(input: &[u8], level: u8) -> Vec<u8> {
+        let mut e = Encoder::with_level(Vec::new(), level);
+        for chunk in input.chunks(1000) {
+            e.write_all(chunk).unwrap();
+        }
+        let (compressed, result) = e.finish();
+        result.unwrap();
+        let mut d = Decoder::new(&compressed[..]);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert!(decoded == input);
+        compressed
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        roundtrip(b"", 9);
+        roundtrip(b"a", 9);
+        roundtrip(b"banana", 1);
+        roundtrip(&[0; 1000], 9);
+        roundtrip(&(0..=255u8).cycle().take(5000).collect::<Vec<_>>(), 9);
+        let text = include_bytes!("data/test.txt");
+        // the bzip2 tool gets this down to 1751 bytes
+        assert!(roundtrip(text, 9).len() < 1760);
+    }
+
+    #[test]
+    fn runs_and_blocks() {
+        // long runs, and several blocks at level 1
+        let mut input = Vec::new();
+        for i in 0..300000usize {
+            input.push(if i % 1000 < 600 { b'x' } else { (i * 7 % 251) as u8 });
+        }
+        roundtrip(&input, 1);
+    }
+
+    #[test]
+    fn decode_reference() {
+        // made with `bzip2 -9`, followed by a second stream made with `bzip2 -1`
+        let mut d = Decoder::new(&include_bytes!("data/test.txt.bz2")[..]);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        let text = include_bytes!("data/test.txt");
+        assert_eq!(&decoded[..text.len()], &text[..]);
+        assert_eq!(&decoded[text.len()..], &text[..]);
+    }
+
+    #[test]
+    fn corrupt() {
+        let text = include_bytes!("data/test.txt");
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(text).unwrap();
+        let (mut compressed, _) = e.finish();
+        let n = compressed.len();
+        compressed[n / 2] ^= 0x10;
+        assert!(Decoder::new(&compressed[..]).read_to_end(&mut Vec::new()).is_err());
+        assert!(Decoder::new(&b"BZh0"[..]).read_to_end(&mut Vec::new()).is_err());
+        let err = Decoder::new(&b""[..]).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
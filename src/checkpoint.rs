@@ -0,0 +1,307 @@
+/*!
+
+Checkpoints of long running decompressions. Requires `checkpoint` feature,
+enabled by default
+
+A `Decoder` decodes a DEFLATE stream or an LZ4 frame while counting the
+compressed bytes it consumed and the bytes it produced. At any point between
+two reads, `checkpoint` captures that position along with the state of the
+codec (window, pending output, bit reader). The `Checkpoint` can be saved to
+disk and `Decoder::resume` picks the decompression up from it later, in
+another process, once the compressed input is positioned at the recorded
+offset again.
+
+Checkpoints are written atomically and carry a CRC-32, so a crash while
+saving one leaves the previous checkpoint intact and a damaged file is
+rejected on load.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::{checkpoint, lz4, Format};
+
+let mut e = lz4::Encoder::new(Vec::new());
+e.write_all(&[7; 100000]).unwrap();
+let (compressed, _) = e.finish();
+
+let mut d = checkpoint::Decoder::new(&compressed[..], Format::Lz4Frame).unwrap();
+let mut head = [0; 1000];
+d.read_exact(&mut head).unwrap();
+let saved = d.checkpoint().to_bytes();
+
+// later, maybe after a restart
+let cp = checkpoint::Checkpoint::from_bytes(&saved).unwrap();
+let input = &compressed[cp.input_offset as usize..];
+let mut d = checkpoint::Decoder::resume(input, &cp).unwrap();
+let mut rest = Vec::new();
+d.read_to_end(&mut rest).unwrap();
+assert_eq!(rest.len(), 99000);
+```
+
+*/
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use super::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::ReadExact;
+use flate;
+use lz4;
+use Crc32;
+use Format;
+
+/// The bytes every saved checkpoint starts with
+pub const MAGIC: [u8; 4] = *b"RCCP";
+
+const VERSION: u8 = 1;
+
+fn invalid<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Position and codec state of a decompression, see the module
+/// documentation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Format of the compressed stream
+    pub format: Format,
+    /// Number of compressed bytes consumed from the start of the stream
+    pub input_offset: u64,
+    /// Number of bytes decoded from the start of the stream
+    pub output_offset: u64,
+    /// Codec state, as returned by its `export_state`
+    pub state: Vec<u8>,
+}
+
+impl Checkpoint {
+    /// Serializes the checkpoint, with its magic bytes and a checksum
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(34 + self.state.len());
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.push(self.format as u8);
+        out.write_u64::<LittleEndian>(self.input_offset).unwrap();
+        out.write_u64::<LittleEndian>(self.output_offset).unwrap();
+        out.write_u32::<LittleEndian>(self.state.len() as u32).unwrap();
+        out.extend_from_slice(&self.state);
+        let mut crc = Crc32::new();
+        crc.feed(&out);
+        out.write_u32::<LittleEndian>(crc.result()).unwrap();
+        out
+    }
+
+    /// Parses a checkpoint serialized by `to_bytes`, checking its checksum
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Checkpoint> {
+        if bytes.len() < 4 {
+            return invalid("truncated checkpoint")
+        }
+        let (body, mut tail) = bytes.split_at(bytes.len() - 4);
+        let mut crc = Crc32::new();
+        crc.feed(body);
+        if try!(tail.read_u32::<LittleEndian>()) != crc.result() {
+            return invalid("invalid checksum on checkpoint")
+        }
+
+        let mut r = body;
+        let mut magic = [0; 4];
+        try!(r.read_exact(&mut magic));
+        if magic != MAGIC || try!(r.read_u8()) != VERSION {
+            return invalid("not a checkpoint")
+        }
+        let format = match Format::from_id(try!(r.read_u8())) {
+            Some(format) => format,
+            None => return invalid("unknown format in checkpoint"),
+        };
+        let input_offset = try!(r.read_u64::<LittleEndian>());
+        let output_offset = try!(r.read_u64::<LittleEndian>());
+        let len = try!(r.read_u32::<LittleEndian>()) as u64;
+        let mut state = Vec::new();
+        try!(r.push_exactly(len, &mut state));
+        if !r.is_empty() {
+            return invalid("trailing bytes in checkpoint")
+        }
+        Ok(Checkpoint {
+            format,
+            input_offset,
+            output_offset,
+            state,
+        })
+    }
+
+    /// Writes the checkpoint to `path`. The data goes to a temporary file
+    /// next to it first, which then replaces `path`, so a crash never leaves
+    /// a half written checkpoint behind.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = OsString::from(path.as_os_str());
+        tmp.push(".tmp");
+        {
+            let mut f = try!(File::create(&tmp));
+            try!(f.write_all(&self.to_bytes()));
+            try!(f.sync_all());
+        }
+        fs::rename(&tmp, path)
+    }
+
+    /// Reads a checkpoint written by `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Checkpoint> {
+        Checkpoint::from_bytes(&try!(fs::read(path)))
+    }
+}
+
+// Counts the bytes read through it
+struct Counter<R> {
+    r: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.r.read(buf));
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+enum Inner<R> {
+    Deflate(flate::Decoder<Counter<R>>),
+    Lz4(lz4::Decoder<Counter<R>>),
+}
+
+/// A decoder whose progress can be saved, see the module documentation
+pub struct Decoder<R> {
+    inner: Inner<R>,
+    output: u64,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder for a stream in the given format, DEFLATE or LZ4
+    /// frames
+    pub fn new(r: R, format: Format) -> io::Result<Decoder<R>> {
+        let r = Counter { r, count: 0 };
+        let inner = match format {
+            Format::Deflate => Inner::Deflate(flate::Decoder::new(r)),
+            Format::Lz4Frame => Inner::Lz4(lz4::Decoder::new(r)),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                           "format can't be checkpointed")),
+        };
+        Ok(Decoder { inner, output: 0 })
+    }
+
+    /// Resumes decoding from a checkpoint. `r` has to be positioned
+    /// `checkpoint.input_offset` bytes into the compressed stream.
+    pub fn resume(r: R, checkpoint: &Checkpoint) -> io::Result<Decoder<R>> {
+        let mut d = try!(Decoder::new(r, checkpoint.format));
+        try!(match d.inner {
+            Inner::Deflate(ref mut d) => d.import_state(&checkpoint.state),
+            Inner::Lz4(ref mut d) => d.import_state(&checkpoint.state),
+        });
+        match d.inner {
+            Inner::Deflate(ref mut d) => d.r.count = checkpoint.input_offset,
+            Inner::Lz4(ref mut d) => d.r.count = checkpoint.input_offset,
+        }
+        d.output = checkpoint.output_offset;
+        Ok(d)
+    }
+
+    /// Captures the current position and codec state
+    pub fn checkpoint(&self) -> Checkpoint {
+        let (format, input_offset, state) = match self.inner {
+            Inner::Deflate(ref d) => (Format::Deflate, d.r.count, d.export_state()),
+            Inner::Lz4(ref d) => (Format::Lz4Frame, d.r.count, d.export_state()),
+        };
+        Checkpoint {
+            format,
+            input_offset,
+            output_offset: self.output,
+            state,
+        }
+    }
+
+    /// Returns the number of bytes decoded from the start of the stream
+    pub fn output_offset(&self) -> u64 {
+        self.output
+    }
+
+    /// Returns the wrapped reader
+    pub fn into_inner(self) -> R {
+        match self.inner {
+            Inner::Deflate(d) => d.r.r,
+            Inner::Lz4(d) => d.r.r,
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(match self.inner {
+            Inner::Deflate(ref mut d) => d.read(buf),
+            Inner::Lz4(ref mut d) => d.read(buf),
+        });
+        self.output += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use super::{Checkpoint, Decoder};
+    use {flate, lz4, Format};
+
+    fn resume_everywhere(compressed: &[u8], format: Format, expected: &[u8]) {
+        let dir = env::temp_dir().join(format!("compress-checkpoint-{}-{}",
+                                               ::std::process::id(), format as u8));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("job");
+
+        let mut d = Decoder::new(compressed, format).unwrap();
+        let mut decoded = Vec::new();
+        let mut buf = [0; 7777];
+        loop {
+            d.checkpoint().save(&path).unwrap();
+            // throw the decoder away and continue from disk
+            let cp = Checkpoint::load(&path).unwrap();
+            assert_eq!(cp.output_offset, decoded.len() as u64);
+            d = Decoder::resume(&compressed[cp.input_offset as usize..], &cp).unwrap();
+            match d.read(&mut buf).unwrap() {
+                0 => break,
+                n => decoded.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert!(decoded == expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let input: Vec<u8> = include_bytes!("data/test.txt").iter().cycle().take(300000).cloned().collect();
+        let mut e = lz4::Encoder::new(Vec::new());
+        e.write_all(&input).unwrap();
+        resume_everywhere(&e.finish().0, Format::Lz4Frame, &input);
+        let mut e = flate::Encoder::new(Vec::new());
+        e.write_all(&input).unwrap();
+        resume_everywhere(&e.finish().0, Format::Deflate, &input);
+    }
+
+    #[test]
+    fn damaged() {
+        let cp = Checkpoint {
+            format: Format::Deflate,
+            input_offset: 1,
+            output_offset: 2,
+            state: vec![3; 10],
+        };
+        let mut bytes = cp.to_bytes();
+        assert_eq!(Checkpoint::from_bytes(&bytes).unwrap(), cp);
+        bytes[8] ^= 1;
+        assert!(Checkpoint::from_bytes(&bytes).is_err());
+        assert!(Checkpoint::from_bytes(&bytes[..3]).is_err());
+        assert!(Decoder::new(&[][..], Format::Bzip2).is_err());
+    }
+}
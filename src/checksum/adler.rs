@@ -3,22 +3,45 @@
 Adler-32 checksum
 
 This implementation is based off the example found at
-http://en.wikipedia.org/wiki/Adler-32.
+http://en.wikipedia.org/wiki/Adler-32. It is the checksum of the zlib
+format, and `Writer` and `Reader` compute it over the data passing through
+them.
 
 # Example
 
 ```rust
+use std::io::{Read, Write};
 use compress::checksum::adler;
+
 let mut state = adler::State32::new();
-state.feed(b"abracadabra");
-let checksum = state.result();
+state.feed(b"Wikipedia");
+assert_eq!(state.result(), 0x11e60398);
+
+let mut w = adler::Writer::new(Vec::new(), adler::State32::new());
+w.write_all(b"Wikipedia").unwrap();
+assert_eq!(w.checksum(), 0x11e60398);
+
+let mut r = adler::Reader::new(&b"Wikipedia"[..], adler::State32::new());
+r.read_to_end(&mut Vec::new()).unwrap();
+assert_eq!(r.checksum(), 0x11e60398);
 ```
 
 */
 
+use super::tee::{Tee, TeeReader};
+
 const MOD_ADLER: u32 = 65521;
+// the most bytes which can be summed before `b` may overflow 32 bits
+const NMAX: usize = 5552;
+
+/// A writer computing the Adler-32 of everything written through it
+pub type Writer<W> = Tee<W, State32>;
+
+/// A reader computing the Adler-32 of everything read through it
+pub type Reader<R> = TeeReader<R, State32>;
 
 /// Adler state for 32 bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct State32 {
     a: u32,
     b: u32,
@@ -32,9 +55,14 @@ impl State32 {
 
     /// Mutate the state for given data
     pub fn feed(&mut self, buf: &[u8]) {
-        for byte in buf.iter() {
-            self.a = (self.a + *byte as u32) % MOD_ADLER;
-            self.b = (self.a + self.b) % MOD_ADLER;
+        // the sums are only reduced once per chunk
+        for chunk in buf.chunks(NMAX) {
+            for &byte in chunk.iter() {
+                self.a += byte as u32;
+                self.b += self.a;
+            }
+            self.a %= MOD_ADLER;
+            self.b %= MOD_ADLER;
         }
     }
 
@@ -49,3 +77,55 @@ impl State32 {
         self.b = 0;
     }
 }
+
+impl Default for State32 {
+    fn default() -> State32 {
+        State32::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Reader, State32, Writer};
+
+    #[test]
+    fn check_values() {
+        let mut state = State32::new();
+        assert_eq!(state.result(), 1);
+        state.feed(b"Wikipedia");
+        assert_eq!(state.result(), 0x11e60398);
+
+        // long runs of the largest byte stress the deferred reduction
+        state.reset();
+        state.feed(&[0xff; 100_000]);
+        let (mut a, mut b) = (1u32, 0u32);
+        for _ in 0..100_000 {
+            a = (a + 0xff) % 65521;
+            b = (b + a) % 65521;
+        }
+        assert_eq!(state.result(), b << 16 | a);
+    }
+
+    #[test]
+    fn adapters() {
+        let input = include_bytes!("../data/test.txt");
+        let mut direct = State32::new();
+        direct.feed(input);
+
+        let mut w = Writer::new(Vec::new(), State32::new());
+        for chunk in input.chunks(7) {
+            w.write_all(chunk).unwrap();
+        }
+        assert_eq!(w.checksum(), direct.result());
+
+        let mut r = Reader::new(&input[..], State32::new());
+        let mut buf = [0; 100];
+        r.read_exact(&mut buf).unwrap();
+        let mut prefix = State32::new();
+        prefix.feed(&buf);
+        assert_eq!(r.checksum(), prefix.result());
+        r.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(r.checksum(), direct.result());
+    }
+}
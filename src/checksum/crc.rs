@@ -0,0 +1,504 @@
+/*!
+
+CRC checksums
+
+`State32` is the reflected CRC-32 with the 0xEDB88320 polynomial, as used by
+gzip, PNG and Ethernet. `State32C` is CRC-32C with the Castagnoli polynomial
+0x82F63B78, as used by iSCSI, ext4 and the snappy framing format. Eight bytes
+are folded in per step with the slicing-by-8 method, whose lookup tables are
+computed at compile time.
+
+Where the CPU has CRC instructions they are used instead, checked at run
+time: ARMv8 has them for both polynomials, SSE4.2 on x86-64 only for
+CRC-32C. The results are the same either way.
+
+`Engine` computes a CRC with any parameters of 8 to 64 bits, a byte at a
+time, with presets for CRC-16/IBM, CRC-32 and CRC-64/XZ. Other CRCs can be
+defined the same way, their tables are built at compile time too. The
+states above are faster for CRC-32 and CRC-32C.
+
+# Example
+
+```rust
+use compress::checksum::crc;
+let mut state = crc::State32::new();
+state.feed(b"123456789");
+assert_eq!(state.result(), 0xcbf43926);
+
+let mut state = crc::State32C::new();
+state.feed(b"123456789");
+assert_eq!(state.result(), 0xe3069283);
+
+assert_eq!(crc::CRC_64_XZ.checksum(b"123456789"), 0x995dc9bbdf1939fa);
+```
+
+*/
+
+const POLY32: u32 = 0xedb88320;
+const POLY32C: u32 = 0x82f63b78;
+
+// TABLES32[k][i] is the CRC of byte i followed by k zero bytes, which lets
+// `feed_sliced` fold eight bytes at once (slicing-by-8)
+static TABLES32: [[u32; 256]; 8] = make_tables(POLY32);
+static TABLES32C: [[u32; 256]; 8] = make_tables(POLY32C);
+
+// The CRC of a single byte with a reflected polynomial, the table entry for
+// that byte of both the fixed CRC-32s and the reflected engines
+const fn reflected_entry(byte: u64, poly: u64) -> u64 {
+    let mut crc = byte;
+    let mut bit = 0;
+    while bit < 8 {
+        crc = if crc & 1 != 0 { (crc >> 1) ^ poly } else { crc >> 1 };
+        bit += 1;
+    }
+    crc
+}
+
+const fn make_tables(poly: u32) -> [[u32; 256]; 8] {
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        tables[0][i] = reflected_entry(i as u64, poly as u64) as u32;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+fn feed_sliced(t: &[[u32; 256]; 8], mut crc: u32, buf: &[u8]) -> u32 {
+    let mut chunks = buf.chunks_exact(8);
+    for c in &mut chunks {
+        let lo = crc ^ u32::from_le_bytes([c[0], c[1], c[2], c[3]]);
+        crc = t[7][(lo & 0xff) as usize] ^ t[6][(lo >> 8 & 0xff) as usize] ^
+              t[5][(lo >> 16 & 0xff) as usize] ^ t[4][(lo >> 24) as usize] ^
+              t[3][c[4] as usize] ^ t[2][c[5] as usize] ^
+              t[1][c[6] as usize] ^ t[0][c[7] as usize];
+    }
+    for &byte in chunks.remainder() {
+        crc = t[0][((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(target_arch="x86_64")]
+mod hw {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+
+    // SSE4.2 only knows the Castagnoli polynomial
+    pub fn crc32(_crc: u32, _buf: &[u8]) -> Option<u32> {
+        None
+    }
+
+    pub fn crc32c(crc: u32, buf: &[u8]) -> Option<u32> {
+        if is_x86_feature_detected!("sse4.2") {
+            Some(unsafe { crc32c_sse42(crc, buf) })
+        } else {
+            None
+        }
+    }
+
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn crc32c_sse42(crc: u32, buf: &[u8]) -> u32 {
+        let mut crc = crc as u64;
+        let mut chunks = buf.chunks_exact(8);
+        for c in &mut chunks {
+            let word = u64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]);
+            crc = _mm_crc32_u64(crc, word);
+        }
+        let mut crc = crc as u32;
+        for &byte in chunks.remainder() {
+            crc = _mm_crc32_u8(crc, byte);
+        }
+        crc
+    }
+}
+
+#[cfg(target_arch="aarch64")]
+mod hw {
+    use std::arch::aarch64::{__crc32b, __crc32cb, __crc32cd, __crc32d};
+
+    pub fn crc32(crc: u32, buf: &[u8]) -> Option<u32> {
+        if is_aarch64_feature_detected!("crc") {
+            Some(unsafe { crc32_armv8(crc, buf) })
+        } else {
+            None
+        }
+    }
+
+    pub fn crc32c(crc: u32, buf: &[u8]) -> Option<u32> {
+        if is_aarch64_feature_detected!("crc") {
+            Some(unsafe { crc32c_armv8(crc, buf) })
+        } else {
+            None
+        }
+    }
+
+    #[target_feature(enable = "crc")]
+    unsafe fn crc32_armv8(mut crc: u32, buf: &[u8]) -> u32 {
+        let mut chunks = buf.chunks_exact(8);
+        for c in &mut chunks {
+            let word = u64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]);
+            crc = __crc32d(crc, word);
+        }
+        for &byte in chunks.remainder() {
+            crc = __crc32b(crc, byte);
+        }
+        crc
+    }
+
+    #[target_feature(enable = "crc")]
+    unsafe fn crc32c_armv8(mut crc: u32, buf: &[u8]) -> u32 {
+        let mut chunks = buf.chunks_exact(8);
+        for c in &mut chunks {
+            let word = u64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]);
+            crc = __crc32cd(crc, word);
+        }
+        for &byte in chunks.remainder() {
+            crc = __crc32cb(crc, byte);
+        }
+        crc
+    }
+}
+
+#[cfg(not(any(target_arch="x86_64", target_arch="aarch64")))]
+mod hw {
+    pub fn crc32(_crc: u32, _buf: &[u8]) -> Option<u32> {
+        None
+    }
+
+    pub fn crc32c(_crc: u32, _buf: &[u8]) -> Option<u32> {
+        None
+    }
+}
+
+/// CRC state for 32 bits
+pub struct State32 {
+    crc: u32,
+}
+
+impl State32 {
+    /// Create a new state
+    pub fn new() -> State32 {
+        State32 { crc: !0 }
+    }
+
+    /// Mutate the state for given data
+    pub fn feed(&mut self, buf: &[u8]) {
+        self.crc = match hw::crc32(self.crc, buf) {
+            Some(crc) => crc,
+            None => feed_sliced(&TABLES32, self.crc, buf),
+        };
+    }
+
+    /// Get checksum
+    pub fn result(&self) -> u32 {
+        !self.crc
+    }
+
+    /// Reset the state
+    pub fn reset(&mut self) {
+        self.crc = !0;
+    }
+}
+
+impl Default for State32 {
+    fn default() -> State32 {
+        State32::new()
+    }
+}
+
+/// CRC-32C state, with the Castagnoli polynomial
+pub struct State32C {
+    crc: u32,
+}
+
+impl State32C {
+    /// Create a new state
+    pub fn new() -> State32C {
+        State32C { crc: !0 }
+    }
+
+    /// Mutate the state for given data
+    pub fn feed(&mut self, buf: &[u8]) {
+        self.crc = match hw::crc32c(self.crc, buf) {
+            Some(crc) => crc,
+            None => feed_sliced(&TABLES32C, self.crc, buf),
+        };
+    }
+
+    /// Get checksum
+    pub fn result(&self) -> u32 {
+        !self.crc
+    }
+
+    /// Reset the state
+    pub fn reset(&mut self) {
+        self.crc = !0;
+    }
+}
+
+impl Default for State32C {
+    fn default() -> State32C {
+        State32C::new()
+    }
+}
+
+/// The parameters of a CRC in the usual notation of CRC catalogues: the
+/// polynomial is given without its top bit and unreflected, and `reflect`
+/// covers both the input and the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Params {
+    /// Number of bits, 8 to 64
+    pub width: u8,
+    /// Generator polynomial
+    pub poly: u64,
+    /// Initial register value
+    pub init: u64,
+    /// Whether bytes are processed least significant bit first
+    pub reflect: bool,
+    /// Value the result is xored with
+    pub xorout: u64,
+}
+
+const fn reflect_bits(mut value: u64, width: u8) -> u64 {
+    let mut out = 0;
+    let mut i = 0;
+    while i < width {
+        out = (out << 1) | (value & 1);
+        value >>= 1;
+        i += 1;
+    }
+    out
+}
+
+/// A table-driven CRC with any `Params`. `new` is a `const fn`, so the
+/// table of a CRC can be built at compile time into a `static`.
+pub struct Engine {
+    params: Params,
+    mask: u64,
+    table: [u64; 256],
+}
+
+impl Engine {
+    /// Builds the table for `params`. Panics if the width is out of range.
+    pub const fn new(params: Params) -> Engine {
+        assert!(params.width >= 8 && params.width <= 64, "CRC width out of range");
+        let mask = !0u64 >> (64 - params.width as u32);
+        let mut table = [0u64; 256];
+        let mut i = 0;
+        while i < 256 {
+            let crc = if params.reflect {
+                reflected_entry(i as u64, reflect_bits(params.poly, params.width))
+            } else {
+                let top = 1u64 << (params.width - 1);
+                let mut crc = (i as u64) << (params.width - 8);
+                let mut bit = 0;
+                while bit < 8 {
+                    crc = if crc & top != 0 { (crc << 1) ^ params.poly } else { crc << 1 };
+                    bit += 1;
+                }
+                crc
+            };
+            table[i] = crc & mask;
+            i += 1;
+        }
+        Engine { params, mask, table }
+    }
+
+    /// Returns the parameters of this CRC
+    pub fn params(&self) -> &Params {
+        &self.params
+    }
+
+    /// Starts computing a checksum
+    pub fn state(&self) -> State<'_> {
+        State { engine: self, crc: self.init() }
+    }
+
+    /// Computes the checksum of `buf` in one go
+    pub fn checksum(&self, buf: &[u8]) -> u64 {
+        let mut state = self.state();
+        state.feed(buf);
+        state.result()
+    }
+
+    fn init(&self) -> u64 {
+        if self.params.reflect {
+            reflect_bits(self.params.init, self.params.width)
+        } else {
+            self.params.init & self.mask
+        }
+    }
+}
+
+/// CRC-16/ARC, also known as CRC-16/IBM
+pub static CRC_16_IBM: Engine = Engine::new(Params {
+    width: 16, poly: 0x8005, init: 0, reflect: true, xorout: 0,
+});
+
+/// The CRC-32 of `State32`, computed a byte at a time
+pub static CRC_32: Engine = Engine::new(Params {
+    width: 32, poly: 0x04c11db7, init: 0xffffffff, reflect: true, xorout: 0xffffffff,
+});
+
+/// CRC-64/XZ, the 64 bit check of the xz format
+pub static CRC_64_XZ: Engine = Engine::new(Params {
+    width: 64, poly: 0x42f0e1eba9ea3693, init: !0, reflect: true, xorout: !0,
+});
+
+/// Checksum state of an `Engine`
+#[derive(Clone)]
+pub struct State<'a> {
+    engine: &'a Engine,
+    crc: u64,
+}
+
+impl<'a> State<'a> {
+    /// Mutate the state for given data
+    pub fn feed(&mut self, buf: &[u8]) {
+        let e = self.engine;
+        let mut crc = self.crc;
+        if e.params.reflect {
+            for &byte in buf {
+                crc = e.table[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8);
+            }
+        } else {
+            let shift = e.params.width - 8;
+            for &byte in buf {
+                let i = ((crc >> shift) ^ byte as u64) & 0xff;
+                crc = (e.table[i as usize] ^ (crc << 8)) & e.mask;
+            }
+        }
+        self.crc = crc;
+    }
+
+    /// Get checksum
+    pub fn result(&self) -> u64 {
+        (self.crc ^ self.engine.params.xorout) & self.engine.mask
+    }
+
+    /// Reset the state
+    pub fn reset(&mut self) {
+        self.crc = self.engine.init();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{feed_sliced, Engine, Params, State32, State32C, TABLES32, TABLES32C};
+    #[cfg(feature="unstable")]
+    use test;
+
+    fn bytewise(t: &[[u32; 256]; 8], buf: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in buf.iter() {
+            crc = t[0][((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+        !crc
+    }
+
+    #[test]
+    fn matches_bytewise() {
+        let input = include_bytes!("../data/test.txt");
+        for start in 0..9 {
+            for len in (0..40).chain(input.len() - start - 20..input.len() - start) {
+                let buf = &input[start..start + len];
+                let mut state = State32::new();
+                state.feed(buf);
+                assert_eq!(state.result(), bytewise(&TABLES32, buf), "{} bytes at {}", len, start);
+                let mut state = State32C::new();
+                state.feed(buf);
+                assert_eq!(state.result(), bytewise(&TABLES32C, buf), "{} bytes at {}", len, start);
+                // whichever way `feed` went, check the tables too
+                assert_eq!(!feed_sliced(&TABLES32, !0, buf), bytewise(&TABLES32, buf));
+                assert_eq!(!feed_sliced(&TABLES32C, !0, buf), bytewise(&TABLES32C, buf));
+            }
+        }
+        // split at odd places
+        let mut state = State32::new();
+        let mut state_c = State32C::new();
+        for chunk in input.chunks(13) {
+            state.feed(chunk);
+            state_c.feed(chunk);
+        }
+        assert_eq!(state.result(), bytewise(&TABLES32, input));
+        assert_eq!(state_c.result(), bytewise(&TABLES32C, input));
+    }
+
+    #[cfg(feature="unstable")]
+    #[bench]
+    fn feed_speed(bh: &mut test::Bencher) {
+        let input = include_bytes!("../data/test.large");
+        bh.iter(|| {
+            let mut state = State32::new();
+            state.feed(input);
+            state.result()
+        });
+        bh.bytes = input.len() as u64;
+    }
+
+    #[test]
+    fn check_values() {
+        let mut state = State32::new();
+        assert_eq!(state.result(), 0);
+        state.feed(b"123456789");
+        assert_eq!(state.result(), 0xcbf43926);
+        state.reset();
+        state.feed(b"The quick brown fox ");
+        state.feed(b"jumps over the lazy dog");
+        assert_eq!(state.result(), 0x414fa339);
+
+        let mut state = State32C::new();
+        assert_eq!(state.result(), 0);
+        state.feed(b"123456789");
+        assert_eq!(state.result(), 0xe3069283);
+        // from the iSCSI specification, RFC 3720
+        state.reset();
+        state.feed(&[0; 32]);
+        assert_eq!(state.result(), 0x8a9136aa);
+        state.reset();
+        state.feed(&[0xff; 32]);
+        assert_eq!(state.result(), 0x62a8ab43);
+    }
+
+    #[test]
+    fn engines() {
+        use super::{CRC_16_IBM, CRC_32, CRC_64_XZ};
+        // the check values of the CRC catalogue, for "123456789"
+        assert_eq!(CRC_16_IBM.checksum(b"123456789"), 0xbb3d);
+        assert_eq!(CRC_32.checksum(b"123456789"), 0xcbf43926);
+        assert_eq!(CRC_64_XZ.checksum(b"123456789"), 0x995dc9bbdf1939fa);
+
+        // an unreflected one, CRC-16/CCITT-FALSE
+        static CCITT: Engine = Engine::new(Params {
+            width: 16, poly: 0x1021, init: 0xffff, reflect: false, xorout: 0,
+        });
+        assert_eq!(CCITT.checksum(b"123456789"), 0x29b1);
+        // CRC-64/ECMA-182, with no init or xorout
+        static ECMA: Engine = Engine::new(Params {
+            width: 64, poly: 0x42f0e1eba9ea3693, init: 0, reflect: false, xorout: 0,
+        });
+        assert_eq!(ECMA.checksum(b"123456789"), 0x6c40df5f0b497347);
+
+        // the preset has to agree with `State32`
+        let input = include_bytes!("../data/test.txt");
+        let mut state = CRC_32.state();
+        for chunk in input.chunks(13) {
+            state.feed(chunk);
+        }
+        let mut fast = State32::new();
+        fast.feed(input);
+        assert_eq!(state.result(), fast.result() as u64);
+        state.reset();
+        assert_eq!(state.result(), 0);
+    }
+}
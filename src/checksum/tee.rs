@@ -0,0 +1,172 @@
+/*!
+
+Checksumming of data on its way through a writer or reader
+
+A `Tee` computes the checksum of everything written through it, so the
+checksum of the uncompressed data can be recorded while it is being
+compressed, without a second pass. A `TeeReader` does the same for the data
+read through it, such as the output of a decoder.
+
+# Example
+
+```rust
+use std::io::Write;
+use compress::checksum::Tee;
+use compress::Crc32;
+
+// any encoder can stand in for the vector here
+let mut tee = Tee::new(Vec::new(), Crc32::new());
+tee.write_all(b"123456789").unwrap();
+let (written, crc) = tee.finish();
+assert_eq!(&written[..], b"123456789");
+assert_eq!(crc.result(), 0xcbf43926);
+```
+
+*/
+
+use std::io::{self, Read, Write};
+
+use super::{adler, crc, xxhash};
+
+/// A running checksum
+pub trait Checksum {
+    /// Mutate the state for given data
+    fn feed(&mut self, buf: &[u8]);
+    /// Get checksum
+    fn result(&self) -> u32;
+    /// Reset the state
+    fn reset(&mut self);
+}
+
+impl Checksum for adler::State32 {
+    fn feed(&mut self, buf: &[u8]) { adler::State32::feed(self, buf) }
+    fn result(&self) -> u32 { adler::State32::result(self) }
+    fn reset(&mut self) { adler::State32::reset(self) }
+}
+
+impl Checksum for crc::State32 {
+    fn feed(&mut self, buf: &[u8]) { crc::State32::feed(self, buf) }
+    fn result(&self) -> u32 { crc::State32::result(self) }
+    fn reset(&mut self) { crc::State32::reset(self) }
+}
+
+impl Checksum for crc::State32C {
+    fn feed(&mut self, buf: &[u8]) { crc::State32C::feed(self, buf) }
+    fn result(&self) -> u32 { crc::State32C::result(self) }
+    fn reset(&mut self) { crc::State32C::reset(self) }
+}
+
+impl Checksum for xxhash::State32 {
+    fn feed(&mut self, buf: &[u8]) { xxhash::State32::feed(self, buf) }
+    fn result(&self) -> u32 { xxhash::State32::result(self) }
+    fn reset(&mut self) { xxhash::State32::reset(self) }
+}
+
+/// A writer feeding everything it passes on into a checksum
+pub struct Tee<W, H> {
+    w: W,
+    hash: H,
+}
+
+impl<W: Write, H: Checksum> Tee<W, H> {
+    /// Creates a new tee writing to `w` and checksumming with `hash`
+    pub fn new(w: W, hash: H) -> Tee<W, H> {
+        Tee { w, hash }
+    }
+
+    /// Returns the checksum of the data written so far
+    pub fn checksum(&self) -> u32 {
+        self.hash.result()
+    }
+
+    /// Returns a reference to the wrapped writer
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Returns the wrapped writer and the checksum state
+    pub fn finish(self) -> (W, H) {
+        (self.w, self.hash)
+    }
+}
+
+impl<W: Write, H: Checksum> Write for Tee<W, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // only what the writer accepted counts
+        let n = try!(self.w.write(buf));
+        self.hash.feed(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// A reader feeding everything it passes on into a checksum
+pub struct TeeReader<R, H> {
+    r: R,
+    hash: H,
+}
+
+impl<R: Read, H: Checksum> TeeReader<R, H> {
+    /// Creates a new tee reading from `r` and checksumming with `hash`
+    pub fn new(r: R, hash: H) -> TeeReader<R, H> {
+        TeeReader { r, hash }
+    }
+
+    /// Returns the checksum of the data read so far
+    pub fn checksum(&self) -> u32 {
+        self.hash.result()
+    }
+
+    /// Returns a reference to the wrapped reader
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+
+    /// Returns the wrapped reader and the checksum state
+    pub fn finish(self) -> (R, H) {
+        (self.r, self.hash)
+    }
+}
+
+impl<R: Read, H: Checksum> Read for TeeReader<R, H> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.r.read(buf));
+        self.hash.feed(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use super::{Checksum, Tee};
+    use super::super::{adler, crc};
+
+    #[test]
+    fn matches_direct_checksum() {
+        let input = include_bytes!("../data/test.txt");
+        let mut tee = Tee::new(Vec::new(), adler::State32::new());
+        for chunk in input.chunks(100) {
+            tee.write_all(chunk).unwrap();
+        }
+        let mut direct = adler::State32::new();
+        direct.feed(input);
+        assert_eq!(tee.checksum(), direct.result());
+
+        let (output, _) = tee.finish();
+        assert_eq!(&output[..], &input[..]);
+    }
+
+    #[test]
+    fn partial_writes() {
+        let mut buf = [0u8; 5];
+        let mut tee = Tee::new(&mut buf[..], crc::State32::new());
+        assert_eq!(tee.write(b"12345678").unwrap(), 5);
+        let mut direct = crc::State32::new();
+        direct.feed(b"12345");
+        assert_eq!(tee.checksum(), Checksum::result(&direct));
+    }
+}
@@ -0,0 +1,312 @@
+/*!
+
+xxHash checksums
+
+xxHash is a fast non-cryptographic hash, see
+https://github.com/Cyan4973/xxHash. The 32 bit variant is the checksum of
+the LZ4 frame format, for the frame header, the blocks and the content. The
+64 bit variant is faster on 64 bit machines. Both take a seed, which is 0
+unless given with `with_seed`.
+
+# Example
+
+```rust
+use compress::checksum::xxhash;
+
+let mut state = xxhash::State32::new();
+state.feed(b"a");
+state.feed(b"bc");
+assert_eq!(state.result(), 0x32d153ff);
+assert_eq!(xxhash::xxh32(b"abc", 0), 0x32d153ff);
+assert_eq!(xxhash::xxh64(b"abc", 0), 0x44bc2cf5ad770999);
+```
+
+*/
+
+const P32_1: u32 = 2654435761;
+const P32_2: u32 = 2246822519;
+const P32_3: u32 = 3266489917;
+const P32_4: u32 = 668265263;
+const P32_5: u32 = 374761393;
+
+const P64_1: u64 = 11400714785074694791;
+const P64_2: u64 = 14029467366897019727;
+const P64_3: u64 = 1609587929392839161;
+const P64_4: u64 = 9650029242287828579;
+const P64_5: u64 = 2870177450012600261;
+
+fn read32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read64(buf: &[u8]) -> u64 {
+    u64::from_le_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]])
+}
+
+fn round32(acc: u32, input: u32) -> u32 {
+    acc.wrapping_add(input.wrapping_mul(P32_2)).rotate_left(13).wrapping_mul(P32_1)
+}
+
+fn round64(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(P64_2)).rotate_left(31).wrapping_mul(P64_1)
+}
+
+fn merge64(acc: u64, v: u64) -> u64 {
+    (acc ^ round64(0, v)).wrapping_mul(P64_1).wrapping_add(P64_4)
+}
+
+/// Computes the 32 bit xxHash of `buf` in one go
+pub fn xxh32(buf: &[u8], seed: u32) -> u32 {
+    let mut state = State32::with_seed(seed);
+    state.feed(buf);
+    state.result()
+}
+
+/// Computes the 64 bit xxHash of `buf` in one go
+pub fn xxh64(buf: &[u8], seed: u64) -> u64 {
+    let mut state = State64::with_seed(seed);
+    state.feed(buf);
+    state.result()
+}
+
+/// xxHash state for 32 bits
+#[derive(Clone, Copy, Debug)]
+pub struct State32 {
+    seed: u32,
+    v: [u32; 4],
+    total: u64,
+    // the input short of a full 16 byte stripe
+    mem: [u8; 16],
+    mem_len: usize,
+}
+
+impl State32 {
+    /// Create a new state, with seed 0
+    pub fn new() -> State32 {
+        State32::with_seed(0)
+    }
+
+    /// Create a new state with the given seed
+    pub fn with_seed(seed: u32) -> State32 {
+        State32 {
+            seed,
+            v: [seed.wrapping_add(P32_1).wrapping_add(P32_2), seed.wrapping_add(P32_2),
+                seed, seed.wrapping_sub(P32_1)],
+            total: 0,
+            mem: [0; 16],
+            mem_len: 0,
+        }
+    }
+
+    fn stripe(&mut self, buf: &[u8]) {
+        for (i, v) in self.v.iter_mut().enumerate() {
+            *v = round32(*v, read32(&buf[4 * i..]));
+        }
+    }
+
+    /// Mutate the state for given data
+    pub fn feed(&mut self, mut buf: &[u8]) {
+        self.total += buf.len() as u64;
+        if self.mem_len > 0 {
+            let n = ::std::cmp::min(16 - self.mem_len, buf.len());
+            self.mem[self.mem_len..self.mem_len + n].copy_from_slice(&buf[..n]);
+            self.mem_len += n;
+            buf = &buf[n..];
+            if self.mem_len < 16 {
+                return
+            }
+            let mem = self.mem;
+            self.stripe(&mem);
+            self.mem_len = 0;
+        }
+        let mut stripes = buf.chunks_exact(16);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.mem[..rest.len()].copy_from_slice(rest);
+        self.mem_len = rest.len();
+    }
+
+    /// Get checksum
+    pub fn result(&self) -> u32 {
+        let v = &self.v;
+        let mut h = if self.total >= 16 {
+            v[0].rotate_left(1).wrapping_add(v[1].rotate_left(7))
+                .wrapping_add(v[2].rotate_left(12)).wrapping_add(v[3].rotate_left(18))
+        } else {
+            self.seed.wrapping_add(P32_5)
+        };
+        h = h.wrapping_add(self.total as u32);
+
+        let mut words = self.mem[..self.mem_len].chunks_exact(4);
+        for word in &mut words {
+            h = h.wrapping_add(read32(word).wrapping_mul(P32_3)).rotate_left(17).wrapping_mul(P32_4);
+        }
+        for &byte in words.remainder() {
+            h = h.wrapping_add((byte as u32).wrapping_mul(P32_5)).rotate_left(11).wrapping_mul(P32_1);
+        }
+
+        h ^= h >> 15;
+        h = h.wrapping_mul(P32_2);
+        h ^= h >> 13;
+        h = h.wrapping_mul(P32_3);
+        h ^ (h >> 16)
+    }
+
+    /// Reset the state, keeping the seed
+    pub fn reset(&mut self) {
+        *self = State32::with_seed(self.seed);
+    }
+}
+
+impl Default for State32 {
+    fn default() -> State32 {
+        State32::new()
+    }
+}
+
+/// xxHash state for 64 bits
+#[derive(Clone, Copy, Debug)]
+pub struct State64 {
+    seed: u64,
+    v: [u64; 4],
+    total: u64,
+    // the input short of a full 32 byte stripe
+    mem: [u8; 32],
+    mem_len: usize,
+}
+
+impl State64 {
+    /// Create a new state, with seed 0
+    pub fn new() -> State64 {
+        State64::with_seed(0)
+    }
+
+    /// Create a new state with the given seed
+    pub fn with_seed(seed: u64) -> State64 {
+        State64 {
+            seed,
+            v: [seed.wrapping_add(P64_1).wrapping_add(P64_2), seed.wrapping_add(P64_2),
+                seed, seed.wrapping_sub(P64_1)],
+            total: 0,
+            mem: [0; 32],
+            mem_len: 0,
+        }
+    }
+
+    fn stripe(&mut self, buf: &[u8]) {
+        for (i, v) in self.v.iter_mut().enumerate() {
+            *v = round64(*v, read64(&buf[8 * i..]));
+        }
+    }
+
+    /// Mutate the state for given data
+    pub fn feed(&mut self, mut buf: &[u8]) {
+        self.total += buf.len() as u64;
+        if self.mem_len > 0 {
+            let n = ::std::cmp::min(32 - self.mem_len, buf.len());
+            self.mem[self.mem_len..self.mem_len + n].copy_from_slice(&buf[..n]);
+            self.mem_len += n;
+            buf = &buf[n..];
+            if self.mem_len < 32 {
+                return
+            }
+            let mem = self.mem;
+            self.stripe(&mem);
+            self.mem_len = 0;
+        }
+        let mut stripes = buf.chunks_exact(32);
+        for stripe in &mut stripes {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.mem[..rest.len()].copy_from_slice(rest);
+        self.mem_len = rest.len();
+    }
+
+    /// Get checksum
+    pub fn result(&self) -> u64 {
+        let v = &self.v;
+        let mut h = if self.total >= 32 {
+            let h = v[0].rotate_left(1).wrapping_add(v[1].rotate_left(7))
+                        .wrapping_add(v[2].rotate_left(12)).wrapping_add(v[3].rotate_left(18));
+            v.iter().fold(h, |h, &v| merge64(h, v))
+        } else {
+            self.seed.wrapping_add(P64_5)
+        };
+        h = h.wrapping_add(self.total);
+
+        let mut rest = &self.mem[..self.mem_len];
+        while rest.len() >= 8 {
+            h ^= round64(0, read64(rest));
+            h = h.rotate_left(27).wrapping_mul(P64_1).wrapping_add(P64_4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            h ^= (read32(rest) as u64).wrapping_mul(P64_1);
+            h = h.rotate_left(23).wrapping_mul(P64_2).wrapping_add(P64_3);
+            rest = &rest[4..];
+        }
+        for &byte in rest {
+            h ^= (byte as u64).wrapping_mul(P64_5);
+            h = h.rotate_left(11).wrapping_mul(P64_1);
+        }
+
+        h ^= h >> 33;
+        h = h.wrapping_mul(P64_2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(P64_3);
+        h ^ (h >> 32)
+    }
+
+    /// Reset the state, keeping the seed
+    pub fn reset(&mut self) {
+        *self = State64::with_seed(self.seed);
+    }
+}
+
+impl Default for State64 {
+    fn default() -> State64 {
+        State64::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{xxh32, xxh64, State32, State64};
+
+    #[test]
+    fn check_values() {
+        assert_eq!(xxh32(b"", 0), 0x02cc5d05);
+        assert_eq!(xxh64(b"", 0), 0xef46db3751d8e999);
+        assert_eq!(xxh32(b"abc", 0), 0x32d153ff);
+        assert_eq!(xxh64(b"abc", 0), 0x44bc2cf5ad770999);
+        let spam = b"Nobody inspects the spammish repetition";
+        assert_eq!(xxh32(spam, 0), 0xe2293b2f);
+        assert_eq!(xxh64(spam, 0), 0xfbcea83c8a378bf1);
+    }
+
+    #[test]
+    fn split_feeds() {
+        let input = include_bytes!("../data/test.txt");
+        for &seed in &[0, 1, 0x9e3779b1] {
+            let (whole32, whole64) = (xxh32(input, seed), xxh64(input, seed as u64));
+            for &size in &[1, 7, 16, 31, 33, 1000] {
+                let mut state32 = State32::with_seed(seed);
+                let mut state64 = State64::with_seed(seed as u64);
+                for chunk in input.chunks(size) {
+                    state32.feed(chunk);
+                    state64.feed(chunk);
+                }
+                assert_eq!(state32.result(), whole32);
+                assert_eq!(state64.result(), whole64);
+            }
+            let mut state32 = State32::with_seed(seed);
+            state32.feed(b"something else");
+            state32.reset();
+            state32.feed(input);
+            assert_eq!(state32.result(), whole32);
+        }
+    }
+}
@@ -0,0 +1,796 @@
+//! Runtime listing and selection of the codecs compiled into this crate
+
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::str;
+
+#[cfg(any(feature = "embed", feature = "serde", feature = "flate", feature = "lz4"))]
+use io::LimitExceeded;
+use metrics;
+use pipeline::Pipeline;
+use Format;
+
+/// What one codec supports. More fields may be added, so it can't be
+/// built outside of this crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capability {
+    /// Path of the codec module below the crate root
+    pub name: &'static str,
+    /// Cargo feature the codec is gated behind, empty if it is always
+    /// compiled in
+    pub feature: &'static str,
+    /// Whether the feature was enabled when this crate was built. The
+    /// other fields tell what the codec supports once it is.
+    pub available: bool,
+    /// Whether data can be compressed
+    pub encode: bool,
+    /// Whether data can be decompressed
+    pub decode: bool,
+    /// Lowest and highest compression level the encoder accepts, if it
+    /// has levels at all
+    pub levels: Option<(u8, u8)>,
+    /// Whether a preset dictionary can be supplied
+    pub dictionary: bool,
+    /// Whether the codec works incrementally through `Read` and `Write`
+    /// adapters rather than on whole buffers
+    pub streaming: bool,
+}
+
+const fn codec(name: &'static str, feature: &'static str, available: bool, encode: bool) -> Capability {
+    Capability {
+        name,
+        feature,
+        available,
+        encode,
+        decode: true,
+        levels: None,
+        dictionary: false,
+        streaming: true,
+    }
+}
+
+// The capability of a codec gated behind a feature, with the fields which
+// differ from the defaults. Whether it is available is read off the same
+// feature name, so the list can't disagree with the build.
+macro_rules! gated {
+    ($name:expr, $feature:tt, encode: $encode:expr $(, $field:ident: $value:expr)*) => {
+        Capability { $($field: $value,)* ..codec($name, $feature, cfg!(feature = $feature), $encode) }
+    };
+}
+
+/// Lists the codecs of this crate, so that formats can be negotiated at
+/// runtime. Codecs whose feature this crate was built without are listed
+/// as well, with `available` unset, so that the feature missing for a
+/// format can be told.
+pub fn capabilities() -> Vec<Capability> {
+    vec![
+        codec("identity", "", true, true),
+        gated!("brotli", "brotli", encode: false),
+        gated!("bwt", "bwt", encode: true),
+        gated!("bwt::mtf", "bwt", encode: true),
+        gated!("bwt::dc", "bwt", encode: true, streaming: false),
+        gated!("bzip2", "bzip2", encode: true, levels: Some((1, 9))),
+        gated!("entropy::ans", "entropy", encode: true),
+        gated!("entropy::ari", "entropy", encode: true),
+        gated!("entropy::huff", "entropy", encode: true, streaming: false),
+        gated!("entropy::ppm", "entropy", encode: true),
+        gated!("entropy::range", "entropy", encode: true),
+        codec("filters", "", true, true),
+        gated!("flate", "flate", encode: true, levels: Some((0, 9))),
+        gated!("gzip", "gzip", encode: true, levels: Some((0, 9))),
+        gated!("lz4", "lz4", encode: true, dictionary: true),
+        gated!("lzma", "lzma", encode: false),
+        gated!("lzma::xz", "lzma", encode: false),
+        gated!("lzw", "lzw", encode: true),
+        gated!("rle", "rle", encode: true),
+        gated!("scramble", "scramble", encode: true),
+        gated!("zlib", "zlib", encode: true, levels: Some((0, 9))),
+        gated!("zstd", "zstd", encode: false),
+    ]
+}
+
+// Whether a feature listed by `capabilities` is compiled in
+#[cfg(test)]
+fn enabled(feature: &str) -> bool {
+    feature.is_empty() || capabilities().iter().any(|c| c.feature == feature && c.available)
+}
+
+/// The error carried by the `io::Error` returned when a codec or format
+/// is known, but the feature it needs wasn't enabled when this crate was
+/// built
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnsupportedFormat {
+    /// Name of the codec, as listed by `capabilities`, or of the pipeline
+    /// stage
+    pub name: &'static str,
+    /// Cargo feature to enable for it
+    pub feature: &'static str,
+}
+
+impl UnsupportedFormat {
+    /// Returns the error carried by `e`, if it is about a missing feature
+    pub fn find(e: &io::Error) -> Option<&UnsupportedFormat> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<UnsupportedFormat>())
+    }
+}
+
+impl fmt::Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} is not compiled in, it needs the `{}` feature", self.name, self.feature)
+    }
+}
+
+impl Error for UnsupportedFormat {}
+
+impl From<UnsupportedFormat> for io::Error {
+    fn from(e: UnsupportedFormat) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// A compression algorithm selected at runtime. The codec modules provide
+/// implementations holding their settings, like `flate::Flate` or
+/// `lz4::Lz4`, and `codec_by_name` creates one with the default settings.
+///
+/// Empty input is a stream like any other: every codec compresses it to a
+/// complete, non-empty stream which decodes back to nothing, while decoding
+/// no bytes at all fails with `ErrorKind::UnexpectedEof`. A missing or
+/// truncated-to-nothing stream is never taken for empty data. `Identity`
+/// is the exception, its streams being the data itself.
+///
+/// ```rust
+/// use std::io::{Read, Write};
+///
+/// let codec = compress::codec_by_name("lz4").unwrap();
+/// let mut compressed = Vec::new();
+/// let mut e = codec.encoder(Box::new(&mut compressed)).unwrap();
+/// e.write_all(b"hello hello hello").unwrap();
+/// e.finish().unwrap();
+///
+/// let mut decoded = Vec::new();
+/// codec.decoder(Box::new(&compressed[..])).unwrap().read_to_end(&mut decoded).unwrap();
+/// assert_eq!(&decoded[..], b"hello hello hello");
+/// ```
+pub trait Codec: Send + Sync {
+    /// Returns the name of the codec, as listed by `capabilities`
+    fn name(&self) -> &'static str;
+
+    /// Returns an encoder compressing what is written to it into `w`. The
+    /// stream is only complete once the encoder is finished.
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>>;
+
+    /// Returns a reader decompressing the data read from `r`
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>>;
+
+    /// Returns whether the streams of the codec are the data as it is, like
+    /// those of `Identity`, so that decoding them can be skipped
+    fn is_identity(&self) -> bool {
+        false
+    }
+}
+
+/// An encoder returned by `Codec::encoder`. Dropping it without calling
+/// `finish` leaves the stream truncated.
+pub trait Finish: Write {
+    /// Writes the end of the stream and flushes the underlying writer
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Returns the codec with the given name, one of "bwt", "flate", "gzip",
+/// "lz4", "rle" or "zlib", with its default settings, or `Identity` for
+/// "identity" and "store". `None` is returned for unknown names and codecs
+/// whose feature is disabled, which `require_codec` tells apart.
+pub fn codec_by_name(name: &str) -> Option<Box<dyn Codec>> {
+    match name {
+        "identity" | "store" => Some(Box::new(Identity)),
+        #[cfg(feature = "bwt")]
+        "bwt" => Some(Box::new(::bwt::Bwt::default())),
+        #[cfg(feature = "flate")]
+        "flate" => Some(Box::new(::flate::Flate::default())),
+        #[cfg(feature = "gzip")]
+        "gzip" => Some(Box::new(::gzip::Gzip::default())),
+        #[cfg(feature = "lz4")]
+        "lz4" => Some(Box::new(::lz4::Lz4::default())),
+        #[cfg(feature = "rle")]
+        "rle" => Some(Box::new(::rle::Rle::default())),
+        #[cfg(feature = "zlib")]
+        "zlib" => Some(Box::new(::zlib::Zlib::default())),
+        _ => None,
+    }
+}
+
+/// Returns the codec with the given name like `codec_by_name`, failing with
+/// an `UnsupportedFormat` error naming the feature to enable if the codec
+/// isn't compiled in, and with `InvalidInput` if the name is unknown
+pub fn require_codec(name: &str) -> io::Result<Box<dyn Codec>> {
+    if let Some(codec) = codec_by_name(name) {
+        return Ok(codec)
+    }
+    Err(missing_feature(name).unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("unknown codec {}", name))
+    }))
+}
+
+/// The codec leaving the data as it is, so that code choosing a codec at
+/// runtime handles uncompressed data the same way as the rest, listed as
+/// "identity" by `capabilities`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Identity;
+
+struct Passthrough<'a>(Box<dyn Write + 'a>);
+
+impl<'a> Write for Passthrough<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+impl<'a> Finish for Passthrough<'a> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Codec for Identity {
+    fn name(&self) -> &'static str { "identity" }
+
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>> {
+        metrics::encoder("identity", w, |w| Ok(Box::new(Passthrough(w))))
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        metrics::decoder("identity", r, Ok)
+    }
+
+    fn is_identity(&self) -> bool {
+        true
+    }
+}
+
+/// Tells the format of a stream from the bytes buffered in `r`, without
+/// consuming them. Formats with magic bytes are recognized as described by
+/// `Format::from_magic`. Other data is taken for raw DEFLATE if its start
+/// decodes without errors, which gets more reliable the more bytes are
+/// buffered and can't be ruled out for short inputs.
+pub fn detect<R: BufRead>(mut r: R) -> io::Result<Option<Format>> {
+    let prefix = try!(r.fill_buf());
+    if let Some(format) = Format::from_magic(prefix) {
+        return Ok(Some(format))
+    }
+    Ok(if looks_like_deflate(prefix) { Some(Format::Deflate) } else { None })
+}
+
+// how much of its output `detect` inflates at most
+#[cfg(feature = "flate")]
+const DETECT_OUTPUT: u64 = 64 << 10;
+
+#[cfg(feature = "flate")]
+fn looks_like_deflate(prefix: &[u8]) -> bool {
+    use flate;
+    // block type 3 is reserved
+    if prefix.is_empty() || (prefix[0] >> 1) & 3 == 3 {
+        return false
+    }
+    // a few blocks are enough to tell, and a short prefix may inflate to a
+    // lot of data
+    match io::copy(&mut flate::Decoder::new(prefix).take(DETECT_OUTPUT), &mut io::sink()) {
+        Ok(_) => true,
+        Err(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+    }
+}
+
+#[cfg(not(feature = "flate"))]
+fn looks_like_deflate(_: &[u8]) -> bool {
+    false
+}
+
+// The name `capabilities` lists for the decoder of a format
+fn codec_name(format: Format) -> &'static str {
+    match format {
+        Format::Gzip => "gzip",
+        Format::Zlib => "zlib",
+        Format::Deflate => "flate",
+        Format::Lz4Frame | Format::Lz4Block => "lz4",
+        Format::Bzip2 => "bzip2",
+        Format::BwtPipe | Format::LegacyBwt => "bwt",
+        Format::Rle => "rle",
+    }
+}
+
+/// Decodes a stream in any format `detect` recognizes, picking the decoder
+/// from its first bytes
+///
+/// ```rust
+/// use std::io::Read;
+/// use compress::{DecoderAny, Format};
+///
+/// let compressed = compress::lz4::compress_to_vec(b"some data");
+/// let mut d = DecoderAny::new(&compressed[..]).unwrap();
+/// assert_eq!(d.format(), Format::Lz4Frame);
+/// let mut decoded = Vec::new();
+/// d.read_to_end(&mut decoded).unwrap();
+/// assert_eq!(&decoded[..], b"some data");
+/// ```
+pub struct DecoderAny<'a> {
+    format: Format,
+    inner: Box<dyn Read + 'a>,
+}
+
+impl<'a> DecoderAny<'a> {
+    /// Detects the format of `r` and creates its decoder. Fails with
+    /// `InvalidData` if the format isn't recognized and with `InvalidInput`
+    /// if its decoder isn't compiled in, carrying an `UnsupportedFormat`
+    /// naming the missing feature.
+    pub fn new<R: BufRead + 'a>(mut r: R) -> io::Result<DecoderAny<'a>> {
+        let format = match try!(detect(&mut r)) {
+            Some(format) => format,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              "unrecognized compressed format")),
+        };
+        let inner = try!(metrics::decoder(codec_name(format), Box::new(r), |r| decoder(format, r)));
+        Ok(DecoderAny { format, inner })
+    }
+
+    /// Returns the detected format
+    pub fn format(&self) -> Format {
+        self.format
+    }
+}
+
+impl<'a> Read for DecoderAny<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+// One-shot compression for the modules storing whole buffers in one of the
+// formats, `None` meaning stored as is
+#[cfg(any(feature = "embed", feature = "serde"))]
+pub fn compress(format: Option<Format>, data: &[u8]) -> io::Result<Vec<u8>> {
+    match format {
+        None => Ok(data.to_vec()),
+        #[cfg(feature = "bzip2")]
+        Some(Format::Bzip2) => {
+            use bzip2;
+            let mut e = bzip2::Encoder::new(Vec::new());
+            try!(e.write_all(data));
+            let (out, result) = e.finish();
+            result.map(|_| out)
+        }
+        #[cfg(feature = "flate")]
+        Some(Format::Deflate) => {
+            use flate;
+            let mut e = flate::Encoder::new(Vec::new());
+            try!(e.write_all(data));
+            let (out, result) = e.finish();
+            result.map(|_| out)
+        }
+        #[cfg(feature = "gzip")]
+        Some(Format::Gzip) => {
+            use gzip;
+            let mut e = gzip::Encoder::new(Vec::new());
+            try!(e.write_all(data));
+            let (out, result) = e.finish();
+            result.map(|_| out)
+        }
+        #[cfg(feature = "lz4")]
+        Some(Format::Lz4Frame) => {
+            use lz4;
+            let mut e = lz4::Encoder::new(Vec::new());
+            try!(e.write_all(data));
+            let (out, result) = e.finish();
+            result.map(|_| out)
+        }
+        #[cfg(feature = "rle")]
+        Some(Format::Rle) => {
+            use rle;
+            let mut e = rle::Encoder::new(Vec::new());
+            try!(e.write_all(data));
+            let (out, result) = e.finish();
+            result.map(|_| out)
+        }
+        Some(format) => unavailable(format),
+    }
+}
+
+// The inverse of `compress`, the format given by its id or 0 for stored,
+// failing with `LimitExceeded` if `out` would take more than `limit` bytes
+#[cfg(any(feature = "embed", feature = "serde"))]
+pub fn decompress(id: u8, data: &[u8], out: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    out.clear();
+    if id == 0 {
+        out.extend_from_slice(data);
+    } else {
+        let format = match Format::from_id(id) {
+            Some(format) => format,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "unknown format id")),
+        };
+        // one byte past the limit tells whether more remain
+        let d = try!(decoder(format, data));
+        try!(d.take((limit as u64).saturating_add(1)).read_to_end(out));
+    }
+    if out.len() > limit {
+        out.clear();
+        return Err(io::Error::new(io::ErrorKind::InvalidData, LimitExceeded { limit }))
+    }
+    Ok(())
+}
+
+// Streaming decoder for the formats `compress` produces, and the ones
+// `detect` recognizes
+pub fn decoder<'a, R: Read + 'a>(format: Format, r: R) -> io::Result<Box<dyn Read + 'a>> {
+    match format {
+        #[cfg(feature = "bzip2")]
+        Format::Bzip2 => {
+            use bzip2;
+            Ok(Box::new(bzip2::Decoder::new(r)))
+        }
+        #[cfg(feature = "bwt")]
+        Format::LegacyBwt => {
+            use bwt;
+            Ok(Box::new(bwt::Decoder::legacy(r, true)))
+        }
+        #[cfg(feature = "flate")]
+        Format::Deflate => {
+            use flate;
+            Ok(Box::new(flate::Decoder::new(r)))
+        }
+        #[cfg(feature = "gzip")]
+        Format::Gzip => {
+            use gzip;
+            Ok(Box::new(gzip::MultiDecoder::new(r)))
+        }
+        #[cfg(feature = "lz4")]
+        Format::Lz4Frame => {
+            use lz4;
+            Ok(Box::new(lz4::Decoder::new(r)))
+        }
+        #[cfg(feature = "rle")]
+        Format::Rle => {
+            use rle;
+            Ok(Box::new(rle::Decoder::new(r)))
+        }
+        #[cfg(feature = "zlib")]
+        Format::Zlib => {
+            use zlib;
+            Ok(Box::new(zlib::Decoder::new(r)))
+        }
+        Format::BwtPipe => tool_decoder(r),
+        _ => {
+            drop(r);
+            unavailable(format)
+        }
+    }
+}
+
+// Archives of the command line tool: the magic bytes, the number of passes
+// and their names, each preceded by its length, then the data run through
+// the passes from the last to the first. A pass is one of the tool's own or
+// a pipeline spec.
+fn tool_decoder<'a, R: Read + 'a>(mut r: R) -> io::Result<Box<dyn Read + 'a>> {
+    let mut header = [0; 5];
+    try!(r.read_exact(&mut header));
+    if header[..4] != *b"r!cs" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a compress tool archive"))
+    }
+    let mut passes = Vec::new();
+    for _ in 0..header[4] {
+        let mut len = [0];
+        try!(r.read_exact(&mut len));
+        let mut name = vec![0; len[0] as usize];
+        try!(r.read_exact(&mut name));
+        passes.push(name);
+    }
+    let mut d: Box<dyn Read + 'a> = Box::new(r);
+    for name in passes.iter() {
+        d = match &name[..] {
+            b"dummy" => d,
+            // archives of earlier versions hold headerless streams
+            #[cfg(feature = "bwt")]
+            b"bwt" => Box::new(::bwt::Decoder::legacy(d, true)),
+            b"scramble" => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "scrambled archives need their key"))
+            }
+            spec => match str::from_utf8(spec).ok().and_then(|spec| spec.parse::<Pipeline>().ok()) {
+                Some(pipeline) => try!(pipeline.decoder(d)),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                                  "unknown pass in compress tool archive")),
+            },
+        };
+    }
+    Ok(d)
+}
+
+// Runs `compress` writing into `dst`, which gives up as soon as the output
+// doesn't fit, for the `compress_into_limit` functions of the codec modules
+#[cfg(any(feature = "flate", feature = "lz4"))]
+pub fn into_limit<F>(dst: &mut [u8], compress: F) -> Result<usize, LimitExceeded>
+    where F: FnOnce(&mut io::Cursor<&mut [u8]>) -> io::Result<()>
+{
+    let limit = dst.len();
+    let mut w = io::Cursor::new(dst);
+    match compress(&mut w) {
+        Ok(()) => Ok(w.position() as usize),
+        // a full slice is the only way writing to it fails
+        Err(_) => Err(LimitExceeded { limit }),
+    }
+}
+
+// Checks the one-shot functions of a codec module against each other: the
+// output of `compress_into_limit` matches `compress_to_vec` when it fits and
+// is refused one byte short
+#[cfg(all(test, any(feature = "flate", feature = "lz4")))]
+pub fn check_one_shot(compress_to_vec: fn(&[u8]) -> Vec<u8>,
+                      decompress_to_vec: fn(&[u8]) -> io::Result<Vec<u8>>,
+                      compress_into_limit: fn(&[u8], &mut [u8]) -> Result<usize, LimitExceeded>) {
+    let input = include_bytes!("data/test.txt");
+    let compressed = compress_to_vec(input);
+    assert_eq!(&decompress_to_vec(&compressed).unwrap()[..], &input[..]);
+    assert!(decompress_to_vec(&compressed[..compressed.len() / 2]).is_err());
+
+    let mut page = vec![0; compressed.len()];
+    assert_eq!(compress_into_limit(input, &mut page), Ok(compressed.len()));
+    assert!(page == compressed);
+    let err = compress_into_limit(input, &mut page[..compressed.len() - 1]).unwrap_err();
+    assert_eq!(err.limit, compressed.len() - 1);
+}
+
+/// The outcome of `compare`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    /// Both streams hold the same data, of this length
+    Equal(u64),
+    /// The data differs from this offset on. If one stream holds a prefix of
+    /// the other, this is the length of the shorter one.
+    Differ(u64),
+}
+
+/// Decompresses `a` and `b`, of the formats `fmt_a` and `fmt_b` or `None`
+/// for uncompressed data, and compares what comes out, for instance to check
+/// a transcoded archive against its original. Both are read in step through
+/// small buffers, so memory use doesn't depend on the size of the data, and
+/// reading stops at the first difference. Fails if either stream can't be
+/// read or decoded.
+pub fn compare<A: Read, B: Read>(a: A, b: B, fmt_a: Option<Format>, fmt_b: Option<Format>)
+                                 -> io::Result<Comparison> {
+    const CHUNK: usize = 32 << 10;
+    let mut a = match fmt_a {
+        Some(format) => try!(decoder(format, a)),
+        None => Box::new(a),
+    };
+    let mut b = match fmt_b {
+        Some(format) => try!(decoder(format, b)),
+        None => Box::new(b),
+    };
+    let (mut buf_a, mut buf_b) = (vec![0; CHUNK], vec![0; CHUNK]);
+    let mut offset = 0;
+    loop {
+        let na = try!(read_full(&mut a, &mut buf_a));
+        let nb = try!(read_full(&mut b, &mut buf_b));
+        let n = cmp::min(na, nb);
+        if let Some(i) = buf_a[..n].iter().zip(&buf_b[..n]).position(|(x, y)| x != y) {
+            return Ok(Comparison::Differ(offset + i as u64))
+        }
+        offset += n as u64;
+        if na != nb {
+            return Ok(Comparison::Differ(offset))
+        }
+        if na < CHUNK {
+            return Ok(Comparison::Equal(offset))
+        }
+    }
+}
+
+// Fills `buf` unless the stream ends first, returning how much was read
+fn read_full<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+// The `UnsupportedFormat` error for a codec which is compiled out
+fn missing_feature(name: &str) -> Option<io::Error> {
+    capabilities().into_iter().find(|c| c.name == name && !c.available)
+                  .map(|c| UnsupportedFormat { name: c.name, feature: c.feature }.into())
+}
+
+fn unavailable<T>(format: Format) -> io::Result<T> {
+    Err(missing_feature(codec_name(format)).unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "format not supported here")
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use super::{capabilities, codec_by_name, compare, detect, enabled, require_codec, Comparison,
+                DecoderAny, UnsupportedFormat};
+    use pipeline::Pipeline;
+    use Format;
+
+    #[test]
+    fn listed_once() {
+        let list = capabilities();
+        for (i, c) in list.iter().enumerate() {
+            assert!(c.encode || c.decode);
+            assert!(list[i + 1..].iter().all(|d| d.name != c.name));
+        }
+        if cfg!(feature = "lz4") {
+            assert!(list.iter().any(|c| c.name == "lz4" && c.encode));
+        }
+    }
+
+    #[test]
+    fn codecs_by_name() {
+        let input = include_bytes!("data/test.txt");
+        for name in &["bwt", "flate", "gzip", "lz4", "rle", "zlib"] {
+            let codec = match codec_by_name(name) {
+                Some(codec) => codec,
+                None => continue,
+            };
+            assert_eq!(codec.name(), *name);
+            assert!(capabilities().iter().any(|c| c.name == *name && c.encode));
+
+            let mut compressed = Vec::new();
+            let mut e = codec.encoder(Box::new(&mut compressed)).unwrap();
+            e.write_all(&input[..]).unwrap();
+            e.finish().unwrap();
+            let mut decoded = Vec::new();
+            codec.decoder(Box::new(&compressed[..])).unwrap().read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], &input[..], "{} didn't roundtrip", name);
+        }
+        assert!(codec_by_name("nope").is_none());
+    }
+
+    #[test]
+    fn identity() {
+        for name in &["identity", "store"] {
+            let codec = codec_by_name(name).unwrap();
+            assert_eq!(codec.name(), "identity");
+            let mut out = Vec::new();
+            let mut e = codec.encoder(Box::new(&mut out)).unwrap();
+            e.write_all(b"as is").unwrap();
+            e.finish().unwrap();
+            assert_eq!(&out[..], b"as is");
+            let mut decoded = Vec::new();
+            codec.decoder(Box::new(&out[..])).unwrap().read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], b"as is");
+        }
+        assert!(capabilities().iter().any(|c| c.name == "identity" && c.encode && c.decode));
+    }
+
+    #[test]
+    fn empty_streams() {
+        for name in &["bwt", "flate", "gzip", "lz4", "rle", "zlib"] {
+            let codec = match codec_by_name(name) {
+                Some(codec) => codec,
+                None => continue,
+            };
+            let mut compressed = Vec::new();
+            codec.encoder(Box::new(&mut compressed)).unwrap().finish().unwrap();
+            assert!(!compressed.is_empty(), "{} wrote nothing for empty input", name);
+            let mut decoded = Vec::new();
+            codec.decoder(Box::new(&compressed[..])).unwrap().read_to_end(&mut decoded).unwrap();
+            assert!(decoded.is_empty());
+
+            let err = codec.decoder(Box::new(&b""[..]))
+                           .and_then(|mut d| d.read_to_end(&mut decoded)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{} on no input", name);
+        }
+    }
+
+    #[test]
+    fn compares() {
+        let input = include_bytes!("data/test.txt");
+        let lz4 = include_bytes!("data/test.lz4.1");
+        let zlib = include_bytes!("data/test.z.5");
+        if cfg!(all(feature = "lz4", feature = "zlib")) {
+            assert_eq!(compare(&lz4[..], &zlib[..], Some(Format::Lz4Frame), Some(Format::Zlib))
+                           .unwrap(), Comparison::Equal(input.len() as u64));
+            let mut changed = input.to_vec();
+            changed[2999] ^= 1;
+            assert_eq!(compare(&changed[..], &zlib[..], None, Some(Format::Zlib)).unwrap(),
+                       Comparison::Differ(2999));
+            assert!(compare(&zlib[..], &zlib[..], Some(Format::Lz4Frame), None).is_err());
+
+            // many buffers worth
+            let large = include_bytes!("data/test.large");
+            let large_zlib = include_bytes!("data/test.large.z.5");
+            assert_eq!(compare(&large[..], &large_zlib[..], None, Some(Format::Zlib)).unwrap(),
+                       Comparison::Equal(large.len() as u64));
+        }
+        if cfg!(feature = "gzip") {
+            // a prefix differs where it ends
+            let gz = include_bytes!("data/test.gz");
+            assert_eq!(compare(&input[..3000], &gz[..], None, Some(Format::Gzip)).unwrap(),
+                       Comparison::Differ(3000));
+        }
+        assert_eq!(compare(&b""[..], &b""[..], None, None).unwrap(), Comparison::Equal(0));
+    }
+
+    #[test]
+    fn missing_features() {
+        let e = require_codec("nope").err().unwrap();
+        assert!(UnsupportedFormat::find(&e).is_none());
+        for c in capabilities() {
+            match require_codec(c.name) {
+                Ok(codec) => assert!(c.available && codec.name() == c.name),
+                Err(e) => match UnsupportedFormat::find(&e) {
+                    Some(u) => assert!(!c.available && u.feature == c.feature, "{}", c.name),
+                    // not one of the codecs `codec_by_name` knows
+                    None => assert!(c.available, "{}", c.name),
+                },
+            }
+        }
+
+        let gzip = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let d = DecoderAny::new(&gzip[..]);
+        match d {
+            Ok(ref d) => assert!(cfg!(feature = "gzip") && d.format() == Format::Gzip),
+            Err(ref e) => assert_eq!(UnsupportedFormat::find(e).map(|u| u.feature), Some("gzip")),
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn corrupt_lz4() {
+        // the first sequence of the first block is one literal and a match
+        // one byte back, which now points before the start of the output
+        let mut stream = include_bytes!("data/test.lz4.1").to_vec();
+        assert_eq!(&stream[11..14], &[0x12, b'0', 0x01]);
+        stream[13] = 0x02;
+        let mut d = DecoderAny::new(&stream[..]).unwrap();
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn detects() {
+        let input = include_bytes!("data/test.txt");
+        let streams: [(&[u8], Format); 4] = [
+            (include_bytes!("data/test.gz"), Format::Gzip),
+            (include_bytes!("data/test.z.5"), Format::Zlib),
+            (include_bytes!("data/test.lz4.1"), Format::Lz4Frame),
+            (include_bytes!("data/test.txt.bz2"), Format::Bzip2),
+        ];
+        // an archive of the command line tool, with a pass of its own and
+        // a pipeline
+        let pipeline = "mtf|rle".parse::<Pipeline>().unwrap();
+        let mut e = pipeline.encoder(b"r!cs\x02\x05dummy\x07mtf|rle".to_vec()).unwrap();
+        e.write_all(input).unwrap();
+        let (archive, result) = e.finish();
+        result.unwrap();
+
+        for &(stream, format) in streams.iter().chain(Some((&archive[..], Format::BwtPipe)).iter()) {
+            assert_eq!(detect(stream).unwrap(), Some(format));
+            match DecoderAny::new(stream) {
+                Ok(mut d) => {
+                    let mut out = Vec::new();
+                    d.read_to_end(&mut out).unwrap();
+                    // test.gz holds more than test.txt
+                    assert!(out.starts_with(&input[..]), "{:?}", format);
+                }
+                Err(e) => match UnsupportedFormat::find(&e) {
+                    Some(u) => assert!(!enabled(u.feature), "{:?}", format),
+                    None => panic!("{:?}: {}", format, e),
+                },
+            }
+        }
+        if cfg!(feature = "flate") {
+            let stream = ::flate::compress_to_vec(&input[..]);
+            assert_eq!(detect(&stream[..]).unwrap(), Some(Format::Deflate));
+        }
+        assert_eq!(detect(&input[..]).unwrap(), None);
+        assert!(DecoderAny::new(&input[..]).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+/*!
+
+Differential checking of the DEFLATE decoder against the system zlib
+
+Unusual but valid streams, like dynamic Huffman blocks with incomplete or
+oddly shaped code tables, are where decoders drift from the specification.
+`check_inflate` decodes the same raw DEFLATE input with `flate::Decoder`
+and with the zlib linked into the program, and reports a `Divergence` if
+they disagree on the output or on whether the input is valid at all. It can
+be called from a fuzzer with arbitrary bytes, or from tests.
+
+This needs the `system-zlib` feature, which is only meant for development:
+it links the system's `libz`, and is not enabled by default.
+
+# Example
+
+```rust
+use compress::crosscheck;
+
+crosscheck::check_inflate(&[0x4b, 0x4c, 0x4a, 0x06, 0x00]).unwrap();
+```
+
+*/
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+
+use flate;
+
+// zlib.h, with the allocation functions left to zlib
+#[repr(C)]
+struct ZStream {
+    next_in: *const u8,
+    avail_in: c_uint,
+    total_in: c_ulong,
+    next_out: *mut u8,
+    avail_out: c_uint,
+    total_out: c_ulong,
+    msg: *const c_char,
+    state: *mut c_void,
+    zalloc: *mut c_void,
+    zfree: *mut c_void,
+    opaque: *mut c_void,
+    data_type: c_int,
+    adler: c_ulong,
+    reserved: c_ulong,
+}
+
+const Z_OK: c_int = 0;
+const Z_STREAM_END: c_int = 1;
+const Z_BUF_ERROR: c_int = -5;
+const Z_NO_FLUSH: c_int = 0;
+
+#[link(name = "z")]
+extern "C" {
+    fn zlibVersion() -> *const c_char;
+    fn inflateInit2_(strm: *mut ZStream, window_bits: c_int, version: *const c_char,
+                     stream_size: c_int) -> c_int;
+    fn inflate(strm: *mut ZStream, flush: c_int) -> c_int;
+    fn inflateEnd(strm: *mut ZStream) -> c_int;
+}
+
+/// Decodes the raw DEFLATE stream at the start of `data` with the system
+/// zlib, failing with zlib's message if it rejects the stream
+pub fn system_inflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut buf = [0u8; 32 * 1024];
+    unsafe {
+        let mut strm: ZStream = mem::zeroed();
+        // negative window bits for a raw stream, without a zlib header
+        let ret = inflateInit2_(&mut strm, -15, zlibVersion(), mem::size_of::<ZStream>() as c_int);
+        if ret != Z_OK {
+            return Err(format!("inflateInit2 failed: {}", ret))
+        }
+        strm.next_in = data.as_ptr();
+        strm.avail_in = data.len() as c_uint;
+        let result = loop {
+            strm.next_out = buf.as_mut_ptr();
+            strm.avail_out = buf.len() as c_uint;
+            let ret = inflate(&mut strm, Z_NO_FLUSH);
+            out.extend_from_slice(&buf[..buf.len() - strm.avail_out as usize]);
+            match ret {
+                Z_STREAM_END => break Ok(()),
+                Z_OK => continue,
+                Z_BUF_ERROR if strm.avail_in == 0 => break Err("truncated stream".to_string()),
+                _ if !strm.msg.is_null() => {
+                    let msg = ::std::ffi::CStr::from_ptr(strm.msg);
+                    break Err(msg.to_string_lossy().into_owned())
+                }
+                _ => break Err(format!("inflate failed: {}", ret)),
+            }
+        };
+        inflateEnd(&mut strm);
+        result.map(|()| out)
+    }
+}
+
+/// How `flate::Decoder` and the system zlib disagreed on an input
+#[derive(Debug)]
+pub enum Divergence {
+    /// Both decoded the input, to different data
+    Output {
+        /// Length of our output
+        ours: usize,
+        /// Length of zlib's output
+        zlib: usize,
+        /// Offset of the first byte which differs
+        offset: usize,
+    },
+    /// Only zlib accepted the input, our decoder failed with this error
+    OnlyZlibAccepts(io::Error),
+    /// Only our decoder accepted the input, zlib failed with this message
+    OnlyOursAccepts(String),
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Divergence::Output { ours, zlib, offset } => {
+                write!(f, "outputs differ at offset {} ({} bytes decoded, zlib {})",
+                       offset, ours, zlib)
+            }
+            Divergence::OnlyZlibAccepts(ref e) => write!(f, "only zlib accepts the input, we fail with: {}", e),
+            Divergence::OnlyOursAccepts(ref msg) => write!(f, "only we accept the input, zlib fails with: {}", msg),
+        }
+    }
+}
+
+impl Error for Divergence {}
+
+/// Decodes `data` as a raw DEFLATE stream with both decoders, see the
+/// module documentation. Agreeing to reject the input is not a divergence,
+/// even if the two errors differ.
+pub fn check_inflate(data: &[u8]) -> Result<(), Divergence> {
+    let mut ours = Vec::new();
+    let ours = flate::Decoder::new(data).read_to_end(&mut ours).map(|_| ours);
+    match (ours, system_inflate(data)) {
+        (Ok(ours), Ok(zlib)) => {
+            if ours == zlib {
+                return Ok(())
+            }
+            let offset = ours.iter().zip(zlib.iter()).position(|(a, b)| a != b)
+                             .unwrap_or(::std::cmp::min(ours.len(), zlib.len()));
+            Err(Divergence::Output { ours: ours.len(), zlib: zlib.len(), offset })
+        }
+        (Err(e), Ok(_)) => Err(Divergence::OnlyZlibAccepts(e)),
+        (Ok(_), Err(msg)) => Err(Divergence::OnlyOursAccepts(msg)),
+        (Err(_), Err(_)) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use flate;
+
+    fn compressed(level: u8) -> Vec<u8> {
+        let mut e = flate::Encoder::with_level(Vec::new(), flate::CompressionLevel::Level(level));
+        e.write_all(include_bytes!("data/test.txt")).unwrap();
+        let (data, result) = e.finish();
+        result.unwrap();
+        data
+    }
+
+    #[test]
+    fn agrees_on_valid_streams() {
+        for level in 0..10 {
+            let data = compressed(level);
+            super::check_inflate(&data).unwrap();
+            assert_eq!(&super::system_inflate(&data).unwrap()[..], &include_bytes!("data/test.txt")[..]);
+        }
+    }
+
+    #[test]
+    fn agrees_on_mutated_streams() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let originals = (0..10).map(compressed).collect::<Vec<_>>();
+        for round in 0..20_000 {
+            let mut data = originals[round % originals.len()].clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let at = rng.gen_range(0, data.len());
+                data[at] ^= 1 << rng.gen_range(0, 8);
+            }
+            if rng.gen::<bool>() {
+                let len = rng.gen_range(0, data.len());
+                data.truncate(len);
+            }
+            if let Err(e) = super::check_inflate(&data) {
+                panic!("round {}: {}", round, e);
+            }
+        }
+    }
+
+    #[test]
+    fn agrees_on_random_bytes() {
+        let mut rng = StdRng::seed_from_u64(0xb17e5);
+        for round in 0..50_000 {
+            let mut data = vec![0; rng.gen_range(1, 200)];
+            rng.fill(&mut data[..]);
+            // mostly dynamic blocks, the ones with code tables to get wrong
+            data[0] = data[0] & !6 | 4;
+            if let Err(e) = super::check_inflate(&data) {
+                panic!("round {} ({:?}): {}", round, data, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+/*!
+
+Chunk-level deduplication. Requires `dedup` feature, enabled by default
+
+Streams are cut into content-defined chunks with a gear rolling hash, so an
+insertion only moves the chunk boundaries next to it. Every chunk is
+recorded with its Adler-32 checksum as a weak hash and a strong hash of the
+caller's choice, and chunks whose hashes were already seen in any stream
+added to the same `Index` are marked as duplicates.
+
+The crate has no cryptographic hash, so the strong hash is a function
+supplied by the caller, typically a SHA-256 from another crate.
+
+# Example
+
+```rust
+use compress::dedup;
+
+// a toy strong hash, use a real digest in practice
+let mut index = dedup::Index::new(|chunk: &[u8]| chunk.to_vec());
+let data: Vec<u8> = (0..100000u32).map(|i| (i.wrapping_mul(i) >> 7) as u8).collect();
+let first = index.add_stream(&data[..]).unwrap();
+let second = index.add_stream(&data[..]).unwrap();
+for chunk in index.chunks().iter().filter(|c| c.stream == second) {
+    assert!(chunk.duplicate_of.map_or(false, |(s, _)| s == first));
+}
+```
+
+*/
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io::{self, Read};
+
+use checksum::adler;
+
+/// Default smallest chunk size
+pub const MIN_CHUNK: usize = 2 << 10;
+/// Default average chunk size, a power of two
+pub const AVG_CHUNK: usize = 8 << 10;
+/// Default largest chunk size
+pub const MAX_CHUNK: usize = 64 << 10;
+
+static GEAR: [u64; 256] = make_gear();
+
+// splitmix64, so the table is the same everywhere
+const fn make_gear() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x9e3779b97f4a7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Finds content-defined chunk boundaries in a byte stream
+#[derive(Clone, Debug)]
+pub struct Chunker {
+    min: usize,
+    max: usize,
+    mask: u64,
+    hash: u64,
+    len: usize,
+}
+
+impl Chunker {
+    /// Creates a chunker with the default chunk sizes
+    pub fn new() -> Chunker {
+        Chunker::with_sizes(MIN_CHUNK, AVG_CHUNK, MAX_CHUNK)
+    }
+
+    /// Creates a chunker cutting chunks between `min` and `max` bytes long,
+    /// `avg` bytes on average. `avg` is rounded up to a power of two.
+    pub fn with_sizes(min: usize, avg: usize, max: usize) -> Chunker {
+        assert!(0 < min && min <= avg && avg <= max, "invalid chunk sizes");
+        let bits = avg.next_power_of_two().trailing_zeros();
+        Chunker {
+            min,
+            max,
+            // use the high bits, the low ones only depend on the last bytes
+            mask: !(!0u64 >> bits),
+            hash: 0,
+            len: 0,
+        }
+    }
+
+    /// Feeds bytes of the stream, returning how many of them belong to the
+    /// current chunk if it ends within `buf`.
+    pub fn next_boundary(&mut self, buf: &[u8]) -> Option<usize> {
+        for (i, &b) in buf.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[b as usize]);
+            self.len += 1;
+            if self.len >= self.max || (self.len >= self.min && self.hash & self.mask == 0) {
+                self.hash = 0;
+                self.len = 0;
+                return Some(i + 1)
+            }
+        }
+        None
+    }
+
+    /// Forgets the chunk in progress
+    pub fn reset(&mut self) {
+        self.hash = 0;
+        self.len = 0;
+    }
+}
+
+impl Default for Chunker {
+    fn default() -> Chunker {
+        Chunker::new()
+    }
+}
+
+/// One chunk of an indexed stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk<D> {
+    /// Number of the stream, in the order they were added
+    pub stream: usize,
+    /// Offset of the chunk within its stream
+    pub offset: u64,
+    /// Length in bytes
+    pub len: usize,
+    /// Adler-32 of the chunk
+    pub weak: u32,
+    /// Caller-supplied strong hash of the chunk
+    pub strong: D,
+    /// Stream and offset of the first chunk with the same contents, if
+    /// this one is a repeat
+    pub duplicate_of: Option<(usize, u64)>,
+}
+
+/// Chunk records of any number of streams, with duplicates identified
+pub struct Index<D, H> {
+    hash: H,
+    chunker: Chunker,
+    chunks: Vec<Chunk<D>>,
+    seen: HashMap<(u32, usize, D), (usize, u64)>,
+    streams: usize,
+}
+
+impl<D: Clone + Eq + Hash, H: FnMut(&[u8]) -> D> Index<D, H> {
+    /// Creates an empty index using the default chunk sizes and the given
+    /// strong hash function
+    pub fn new(hash: H) -> Index<D, H> {
+        Index::with_chunker(hash, Chunker::new())
+    }
+
+    /// Creates an empty index cutting chunks with the given chunker
+    pub fn with_chunker(hash: H, chunker: Chunker) -> Index<D, H> {
+        Index {
+            hash,
+            chunker,
+            chunks: Vec::new(),
+            seen: HashMap::new(),
+            streams: 0,
+        }
+    }
+
+    /// Reads a whole stream and records its chunks, returning the number
+    /// assigned to the stream.
+    pub fn add_stream<R: Read>(&mut self, mut r: R) -> io::Result<usize> {
+        let stream = self.streams;
+        self.streams += 1;
+        self.chunker.reset();
+
+        let mut buf = [0u8; 0x4000];
+        let mut chunk = Vec::new();
+        let mut offset = 0;
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            let mut rest = &buf[..n];
+            while let Some(end) = self.chunker.next_boundary(rest) {
+                chunk.extend_from_slice(&rest[..end]);
+                self.record(stream, offset, &chunk);
+                offset += chunk.len() as u64;
+                chunk.clear();
+                rest = &rest[end..];
+            }
+            chunk.extend_from_slice(rest);
+        }
+        if !chunk.is_empty() {
+            self.record(stream, offset, &chunk);
+        }
+        Ok(stream)
+    }
+
+    fn record(&mut self, stream: usize, offset: u64, data: &[u8]) {
+        let mut weak = adler::State32::new();
+        weak.feed(data);
+        let weak = weak.result();
+        let strong = (self.hash)(data);
+        let key = (weak, data.len(), strong.clone());
+        let duplicate_of = self.seen.get(&key).cloned();
+        if duplicate_of.is_none() {
+            self.seen.insert(key, (stream, offset));
+        }
+        self.chunks.push(Chunk {
+            stream,
+            offset,
+            len: data.len(),
+            weak,
+            strong,
+            duplicate_of,
+        });
+    }
+
+    /// All chunks recorded so far, in stream order
+    pub fn chunks(&self) -> &[Chunk<D>] {
+        &self.chunks
+    }
+
+    /// Total size of the chunks that repeat an earlier one
+    pub fn duplicate_bytes(&self) -> u64 {
+        self.chunks.iter()
+            .filter(|c| c.duplicate_of.is_some())
+            .map(|c| c.len as u64)
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Chunker, Index};
+
+    fn data(n: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..n).map(|_| {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            (x >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let input = data(1 << 20, 1);
+        let mut chunker = Chunker::with_sizes(512, 2048, 8192);
+        let mut rest = &input[..];
+        let mut count = 0;
+        while let Some(end) = chunker.next_boundary(rest) {
+            assert!((512..=8192).contains(&end));
+            rest = &rest[end..];
+            count += 1;
+        }
+        // roughly a chunk per 2.5k
+        assert!(count > 200 && count < 800);
+    }
+
+    #[test]
+    fn shifted_duplicates() {
+        let shared = data(200000, 2);
+        let mut a = data(1000, 3);
+        a.extend_from_slice(&shared);
+        let mut b = data(7777, 4);
+        b.extend_from_slice(&shared);
+
+        let mut index = Index::new(|chunk: &[u8]| chunk.to_vec());
+        assert_eq!(index.add_stream(&a[..]).unwrap(), 0);
+        assert_eq!(index.add_stream(&b[..]).unwrap(), 1);
+
+        let chunks = index.chunks();
+        let total: usize = chunks.iter().map(|c| c.len).sum();
+        assert_eq!(total, a.len() + b.len());
+        assert!(chunks.iter().filter(|c| c.stream == 0).all(|c| c.duplicate_of.is_none()));
+        // all but the chunks around the differing prefix are found again
+        assert!(index.duplicate_bytes() > 150000);
+        for c in chunks.iter() {
+            if let Some((stream, offset)) = c.duplicate_of {
+                let src = if stream == 0 { &a } else { &b };
+                let dst = if c.stream == 0 { &a } else { &b };
+                let (o1, o2) = (offset as usize, c.offset as usize);
+                assert_eq!(&src[o1..o1 + c.len], &dst[o2..o2 + c.len]);
+            }
+        }
+    }
+}
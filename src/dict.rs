@@ -0,0 +1,153 @@
+/*!
+
+Preset dictionaries which can be swapped while a program runs
+
+A preset dictionary primes a compressor with data typical of the input,
+which makes a big difference for small messages. Dictionaries get retrained
+as the data drifts, and a long-lived server wants to roll out a new one
+without restarting. A `DictHandle` holds the current dictionary for encoders
+to pick up, and every dictionary ever loaded into it, by id, for decoders:
+frames record the id of the dictionary they were compressed with, so frames
+written before a swap still decode after it.
+
+Encoders take the dictionary current when they start a frame and keep it
+until the frame ends, a swap only affects frames started after it. `lz4` is
+the codec supporting dictionaries, see `lz4::Encoder::with_dictionary` and
+`lz4::Decoder::with_dictionaries`. Their `export_state` snapshots record the
+id too, so a snapshot is imported by an encoder created with the same
+dictionary, or a decoder whose handle still has it.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::dict::{DictHandle, Dictionary};
+use compress::lz4;
+
+let handle = DictHandle::new(Dictionary::new(1, b"GET /index.html HTTP/1.1".to_vec()));
+let mut e = lz4::Encoder::with_dictionary(Vec::new(), &handle.current());
+e.write_all(b"GET /about.html HTTP/1.1").unwrap();
+let (old_frame, _) = e.finish();
+
+// roll out a retrained dictionary, old frames keep decoding
+handle.swap(Dictionary::new(2, b"POST /api HTTP/1.1".to_vec()));
+let mut decoded = Vec::new();
+lz4::Decoder::with_dictionaries(&old_frame[..], handle.clone())
+    .read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], b"GET /about.html HTTP/1.1");
+```
+
+*/
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A preset dictionary and the id frames refer to it by
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dictionary {
+    id: u32,
+    data: Vec<u8>,
+}
+
+impl Dictionary {
+    /// Creates a dictionary. Ids have to be unique among the dictionaries of
+    /// a `DictHandle`, a version number or a hash of the data does fine.
+    pub fn new(id: u32, data: Vec<u8>) -> Dictionary {
+        Dictionary { id, data }
+    }
+
+    /// Returns the id recorded in frames using this dictionary
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Returns the contents of the dictionary
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+struct Versions {
+    current: Arc<Dictionary>,
+    known: HashMap<u32, Arc<Dictionary>>,
+}
+
+/// A shared, swappable dictionary, see the module documentation. Clones
+/// refer to the same dictionaries.
+#[derive(Clone)]
+pub struct DictHandle {
+    versions: Arc<RwLock<Versions>>,
+}
+
+impl DictHandle {
+    /// Creates a handle with `dict` as the current dictionary
+    pub fn new(dict: Dictionary) -> DictHandle {
+        let dict = Arc::new(dict);
+        let mut known = HashMap::new();
+        known.insert(dict.id, dict.clone());
+        DictHandle {
+            versions: Arc::new(RwLock::new(Versions { current: dict, known })),
+        }
+    }
+
+    /// Returns the dictionary new frames should be compressed with
+    pub fn current(&self) -> Arc<Dictionary> {
+        self.versions.read().unwrap().current.clone()
+    }
+
+    /// Returns the dictionary with the given id, if it was ever loaded and
+    /// hasn't been retired
+    pub fn get(&self, id: u32) -> Option<Arc<Dictionary>> {
+        self.versions.read().unwrap().known.get(&id).cloned()
+    }
+
+    /// Makes `dict` the current dictionary, returning the previous one. The
+    /// previous dictionary stays available to decoders until retired, a
+    /// dictionary with the same id is replaced.
+    pub fn swap(&self, dict: Dictionary) -> Arc<Dictionary> {
+        let dict = Arc::new(dict);
+        let mut versions = self.versions.write().unwrap();
+        versions.known.insert(dict.id, dict.clone());
+        ::std::mem::replace(&mut versions.current, dict)
+    }
+
+    /// Forgets the dictionary with the given id once no frame needs it
+    /// anymore. The current dictionary can't be retired, returns whether a
+    /// dictionary was dropped.
+    pub fn retire(&self, id: u32) -> bool {
+        let mut versions = self.versions.write().unwrap();
+        if versions.current.id == id {
+            return false
+        }
+        versions.known.remove(&id).is_some()
+    }
+
+    /// Returns the ids of the dictionaries decoders can use, in no
+    /// particular order
+    pub fn ids(&self) -> Vec<u32> {
+        self.versions.read().unwrap().known.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use super::{DictHandle, Dictionary};
+
+    #[test]
+    fn swaps() {
+        let handle = DictHandle::new(Dictionary::new(1, b"one".to_vec()));
+        let other = handle.clone();
+        let old = thread::spawn(move || other.swap(Dictionary::new(2, b"two".to_vec())))
+            .join().unwrap();
+        assert_eq!(old.id(), 1);
+        assert_eq!(handle.current().data(), b"two");
+        assert_eq!(handle.get(1).unwrap().data(), b"one");
+
+        assert!(!handle.retire(2));
+        assert!(handle.retire(1));
+        assert!(!handle.retire(1));
+        assert!(handle.get(1).is_none());
+        assert_eq!(handle.ids(), vec![2]);
+    }
+}
@@ -0,0 +1,173 @@
+/*!
+
+Assets compressed at build time. Requires `embed` feature, enabled by default
+
+Large files embedded with `include_bytes!` bloat a binary. Instead, a build
+script compresses them with `build` and the crate includes the result with
+`compress_include!`, which gives an `Asset` decompressing itself on first
+use.
+
+# Example
+
+In `build.rs`, with this crate as a build dependency:
+
+```rust,ignore
+extern crate compress;
+use compress::Format;
+
+fn main() {
+    compress::embed::build("assets/dictionary.txt", Some(Format::Lz4Frame)).unwrap();
+}
+```
+
+In the crate, with this crate as a normal dependency:
+
+```rust,ignore
+#[macro_use]
+extern crate compress;
+use compress::embed::Asset;
+
+static DICTIONARY: Asset = compress_include!("dictionary.txt");
+
+fn main() {
+    let words = DICTIONARY.get().unwrap();
+}
+```
+
+*/
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use codecs;
+use Format;
+
+/// Directory below `OUT_DIR` which `build` writes to and `compress_include!`
+/// reads from
+pub const DIR: &str = "compress-embed";
+
+/// Compresses the file at `input` into `dir`, under the same file name, and
+/// returns the path written. The first byte of the output records the
+/// format, or 0 if the data is stored as is.
+pub fn write_asset<P, Q>(input: P, dir: Q, format: Option<Format>) -> io::Result<PathBuf>
+    where P: AsRef<Path>, Q: AsRef<Path>
+{
+    let input = input.as_ref();
+    let name = match input.file_name() {
+        Some(name) => name,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "asset path has no file name")),
+    };
+    let data = try!(fs::read(input));
+    let mut out = vec![format.map_or(0, |f| f as u8)];
+    out.extend_from_slice(&try!(codecs::compress(format, &data)));
+
+    try!(fs::create_dir_all(dir.as_ref()));
+    let path = dir.as_ref().join(name);
+    try!(fs::write(&path, out));
+    Ok(path)
+}
+
+/// Compresses an asset from a build script so that `compress_include!` can
+/// pick it up, and makes cargo rerun the script when the asset changes.
+pub fn build<P: AsRef<Path>>(input: P, format: Option<Format>) -> io::Result<PathBuf> {
+    let out_dir = match env::var_os("OUT_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "OUT_DIR is not set, not in a build script?")),
+    };
+    println!("cargo:rerun-if-changed={}", input.as_ref().display());
+    write_asset(input, out_dir.join(DIR), format)
+}
+
+/// A compressed asset, decompressed the first time it is needed
+pub struct Asset {
+    data: &'static [u8],
+    cache: OnceLock<Vec<u8>>,
+}
+
+impl Asset {
+    /// Wraps data as written by `write_asset`
+    pub const fn new(data: &'static [u8]) -> Asset {
+        Asset {
+            data,
+            cache: OnceLock::new(),
+        }
+    }
+
+    /// Returns the compressed bytes, including the format byte
+    pub fn compressed(&self) -> &'static [u8] {
+        self.data
+    }
+
+    /// Decompresses into a fresh buffer, without touching the cache
+    pub fn decompress(&self) -> io::Result<Vec<u8>> {
+        let (id, data) = match self.data.split_first() {
+            Some((&id, data)) => (id, data),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty asset")),
+        };
+        // built into the program, so there is no limit on the size
+        let mut out = Vec::new();
+        try!(codecs::decompress(id, data, &mut out, usize::MAX));
+        Ok(out)
+    }
+
+    /// Returns the decompressed asset, decompressing it on the first call
+    pub fn get(&self) -> io::Result<&[u8]> {
+        if let Some(data) = self.cache.get() {
+            return Ok(data)
+        }
+        let data = try!(self.decompress());
+        Ok(self.cache.get_or_init(|| data))
+    }
+}
+
+/// Includes an asset compressed by `embed::build` in the build script,
+/// given its file name, as an `embed::Asset`.
+#[macro_export]
+macro_rules! compress_include {
+    ($name:expr) => {
+        $crate::embed::Asset::new(include_bytes!(concat!(
+            env!("OUT_DIR"), "/compress-embed/", $name)))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use super::{write_asset, Asset};
+    use Format;
+
+    #[test]
+    fn roundtrip() {
+        let dir = env::temp_dir().join(format!("compress-embed-{}", ::std::process::id()));
+        let text = &include_bytes!("data/test.txt")[..];
+        let mut formats = vec![None];
+        if cfg!(feature="lz4") {
+            formats.push(Some(Format::Lz4Frame));
+        }
+        if cfg!(feature="flate") {
+            formats.push(Some(Format::Deflate));
+        }
+        for &format in formats.iter() {
+            let path = write_asset("src/data/test.txt", &dir, format).unwrap();
+            let data: &'static [u8] = Box::leak(fs::read(path).unwrap().into_boxed_slice());
+            let asset = Asset::new(data);
+            assert_eq!(asset.get().unwrap(), text);
+            assert_eq!(asset.get().unwrap().as_ptr(), asset.get().unwrap().as_ptr());
+            if format.is_some() {
+                assert!(asset.compressed().len() < text.len());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(write_asset("src/data/test.txt", env::temp_dir(), Some(Format::BwtPipe)).is_err());
+        assert!(Asset::new(&[6, 1, 2, 3]).get().is_err());
+        assert!(Asset::new(&[]).get().is_err());
+    }
+}
@@ -0,0 +1,595 @@
+/*!
+
+Table-based asymmetric numeral system (tANS) coder, the scheme known as FSE
+in zstd. Requires `entropy` feature, enabled by default
+
+Symbol frequencies are first normalized to a power of two, the table size.
+`Table` spreads the symbols over that many states in proportion to their
+frequencies; coding a symbol moves from one state to another and shifts a
+few bits in or out, so both directions are nothing but table lookups. The
+compression is about that of arithmetic coding with a static model.
+
+An ANS coder is a stack: the decoder gets the symbols in the opposite order
+to the one they were encoded in. `Table::encode` therefore walks its input
+backwards and reverses the bits it produced, so that `Table::decode` reads
+everything front to back. Several states can be interleaved, each coding
+every n-th symbol, which lets the decoder work on independent dependency
+chains.
+
+`ByteEncoder` and `ByteDecoder` build a stream on top of that: the input is
+cut into blocks, and every block is written as its length, its normalized
+frequencies and its code. A zero length ends the stream.
+
+# Links
+
+* http://arxiv.org/abs/1311.2540 (Duda, Asymmetric numeral systems)
+* http://fastcompression.blogspot.com/2013/12/finite-state-entropy-new-breed-of.html
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::entropy::ans;
+
+let text = "some text";
+let mut e = ans::ByteEncoder::new(Vec::new());
+e.write_all(text.as_bytes()).unwrap();
+let (encoded, _) = e.finish();
+
+let mut d = ans::ByteDecoder::new(&encoded[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], text.as_bytes());
+```
+
+# Credit
+
+Jarek Duda for ANS, Yann Collet for the symbol spreading of FSE.
+
+*/
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use super::super::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::super::byteorder_err_to_io;
+use super::cache::Cache;
+
+/// The smallest supported table size, as a power of two
+pub const MIN_TABLE_LOG: u8 = 5;
+/// The largest supported table size, as a power of two
+pub const MAX_TABLE_LOG: u8 = 15;
+/// The table size used by `ByteEncoder::new`
+pub const DEFAULT_TABLE_LOG: u8 = 11;
+/// The largest number of interleaved states
+pub const MAX_LANES: usize = 8;
+/// The number of interleaved states used by `ByteEncoder::new`
+pub const DEFAULT_LANES: usize = 4;
+/// The block size used by `ByteEncoder::new`
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 16;
+/// The largest block `ByteDecoder` accepts
+pub const MAX_BLOCK_SIZE: usize = 1 << 24;
+
+// the tables of the decoders alive, by table log and frequencies
+static TABLES: Cache<(u8, Vec<u32>), Table> = Cache::new();
+
+fn invalid_data<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn invalid_input<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+fn floor_log2(x: u32) -> u32 {
+    31 - x.leading_zeros()
+}
+
+/// Scales symbol counts to frequencies summing to `1 << table_log`, keeping
+/// every present symbol at a frequency of one at least
+pub fn normalize(counts: &[u32], table_log: u8) -> io::Result<Vec<u32>> {
+    if !(MIN_TABLE_LOG..=MAX_TABLE_LOG).contains(&table_log) {
+        return invalid_input("table log out of range")
+    }
+    let size = 1u64 << table_log;
+    let total: u64 = counts.iter().map(|&c| c as u64).sum();
+    let present = counts.iter().filter(|&&c| c > 0).count() as u64;
+    if total == 0 {
+        return invalid_input("no symbols to normalize")
+    }
+    if present > size {
+        return invalid_input("more symbols than table entries")
+    }
+    let mut freqs: Vec<u32> = counts.iter().map(|&c| match c {
+        0 => 0,
+        c => ((c as u64 * size + total / 2) / total).max(1) as u32,
+    }).collect();
+    let mut sum: u64 = freqs.iter().map(|&f| f as u64).sum();
+    // rounding is off by a little, settle it on the most frequent symbols
+    while sum != size {
+        let (i, _) = freqs.iter().enumerate().max_by_key(|&(_, &f)| f).unwrap();
+        if sum < size {
+            freqs[i] += (size - sum) as u32;
+            sum = size;
+        } else {
+            let cut = (sum - size).min(freqs[i] as u64 / 2).max(1);
+            freqs[i] -= cut as u32;
+            sum -= cut;
+        }
+    }
+    Ok(freqs)
+}
+
+// A decoding table entry: the symbol of a state, and how to get the next one
+#[derive(Clone, Copy)]
+struct Entry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// Coding tables for a set of normalized frequencies
+pub struct Table {
+    log: u8,
+    freqs: Vec<u32>,
+    // start of each symbol's states in `states`
+    starts: Vec<u32>,
+    // the states of every symbol in turn, as table positions
+    states: Vec<u16>,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    /// Builds the tables for frequencies of the symbols `0..freqs.len()`,
+    /// which have to sum to `1 << table_log`
+    pub fn new(freqs: &[u32], table_log: u8) -> io::Result<Table> {
+        if !(MIN_TABLE_LOG..=MAX_TABLE_LOG).contains(&table_log) {
+            return invalid_input("table log out of range")
+        }
+        if freqs.len() > 256 {
+            return invalid_input("too many symbols")
+        }
+        let size = 1usize << table_log;
+        if freqs.iter().map(|&f| f as u64).sum::<u64>() != size as u64 {
+            return invalid_input("frequencies don't sum to the table size")
+        }
+
+        // spread the symbols over the table, the step being coprime with
+        // the size visits every position once
+        let mask = size - 1;
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut spread = vec![0u8; size];
+        let mut pos = 0;
+        for (symbol, &f) in freqs.iter().enumerate() {
+            for _ in 0..f {
+                spread[pos] = symbol as u8;
+                pos = (pos + step) & mask;
+            }
+        }
+
+        let mut starts = Vec::with_capacity(freqs.len() + 1);
+        let mut start = 0;
+        for &f in freqs.iter() {
+            starts.push(start);
+            start += f;
+        }
+        starts.push(start);
+
+        // the n-th state of a symbol decodes to the intermediate state
+        // `freq + n`, from which the next state is read bitwise
+        let mut seen = vec![0u32; freqs.len()];
+        let mut states = vec![0u16; size];
+        let mut entries = Vec::with_capacity(size);
+        for (i, &symbol) in spread.iter().enumerate() {
+            let s = symbol as usize;
+            states[(starts[s] + seen[s]) as usize] = i as u16;
+            let x = freqs[s] + seen[s];
+            seen[s] += 1;
+            let bits = table_log as u32 - floor_log2(x);
+            entries.push(Entry {
+                symbol,
+                bits: bits as u8,
+                base: ((x << bits) - size as u32) as u16,
+            });
+        }
+
+        Ok(Table {
+            log: table_log,
+            freqs: freqs.to_vec(),
+            starts,
+            states,
+            entries,
+        })
+    }
+
+    /// Returns the table size as a power of two
+    pub fn log(&self) -> u8 {
+        self.log
+    }
+
+    /// Returns the normalized frequencies
+    pub fn freqs(&self) -> &[u32] {
+        &self.freqs
+    }
+
+    /// Encodes `input` with `lanes` interleaved states, appending the final
+    /// states as u16 little endian, then the bits of the code, to `out`
+    pub fn encode(&self, input: &[u8], lanes: usize, out: &mut Vec<u8>) -> io::Result<()> {
+        if lanes == 0 || lanes > MAX_LANES {
+            return invalid_input("bad number of lanes")
+        }
+        let size = 1u32 << self.log;
+        let mut x = [size; MAX_LANES];
+        let mut chunks = Vec::with_capacity(input.len());
+        for (i, &symbol) in input.iter().enumerate().rev() {
+            let s = symbol as usize;
+            let f = match self.freqs.get(s) {
+                Some(&f) if f > 0 => f,
+                _ => return invalid_input("symbol missing from the table"),
+            };
+            let state = &mut x[i % lanes];
+            let d = floor_log2(*state) - floor_log2(f);
+            let bits = if (*state >> d) < f { d - 1 } else { d };
+            chunks.push((*state & ((1 << bits) - 1), bits));
+            let n = (*state >> bits) - f;
+            *state = size + self.states[(self.starts[s] + n) as usize] as u32;
+        }
+
+        for &state in x[..lanes].iter() {
+            try!(out.write_u16::<LittleEndian>((state - size) as u16));
+        }
+        // the decoder wants the bits of the first symbol first
+        let mut acc = 0u64;
+        let mut count = 0;
+        for &(value, bits) in chunks.iter().rev() {
+            acc |= (value as u64) << count;
+            count += bits;
+            while count >= 8 {
+                out.push(acc as u8);
+                acc >>= 8;
+                count -= 8;
+            }
+        }
+        if count > 0 {
+            out.push(acc as u8);
+        }
+        Ok(())
+    }
+
+    /// Decodes `n` symbols written by `encode` with as many `lanes`,
+    /// appending them to `out`. Returns the number of bytes consumed.
+    pub fn decode(&self, data: &[u8], lanes: usize, n: usize, out: &mut Vec<u8>) -> io::Result<usize> {
+        if lanes == 0 || lanes > MAX_LANES {
+            return invalid_input("bad number of lanes")
+        }
+        if data.len() < 2 * lanes {
+            return invalid_data("truncated ANS code")
+        }
+        let size = 1usize << self.log;
+        let mut x = [0usize; MAX_LANES];
+        for (i, state) in x[..lanes].iter_mut().enumerate() {
+            *state = u16::from_le_bytes([data[2*i], data[2*i+1]]) as usize;
+            if *state >= size {
+                return invalid_data("ANS state out of range")
+            }
+        }
+
+        let mut pos = 2 * lanes;
+        let mut acc = 0u64;
+        let mut count = 0;
+        out.reserve(n);
+        for i in 0..n {
+            let state = &mut x[i % lanes];
+            let entry = self.entries[*state];
+            out.push(entry.symbol);
+            let bits = entry.bits as u32;
+            while count < bits {
+                match data.get(pos) {
+                    Some(&b) => acc |= (b as u64) << count,
+                    None => return invalid_data("truncated ANS code"),
+                }
+                pos += 1;
+                count += 8;
+            }
+            *state = entry.base as usize + (acc & ((1 << bits) - 1)) as usize;
+            acc >>= bits;
+            count -= bits;
+        }
+        // every lane has to be back where the encoder started
+        if x[..lanes].iter().any(|&state| state != 0) {
+            return invalid_data("corrupt ANS code")
+        }
+        Ok(pos)
+    }
+}
+
+
+/// A block-wise byte encoder, see the module documentation
+pub struct ByteEncoder<W> {
+    w: W,
+    buf: Vec<u8>,
+    block_size: usize,
+    table_log: u8,
+    lanes: usize,
+}
+
+impl<W: Write> ByteEncoder<W> {
+    /// Create a new encoder on top of a given Writer
+    pub fn new(w: W) -> ByteEncoder<W> {
+        ByteEncoder {
+            w,
+            buf: Vec::new(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            table_log: DEFAULT_TABLE_LOG,
+            lanes: DEFAULT_LANES,
+        }
+    }
+
+    /// Create an encoder with a given block size, table size as a power of
+    /// two, and number of interleaved states
+    pub fn with_params(w: W, block_size: usize, table_log: u8, lanes: usize) -> io::Result<ByteEncoder<W>> {
+        if block_size == 0 || block_size > MAX_BLOCK_SIZE {
+            return invalid_input("block size out of range")
+        }
+        if !(MIN_TABLE_LOG..=MAX_TABLE_LOG).contains(&table_log) {
+            return invalid_input("table log out of range")
+        }
+        if lanes == 0 || lanes > MAX_LANES {
+            return invalid_input("bad number of lanes")
+        }
+        Ok(ByteEncoder {
+            block_size,
+            table_log,
+            lanes,
+            .. ByteEncoder::new(w)
+        })
+    }
+
+    fn encode_block(&mut self) -> io::Result<()> {
+        let mut counts = [0u32; 256];
+        for &b in self.buf.iter() {
+            counts[b as usize] += 1;
+        }
+        let freqs = try!(normalize(&counts, self.table_log));
+        let table = try!(Table::new(&freqs, self.table_log));
+
+        let mut block = Vec::new();
+        try!(block.write_u32::<LittleEndian>(self.buf.len() as u32));
+        block.push(self.table_log);
+        block.push(self.lanes as u8);
+        let present = freqs.iter().filter(|&&f| f > 0).count();
+        try!(block.write_u16::<LittleEndian>(present as u16));
+        for (symbol, &f) in freqs.iter().enumerate().filter(|&(_, &f)| f > 0) {
+            block.push(symbol as u8);
+            try!(block.write_u16::<LittleEndian>(f as u16));
+        }
+        let mut code = Vec::new();
+        try!(table.encode(&self.buf, self.lanes, &mut code));
+        try!(block.write_u32::<LittleEndian>(code.len() as u32));
+        block.extend_from_slice(&code);
+
+        self.buf.clear();
+        self.w.write_all(&block)
+    }
+
+    /// Finish encoding, writing out the last block and the end marker
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = if self.buf.is_empty() { Ok(()) } else { self.encode_block() };
+        let result = result.and_then(|_| self.w.write_u32::<LittleEndian>(0))
+                           .and_then(|_| self.w.flush());
+        (self.w, result)
+    }
+}
+
+impl<W: Write> Write for ByteEncoder<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let amount = buf.len();
+        while !buf.is_empty() {
+            let n = (self.block_size - self.buf.len()).min(buf.len());
+            self.buf.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.buf.len() == self.block_size {
+                try!(self.encode_block());
+            }
+        }
+        Ok(amount)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// A block-wise byte decoder, see the module documentation. Blocks with the
+/// same frequencies share one table instead of rebuilding it, consecutive
+/// ones in a stream as well as those of streams decoded at the same time.
+pub struct ByteDecoder<R> {
+    r: R,
+    output: Vec<u8>,
+    start: usize,
+    is_eof: bool,
+    table: Option<Arc<Table>>,
+}
+
+impl<R: Read> ByteDecoder<R> {
+    /// Create a decoder on top of a given Reader
+    pub fn new(r: R) -> ByteDecoder<R> {
+        ByteDecoder {
+            r,
+            output: Vec::new(),
+            start: 0,
+            is_eof: false,
+            table: None,
+        }
+    }
+
+    /// Finish decoding
+    pub fn finish(self) -> (R, io::Result<()>) {
+        (self.r, Ok(()))
+    }
+
+    fn decode_block(&mut self) -> io::Result<()> {
+        let n = try!(self.r.read_u32::<LittleEndian>().map_err(byteorder_err_to_io)) as usize;
+        self.output.clear();
+        self.start = 0;
+        if n == 0 {
+            self.is_eof = true;
+            return Ok(())
+        }
+        if n > MAX_BLOCK_SIZE {
+            return invalid_data("ANS block too large")
+        }
+        let table_log = try!(self.r.read_u8().map_err(byteorder_err_to_io));
+        let lanes = try!(self.r.read_u8().map_err(byteorder_err_to_io)) as usize;
+        if !(MIN_TABLE_LOG..=MAX_TABLE_LOG).contains(&table_log) || lanes == 0 || lanes > MAX_LANES {
+            return invalid_data("bad ANS block parameters")
+        }
+        let present = try!(self.r.read_u16::<LittleEndian>().map_err(byteorder_err_to_io));
+        if present == 0 || present > 256 {
+            return invalid_data("bad ANS symbol count")
+        }
+        let mut freqs = vec![0u32; 256];
+        for _ in 0..present {
+            let symbol = try!(self.r.read_u8().map_err(byteorder_err_to_io)) as usize;
+            let f = try!(self.r.read_u16::<LittleEndian>().map_err(byteorder_err_to_io));
+            if freqs[symbol] != 0 || f == 0 {
+                return invalid_data("bad ANS frequencies")
+            }
+            freqs[symbol] = f as u32;
+        }
+        let reuse = match self.table {
+            Some(ref table) => table.log == table_log && table.freqs == freqs,
+            None => false,
+        };
+        if !reuse {
+            self.table = Some(try!(TABLES.get((table_log, freqs), |&(log, ref freqs)| {
+                Table::new(freqs, log).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })));
+        }
+        let table = self.table.as_ref().unwrap();
+
+        // no symbol takes more than a table log of bits
+        let len = try!(self.r.read_u32::<LittleEndian>().map_err(byteorder_err_to_io)) as usize;
+        if len > 2 * lanes + (n * table_log as usize).div_ceil(8) {
+            return invalid_data("ANS code too long")
+        }
+        let mut code = vec![0u8; len];
+        try!(self.r.read_exact(&mut code));
+        if try!(table.decode(&code, lanes, n, &mut self.output)) != len {
+            return invalid_data("trailing bytes in ANS code")
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ByteDecoder<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        while self.start == self.output.len() && !self.is_eof {
+            try!(self.decode_block());
+        }
+        let n = (self.output.len() - self.start).min(dst.len());
+        dst[..n].copy_from_slice(&self.output[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use super::{normalize, ByteDecoder, ByteEncoder, Table};
+    use entropy::ari;
+
+    fn roundtrip(bytes: &[u8], e: ByteEncoder<Vec<u8>>) -> usize {
+        let mut e = e;
+        e.write_all(bytes).unwrap();
+        let (encoded, r) = e.finish();
+        r.unwrap();
+        let mut decoded = Vec::new();
+        ByteDecoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(bytes, &decoded[..]);
+        encoded.len()
+    }
+
+    #[test]
+    fn normalizes() {
+        let freqs = normalize(&[1, 0, 1000, 3, 70000], 8).unwrap();
+        assert_eq!(freqs.iter().sum::<u32>(), 256);
+        assert_eq!((freqs[0], freqs[1]), (1, 0));
+        assert!(freqs[4] > freqs[2] && freqs[2] >= freqs[3]);
+        // as many symbols as table entries
+        let freqs = normalize(&[1; 32], 5).unwrap();
+        assert!(freqs.iter().all(|&f| f == 1));
+        assert!(normalize(&[1; 33], 5).is_err());
+        assert!(normalize(&[0; 4], 5).is_err());
+    }
+
+    #[test]
+    fn lanes() {
+        let input = include_bytes!("../data/test.txt");
+        let mut counts = [0u32; 256];
+        for &b in input.iter() {
+            counts[b as usize] += 1;
+        }
+        let table = Table::new(&normalize(&counts, 10).unwrap(), 10).unwrap();
+        for lanes in 1..super::MAX_LANES + 1 {
+            let mut code = Vec::new();
+            table.encode(input, lanes, &mut code).unwrap();
+            let mut decoded = Vec::new();
+            assert_eq!(table.decode(&code, lanes, input.len(), &mut decoded).unwrap(), code.len());
+            assert_eq!(&decoded[..], &input[..]);
+            // one lane less doesn't end in the initial states
+            if lanes > 1 {
+                assert!(table.decode(&code, lanes - 1, input.len(), &mut Vec::new()).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrips() {
+        let input = include_bytes!("../data/test.txt");
+        roundtrip(b"", ByteEncoder::new(Vec::new()));
+        roundtrip(b"a", ByteEncoder::new(Vec::new()));
+        roundtrip(&[7; 100000], ByteEncoder::new(Vec::new()));
+        let all: Vec<u8> = (0..256).map(|i| i as u8).collect();
+        roundtrip(&all, ByteEncoder::new(Vec::new()));
+        for &(block, log, lanes) in [(100, 5, 1), (1000, 8, 3), (1 << 20, 15, 8)].iter() {
+            roundtrip(input, ByteEncoder::with_params(Vec::new(), block, log, lanes).unwrap());
+        }
+        // close to the adaptive arithmetic coder
+        let size = roundtrip(input, ByteEncoder::new(Vec::new()));
+        let mut e = ari::ByteEncoder::new(Vec::new());
+        e.write_all(input).unwrap();
+        assert!(size < e.finish().0.len() + 256);
+    }
+
+    #[test]
+    fn corrupt() {
+        let input = include_bytes!("../data/test.txt");
+        let mut e = ByteEncoder::new(Vec::new());
+        e.write_all(input).unwrap();
+        let (encoded, _) = e.finish();
+        for &(at, value) in [(4, 2), (5, 1), (0, 0xff), (encoded.len() - 10, 0x55)].iter() {
+            let mut bad = encoded.clone();
+            bad[at] ^= value;
+            let mut decoded = Vec::new();
+            let ok = ByteDecoder::new(&bad[..]).read_to_end(&mut decoded).is_ok();
+            assert!(!ok || decoded[..] != input[..]);
+        }
+        assert!(ByteDecoder::new(&encoded[..encoded.len() - 5]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn shared_tables() {
+        let input = include_bytes!("../data/test.txt");
+        let mut e = ByteEncoder::new(Vec::new());
+        e.write_all(input).unwrap();
+        let (encoded, _) = e.finish();
+        let mut a = ByteDecoder::new(&encoded[..]);
+        let mut b = ByteDecoder::new(&encoded[..]);
+        a.read_exact(&mut [0; 10]).unwrap();
+        b.read_exact(&mut [0; 10]).unwrap();
+        assert!(Arc::ptr_eq(a.table.as_ref().unwrap(), b.table.as_ref().unwrap()));
+    }
+}
@@ -2,6 +2,12 @@
 
 Adaptive Probability Models
 
+Converting between flat and wide (logistic) probabilities goes through
+lookup tables built at compile time, with integer arithmetic only. This
+keeps the output the same on every target, with or without an FPU and
+whatever the `integer-only` feature is set to, which a stream coded with
+floating point logarithms couldn't promise.
+
 # Links
 * http://mattmahoney.net/dc/bbb.cpp
 * https://github.com/IlyaGrebnov/libbsc
@@ -9,16 +15,14 @@ Adaptive Probability Models
 # Example
 
 # Credit
-Matt Mahoney for the wonderful 'bbb' commented source
+Matt Mahoney for the wonderful 'bbb' commented source,
+and the interpolated squash table of lpaq
 
 */
 
-extern crate num;
-
-use self::num::traits::{ToPrimitive};
 use super::Border;
 pub type FlatProbability = u16;
-pub type WideProbability = i16;
+pub(crate) type WideProbability = i16;
 
 const BIN_WEIGHT_BITS: usize = 8;
 const BIN_WEIGHT_TOTAL: usize = 1<<BIN_WEIGHT_BITS;
@@ -30,6 +34,45 @@ const WIDE_OFFSET: WideProbability = 1<<(WIDE_BITS-1);
 const PORTAL_OFFSET: usize = 1<<(WIDE_BITS-BIN_WEIGHT_BITS-1);
 const PORTAL_BINS: usize = 2*PORTAL_OFFSET + 1;
 
+/// Logistic function, 4096/(1+exp(-x/256)) for x in [-2047,2047],
+/// interpolated between 33 precomputed points
+const fn squash(x: isize) -> FlatProbability {
+    const T: [isize; 33] = [
+        1, 2, 3, 6, 10, 16, 27, 45, 73, 120, 194, 310, 488, 747, 1101,
+        1546, 2047, 2549, 2994, 3348, 3607, 3785, 3901, 3975, 4022,
+        4050, 4068, 4079, 4085, 4089, 4092, 4093, 4094];
+    if x > 2047 { return 4095 }
+    if x < -2047 { return 1 }
+    let w = x & 127;
+    let i = ((x + 2048) >> 7) as usize;
+    ((T[i] * (128 - w) + T[i + 1] * w + 64) >> 7) as FlatProbability
+}
+
+/// Inverse of `squash`, on the same scale
+static STRETCH: [i16; FLAT_TOTAL as usize] = make_stretch();
+
+const fn make_stretch() -> [i16; FLAT_TOTAL as usize] {
+    let mut table = [0i16; FLAT_TOTAL as usize];
+    let mut pi = 0;
+    let mut x = -2047;
+    while x <= 2047 {
+        let v = squash(x) as usize;
+        while pi <= v {
+            table[pi] = x as i16;
+            pi += 1;
+        }
+        x += 1;
+    }
+    while pi < FLAT_TOTAL as usize {
+        table[pi] = 2047;
+        pi += 1;
+    }
+    table
+}
+
+// wide probabilities are in units of 1/2048, squash works in 1/256
+const SQUASH_SCALE: WideProbability = WIDE_OFFSET >> 8;
+
 
 /// Bit probability model
 #[derive(Copy, Clone)]
@@ -51,12 +94,8 @@ impl Bit {
 
     /// Return wide probability
     #[inline]
-    pub fn to_wide(&self) -> WideProbability {
-        //table_stretch[self.to_flat() as usize]
-        let p = (self.to_flat() as f32) / (FLAT_TOTAL as f32);
-        let d = (p / (1.0-p)).ln();
-        let wp = (d * WIDE_OFFSET as f32).to_i16().unwrap();
-        wp
+    pub(crate) fn to_wide(self) -> WideProbability {
+        STRETCH[self.to_flat() as usize] * SQUASH_SCALE
     }
 
     /// Construct from flat probability
@@ -67,12 +106,8 @@ impl Bit {
 
     /// Construct from wide probability
     #[inline]
-    pub fn from_wide(wp: WideProbability) -> Bit {
-        //Bit(table_squash[(wp+WIDE_OFFSET) as usize])
-        let d = (wp as f32) / (WIDE_OFFSET as f32);
-        let p = 1.0 / (1.0 + (-d).exp());
-        let fp = (p * FLAT_TOTAL as f32).to_u16().unwrap();
-        Bit(fp)
+    pub(crate) fn from_wide(wp: WideProbability) -> Bit {
+        Bit(squash((wp / SQUASH_SCALE) as isize))
     }
 
     /// Mutate for better zeroes
@@ -143,9 +178,9 @@ impl Gate {
             map: [Bit::new_equal(); PORTAL_BINS],
         };
         for (i,bit) in g.map.iter_mut().enumerate() {
-            let rp = (i as f32)/(PORTAL_OFFSET as f32) - 1.0;
-            let wp = (rp * (WIDE_OFFSET as f32)).to_i16().unwrap();
-            *bit = Bit::from_wide(wp);
+            let rp = i as isize - PORTAL_OFFSET as isize;
+            let wp = rp * WIDE_OFFSET as isize / PORTAL_OFFSET as isize;
+            *bit = Bit::from_wide(wp as WideProbability);
         }
         g
     }
@@ -159,7 +194,7 @@ impl Gate {
 
     /// Pass a wide probability on input, usable when
     /// you mix it linearly beforehand (libbsc does that)
-    pub fn pass_wide(&self, wp: WideProbability) -> (FlatProbability, BinCoords) {
+    pub(crate) fn pass_wide(&self, wp: WideProbability) -> (FlatProbability, BinCoords) {
         let index = ((wp + WIDE_OFFSET) >> BIN_WEIGHT_BITS) as usize;
         let weight = wp as usize & (BIN_WEIGHT_TOTAL-1);
         let z = [
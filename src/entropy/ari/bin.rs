@@ -20,7 +20,7 @@ pub struct Model {
     /// total frequency (constant)
     total: Border,
     /// learning rate
-    pub rate: Border,
+    rate: Border,
 }
 
 impl Model {
@@ -60,13 +60,13 @@ impl Model {
 
     /// Update the frequency of zero
     pub fn update_zero(&mut self) {
-        debug!("\tUpdating zero");
+        trace!(target: "compress::entropy::ari", "\tUpdating zero");
         self.zero += (self.total-self.zero) >> (self.rate as usize);
     }
 
     /// Update the frequency of one
     pub fn update_one(&mut self) {
-        debug!("\tUpdating one");
+        trace!(target: "compress::entropy::ari", "\tUpdating one");
         self.zero -= self.zero >> (self.rate as usize);
     }
 
@@ -0,0 +1,496 @@
+/*!
+
+Bit-level context mixing on top of the arithmetic coder.
+
+Every byte is coded as 8 binary decisions, most significant bit first. Before
+each bit a `ContextModel` predicts the probability of a zero given what was
+seen so far (the `Context`), the bit is coded with that probability and the
+model learns from the actual value. Models are free to look at any part of
+the history, so custom ones can be plugged in next to the provided order-0,
+order-1 and match models. A `Mixer` combines the predictions of several models
+in the logistic domain, with weights trained online, which is the basic
+building block of PAQ-like compressors.
+
+The stream carries no header; an extra end-of-data flag precedes every byte,
+costing a small fraction of a bit once adapted. Encoder and decoder have to
+be built with identical models.
+
+# Links
+* http://mattmahoney.net/dc/dce.html#Section_43
+* http://mattmahoney.net/dc/paq.html
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::entropy::ari::cm;
+
+fn model() -> cm::Mixer {
+    let mut mixer = cm::Mixer::new();
+    mixer.add(cm::Order0::new());
+    mixer.add(cm::Order1::new());
+    mixer.add(cm::MatchModel::new(16));
+    mixer
+}
+
+let text = b"an order-1 context mixing example, mixing contexts in order";
+let mut e = cm::Encoder::new(Vec::new(), model());
+e.write_all(text).unwrap();
+let (encoded, _) = e.finish();
+
+let mut d = cm::Decoder::new(&encoded[..], model());
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], &text[..]);
+```
+
+# Credit
+
+Matt Mahoney for the mixer and match model designs described in his book.
+
+*/
+
+use std::io::{self, Read, Write};
+
+use super::apm::{Bit, FlatProbability};
+
+/// Learning rate of the counters of the order-N models
+const COUNTER_RATE: isize = 4;
+/// Learning rate of the match model and the end-of-data flag
+const SLOW_RATE: isize = 6;
+/// Matches shorter than this aren't looked up
+const MATCH_MIN: usize = 4;
+/// Match lengths are bucketed up to this value
+const MATCH_LIMIT: usize = 15;
+/// Fixed point position of the mixer weights
+const WEIGHT_BITS: usize = 16;
+/// Default shift applied to the mixer updates, larger is slower
+const MIXER_RATE: usize = 14;
+
+// keep predictions away from certainty, so either bit can still be coded
+fn clamp(bit: Bit) -> Bit {
+    Bit::from_flat(bit.to_flat().clamp(1, 4095))
+}
+
+/// What is known at the time a bit is predicted: the bytes coded so far and
+/// the leading bits of the current one
+pub struct Context {
+    history: Vec<u8>,
+    partial: usize,
+}
+
+impl Context {
+    /// Create an empty context, at the start of a stream
+    pub fn new() -> Context {
+        Context {
+            history: Vec::new(),
+            partial: 1,
+        }
+    }
+
+    /// Return the bits of the current byte known so far, below a leading 1,
+    /// so the value is in 1..256 and unique for every position in the byte
+    #[inline]
+    pub fn partial(&self) -> usize {
+        self.partial
+    }
+
+    /// Return the number of bits of the current byte known so far
+    #[inline]
+    pub fn bit_position(&self) -> usize {
+        (usize::BITS - 1 - self.partial.leading_zeros()) as usize
+    }
+
+    /// Return all the complete bytes so far
+    #[inline]
+    pub fn history(&self) -> &[u8] {
+        &self.history
+    }
+
+    /// Return the byte `back` positions before the current one (starting at
+    /// 1), or 0 before the start of the stream
+    #[inline]
+    pub fn byte(&self, back: usize) -> u8 {
+        let n = self.history.len();
+        if back > 0 && back <= n { self.history[n - back] } else { 0 }
+    }
+
+    fn push(&mut self, bit: bool) {
+        self.partial = (self.partial << 1) | bit as usize;
+        if self.partial >= 0x100 {
+            self.history.push(self.partial as u8);
+            self.partial = 1;
+        }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
+/// A bit probability model driven by the context. `predict` is called once
+/// before every bit and `update` after it with the actual value, both with
+/// the same context.
+pub trait ContextModel {
+    /// Return the probability of the next bit being zero
+    fn predict(&mut self, ctx: &Context) -> Bit;
+    /// Learn the actual value of the bit
+    fn update(&mut self, ctx: &Context, bit: bool);
+}
+
+impl<M: ContextModel + ?Sized> ContextModel for Box<M> {
+    fn predict(&mut self, ctx: &Context) -> Bit {
+        (**self).predict(ctx)
+    }
+
+    fn update(&mut self, ctx: &Context, bit: bool) {
+        (**self).update(ctx, bit)
+    }
+}
+
+/// Order-0 model: the bit statistics only depend on the position in the
+/// current byte and its previous bits
+pub struct Order0 {
+    bits: Vec<Bit>,
+}
+
+impl Order0 {
+    /// Create a new model with equal probabilities
+    pub fn new() -> Order0 {
+        Order0 {
+            bits: vec![Bit::new_equal(); 0x100],
+        }
+    }
+}
+
+impl Default for Order0 {
+    fn default() -> Order0 {
+        Order0::new()
+    }
+}
+
+impl ContextModel for Order0 {
+    fn predict(&mut self, ctx: &Context) -> Bit {
+        self.bits[ctx.partial()]
+    }
+
+    fn update(&mut self, ctx: &Context, bit: bool) {
+        self.bits[ctx.partial()].update(bit, COUNTER_RATE, 0)
+    }
+}
+
+/// Order-1 model: like `Order0`, but with separate statistics for every
+/// value of the previous byte
+pub struct Order1 {
+    bits: Vec<Bit>,
+}
+
+impl Order1 {
+    /// Create a new model with equal probabilities
+    pub fn new() -> Order1 {
+        Order1 {
+            bits: vec![Bit::new_equal(); 0x10000],
+        }
+    }
+
+    fn index(ctx: &Context) -> usize {
+        ((ctx.byte(1) as usize) << 8) | ctx.partial()
+    }
+}
+
+impl Default for Order1 {
+    fn default() -> Order1 {
+        Order1::new()
+    }
+}
+
+impl ContextModel for Order1 {
+    fn predict(&mut self, ctx: &Context) -> Bit {
+        self.bits[Order1::index(ctx)]
+    }
+
+    fn update(&mut self, ctx: &Context, bit: bool) {
+        self.bits[Order1::index(ctx)].update(bit, COUNTER_RATE, 0)
+    }
+}
+
+/// Match model: finds the last occurrence of the most recent bytes in the
+/// history and predicts the byte that followed it, with a confidence
+/// learned per match length
+pub struct MatchModel {
+    /// hash of the last `MATCH_MIN` bytes -> position following them
+    table: Vec<u32>,
+    mask: usize,
+    /// position of the predicted byte in the history, 0 if none
+    ptr: usize,
+    /// length of the current match
+    len: usize,
+    /// history length at the last byte boundary seen
+    seen: usize,
+    /// confidence per (length, expected bit)
+    bits: [Bit; 2 * (MATCH_LIMIT + 1)],
+    /// the confidence used for the current bit
+    slot: Option<usize>,
+}
+
+impl MatchModel {
+    /// Create a new model with a hash table of `2^table_bits` entries
+    pub fn new(table_bits: usize) -> MatchModel {
+        assert!(table_bits > 0 && table_bits <= 28);
+        MatchModel {
+            table: vec![0; 1 << table_bits],
+            mask: (1 << table_bits) - 1,
+            ptr: 0,
+            len: 0,
+            seen: 0,
+            bits: [Bit::new_equal(); 2 * (MATCH_LIMIT + 1)],
+            slot: None,
+        }
+    }
+
+    fn hash(bytes: &[u8]) -> usize {
+        bytes.iter().fold(0u32, |h, &b| (h ^ b as u32).wrapping_mul(0x2f0b_4c23)) as usize
+    }
+
+    // a new byte was completed, follow or look up the match
+    fn next_byte(&mut self, h: &[u8]) {
+        let n = h.len();
+        if self.len > 0 && h[self.ptr] == h[n - 1] {
+            self.ptr += 1;
+            self.len += 1;
+        } else {
+            self.len = 0;
+        }
+        if n < MATCH_MIN {
+            return
+        }
+        let key = MatchModel::hash(&h[n - MATCH_MIN..]) & self.mask;
+        if self.len == 0 {
+            let candidate = self.table[key] as usize;
+            if candidate > 0 {
+                self.len = h[..candidate].iter().rev()
+                                         .zip(h.iter().rev())
+                                         .take(MATCH_LIMIT)
+                                         .take_while(|&(a, b)| a == b)
+                                         .count();
+                self.ptr = candidate;
+            }
+        }
+        self.table[key] = n as u32;
+    }
+}
+
+impl ContextModel for MatchModel {
+    fn predict(&mut self, ctx: &Context) -> Bit {
+        let h = ctx.history();
+        if h.len() != self.seen {
+            self.seen = h.len();
+            self.next_byte(h);
+        }
+        self.slot = None;
+        if self.len == 0 {
+            return Bit::new_equal()
+        }
+        let expected = h[self.ptr] as usize | 0x100;
+        let shift = 8 - ctx.bit_position();
+        if expected >> shift != ctx.partial() {
+            // the current byte went another way
+            self.len = 0;
+            return Bit::new_equal()
+        }
+        let slot = 2 * self.len.min(MATCH_LIMIT) + ((expected >> (shift - 1)) & 1);
+        self.slot = Some(slot);
+        self.bits[slot]
+    }
+
+    fn update(&mut self, _ctx: &Context, bit: bool) {
+        if let Some(slot) = self.slot {
+            self.bits[slot].update(bit, SLOW_RATE, 0)
+        }
+    }
+}
+
+/// Mixes the predictions of several models by a weighted sum in the
+/// logistic domain. The weights are trained online to minimize the coding
+/// cost. A mixer is a model itself, so mixers can be nested.
+pub struct Mixer {
+    models: Vec<Box<dyn ContextModel>>,
+    inputs: Vec<i32>,
+    weights: Vec<i32>,
+    output: FlatProbability,
+    /// Shift applied to the weight updates, larger values learn slower
+    pub rate: usize,
+}
+
+impl Mixer {
+    /// Create a mixer without models
+    pub fn new() -> Mixer {
+        Mixer {
+            models: Vec::new(),
+            inputs: Vec::new(),
+            weights: Vec::new(),
+            output: Bit::new_equal().to_flat(),
+            rate: MIXER_RATE,
+        }
+    }
+
+    /// Add a model to the mix. The weights are reset to be equal.
+    pub fn add<M: ContextModel + 'static>(&mut self, model: M) {
+        self.models.push(Box::new(model));
+        self.inputs.push(0);
+        let w = (1 << WEIGHT_BITS) / self.models.len() as i32;
+        self.weights = vec![w; self.models.len()];
+    }
+
+    /// Return the number of models mixed
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    /// Return true if no model was added yet
+    pub fn is_empty(&self) -> bool {
+        self.models.is_empty()
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Mixer {
+        Mixer::new()
+    }
+}
+
+impl ContextModel for Mixer {
+    fn predict(&mut self, ctx: &Context) -> Bit {
+        let mut dot = 0i64;
+        for ((model, input), &w) in self.models.iter_mut().zip(self.inputs.iter_mut()).zip(self.weights.iter()) {
+            *input = clamp(model.predict(ctx)).to_wide() as i32;
+            dot += *input as i64 * w as i64;
+        }
+        let wide = (dot >> WEIGHT_BITS).clamp(-0x7fff, 0x7fff);
+        let bit = clamp(Bit::from_wide(wide as i16));
+        self.output = bit.to_flat();
+        bit
+    }
+
+    fn update(&mut self, ctx: &Context, bit: bool) {
+        let target = if bit { 0 } else { 4096 };
+        let err = target - self.output as i32;
+        for (&input, w) in self.inputs.iter().zip(self.weights.iter_mut()) {
+            *w += (input * err) >> self.rate;
+        }
+        for model in self.models.iter_mut() {
+            model.update(ctx, bit);
+        }
+    }
+}
+
+
+/// Context mixing encoder, writes the bytes through a `ContextModel`
+pub struct Encoder<W, M> {
+    encoder: super::Encoder<W>,
+    model: M,
+    ctx: Context,
+    end: Bit,
+}
+
+impl<W: Write, M: ContextModel> Encoder<W, M> {
+    /// Create a new encoder on top of a given Writer, predicting with `model`
+    pub fn new(w: W, model: M) -> Encoder<W, M> {
+        Encoder {
+            encoder: super::Encoder::new(w),
+            model,
+            ctx: Context::new(),
+            end: Bit::new_equal(),
+        }
+    }
+
+    /// Return the model, e.g. to inspect what it learned
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    /// Finish encoding by writing the end-of-data flag
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let ret = self.encoder.encode(true, &self.end);
+        let (w, r2) = self.encoder.finish();
+        (w, ret.and(r2))
+    }
+}
+
+impl<W: Write, M: ContextModel> Write for Encoder<W, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf.iter() {
+            try!(self.encoder.encode(false, &self.end));
+            self.end.update(false, SLOW_RATE, 0);
+            for i in (0..8).rev() {
+                let bit = (byte >> i) & 1 != 0;
+                let p = clamp(self.model.predict(&self.ctx));
+                try!(self.encoder.encode(bit, &p));
+                self.model.update(&self.ctx, bit);
+                self.ctx.push(bit);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+
+/// Context mixing decoder, expects a model identical to the encoder's
+pub struct Decoder<R, M> {
+    decoder: super::Decoder<R>,
+    model: M,
+    ctx: Context,
+    end: Bit,
+    is_eof: bool,
+}
+
+impl<R: Read, M: ContextModel> Decoder<R, M> {
+    /// Create a decoder on top of a given Reader, predicting with `model`
+    pub fn new(r: R, model: M) -> Decoder<R, M> {
+        Decoder {
+            decoder: super::Decoder::new(r),
+            model,
+            ctx: Context::new(),
+            end: Bit::new_equal(),
+            is_eof: false,
+        }
+    }
+
+    /// Finish decoding
+    pub fn finish(self) -> (R, io::Result<()>) {
+        self.decoder.finish()
+    }
+}
+
+impl<R: Read, M: ContextModel> Read for Decoder<R, M> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut amount = 0;
+        for out_byte in dst.iter_mut() {
+            if self.is_eof {
+                break
+            }
+            if try!(self.decoder.decode(&self.end)) {
+                self.is_eof = true;
+                break
+            }
+            self.end.update(false, SLOW_RATE, 0);
+            let mut byte = 0u8;
+            for _ in 0..8 {
+                let p = clamp(self.model.predict(&self.ctx));
+                let bit = try!(self.decoder.decode(&p));
+                self.model.update(&self.ctx, bit);
+                self.ctx.push(bit);
+                byte = (byte << 1) | bit as u8;
+            }
+            *out_byte = byte;
+            amount += 1;
+        }
+        Ok(amount)
+    }
+}
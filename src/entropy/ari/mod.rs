@@ -44,6 +44,7 @@ pub use self::table::{ByteDecoder, ByteEncoder};
 
 pub mod apm;
 pub mod bin;
+pub mod cm;
 pub mod table;
 #[cfg(test)]
 mod test;
@@ -72,7 +73,9 @@ pub struct RangeEncoder {
     /// and optimally many times larger
     pub threshold: Border,
     /// Tuning parameters
+    #[cfg(tune)]
     bits_lost_on_threshold_cut: f32,
+    #[cfg(tune)]
     bits_lost_on_division: f32,
 }
 
@@ -85,7 +88,9 @@ impl RangeEncoder {
             low: 0,
             hai: !0,
             threshold: max_range,
+            #[cfg(tune)]
             bits_lost_on_threshold_cut: 0.0,
+            #[cfg(tune)]
             bits_lost_on_division: 0.0,
         }
     }
@@ -101,11 +106,6 @@ impl RangeEncoder {
         -((range as f32) / (total as f32)).log2()
     }
 
-    #[cfg(not(tune))]
-    fn count_bits(_range: Border, _total: Border) -> f32 {
-        0.0
-    }
-
     /// Return the number of bits lost due to threshold cuts and integer operations
     #[cfg(tune)]
     pub fn get_bits_lost(&self) -> (f32, f32) {
@@ -120,25 +120,28 @@ impl RangeEncoder {
         let range = old_range / total;
         debug_assert!(range>0, "RangeCoder range is too narrow [{}-{}) for the total {}",
             self.low, self.hai, total);
-        debug!("\t\tProcessing [{}-{})/{} with range {}", from, to, total, range);
+        trace!(target: "compress::entropy::ari", "\t\tProcessing [{}-{})/{} with range {}", from, to, total, range);
         let mut lo = self.low + range*from;
         let mut hi = self.low + range*to;
-        self.bits_lost_on_division += RangeEncoder::count_bits(range*total, old_range);
+        #[cfg(tune)]
+        { self.bits_lost_on_division += RangeEncoder::count_bits(range*total, old_range); }
         let mut num_shift = 0;
         loop {
             if (lo^hi) & BORDER_SYMBOL_MASK != 0 {
                 if hi-lo > self.threshold {
                     break
                 }
+                #[cfg(tune)]
                 let old_range = hi-lo;
                 let lim = hi & BORDER_SYMBOL_MASK;
                 if hi-lim >= lim-lo {lo=lim}
                 else {hi=lim-1};
                 debug_assert!(lo < hi);
-                self.bits_lost_on_threshold_cut += RangeEncoder::count_bits(hi-lo, old_range);
+                #[cfg(tune)]
+                { self.bits_lost_on_threshold_cut += RangeEncoder::count_bits(hi-lo, old_range); }
             }
 
-            debug!("\t\tShifting on [{}-{}) to symbol {}", lo, hi, lo>>BORDER_EXCESS);
+            trace!(target: "compress::entropy::ari", "\t\tShifting on [{}-{}) to symbol {}", lo, hi, lo>>BORDER_EXCESS);
             output[num_shift] = (lo>>BORDER_EXCESS) as Symbol;
             num_shift += 1;
             lo<<=SYMBOL_BITS; hi<<=SYMBOL_BITS;
@@ -151,7 +154,7 @@ impl RangeEncoder {
 
     /// Query the value encoded by 'code' in range [0,total)
     pub fn query(&self, total: Border, code: Border) -> Border {
-        debug!("\t\tQuerying code {} of total {} under range [{}-{})",
+        trace!(target: "compress::entropy::ari", "\t\tQuerying code {} of total {} under range [{}-{})",
             code, total, self.low, self.hai);
         debug_assert!(self.low <= code && code < self.hai);
         let range = (self.hai - self.low) / total;
@@ -184,7 +187,7 @@ pub trait Model<V: Copy + Display> {
     fn encode(&self, value: V, re: &mut RangeEncoder, out: &mut [Symbol]) -> usize {
         let (lo, hi) = self.get_range(value);
         let total = self.get_denominator();
-        debug!("\tEncoding value {} of range [{}-{}) with total {}", value, lo, hi, total);
+        trace!(target: "compress::entropy::ari", "\tEncoding value {} of range [{}-{}) with total {}", value, lo, hi, total);
         re.process(total, lo, hi, out)
     }
 
@@ -194,7 +197,7 @@ pub trait Model<V: Copy + Display> {
         let total = self.get_denominator();
         let offset = re.query(total, code);
         let (value, lo, hi) = self.find_value(offset);
-        debug!("\tDecoding value {} of offset {} with total {}", value, offset, total);
+        trace!(target: "compress::entropy::ari", "\tDecoding value {} of offset {} with total {}", value, offset, total);
         let mut out = [0 as Symbol; BORDER_BYTES];
         let shift = re.process(total, lo, hi, &mut out[..]);
         debug_assert_eq!(if shift==0 {0} else {code>>(BORDER_BITS - shift*8)},
@@ -213,9 +216,15 @@ pub struct Encoder<W> {
 impl<W: Write> Encoder<W> {
     /// Create a new encoder on top of a given Writer
     pub fn new(w: W) -> Encoder<W> {
+        Encoder::with_threshold(w, RANGE_DEFAULT_THRESHOLD)
+    }
+
+    /// Create a new encoder keeping the range above 'threshold',
+    /// which has to be larger than the totals of the models used
+    pub fn with_threshold(w: W, threshold: Border) -> Encoder<W> {
         Encoder {
             stream: w,
-            range: RangeEncoder::new(RANGE_DEFAULT_THRESHOLD),
+            range: RangeEncoder::new(threshold),
         }
     }
 
@@ -249,6 +258,10 @@ impl<W: Write> Encoder<W> {
     }
 }
 
+fn corrupt() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "arithmetic code out of range")
+}
+
 /// An arithmetic decoder helper
 pub struct Decoder<R> {
     stream: R,
@@ -260,9 +273,14 @@ pub struct Decoder<R> {
 impl<R: Read> Decoder<R> {
     /// Create a decoder on top of a given Reader
     pub fn new(r: R) -> Decoder<R> {
+        Decoder::with_threshold(r, RANGE_DEFAULT_THRESHOLD)
+    }
+
+    /// Create a decoder for data encoded with `Encoder::with_threshold`
+    pub fn with_threshold(r: R, threshold: Border) -> Decoder<R> {
         Decoder {
             stream: r,
-            range: RangeEncoder::new(RANGE_DEFAULT_THRESHOLD),
+            range: RangeEncoder::new(threshold),
             code: 0,
             bytes_pending: BORDER_BYTES,
         }
@@ -279,8 +297,22 @@ impl<R: Read> Decoder<R> {
 
     /// Decode an abstract value based on the given Model
     pub fn decode<V: Copy + Display, M: Model<V>>(&mut self, model: &M) -> io::Result<V> {
-        self.feed().unwrap();
-        let (value, shift) = model.decode(self.code, &mut self.range);
+        try!(self.feed());
+        // same as `Model::decode`, but a corrupt stream can leave the code
+        // outside of the range, or point past the end of the model
+        let total = model.get_denominator();
+        if self.code < self.range.low || self.code >= self.range.hai ||
+                self.range.query(total, self.code) >= total {
+            return Err(corrupt())
+        }
+        let offset = self.range.query(total, self.code);
+        let (value, lo, hi) = model.find_value(offset);
+        let mut out = [0 as Symbol; BORDER_BYTES];
+        let shift = self.range.process(total, lo, hi, &mut out[..]);
+        let code = if shift == 0 { 0 } else { self.code >> (BORDER_BITS - shift*8) };
+        if code != out[..shift].iter().fold(0 as Border, |u,&b| (u<<8)+(b as Border)) {
+            return Err(corrupt())
+        }
         self.bytes_pending = shift;
         Ok(value)
     }
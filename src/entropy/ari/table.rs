@@ -69,7 +69,7 @@ impl Model {
     pub fn update(&mut self, value: usize, add_log: usize, add_const: Border) {
         let add = (self.total>>add_log) + add_const;
         assert!(add < 2*self.cut_threshold);
-        debug!("\tUpdating by adding {} to value {}", add, value);
+        trace!(target: "compress::entropy::ari", "\tUpdating by adding {} to value {}", add, value);
         self.table[value] += add as Frequency;
         self.total += add;
         if self.total >= self.cut_threshold {
@@ -80,7 +80,7 @@ impl Model {
 
     /// Reduce frequencies by 'cut_iter' bits
     pub fn downscale(&mut self) {
-        debug!("\tDownscaling frequencies");
+        trace!(target: "compress::entropy::ari", "\tDownscaling frequencies");
         let roundup = (1<<self.cut_shift) - 1;
         self.total = 0;
         for freq in self.table.iter_mut() {
@@ -250,6 +250,11 @@ impl<R: Read> ByteDecoder<R> {
     pub fn finish(self) -> (R, io::Result<()>) {
         self.decoder.finish()
     }
+
+    /// Decode and throw away the next `n` bytes, return how many were skipped
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        io::copy(&mut self.by_ref().take(n), &mut io::sink())
+    }
 }
 
 impl<R: Read> Read for ByteDecoder<R> {
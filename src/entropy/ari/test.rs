@@ -6,13 +6,13 @@ use test::Bencher;
 static TEXT_INPUT: &'static [u8] = include_bytes!("../../data/test.txt");
 
 fn roundtrip(bytes: &[u8]) {
-    info!("Roundtrip Ari of size {}", bytes.len());
+    info!(target: "compress::entropy::ari", "Roundtrip Ari of size {}", bytes.len());
     let mut e = super::table::ByteEncoder::new(BufWriter::new(Vec::new()));
     e.write(bytes).unwrap();
     let (e, r) = e.finish();
     r.unwrap();
     let encoded = e.into_inner().unwrap();
-    debug!("Roundtrip input {:?} encoded {:?}", bytes, encoded);
+    debug!(target: "compress::entropy::ari", "Roundtrip input {:?} encoded {:?}", bytes, encoded);
     let mut d = super::ByteDecoder::new(BufReader::new(&encoded[..]));
     let mut decoded = Vec::new();
     d.read_to_end(&mut decoded).unwrap();
@@ -66,7 +66,7 @@ fn roundtrip_term(bytes1: &[u8], bytes2: &[u8]) {
         stream
     };
     let encoded = mw.into_inner().unwrap();
-    debug!("Roundtrip term input {:?}:{:?} encoded {:?}", bytes1, bytes2, encoded);
+    debug!(target: "compress::entropy::ari", "Roundtrip term input {:?}:{:?} encoded {:?}", bytes1, bytes2, encoded);
     let br = BufReader::new(&encoded[..]);
     let br = {
         let mut d = super::ByteDecoder::new(br);
@@ -209,6 +209,58 @@ fn roundtrips_proxy() {
 #[test]
 fn roundtrips_apm() {
     roundtrip_apm(b"abracadabra");
+    roundtrip_apm(TEXT_INPUT);
+}
+
+fn cm_model() -> super::cm::Mixer {
+    use super::cm;
+    let mut mixer = cm::Mixer::new();
+    mixer.add(cm::Order0::new());
+    mixer.add(cm::Order1::new());
+    mixer.add(cm::MatchModel::new(16));
+    mixer
+}
+
+fn roundtrip_cm(bytes: &[u8]) -> usize {
+    let mut e = super::cm::Encoder::new(Vec::new(), cm_model());
+    e.write_all(bytes).unwrap();
+    let (encoded, r) = e.finish();
+    r.unwrap();
+    let mut d = super::cm::Decoder::new(&encoded[..], cm_model());
+    let mut decoded = Vec::new();
+    d.read_to_end(&mut decoded).unwrap();
+    assert_eq!(bytes, &decoded[..]);
+    encoded.len()
+}
+
+#[test]
+fn roundtrips_cm() {
+    roundtrip_cm(b"");
+    roundtrip_cm(b"abracadabra");
+    let size = roundtrip_cm(TEXT_INPUT);
+    // the order-0 byte coder is the baseline to beat
+    let mut e = super::ByteEncoder::new(Vec::new());
+    e.write_all(TEXT_INPUT).unwrap();
+    assert!(size < e.finish().0.len());
+    // the match model picks up the repetition
+    let twice: Vec<u8> = TEXT_INPUT.iter().chain(TEXT_INPUT.iter()).cloned().collect();
+    assert!(roundtrip_cm(&twice) < size + size / 8);
+}
+
+#[test]
+fn wide_probability() {
+    use super::apm::Bit;
+    assert!(Bit::new_equal().to_wide().abs() <= 8);
+    assert!((Bit::from_wide(0).to_flat() as isize - 2048).abs() <= 1);
+    // stretching and squashing back stays close
+    for fp in (64..4096-64).step_by(61) {
+        let back = Bit::from_wide(Bit::from_flat(fp).to_wide()).to_flat();
+        assert!((back as isize - fp as isize).abs() <= fp as isize / 32 + 2,
+            "{} came back as {}", fp, back);
+    }
+    // the tables are the same on every build, so are streams
+    assert_eq!((Bit::from_flat(1000).to_wide(), Bit::from_flat(3500).to_wide()), (-2336, 3672));
+    assert_eq!((Bit::from_wide(-700).to_flat(), Bit::from_wide(1500).to_flat()), (1706, 2754));
 }
 
 #[cfg(feature="unstable")]
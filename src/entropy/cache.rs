@@ -0,0 +1,36 @@
+//! Decoding tables shared by the decoders of a process, so that streams
+//! decoded at the same time with the same code build its table only once
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+
+// Tables of type `T` built from keys of type `K`. Only weak references are
+// kept: a table goes away with the last decoder using it, and the entries
+// of those gone are dropped whenever another table is built.
+pub struct Cache<K, T> {
+    tables: Mutex<Option<HashMap<K, Weak<T>>>>,
+}
+
+impl<K: Hash + Eq, T> Cache<K, T> {
+    pub const fn new() -> Cache<K, T> {
+        Cache { tables: Mutex::new(None) }
+    }
+
+    // Returns the table for `key`, built with `build` unless a decoder holds
+    // one already
+    pub fn get<F>(&self, key: K, build: F) -> io::Result<Arc<T>>
+        where F: FnOnce(&K) -> io::Result<T>
+    {
+        let mut tables = self.tables.lock().unwrap();
+        let tables = tables.get_or_insert_with(HashMap::new);
+        if let Some(table) = tables.get(&key).and_then(Weak::upgrade) {
+            return Ok(table)
+        }
+        let table = Arc::new(try!(build(&key)));
+        tables.retain(|_, table| table.strong_count() > 0);
+        tables.insert(key, Arc::downgrade(&table));
+        Ok(table)
+    }
+}
@@ -0,0 +1,414 @@
+/*!
+
+Canonical Huffman coding. Requires `entropy` feature, enabled by default
+
+`code_lengths` finds the optimal code lengths for a set of symbol
+frequencies under a maximum length, with the package-merge algorithm.
+`canonical_codes` turns lengths into the canonical code, where shorter codes
+come first and codes of the same length follow the symbol order, the
+convention of DEFLATE and bzip2. A canonical code is fully described by its
+lengths, so formats only need to store those.
+
+`Encoder` and `Decoder` write and read symbols of such a code as a
+bitstream, most significant bit first, and can interleave raw bits for
+anything else a format needs to store.
+
+# Links
+
+* http://en.wikipedia.org/wiki/Canonical_Huffman_code
+* http://en.wikipedia.org/wiki/Package-merge_algorithm
+
+# Example
+
+```rust
+use compress::entropy::huff;
+
+let text = b"abracadabra";
+let mut freqs = [0u32; 256];
+for &b in text.iter() {
+    freqs[b as usize] += 1;
+}
+let lens = huff::code_lengths(&freqs, 15);
+
+let mut e = huff::Encoder::new(Vec::new(), &lens).unwrap();
+for &b in text.iter() {
+    e.encode(b as usize).unwrap();
+}
+let (encoded, _) = e.finish();
+
+let mut d = huff::Decoder::new(&encoded[..], &lens).unwrap();
+for &b in text.iter() {
+    assert_eq!(d.decode().unwrap(), b as usize);
+}
+```
+
+# Credit
+
+Lawrence Larmore and Daniel Hirschberg for package-merge, Mark Adler's
+`puff` for the canonical decoding loop.
+
+*/
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use super::super::byteorder::ReadBytesExt;
+use super::super::byteorder_err_to_io;
+use super::cache::Cache;
+
+/// The longest code supported
+pub const MAX_CODE_LEN: u8 = 32;
+
+// the tables of the decoders alive, by code lengths
+static TABLES: Cache<Vec<u8>, Table> = Cache::new();
+
+fn invalid<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+#[derive(Clone, Copy)]
+enum Item {
+    Leaf(usize),
+    // two items of the previous level
+    Package(usize),
+}
+
+/// Computes the lengths of an optimal prefix code for the given frequencies,
+/// with no code longer than `max_len`. Symbols of zero frequency get no code
+/// (length 0). A lone symbol gets a 1-bit code.
+///
+/// Panics if `max_len` is too small to give every used symbol a code, or
+/// larger than `MAX_CODE_LEN`.
+pub fn code_lengths(freqs: &[u32], max_len: u8) -> Vec<u8> {
+    assert!(max_len > 0 && max_len <= MAX_CODE_LEN);
+    let mut lens = vec![0u8; freqs.len()];
+    let mut leaves: Vec<(u64, Item)> = freqs.iter().enumerate()
+                                            .filter(|&(_, &f)| f > 0)
+                                            .map(|(i, &f)| (f as u64, Item::Leaf(i)))
+                                            .collect();
+    match leaves.len() {
+        0 => return lens,
+        1 => {
+            if let Item::Leaf(s) = leaves[0].1 {
+                lens[s] = 1;
+            }
+            return lens
+        }
+        n => assert!(n as u64 <= 1u64 << max_len,
+                     "{} symbols don't fit into codes of {} bits", n, max_len),
+    }
+    leaves.sort_by_key(|&(w, _)| w);
+
+    // each level merges the leaves with the pairs of the previous level
+    let mut levels: Vec<Vec<(u64, Item)>> = vec![leaves.clone()];
+    for _ in 1..max_len {
+        let packages: Vec<(u64, Item)> = {
+            let prev = levels.last().unwrap();
+            prev.chunks(2).filter(|pair| pair.len() == 2)
+                .enumerate()
+                .map(|(i, pair)| (pair[0].0 + pair[1].0, Item::Package(2 * i)))
+                .collect()
+        };
+        let mut merged = Vec::with_capacity(leaves.len() + packages.len());
+        let (mut a, mut b) = (0, 0);
+        while a < leaves.len() || b < packages.len() {
+            if b == packages.len() || (a < leaves.len() && leaves[a].0 <= packages[b].0) {
+                merged.push(leaves[a]);
+                a += 1;
+            } else {
+                merged.push(packages[b]);
+                b += 1;
+            }
+        }
+        levels.push(merged);
+    }
+
+    // every leaf among the first 2n-2 items adds a bit to its symbol
+    let top = levels.len() - 1;
+    let mut stack: Vec<(usize, usize)> = (0..2 * leaves.len() - 2).map(|i| (top, i)).collect();
+    while let Some((level, i)) = stack.pop() {
+        match levels[level][i].1 {
+            Item::Leaf(s) => lens[s] += 1,
+            Item::Package(first) => {
+                stack.push((level - 1, first));
+                stack.push((level - 1, first + 1));
+            }
+        }
+    }
+    lens
+}
+
+/// Assigns the canonical codes to the given lengths. Symbols of length 0
+/// get no code, their entry is left at 0. Fails if the lengths describe an
+/// over-subscribed code.
+pub fn canonical_codes(lens: &[u8]) -> io::Result<Vec<u32>> {
+    let mut count = [0u64; MAX_CODE_LEN as usize + 1];
+    for &len in lens.iter() {
+        if len > MAX_CODE_LEN {
+            return invalid("Huffman code length too large")
+        }
+        count[len as usize] += 1;
+    }
+    count[0] = 0;
+    let mut next = [0u64; MAX_CODE_LEN as usize + 1];
+    let mut code = 0u64;
+    for len in 1..MAX_CODE_LEN as usize + 1 {
+        code = (code + count[len - 1]) << 1;
+        next[len] = code;
+        if code + count[len] > 1 << len {
+            return invalid("over-subscribed Huffman code lengths")
+        }
+    }
+    Ok(lens.iter().map(|&len| {
+        if len == 0 {
+            return 0
+        }
+        let code = next[len as usize];
+        next[len as usize] += 1;
+        code as u32
+    }).collect())
+}
+
+/// Writes symbols of a canonical Huffman code to a bitstream
+pub struct Encoder<W> {
+    w: W,
+    lens: Vec<u8>,
+    codes: Vec<u32>,
+    buf: u64,
+    cnt: u32,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder for the code with the given lengths, writing to `w`
+    pub fn new(w: W, lens: &[u8]) -> io::Result<Encoder<W>> {
+        let codes = try!(canonical_codes(lens));
+        Ok(Encoder {
+            w,
+            lens: lens.to_vec(),
+            codes,
+            buf: 0,
+            cnt: 0,
+        })
+    }
+
+    /// Writes the code of `symbol`, which must have one
+    pub fn encode(&mut self, symbol: usize) -> io::Result<()> {
+        match self.lens.get(symbol) {
+            Some(&len) if len > 0 => {
+                let code = self.codes[symbol];
+                self.write_bits(code, len as u32)
+            }
+            _ => invalid("symbol has no Huffman code"),
+        }
+    }
+
+    /// Writes the `cnt` low bits of `bits`, most significant first
+    pub fn write_bits(&mut self, bits: u32, cnt: u32) -> io::Result<()> {
+        debug_assert!(cnt <= 32);
+        self.buf = self.buf << cnt | (bits as u64 & ((1 << cnt) - 1));
+        self.cnt += cnt;
+        while self.cnt >= 8 {
+            self.cnt -= 8;
+            try!(self.w.write_all(&[(self.buf >> self.cnt) as u8]));
+        }
+        Ok(())
+    }
+
+    /// Pads the last byte with zero bits and returns the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let pad = (8 - self.cnt % 8) % 8;
+        let result = self.write_bits(0, pad).and_then(|_| self.w.flush());
+        (self.w, result)
+    }
+}
+
+/// The decoding table of a canonical Huffman code. It never changes once
+/// built, so decoders of the same code share one through an `Arc` instead
+/// of each building their own, see `Decoder::new`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Table {
+    /// number of codes of each length
+    count: Vec<u32>,
+    /// symbols ordered by their code
+    symbols: Vec<usize>,
+}
+
+impl Table {
+    /// Builds the table for the code with the given lengths
+    pub fn new(lens: &[u8]) -> io::Result<Table> {
+        try!(canonical_codes(lens));
+        let max = lens.iter().cloned().max().unwrap_or(0) as usize;
+        let mut count = vec![0; max + 1];
+        for &len in lens.iter() {
+            count[len as usize] += 1;
+        }
+        count[0] = 0;
+        let mut symbols = Vec::with_capacity(lens.len());
+        for len in 1..max + 1 {
+            symbols.extend(lens.iter().enumerate()
+                               .filter(|&(_, &l)| l as usize == len)
+                               .map(|(s, _)| s));
+        }
+        Ok(Table { count, symbols })
+    }
+}
+
+/// Reads symbols of a canonical Huffman code from a bitstream
+pub struct Decoder<R> {
+    r: R,
+    table: Arc<Table>,
+    buf: u64,
+    cnt: u32,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder for the code with the given lengths, reading from
+    /// `r`. The table is only built if no other decoder of the same code is
+    /// alive, in this thread or another, otherwise theirs is shared.
+    pub fn new(r: R, lens: &[u8]) -> io::Result<Decoder<R>> {
+        let table = try!(TABLES.get(lens.to_vec(), |lens| Table::new(lens)));
+        Ok(Decoder::with_table(r, table))
+    }
+
+    /// Creates a decoder reading from `r` with a table built beforehand,
+    /// which costs nothing but a reference count, not even the lookup of
+    /// `new`
+    pub fn with_table(r: R, table: Arc<Table>) -> Decoder<R> {
+        Decoder {
+            r,
+            table,
+            buf: 0,
+            cnt: 0,
+        }
+    }
+
+    /// Returns the table of this decoder, for other decoders of the same
+    /// code to share
+    pub fn table(&self) -> &Arc<Table> {
+        &self.table
+    }
+
+    /// Reads the next symbol
+    pub fn decode(&mut self) -> io::Result<usize> {
+        // code - first is the index of the code among those of its length
+        let (mut code, mut first, mut index) = (0i64, 0i64, 0i64);
+        for len in 1..self.table.count.len() {
+            code |= try!(self.read_bits(1)) as i64;
+            let count = self.table.count[len] as i64;
+            if code - first < count {
+                return Ok(self.table.symbols[(index + code - first) as usize])
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(io::Error::new(io::ErrorKind::InvalidData, "invalid Huffman code"))
+    }
+
+    /// Reads `cnt` raw bits, most significant first
+    pub fn read_bits(&mut self, cnt: u32) -> io::Result<u32> {
+        debug_assert!(cnt <= 32);
+        while self.cnt < cnt {
+            let byte = try!(self.r.read_u8().map_err(byteorder_err_to_io));
+            self.buf = self.buf << 8 | byte as u64;
+            self.cnt += 8;
+        }
+        self.cnt -= cnt;
+        Ok(((self.buf >> self.cnt) & ((1 << cnt) - 1)) as u32)
+    }
+
+    /// Returns the wrapped reader. Bits read ahead of the last symbol are
+    /// lost, which are at most the padding of the last byte.
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use super::{canonical_codes, code_lengths, Decoder, Encoder};
+
+    fn cost(freqs: &[u32], lens: &[u8]) -> u64 {
+        freqs.iter().zip(lens.iter()).map(|(&f, &l)| f as u64 * l as u64).sum()
+    }
+
+    fn kraft(lens: &[u8]) -> f64 {
+        lens.iter().filter(|&&l| l > 0).map(|&l| 0.5f64.powi(l as i32)).sum()
+    }
+
+    #[test]
+    fn lengths() {
+        // plain Huffman: a 1, b 2, c 3, d 3
+        let freqs = [10, 5, 2, 2];
+        assert_eq!(code_lengths(&freqs, 15), vec![1, 2, 3, 3]);
+        // limiting to 2 bits forces a flat code
+        assert_eq!(code_lengths(&freqs, 2), vec![2, 2, 2, 2]);
+        assert_eq!(code_lengths(&[0, 7, 0], 8), vec![0, 1, 0]);
+        assert_eq!(code_lengths(&[0, 0], 8), vec![0, 0]);
+
+        // fibonacci weights make the deepest unlimited tree
+        let mut fib = vec![1u32, 1];
+        while fib.len() < 30 {
+            let n = fib.len();
+            fib.push(fib[n - 1] + fib[n - 2]);
+        }
+        let free = code_lengths(&fib, 32);
+        assert_eq!(*free.iter().max().unwrap(), 29);
+        let limited = code_lengths(&fib, 12);
+        assert_eq!(*limited.iter().max().unwrap(), 12);
+        assert!(kraft(&limited) == 1.0);
+        assert!(cost(&fib, &limited) > cost(&fib, &free));
+    }
+
+    #[test]
+    fn codes() {
+        // the example of RFC 1951, section 3.2.2
+        let lens = [3, 3, 3, 3, 3, 2, 4, 4];
+        assert_eq!(canonical_codes(&lens).unwrap(),
+                   vec![0b010, 0b011, 0b100, 0b101, 0b110, 0b00, 0b1110, 0b1111]);
+        assert!(canonical_codes(&[1, 1, 1]).is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let input = include_bytes!("../data/test.txt");
+        let mut freqs = [0u32; 256];
+        for &b in input.iter() {
+            freqs[b as usize] += 1;
+        }
+        let lens = code_lengths(&freqs, 9);
+        let mut e = Encoder::new(Vec::new(), &lens).unwrap();
+        for &b in input.iter() {
+            e.encode(b as usize).unwrap();
+            e.write_bits(b as u32 & 1, 1).unwrap();
+        }
+        assert!(e.encode(0).is_err());
+        let (encoded, result) = e.finish();
+        result.unwrap();
+        assert!(encoded.len() as u64 == (cost(&freqs, &lens) + input.len() as u64).div_ceil(8));
+
+        let mut d = Decoder::new(&encoded[..], &lens).unwrap();
+        for &b in input.iter() {
+            assert_eq!(d.decode().unwrap(), b as usize);
+            assert_eq!(d.read_bits(1).unwrap(), b as u32 & 1);
+        }
+
+        // decoders of the same code on other threads share the table
+        let table = d.table().clone();
+        let threads = (0..4).map(|_| {
+            let (encoded, lens, table) = (encoded.clone(), lens.clone(), table.clone());
+            thread::spawn(move || {
+                let mut d = Decoder::new(&encoded[..], &lens).unwrap();
+                Arc::ptr_eq(d.table(), &table) && input.iter().all(|&b| {
+                    d.decode().unwrap() == b as usize && d.read_bits(1).unwrap() == b as u32 & 1
+                })
+            })
+        }).collect::<Vec<_>>();
+        for t in threads {
+            assert!(t.join().unwrap());
+        }
+        assert_eq!(Arc::strong_count(&table), 2);
+    }
+}
@@ -0,0 +1,432 @@
+/*!
+
+PPM (prediction by partial matching) byte coder. Requires `entropy` feature, enabled by default
+
+PPM predicts every byte from the bytes before it, its context, trying the longest context first. Each
+context counts the bytes seen after it. If the byte to code has been seen in the context it is coded
+with the counts, otherwise an escape is coded and the next shorter context is tried, down to a flat
+distribution over all bytes. The probabilities follow PPMD: a byte seen `c` times out of `n` gets
+`(2c - 1) / 2n` and the escape `u / 2n`, `u` being the number of distinct bytes seen. Bytes already
+ruled out by a longer context are excluded from the shorter ones, and only the contexts from the
+one the byte was found in up are updated.
+
+The counts are kept in a hash table, up to `MAX_CONTEXTS` contexts; past that the model starts over
+from scratch. The coding itself is done by the arithmetic coder of `ari`. The stream has no header,
+so the decoder has to be given the order the data was encoded with.
+
+This is an experimental coder: it compresses text better than the other entropy coders of this
+crate, but it is slow and its memory use grows with the order.
+
+# Links
+
+* http://en.wikipedia.org/wiki/Prediction_by_partial_matching
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::entropy::ppm;
+
+let text = b"abracadabra abracadabra abracadabra";
+let mut e = ppm::Encoder::new(Vec::new());
+e.write_all(text).unwrap();
+let (encoded, result) = e.finish();
+result.unwrap();
+
+let mut d = ppm::Decoder::new(&encoded[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], &text[..]);
+```
+
+# Credit
+
+PPM is due to John Cleary and Ian Witten, the PPMD estimate to Paul Howard.
+
+*/
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use super::ari::{self, Border};
+
+/// Order used by `Encoder::new` and `Decoder::new`
+pub const DEFAULT_ORDER: usize = 4;
+/// Longest context supported
+pub const MAX_ORDER: usize = 7;
+/// Number of contexts after which the model starts over
+pub const MAX_CONTEXTS: usize = 1 << 20;
+
+// the escape in a context, and the end of the stream in the flat distribution
+const ESCAPE: usize = 256;
+const END: usize = 256;
+// counts are halved past this, keeping the denominators well below the
+// threshold of the range coder
+const MAX_TOTAL: u32 = ari::RANGE_DEFAULT_THRESHOLD >> 3;
+
+// The bytes seen after a context and how many times
+struct Context {
+    counts: Vec<(u8, u16)>,
+    total: u32,
+}
+
+impl Context {
+    fn update(&mut self, byte: u8) {
+        match self.counts.iter().position(|&(b, _)| b == byte) {
+            Some(i) => self.counts[i].1 += 1,
+            None => self.counts.push((byte, 1)),
+        }
+        self.total += 1;
+        if self.total > MAX_TOTAL {
+            self.total = 0;
+            for &mut (_, ref mut count) in self.counts.iter_mut() {
+                *count = (*count + 1) >> 1;
+                self.total += *count as u32;
+            }
+        }
+    }
+}
+
+// The PPMD distribution of a context without the excluded bytes
+struct Masked<'a> {
+    context: &'a Context,
+    excluded: &'a [bool; 256],
+    total: Border,
+}
+
+impl<'a> Masked<'a> {
+    fn new(context: &'a Context, excluded: &'a [bool; 256]) -> Masked<'a> {
+        let total = context.counts.iter()
+            .filter(|&&(b, _)| !excluded[b as usize])
+            .fold(0, |sum, &(_, c)| sum + 2 * c as Border - 1);
+        Masked { context, excluded, total }
+    }
+
+    // whether everything seen in the context is excluded, leaving the
+    // escape as the only choice
+    fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    fn weights(&self) -> Box<dyn Iterator<Item = (usize, Border)> + 'a> {
+        let excluded = self.excluded;
+        let symbols = self.context.counts.iter()
+            .filter(move |&&(b, _)| !excluded[b as usize])
+            .map(|&(b, c)| (b as usize, 2 * c as Border - 1));
+        Box::new(symbols.chain(Some((ESCAPE, self.context.counts.len() as Border))))
+    }
+}
+
+impl<'a> ari::Model<usize> for Masked<'a> {
+    fn get_range(&self, value: usize) -> (Border, Border) {
+        let mut lo = 0;
+        for (symbol, weight) in self.weights() {
+            if symbol == value {
+                return (lo, lo + weight)
+            }
+            lo += weight;
+        }
+        unreachable!("{} is not in the context", value)
+    }
+
+    // an offset past the end, which only corrupt data gives, picks the escape
+    fn find_value(&self, offset: Border) -> (usize, Border, Border) {
+        let mut lo = 0;
+        let mut last = (ESCAPE, 0, 0);
+        for (symbol, weight) in self.weights() {
+            last = (symbol, lo, lo + weight);
+            if offset < lo + weight {
+                break
+            }
+            lo += weight;
+        }
+        last
+    }
+
+    fn get_denominator(&self) -> Border {
+        self.total + self.context.counts.len() as Border
+    }
+}
+
+// The flat distribution over the bytes not excluded and the end
+struct Flat<'a> {
+    excluded: &'a [bool; 256],
+}
+
+impl<'a> ari::Model<usize> for Flat<'a> {
+    fn get_range(&self, value: usize) -> (Border, Border) {
+        let lo = self.excluded[..value].iter().filter(|&&e| !e).count() as Border;
+        (lo, lo + 1)
+    }
+
+    fn find_value(&self, offset: Border) -> (usize, Border, Border) {
+        let mut lo = 0;
+        for (value, &e) in self.excluded.iter().enumerate() {
+            if !e {
+                if lo == offset {
+                    return (value, lo, lo + 1)
+                }
+                lo += 1;
+            }
+        }
+        (END, lo, lo + 1)
+    }
+
+    fn get_denominator(&self) -> Border {
+        self.excluded.iter().filter(|&&e| !e).count() as Border + 1
+    }
+}
+
+// The contexts and the bytes before, shared by the encoder and the decoder
+struct Model {
+    order: usize,
+    contexts: HashMap<u64, Context>,
+    // the last bytes, the latest in the low byte
+    history: u64,
+    seen: usize,
+    excluded: [bool; 256],
+}
+
+impl Model {
+    fn new(order: usize) -> Model {
+        assert!(order <= MAX_ORDER, "PPM order {} is above {}", order, MAX_ORDER);
+        Model {
+            order,
+            contexts: HashMap::new(),
+            history: 0,
+            seen: 0,
+            excluded: [false; 256],
+        }
+    }
+
+    // the longest context there are bytes for
+    fn top(&self) -> usize {
+        if self.seen < self.order { self.seen } else { self.order }
+    }
+
+    fn key(&self, order: usize) -> u64 {
+        (order as u64) << 56 | (self.history & ((1 << (8 * order)) - 1))
+    }
+
+    fn exclude(&mut self, order: usize) {
+        let context = &self.contexts[&self.key(order)];
+        for &(b, _) in context.counts.iter() {
+            self.excluded[b as usize] = true;
+        }
+    }
+
+    // Counts `byte` in the contexts from `found` up, or in all of them if
+    // it came from the flat distribution
+    fn update(&mut self, byte: u8, found: Option<usize>) {
+        let from = found.unwrap_or(0);
+        for order in from..self.top() + 1 {
+            let key = self.key(order);
+            self.contexts.entry(key)
+                .or_insert_with(|| Context { counts: Vec::new(), total: 0 })
+                .update(byte);
+        }
+        if self.contexts.len() > MAX_CONTEXTS {
+            self.contexts.clear();
+        }
+        self.history = (self.history << 8) | byte as u64;
+        self.seen += 1;
+    }
+}
+
+/// PPM encoder
+pub struct Encoder<W> {
+    encoder: ari::Encoder<W>,
+    model: Model,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder of order `DEFAULT_ORDER` writing into `w`
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder::with_order(w, DEFAULT_ORDER)
+    }
+
+    /// Creates an encoder using contexts of up to `order` bytes, at most
+    /// `MAX_ORDER`
+    pub fn with_order(w: W, order: usize) -> Encoder<W> {
+        Encoder {
+            encoder: ari::Encoder::new(w),
+            model: Model::new(order),
+        }
+    }
+
+    // Codes a byte, or the end of the stream, returning the order of the
+    // context it was found in
+    fn encode(&mut self, value: usize) -> io::Result<Option<usize>> {
+        self.model.excluded = [false; 256];
+        for order in (0..self.model.top() + 1).rev() {
+            let model = &self.model;
+            let context = match model.contexts.get(&model.key(order)) {
+                Some(context) => context,
+                None => continue,
+            };
+            let dist = Masked::new(context, &model.excluded);
+            if dist.is_empty() {
+                continue
+            }
+            if value != END && context.counts.iter().any(|&(b, _)| b as usize == value) {
+                try!(self.encoder.encode(value, &dist));
+                return Ok(Some(order))
+            }
+            try!(self.encoder.encode(ESCAPE, &dist));
+            self.model.exclude(order);
+        }
+        try!(self.encoder.encode(value, &Flat { excluded: &self.model.excluded }));
+        Ok(None)
+    }
+
+    /// Writes the end of the stream and returns the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.encode(END);
+        let (w, rest) = self.encoder.finish();
+        (w, result.and(rest))
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf.iter() {
+            let found = try!(self.encode(byte as usize));
+            self.model.update(byte, found);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// PPM decoder
+pub struct Decoder<R> {
+    decoder: ari::Decoder<R>,
+    model: Model,
+    eof: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder of order `DEFAULT_ORDER` reading from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder::with_order(r, DEFAULT_ORDER)
+    }
+
+    /// Creates a decoder for data encoded with `Encoder::with_order`
+    pub fn with_order(r: R, order: usize) -> Decoder<R> {
+        Decoder {
+            decoder: ari::Decoder::new(r),
+            model: Model::new(order),
+            eof: false,
+        }
+    }
+
+    /// Returns the wrapped reader
+    pub fn finish(self) -> (R, io::Result<()>) {
+        self.decoder.finish()
+    }
+
+    fn decode(&mut self) -> io::Result<(usize, Option<usize>)> {
+        self.model.excluded = [false; 256];
+        for order in (0..self.model.top() + 1).rev() {
+            let model = &self.model;
+            let context = match model.contexts.get(&model.key(order)) {
+                Some(context) => context,
+                None => continue,
+            };
+            let dist = Masked::new(context, &model.excluded);
+            if dist.is_empty() {
+                continue
+            }
+            let value = try!(self.decoder.decode(&dist));
+            if value != ESCAPE {
+                return Ok((value, Some(order)))
+            }
+            self.model.exclude(order);
+        }
+        let value = try!(self.decoder.decode(&Flat { excluded: &self.model.excluded }));
+        Ok((value, None))
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut amount = 0;
+        while amount < buf.len() && !self.eof {
+            let (value, found) = try!(self.decode());
+            if value == END && found.is_none() {
+                self.eof = true;
+                break
+            }
+            buf[amount] = value as u8;
+            amount += 1;
+            self.model.update(value as u8, found);
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Encoder, Decoder};
+
+    fn roundtrip(bytes: &[u8], order: usize) -> usize {
+        let mut e = Encoder::with_order(Vec::new(), order);
+        e.write_all(bytes).unwrap();
+        let (encoded, result) = e.finish();
+        result.unwrap();
+        let mut decoded = Vec::new();
+        Decoder::with_order(&encoded[..], order).read_to_end(&mut decoded).unwrap();
+        assert!(decoded[..] == bytes[..], "order {} failed", order);
+        encoded.len()
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        let text = include_bytes!("../data/test.txt");
+        for order in 0..8 {
+            roundtrip(b"", order);
+            roundtrip(b"a", order);
+            roundtrip(b"abracadabra", order);
+            roundtrip(text, order);
+        }
+        let all: Vec<u8> = (0..10_000u32).map(|i| (i * 7 + i / 256) as u8).collect();
+        roundtrip(&all, 3);
+    }
+
+    #[test]
+    fn beats_order0() {
+        let input = include_bytes!("../data/test.txt");
+        let mut e = ::entropy::ari::ByteEncoder::new(Vec::new());
+        e.write_all(input).unwrap();
+        let (order0, _) = e.finish();
+        let size = roundtrip(input, 4);
+        assert!(size < order0.len(), "{} against {}", size, order0.len());
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(include_bytes!("../data/test.txt")).unwrap();
+        let (encoded, _) = e.finish();
+        let mut d = Decoder::new(&encoded[..encoded.len() / 2]);
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn flipped() {
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(&include_bytes!("../data/test.txt")[..512]).unwrap();
+        let (encoded, _) = e.finish();
+        for i in 0..encoded.len() {
+            for &mask in [0x01u8, 0x80, 0xff].iter() {
+                let mut corrupt = encoded.clone();
+                corrupt[i] ^= mask;
+                let _ = Decoder::new(&corrupt[..]).take(1024).read_to_end(&mut Vec::new());
+            }
+        }
+    }
+}
@@ -0,0 +1,321 @@
+/*!
+
+Carry-less range encoder/decoder, after Dmitry Subbotin. Requires `entropy`
+feature, enabled by default
+
+The coder keeps a 32-bit `low` and `range` and shifts out whole bytes. A carry
+can never propagate into bytes already written: whenever the top byte of the
+interval is still undecided while the range gets too small, the range is cut
+down to the next byte boundary instead. That wastes a tiny bit of code space
+but needs no carry handling nor buffering, which makes it faster than the
+classic arithmetic coder in `ari`. It uses the same `Model` trait, so every
+model of `ari` works here as well; their denominator has to stay below
+`BOTTOM`.
+
+# Links
+
+* http://www.compression.ru/ds/ (Subbotin's range coder)
+* http://en.wikipedia.org/wiki/Range_encoding
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::entropy::range;
+
+let text = "some text";
+let mut e = range::ByteEncoder::new(Vec::new());
+e.write_all(text.as_bytes()).unwrap();
+let (encoded, _) = e.finish();
+
+let mut d = range::ByteDecoder::new(&encoded[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], text.as_bytes());
+```
+
+# Credit
+
+Dmitry Subbotin for the carry-less normalization.
+
+*/
+
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+
+use super::super::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::super::byteorder_err_to_io;
+use super::ari::{self, Border, Model};
+
+/// Bytes are shifted out once the top byte of the interval is settled
+const TOP: Border = 1 << 24;
+/// The smallest range kept, and the limit for model denominators
+pub const BOTTOM: Border = 1 << 16;
+
+/// The encoding state, shared by the encoder and the decoder
+struct Range {
+    low: Border,
+    range: Border,
+}
+
+impl Range {
+    fn new() -> Range {
+        Range { low: 0, range: !0 }
+    }
+
+    // narrow down to [from/total, to/total) of the current range
+    fn narrow(&mut self, total: Border, from: Border, to: Border) {
+        debug_assert!(from < to && to <= total && total <= BOTTOM);
+        self.range /= total;
+        self.low = self.low.wrapping_add(from * self.range);
+        self.range *= to - from;
+    }
+
+    // returns true while a byte can be shifted out
+    fn settled(&mut self) -> bool {
+        if self.low ^ self.low.wrapping_add(self.range) < TOP {
+            true
+        } else if self.range < BOTTOM {
+            self.range = self.low.wrapping_neg() & (BOTTOM - 1);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn shift(&mut self) -> u8 {
+        let byte = (self.low >> 24) as u8;
+        self.low <<= 8;
+        self.range <<= 8;
+        byte
+    }
+}
+
+/// A range encoder of values under a `Model`
+pub struct Encoder<W> {
+    stream: W,
+    range: Range,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create a new encoder on top of a given Writer
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder {
+            stream: w,
+            range: Range::new(),
+        }
+    }
+
+    /// Encode an abstract value under the given Model
+    pub fn encode<V: Copy + Display, M: Model<V>>(&mut self, value: V, model: &M) -> io::Result<()> {
+        let (from, to) = model.get_range(value);
+        self.range.narrow(model.get_denominator(), from, to);
+        let mut buf = [0u8; 4];
+        let mut n = 0;
+        while self.range.settled() {
+            buf[n] = self.range.shift();
+            n += 1;
+        }
+        self.stream.write_all(&buf[..n])
+    }
+
+    /// Finish encoding by writing out the low end of the range
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.stream.write_u32::<BigEndian>(self.range.low)
+                                .and_then(|_| self.stream.flush());
+        (self.stream, result)
+    }
+
+    /// Flush the output stream
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A range decoder of values under a `Model`
+pub struct Decoder<R> {
+    stream: R,
+    range: Range,
+    code: Border,
+    started: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Create a decoder on top of a given Reader
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            stream: r,
+            range: Range::new(),
+            code: 0,
+            started: false,
+        }
+    }
+
+    /// Decode an abstract value based on the given Model
+    pub fn decode<V: Copy + Display, M: Model<V>>(&mut self, model: &M) -> io::Result<V> {
+        if !self.started {
+            self.code = try!(self.stream.read_u32::<BigEndian>().map_err(byteorder_err_to_io));
+            self.started = true;
+        }
+        let total = model.get_denominator();
+        let offset = self.code.wrapping_sub(self.range.low) / (self.range.range / total);
+        if offset >= total {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "range code out of bounds"))
+        }
+        let (value, from, to) = model.find_value(offset);
+        self.range.narrow(total, from, to);
+        while self.range.settled() {
+            self.range.shift();
+            let byte = try!(self.stream.read_u8().map_err(byteorder_err_to_io));
+            self.code = (self.code << 8) | byte as Border;
+        }
+        Ok(value)
+    }
+
+    /// Finish decoding
+    pub fn finish(self) -> (R, io::Result<()>) {
+        (self.stream, Ok(()))
+    }
+}
+
+
+/// A basic byte-encoding range coder with an adaptive frequency table,
+/// uses a special terminator code to end the stream
+pub struct ByteEncoder<W> {
+    /// A lower level encoder
+    pub encoder: Encoder<W>,
+    /// A basic frequency table
+    pub freq: ari::table::Model,
+}
+
+impl<W: Write> ByteEncoder<W> {
+    /// Create a new encoder on top of a given Writer
+    pub fn new(w: W) -> ByteEncoder<W> {
+        ByteEncoder {
+            encoder: Encoder::new(w),
+            freq: ari::table::Model::new_flat(0x101, BOTTOM >> 2),
+        }
+    }
+
+    /// Finish encoding & write the terminator symbol
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let ret = self.encoder.encode(0x100, &self.freq);
+        let (w, r2) = self.encoder.finish();
+        (w, ret.and(r2))
+    }
+}
+
+impl<W: Write> Write for ByteEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf.iter() {
+            let value = byte as usize;
+            try!(self.encoder.encode(value, &self.freq));
+            self.freq.update(value, 10, 1);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+/// A basic byte-decoding range coder,
+/// expects a special terminator code for the end of the stream
+pub struct ByteDecoder<R> {
+    /// A lower level decoder
+    pub decoder: Decoder<R>,
+    /// A basic frequency table
+    pub freq: ari::table::Model,
+    is_eof: bool,
+}
+
+impl<R: Read> ByteDecoder<R> {
+    /// Create a decoder on top of a given Reader
+    pub fn new(r: R) -> ByteDecoder<R> {
+        ByteDecoder {
+            decoder: Decoder::new(r),
+            freq: ari::table::Model::new_flat(0x101, BOTTOM >> 2),
+            is_eof: false,
+        }
+    }
+
+    /// Finish decoding
+    pub fn finish(self) -> (R, io::Result<()>) {
+        self.decoder.finish()
+    }
+}
+
+impl<R: Read> Read for ByteDecoder<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let mut amount = 0;
+        for out_byte in dst.iter_mut() {
+            if self.is_eof {
+                break
+            }
+            let value = try!(self.decoder.decode(&self.freq));
+            if value == 0x100 {
+                self.is_eof = true;
+                break
+            }
+            self.freq.update(value, 10, 1);
+            *out_byte = value as u8;
+            amount += 1;
+        }
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{ByteDecoder, ByteEncoder, Decoder, Encoder};
+    use entropy::ari;
+
+    fn roundtrip(bytes: &[u8]) -> usize {
+        let mut e = ByteEncoder::new(Vec::new());
+        e.write_all(bytes).unwrap();
+        let (encoded, r) = e.finish();
+        r.unwrap();
+        let mut d = ByteDecoder::new(&encoded[..]);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert_eq!(bytes, &decoded[..]);
+        encoded.len()
+    }
+
+    #[test]
+    fn roundtrips() {
+        roundtrip(b"");
+        roundtrip(b"abracadabra");
+        let input = include_bytes!("../data/test.txt");
+        let size = roundtrip(input);
+        // on par with the arithmetic coder
+        let mut e = ari::ByteEncoder::new(Vec::new());
+        e.write_all(input).unwrap();
+        assert!(size <= e.finish().0.len() + 8);
+        roundtrip(&[0; 100000]);
+    }
+
+    #[test]
+    fn skewed_bits() {
+        // long runs of nearly certain bits hit the carry-less range cut
+        let mut model = ari::bin::Model::new_flat(1 << 12, 5);
+        let bits: Vec<bool> = (0..100000).map(|i| i % 997 == 0).collect();
+        let mut e = Encoder::new(Vec::new());
+        for &bit in bits.iter() {
+            e.encode(bit, &model).unwrap();
+            model.update(bit);
+        }
+        let (encoded, r) = e.finish();
+        r.unwrap();
+        assert!(encoded.len() < 2000);
+
+        model.reset_flat();
+        let mut d = Decoder::new(&encoded[..]);
+        for &bit in bits.iter() {
+            assert_eq!(d.decode(&model).unwrap(), bit);
+            model.update(bit);
+        }
+    }
+}
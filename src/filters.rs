@@ -0,0 +1,497 @@
+/*!
+
+Reversible preprocessing filters for numeric data and executables
+
+Samples from sensors, audio or tables of counters change little from one
+value to the next, which a general purpose compressor doesn't see, as it
+looks for repeated strings. A `Filter` rewrites every value as its
+difference to the previous one (`Delta`) or as its XOR with it (`Xor`), so
+the slowly changing values become runs of small numbers which `flate`,
+`lz4` or `rle` compress far better. The values are bytes or 16 and 32 bit
+words of either byte order, as given by a `Word`.
+
+The branch converters (`X86`, `Arm` and `Arm64`) do the same for machine
+code: calls to a function are encoded relative to the calling instruction,
+so no two calls look alike, and the filters turn the relative targets into
+absolute ones which repeat. They are the BCJ filters of 7-Zip and `xz`, and
+give the same output.
+
+`Encoder` and `Decoder` apply a filter to what is written to them and undo
+it on what is read through them, and can be stacked with each other and
+with the crate's compressors. The output has the size of the input; bytes
+at the end which don't make up a whole word or instruction are passed
+through unchanged. In pipelines the filters are the `delta`, `xor`, `x86`,
+`arm` and `arm64` stages.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::filters::{Decoder, Encoder, Filter, Word};
+
+let samples: Vec<u8> = (0..100u16).flat_map(|i| (1000 + i * 3).to_le_bytes()).collect();
+let filter = Filter::Delta(Word::U16Le);
+let mut e = Encoder::new(Vec::new(), filter);
+e.write_all(&samples).unwrap();
+let (filtered, result) = e.finish();
+result.unwrap();
+assert_eq!(&filtered[2..8], &[3, 0, 3, 0, 3, 0]);
+
+let mut d = Decoder::new(&filtered[..], filter);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert!(decoded == samples);
+```
+
+*/
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+/// Width and byte order of the values a filter works on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Word {
+    /// Single bytes
+    U8,
+    /// 16 bit little endian words
+    U16Le,
+    /// 16 bit big endian words
+    U16Be,
+    /// 32 bit little endian words
+    U32Le,
+    /// 32 bit big endian words
+    U32Be,
+}
+
+impl Word {
+    /// Returns the size of a word in bytes
+    pub fn size(self) -> usize {
+        match self {
+            Word::U8 => 1,
+            Word::U16Le | Word::U16Be => 2,
+            Word::U32Le | Word::U32Be => 4,
+        }
+    }
+
+    fn get(self, buf: &[u8]) -> u32 {
+        match self {
+            Word::U8 => buf[0] as u32,
+            Word::U16Le => LittleEndian::read_u16(buf) as u32,
+            Word::U16Be => BigEndian::read_u16(buf) as u32,
+            Word::U32Le => LittleEndian::read_u32(buf),
+            Word::U32Be => BigEndian::read_u32(buf),
+        }
+    }
+
+    // stores the low bits of `value` which fit the word
+    fn put(self, value: u32, buf: &mut [u8]) {
+        match self {
+            Word::U8 => buf[0] = value as u8,
+            Word::U16Le => LittleEndian::write_u16(buf, value as u16),
+            Word::U16Be => BigEndian::write_u16(buf, value as u16),
+            Word::U32Le => LittleEndian::write_u32(buf, value),
+            Word::U32Be => BigEndian::write_u32(buf, value),
+        }
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Word::U8 => "8",
+            Word::U16Le => "16le",
+            Word::U16Be => "16be",
+            Word::U32Le => "32le",
+            Word::U32Be => "32be",
+        })
+    }
+}
+
+impl FromStr for Word {
+    type Err = ();
+
+    /// Parses the names `Display` prints: "8", "16le", "16be", "32le" and
+    /// "32be"
+    fn from_str(s: &str) -> Result<Word, ()> {
+        match s {
+            "8" => Ok(Word::U8),
+            "16le" => Ok(Word::U16Le),
+            "16be" => Ok(Word::U16Be),
+            "32le" => Ok(Word::U32Le),
+            "32be" => Ok(Word::U32Be),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A reversible transform of a stream of values
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Filter {
+    /// Every value minus the previous one, wrapping around
+    Delta(Word),
+    /// Every value XORed with the previous one, which suits floating point
+    /// numbers better, as close values share their high bits
+    Xor(Word),
+    /// Targets of the `call` and `jmp` instructions of 32 and 64 bit x86
+    /// code made absolute
+    X86,
+    /// Targets of the `bl` instructions of 32 bit ARM code made absolute
+    Arm,
+    /// Targets of the `bl` and `adrp` instructions of 64 bit ARM code made
+    /// absolute
+    Arm64,
+}
+
+// x86 operands are only converted if their high byte is one of these, as
+// those of near calls are
+fn x86_near(b: u8) -> bool {
+    b == 0 || b == 0xFF
+}
+
+// The filter and what it needs to know about the data before: the previous
+// value, which is 0 before the first one, the position in the stream, and
+// where the last E8/E9 byte was seen and which bytes before it were
+// E8/E9 bytes not taken for calls, for `X86`.
+struct State {
+    filter: Filter,
+    prev: u32,
+    pos: u32,
+    prev_pos: u32,
+    prev_mask: u32,
+}
+
+impl State {
+    fn new(filter: Filter) -> State {
+        State { filter, prev: 0, pos: 0, prev_pos: 5u32.wrapping_neg(), prev_mask: 0 }
+    }
+
+    // Filters `buf` in place, or undoes the filter if `encode` is false,
+    // returning how many bytes at its start are done. The rest are left
+    // for when the bytes following them are known.
+    fn run(&mut self, buf: &mut [u8], encode: bool) -> usize {
+        let done = match self.filter {
+            Filter::Delta(word) | Filter::Xor(word) => {
+                for chunk in buf.chunks_exact_mut(word.size()) {
+                    let value = word.get(chunk);
+                    let coded = match (self.filter, encode) {
+                        (Filter::Delta(_), true) => value.wrapping_sub(self.prev),
+                        (Filter::Delta(_), false) => value.wrapping_add(self.prev),
+                        _ => value ^ self.prev,
+                    };
+                    word.put(coded, chunk);
+                    self.prev = if encode { value } else { word.get(chunk) };
+                }
+                buf.len() / word.size() * word.size()
+            }
+            Filter::X86 => self.x86(buf, encode),
+            Filter::Arm => {
+                for (i, chunk) in buf.chunks_exact_mut(4).enumerate() {
+                    if chunk[3] == 0xEB {
+                        let pc = self.pos.wrapping_add(i as u32 * 4 + 8);
+                        let src = LittleEndian::read_u24(chunk) << 2;
+                        let dest = if encode { src.wrapping_add(pc) } else { src.wrapping_sub(pc) };
+                        LittleEndian::write_u24(chunk, (dest >> 2) & 0xFF_FFFF);
+                    }
+                }
+                buf.len() & !3
+            }
+            Filter::Arm64 => {
+                for (i, chunk) in buf.chunks_exact_mut(4).enumerate() {
+                    let pc = self.pos.wrapping_add(i as u32 * 4);
+                    let instr = LittleEndian::read_u32(chunk);
+                    if instr >> 26 == 0x25 {
+                        // bl
+                        let pc = if encode { pc >> 2 } else { (pc >> 2).wrapping_neg() };
+                        let instr = 0x9400_0000 | (instr.wrapping_add(pc) & 0x03FF_FFFF);
+                        LittleEndian::write_u32(chunk, instr);
+                    } else if instr & 0x9F00_0000 == 0x9000_0000 {
+                        // adrp, of which only those within 512 MB are converted
+                        let src = ((instr >> 29) & 3) | ((instr >> 3) & 0x001F_FFFC);
+                        if src.wrapping_add(0x0002_0000) & 0x001C_0000 != 0 {
+                            continue
+                        }
+                        let pc = if encode { pc >> 12 } else { (pc >> 12).wrapping_neg() };
+                        let dest = src.wrapping_add(pc);
+                        let instr = (instr & 0x9000_001F) | (dest & 3) << 29 | (dest & 0x0003_FFFC) << 3
+                                  | (dest & 0x0002_0000).wrapping_neg() & 0x00E0_0000;
+                        LittleEndian::write_u32(chunk, instr);
+                    }
+                }
+                buf.len() & !3
+            }
+        };
+        self.pos = self.pos.wrapping_add(done as u32);
+        done
+    }
+
+    fn x86(&mut self, buf: &mut [u8], encode: bool) -> usize {
+        const ALLOWED: [bool; 8] = [true, true, true, false, true, false, false, false];
+        const BIT: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+
+        if buf.len() < 5 {
+            return 0
+        }
+        if self.pos.wrapping_sub(self.prev_pos) > 5 {
+            self.prev_pos = self.pos.wrapping_sub(5);
+        }
+        let mut i = 0;
+        while i + 5 <= buf.len() {
+            if buf[i] != 0xE8 && buf[i] != 0xE9 {
+                i += 1;
+                continue
+            }
+            let here = self.pos.wrapping_add(i as u32);
+            let offset = here.wrapping_sub(self.prev_pos);
+            self.prev_pos = here;
+            if offset > 5 {
+                self.prev_mask = 0;
+            } else {
+                for _ in 0..offset {
+                    self.prev_mask = (self.prev_mask & 0x77) << 1;
+                }
+            }
+
+            let high = buf[i + 4];
+            let mask = self.prev_mask >> 1;
+            if !x86_near(high) || !ALLOWED[mask as usize & 7] || mask >= 0x10 {
+                i += 1;
+                self.prev_mask |= 1;
+                if x86_near(high) {
+                    self.prev_mask |= 0x10;
+                }
+                continue
+            }
+            let pc = here.wrapping_add(5);
+            let mut src = LittleEndian::read_u32(&buf[i + 1..]);
+            let mut dest;
+            loop {
+                dest = if encode { src.wrapping_add(pc) } else { src.wrapping_sub(pc) };
+                if self.prev_mask == 0 {
+                    break
+                }
+                let bit = BIT[mask as usize] * 8;
+                if !x86_near((dest >> (24 - bit)) as u8) {
+                    break
+                }
+                src = dest ^ ((1 << (32 - bit)) - 1);
+            }
+            // the high byte is all copies of bit 24
+            let dest = (dest & 0xFF_FFFF) | ((dest >> 24) & 1).wrapping_neg() << 24;
+            LittleEndian::write_u32(&mut buf[i + 1..], dest);
+            i += 5;
+            self.prev_mask = 0;
+        }
+        i
+    }
+}
+
+/// A writer applying a filter to the data before passing it on
+pub struct Encoder<W> {
+    w: W,
+    state: State,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder filtering into `w`
+    pub fn new(w: W, filter: Filter) -> Encoder<W> {
+        Encoder { w, state: State::new(filter), buf: Vec::new() }
+    }
+
+    /// Writes the bytes of an incomplete last word as they are, and returns
+    /// the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.w.write_all(&self.buf).and_then(|_| self.w.flush());
+        (self.w, result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        let done = self.state.run(&mut self.buf, true);
+        try!(self.w.write_all(&self.buf[..done]));
+        self.buf.drain(..done);
+        Ok(buf.len())
+    }
+
+    /// Flushes the wrapped writer, holding back the bytes of an incomplete
+    /// word or instruction
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+const BUF_SIZE: usize = 4096;
+
+/// A reader undoing a filter on the data read through it
+pub struct Decoder<R> {
+    r: R,
+    state: State,
+    // the decoded bytes from `start` to `ready`, then those read after them
+    buf: Vec<u8>,
+    start: usize,
+    ready: usize,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading filtered data from `r`
+    pub fn new(r: R, filter: Filter) -> Decoder<R> {
+        Decoder { r, state: State::new(filter), buf: Vec::new(), start: 0, ready: 0 }
+    }
+
+    /// Returns the wrapped reader
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.ready);
+        self.start = 0;
+        self.ready = 0;
+        while self.ready == 0 {
+            let old = self.buf.len();
+            self.buf.resize(old + BUF_SIZE, 0);
+            let n = try!(self.r.read(&mut self.buf[old..]));
+            self.buf.truncate(old + n);
+            if n == 0 {
+                // what is left at the end was passed through
+                self.ready = self.buf.len();
+                return Ok(())
+            }
+            self.ready = self.state.run(&mut self.buf, false);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.ready {
+            try!(self.fill());
+        }
+        let n = (self.ready - self.start).min(dst.len());
+        dst[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Decoder, Encoder, Filter, Word};
+
+    const WORDS: [Word; 5] = [Word::U8, Word::U16Le, Word::U16Be, Word::U32Le, Word::U32Be];
+
+    fn roundtrip(bytes: &[u8], filter: Filter) -> Vec<u8> {
+        let mut e = Encoder::new(Vec::new(), filter);
+        for chunk in bytes.chunks(77) {
+            e.write_all(chunk).unwrap();
+        }
+        let (filtered, result) = e.finish();
+        result.unwrap();
+        assert_eq!(filtered.len(), bytes.len());
+
+        let mut d = Decoder::new(&filtered[..], filter);
+        let mut decoded = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match d.read(&mut buf).unwrap() {
+                0 => break,
+                n => decoded.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert!(decoded == bytes, "{:?} failed", filter);
+        filtered
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        let mut filters = vec![Filter::X86, Filter::Arm, Filter::Arm64];
+        for &word in WORDS.iter() {
+            filters.push(Filter::Delta(word));
+            filters.push(Filter::Xor(word));
+        }
+        for &filter in filters.iter() {
+            roundtrip(b"", filter);
+            roundtrip(b"abc", filter);
+            roundtrip(include_bytes!("data/test.txt"), filter);
+        }
+    }
+
+    #[test]
+    fn words() {
+        let data = [1, 2, 3, 5, 8, 13, 21, 34, 55];
+        assert_eq!(roundtrip(&data, Filter::Delta(Word::U8)), [1, 1, 1, 2, 3, 5, 8, 13, 21]);
+        assert_eq!(roundtrip(&data, Filter::Xor(Word::U8)), [1, 3, 1, 6, 13, 5, 24, 55, 21]);
+        assert_eq!(roundtrip(&data, Filter::Delta(Word::U16Le)), [1, 2, 2, 3, 5, 8, 13, 21, 55]);
+        assert_eq!(roundtrip(&[1, 2, 0, 3], Filter::Delta(Word::U16Be)), [1, 2, 255, 1]);
+        assert_eq!(roundtrip(&[0, 1, 0, 0, 255, 0, 0, 0], Filter::Delta(Word::U32Le)),
+                   [0, 1, 0, 0, 255, 255, 255, 255]);
+        assert_eq!(roundtrip(&[0, 0, 1, 0, 0, 0, 0, 255], Filter::Delta(Word::U32Be)),
+                   [0, 0, 1, 0, 255, 255, 255, 255]);
+        for &word in WORDS.iter() {
+            assert_eq!(word.to_string().parse(), Ok(word));
+        }
+    }
+
+    #[test]
+    fn branches() {
+        // as filtered by `xz --x86`, `--arm` and `--arm64`
+        let x86 = [0x90, 0xE8, 0x10, 0, 0, 0, 0xE9, 0xF0, 0xFF, 0xFF, 0xFF, 0xE8, 1, 2, 3, 4, 0x90, 0xE8, 0, 0];
+        assert_eq!(roundtrip(&x86, Filter::X86),
+                   [0x90, 0xE8, 0x16, 0, 0, 0, 0xE9, 0xFB, 0xFF, 0xFF, 0xFF, 0xE8, 1, 2, 3, 4, 0x90, 0xE8, 0, 0]);
+        let arm = [1, 0, 0, 0xEB, 0x10, 0, 0, 0xEB, 0, 0, 0, 0xEA, 0xFF, 0xFF, 0xFF, 0xEB, 0xEB];
+        assert_eq!(roundtrip(&arm, Filter::Arm),
+                   [3, 0, 0, 0xEB, 0x13, 0, 0, 0xEB, 0, 0, 0, 0xEA, 4, 0, 0, 0xEB, 0xEB]);
+        let mut arm64 = vec![0; 0x3000];
+        arm64.extend_from_slice(&[0x10, 0, 0, 0x94, 0xFF, 0xFF, 0xFF, 0x97, 0x01, 0, 0, 0x90,
+                                  0x00, 0x10, 0, 0xB0, 0, 0, 0, 0]);
+        assert_eq!(roundtrip(&arm64, Filter::Arm64)[0x3000..],
+                   [0x10, 0x0C, 0, 0x94, 0, 0x0C, 0, 0x94, 0x01, 0, 0, 0xF0, 0x20, 0x10, 0, 0x90, 0, 0, 0, 0]);
+    }
+
+    #[cfg(feature="lz4")]
+    #[test]
+    fn helps_lz4() {
+        use lz4;
+
+        // calls to a few functions between runs of other instructions
+        let mut code = Vec::new();
+        let mut seed = 1u32;
+        while code.len() < 200_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let filler = [0x48, 0x89, 0xC7, 0x8B, 0x45, 0xF8, 0x31, 0xC0, 0x5D, 0xC3];
+            let start = (seed >> 8) as usize % 5;
+            code.extend_from_slice(&filler[start..start + 5]);
+            let target = 0x1000 * ((seed >> 16) % 40);
+            let rel = target.wrapping_sub(code.len() as u32 + 5);
+            code.push(0xE8);
+            code.extend_from_slice(&rel.to_le_bytes());
+        }
+        let raw = lz4::compress_to_vec(&code).len();
+        let filtered = lz4::compress_to_vec(&roundtrip(&code, Filter::X86)).len();
+        assert!(filtered * 4 < raw * 3, "{} against {}", filtered, raw);
+    }
+
+    #[cfg(feature="flate")]
+    #[test]
+    fn helps_flate() {
+        use flate;
+
+        // a slowly changing signal with a little noise, as 32 bit samples
+        let mut samples = Vec::new();
+        let mut value = 1_000_000u32;
+        for i in 0..20_000u32 {
+            value = value.wrapping_add(i % 7 + (i.wrapping_mul(2_654_435_761) >> 29));
+            samples.extend_from_slice(&value.to_le_bytes());
+        }
+        let raw = flate::compress_to_vec(&samples).len();
+        let filtered = flate::compress_to_vec(&roundtrip(&samples, Filter::Delta(Word::U32Le))).len();
+        assert!(filtered * 4 < raw, "{} against {}", filtered, raw);
+    }
+}
@@ -16,6 +16,23 @@
 //! flate::Decoder::new(stream).read_to_end(&mut decompressed);
 //! ```
 //!
+//! `InflateState` is the same decoder as a resumable state machine over
+//! slices, with the surface of miniz's `tinfl_decompress`, for code which is
+//! handed the input in pieces or calls in through an FFI layer:
+//!
+//! ```rust
+//! use compress::flate::{self, InflateState, InflateStatus};
+//!
+//! let compressed = flate::compress_to_vec(b"hello hello hello");
+//! let mut state = Box::new(InflateState::new());
+//! let mut out = [0; 64];
+//! let (used, written, status) = state.process(&compressed[..4], &mut out);
+//! assert_eq!((used, status), (4, InflateStatus::NeedsMoreInput));
+//! let (_, more, status) = state.process(&compressed[4..], &mut out[written..]);
+//! assert_eq!(status, InflateStatus::Done);
+//! assert_eq!(&out[..written + more], b"hello hello hello");
+//! ```
+//!
 //! # Related links
 //!
 //! * http://tools.ietf.org/html/rfc1951 - RFC that this implementation is based
@@ -27,18 +44,137 @@
 
 use std::cmp;
 use std::ptr::copy_nonoverlapping;
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
 use std::vec::Vec;
 
-use super::byteorder::{LittleEndian, ReadBytesExt};
+use super::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use super::ReadExact;
+use codecs::{self, Codec, Finish};
+use io::{Core, LimitExceeded, Status};
+use metrics;
+use stats::{Stage, Stats, Timer};
 
 const MAXBITS: usize = 15;
 const MAXLCODES: u16 = 286;
 const MAXDCODES: u16 = 30;
 const MAXCODES: u16 = MAXLCODES + MAXDCODES;
 const HISTORY: usize = 32 * 1024;
+const STATE_VERSION: u8 = 1;
+
+/// How hard an encoder tries to find matches, trading speed for ratio. The
+/// same levels are understood by the zlib and gzip modules, which record them
+/// in their headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// Level 1, greedy matching over very short hash chains
+    Fastest,
+    /// Level 6, the usual balance between speed and ratio
+    #[default]
+    Default,
+    /// Level 9, the longest hash chains and lazy matching
+    Best,
+    /// A numeric level from 0 (store only) to 9, larger values are clamped
+    Level(u8),
+}
 
+/// How much `Encoder::flush_with` does to make the output so far decodable,
+/// the flush modes of zlib's `deflate`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Ends the pending data with an empty fixed Huffman block, which
+    /// pushes out all of it but the last few bits of the empty block
+    /// itself, like `Z_PARTIAL_FLUSH`. The cheapest mode, two bytes at most.
+    Partial,
+    /// Ends the pending data with an empty stored block, which leaves the
+    /// output on a byte boundary ending in `00 00 ff ff`, like
+    /// `Z_SYNC_FLUSH`. This is what `Write::flush` does.
+    #[default]
+    Sync,
+    /// Same as `Sync`, and later data doesn't refer back to anything before
+    /// the flush, so decoding can start over from there, like
+    /// `Z_FULL_FLUSH`. Costs ratio when done often.
+    Full,
+}
+
+/// Match finder settings behind a `CompressionLevel`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SearchParams {
+    /// Maximum number of hash chain entries examined per position, zero
+    /// disables matching altogether
+    pub max_chain: u16,
+    /// A match at least this long ends the search early
+    pub nice_length: u16,
+    /// Whether a match is held back to see if the next position does better
+    pub lazy: bool,
+    /// Whether blocks are ended early when the statistics change, instead
+    /// of only when the buffer is full
+    pub split_blocks: bool,
+}
+
+impl CompressionLevel {
+    /// Returns the numeric level, from 0 to 9
+    pub fn level(&self) -> u8 {
+        match *self {
+            CompressionLevel::Fastest => 1,
+            CompressionLevel::Default => 6,
+            CompressionLevel::Best => 9,
+            CompressionLevel::Level(n) => cmp::min(n, 9),
+        }
+    }
+
+    /// Returns the match finder settings for this level, following the
+    /// table of zlib's deflate.c
+    pub fn params(&self) -> SearchParams {
+        let (max_chain, nice_length) = match self.level() {
+            0 => (0, 0),
+            1 => (4, 8),
+            2 => (8, 16),
+            3 => (32, 32),
+            4 => (16, 16),
+            5 => (32, 32),
+            6 => (128, 128),
+            7 => (256, 128),
+            8 => (1024, 258),
+            _ => (4096, 258),
+        };
+        SearchParams {
+            max_chain,
+            nice_length,
+            lazy: self.level() >= 4,
+            split_blocks: self.level() >= 4,
+        }
+    }
+}
+
+impl From<u8> for CompressionLevel {
+    fn from(level: u8) -> CompressionLevel {
+        CompressionLevel::Level(level)
+    }
+}
+
+// extra base length for codes 257-285
+static EXTRALENS: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51,
+    59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+// extra bits to read for codes 257-285
+static EXTRABITS: [u16; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4,
+    4, 5, 5, 5, 5, 0,
+];
+// base offset for distance codes.
+static EXTRADIST: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385,
+    513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385,
+    24577,
+];
+// number of bits to read for distance codes (to add to the offset)
+static EXTRADBITS: [u16; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9,
+    10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Error {
     HuffmanTreeTooLarge,
     InvalidBlockCode,
@@ -47,13 +183,13 @@ enum Error {
     InvalidHuffmanTreeHeader,
     InvalidHuffmanCode,
     InvalidStaticSize,
+    InvalidState,
     NotEnoughBits,
 }
 
-fn error<T>(e: Error) -> io::Result<T> {
-    Err(io::Error::new(
-        io::ErrorKind::InvalidInput,
-        match e {
+impl Error {
+    fn message(&self) -> &'static str {
+        match *self {
             Error::HuffmanTreeTooLarge => "huffman tree too large",
             Error::InvalidBlockCode => "invalid block code",
             Error::InvalidHuffmanHeaderSymbol => "invalid huffman header symbol",
@@ -61,11 +197,17 @@ fn error<T>(e: Error) -> io::Result<T> {
             Error::InvalidHuffmanTreeHeader => "invalid huffman tree header",
             Error::InvalidHuffmanCode => "invalid huffman code",
             Error::InvalidStaticSize => "invalid static size",
+            Error::InvalidState => "invalid decoder state",
             Error::NotEnoughBits => "not enough bits",
         }
-    ))
+    }
+}
+
+fn error<T>(e: Error) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, e.message()))
 }
 
+#[derive(Clone, Copy)]
 struct HuffmanTree {
     /// An array which counts the number of codes which can be found at the
     /// index's bit length, or count[n] is the number of n-bit codes
@@ -80,7 +222,7 @@ impl HuffmanTree {
     /// Constructs a new huffman tree for decoding. If the given array has
     /// length N, then the huffman tree can be used to decode N symbols. Each
     /// entry in the array corresponds to the length of the nth symbol.
-    fn construct(lens: &[u16]) -> io::Result<HuffmanTree> {
+    fn construct(lens: &[u16]) -> Result<HuffmanTree, Error> {
         let mut tree = HuffmanTree {
             count: [0; MAXBITS + 1],
             symbol: [0; MAXCODES as usize],
@@ -99,7 +241,7 @@ impl HuffmanTree {
         for i in 1..(MAXBITS + 1) {
             left *= 2;
             left -= tree.count[i] as isize;
-            if left < 0 { return error(Error::InvalidHuffmanTree) }
+            if left < 0 { return Err(Error::InvalidHuffmanTree) }
         }
 
         // Generate the offset of each length into the 'symbol' array
@@ -146,6 +288,56 @@ impl HuffmanTree {
     }
 }
 
+// Generated by the main function above
+static FIXED_LEN: HuffmanTree = HuffmanTree {
+    count: [100, 0, 0, 0, 0, 0, 0, 24, 152, 112, 0, 0, 0, 0, 0, 0],
+    symbol: [
+        256, 257, 258, 259, 260, 261, 262, 263, 264, 265, 266, 267, 268,
+        269, 270, 271, 272, 273, 274, 275, 276, 277, 278, 279, 0, 1, 2,
+        3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+        21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36,
+        37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52,
+        53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68,
+        69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84,
+        85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, 96, 97, 98, 99, 100,
+        101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111, 112, 113,
+        114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126,
+        127, 128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139,
+        140, 141, 142, 143, 280, 281, 282, 283, 284, 285, 286, 287, 144,
+        145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157,
+        158, 159, 160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170,
+        171, 172, 173, 174, 175, 176, 177, 178, 179, 180, 181, 182, 183,
+        184, 185, 186, 187, 188, 189, 190, 191, 192, 193, 194, 195, 196,
+        197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207, 208, 209,
+        210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222,
+        223, 224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235,
+        236, 237, 238, 239, 240, 241, 242, 243, 244, 245, 246, 247, 248,
+        249, 250, 251, 252, 253, 254, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+    ]
+};
+static FIXED_DIST: HuffmanTree = HuffmanTree {
+    count: [0, 0, 0, 0, 0, 30, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    symbol: [
+        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17,
+        18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0, 0, 0, 0, 0, 0, 0, 0
+    ]
+};
+
 #[cfg(genflate)]
 fn main() {
     static FIXLCODES: usize = 388;
@@ -162,8 +354,7 @@ fn main() {
 /// The structure that is used to decode an LZ4 data stream. This wraps an
 /// internal reader which is used as the source of all data.
 pub struct Decoder<R> {
-    /// Wrapped reader which is exposed to allow getting it back.
-    pub r: R,
+    pub(crate) r: R,
 
     output: Vec<u8>,
     outpos: usize,
@@ -196,7 +387,9 @@ impl<R: Read> Decoder<R> {
         self.pos = 0;
         self.block = Vec::with_capacity(4096);
         if try!(self.bits(1)) == 1 { self.eof = true; }
-        match try!(self.bits(2)) {
+        let btype = try!(self.bits(2));
+        debug!(target: "compress::flate", "block type {}, final: {}", btype, self.eof);
+        match btype {
             0 => self.statik(),
             1 => self.fixed(),
             2 => self.dynamic(),
@@ -235,13 +428,22 @@ impl<R: Read> Decoder<R> {
     }
 
     fn statik(&mut self) -> io::Result<()> {
-        let len = try!(self.r.read_u16::<LittleEndian>());
-        let nlen = try!(self.r.read_u16::<LittleEndian>());
+        // drop the padding up to the byte boundary, whole bytes which are
+        // still buffered come before the rest of the stream
+        let pad = self.bitcnt % 8;
+        self.bitbuf >>= pad;
+        self.bitcnt -= pad;
+        let len = try!(self.bits(16));
+        let nlen = try!(self.bits(16));
         if !nlen != len { return error(Error::InvalidStaticSize) }
-        try!(self.r.push_exactly(len as u64, &mut self.block));
+        let mut left = len as u64;
+        while left > 0 && self.bitcnt > 0 {
+            let byte = try!(self.bits(8));
+            self.block.push(byte as u8);
+            left -= 1;
+        }
+        try!(self.r.push_exactly(left, &mut self.block));
         self.update_output(0);
-        self.bitcnt = 0;
-        self.bitbuf = 0;
         Ok(())
     }
 
@@ -261,27 +463,6 @@ impl<R: Read> Decoder<R> {
 
     fn codes(&mut self, lens: &HuffmanTree,
              dist: &HuffmanTree) -> io::Result<()> {
-        // extra base length for codes 257-285
-        static EXTRALENS: [u16; 29] = [
-            3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51,
-            59, 67, 83, 99, 115, 131, 163, 195, 227, 258
-        ];
-        // extra bits to read for codes 257-285
-        static EXTRABITS: [u16; 29] = [
-            0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4,
-            4, 5, 5, 5, 5, 0,
-        ];
-        // base offset for distance codes.
-        static EXTRADIST: [u16; 30] = [
-            1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385,
-            513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385,
-            24577,
-        ];
-        // number of bits to read for distance codes (to add to the offset)
-        static EXTRADBITS: [u16; 30] = [
-            0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9,
-            10, 10, 11, 11, 12, 12, 13, 13,
-        ];
         let mut last_updated = 0;
         loop {
             let sym = try!(lens.decode(self));
@@ -291,7 +472,7 @@ impl<R: Read> Decoder<R> {
                 n if n < 290 => {
                     // figure out len/dist that we're working with
                     let n = n - 257;
-                    if n as usize > EXTRALENS.len() {
+                    if n as usize >= EXTRALENS.len() {
                         return error(Error::InvalidHuffmanCode)
                     }
                     let len = EXTRALENS[n as usize] +
@@ -300,6 +481,9 @@ impl<R: Read> Decoder<R> {
                     let len = len as usize;
 
                     let dist = try!(dist.decode(self)) as usize;
+                    if dist >= EXTRADIST.len() {
+                        return error(Error::InvalidHuffmanCode)
+                    }
                     let dist = EXTRADIST[dist] +
                                try!(self.bits(EXTRADBITS[dist] as usize));
                     let dist = dist as usize;
@@ -341,57 +525,7 @@ impl<R: Read> Decoder<R> {
     }
 
     fn fixed(&mut self) -> io::Result<()> {
-        // Generated by the main function above
-        static LEN: HuffmanTree = HuffmanTree {
-            count: [100, 0, 0, 0, 0, 0, 0, 24, 152, 112, 0, 0, 0, 0, 0, 0],
-            symbol: [
-                256, 257, 258, 259, 260, 261, 262, 263, 264, 265, 266, 267, 268,
-                269, 270, 271, 272, 273, 274, 275, 276, 277, 278, 279, 0, 1, 2,
-                3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
-                21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36,
-                37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52,
-                53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68,
-                69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79, 80, 81, 82, 83, 84,
-                85, 86, 87, 88, 89, 90, 91, 92, 93, 94, 95, 96, 97, 98, 99, 100,
-                101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111, 112, 113,
-                114, 115, 116, 117, 118, 119, 120, 121, 122, 123, 124, 125, 126,
-                127, 128, 129, 130, 131, 132, 133, 134, 135, 136, 137, 138, 139,
-                140, 141, 142, 143, 280, 281, 282, 283, 284, 285, 286, 287, 144,
-                145, 146, 147, 148, 149, 150, 151, 152, 153, 154, 155, 156, 157,
-                158, 159, 160, 161, 162, 163, 164, 165, 166, 167, 168, 169, 170,
-                171, 172, 173, 174, 175, 176, 177, 178, 179, 180, 181, 182, 183,
-                184, 185, 186, 187, 188, 189, 190, 191, 192, 193, 194, 195, 196,
-                197, 198, 199, 200, 201, 202, 203, 204, 205, 206, 207, 208, 209,
-                210, 211, 212, 213, 214, 215, 216, 217, 218, 219, 220, 221, 222,
-                223, 224, 225, 226, 227, 228, 229, 230, 231, 232, 233, 234, 235,
-                236, 237, 238, 239, 240, 241, 242, 243, 244, 245, 246, 247, 248,
-                249, 250, 251, 252, 253, 254, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
-            ]
-        };
-        static DIST: HuffmanTree = HuffmanTree {
-            count: [0, 0, 0, 0, 0, 30, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-            symbol: [
-                0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17,
-                18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-                0, 0, 0, 0, 0, 0, 0, 0
-            ]
-        };
-
-        self.codes(&LEN, &DIST)
+        self.codes(&FIXED_LEN, &FIXED_DIST)
     }
 
     fn dynamic(&mut self) -> io::Result<()> {
@@ -412,7 +546,7 @@ impl<R: Read> Decoder<R> {
         for i in 0..(hclen as usize) {
             lengths[ORDER[i]] = try!(self.bits(3));
         }
-        let tree = try!(HuffmanTree::construct(&lengths));
+        let tree = try!(HuffmanTree::construct(&lengths).or_else(error));
 
         // Decode all of the length and distance codes in one go, we'll
         // partition them into two huffman trees later
@@ -443,12 +577,72 @@ impl<R: Read> Decoder<R> {
 
         // Use the decoded codes to construct yet another huffman tree
         let arr = &lengths[..(hlit as usize)];
-        let lencode = try!(HuffmanTree::construct(arr));
+        let lencode = try!(HuffmanTree::construct(arr).or_else(error));
         let arr = &lengths[(hlit as usize)..((hlit + hdist) as usize)];
-        let distcode = try!(HuffmanTree::construct(arr));
+        let distcode = try!(HuffmanTree::construct(arr).or_else(error));
         self.codes(&lencode, &distcode)
     }
 
+    /// Hands bytes which were already taken off the wrapped reader, for
+    /// example to sniff the stream format, back to the decoder. Must be
+    /// called before anything is decoded, with at most 4 bytes.
+    pub fn unread(&mut self, bytes: &[u8]) {
+        assert!(self.bitcnt == 0 && bytes.len() <= 4,
+                "unread is only possible at the start of a stream");
+        for &b in bytes.iter() {
+            self.bitbuf |= (b as usize) << self.bitcnt;
+            self.bitcnt += 8;
+        }
+    }
+
+    /// Seeds the sliding window with `history`, which the stream may refer
+    /// back to as if it had been decoded just before. This is how protocols
+    /// compress a message against the previous one or against a preset
+    /// dictionary; the encoder has to be primed the same way, see
+    /// `Encoder::prime_window`. Only the last 32K of `history` are kept.
+    ///
+    /// Must be called at the start of a stream, before anything is decoded
+    /// or right after `reset`.
+    pub fn prime_window(&mut self, history: &[u8]) {
+        let keep = &history[history.len().saturating_sub(HISTORY)..];
+        self.output.clear();
+        self.output.extend_from_slice(keep);
+        self.outpos = keep.len();
+    }
+
+    /// Returns the last `n` bytes of output already read from this decoder,
+    /// which the stream may still refer back to, or fewer if the decoder no
+    /// longer holds that many. It holds the 32K window of DEFLATE over what
+    /// it decoded, which runs ahead of what was read by up to a block, so
+    /// the whole window is only there once a block has been read out.
+    /// History seeded by `prime_window` counts as output read before the
+    /// stream.
+    ///
+    /// The window is kept in a ring buffer, so it comes as two slices, the
+    /// second one following the first, like `VecDeque::as_slices`.
+    ///
+    /// This is meant for protocols referring back to earlier data on their
+    /// own, to check what the decoder has to work with.
+    pub fn window(&self, n: usize) -> (&[u8], &[u8]) {
+        let (old, new) = if self.output.len() < HISTORY {
+            (&self.output[..0], &self.output[..])
+        } else {
+            (&self.output[self.outpos..], &self.output[..self.outpos])
+        };
+        let ring = old.len() + new.len();
+        if self.block.len() > ring {
+            // the ring only holds the end of the current block
+            let read = &self.block[..self.pos];
+            return (&read[read.len() - cmp::min(n, read.len())..], &[])
+        }
+        // the ring already holds the whole current block, only the part
+        // which was read out belongs in the window
+        let end = ring - (self.block.len() - self.pos);
+        let start = end - cmp::min(n, end);
+        (&old[cmp::min(start, old.len())..cmp::min(end, old.len())],
+         &new[start.saturating_sub(old.len())..end.saturating_sub(old.len())])
+    }
+
     /// Returns whether this deflate stream has reached the EOF marker
     pub fn eof(&self) -> bool {
         self.eof && self.pos == self.block.len()
@@ -463,11 +657,96 @@ impl<R: Read> Decoder<R> {
         self.block = Vec::new();
         self.pos = 0;
     }
+
+    /// Destroys this decoder, returning the wrapped reader. Once `eof`
+    /// returns true, it is positioned right after the deflate stream, where
+    /// the trailer of a container format follows.
+    pub fn finish(self) -> R {
+        self.r
+    }
+
+    /// Decodes and throws away the next `n` bytes of output, returning how
+    /// many were skipped, which is less than `n` only at the end of the
+    /// stream.
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let mut left = n;
+        while left > 0 {
+            if self.pos == self.block.len() {
+                if self.eof { break }
+                try!(self.block());
+            }
+            let k = cmp::min(left, (self.block.len() - self.pos) as u64);
+            self.pos += k as usize;
+            left -= k;
+        }
+        Ok(n - left)
+    }
+
+    /// Snapshots the sliding window, the pending output and the bit reader
+    /// into a byte vector which can later be handed to `import_state`.
+    ///
+    /// The position of the wrapped reader is not part of the snapshot, the
+    /// caller is responsible for resuming the input at the same offset.
+    pub fn export_state(&self) -> Vec<u8> {
+        let pending = &self.block[self.pos..];
+        let mut state = Vec::with_capacity(20 + self.output.len() + pending.len());
+        state.push(STATE_VERSION);
+        state.push(self.eof as u8);
+        state.push(self.bitcnt as u8);
+        state.write_u64::<LittleEndian>(self.bitbuf as u64).unwrap();
+        state.write_u32::<LittleEndian>(self.outpos as u32).unwrap();
+        state.write_u32::<LittleEndian>(self.output.len() as u32).unwrap();
+        state.extend_from_slice(&self.output);
+        state.write_u32::<LittleEndian>(pending.len() as u32).unwrap();
+        state.extend_from_slice(pending);
+        state
+    }
+
+    /// Restores a snapshot previously produced by `export_state`, replacing
+    /// the current window and pending output of this decoder.
+    pub fn import_state(&mut self, mut state: &[u8]) -> io::Result<()> {
+        if try!(state.read_u8()) != STATE_VERSION {
+            return error(Error::InvalidState)
+        }
+        let eof = try!(state.read_u8()) != 0;
+        let bitcnt = try!(state.read_u8()) as usize;
+        let bitbuf = try!(state.read_u64::<LittleEndian>()) as usize;
+        let outpos = try!(state.read_u32::<LittleEndian>()) as usize;
+        let len = try!(state.read_u32::<LittleEndian>()) as u64;
+        // the window never holds more than the history DEFLATE refers to
+        if len > HISTORY as u64 {
+            return error(Error::InvalidState)
+        }
+        let mut output = Vec::with_capacity(HISTORY);
+        try!(state.push_exactly(len, &mut output));
+        let len = try!(state.read_u32::<LittleEndian>()) as u64;
+        let mut block = Vec::new();
+        try!(state.push_exactly(len, &mut block));
+        // until the window fills up the write position trails its length
+        let window_ok = if output.len() < HISTORY {
+            outpos == output.len()
+        } else {
+            outpos <= HISTORY
+        };
+        if bitcnt > 32 || bitbuf >> bitcnt != 0 || !window_ok {
+            return error(Error::InvalidState)
+        }
+
+        self.eof = eof;
+        self.bitcnt = bitcnt;
+        self.bitbuf = bitbuf;
+        self.outpos = outpos;
+        self.output = output;
+        self.block = block;
+        self.pos = 0;
+        Ok(())
+    }
 }
 
 impl<R: Read> Read for Decoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.pos == self.block.len() {
+        // empty blocks, as written by a flushing encoder, are not the end
+        while self.pos == self.block.len() {
             if self.eof { return Ok(0) }
             try!(self.block());
         }
@@ -487,14 +766,839 @@ impl<R: Read> Read for Decoder<R> {
     }
 }
 
+/// What `InflateState::process` stopped at. The values are those of the
+/// `tinfl_status` of miniz, for callers across an FFI boundary.
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InflateStatus {
+    /// The stream is invalid, see `InflateState::error`. Every later call
+    /// fails the same way until `reset`.
+    Failed = -1,
+    /// The stream is complete, all of its output was produced
+    Done = 0,
+    /// All of the input was consumed, more is needed to go on
+    NeedsMoreInput = 1,
+    /// The output is full, more room is needed to go on
+    HasMoreOutput = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Header,
+    StoredLen,
+    Stored,
+    TableSizes,
+    CodeLens,
+    Lens,
+    Codes,
+    Copy,
+    Done,
+    Failed(Error),
+}
+
+/// A resumable DEFLATE decoder over slices, the counterpart of miniz's
+/// `tinfl_decompress`: it is pushed input as it arrives and pulls out as
+/// much output as fits, keeping its place in between. It holds the 32K
+/// window inline and never allocates nor does I/O, which makes it easy to
+/// embed behind a C interface or in another runtime; box it to keep it off
+/// the stack.
+///
+/// Input bytes are only consumed as far as they are needed, so once
+/// `Done` is returned the input right after the stream, like a gzip
+/// trailer, is where `process` stopped. `InflateState` also implements
+/// `io::Core`, so `io::CoreReader` reads through it.
+pub struct InflateState {
+    step: Step,
+    last: bool,
+    bitbuf: u64,
+    bitcnt: usize,
+    // stored bytes or code lengths left, or the length of a copy
+    left: usize,
+    dist: usize,
+    hlit: usize,
+    hdist: usize,
+    hclen: usize,
+    lens: [u16; MAXCODES as usize],
+    lencode: HuffmanTree,
+    distcode: HuffmanTree,
+    window: [u8; HISTORY],
+    wpos: usize,
+    filled: usize,
+}
+
+impl InflateState {
+    /// Creates a decoder at the start of a raw DEFLATE stream
+    pub fn new() -> InflateState {
+        InflateState {
+            step: Step::Header,
+            last: false,
+            bitbuf: 0,
+            bitcnt: 0,
+            left: 0,
+            dist: 0,
+            hlit: 0,
+            hdist: 0,
+            hclen: 0,
+            lens: [0; MAXCODES as usize],
+            lencode: FIXED_LEN,
+            distcode: FIXED_DIST,
+            window: [0; HISTORY],
+            wpos: 0,
+            filled: 0,
+        }
+    }
+
+    /// Goes back to the start of a stream, forgetting the window
+    pub fn reset(&mut self) {
+        self.step = Step::Header;
+        self.last = false;
+        self.bitbuf = 0;
+        self.bitcnt = 0;
+        self.wpos = 0;
+        self.filled = 0;
+    }
+
+    /// Returns why the stream was rejected, once `Failed` was returned
+    pub fn error(&self) -> Option<&'static str> {
+        match self.step {
+            Step::Failed(e) => Some(e.message()),
+            _ => None,
+        }
+    }
+
+    /// Decodes from `input` into `output`, returning how many bytes of each
+    /// were used and why it stopped. Unless the output fills up or the
+    /// stream ends, all of the input is used; the bytes which don't make up
+    /// a whole code yet are kept for the next call.
+    pub fn process(&mut self, input: &[u8], output: &mut [u8]) -> (usize, usize, InflateStatus) {
+        let (mut used, mut written) = (0, 0);
+        let status = match self.run(input, &mut used, output, &mut written) {
+            Ok(status) => status,
+            Err(e) => {
+                self.step = Step::Failed(e);
+                InflateStatus::Failed
+            }
+        };
+        (used, written, status)
+    }
+
+    // Makes at least `n` bits available, returning false if the input runs
+    // out first. Bytes are taken one at a time so that none is taken past
+    // the end of the stream.
+    fn need(&mut self, input: &[u8], used: &mut usize, n: usize) -> bool {
+        while self.bitcnt < n {
+            if *used == input.len() {
+                return false
+            }
+            self.bitbuf |= (input[*used] as u64) << self.bitcnt;
+            self.bitcnt += 8;
+            *used += 1;
+        }
+        true
+    }
+
+    // The `n` bits `at` bits into the buffer, which have to be available
+    fn peek(&self, at: usize, n: usize) -> usize {
+        ((self.bitbuf >> at) & ((1 << n) - 1)) as usize
+    }
+
+    fn take(&mut self, n: usize) -> usize {
+        let bits = self.peek(0, n);
+        self.bitbuf >>= n;
+        self.bitcnt -= n;
+        bits
+    }
+
+    // Decodes the symbol `at` bits into the buffer without consuming it,
+    // returning it along with its length, or None if the input runs out
+    fn symbol(&mut self, tree: &HuffmanTree, at: usize, input: &[u8], used: &mut usize)
+              -> Result<Option<(usize, usize)>, Error> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for len in 1..(MAXBITS + 1) {
+            if !self.need(input, used, at + len) {
+                return Ok(None)
+            }
+            code |= self.peek(at + len - 1, 1);
+            let count = tree.count[len] as usize;
+            if code < first + count {
+                return Ok(Some((tree.symbol[index + code - first] as usize, len)))
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::NotEnoughBits)
+    }
+
+    fn put(&mut self, output: &mut [u8], written: &mut usize, byte: u8) {
+        output[*written] = byte;
+        *written += 1;
+        self.window[self.wpos] = byte;
+        self.wpos = (self.wpos + 1) % HISTORY;
+        self.filled = cmp::min(self.filled + 1, HISTORY);
+    }
+
+    fn end_block(&mut self) {
+        self.step = if self.last { Step::Done } else { Step::Header };
+    }
+
+    fn run(&mut self, input: &[u8], used: &mut usize, output: &mut [u8], written: &mut usize)
+           -> Result<InflateStatus, Error> {
+        let more_input = Ok(InflateStatus::NeedsMoreInput);
+        loop {
+            match self.step {
+                Step::Header => {
+                    if !self.need(input, used, 3) { return more_input }
+                    self.last = self.take(1) == 1;
+                    self.step = match self.take(2) {
+                        0 => {
+                            // drop the padding up to the byte boundary
+                            let pad = self.bitcnt % 8;
+                            self.take(pad);
+                            Step::StoredLen
+                        }
+                        1 => {
+                            self.lencode = FIXED_LEN;
+                            self.distcode = FIXED_DIST;
+                            Step::Codes
+                        }
+                        2 => Step::TableSizes,
+                        _ => return Err(Error::InvalidBlockCode),
+                    };
+                }
+                Step::StoredLen => {
+                    if !self.need(input, used, 32) { return more_input }
+                    let len = self.take(16);
+                    let nlen = self.take(16);
+                    if len != !nlen & 0xffff {
+                        return Err(Error::InvalidStaticSize)
+                    }
+                    self.left = len;
+                    self.step = Step::Stored;
+                }
+                Step::Stored => {
+                    while self.left > 0 {
+                        if *written == output.len() { return Ok(InflateStatus::HasMoreOutput) }
+                        if *used == input.len() { return more_input }
+                        let byte = input[*used];
+                        *used += 1;
+                        self.put(output, written, byte);
+                        self.left -= 1;
+                    }
+                    self.end_block();
+                }
+                Step::TableSizes => {
+                    if !self.need(input, used, 14) { return more_input }
+                    self.hlit = self.take(5) + 257;
+                    self.hdist = self.take(5) + 1;
+                    self.hclen = self.take(4) + 4;
+                    if self.hlit > MAXLCODES as usize || self.hdist > MAXDCODES as usize {
+                        return Err(Error::HuffmanTreeTooLarge)
+                    }
+                    self.lens = [0; MAXCODES as usize];
+                    self.left = 0;
+                    self.step = Step::CodeLens;
+                }
+                Step::CodeLens => {
+                    static ORDER: [usize; 19] = [
+                        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+                    ];
+                    while self.left < self.hclen {
+                        if !self.need(input, used, 3) { return more_input }
+                        self.lens[ORDER[self.left]] = self.take(3) as u16;
+                        self.left += 1;
+                    }
+                    // the code length code lives in the length code's place
+                    // until the lengths are read
+                    self.lencode = try!(HuffmanTree::construct(&self.lens[..19]));
+                    self.lens = [0; MAXCODES as usize];
+                    self.left = 0;
+                    self.step = Step::Lens;
+                }
+                Step::Lens => {
+                    let total = self.hlit + self.hdist;
+                    while self.left < total {
+                        let tree = self.lencode;
+                        let (sym, len) = match try!(self.symbol(&tree, 0, input, used)) {
+                            Some(s) => s,
+                            None => return more_input,
+                        };
+                        let extra = match sym { 16 => 2, 17 => 3, 18 => 7, _ => 0 };
+                        if !self.need(input, used, len + extra) { return more_input }
+                        self.take(len);
+                        let (value, repeat) = match sym {
+                            0..=15 => (sym as u16, 1),
+                            16 if self.left == 0 => return Err(Error::InvalidHuffmanHeaderSymbol),
+                            16 => (self.lens[self.left - 1], self.take(2) + 3),
+                            17 => (0, self.take(3) + 3),
+                            18 => (0, self.take(7) + 11),
+                            _ => return Err(Error::InvalidHuffmanHeaderSymbol),
+                        };
+                        if self.left + repeat > total {
+                            return Err(Error::InvalidHuffmanTreeHeader)
+                        }
+                        for len in self.lens[self.left..self.left + repeat].iter_mut() {
+                            *len = value;
+                        }
+                        self.left += repeat;
+                    }
+                    self.lencode = try!(HuffmanTree::construct(&self.lens[..self.hlit]));
+                    self.distcode = try!(HuffmanTree::construct(&self.lens[self.hlit..total]));
+                    self.step = Step::Codes;
+                }
+                Step::Codes => {
+                    let (lencode, distcode) = (self.lencode, self.distcode);
+                    loop {
+                        if *written == output.len() { return Ok(InflateStatus::HasMoreOutput) }
+                        let (sym, len) = match try!(self.symbol(&lencode, 0, input, used)) {
+                            Some(s) => s,
+                            None => return more_input,
+                        };
+                        if sym < 256 {
+                            self.take(len);
+                            self.put(output, written, sym as u8);
+                            continue
+                        }
+                        if sym == 256 {
+                            self.take(len);
+                            break
+                        }
+                        // a whole length and distance pair is read at once,
+                        // or not at all
+                        let n = sym - 257;
+                        if n >= EXTRALENS.len() {
+                            return Err(Error::InvalidHuffmanCode)
+                        }
+                        let lenbits = EXTRABITS[n] as usize;
+                        if !self.need(input, used, len + lenbits) { return more_input }
+                        let (dsym, dlen) = match try!(self.symbol(&distcode, len + lenbits, input, used)) {
+                            Some(s) => s,
+                            None => return more_input,
+                        };
+                        if dsym >= EXTRADIST.len() {
+                            return Err(Error::InvalidHuffmanCode)
+                        }
+                        let distbits = EXTRADBITS[dsym] as usize;
+                        if !self.need(input, used, len + lenbits + dlen + distbits) { return more_input }
+                        self.take(len);
+                        self.left = EXTRALENS[n] as usize + self.take(lenbits);
+                        self.take(dlen);
+                        self.dist = EXTRADIST[dsym] as usize + self.take(distbits);
+                        if self.dist > self.filled {
+                            return Err(Error::InvalidHuffmanCode)
+                        }
+                        self.step = Step::Copy;
+                        break
+                    }
+                    if self.step == Step::Codes {
+                        self.end_block();
+                    }
+                }
+                Step::Copy => {
+                    while self.left > 0 {
+                        if *written == output.len() { return Ok(InflateStatus::HasMoreOutput) }
+                        let byte = self.window[(self.wpos + HISTORY - self.dist) % HISTORY];
+                        self.put(output, written, byte);
+                        self.left -= 1;
+                    }
+                    self.step = Step::Codes;
+                }
+                Step::Done => return Ok(InflateStatus::Done),
+                Step::Failed(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Default for InflateState {
+    fn default() -> InflateState {
+        InflateState::new()
+    }
+}
+
+impl Core for InflateState {
+    fn process(&mut self, input: &[u8], output: &mut [u8], finish: bool)
+               -> io::Result<(usize, usize, Status)> {
+        match InflateState::process(self, input, output) {
+            (used, written, InflateStatus::Done) => Ok((used, written, Status::StreamEnd)),
+            (_, _, InflateStatus::NeedsMoreInput) if finish => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated deflate stream"))
+            }
+            (used, written, InflateStatus::NeedsMoreInput) |
+            (used, written, InflateStatus::HasMoreOutput) => Ok((used, written, Status::Ok)),
+            (_, _, InflateStatus::Failed) => match self.step {
+                Step::Failed(e) => error(e),
+                _ => error(Error::InvalidState),
+            },
+        }
+    }
+}
+
+// The encoder keeps its working state inline: ENC_WINDOW bytes of history
+// followed by up to as much pending input, a hash table holding the last
+// position of every 3-byte prefix and a small output buffer. Only input
+// gathered for a stored block goes to the heap.
+const ENC_WINDOW: usize = 1024;
+const ENC_BUF: usize = 2 * ENC_WINDOW;
+const ENC_HASH_BITS: usize = 9;
+const ENC_OUT: usize = 256;
+// blocks not compressing below this percentage of their size are stored,
+// the same default as lz4
+const DEFAULT_STORE_THRESHOLD: usize = 98;
+// the most a stored block holds
+const MAX_STORED: usize = 0xffff;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+struct BitWriter<W> {
+    w: W,
+    out: [u8; ENC_OUT],
+    outlen: usize,
+    bitbuf: u32,
+    bitcnt: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn put(&mut self, bits: u32, cnt: u32) -> io::Result<()> {
+        self.bitbuf |= bits << self.bitcnt;
+        self.bitcnt += cnt;
+        while self.bitcnt >= 8 {
+            let byte = self.bitbuf as u8;
+            try!(self.byte(byte));
+            self.bitbuf >>= 8;
+            self.bitcnt -= 8;
+        }
+        Ok(())
+    }
+
+    // huffman codes go out most significant bit first
+    fn put_code(&mut self, code: u32, cnt: u32) -> io::Result<()> {
+        self.put(code.reverse_bits() >> (32 - cnt), cnt)
+    }
+
+    fn align(&mut self) -> io::Result<()> {
+        match self.bitcnt {
+            0 => Ok(()),
+            n => self.put(0, 8 - n),
+        }
+    }
+
+    fn byte(&mut self, byte: u8) -> io::Result<()> {
+        self.out[self.outlen] = byte;
+        self.outlen += 1;
+        if self.outlen == ENC_OUT {
+            try!(self.flush_out());
+        }
+        Ok(())
+    }
+
+    fn flush_out(&mut self) -> io::Result<()> {
+        try!(self.w.write_all(&self.out[..self.outlen]));
+        self.outlen = 0;
+        Ok(())
+    }
+
+    // writes `data` as a stored block of at most MAX_STORED bytes
+    fn stored(&mut self, data: &[u8], last: bool) -> io::Result<()> {
+        try!(self.put(last as u32, 3));
+        try!(self.align());
+        try!(self.put(data.len() as u32, 16));
+        try!(self.put(!data.len() as u32 & 0xffff, 16));
+        for &byte in data.iter() {
+            try!(self.byte(byte));
+        }
+        Ok(())
+    }
+
+    // writes a literal or length symbol with the fixed literal/length code
+    fn literal(&mut self, sym: u32) -> io::Result<()> {
+        match sym {
+            0..=143 => self.put_code(0x30 + sym, 8),
+            144..=255 => self.put_code(0x190 + sym - 144, 9),
+            256..=279 => self.put_code(sym - 256, 7),
+            _ => self.put_code(0xc0 + sym - 280, 8),
+        }
+    }
+
+    fn token(&mut self, len: usize, dist: usize) -> io::Result<()> {
+        if dist == 0 {
+            return self.literal(len as u32)
+        }
+        let l = length_code(len);
+        try!(self.literal(257 + l as u32));
+        try!(self.put((len - EXTRALENS[l] as usize) as u32, EXTRABITS[l] as u32));
+        let d = distance_code(dist);
+        try!(self.put_code(d as u32, 5));
+        self.put((dist - EXTRADIST[d] as usize) as u32, EXTRADBITS[d] as u32)
+    }
+}
+
+fn length_code(len: usize) -> usize {
+    EXTRALENS.iter().rposition(|&base| base as usize <= len).unwrap()
+}
+
+fn distance_code(dist: usize) -> usize {
+    EXTRADIST.iter().rposition(|&base| base as usize <= dist).unwrap()
+}
+
+// size in bits of a token with the fixed codes
+fn fixed_cost(len: usize, dist: usize) -> usize {
+    if dist == 0 {
+        return if len < 144 { 8 } else { 9 }
+    }
+    let l = 257 + length_code(len);
+    let d = distance_code(dist);
+    (if l < 280 { 7 } else { 8 }) + EXTRABITS[l - 257] as usize +
+        5 + EXTRADBITS[d] as usize
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16;
+    (v.wrapping_mul(2654435761) >> (32 - ENC_HASH_BITS)) as usize
+}
+
+// Greedy parse of `buf[start..]`, calling `f` with (literal, 0) or
+// (length, distance) for every token. `head` holds position + 1 of the last
+// occurrence of each hash, or 0.
+fn tokens<F>(buf: &[u8], start: usize, head: &mut [u16; 1 << ENC_HASH_BITS],
+             mut f: F) -> io::Result<()>
+    where F: FnMut(usize, usize) -> io::Result<()>
+{
+    let mut i = start;
+    while i < buf.len() {
+        let mut len = 0;
+        if i + MIN_MATCH <= buf.len() {
+            let h = hash(&buf[i..]);
+            if head[h] != 0 {
+                let cand = head[h] as usize - 1;
+                let max = cmp::min(MAX_MATCH, buf.len() - i);
+                len = buf[cand..].iter().zip(buf[i..i + max].iter())
+                                 .take_while(|&(a, b)| a == b).count();
+                if len >= MIN_MATCH {
+                    try!(f(len, i - cand));
+                }
+            }
+            head[h] = (i + 1) as u16;
+        }
+        if len < MIN_MATCH {
+            try!(f(buf[i] as usize, 0));
+            i += 1;
+            continue
+        }
+        // the positions inside the match are indexed too
+        for j in i + 1..i + len {
+            if j + MIN_MATCH <= buf.len() {
+                head[hash(&buf[j..])] = (j + 1) as u16;
+            }
+        }
+        i += len;
+    }
+    Ok(())
+}
+
+/// A DEFLATE encoder for small systems. It only emits stored blocks and
+/// blocks using the fixed Huffman codes, finds matches with a single-entry
+/// hash table over a 1K window, and keeps its state inline in less than 4K
+/// of memory. The ratio is well below that of a full encoder, but the
+/// output is valid DEFLATE data.
+///
+/// Every 1K of input is costed both ways and stored unless compressing it
+/// saves enough, see `set_store_threshold`. Consecutive stored input is
+/// gathered into blocks of up to 64K, the only memory the encoder
+/// allocates, so incompressible input passes through with 5 bytes of
+/// overhead per 64K.
+pub struct Encoder<W> {
+    bits: BitWriter<W>,
+    buf: [u8; ENC_BUF],
+    len: usize,
+    start: usize,
+    head: [u16; 1 << ENC_HASH_BITS],
+    // input stored but not written yet, as the length of a stored block
+    // comes first
+    stored: Vec<u8>,
+    store_threshold: usize,
+    timer: Timer,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates a new encoder writing DEFLATE data to the given stream
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder::with_level(w, CompressionLevel::Default)
+    }
+
+    /// Creates a new encoder for the given level. Level 0 emits only stored
+    /// blocks, every other level the same fixed Huffman blocks.
+    pub fn with_level(w: W, level: CompressionLevel) -> Encoder<W> {
+        Encoder {
+            bits: BitWriter {
+                w,
+                out: [0; ENC_OUT],
+                outlen: 0,
+                bitbuf: 0,
+                bitcnt: 0,
+            },
+            buf: [0; ENC_BUF],
+            len: 0,
+            start: 0,
+            head: [0; 1 << ENC_HASH_BITS],
+            stored: Vec::new(),
+            store_threshold: if level.level() == 0 { 0 } else { DEFAULT_STORE_THRESHOLD },
+            timer: Timer::default(),
+        }
+    }
+
+    /// Writes every block stored from now on, whatever the data, as level 0
+    /// does. Mostly of use to exercise decoders and stored block handling.
+    pub fn force_stored(&mut self) {
+        self.store_threshold = 0;
+    }
+
+    /// Sets the ratio guardrail, in percent of the input size. Blocks which
+    /// don't compress below this size are stored instead, 98 by default.
+    /// 100 only stores blocks that would expand, 0 stores everything like
+    /// `force_stored`.
+    pub fn set_store_threshold(&mut self, percent: usize) {
+        self.store_threshold = cmp::min(percent, 100);
+    }
+
+    /// Starts timing the match finding and the entropy coding, see `Stats`.
+    /// The blocks are written replaying the matches found, so part of the
+    /// searching is counted as entropy coding.
+    pub fn enable_stats(&mut self) {
+        self.timer.enable();
+    }
+
+    /// Returns a mutable reference to the wrapped writer. Data written to it
+    /// lands before any compressed data not yet flushed.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.bits.w
+    }
+
+    // Writes out the stored input gathered so far
+    fn end_stored(&mut self) -> io::Result<()> {
+        if !self.stored.is_empty() {
+            try!(self.bits.stored(&self.stored, false));
+            self.stored.clear();
+        }
+        Ok(())
+    }
+
+    // Encodes the pending input as one block, fixed Huffman unless storing
+    // is smaller or the guardrail says so. Stored input is held back to be
+    // written along with the next until a block fills up.
+    fn block(&mut self, last: bool) -> io::Result<()> {
+        let data = &self.buf[..self.len];
+        let pending = self.len - self.start;
+        let stored = 3 + 7 + 32 + 8 * pending;
+        let start = self.timer.start();
+        let fixed = if self.store_threshold == 0 {
+            stored
+        } else {
+            let mut head = self.head;
+            let mut cost = 3 + 7;
+            try!(tokens(data, self.start, &mut head, |len, dist| {
+                cost += fixed_cost(len, dist);
+                Ok(())
+            }));
+            cost
+        };
+
+        debug!(target: "compress::flate", "encoding {} bytes, fixed: {} bits, stored: {} bits",
+               pending, fixed, stored);
+        if fixed < stored && fixed * 100 < 8 * pending * self.store_threshold {
+            self.timer.stop(start, Stage::MatchFinding);
+            let start = self.timer.start();
+            try!(self.end_stored());
+            let (bits, data) = (&mut self.bits, &self.buf[..self.len]);
+            try!(bits.put(last as u32 | 1 << 1, 3));
+            try!(tokens(data, self.start, &mut self.head, |len, dist| bits.token(len, dist)));
+            try!(bits.literal(256));
+            self.timer.stop(start, Stage::EntropyCoding);
+        } else {
+            // still index the data for matches in later blocks
+            try!(tokens(data, self.start, &mut self.head, |_, _| Ok(())));
+            self.timer.stop(start, Stage::MatchFinding);
+            let start = self.timer.start();
+            self.stored.extend_from_slice(&data[self.start..]);
+            while self.stored.len() > MAX_STORED {
+                try!(self.bits.stored(&self.stored[..MAX_STORED], false));
+                self.stored.drain(..MAX_STORED);
+            }
+            if last {
+                try!(self.bits.stored(&self.stored, true));
+                self.stored.clear();
+            }
+            self.timer.stop(start, Stage::EntropyCoding);
+        }
+        self.start = self.len;
+        Ok(())
+    }
+
+    /// Seeds the window with `history`, which matches may then refer back
+    /// to without it being part of the output. The decoder has to be primed
+    /// with the same bytes, see `Decoder::prime_window`. Matches only reach
+    /// 1K back, so only the end of `history` is used. Panics if anything
+    /// was written already.
+    pub fn prime_window(&mut self, history: &[u8]) {
+        assert!(self.len == 0, "the window can only be primed before writing");
+        let keep = &history[history.len().saturating_sub(ENC_WINDOW)..];
+        self.buf[..keep.len()].copy_from_slice(keep);
+        self.len = keep.len();
+        tokens(&self.buf[..self.len], 0, &mut self.head, |_, _| Ok(()))
+            .expect("indexing can't fail");
+        self.start = self.len;
+    }
+
+    /// Encodes everything written so far and writes it out along with an
+    /// empty block, so that a decoder can produce all of it from the bytes
+    /// written, then flushes the wrapped writer. Interactive protocols do
+    /// this after every message, see `FlushMode` for the differences.
+    pub fn flush_with(&mut self, mode: FlushMode) -> io::Result<()> {
+        if self.len > self.start {
+            try!(self.block(false));
+        }
+        try!(self.end_stored());
+        if mode == FlushMode::Partial {
+            try!(self.bits.put(1 << 1, 3));
+            try!(self.bits.literal(256));
+        } else {
+            try!(self.bits.put(0, 3));
+            try!(self.bits.align());
+            try!(self.bits.put(0xffff << 16, 32));
+        }
+        if mode == FlushMode::Full {
+            self.head = [0; 1 << ENC_HASH_BITS];
+        }
+        try!(self.bits.flush_out());
+        self.bits.w.flush()
+    }
+
+    /// Ends the stream like `finish` does, but keeps the writer and starts
+    /// a new stream which doesn't refer back to anything before. Formats
+    /// made of several DEFLATE streams, like multi-member GZIP files, are
+    /// written this way.
+    pub fn restart(&mut self) -> io::Result<()> {
+        try!(self.block(true));
+        try!(self.bits.align());
+        try!(self.bits.flush_out());
+        self.len = 0;
+        self.start = 0;
+        self.head = [0; 1 << ENC_HASH_BITS];
+        Ok(())
+    }
+
+    // Keeps the last ENC_WINDOW bytes as history for the next block
+    fn slide(&mut self) {
+        self.buf.copy_within(ENC_BUF - ENC_WINDOW.., 0);
+        self.len -= ENC_BUF - ENC_WINDOW;
+        self.start -= ENC_BUF - ENC_WINDOW;
+        for pos in self.head.iter_mut() {
+            *pos = pos.saturating_sub((ENC_BUF - ENC_WINDOW) as u16);
+        }
+    }
+
+    /// This function is used to flag that this session of compression is done
+    /// with. The stream is finished up (final bytes are written), and then the
+    /// wrapped writer is returned.
+    pub fn finish(self) -> (W, io::Result<()>) {
+        let (w, _, result) = self.finish_with_stats();
+        (w, result)
+    }
+
+    /// Same as `finish`, also returning the time spent in each stage if
+    /// `enable_stats` was called
+    pub fn finish_with_stats(mut self) -> (W, Stats, io::Result<()>) {
+        let result = self.block(true)
+                         .and_then(|_| self.bits.align())
+                         .and_then(|_| self.bits.flush_out())
+                         .and_then(|_| self.bits.w.flush());
+        (self.bits.w, self.timer.stats(), result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = cmp::min(ENC_BUF - self.len, rest.len());
+            self.buf[self.len..self.len + n].copy_from_slice(&rest[..n]);
+            self.len += n;
+            rest = &rest[n..];
+            if self.len == ENC_BUF {
+                try!(self.block(false));
+                self.slide();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Same as `flush_with(FlushMode::Sync)`
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_with(FlushMode::Sync)
+    }
+}
+
+
+/// Compresses `data` into raw DEFLATE data in one go, at the default level
+pub fn compress_to_vec(data: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.write_all(data).expect("writing to a Vec can't fail");
+    let (out, result) = e.finish();
+    result.expect("writing to a Vec can't fail");
+    out
+}
+
+/// Compresses `data` into raw DEFLATE data in `dst`, at the default level,
+/// returning the size of the output. Gives up with `LimitExceeded` as soon as
+/// the output doesn't fit, without compressing the rest of `data`.
+pub fn compress_into_limit(data: &[u8], dst: &mut [u8]) -> Result<usize, LimitExceeded> {
+    codecs::into_limit(dst, |w| {
+        let mut e = Encoder::new(w);
+        try!(e.write_all(data));
+        e.finish().1
+    })
+}
+
+/// Decompresses raw DEFLATE data held in memory in one go
+pub fn decompress_to_vec(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    try!(Decoder::new(data).read_to_end(&mut out));
+    Ok(out)
+}
+
+/// Raw DEFLATE as a runtime selectable `Codec`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flate {
+    /// The level encoders compress at
+    pub level: CompressionLevel,
+}
+
+impl Codec for Flate {
+    fn name(&self) -> &'static str { "flate" }
+
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>> {
+        metrics::encoder("flate", w, |w| Ok(Box::new(Encoder::with_level(w, self.level))))
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        metrics::decoder("flate", r, |r| Ok(Box::new(Decoder::new(r))))
+    }
+}
+
+impl<W: Write> Finish for Encoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (*self).finish().1
+    }
+}
+
 #[cfg(test)]
 #[allow(warnings)]
 mod test {
     use std::io::{BufReader, BufWriter, Read, Write};
     use super::super::rand::{random};
     use super::super::byteorder::{LittleEndian, BigEndian, WriteBytesExt, ReadBytesExt};
-    use std::str;
-    use super::{Decoder};
+    use std::{cmp, str};
+    use super::{CompressionLevel, Decoder, Encoder};
     #[cfg(feature="unstable")]
     use test;
 
@@ -541,6 +1645,15 @@ mod test {
         test_decode_pure(include_bytes!("data/test.z.go"), reference);
     }
 
+    #[test]
+    fn invalid_symbols() {
+        // fixed blocks with the unused length code 286 and distance code 30
+        for &data in &[&[0x1b, 0x03, 0x00][..], &[0x4b, 0x04, 0x3e, 0x00, 0x00][..]] {
+            let mut out = Vec::new();
+            assert!(Decoder::new(data).read_to_end(&mut out).is_err());
+        }
+    }
+
     #[test]
     fn large() {
         let reference = include_bytes!("data/test.large");
@@ -581,22 +1694,211 @@ mod test {
         assert!(&out[..] == &include_bytes!("data/test.txt")[..]);
     }
 
-    //fn roundtrip(bytes: &[u8]) {
-    //    let mut e = Encoder::new(MemWriter::new());
-    //    e.write(bytes);
-    //    let encoded = e.finish().unwrap();
-    //
-    //    let mut d = Decoder::new(BufReader::new(encoded));
-    //    let decoded = d.read_to_end();
-    //    assert_eq!(&decoded[..], bytes);
-    //}
-    //
-    //#[test]
-    //fn some_roundtrips() {
-    //    roundtrip(bytes!("test"));
-    //    roundtrip(bytes!(""));
-    //    roundtrip(include_bytes!("data/test.txt"));
-    //}
+    #[test]
+    fn export_import_state() {
+        use std::io::Cursor;
+        let input = fixup(include_bytes!("data/test.z.1"));
+        let reference = &include_bytes!("data/test.txt")[..];
+
+        let mut d = Decoder::new(Cursor::new(input));
+        let mut out = vec![0u8; 3000];
+        d.read_exact(&mut out).unwrap();
+        let state = d.export_state();
+        let offset = d.r.position();
+
+        let mut d = Decoder::new(Cursor::new(input));
+        d.r.set_position(offset);
+        d.import_state(&state).unwrap();
+        d.read_to_end(&mut out).unwrap();
+        assert!(&out[..] == reference);
+
+        assert!(d.import_state(&state[..state.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn import_oversized_window() {
+        for &len in [super::HISTORY, super::HISTORY + 1, 40000].iter() {
+            let mut state = vec![super::STATE_VERSION, 0, 0];
+            state.extend_from_slice(&[0; 8]);
+            state.extend_from_slice(&100u32.to_le_bytes());
+            state.extend_from_slice(&(len as u32).to_le_bytes());
+            state.resize(state.len() + len, b'a');
+            state.extend_from_slice(&0u32.to_le_bytes());
+            let mut d = Decoder::new(&[0x03, 0x00][..]);
+            let result = d.import_state(&state);
+            if len == super::HISTORY {
+                result.unwrap();
+                assert_eq!(d.read_to_end(&mut Vec::new()).unwrap(), 0);
+            } else {
+                assert_eq!(result.unwrap_err().kind(), ::std::io::ErrorKind::InvalidInput);
+            }
+        }
+    }
+
+    #[test]
+    fn skip() {
+        let input = include_bytes!("data/test.large.z.5");
+        let reference = &include_bytes!("data/test.large")[..];
+        let mut d = Decoder::new(BufReader::new(fixup(input)));
+        assert_eq!(d.skip(100000).unwrap(), 100000);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert!(out == &reference[100000..]);
+        assert_eq!(d.skip(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn compression_levels() {
+        assert_eq!(CompressionLevel::default().level(), 6);
+        assert_eq!(CompressionLevel::from(42).level(), 9);
+        assert_eq!(CompressionLevel::Level(0).params().max_chain, 0);
+        assert!(!CompressionLevel::Fastest.params().lazy);
+        assert!(CompressionLevel::Best.params().lazy);
+        let mut last = 0;
+        for level in 4..10 {
+            let chain = CompressionLevel::Level(level).params().max_chain;
+            assert!(chain >= last);
+            last = chain;
+        }
+    }
+
+    fn roundtrip(bytes: &[u8], level: CompressionLevel) -> usize {
+        let mut e = Encoder::with_level(Vec::new(), level);
+        for chunk in bytes.chunks(1000) {
+            e.write_all(chunk).unwrap();
+        }
+        let (encoded, result) = e.finish();
+        result.unwrap();
+
+        let mut d = Decoder::new(&encoded[..]);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert!(&decoded[..] == bytes);
+        encoded.len()
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        let text = include_bytes!("data/test.txt");
+        for &level in [CompressionLevel::Level(0), CompressionLevel::Default].iter() {
+            roundtrip(b"", level);
+            roundtrip(b"test", level);
+            roundtrip(text, level);
+            roundtrip(include_bytes!("data/test.large"), level);
+            roundtrip(&[b'a'; 10000], level);
+        }
+        assert!(roundtrip(text, CompressionLevel::Default) < text.len() * 9 / 10);
+
+        // incompressible data falls back to stored blocks of up to 64K
+        let noise = (0..200_000).map(|_| random::<u8>()).collect::<Vec<_>>();
+        let blocks = noise.len().div_ceil(0xffff);
+        assert_eq!(roundtrip(&noise, CompressionLevel::Default), noise.len() + 5 * blocks);
+    }
+
+    #[test]
+    fn forced_stored() {
+        let text = include_bytes!("data/test.txt");
+        let mut e = Encoder::new(Vec::new());
+        e.force_stored();
+        e.write_all(text).unwrap();
+        let (encoded, result) = e.finish();
+        result.unwrap();
+        // a single final block holds all of it
+        assert_eq!(encoded.len(), text.len() + 5);
+        assert_eq!(&encoded[..5], &[1, 0xea, 0x0b, 0x15, 0xf4]);
+        assert_eq!(&encoded[5..], &text[..]);
+        assert_eq!(&super::decompress_to_vec(&encoded).unwrap()[..], &text[..]);
+
+        // flushing writes out what was gathered so far
+        let mut e = Encoder::new(Vec::new());
+        e.force_stored();
+        e.write_all(text).unwrap();
+        e.flush().unwrap();
+        assert_eq!(e.get_mut().len(), text.len() + 5 * 2);
+        assert_eq!(&e.get_mut()[5..text.len() + 5], &text[..]);
+    }
+
+    #[test]
+    fn store_threshold() {
+        let text = include_bytes!("data/test.txt");
+        let encode = |percent| {
+            let mut e = Encoder::new(Vec::new());
+            e.set_store_threshold(percent);
+            e.write_all(text).unwrap();
+            let (encoded, result) = e.finish();
+            result.unwrap();
+            assert_eq!(&super::decompress_to_vec(&encoded).unwrap()[..], &text[..]);
+            encoded.len()
+        };
+        // no block of text compresses below 30% with the fixed codes
+        assert!(encode(98) < text.len() * 9 / 10);
+        assert_eq!(encode(30), text.len() + 5);
+        assert_eq!(encode(0), text.len() + 5);
+    }
+
+    #[test]
+    fn encoder_flush() {
+        let text = include_bytes!("data/test.txt");
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(&text[..3000]).unwrap();
+        e.flush().unwrap();
+        let mut decoded = Vec::new();
+        {
+            let mut d = Decoder::new(&e.bits.w[..]);
+            let mut buf = [0; 4096];
+            while decoded.len() < 3000 {
+                let n = d.read(&mut buf).unwrap();
+                decoded.extend_from_slice(&buf[..n]);
+            }
+        }
+        assert!(&decoded[..] == &text[..3000]);
+
+        e.write_all(&text[3000..]).unwrap();
+        let (encoded, result) = e.finish();
+        result.unwrap();
+        decoded.clear();
+        Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert!(&decoded[..] == &text[..]);
+    }
+
+    #[test]
+    fn flush_modes() {
+        use super::FlushMode;
+
+        let text = include_bytes!("data/test.txt");
+        for &mode in [FlushMode::Partial, FlushMode::Sync, FlushMode::Full].iter() {
+            let mut e = Encoder::new(Vec::new());
+            e.write_all(&text[..1500]).unwrap();
+            e.flush_with(mode).unwrap();
+            let flushed = e.bits.w.len();
+            match mode {
+                FlushMode::Partial => assert!(e.bits.bitcnt > 0),
+                _ => assert!(e.bits.bitcnt == 0 && e.bits.w.ends_with(&[0, 0, 0xff, 0xff])),
+            }
+            let mut decoded = vec![0; 1500];
+            Decoder::new(&e.bits.w[..]).read_exact(&mut decoded).unwrap();
+            assert!(&decoded[..] == &text[..1500]);
+
+            e.write_all(&text[1500..]).unwrap();
+            let (encoded, result) = e.finish();
+            result.unwrap();
+            decoded.clear();
+            Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+            assert!(&decoded[..] == &text[..]);
+
+            // after a full flush the rest decodes on its own
+            if mode == FlushMode::Full {
+                decoded.clear();
+                Decoder::new(&encoded[flushed..]).read_to_end(&mut decoded).unwrap();
+                assert!(&decoded[..] == &text[1500..]);
+            }
+        }
+    }
+
+    #[test]
+    fn encoder_footprint() {
+        assert!(::std::mem::size_of::<Encoder<()>>() <= 4096);
+    }
 
     #[cfg(feature="unstable")]
     #[bench]
@@ -612,4 +1914,153 @@ mod test {
         });
         bh.bytes = output_size as u64;
     }
+
+    #[test]
+    fn one_shot() {
+        ::codecs::check_one_shot(super::compress_to_vec, super::decompress_to_vec,
+                                 super::compress_into_limit);
+    }
+
+    #[test]
+    fn primed_window() {
+        let previous = &b"{\"user\": \"alice\", \"action\": \"login\", \"ok\": true}"[..];
+        let message = &b"{\"user\": \"alice\", \"action\": \"logout\", \"ok\": true}"[..];
+
+        let mut e = Encoder::new(Vec::new());
+        e.prime_window(previous);
+        e.write_all(message).unwrap();
+        let (delta, result) = e.finish();
+        result.unwrap();
+        assert!(delta.len() < super::compress_to_vec(message).len() / 2);
+
+        let mut d = Decoder::new(&delta[..]);
+        d.prime_window(previous);
+        let mut out = Vec::new();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], message);
+
+        // the same decoder goes on with the next message
+        d.reset();
+        d.r = &delta[..];
+        d.prime_window(previous);
+        out.clear();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], message);
+        assert!(super::decompress_to_vec(&delta).is_err());
+    }
+
+    #[test]
+    fn window() {
+        // short blocks, then whatever the encoder makes of the rest
+        let input = &include_bytes!("data/test.large")[..200000];
+        let mut e = Encoder::new(Vec::new());
+        for chunk in input[..100000].chunks(3000) {
+            e.write_all(chunk).unwrap();
+            e.flush().unwrap();
+        }
+        e.write_all(&input[100000..]).unwrap();
+        let (compressed, result) = e.finish();
+        result.unwrap();
+
+        let mut d = Decoder::new(&compressed[..]);
+        d.prime_window(b"seed");
+        let mut read = b"seed".to_vec();
+        let mut buf = [0; 1000];
+        loop {
+            let (a, b) = d.window(40000);
+            let window = [a, b].concat();
+            assert_eq!(window, &read[read.len() - window.len()..]);
+            let (a, b) = d.window(10);
+            assert_eq!([a, b].concat(), &read[read.len().saturating_sub(10)..]);
+            match d.read(&mut buf).unwrap() {
+                0 => break,
+                n => read.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(&read[4..], input);
+        let (a, b) = d.window(40000);
+        assert_eq!([a, b].concat(), &read[read.len() - super::HISTORY..]);
+
+        // a stored block larger than the ring, which only holds its end
+        let mut stored = vec![1];
+        stored.extend_from_slice(&40000u16.to_le_bytes());
+        stored.extend_from_slice(&(!40000u16).to_le_bytes());
+        stored.extend_from_slice(&input[..40000]);
+        let mut d = Decoder::new(&stored[..]);
+        d.read_exact(&mut buf).unwrap();
+        assert_eq!(d.window(40000), (&input[..1000], &[][..]));
+        assert_eq!(d.window(10), (&input[990..1000], &[][..]));
+    }
+
+    fn inflate_chunked(data: &[u8], in_size: usize, out_size: usize) -> Result<(Vec<u8>, usize), &'static str> {
+        use super::{InflateState, InflateStatus};
+        let mut state = Box::new(InflateState::new());
+        let (mut out, mut pos) = (Vec::new(), 0);
+        let mut buf = vec![0; out_size];
+        loop {
+            let end = cmp::min(pos + in_size, data.len());
+            let (used, written, status) = state.process(&data[pos..end], &mut buf);
+            pos += used;
+            out.extend_from_slice(&buf[..written]);
+            match status {
+                InflateStatus::Done => return Ok((out, pos)),
+                InflateStatus::Failed => return Err(state.error().unwrap()),
+                InflateStatus::NeedsMoreInput if end == data.len() => return Err("truncated"),
+                InflateStatus::NeedsMoreInput => assert_eq!(pos, end),
+                InflateStatus::HasMoreOutput => assert_eq!(written, out_size),
+            }
+        }
+    }
+
+    #[test]
+    fn inflate_state() {
+        let reference = &include_bytes!("data/test.txt")[..];
+        let streams: [&[u8]; 3] = [include_bytes!("data/test.z.0"), include_bytes!("data/test.z.5"),
+                                   include_bytes!("data/test.z.9")];
+        for stream in streams.iter() {
+            // the adler32 trailer is left alone
+            let data = &stream[2..];
+            for &(in_size, out_size) in [(1, 1), (7, 3000), (100_000, 1), (100_000, 100_000)].iter() {
+                let (out, used) = inflate_chunked(data, in_size, out_size).unwrap();
+                assert!(&out[..] == reference);
+                assert_eq!(used, data.len() - 4);
+            }
+        }
+
+        let mut r = ::io::CoreReader::new(&include_bytes!("data/test.z.go")[..], super::InflateState::new());
+        let mut out = Vec::new();
+        r.read_to_end(&mut out).unwrap();
+        assert!(&out[..] == reference);
+        let data = &include_bytes!("data/test.z.1")[2..1000];
+        let mut r = ::io::CoreReader::new(data, super::InflateState::new());
+        assert!(r.read_to_end(&mut out).is_err());
+    }
+
+    #[test]
+    fn inflate_state_matches_decoder() {
+        use super::super::rand::{Rng, SeedableRng};
+        use super::super::rand::rngs::StdRng;
+        let mut rng = StdRng::seed_from_u64(0x1f1a7e);
+        let originals = (0..10).map(|level| {
+            let mut e = Encoder::with_level(Vec::new(), CompressionLevel::Level(level));
+            e.write_all(&include_bytes!("data/test.txt")[..2000]).unwrap();
+            e.finish().0
+        }).collect::<Vec<_>>();
+        for round in 0..3000 {
+            let mut data = originals[round % originals.len()].clone();
+            for _ in 0..rng.gen_range(1, 4) {
+                let at = rng.gen_range(0, data.len());
+                data[at] ^= 1 << rng.gen_range(0, 8);
+            }
+            let mut expected = Vec::new();
+            let expected = Decoder::new(&data[..]).read_to_end(&mut expected).map(|_| expected);
+            let ours = inflate_chunked(&data, rng.gen_range(1, 64), rng.gen_range(1, 64));
+            match (expected, ours) {
+                (Ok(expected), Ok((ours, _))) => assert!(ours == expected, "round {}", round),
+                (Err(_), Err(_)) => {}
+                (expected, ours) => panic!("round {}: {:?} vs {:?}", round, expected.map(|v| v.len()),
+                                           ours.map(|v| v.0.len())),
+            }
+        }
+    }
 }
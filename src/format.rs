@@ -0,0 +1,147 @@
+//! Identifiers for the compressed formats this crate knows about
+
+/// A compressed data format. The discriminants are stable and may be stored.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    /// GZIP file, RFC 1952
+    Gzip = 1,
+    /// ZLIB stream, RFC 1950
+    Zlib = 2,
+    /// Raw DEFLATE stream, RFC 1951
+    Deflate = 3,
+    /// LZ4 frame format, with magic number and block framing
+    Lz4Frame = 4,
+    /// Single LZ4 block without any framing
+    Lz4Block = 5,
+    /// bzip2 file
+    Bzip2 = 6,
+    /// Archive written by the `compress` command line tool, which records
+    /// its chain of passes (bwt, mtf, ari, ...) in the header
+    BwtPipe = 7,
+    /// Run-length encoded stream, see the `rle` module
+    Rle = 8,
+    /// BWT stream of earlier versions of this crate, without magic bytes,
+    /// see `bwt::Decoder::legacy`
+    LegacyBwt = 9,
+}
+
+impl Format {
+    /// Every format, in discriminant order
+    pub const ALL: [Format; 9] = [
+        Format::Gzip, Format::Zlib, Format::Deflate, Format::Lz4Frame,
+        Format::Lz4Block, Format::Bzip2, Format::BwtPipe, Format::Rle,
+        Format::LegacyBwt,
+    ];
+
+    /// Looks a format up by its stored discriminant
+    pub fn from_id(id: u8) -> Option<Format> {
+        Format::ALL.iter().cloned().find(|&f| f as u8 == id)
+    }
+
+    /// Usual file extension, without the dot
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            Format::Gzip => Some("gz"),
+            Format::Zlib => Some("zz"),
+            Format::Deflate => Some("deflate"),
+            Format::Lz4Frame => Some("lz4"),
+            Format::Lz4Block | Format::LegacyBwt => None,
+            Format::Bzip2 => Some("bz2"),
+            Format::BwtPipe => Some("rcs"),
+            Format::Rle => Some("rle"),
+        }
+    }
+
+    /// Guesses the format from a file extension, with or without the
+    /// leading dot and ignoring case. Shorthands like `tgz` are understood.
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        let ext = ext.trim_start_matches('.').to_ascii_lowercase();
+        match &ext[..] {
+            "gz" | "gzip" | "tgz" => Some(Format::Gzip),
+            "zz" | "zlib" => Some(Format::Zlib),
+            "deflate" => Some(Format::Deflate),
+            "lz4" => Some(Format::Lz4Frame),
+            "bz2" | "bzip2" | "tbz" | "tbz2" => Some(Format::Bzip2),
+            "rcs" => Some(Format::BwtPipe),
+            "rle" => Some(Format::Rle),
+            _ => None,
+        }
+    }
+
+    /// Registered or conventional MIME type
+    pub fn mime(self) -> Option<&'static str> {
+        match self {
+            Format::Gzip => Some("application/gzip"),
+            Format::Zlib => Some("application/zlib"),
+            Format::Lz4Frame => Some("application/x-lz4"),
+            Format::Bzip2 => Some("application/x-bzip2"),
+            Format::Deflate | Format::Lz4Block | Format::BwtPipe | Format::Rle |
+            Format::LegacyBwt => None,
+        }
+    }
+
+    /// Maps a MIME type, including common aliases, back to a format.
+    /// Parameters after a `;` are ignored.
+    pub fn from_mime(mime: &str) -> Option<Format> {
+        let mime = mime.split(';').next().unwrap().trim().to_ascii_lowercase();
+        match &mime[..] {
+            "application/gzip" | "application/x-gzip" => Some(Format::Gzip),
+            "application/zlib" => Some(Format::Zlib),
+            "application/x-lz4" => Some(Format::Lz4Frame),
+            "application/x-bzip2" | "application/x-bzip" => Some(Format::Bzip2),
+            _ => None,
+        }
+    }
+
+    /// Recognizes a format by the magic bytes at the start of `prefix`:
+    /// GZIP, ZLIB, LZ4 frames (including the legacy format), bzip2 and
+    /// archives of the command line tool. Raw DEFLATE has no magic, see
+    /// `detect` for a heuristic covering it.
+    pub fn from_magic(prefix: &[u8]) -> Option<Format> {
+        match prefix {
+            [0x1f, 0x8b, ..] => Some(Format::Gzip),
+            [0x04, 0x22, 0x4d, 0x18, ..] | [0x02, 0x21, 0x4c, 0x18, ..] => Some(Format::Lz4Frame),
+            [b'B', b'Z', b'h', level, ..] if (b'1'..=b'9').contains(level) => Some(Format::Bzip2),
+            [b'r', b'!', b'c', b's', ..] => Some(Format::BwtPipe),
+            // deflate with a window of at most 32K and a valid check
+            &[cmf, flg, ..] if cmf & 0xf == 8 && cmf >> 4 <= 7 &&
+                               u16::from_be_bytes([cmf, flg]).is_multiple_of(31) => Some(Format::Zlib),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Format;
+
+    #[test]
+    fn mappings_roundtrip() {
+        for &f in Format::ALL.iter() {
+            assert_eq!(Format::from_id(f as u8), Some(f));
+            if let Some(ext) = f.extension() {
+                assert_eq!(Format::from_extension(ext), Some(f));
+            }
+            if let Some(mime) = f.mime() {
+                assert_eq!(Format::from_mime(mime), Some(f));
+            }
+        }
+        assert_eq!(Format::from_extension(".TGZ"), Some(Format::Gzip));
+        assert_eq!(Format::from_mime("application/x-gzip; q=0.5"), Some(Format::Gzip));
+        assert_eq!(Format::from_extension("txt"), None);
+        assert_eq!(Format::from_id(0), None);
+    }
+
+    #[test]
+    fn magic() {
+        assert_eq!(Format::from_magic(include_bytes!("data/test.gz")), Some(Format::Gzip));
+        assert_eq!(Format::from_magic(include_bytes!("data/test.z.5")), Some(Format::Zlib));
+        assert_eq!(Format::from_magic(include_bytes!("data/test.lz4.1")), Some(Format::Lz4Frame));
+        assert_eq!(Format::from_magic(include_bytes!("data/test.lz4.legacy")), Some(Format::Lz4Frame));
+        assert_eq!(Format::from_magic(include_bytes!("data/test.txt.bz2")), Some(Format::Bzip2));
+        assert_eq!(Format::from_magic(include_bytes!("data/test.txt")), None);
+        assert_eq!(Format::from_magic(b"BZh0"), None);
+        assert_eq!(Format::from_magic(&[0x1f]), None);
+    }
+}
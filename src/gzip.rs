@@ -0,0 +1,1273 @@
+//! GZIP file format. Requires `gzip` feature, enabled by default
+//!
+//! This module contains the handling of GZIP member headers, the envelope
+//! which wraps DEFLATE-encoded data in `.gz` files. The headers carry
+//! metadata such as the original file name and modification time which can
+//! be rewritten without touching the compressed payload. `Encoder` writes
+//! complete members. A file may hold several members one after another,
+//! `Decoder` reads them one at a time and `MultiDecoder` joins their
+//! contents like `gzip -d` does. That also lets `concat` splice whole files
+//! into one without recompressing anything.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use compress::gzip;
+//! use std::fs::File;
+//!
+//! // strip the file name and timestamp from an archive for reproducible builds
+//! let input = File::open("path/to/file.gz").unwrap();
+//! let output = File::create("path/to/anonymous.gz").unwrap();
+//! gzip::redact(input, output, gzip::Redaction::Mask).unwrap();
+//! ```
+//!
+//! # Related links
+//!
+//! * http://tools.ietf.org/html/rfc1952 - RFC that this implementation is based
+//!   on
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::ReadExact;
+use checksum::crc;
+use codecs::{Codec, Finish};
+use metrics;
+use flate::{self, CompressionLevel};
+use io::{Echo, Validation};
+use stats::{Stage, Stats, Timer};
+
+/// The two bytes every GZIP member starts with
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const METHOD_DEFLATE: u8 = 8;
+const FTEXT: u8 = 0x01;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+const FRESERVED: u8 = 0xe0;
+
+/// Operating system code for "unknown"
+pub const OS_UNKNOWN: u8 = 255;
+
+fn invalid<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+/// The header of a single GZIP member
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// FTEXT: the payload is probably text
+    pub text: bool,
+    /// FHCRC: the header is protected by a CRC-16
+    pub header_crc: bool,
+    /// Modification time of the original file, in seconds since the epoch,
+    /// or zero if not available, see `modified`
+    pub mtime: u32,
+    /// Extra flags describing the compression level used
+    pub xfl: u8,
+    /// Operating system on which the compression took place
+    pub os: u8,
+    /// Raw contents of the FEXTRA field, see `subfields`
+    pub extra: Option<Vec<u8>>,
+    /// Original file name, without the zero terminator
+    pub name: Option<Vec<u8>>,
+    /// File comment, without the zero terminator
+    pub comment: Option<Vec<u8>>,
+}
+
+/// Upper bounds on the variable length fields of a header, in bytes. The
+/// zero-terminated name and comment have no length limit in the format, so
+/// a malicious file can make a reader buffer a header of any size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Longest file name, without the zero terminator
+    pub name: usize,
+    /// Longest file comment, without the zero terminator
+    pub comment: usize,
+    /// Longest FEXTRA field, which the format limits to 65535 bytes anyway
+    pub extra: usize,
+}
+
+impl Limits {
+    /// Accepts fields of any length
+    pub fn unlimited() -> Limits {
+        Limits { name: usize::MAX, comment: usize::MAX, extra: usize::MAX }
+    }
+}
+
+impl Default for Limits {
+    /// 4 KiB for the name and the comment, any FEXTRA field
+    fn default() -> Limits {
+        Limits { name: 4 << 10, comment: 4 << 10, extra: usize::MAX }
+    }
+}
+
+/// A header field with a length limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    /// FEXTRA
+    Extra,
+    /// FNAME
+    Name,
+    /// FCOMMENT
+    Comment,
+}
+
+/// A header field exceeding its limit, found inside the `io::Error`
+/// returned by `Header::read`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FieldTooLong {
+    /// The field which is too long
+    pub field: Field,
+    /// The limit it exceeds
+    pub limit: usize,
+}
+
+impl FieldTooLong {
+    /// Returns the field error carried by `e`, if any
+    pub fn find(e: &io::Error) -> Option<&FieldTooLong> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<FieldTooLong>())
+    }
+}
+
+impl fmt::Display for FieldTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let field = match self.field {
+            Field::Extra => "extra field",
+            Field::Name => "file name",
+            Field::Comment => "comment",
+        };
+        write!(f, "gzip header {} is longer than {} bytes", field, self.limit)
+    }
+}
+
+impl Error for FieldTooLong {}
+
+fn too_long<T>(field: Field, limit: usize) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, FieldTooLong { field, limit }))
+}
+
+/// How `Header::redact` anonymizes a header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Redaction {
+    /// Zero MTIME and drop the FNAME and FCOMMENT fields entirely
+    Strip,
+    /// Zero MTIME and overwrite every FNAME and FCOMMENT byte with `_`, which
+    /// keeps the header (and so every offset in the file) the same length
+    Mask,
+}
+
+impl Header {
+    /// Creates an empty header with an unknown operating system
+    pub fn new() -> Header {
+        Header {
+            text: false,
+            header_crc: false,
+            mtime: 0,
+            xfl: 0,
+            os: OS_UNKNOWN,
+            extra: None,
+            name: None,
+            comment: None,
+        }
+    }
+
+    /// Reads and validates a member header from the given stream, leaving it
+    /// positioned at the start of the DEFLATE payload. Fields longer than
+    /// the default `Limits` are rejected with a `FieldTooLong` error.
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Header> {
+        Header::read_with_limits(r, &Limits::default())
+    }
+
+    /// Same as `read`, with the given limits on the field lengths
+    pub fn read_with_limits<R: Read>(r: &mut R, limits: &Limits) -> io::Result<Header> {
+        // everything is recorded for the optional header checksum
+        let mut raw = Vec::new();
+        try!(r.push_exactly(10, &mut raw));
+        if raw[..2] != MAGIC {
+            return invalid("invalid gzip magic")
+        }
+        if raw[2] != METHOD_DEFLATE {
+            return invalid("unsupported gzip compression method")
+        }
+        let flags = raw[3];
+        if flags & FRESERVED != 0 {
+            return invalid("reserved gzip header flags are set")
+        }
+        let mut header = Header {
+            text: flags & FTEXT != 0,
+            header_crc: flags & FHCRC != 0,
+            mtime: try!((&raw[4..8]).read_u32::<LittleEndian>()),
+            xfl: raw[8],
+            os: raw[9],
+            extra: None,
+            name: None,
+            comment: None,
+        };
+
+        if flags & FEXTRA != 0 {
+            let start = raw.len();
+            try!(r.push_exactly(2, &mut raw));
+            let len = try!((&raw[start..]).read_u16::<LittleEndian>());
+            if len as usize > limits.extra {
+                return too_long(Field::Extra, limits.extra)
+            }
+            try!(r.push_exactly(len as u64, &mut raw));
+            header.extra = Some(raw[start + 2..].to_vec());
+        }
+        if flags & FNAME != 0 {
+            header.name = Some(try!(read_zero_terminated(r, &mut raw, Field::Name, limits.name)));
+        }
+        if flags & FCOMMENT != 0 {
+            header.comment = Some(try!(read_zero_terminated(r, &mut raw, Field::Comment,
+                                                            limits.comment)));
+        }
+        if header.header_crc {
+            let mut state = crc::State32::new();
+            state.feed(&raw);
+            if try!(r.read_u16::<LittleEndian>()) != state.result() as u16 {
+                return invalid("invalid gzip header checksum")
+            }
+        }
+        Ok(header)
+    }
+
+    /// Writes this header out, computing the header checksum if requested.
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut flags = 0;
+        if self.text { flags |= FTEXT }
+        if self.header_crc { flags |= FHCRC }
+        if self.extra.is_some() { flags |= FEXTRA }
+        if self.name.is_some() { flags |= FNAME }
+        if self.comment.is_some() { flags |= FCOMMENT }
+
+        let mut raw = Vec::with_capacity(10);
+        raw.extend_from_slice(&MAGIC);
+        raw.push(METHOD_DEFLATE);
+        raw.push(flags);
+        raw.write_u32::<LittleEndian>(self.mtime).unwrap();
+        raw.push(self.xfl);
+        raw.push(self.os);
+        if let Some(ref extra) = self.extra {
+            if extra.len() > 0xffff {
+                return invalid("gzip extra field is too long")
+            }
+            raw.write_u16::<LittleEndian>(extra.len() as u16).unwrap();
+            raw.extend_from_slice(extra);
+        }
+        for field in [&self.name, &self.comment].iter() {
+            if let Some(ref text) = **field {
+                if text.contains(&0) {
+                    return invalid("gzip header text contains a zero byte")
+                }
+                raw.extend_from_slice(text);
+                raw.push(0);
+            }
+        }
+        if self.header_crc {
+            let mut state = crc::State32::new();
+            state.feed(&raw);
+            raw.write_u16::<LittleEndian>(state.result() as u16).unwrap();
+        }
+        w.write_all(&raw)
+    }
+
+    /// Returns the file name decoded as ISO 8859-1 (Latin-1), the character
+    /// set RFC 1952 prescribes. Every byte maps to a character, so this
+    /// never fails, but names written as UTF-8 come out garbled.
+    pub fn name_latin1(&self) -> Option<Cow<'_, str>> {
+        self.name.as_ref().map(|name| latin1(name))
+    }
+
+    /// Returns the file name decoded as UTF-8, which is what most tools
+    /// write in practice, with invalid sequences replaced by U+FFFD
+    pub fn name_lossy(&self) -> Option<Cow<'_, str>> {
+        self.name.as_ref().map(|name| String::from_utf8_lossy(name))
+    }
+
+    /// Returns the file comment decoded as ISO 8859-1, see `name_latin1`
+    pub fn comment_latin1(&self) -> Option<Cow<'_, str>> {
+        self.comment.as_ref().map(|comment| latin1(comment))
+    }
+
+    /// Returns the file comment decoded as UTF-8, see `name_lossy`
+    pub fn comment_lossy(&self) -> Option<Cow<'_, str>> {
+        self.comment.as_ref().map(|comment| String::from_utf8_lossy(comment))
+    }
+
+    /// Sets the file name, encoded as ISO 8859-1. Fails for names with
+    /// characters beyond U+00FF or with a zero character, leaving the header
+    /// unchanged. Set the `name` field directly to store other bytes.
+    pub fn set_name(&mut self, name: &str) -> io::Result<()> {
+        self.name = Some(try!(to_latin1(name)));
+        Ok(())
+    }
+
+    /// Sets the file comment, encoded as ISO 8859-1, see `set_name`
+    pub fn set_comment(&mut self, comment: &str) -> io::Result<()> {
+        self.comment = Some(try!(to_latin1(comment)));
+        Ok(())
+    }
+
+    /// Returns the modification time, or `None` if the header doesn't
+    /// record one
+    pub fn modified(&self) -> Option<SystemTime> {
+        match self.mtime {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs as u64)),
+        }
+    }
+
+    /// Sets the modification time, truncated to whole seconds, or clears it.
+    /// Times before 1970 or after 2106 don't fit the header and are
+    /// rejected, leaving it unchanged.
+    pub fn set_modified(&mut self, time: Option<SystemTime>) -> io::Result<()> {
+        self.mtime = match time {
+            None => 0,
+            Some(time) => match time.duration_since(UNIX_EPOCH) {
+                Ok(since) if since.as_secs() <= u32::MAX as u64 => since.as_secs() as u32,
+                _ => return invalid("time out of the range of a gzip header"),
+            },
+        };
+        Ok(())
+    }
+
+    /// Returns the subfields of the FEXTRA field in order, each as its id
+    /// (SI1 and SI2) and data. Fails if the field isn't a sequence of
+    /// subfields, which RFC 1952 expects but doesn't enforce.
+    pub fn subfields(&self) -> io::Result<Vec<([u8; 2], &[u8])>> {
+        let mut rest = match self.extra {
+            Some(ref extra) => &extra[..],
+            None => return Ok(Vec::new()),
+        };
+        let mut fields = Vec::new();
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return invalid("truncated gzip extra subfield")
+            }
+            let len = rest[2] as usize | (rest[3] as usize) << 8;
+            if rest.len() < 4 + len {
+                return invalid("truncated gzip extra subfield")
+            }
+            fields.push(([rest[0], rest[1]], &rest[4..4 + len]));
+            rest = &rest[4 + len..];
+        }
+        Ok(fields)
+    }
+
+    /// Returns the data of the first subfield with the given id, if the
+    /// FEXTRA field holds well-formed subfields
+    pub fn subfield(&self, id: [u8; 2]) -> Option<&[u8]> {
+        self.subfields().ok()
+            .and_then(|fields| fields.into_iter().find(|&(i, _)| i == id))
+            .map(|(_, data)| data)
+    }
+
+    /// Appends a subfield to the FEXTRA field, creating the field if needed.
+    /// Ids with a zero SI2 are reserved, and the whole field is limited to
+    /// 65535 bytes; anything else is rejected, leaving the header unchanged.
+    pub fn add_subfield(&mut self, id: [u8; 2], data: &[u8]) -> io::Result<()> {
+        if id[1] == 0 {
+            return invalid("reserved gzip extra subfield id")
+        }
+        let len = self.extra.as_ref().map_or(0, |extra| extra.len());
+        if len + 4 + data.len() > 0xffff {
+            return invalid("gzip extra field is too long")
+        }
+        let extra = self.extra.get_or_insert_with(Vec::new);
+        extra.extend_from_slice(&id);
+        extra.write_u16::<LittleEndian>(data.len() as u16).unwrap();
+        extra.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Sets the extra flags to describe a member compressed at `level`, as
+    /// gzip does: 2 for the slowest levels and 4 for the fastest one
+    pub fn set_level(&mut self, level: CompressionLevel) {
+        self.xfl = match level.level() {
+            1 => 4,
+            9 => 2,
+            _ => 0,
+        };
+    }
+
+    /// Removes identifying metadata from this header
+    pub fn redact(&mut self, how: Redaction) {
+        self.mtime = 0;
+        match how {
+            Redaction::Strip => {
+                self.name = None;
+                self.comment = None;
+            }
+            Redaction::Mask => {
+                for field in [&mut self.name, &mut self.comment].iter_mut() {
+                    if let Some(ref mut text) = **field {
+                        for byte in text.iter_mut() {
+                            *byte = b'_';
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn latin1(bytes: &[u8]) -> Cow<'_, str> {
+    if bytes.is_ascii() {
+        // ASCII is valid UTF-8 as is
+        Cow::Borrowed(str::from_utf8(bytes).unwrap())
+    } else {
+        Cow::Owned(bytes.iter().map(|&b| b as char).collect())
+    }
+}
+
+fn to_latin1(text: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match c as u32 {
+            0 => return invalid("gzip header text contains a zero byte"),
+            c @ 1..=0xff => bytes.push(c as u8),
+            _ => return invalid("gzip header text is not ISO 8859-1"),
+        }
+    }
+    Ok(bytes)
+}
+
+impl Default for Header {
+    fn default() -> Header {
+        Header::new()
+    }
+}
+
+/// Writes a single GZIP member, compressing the data with `flate::Encoder`.
+/// The header goes out with the first write and the CRC-32 and length
+/// trailer on `finish`. With `set_member_size` the data is split over
+/// several members instead.
+pub struct Encoder<W: Write> {
+    inner: flate::Encoder<W>,
+    header: Option<Header>,
+    template: Header,
+    crc: crc::State32,
+    size: u32,
+    member_size: Option<u64>,
+    member_len: u64,
+    timer: Timer,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder writing a member with an empty header
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder::with_header(w, Header::new(), CompressionLevel::Default)
+    }
+
+    /// Creates an encoder writing a member with the given header, whose
+    /// extra flags are set to match `level`
+    pub fn with_header(w: W, mut header: Header, level: CompressionLevel) -> Encoder<W> {
+        header.set_level(level);
+        Encoder {
+            inner: flate::Encoder::with_level(w, level),
+            header: Some(header.clone()),
+            template: header,
+            crc: crc::State32::new(),
+            size: 0,
+            member_size: None,
+            member_len: 0,
+            timer: Timer::default(),
+        }
+    }
+
+    /// Starts a new member, with the same header, every `size` bytes of
+    /// uncompressed data, or writes everything as a single member with
+    /// `None`, the default. Members are independent, so they can be
+    /// decoded in parallel, and corruption in one of them doesn't spread to
+    /// the next. `MultiDecoder` and `gzip -d` join them back together.
+    /// Members hold at least one byte, so a size of zero is ignored. A size
+    /// below what the current member already holds ends it with the next
+    /// write.
+    pub fn set_member_size(&mut self, size: Option<u64>) {
+        if size != Some(0) {
+            self.member_size = size;
+        }
+    }
+
+    // Writes the trailer of the member written so far, the next one is
+    // started by the next write
+    fn end_member(&mut self) -> io::Result<()> {
+        try!(self.inner.restart());
+        let (crc, size) = (self.crc.result(), self.size);
+        try!(self.inner.get_mut().write_u32::<LittleEndian>(crc));
+        try!(self.inner.get_mut().write_u32::<LittleEndian>(size));
+        self.header = Some(self.template.clone());
+        self.crc.reset();
+        self.size = 0;
+        self.member_len = 0;
+        Ok(())
+    }
+
+    /// Sets the ratio guardrail of the DEFLATE blocks, see
+    /// `flate::Encoder::set_store_threshold`
+    pub fn set_store_threshold(&mut self, percent: usize) {
+        self.inner.set_store_threshold(percent);
+    }
+
+    /// Starts timing the match finding, the entropy coding and the CRC-32
+    /// computation, see `Stats`
+    pub fn enable_stats(&mut self) {
+        self.inner.enable_stats();
+        self.timer.enable();
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        match self.header.take() {
+            Some(header) => header.write(self.inner.get_mut()),
+            None => Ok(()),
+        }
+    }
+
+    /// This function is used to flag that this session of compression is done
+    /// with. The stream is finished up (final bytes are written), and then the
+    /// wrapped writer is returned.
+    pub fn finish(self) -> (W, io::Result<()>) {
+        let (w, _, result) = self.finish_with_stats();
+        (w, result)
+    }
+
+    /// Same as `finish`, also returning the time spent in each stage if
+    /// `enable_stats` was called
+    pub fn finish_with_stats(mut self) -> (W, Stats, io::Result<()>) {
+        if let Err(e) = self.write_header() {
+            let (w, stats, _) = self.inner.finish_with_stats();
+            return (w, stats, Err(e))
+        }
+        let (mut w, mut stats, result) = self.inner.finish_with_stats();
+        stats.checksumming = self.timer.stats().checksumming;
+        let crc = self.crc.result();
+        let size = self.size;
+        let result = result.and_then(|_| w.write_u32::<LittleEndian>(crc))
+                           .and_then(|_| w.write_u32::<LittleEndian>(size))
+                           .and_then(|_| w.flush());
+        (w, stats, result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        if let Some(member_size) = self.member_size {
+            // a full member is only ended once there is more data, so that
+            // `finish` doesn't leave an empty one behind
+            if self.member_len >= member_size && !buf.is_empty() {
+                try!(self.end_member());
+            }
+            let room = member_size.saturating_sub(self.member_len);
+            if (buf.len() as u64) > room {
+                buf = &buf[..room as usize];
+            }
+        }
+        try!(self.write_header());
+        let n = try!(self.inner.write(buf));
+        let start = self.timer.start();
+        self.crc.feed(&buf[..n]);
+        self.timer.stop(start, Stage::Checksumming);
+        self.size = self.size.wrapping_add(n as u32);
+        self.member_len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_header());
+        self.inner.flush()
+    }
+}
+
+/// Reads the members of a GZIP file one after another. `member` moves on
+/// to the next member and returns its header, after which the decoder reads
+/// the contents of that member, checking its CRC-32 and length at the end.
+pub struct Decoder<R> {
+    inner: flate::Decoder<R>,
+    limits: Limits,
+    crc: crc::State32,
+    size: u32,
+    in_member: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading members from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder::with_limits(r, Limits::default())
+    }
+
+    /// Creates a decoder rejecting headers whose fields exceed `limits`
+    pub fn with_limits(r: R, limits: Limits) -> Decoder<R> {
+        Decoder {
+            inner: flate::Decoder::new(r),
+            limits,
+            crc: crc::State32::new(),
+            size: 0,
+            in_member: false,
+        }
+    }
+
+    /// Skips whatever is left of the current member and reads the header of
+    /// the next one, returning `None` at the end of the file
+    pub fn member(&mut self) -> io::Result<Option<Header>> {
+        if self.in_member {
+            try!(io::copy(self, &mut io::sink()));
+        }
+        let mut first = [0];
+        loop {
+            match self.inner.r.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let header = try!(Header::read_with_limits(&mut (&first[..]).chain(&mut self.inner.r),
+                                                   &self.limits));
+        self.inner.reset();
+        self.crc.reset();
+        self.size = 0;
+        self.in_member = true;
+        Ok(Some(header))
+    }
+
+    /// Returns an iterator decoding the remaining members in full
+    pub fn members(&mut self) -> Members<'_, R> {
+        Members { decoder: self, done: false }
+    }
+
+    /// Destroys this decoder, returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner.r
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    /// Reads the contents of the current member, returning 0 at its end or
+    /// before the first call to `member`
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.in_member || buf.is_empty() {
+            return Ok(0)
+        }
+        let n = try!(self.inner.read(buf));
+        if n > 0 {
+            self.crc.feed(&buf[..n]);
+            self.size = self.size.wrapping_add(n as u32);
+            return Ok(n)
+        }
+        self.in_member = false;
+        let crc = try!(self.inner.r.read_u32::<LittleEndian>());
+        let size = try!(self.inner.r.read_u32::<LittleEndian>());
+        if crc != self.crc.result() || size != self.size {
+            return invalid("invalid checksum on gzip member")
+        }
+        Ok(0)
+    }
+}
+
+/// A member decoded in full
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Member {
+    /// The header of the member
+    pub header: Header,
+    /// The decompressed contents
+    pub data: Vec<u8>,
+}
+
+/// Iterator over the members of a GZIP file, see `Decoder::members`. It
+/// ends after the first error.
+pub struct Members<'a, R: 'a> {
+    decoder: &'a mut Decoder<R>,
+    done: bool,
+}
+
+impl<'a, R: Read> Iterator for Members<'a, R> {
+    type Item = io::Result<Member>;
+
+    fn next(&mut self) -> Option<io::Result<Member>> {
+        if self.done {
+            return None
+        }
+        let result = self.decoder.member().and_then(|header| match header {
+            Some(header) => {
+                let mut data = Vec::new();
+                try!(self.decoder.read_to_end(&mut data));
+                Ok(Some(Member { header, data }))
+            }
+            None => Ok(None),
+        });
+        match result {
+            Ok(Some(member)) => Some(Ok(member)),
+            Ok(None) => { self.done = true; None }
+            Err(e) => { self.done = true; Some(Err(e)) }
+        }
+    }
+}
+
+/// Reads the contents of all members of a GZIP file as one stream, the way
+/// `gzip -d` does. A file has to hold at least one member.
+pub struct MultiDecoder<R> {
+    decoder: Decoder<R>,
+    started: bool,
+}
+
+impl<R: Read> MultiDecoder<R> {
+    /// Creates a decoder reading a GZIP file from `r`
+    pub fn new(r: R) -> MultiDecoder<R> {
+        MultiDecoder { decoder: Decoder::new(r), started: false }
+    }
+
+    /// Returns the member decoder underneath, to change its settings or look
+    /// at the position in the file
+    pub fn get_mut(&mut self) -> &mut Decoder<R> {
+        &mut self.decoder
+    }
+
+    /// Destroys this decoder, returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.decoder.into_inner()
+    }
+}
+
+impl<R: Read> Read for MultiDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.decoder.in_member {
+                match try!(self.decoder.member()) {
+                    Some(_) => self.started = true,
+                    None if self.started => return Ok(0),
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                      "empty gzip file")),
+                }
+            }
+            let n = try!(self.decoder.read(buf));
+            if n > 0 || buf.is_empty() {
+                return Ok(n)
+            }
+        }
+    }
+}
+
+fn read_zero_terminated<R: Read>(r: &mut R, raw: &mut Vec<u8>, field: Field, limit: usize)
+                                 -> io::Result<Vec<u8>> {
+    let start = raw.len();
+    loop {
+        match try!(r.read_u8()) {
+            0 => break,
+            _ if raw.len() - start == limit => return too_long(field, limit),
+            b => raw.push(b),
+        }
+    }
+    let text = raw[start..].to_vec();
+    raw.push(0);
+    Ok(text)
+}
+
+/// Copies a GZIP file from `r` to `w`, anonymizing the header of every
+/// member as described by `how`. The compressed payloads and trailers are
+/// passed through untouched, so nothing gets recompressed, but they are
+/// inflated on the way to find where each member ends.
+pub fn redact<R: Read, W: Write>(mut r: R, mut w: W, how: Redaction) -> io::Result<()> {
+    let mut first = [0];
+    let mut started = false;
+    loop {
+        match r.read(&mut first) {
+            Ok(0) if started => break,
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty gzip file")),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        started = true;
+        let mut header = try!(Header::read(&mut (&first[..]).chain(&mut r)));
+        header.redact(how);
+        try!(header.write(&mut w));
+        // the decoder stops right after the compressed data, the trailer
+        // follows
+        let mut d = flate::Decoder::new(Echo::new(&mut r, &mut w));
+        try!(io::copy(&mut d, &mut io::sink()));
+        try!(d.r.read_exact(&mut [0; 8]));
+    }
+    w.flush()
+}
+
+/// Splices GZIP files into one, copying them to `w` one after another and
+/// returning the number of bytes written. A GZIP file may hold any number
+/// of members, so the bytes of the parts are all that is copied: headers
+/// and trailers stay as they were, nothing gets recompressed, and the
+/// result decompresses to the contents of the parts in order. Each part is
+/// checked as `validation` says while it is copied, an empty part being an
+/// error. The error then carries a `SpliceFailed` saying how many bytes of
+/// `w` are made of whole parts, the rest coming from the failing one.
+pub fn concat<I, W>(parts: I, w: W, validation: Validation) -> io::Result<u64>
+    where I: IntoIterator, I::Item: Read, W: Write
+{
+    ::io::splice(parts, w, |r| {
+        match validation {
+            Validation::Header => {
+                try!(Header::read(&mut *r));
+                try!(io::copy(r, &mut io::sink()));
+            }
+            Validation::Full => {
+                try!(io::copy(&mut MultiDecoder::new(r), &mut io::sink()));
+            }
+        }
+        Ok(())
+    })
+}
+
+
+/// Compresses `data` into a GZIP member with an empty header in one go, at
+/// the default level
+pub fn compress_to_vec(data: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.write_all(data).expect("writing to a Vec can't fail");
+    let (out, result) = e.finish();
+    result.expect("writing to a Vec can't fail");
+    out
+}
+
+/// Decompresses the first GZIP member held in memory in one go, checking
+/// its CRC-32 and length
+pub fn decompress_to_vec(mut data: &[u8]) -> io::Result<Vec<u8>> {
+    try!(Header::read(&mut data));
+    let mut d = flate::Decoder::new(data);
+    let mut out = Vec::new();
+    try!(d.read_to_end(&mut out));
+    let mut state = crc::State32::new();
+    state.feed(&out);
+    let crc = try!(d.r.read_u32::<LittleEndian>());
+    let size = try!(d.r.read_u32::<LittleEndian>());
+    if crc != state.result() || size != out.len() as u32 {
+        return invalid("invalid checksum on gzip member")
+    }
+    Ok(out)
+}
+
+/// GZIP members with an empty header as a runtime selectable `Codec`,
+/// decoded with `MultiDecoder`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Gzip {
+    /// The level encoders compress at
+    pub level: CompressionLevel,
+}
+
+impl Codec for Gzip {
+    fn name(&self) -> &'static str { "gzip" }
+
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>> {
+        metrics::encoder("gzip", w, |w| Ok(Box::new(Encoder::with_header(w, Header::new(), self.level))))
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        metrics::decoder("gzip", r, |r| Ok(Box::new(MultiDecoder::new(r))))
+    }
+}
+
+impl<W: Write> Finish for Encoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (*self).finish().1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+    use super::{Field, FieldTooLong, Header, Limits, Redaction};
+    use flate::CompressionLevel;
+
+    fn sample() -> Header {
+        let mut header = Header::new();
+        header.mtime = 1401624000;
+        header.header_crc = true;
+        header.extra = Some(b"AB\x02\x00hi".to_vec());
+        header.name = Some(b"secret.txt".to_vec());
+        header.comment = Some(b"made by me".to_vec());
+        header
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let header = sample();
+        let mut raw = Vec::new();
+        header.write(&mut raw).unwrap();
+        assert_eq!(Header::read(&mut &raw[..]).unwrap(), header);
+
+        // a corrupted name must be caught by the header checksum
+        raw[20] ^= 1;
+        assert!(Header::read(&mut &raw[..]).is_err());
+    }
+
+    #[test]
+    fn field_limits() {
+        let mut header = Header::new();
+        header.name = Some(vec![b'a'; 5000]);
+        header.comment = Some(b"short".to_vec());
+        let mut raw = Vec::new();
+        header.write(&mut raw).unwrap();
+
+        let e = Header::read(&mut &raw[..]).unwrap_err();
+        assert_eq!(FieldTooLong::find(&e), Some(&FieldTooLong { field: Field::Name, limit: 4096 }));
+        let limits = Limits { name: 5000, ..Limits::default() };
+        assert_eq!(Header::read_with_limits(&mut &raw[..], &limits).unwrap(), header);
+        let limits = Limits { comment: 4, ..Limits::unlimited() };
+        let e = Header::read_with_limits(&mut &raw[..], &limits).unwrap_err();
+        assert_eq!(FieldTooLong::find(&e).unwrap().field, Field::Comment);
+
+        header.name = None;
+        header.extra = Some(vec![0; 100]);
+        raw.clear();
+        header.write(&mut raw).unwrap();
+        let limits = Limits { extra: 99, ..Limits::default() };
+        let e = Header::read_with_limits(&mut &raw[..], &limits).unwrap_err();
+        assert_eq!(FieldTooLong::find(&e).unwrap().field, Field::Extra);
+    }
+
+    #[test]
+    fn members() {
+        use std::io::{Read, Write};
+        use super::{Decoder, Encoder, MultiDecoder};
+
+        let mut file = Vec::new();
+        for (i, part) in [&b"first part, "[..], b"", b"and the last one"].iter().enumerate() {
+            let mut header = Header::new();
+            header.set_name(&format!("part{}", i)).unwrap();
+            let mut e = Encoder::with_header(file, header, CompressionLevel::Default);
+            e.write_all(part).unwrap();
+            let (w, result) = e.finish();
+            result.unwrap();
+            file = w;
+        }
+
+        let mut d = Decoder::new(&file[..]);
+        let members = d.members().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(members.len(), 3);
+        assert_eq!(members[1].header.name_lossy().unwrap(), "part1");
+        assert_eq!(&members[2].data[..], b"and the last one");
+
+        // skipping the unread part of a member
+        let mut d = Decoder::new(&file[..]);
+        d.member().unwrap().unwrap();
+        let mut start = [0; 5];
+        d.read_exact(&mut start).unwrap();
+        assert_eq!(d.member().unwrap().unwrap().name_lossy().unwrap(), "part1");
+
+        let mut joined = Vec::new();
+        MultiDecoder::new(&file[..]).read_to_end(&mut joined).unwrap();
+        assert_eq!(&joined[..], b"first part, and the last one");
+
+        let mut corrupt = file.clone();
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        assert!(MultiDecoder::new(&corrupt[..]).read_to_end(&mut Vec::new()).is_err());
+        let mut members = Decoder::new(&corrupt[..]).members().collect::<Vec<_>>();
+        assert!(members.pop().unwrap().is_err());
+        assert!(MultiDecoder::new(&b""[..]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn text_fields() {
+        let mut header = Header::new();
+        assert!(header.name_latin1().is_none());
+        header.set_name("café.txt").unwrap();
+        assert_eq!(header.name, Some(b"caf\xe9.txt".to_vec()));
+        assert_eq!(header.name_latin1().unwrap(), "café.txt");
+        assert_eq!(header.name_lossy().unwrap(), "caf\u{fffd}.txt");
+
+        // UTF-8 as written by most tools
+        header.comment = Some("naïve".as_bytes().to_vec());
+        assert_eq!(header.comment_lossy().unwrap(), "naïve");
+        assert_eq!(header.comment_latin1().unwrap(), "na\u{c3}\u{af}ve");
+
+        assert!(header.set_comment("\u{20ac}").is_err());
+        assert!(header.set_comment("a\0b").is_err());
+        assert_eq!(header.comment_lossy().unwrap(), "naïve");
+        header.set_comment("plain").unwrap();
+        assert!(matches!(header.comment_latin1(), Some(Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn mtime_and_subfields() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let mut header = sample();
+        assert_eq!(header.modified(), Some(UNIX_EPOCH + Duration::from_secs(1401624000)));
+        header.set_modified(Some(UNIX_EPOCH + Duration::from_millis(1_500_000_000_999))).unwrap();
+        assert_eq!(header.mtime, 1_500_000_000);
+        assert!(header.set_modified(Some(UNIX_EPOCH - Duration::from_secs(1))).is_err());
+        assert!(header.set_modified(Some(UNIX_EPOCH + Duration::from_secs(1 << 32))).is_err());
+        assert_eq!(header.mtime, 1_500_000_000);
+        header.set_modified(None).unwrap();
+        assert_eq!(header.modified(), None);
+
+        header.add_subfield(*b"RA", &[1, 2, 3]).unwrap();
+        assert!(header.add_subfield(*b"R\0", b"").is_err());
+        assert!(header.add_subfield(*b"BB", &[0; 0xffff]).is_err());
+        let mut raw = Vec::new();
+        header.write(&mut raw).unwrap();
+        let header = Header::read(&mut &raw[..]).unwrap();
+        assert_eq!(header.subfields().unwrap(),
+                   vec![(*b"AB", &b"hi"[..]), (*b"RA", &[1, 2, 3][..])]);
+        assert_eq!(header.subfield(*b"RA"), Some(&[1, 2, 3][..]));
+        assert_eq!(header.subfield(*b"XY"), None);
+
+        let mut broken = Header::new();
+        assert!(broken.subfields().unwrap().is_empty());
+        broken.extra = Some(b"AB\x05\x00hi".to_vec());
+        assert!(broken.subfields().is_err());
+        assert_eq!(broken.subfield(*b"AB"), None);
+    }
+
+    #[test]
+    fn encoder_member() {
+        use std::io::Write;
+        use super::super::byteorder::{LittleEndian, ReadBytesExt};
+        use super::Encoder;
+        use Crc32;
+
+        let text = include_bytes!("data/test.txt");
+        let mut header = sample();
+        header.os = 3;
+        let mut e = Encoder::with_header(Vec::new(), header.clone(), CompressionLevel::Best);
+        e.write_all(text).unwrap();
+        let (member, result) = e.finish();
+        result.unwrap();
+
+        let mut rest = &member[..];
+        header.xfl = 2;
+        assert_eq!(Header::read(&mut rest).unwrap(), header);
+        let mut trailer = &rest[rest.len() - 8..];
+        let mut crc = Crc32::new();
+        crc.feed(text);
+        assert_eq!(trailer.read_u32::<LittleEndian>().unwrap(), crc.result());
+        assert_eq!(trailer.read_u32::<LittleEndian>().unwrap(), text.len() as u32);
+    }
+
+    #[test]
+    fn encoder_member_size() {
+        use std::io::{Read, Write};
+        use super::{Decoder, Encoder, MultiDecoder};
+
+        let text = &include_bytes!("data/test.txt")[..];
+        let mut e = Encoder::new(Vec::new());
+        e.set_member_size(Some(1000));
+        for chunk in text.chunks(333) {
+            e.write_all(chunk).unwrap();
+        }
+        let (gz, result) = e.finish();
+        result.unwrap();
+
+        let mut d = Decoder::new(&gz[..]);
+        let sizes = d.members().map(|m| m.unwrap().data.len()).collect::<Vec<_>>();
+        assert_eq!(sizes.len(), text.len().div_ceil(1000));
+        assert!(sizes[..sizes.len() - 1].iter().all(|&n| n == 1000));
+        let mut decoded = Vec::new();
+        MultiDecoder::new(&gz[..]).read_to_end(&mut decoded).unwrap();
+        assert!(&decoded[..] == text);
+
+        // no empty member when the data ends on a boundary
+        let mut e = Encoder::new(Vec::new());
+        e.set_member_size(Some(100));
+        e.write_all(&text[..300]).unwrap();
+        let (gz, result) = e.finish();
+        result.unwrap();
+        assert_eq!(Decoder::new(&gz[..]).members().count(), 3);
+
+        // shrinking the size ends the current member, zero is ignored
+        let mut e = Encoder::new(Vec::new());
+        e.set_member_size(Some(1000));
+        e.write_all(&text[..500]).unwrap();
+        e.set_member_size(Some(0));
+        e.set_member_size(Some(100));
+        e.write_all(&text[500..700]).unwrap();
+        let (gz, result) = e.finish();
+        result.unwrap();
+        let sizes = Decoder::new(&gz[..]).members().map(|m| m.unwrap().data.len()).collect::<Vec<_>>();
+        assert_eq!(sizes, [500, 100, 100]);
+    }
+
+    #[cfg(feature="zlib")]
+    #[test]
+    fn encoder_roundtrip() {
+        use std::io::{Read, Write};
+        use super::Encoder;
+        use zlib;
+
+        for input in [&b""[..], b"x", include_bytes!("data/test.txt")].iter() {
+            let mut e = Encoder::new(Vec::new());
+            e.write_all(input).unwrap();
+            let (member, result) = e.finish();
+            result.unwrap();
+            let mut d = zlib::Decoder::with_wrapper(&member[..], zlib::Wrapper::Gzip);
+            let mut decoded = Vec::new();
+            d.read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], &input[..]);
+        }
+    }
+
+    #[test]
+    fn encoder_stats() {
+        use std::io::Write;
+        use super::Encoder;
+
+        let input = include_bytes!("data/test.txt");
+        let mut plain = Encoder::new(Vec::new());
+        plain.write_all(input).unwrap();
+        let (expected, stats, result) = plain.finish_with_stats();
+        result.unwrap();
+        assert_eq!(stats, Default::default());
+
+        let mut timed = Encoder::new(Vec::new());
+        timed.enable_stats();
+        timed.write_all(input).unwrap();
+        let (member, stats, result) = timed.finish_with_stats();
+        result.unwrap();
+        assert_eq!(member, expected);
+        assert!(stats.match_finding > Default::default());
+        assert!(stats.entropy_coding > Default::default());
+        assert!(stats.checksumming > Default::default());
+        assert_eq!(stats.total(), stats.match_finding + stats.entropy_coding + stats.checksumming);
+    }
+
+    #[test]
+    fn level_flags() {
+        let mut header = Header::new();
+        header.set_level(CompressionLevel::Best);
+        assert_eq!(header.xfl, 2);
+        header.set_level(CompressionLevel::Level(1));
+        assert_eq!(header.xfl, 4);
+        header.set_level(CompressionLevel::Default);
+        assert_eq!(header.xfl, 0);
+    }
+
+    #[test]
+    fn read_file_header() {
+        let mut input = &include_bytes!("data/test.gz")[..];
+        let header = Header::read(&mut input).unwrap();
+        assert_eq!(header.name, Some(b"test.txt".to_vec()));
+        assert_eq!(header.mtime, 1401624000);
+        assert_eq!(header.comment, None);
+    }
+
+    #[test]
+    fn redact_mask_keeps_length() {
+        let input = include_bytes!("data/test.gz");
+        let mut output = Vec::new();
+        super::redact(&input[..], &mut output, Redaction::Mask).unwrap();
+        assert_eq!(output.len(), input.len());
+
+        let mut rest = &output[..];
+        let header = Header::read(&mut rest).unwrap();
+        assert_eq!(header.mtime, 0);
+        assert_eq!(header.name, Some(b"________".to_vec()));
+        assert_eq!(rest, &input[input.len() - rest.len()..]);
+    }
+
+    #[test]
+    fn redact_strip() {
+        use std::io::{Read, Write};
+        use super::{Encoder, MultiDecoder};
+
+        let mut e = Encoder::with_header(Vec::new(), sample(), CompressionLevel::Default);
+        e.write_all(b"payload").unwrap();
+        let (raw, result) = e.finish();
+        result.unwrap();
+
+        let mut output = Vec::new();
+        super::redact(&raw[..], &mut output, Redaction::Strip).unwrap();
+        let mut rest = &output[..];
+        let header = Header::read(&mut rest).unwrap();
+        assert_eq!(header.name, None);
+        assert_eq!(header.comment, None);
+        assert_eq!(header.extra, sample().extra);
+        let mut decoded = Vec::new();
+        MultiDecoder::new(&output[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], b"payload");
+    }
+
+    #[test]
+    fn redact_every_member() {
+        use std::io::Write;
+        use super::{Decoder, Encoder};
+
+        let mut raw = Vec::new();
+        for text in [&b"first"[..], b"second"].iter() {
+            let mut e = Encoder::with_header(raw, sample(), CompressionLevel::Default);
+            e.write_all(text).unwrap();
+            let (out, result) = e.finish();
+            result.unwrap();
+            raw = out;
+        }
+
+        let mut output = Vec::new();
+        super::redact(&raw[..], &mut output, Redaction::Mask).unwrap();
+        assert_eq!(output.len(), raw.len());
+        let members = Decoder::new(&output[..]).members().collect::<::std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(members.len(), 2);
+        for (member, text) in members.iter().zip([&b"first"[..], b"second"].iter()) {
+            assert_eq!(member.header.mtime, 0);
+            assert!(member.header.name.as_ref().unwrap().iter().all(|&b| b == b'_'));
+            assert!(member.header.comment.as_ref().unwrap().iter().all(|&b| b == b'_'));
+            assert_eq!(&member.data[..], &text[..]);
+        }
+    }
+
+    #[test]
+    fn one_shot() {
+        let input = include_bytes!("data/test.txt");
+        let compressed = super::compress_to_vec(input);
+        assert_eq!(&super::decompress_to_vec(&compressed).unwrap()[..], &input[..]);
+        let mut bad = compressed.clone();
+        let at = bad.len() - 5;
+        bad[at] ^= 1;
+        assert!(super::decompress_to_vec(&bad).is_err());
+    }
+
+    #[test]
+    fn concat() {
+        use std::io::Read;
+        use io::{SpliceFailed, Validation};
+
+        let parts = vec![super::compress_to_vec(b"first, "),
+                         super::compress_to_vec(b""),
+                         super::compress_to_vec(b"last")];
+        for &validation in &[Validation::Header, Validation::Full] {
+            let mut out = Vec::new();
+            let n = super::concat(parts.iter().map(|p| &p[..]), &mut out, validation).unwrap();
+            assert_eq!(n as usize, out.len());
+            assert_eq!(out, parts.concat());
+            let mut data = Vec::new();
+            super::MultiDecoder::new(&out[..]).read_to_end(&mut data).unwrap();
+            assert_eq!(data, b"first, last");
+        }
+
+        // a corrupt payload only shows when decompressing, an empty part always
+        let mut bad = parts.clone();
+        let at = bad[0].len() - 5;
+        bad[0][at] ^= 1;
+        let mut out = Vec::new();
+        assert!(super::concat(bad.iter().map(|p| &p[..]), &mut out, Validation::Header).is_ok());
+        assert!(super::concat(bad.iter().map(|p| &p[..]), &mut out, Validation::Full).is_err());
+        let empty: &[u8] = &[];
+        for &validation in &[Validation::Header, Validation::Full] {
+            assert!(super::concat(vec![&parts[0][..], empty], &mut out, validation).is_err());
+        }
+
+        // the output is good up to the failing part
+        let mut out = Vec::new();
+        let err = super::concat(vec![&parts[0][..], &bad[0][..], &parts[2][..]], &mut out,
+                                Validation::Full).unwrap_err();
+        let failed = SpliceFailed::find(&err).unwrap();
+        assert_eq!((failed.part, failed.valid), (1, parts[0].len() as u64));
+        assert!(out.len() > parts[0].len());
+        out.truncate(failed.valid as usize);
+        assert_eq!(super::decompress_to_vec(&out).unwrap(), b"first, ");
+    }
+}
@@ -0,0 +1,906 @@
+/*!
+
+I/O helpers to put around the encoders and decoders
+
+A `Tee` writes everything to two sinks, for instance a local file and a
+network replica of it. Each sink fails independently: once a write or flush
+to one of them fails, the error is kept and that sink is left alone from then
+on, so it never receives data with a hole in the middle. The `Policy` decides
+whether the tee carries on with the remaining sink or reports the failure to
+the encoder writing into it.
+
+A sink returning `WouldBlock` isn't out of service, it's only behind. The
+tee reports how much data both sinks took, and remembers how far ahead the
+other one is, so that neither gets the same bytes twice when the rest is
+written again.
+
+# Example
+
+```rust
+use std::io::Write;
+use compress::io::{Policy, Tee};
+use compress::lz4;
+
+let local = Vec::new();
+let replica = Vec::new();
+let mut e = lz4::Encoder::new(Tee::new(local, replica, Policy::RequireFirst));
+e.write_all(b"replicated twice").unwrap();
+let (tee, result) = e.finish();
+result.unwrap();
+assert!(tee.second_error().is_none());
+let (local, replica) = tee.into_inner();
+assert_eq!(local, replica);
+```
+
+Disk images and similar archives decompress to long stretches of zeros.
+Written through a `SparseWriter` into a file, those stretches are skipped
+over with a seek rather than written, so the file system can leave them
+unallocated and the file ends up sparse.
+
+`read_to_end` on a decoder materializes whatever the input expands to, and
+a few kilobytes of crafted input can expand to gigabytes. `ReadMax` reads
+like it but gives up past a limit, failing with a `LimitExceeded` error
+instead of growing the buffer further.
+
+```rust
+use compress::io::{LimitExceeded, ReadMax};
+use compress::rle;
+
+let bomb = rle::compress_to_vec(&[0; 1 << 20]);
+let err = rle::Decoder::new(&bomb[..]).read_max(64 << 10).unwrap_err();
+assert_eq!(LimitExceeded::find(&err).unwrap().limit, 64 << 10);
+```
+
+An `Echo` reader copies what it reads into a writer. Placed under a
+decoder, it passes compressed data through while the decoder checks it,
+which is how `gzip::concat` and `lz4::concat` splice parts together without
+recompressing them; `Validation` says how far the parts are checked, and a
+`SpliceFailed` error how much of the output is good when one fails.
+
+A codec is split in two levels. Its core is a state machine over slices,
+implementing `Core`: it is handed some input and room for output, and says
+how much of each it used. It does no I/O at all, so it can be driven from
+a test or a fuzzer directly, or pushed the data of a network buffer as it
+comes in. `CoreReader` and `CoreWriter` are the `Read` and `Write`
+adapters built on top. `rle` and `bwt` are split this way: their `Encoder`
+and `Decoder` wrap `EncoderCore` and `DecoderCore`. `flate::InflateState` is
+a core for DEFLATE decoding, but `flate::Decoder` is not built on it:
+`CoreReader` reads its input ahead, while the gzip and zlib decoders need
+the DEFLATE stream to stop right at its end, where their trailer starts.
+`lz4::concat` needs the same of LZ4 frames. The other codecs still do their
+own I/O.
+
+```rust
+use compress::io::{Core, Status};
+use compress::rle;
+
+let mut core = rle::EncoderCore::new();
+let mut out = [0; 16];
+let (used, _, _) = core.process(b"aaaaaaaa", &mut out, false).unwrap();
+assert_eq!(used, 8);
+// the run might still go on, so it is only written out at the end
+let (_, produced, status) = core.process(&[], &mut out, true).unwrap();
+assert_eq!((&out[..produced], status), (&[b'a', b'a', 6 | 0x80][..], Status::StreamEnd));
+```
+
+*/
+
+use std::cmp;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// What a `Tee` does when one of its sinks fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Fail as soon as any sink fails
+    RequireBoth,
+    /// Fail if the first sink fails, the second is best effort
+    RequireFirst,
+    /// Carry on as long as one of the sinks works
+    RequireAny,
+}
+
+// A sink, the error which took it out of service and how many bytes of the
+// data to come it already took
+struct Sink<W> {
+    w: W,
+    error: Option<io::Error>,
+    ahead: usize,
+}
+
+impl<W: Write> Sink<W> {
+    fn new(w: W) -> Sink<W> {
+        Sink { w, error: None, ahead: 0 }
+    }
+
+    // Writes as much of `buf` as the sink takes, returning how much of it
+    // the sink has. A sink out of service holds nothing back.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.error.is_some() {
+            return Ok(buf.len())
+        }
+        let mut done = cmp::min(self.ahead, buf.len());
+        while done < buf.len() {
+            match self.w.write(&buf[done..]) {
+                Ok(0) => {
+                    self.error = Some(io::Error::new(io::ErrorKind::WriteZero,
+                                                     "failed to write whole buffer"));
+                    return Ok(buf.len())
+                }
+                Ok(n) => done += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && done > 0 => break,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Err(e)
+                    }
+                    self.error = Some(e);
+                    return Ok(buf.len())
+                }
+            }
+        }
+        Ok(done)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.error.is_none() {
+            if let Err(e) = self.w.flush() {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Err(e)
+                }
+                self.error = Some(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn failure(which: &str, e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{} sink of tee failed: {}", which, e))
+}
+
+/// A writer duplicating its output into two sinks, see the module
+/// documentation
+pub struct Tee<W1, W2> {
+    first: Sink<W1>,
+    second: Sink<W2>,
+    policy: Policy,
+}
+
+impl<W1: Write, W2: Write> Tee<W1, W2> {
+    /// Creates a tee writing to `first` and `second`, handling failures of
+    /// either according to `policy`
+    pub fn new(first: W1, second: W2, policy: Policy) -> Tee<W1, W2> {
+        Tee {
+            first: Sink::new(first),
+            second: Sink::new(second),
+            policy,
+        }
+    }
+
+    /// Returns the error which stopped the first sink, if any
+    pub fn first_error(&self) -> Option<&io::Error> {
+        self.first.error.as_ref()
+    }
+
+    /// Returns the error which stopped the second sink, if any
+    pub fn second_error(&self) -> Option<&io::Error> {
+        self.second.error.as_ref()
+    }
+
+    /// Returns true if both sinks still receive the data
+    pub fn is_healthy(&self) -> bool {
+        self.first.error.is_none() && self.second.error.is_none()
+    }
+
+    /// Returns references to the sinks
+    pub fn get_ref(&self) -> (&W1, &W2) {
+        (&self.first.w, &self.second.w)
+    }
+
+    /// Returns mutable references to the sinks. Writing to them directly
+    /// makes their contents differ.
+    pub fn get_mut(&mut self) -> (&mut W1, &mut W2) {
+        (&mut self.first.w, &mut self.second.w)
+    }
+
+    /// Returns the sinks, whatever their state
+    pub fn into_inner(self) -> (W1, W2) {
+        (self.first.w, self.second.w)
+    }
+
+    // reports the failure the policy doesn't tolerate, if any
+    fn check(&self) -> io::Result<()> {
+        match (self.policy, &self.first.error, &self.second.error) {
+            (_, Some(e), None) if self.policy != Policy::RequireAny => Err(failure("first", e)),
+            (Policy::RequireBoth, None, Some(e)) => Err(failure("second", e)),
+            (_, Some(_), Some(e)) => Err(failure("second", e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<W1: Write, W2: Write> Write for Tee<W1, W2> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.check());
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        let first = self.first.write(buf);
+        let second = self.second.write(buf);
+        try!(self.check());
+        // only what both sinks have is reported, the one ahead skips the
+        // rest of it when it's written again
+        let (a, b) = (*first.as_ref().unwrap_or(&0), *second.as_ref().unwrap_or(&0));
+        let n = cmp::min(a, b);
+        self.first.ahead = cmp::max(self.first.ahead, a) - n;
+        self.second.ahead = cmp::max(self.second.ahead, b) - n;
+        if n == 0 {
+            return first.and(second)
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.check());
+        let first = self.first.flush();
+        let second = self.second.flush();
+        try!(self.check());
+        first.and(second)
+    }
+}
+
+/// Size of the blocks `SparseWriter` checks for zeros, the usual file
+/// system block size
+pub const SPARSE_BLOCK: usize = 4096;
+
+/// A writer seeking over blocks of zeros instead of writing them, see the
+/// module documentation. Data is looked at in blocks of `SPARSE_BLOCK`
+/// bytes, aligned to the start of the output. A block is written out once
+/// it is full and more data or a `flush` comes, so that a failing `write`
+/// takes none of its input. A partial last block, and the last byte of a
+/// run of zeros at the very end which gives the output its full length, are
+/// written by `finish`.
+pub struct SparseWriter<W> {
+    w: W,
+    block: Vec<u8>,
+    skipped: u64,
+    // whether the output ends with zeros seeked over
+    in_hole: bool,
+}
+
+impl<W: Write + Seek> SparseWriter<W> {
+    /// Creates a writer writing to `w` from its current position
+    pub fn new(w: W) -> SparseWriter<W> {
+        SparseWriter { w, block: Vec::with_capacity(SPARSE_BLOCK), skipped: 0, in_hole: false }
+    }
+
+    /// Returns how many bytes of zeros were seeked over rather than written
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Returns a reference to the wrapped writer
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Writes out what is pending and returns the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.end();
+        (self.w, result)
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        if !self.block.is_empty() {
+            try!(self.emit_block());
+        }
+        // seeking alone doesn't make the output any longer, the last zero
+        // is written for real
+        if self.in_hole {
+            try!(self.w.seek(SeekFrom::Current(-1)));
+            try!(self.w.write_all(&[0]));
+            self.skipped -= 1;
+            self.in_hole = false;
+        }
+        self.w.flush()
+    }
+
+    fn emit_block(&mut self) -> io::Result<()> {
+        if self.block.iter().all(|&b| b == 0) {
+            try!(self.w.seek(SeekFrom::Current(self.block.len() as i64)));
+            self.skipped += self.block.len() as u64;
+            self.in_hole = true;
+        } else {
+            try!(self.w.write_all(&self.block));
+            self.in_hole = false;
+        }
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for SparseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a full block goes out before anything more is taken, so that an
+        // error leaves `buf` untouched
+        if self.block.len() == SPARSE_BLOCK {
+            try!(self.emit_block());
+        }
+        let n = cmp::min(SPARSE_BLOCK - self.block.len(), buf.len());
+        self.block.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.block.len() == SPARSE_BLOCK {
+            try!(self.emit_block());
+        }
+        self.w.flush()
+    }
+}
+
+/// More data than allowed by `ReadMax::read_max`, found inside the
+/// `io::Error` it returns
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LimitExceeded {
+    /// The limit which was exceeded
+    pub limit: usize,
+}
+
+impl LimitExceeded {
+    /// Returns the limit error carried by `e`, if any
+    pub fn find(e: &io::Error) -> Option<&LimitExceeded> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<LimitExceeded>())
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "more than {} bytes of data", self.limit)
+    }
+}
+
+impl Error for LimitExceeded {}
+
+/// Reading everything up to a limit, implemented for every reader, see the
+/// module documentation
+pub trait ReadMax: Read {
+    /// Reads until the end of the stream, like `read_to_end`, as long as
+    /// there are no more than `limit` bytes. If more remain, fails with an
+    /// `InvalidData` error carrying `LimitExceeded` once `limit` and one
+    /// more byte were read, and the data read is dropped.
+    fn read_max(&mut self, limit: usize) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        // one byte past the limit tells whether more remain
+        try!(Read::take(&mut *self, (limit as u64).saturating_add(1)).read_to_end(&mut data));
+        if data.len() > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, LimitExceeded { limit }))
+        }
+        Ok(data)
+    }
+}
+
+impl<R: Read + ?Sized> ReadMax for R {}
+
+/// How thoroughly compressed parts are checked before they are spliced
+/// together, see `gzip::concat` and `lz4::concat`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Validation {
+    /// Only the header at the start of each part is checked, the rest is
+    /// copied blindly, so a truncated or corrupt part goes unnoticed
+    Header,
+    /// Each part is decompressed in full and its checksums verified, the
+    /// decompressed data being thrown away
+    Full,
+}
+
+/// A part which couldn't be spliced by `gzip::concat` or `lz4::concat`,
+/// found inside the `io::Error` they return, which has the kind of the
+/// original error
+#[derive(Debug)]
+pub struct SpliceFailed {
+    /// The index of the failing part
+    pub part: usize,
+    /// The number of bytes of the parts before it, all written out in full.
+    /// Anything the writer holds past them comes from the failing part, and
+    /// truncating the output there leaves a valid file.
+    pub valid: u64,
+    error: io::Error,
+}
+
+impl SpliceFailed {
+    /// Returns the splice error carried by `e`, if any
+    pub fn find(e: &io::Error) -> Option<&SpliceFailed> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<SpliceFailed>())
+    }
+
+    /// The error the part failed with
+    pub fn error(&self) -> &io::Error {
+        &self.error
+    }
+}
+
+impl fmt::Display for SpliceFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "part {} can't be spliced after {} bytes: {}", self.part, self.valid, self.error)
+    }
+}
+
+impl Error for SpliceFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Copies each part to `w` through an `Echo` handed to `check`, which reads
+// it to the end, checking it on the way. Returns the number of bytes
+// written, or the first failure wrapped in a `SpliceFailed`.
+#[cfg(any(feature="gzip", feature="lz4"))]
+pub(crate) fn splice<I, W, F>(parts: I, mut w: W, mut check: F) -> io::Result<u64>
+    where I: IntoIterator, I::Item: Read, W: Write,
+          F: FnMut(&mut Echo<I::Item, &mut W>) -> io::Result<()>
+{
+    let mut valid = 0;
+    for (part, r) in parts.into_iter().enumerate() {
+        let mut r = Echo::new(r, &mut w);
+        if let Err(error) = check(&mut r) {
+            let kind = error.kind();
+            return Err(io::Error::new(kind, SpliceFailed { part, valid, error }))
+        }
+        valid += r.count();
+    }
+    try!(w.flush());
+    Ok(valid)
+}
+
+/// A reader writing everything it reads into a writer as well, and counting
+/// it
+pub struct Echo<R, W> {
+    r: R,
+    w: W,
+    count: u64,
+}
+
+impl<R: Read, W: Write> Echo<R, W> {
+    /// Creates a reader reading from `r` and echoing into `w`
+    pub fn new(r: R, w: W) -> Echo<R, W> {
+        Echo { r, w, count: 0 }
+    }
+
+    /// Returns how many bytes were read and echoed so far
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Destroys this reader, returning the reader and the writer
+    pub fn into_inner(self) -> (R, W) {
+        (self.r, self.w)
+    }
+}
+
+impl<R: Read, W: Write> Read for Echo<R, W> {
+    /// Reads from the wrapped reader, failing if the data can't be written
+    /// into the writer in full
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.r.read(buf));
+        try!(self.w.write_all(&buf[..n]));
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// How far a `Core` got
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// More input or more room for output is needed to go on
+    Ok,
+    /// The stream is complete and all of its output was produced
+    StreamEnd,
+}
+
+/// The I/O free core of an encoder or decoder, see the module documentation
+pub trait Core {
+    /// Consumes data from `input` and produces data into `output`, returning
+    /// how many bytes of each were used. Unless `output` fills up or the
+    /// stream ends, all of `input` is consumed, whatever can't be acted on
+    /// yet is kept in the core. `finish` says that no input follows, it is
+    /// set until `Status::StreamEnd` is returned, which may take several
+    /// calls if the output is short.
+    fn process(&mut self, input: &[u8], output: &mut [u8], finish: bool)
+               -> io::Result<(usize, usize, Status)>;
+
+    /// Lets what the core holds back so far come out of the next calls to
+    /// `process`, as far as the format allows it without ending the stream.
+    /// Does nothing by default.
+    fn flush(&mut self) {}
+}
+
+// big enough to take most reads and writes in one call to the core
+const CORE_BUFFER: usize = 32 * 1024;
+
+/// A reader running the data of another reader through a `Core`
+pub struct CoreReader<R, C> {
+    r: R,
+    core: C,
+    buf: Box<[u8]>,
+    pos: usize,
+    end: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: Read, C: Core> CoreReader<R, C> {
+    /// Creates a reader processing what is read from `r` with `core`
+    pub fn new(r: R, core: C) -> CoreReader<R, C> {
+        CoreReader {
+            r,
+            core,
+            buf: vec![0; CORE_BUFFER].into_boxed_slice(),
+            pos: 0,
+            end: 0,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Returns a reference to the core
+    pub fn core(&self) -> &C {
+        &self.core
+    }
+
+    /// Returns a mutable reference to the core
+    pub fn core_mut(&mut self) -> &mut C {
+        &mut self.core
+    }
+
+    /// Returns a reference to the wrapped reader. Input is read ahead, so
+    /// it may be further along than the core.
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+
+    /// Returns the wrapped reader and the input read ahead of the core
+    pub fn into_inner(self) -> (R, Vec<u8>) {
+        let rest = self.buf[self.pos..self.end].to_vec();
+        (self.r, rest)
+    }
+}
+
+impl<R: Read, C: Core> Read for CoreReader<R, C> {
+    /// Reads through the core. An empty `buf` still has the core look at
+    /// input once, so that it can pick up headers.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done {
+            if self.pos == self.end && !self.eof {
+                self.end = try!(self.r.read(&mut self.buf));
+                self.pos = 0;
+                self.eof = self.end == 0;
+            }
+            let (used, produced, status) =
+                try!(self.core.process(&self.buf[self.pos..self.end], buf, self.eof));
+            self.pos += used;
+            self.done = status == Status::StreamEnd;
+            if produced > 0 || buf.is_empty() {
+                return Ok(produced)
+            }
+            if self.eof && !self.done && used == 0 {
+                return Err(io::Error::other("codec core is stuck"))
+            }
+        }
+        Ok(0)
+    }
+}
+
+/// A writer running the data written to it through a `Core` into another
+/// writer. The stream is only complete after `finish`.
+pub struct CoreWriter<W, C> {
+    w: W,
+    core: C,
+    buf: Box<[u8]>,
+}
+
+impl<W: Write, C: Core> CoreWriter<W, C> {
+    /// Creates a writer processing what is written to it with `core` and
+    /// writing the result to `w`
+    pub fn new(w: W, core: C) -> CoreWriter<W, C> {
+        CoreWriter { w, core, buf: vec![0; CORE_BUFFER].into_boxed_slice() }
+    }
+
+    /// Returns a reference to the core
+    pub fn core(&self) -> &C {
+        &self.core
+    }
+
+    /// Returns a mutable reference to the core
+    pub fn core_mut(&mut self) -> &mut C {
+        &mut self.core
+    }
+
+    /// Returns a reference to the wrapped writer
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    /// Ends the stream, writing out everything left in the core, and
+    /// returns the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.finish_stream().and_then(|_| self.w.flush());
+        (self.w, result)
+    }
+
+    fn finish_stream(&mut self) -> io::Result<()> {
+        loop {
+            let (_, produced, status) = try!(self.core.process(&[], &mut self.buf, true));
+            try!(self.w.write_all(&self.buf[..produced]));
+            if status == Status::StreamEnd {
+                return Ok(())
+            }
+        }
+    }
+}
+
+impl<W: Write, C: Core> Write for CoreWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut input = buf;
+        loop {
+            let (used, produced, _) = try!(self.core.process(input, &mut self.buf, false));
+            try!(self.w.write_all(&self.buf[..produced]));
+            input = &input[used..];
+            if input.is_empty() && produced < self.buf.len() {
+                return Ok(buf.len())
+            }
+        }
+    }
+
+    /// Writes out what the core holds back, see `Core::flush`, and flushes
+    /// the wrapped writer
+    fn flush(&mut self) -> io::Result<()> {
+        self.core.flush();
+        try!(self.write(&[]));
+        self.w.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+    use super::{LimitExceeded, Policy, ReadMax, SparseWriter, Tee, SPARSE_BLOCK};
+
+    // Accepts `left` bytes, then fails
+    struct Failing {
+        data: Vec<u8>,
+        left: usize,
+    }
+
+    impl Write for Failing {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.left == 0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection lost"))
+            }
+            let n = buf.len().min(self.left);
+            self.data.extend_from_slice(&buf[..n]);
+            self.left -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn failing(left: usize) -> Failing {
+        Failing { data: Vec::new(), left }
+    }
+
+    #[test]
+    fn policies() {
+        let mut tee = Tee::new(Vec::new(), failing(5), Policy::RequireFirst);
+        tee.write_all(b"1234").unwrap();
+        tee.write_all(b"5678").unwrap();
+        tee.write_all(b"9").unwrap();
+        assert!(!tee.is_healthy());
+        assert_eq!(tee.second_error().unwrap().kind(), io::ErrorKind::BrokenPipe);
+        let (first, second) = tee.into_inner();
+        assert_eq!(&first[..], b"123456789");
+        // the broken sink got nothing after the failed write
+        assert_eq!(&second.data[..], b"12345");
+
+        let mut tee = Tee::new(Vec::new(), failing(5), Policy::RequireBoth);
+        tee.write_all(b"1234").unwrap();
+        let err = tee.write_all(b"5678").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(tee.write_all(b"9").is_err());
+        assert!(tee.flush().is_err());
+
+        let mut tee = Tee::new(failing(0), failing(3), Policy::RequireAny);
+        tee.write_all(b"12").unwrap();
+        assert!(tee.first_error().is_some());
+        assert!(tee.write_all(b"34").is_err());
+
+        let mut tee = Tee::new(failing(0), Vec::new(), Policy::RequireFirst);
+        assert!(tee.write_all(b"12").is_err());
+    }
+
+    // Takes up to 3 bytes per write, and would block every other time
+    struct Slow {
+        data: Vec<u8>,
+        block: bool,
+    }
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.block = !self.block;
+            if self.block {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "try again"))
+            }
+            let n = buf.len().min(3);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retries() {
+        let input = b"written again and again";
+        for &policy in [Policy::RequireBoth, Policy::RequireFirst, Policy::RequireAny].iter() {
+            let mut tee = Tee::new(Vec::new(), Slow { data: Vec::new(), block: false }, policy);
+            let mut pos = 0;
+            while pos < input.len() {
+                match tee.write(&input[pos..]) {
+                    Ok(n) => pos += n,
+                    Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+                }
+            }
+            assert!(tee.is_healthy());
+            let (first, second) = tee.into_inner();
+            assert_eq!(&first[..], &input[..]);
+            assert_eq!(&second.data[..], &input[..]);
+        }
+    }
+
+    // Counts the bytes actually written
+    struct Counting {
+        inner: io::Cursor<Vec<u8>>,
+        written: usize,
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written += buf.len();
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Seek for Counting {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn sparse() {
+        let mut image = vec![0; 10 * SPARSE_BLOCK + 100];
+        image[5] = 1;
+        image[4 * SPARSE_BLOCK + 7] = 2;
+        for &tail in [0, 1].iter() {
+            let end = image.len() - 1;
+            image[end] = tail;
+            let mut w = SparseWriter::new(Counting { inner: io::Cursor::new(Vec::new()), written: 0 });
+            // odd sized writes and flushes don't get in the way
+            for chunk in image.chunks(1000) {
+                w.write_all(chunk).unwrap();
+                w.flush().unwrap();
+            }
+            // only whole blocks are out, up to the last one with data
+            assert_eq!(w.get_ref().inner.get_ref().len(), 5 * SPARSE_BLOCK);
+            assert_eq!(w.skipped(), 8 * SPARSE_BLOCK as u64);
+            let (out, result) = w.finish();
+            result.unwrap();
+            assert_eq!(out.inner.get_ref(), &image);
+            assert!(out.written <= 2 * SPARSE_BLOCK + 100);
+        }
+    }
+
+    // Fails whatever it is asked to do while `broken` is set
+    struct Unreliable {
+        inner: io::Cursor<Vec<u8>>,
+        broken: Rc<Cell<bool>>,
+    }
+
+    impl Unreliable {
+        fn check(&self) -> io::Result<()> {
+            if self.broken.get() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection lost"))
+            }
+            Ok(())
+        }
+    }
+
+    impl Write for Unreliable {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            try!(self.check());
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.check()
+        }
+    }
+
+    impl io::Seek for Unreliable {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            try!(self.check());
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn sparse_errors() {
+        let mut image = vec![0; 10 * SPARSE_BLOCK + 100];
+        image[5] = 1;
+        image[4 * SPARSE_BLOCK + 7] = 2;
+        image[8 * SPARSE_BLOCK..9 * SPARSE_BLOCK].iter_mut().for_each(|b| *b = 3);
+        let broken = Rc::new(Cell::new(false));
+        let mut w = SparseWriter::new(Unreliable { inner: io::Cursor::new(Vec::new()), broken: broken.clone() });
+        // a failed write takes nothing, so what it didn't take is all there
+        // is to write again. Writes in quarter blocks leave a full block
+        // to emit for every fourth one.
+        let mut failed = 0;
+        for chunk in image.chunks(SPARSE_BLOCK / 4) {
+            broken.set(true);
+            let taken = match w.write(chunk) {
+                Ok(n) => n,
+                Err(_) => { failed += 1; 0 }
+            };
+            broken.set(false);
+            w.write_all(&chunk[taken..]).unwrap();
+        }
+        assert!(failed > 0);
+        let (out, result) = w.finish();
+        result.unwrap();
+        assert_eq!(out.inner.get_ref(), &image);
+    }
+
+    #[test]
+    fn read_max() {
+        assert_eq!((&b"abc"[..]).read_max(3).unwrap(), b"abc");
+        assert_eq!((&b""[..]).read_max(0).unwrap(), b"");
+        let err = (&b"abc"[..]).read_max(2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(LimitExceeded::find(&err), Some(&LimitExceeded { limit: 2 }));
+
+        // an endless stream stops at the limit
+        let mut endless: Box<dyn io::Read> = Box::new(io::repeat(7));
+        assert!(endless.read_max(100_000).is_err());
+        assert!(LimitExceeded::find(&io::Error::other("other")).is_none());
+    }
+
+    #[test]
+    #[cfg(feature="lz4")]
+    fn around_encoder() {
+        use std::io::Read;
+        use lz4;
+        let input = include_bytes!("data/test.txt");
+        let mut e = lz4::Encoder::new(Tee::new(Vec::new(), failing(100), Policy::RequireFirst));
+        e.write_all(input).unwrap();
+        let (tee, result) = e.finish();
+        result.unwrap();
+        assert!(tee.second_error().is_some());
+        let (local, _) = tee.into_inner();
+        let mut decoded = Vec::new();
+        lz4::Decoder::new(&local[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], &input[..]);
+    }
+}
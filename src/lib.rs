@@ -2,59 +2,170 @@
 #![allow(missing_copy_implementations)]
 #![allow(deprecated)]
 
-//! dox (placeholder)
+/*!
+
+Various compression algorithms written in Rust
+
+Each algorithm lives in a module of its own, behind a cargo feature of the
+same name, with `Encoder` and `Decoder` adapters around `Write` and `Read`
+streams. On top of them the crate root has the runtime selectable `Codec`
+layer: `codec_by_name`, `detect` and `DecoderAny`, along with the error
+types attached to the `io::Error`s streams fail with, which can be picked
+out with their `find` functions.
+
+Everything public is covered by semver, except for items hidden from these
+docs with `#[doc(hidden)]`: they are shared between modules of the crate
+and its command line tool, and may change in any release. The `public_api`
+test keeps a list of the public items, so that changes to them stand out in
+review.
+
+*/
 
 extern crate byteorder;
 extern crate rand;
-
-#[macro_use]
+#[cfg(feature="serde")]
+extern crate bincode;
+#[cfg(feature="serde")]
+extern crate serde;
+#[cfg(feature="bytes")]
+extern crate bytes;
+
+#[cfg(feature="logging")]
 extern crate log;
 
 #[cfg(test)]
 #[cfg(feature="unstable")]
 extern crate test;
 
-use std::io::{self, Read};
+use std::io::Read;
 
-/// Public exports
+// The runtime selectable codecs and the errors streams carry, which most
+// code needs without digging into the modules
 #[cfg(feature="checksum")]
 pub use self::checksum::adler::State32 as Adler32;
+#[cfg(feature="checksum")]
+pub use self::checksum::crc::State32 as Crc32;
+#[cfg(feature="checksum")]
+pub use self::checksum::crc::State32C as Crc32c;
+pub use self::codecs::{capabilities, codec_by_name, compare, detect, require_codec, Capability,
+                       Codec, Comparison, DecoderAny, Finish, Identity, UnsupportedFormat};
+pub use self::format::Format;
+pub use self::io::LimitExceeded;
+pub use self::pipeline::{Pipeline, StageError};
+#[cfg(any(feature="flate", feature="lz4"))]
+pub use self::stats::Stats;
+
+#[macro_use]
+mod logging;
+
+#[cfg(test)]
+mod api;
+mod codecs;
+mod format;
+#[cfg(any(feature="flate", feature="lz4"))]
+mod stats;
 
 #[cfg(feature="checksum")]
 /// Checksum algorithms. Requires `checksum` feature, enabled by default
 // http://en.wikipedia.org/wiki/Checksum
 pub mod checksum {
     pub mod adler;
+    pub mod crc;
+    pub mod tee;
+    pub mod xxhash;
+    pub use self::tee::{Checksum, Tee, TeeReader};
 }
 
+pub mod alloc;
+pub mod analyze;
+pub mod batch;
+
+#[cfg(feature="brotli")]
+pub mod brotli;
+
 #[cfg(feature="bwt")]
 pub mod bwt;
 
+#[cfg(feature="bzip2")]
+pub mod bzip2;
+
+#[cfg(feature="checkpoint")]
+pub mod checkpoint;
+
+#[cfg(feature="system-zlib")]
+pub mod crosscheck;
+
+#[cfg(feature="dedup")]
+pub mod dedup;
+
+pub mod dict;
+
+pub mod filters;
+
+#[cfg(feature="embed")]
+#[macro_use]
+pub mod embed;
+
 #[cfg(feature="flate")]
 pub mod flate;
 
+#[cfg(feature="gzip")]
+pub mod gzip;
+
+pub mod io;
+
 #[cfg(feature="lz4")]
 pub mod lz4;
 
+#[cfg(feature="lzma")]
+pub mod lzma;
+
+#[cfg(feature="lzw")]
+pub mod lzw;
+
+pub mod metrics;
+
+pub mod pipeline;
+
+#[cfg(feature="serde")]
+pub mod records;
+
+#[cfg(feature="verify")]
+pub mod verify;
+
 #[cfg(feature="zlib")]
 pub mod zlib;
 
+#[cfg(feature="zstd")]
+pub mod zstd;
+
 /// Entropy coder family. Requires `entropy` feature, enabled by default
 // http://en.wikipedia.org/wiki/Entropy_encoding
 #[cfg(feature="entropy")]
 pub mod entropy {
+    pub mod ans;
     pub mod ari;
+    mod cache;
+    pub mod huff;
+    pub mod ppm;
+    pub mod range;
 }
 
 #[cfg(feature="rle")]
 pub mod rle;
 
+#[cfg(feature="scramble")]
+pub mod scramble;
+
+#[cfg(feature="bytes")]
+pub mod shared;
+
 #[cfg(any(feature = "lz4", feature = "entropy", feature = "bwt"))]
-fn byteorder_err_to_io(err: io::Error) -> io::Error {
+fn byteorder_err_to_io(err: std::io::Error) -> std::io::Error {
     match err {
-        e if e.kind() == io::ErrorKind::UnexpectedEof =>
-            io::Error::new(
-                io::ErrorKind::Other,
+        e if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
                 "unexpected end of file"
             ),
         e => e,
@@ -63,7 +174,8 @@ fn byteorder_err_to_io(err: io::Error) -> io::Error {
 
 #[cfg(test)]
 mod test {
-    use super::{io,byteorder_err_to_io};
+    use std::io;
+    use super::byteorder_err_to_io;
     #[cfg(feature="unstable")]
     use test;
     
@@ -106,14 +218,15 @@ mod test {
 
 /// Adds a convenience method for types with the read trait, very similar
 /// to push_at_least in the late Reader trait
+#[doc(hidden)]
 pub trait ReadExact: Read + Sized {
     /// Appends exact number of bytes to a buffer
-    fn push_exactly(&mut self, bytes: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+    fn push_exactly(&mut self, bytes: u64, buf: &mut Vec<u8>) -> std::io::Result<()> {
         let n = try!(self.by_ref().take(bytes).read_to_end(buf)) as u64;
 
         if n < bytes {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
                 "unexpected end of file"
             ));
         }
@@ -0,0 +1,42 @@
+//! Internal logging macros
+//!
+//! Every codec logs under its own target, `compress::<module>`, so levels can
+//! be set per codec by the logger, e.g. `RUST_LOG=compress::lz4=debug` with
+//! `env_logger`. The macros only forward to the `log` crate when the
+//! `logging` feature is enabled; otherwise they expand to dead code which
+//! still type checks the arguments but is compiled out entirely, so the hot
+//! loops of the codecs pay nothing for it.
+
+#[cfg(feature="logging")]
+#[allow(unused_macros)]
+macro_rules! log_event {
+    ($level:ident, $target:expr, $($arg:tt)+) => (
+        ::log::log!(target: $target, ::log::Level::$level, $($arg)+)
+    )
+}
+
+#[cfg(not(feature="logging"))]
+#[allow(unused_macros)]
+macro_rules! log_event {
+    ($level:ident, $target:expr, $($arg:tt)+) => (
+        if false {
+            let _ = $target;
+            let _ = format_args!($($arg)+);
+        }
+    )
+}
+
+#[allow(unused_macros)]
+macro_rules! trace {
+    (target: $target:expr, $($arg:tt)+) => (log_event!(Trace, $target, $($arg)+))
+}
+
+#[allow(unused_macros)]
+macro_rules! debug {
+    (target: $target:expr, $($arg:tt)+) => (log_event!(Debug, $target, $($arg)+))
+}
+
+#[allow(unused_macros)]
+macro_rules! info {
+    (target: $target:expr, $($arg:tt)+) => (log_event!(Info, $target, $($arg)+))
+}
@@ -6,6 +6,13 @@ This module contains an implementation in Rust of decompression and compression
 of LZ4-encoded streams. These are exposed as a standard `Reader` and `Writer`
 interfaces wrapping an underlying stream.
 
+Frames can be compressed against a preset dictionary, whose id is recorded in
+the frame descriptor; see the `dict` module for swapping dictionaries at
+runtime. Only the last 64KB of a dictionary are used.
+
+Empty input compresses to a frame without blocks, which decodes to nothing,
+while a stream without any frame is reported as `UnexpectedEof`.
+
 # Example
 
 ```rust,ignore
@@ -27,17 +34,32 @@ can be found at https://github.com/bkaradzic/go-lz4.
 */
 
 use std::cmp;
-use std::ptr::copy_nonoverlapping;
 use std::io::{self, Read, Write};
-use std::iter::repeat;
 use std::vec::Vec;
 use std::num::Wrapping;
 use std::ops::Shr;
+use std::thread;
 
 use super::byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
 use super::{ReadExact, byteorder_err_to_io};
+use checksum::xxhash;
+use codecs::{self, Codec, Finish};
+use io::{LimitExceeded, Validation};
+use metrics;
+use dict::{DictHandle, Dictionary};
+use stats::{Stage, Stats, Timer};
 
 const MAGIC: u32 = 0x184d2204;
+// legacy format of lz4demo and the lz4 -l option, still used for kernel images
+const LEGACY_MAGIC: u32 = 0x184c2102;
+const LEGACY_BLOCK_SIZE: usize = 8 << 20;
+// the block sizes of the frame descriptor, indexed by its bits 6-4
+static MAX_SIZES: [usize; 8] =
+    [0, 0, 0, 0, // all N/A
+     64 << 10,   // 64KB
+     256 << 10,  // 256 KB
+     1 << 20,    // 1MB
+     4 << 20];   // 4MB
 
 const ML_BITS: u32 = 4;
 const ML_MASK: u32 = (1 << ML_BITS as usize) - 1;
@@ -51,114 +73,93 @@ const HASH_SHIFT: u32 = (MIN_MATCH * 8) - HASH_LOG;
 const INCOMPRESSIBLE: u32 = 128;
 const UNINITHASH: u32 = 0x88888888;
 const MAX_INPUT_SIZE: u32 = 0x7e000000;
+const STATE_VERSION: u8 = 1;
+const DEFAULT_STORE_THRESHOLD: usize = 98;
+const BLOCK_SIZE: usize = 256 * 1024;
+// how far back matches of linked blocks reach into earlier blocks
+const HISTORY: usize = 64 * 1024;
+
+fn invalid_state() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "invalid lz4 codec state")
+}
 
 struct BlockDecoder<'a> {
     input: &'a [u8],
     output: &'a mut Vec<u8>,
     cur: usize,
-
-    start: usize,
-    end: usize,
+    // the most output the block may bring the buffer up to
+    limit: usize,
 }
 
 impl<'a> BlockDecoder<'a> {
-    /// Decodes this block of data from 'input' to 'output', returning the
-    /// number of valid bytes in the output.
-    fn decode(&mut self) -> usize {
+    /// Decodes this block of data from 'input', appending to 'output', and
+    /// returns the number of valid bytes in the output. Matches may refer
+    /// back into what the output held before, nothing else in the block is
+    /// trusted.
+    fn decode(&mut self) -> io::Result<usize> {
         while self.cur < self.input.len() {
-            let code = self.bump();
-            debug!("block with code: {:x}", code);
+            let code = self.input[self.cur];
+            self.cur += 1;
+            trace!(target: "compress::lz4", "block with code: {:x}", code);
+
             // Extract a chunk of data from the input to the output.
-            {
-                let len = self.length(code >> 4);
-                debug!("consume len {}", len);
-                if len > 0 {
-                    let end = self.end;
-                    self.grow_output(end + len);
-                    unsafe { copy_nonoverlapping(
-                        &self.input[self.cur],
-                        &mut self.output[end],
-                        len
-                    )};
-                    self.end += len;
-                    self.cur += len;
-                }
+            let len = try!(block_length(self.input, &mut self.cur, code >> 4));
+            trace!(target: "compress::lz4", "consume len {}", len);
+            if len > self.input.len() - self.cur {
+                return Err(corrupt_block())
             }
+            try!(self.room(len));
+            self.output.extend_from_slice(&self.input[self.cur..self.cur + len]);
+            self.cur += len;
             if self.cur == self.input.len() { break }
 
             // Read off the next i16 offset
-            {
-                let back = (self.bump() as usize) | ((self.bump() as usize) << 8);
-                debug!("found back {}", back);
-                self.start = self.end - back;
+            if self.input.len() - self.cur < 2 {
+                return Err(corrupt_block())
+            }
+            let back = (self.input[self.cur] as usize) | ((self.input[self.cur + 1] as usize) << 8);
+            self.cur += 2;
+            trace!(target: "compress::lz4", "found back {}", back);
+            let end = self.output.len();
+            if back == 0 || back > end {
+                return Err(corrupt_block())
             }
 
             // Slosh around some bytes now
-            {
-                let mut len = self.length(code & 0xf);
-                let literal = self.end - self.start;
-                if literal < 4 {
-                    static DECR: [usize; 4] = [0, 3, 2, 3];
-                    self.cp(4, DECR[literal]);
-                } else {
-                    len += 4;
+            let len = try!(block_length(self.input, &mut self.cur, code & 0xf)) + 4;
+            try!(self.room(len));
+            if back >= len {
+                self.output.extend_from_within(end - back..end - back + len);
+            } else {
+                // the match overlaps the bytes it produces
+                for i in end - back..end - back + len {
+                    let b = self.output[i];
+                    self.output.push(b);
                 }
-                self.cp(len, 0);
-            }
-        }
-        self.end
-    }
-
-    fn length(&mut self, code: u8) -> usize {
-        let mut ret = code as usize;
-        if code == 0xf {
-            loop {
-                let tmp = self.bump();
-                ret += tmp as usize;
-                if tmp != 0xff { break }
             }
         }
-        ret
-    }
-
-    fn bump(&mut self) -> u8 {
-        let ret = self.input[self.cur];
-        self.cur += 1;
-        ret
+        Ok(self.output.len())
     }
 
-    #[inline]
-    fn cp(&mut self, len: usize, decr: usize) {
-        let end = self.end;
-        self.grow_output(end + len);
-        for i in 0..len {
-            self.output[end + i] = (*self.output)[self.start + i];
+    fn room(&self, len: usize) -> io::Result<()> {
+        if len > self.limit - self.output.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      "lz4 block decodes to more than its size limit"))
         }
-
-        self.end += len;
-        self.start += len - decr;
+        Ok(())
     }
+}
 
-    // Extends the output vector to a target number of bytes (in total), but
-    // does not actually initialize the new data. The length of the vector is
-    // updated, but the bytes will all have undefined values. It is assumed that
-    // the next operation is to pave over these bytes (so the initialization is
-    // unnecessary).
-    #[inline]
-    fn grow_output(&mut self, target: usize) {
-        if self.output.capacity() < target {
-            debug!("growing {} to {}", self.output.capacity(), target);
-            //let additional = target - self.output.capacity();
-            //self.output.reserve(additional);
-            while self.output.len() < target {
-                self.output.push(0);
-            }
-        }else {
-            unsafe {
-               self.output.set_len(target);
-            }
-        }
-    }
+/// The match finder used by the encoders
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// Single-entry hash table, skipping ahead faster over incompressible data
+    #[default]
+    Fast,
+    /// Hash chains searched deeper as the level goes from 1 to 12, like
+    /// lz4hc; much slower, with a better ratio. Out of range levels are
+    /// clamped.
+    HighCompression(u8),
 }
 
 struct BlockEncoder<'a> {
@@ -181,6 +182,28 @@ pub fn compression_bound(size: u32) -> Option<u32> {
 }
 
 impl<'a> BlockEncoder<'a> {
+    // An encoder for `input[dict..]`, matches may refer back into the
+    // `dict` bytes before it
+    fn new(input: &'a [u8], output: &'a mut Vec<u8>, dict: usize) -> BlockEncoder<'a> {
+        BlockEncoder {
+            input,
+            output,
+            hash_table: vec![0; HASH_TABLE_SIZE as usize],
+            pos: dict as u32,
+            anchor: dict as u32,
+            dest_pos: 0
+        }
+    }
+
+    // Indexes the dictionary for the fast match finder, the chains of the
+    // high compression one pick it up on their own
+    fn index_dict(&mut self) {
+        let dict = cmp::min(self.pos as usize, self.input.len().saturating_sub(3));
+        for pos in 0..dict as u32 {
+            let hash = self.hash(pos);
+            self.hash_table[hash] = (Wrapping(pos) - Wrapping(UNINITHASH)).0;
+        }
+    }
     #[inline(always)]
     fn seq_at(&self, pos: u32) -> u32 {
         (self.input[pos as usize + 3] as u32) << 24
@@ -223,18 +246,119 @@ impl<'a> BlockEncoder<'a> {
         self.dest_pos += len;
     }
 
-    fn encode(&mut self) -> u32 {
-        let input_len = self.input.len() as u32;
+    // Writes `len` literals from `pos` followed by a match `back` bytes back,
+    // `ml_len` being the match length minus MIN_MATCH
+    fn write_sequence(&mut self, len: u32, pos: u32, back: u32, mut ml_len: u32) {
+        self.write_literals(len, ml_len, pos);
+        self.output[self.dest_pos as usize] = back as u8;
+        self.output[self.dest_pos as usize + 1] = (back >> 8) as u8;
+        self.dest_pos += 2;
+
+        if ml_len > ML_MASK - 1 {
+            ml_len -= ML_MASK;
+            while ml_len > 254 {
+                ml_len -= 255;
+
+                self.output[self.dest_pos as usize] = 255;
+                self.dest_pos += 1;
+            }
+
+            self.output[self.dest_pos as usize] = ml_len as u8;
+            self.dest_pos += 1;
+        }
+    }
+
+    fn hash(&self, pos: u32) -> usize {
+        (Wrapping(self.seq_at(pos)) * Wrapping(2654435761)).shr(HASH_SHIFT as usize).0 as usize
+    }
 
-        match compression_bound(input_len) {
-            None => 0,
+    // Makes room for the worst case output, returning false if the input is
+    // too large for a block
+    fn reserve_output(&mut self) -> bool {
+        match compression_bound(self.input.len() as u32) {
+            None => false,
             Some(out_size) => {
                 let out_size_usize = out_size as usize;
+                // reserve counts from the length, not the capacity
                 if self.output.capacity() < out_size_usize {
-                    let additional = out_size_usize - self.output.capacity();
+                    let additional = out_size_usize - self.output.len();
                     self.output.reserve(additional);
                 }
                 unsafe {self.output.set_len(out_size_usize); }
+                true
+            }
+        }
+    }
+
+    // Longest match for `pos` among the earlier positions with the same hash,
+    // following at most `attempts` links of the chain. Returns the length and
+    // the position of the match, or a length of 0.
+    fn longest_match(&self, pos: u32, chain: &[u32], attempts: u32) -> (u32, u32) {
+        let limit = self.input.len() - 5;
+        let here = &self.input[pos as usize..limit];
+        let mut best = (0, 0);
+        let mut cand = chain[pos as usize];
+        for _ in 0..attempts {
+            if cand == 0 || pos - (cand - 1) > 0xffff {
+                break
+            }
+            let r = cand - 1;
+            let len = self.input[r as usize..].iter().zip(here.iter())
+                          .take_while(|&(a, b)| a == b).count() as u32;
+            if len > best.0 {
+                best = (len, r);
+            }
+            cand = chain[r as usize];
+        }
+        if best.0 < MIN_MATCH { (0, 0) } else { best }
+    }
+
+    // The chained-hash match finder of the high compression mode. Positions
+    // are linked to the previous one with the same hash, and `attempts`
+    // candidates are compared for each position. With `lazy` a match is
+    // dropped for a literal if the next position has a longer one.
+    fn encode_hc(&mut self, attempts: u32, lazy: bool) -> u32 {
+        if !self.reserve_output() {
+            return 0
+        }
+        let input_len = self.input.len() as u32;
+        let mut chain = vec![0u32; self.input.len()];
+        let mut inserted = 0;
+        // the last match has to start 12 bytes before the end
+        while self.pos + 12 <= input_len {
+            while inserted <= self.pos + 1 && inserted + 12 <= input_len {
+                let hash = self.hash(inserted);
+                chain[inserted as usize] = self.hash_table[hash];
+                self.hash_table[hash] = inserted + 1;
+                inserted += 1;
+            }
+            let (len, r) = self.longest_match(self.pos, &chain, attempts);
+            if len == 0 {
+                self.pos += 1;
+                continue
+            }
+            if lazy && self.pos + 13 <= input_len &&
+               self.longest_match(self.pos + 1, &chain, attempts).0 > len {
+                self.pos += 1;
+                continue
+            }
+            let (ln, anchor) = (self.pos - self.anchor, self.anchor);
+            self.write_sequence(ln, anchor, self.pos - r, len - MIN_MATCH);
+            self.pos += len;
+            self.anchor = self.pos;
+        }
+        let anchor = self.anchor;
+        self.write_literals(input_len - anchor, 0, anchor);
+        unsafe { self.output.set_len(self.dest_pos as usize) };
+        self.dest_pos
+    }
+
+    fn encode(&mut self) -> u32 {
+        let input_len = self.input.len() as u32;
+
+        match self.reserve_output() {
+            false => 0,
+            true => {
 
                 let mut step = 1u32;
                 let mut limit = INCOMPRESSIBLE;
@@ -283,26 +407,8 @@ impl<'a> BlockEncoder<'a> {
                         r += 1
                     }
 
-                    let mut ml_len = self.pos - self.anchor;
-
-                    self.write_literals(ln, ml_len, anchor);
-                    self.output[self.dest_pos as usize] = back as u8;
-                    self.output[self.dest_pos as usize + 1] = (back >> 8) as u8;
-                    self.dest_pos += 2;
-
-                    if ml_len > ML_MASK - 1 {
-                        ml_len -= ML_MASK;
-                        while ml_len > 254 {
-                            ml_len -= 255;
-
-                            self.output[self.dest_pos as usize] = 255;
-                            self.dest_pos += 1;
-                        }
-
-                        self.output[self.dest_pos as usize] = ml_len as u8;
-                        self.dest_pos += 1;
-                    }
-
+                    let ml_len = self.pos - self.anchor;
+                    self.write_sequence(ln, anchor, back, ml_len);
                     self.anchor = self.pos;
                 }
             }
@@ -327,9 +433,19 @@ pub struct Decoder<R> {
     eof: bool,
 
     header: bool,
+    legacy: bool,
+    linked: bool,
     blk_checksum: bool,
     stream_checksum: bool,
+    // the checksum of the frame's content so far, unless it isn't checked
+    content: Option<xxhash::State32>,
     max_block_size: usize,
+
+    dicts: Option<DictHandle>,
+    // the id and the tail of the dictionary of the current frame, if it
+    // has one
+    dict_id: Option<u32>,
+    preset: Vec<u8>,
 }
 
 impl<R: Read + Sized> Decoder<R> {
@@ -342,12 +458,28 @@ impl<R: Read + Sized> Decoder<R> {
             temp: Vec::new(),
             output: Vec::new(),
             header: false,
+            legacy: false,
+            linked: false,
             blk_checksum: false,
             stream_checksum: false,
+            content: None,
             start: 0,
             end: 0,
             eof: false,
             max_block_size: 0,
+            dicts: None,
+            dict_id: None,
+            preset: Vec::new(),
+        }
+    }
+
+    /// Creates a decoder which looks up the dictionaries of frames
+    /// compressed with one in `dicts`, by the id the frames record. Frames
+    /// without a dictionary decode as usual.
+    pub fn with_dictionaries(r: R, dicts: DictHandle) -> Decoder<R> {
+        Decoder {
+            dicts: Some(dicts),
+            .. Decoder::new(r)
         }
     }
 
@@ -361,21 +493,78 @@ impl<R: Read + Sized> Decoder<R> {
     }
 
     fn read_header(&mut self) -> io::Result<()> {
-        // Make sure the magic number is what's expected.
-        if try!(self.r.read_u32::<LittleEndian>()) != MAGIC {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, ""))
+        let magic = try!(self.r.read_u32::<LittleEndian>());
+        self.start_frame(magic)
+    }
+
+    // Make sure the magic number is what's expected, both the current frame
+    // format and the legacy one are accepted.
+    fn start_frame(&mut self, magic: u32) -> io::Result<()> {
+        match magic {
+            MAGIC => {
+                self.legacy = false;
+                self.read_descriptor()
+            }
+            LEGACY_MAGIC => {
+                self.legacy = true;
+                self.dict_id = None;
+                self.preset.clear();
+                self.linked = false;
+                self.blk_checksum = false;
+                self.stream_checksum = false;
+                self.content = None;
+                self.max_block_size = LEGACY_BLOCK_SIZE;
+                Ok(())
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "")),
         }
+    }
+
+    // Reads a little endian u32, or nothing if the stream ends cleanly
+    // before it.
+    fn read_u32_or_eof(&mut self) -> io::Result<Option<u32>> {
+        let mut word = [0u8; 4];
+        let mut n = 0;
+        while n < word.len() {
+            match try!(self.r.read(&mut word[n..])) {
+                0 if n == 0 => return Ok(None),
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                               "unexpected end of file")),
+                k => n += k,
+            }
+        }
+        Ok(Some(try!((&word[..]).read_u32::<LittleEndian>())))
+    }
+
+    /// Prepares this decoder for another frame concatenated right after the
+    /// one which has just reached its end. Returns `false` if the wrapped
+    /// stream has no more data.
+    pub fn next_frame(&mut self) -> io::Result<bool> {
+        let magic = match try!(self.read_u32_or_eof()) {
+            Some(magic) => magic,
+            None => return Ok(false),
+        };
+        self.reset();
+        try!(self.start_frame(magic));
+        self.header = true;
+        Ok(true)
+    }
 
-        let mut bits = [0; 3];
-        try!(self.r.read(&mut bits[..2]));
-        let flg = bits[0];
-        let bd = bits[1];
+    fn read_descriptor(&mut self) -> io::Result<()> {
+        // everything from the flags to the header checksum is checksummed
+        let mut descriptor = [0; 14];
+        try!(self.r.read_exact(&mut descriptor[..2]));
+        let mut len = 2;
+        let flg = descriptor[0];
+        let bd = descriptor[1];
 
         // bits 7/6, the version number. Right now this must be 1
         if (flg >> 6) != 0b01 {
             return Err(io::Error::new(io::ErrorKind::InvalidInput, ""))
         }
-        // bit 5 is the "block independence", don't care about this yet
+        // bit 5 is the "block independence", without it matches may reach
+        // back into the previous 64KB of output
+        self.linked = (flg & 0x20) == 0;
         // bit 4 is whether blocks have checksums or not
         self.blk_checksum = (flg & 0x10) != 0;
         // bit 3 is whether there is a following stream size
@@ -386,13 +575,6 @@ impl<R: Read + Sized> Decoder<R> {
         // bit 0 is whether there is a preset dictionary
         let preset_dictionary = (flg & 0x01) != 0;
 
-        static MAX_SIZES: [usize; 8] =
-            [0, 0, 0, 0, // all N/A
-             64 << 10,   // 64KB
-             256 << 10,  // 256 KB
-             1 << 20,    // 1MB
-             4 << 20];   // 4MB
-
         // bit 7 is reserved
         // bits 6-4 are the maximum block size
         let max_block_size = MAX_SIZES[(bd >> 4) as usize & 0x7];
@@ -400,71 +582,318 @@ impl<R: Read + Sized> Decoder<R> {
 
         // read off other portions of the stream
         let size = if stream_size {
-            Some(try!(self.r.read_u64::<LittleEndian>()))
+            try!(self.r.read_exact(&mut descriptor[len..len + 8]));
+            len += 8;
+            Some(try!((&descriptor[len - 8..len]).read_u64::<LittleEndian>()))
+        } else {
+            None
+        };
+        let id = if preset_dictionary {
+            try!(self.r.read_exact(&mut descriptor[len..len + 4]));
+            len += 4;
+            Some(try!((&descriptor[len - 4..len]).read_u32::<LittleEndian>()))
         } else {
             None
         };
-        assert!(!preset_dictionary, "preset dictionaries not supported yet");
+        if try!(self.r.read_u8()) != header_checksum(&descriptor[..len]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "invalid lz4 header checksum"))
+        }
 
-        debug!("blk: {}", self.blk_checksum);
-        debug!("stream: {}", self.stream_checksum);
-        debug!("max size: {}", max_block_size);
-        debug!("stream size: {:?}", size);
+        self.preset = match id {
+            Some(id) => {
+                debug!(target: "compress::lz4", "dictionary: {}", id);
+                try!(self.preset(id, "frame"))
+            }
+            None => Vec::new(),
+        };
+        self.dict_id = id;
+        // the dictionary is the history of the first block
+        self.output.clear();
+        self.output.extend_from_slice(&self.preset);
+        self.start = self.preset.len();
+        self.end = self.preset.len();
+
+        debug!(target: "compress::lz4", "blk: {}", self.blk_checksum);
+        debug!(target: "compress::lz4", "stream: {}", self.stream_checksum);
+        debug!(target: "compress::lz4", "max size: {}", max_block_size);
+        debug!(target: "compress::lz4", "stream size: {:?}", size);
 
         self.max_block_size = max_block_size;
+        self.content = if self.stream_checksum { Some(xxhash::State32::new()) } else { None };
+        Ok(())
+    }
 
-        // XXX: implement checksums
-        let cksum = try!(self.r.read_u8());
-        debug!("ignoring header checksum: {}", cksum);
-        return Ok(());
+    // The tail of dictionary `id`, which the frame or the state named by
+    // `what` needs
+    fn preset(&self, id: u32, what: &str) -> io::Result<Vec<u8>> {
+        match self.dicts.as_ref().and_then(|dicts| dicts.get(id)) {
+            Some(dict) => {
+                let data = dict.data();
+                Ok(data[data.len() - cmp::min(data.len(), HISTORY)..].to_vec())
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                       format!("lz4 {} needs unknown dictionary {}", what, id))),
+        }
     }
 
-    fn decode_block(&mut self) -> io::Result<bool> {
-        match try!(self.r.read_u32::<LittleEndian>()) {
+    // Decodes the next block into the output buffer, returning `None` at the
+    // end of the frame. Raw blocks no longer than `discard` bytes are instead
+    // dropped without being buffered, their size is returned.
+    fn decode_block(&mut self, discard: u64) -> io::Result<Option<u64>> {
+        if self.legacy {
+            return self.decode_legacy_block()
+        }
+        let mut discarded = 0;
+        // blocks are checksummed as stored, compressed or not
+        let stored = match try!(self.r.read_u32::<LittleEndian>()) {
             // final block, we're done here
-            0 => return Ok(false),
+            0 => {
+                if self.stream_checksum {
+                    let cksum = try!(self.r.read_u32::<LittleEndian>());
+                    if self.content.as_ref().is_some_and(|c| c.result() != cksum) {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                  "lz4 content checksum mismatch"))
+                    }
+                }
+                return Ok(None)
+            }
+
+            // raw block to skip over, unless later blocks need it as history
+            // or checksums need to see it
+            n if n & 0x80000000 != 0 && (n & 0x7fffffff) as u64 <= discard && !self.linked &&
+                 !self.blk_checksum && !self.stream_checksum => {
+                let amt = (n & 0x7fffffff) as u64;
+                let mut raw = (&mut self.r).take(amt);
+                if try!(io::copy(&mut raw, &mut io::sink())) != amt {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                              "unexpected end of file"))
+                }
+                self.start = 0;
+                self.end = 0;
+                discarded = amt;
+                None
+            }
 
             // raw block to read
             n if n & 0x80000000 != 0 => {
                 let amt = (n & 0x7fffffff) as usize;
-                self.output.truncate(0);
+                let keep = self.keep_history();
                 self.output.reserve(amt);
                 try!(self.r.push_exactly(amt as u64, &mut self.output));
-                self.start = 0;
-                self.end = amt;
+                self.start = keep;
+                self.end = keep + amt;
+                Some(&self.output[keep..keep + amt])
             }
 
             // actual block to decompress
             n => {
-                let n = n as usize;
-                self.temp.truncate(0);
-                self.temp.reserve(n);
-                try!(self.r.push_exactly(n as u64, &mut self.temp));
-
-                let target = cmp::min(self.max_block_size, 4 * n / 3);
-                self.output.truncate(0);
-                self.output.reserve(target);
-                let mut decoder = BlockDecoder {
-                    input: &self.temp[..n],
-                    output: &mut self.output,
-                    cur: 0,
-                    start: 0,
-                    end: 0,
-                };
-                self.start = 0;
-                self.end = decoder.decode();
+                try!(self.decompress_block(n as usize));
+                Some(&self.temp[..n as usize])
             }
-        }
+        };
 
         if self.blk_checksum {
+            let expected = stored.map(|block| xxhash::xxh32(block, 0));
             let cksum = try!(self.r.read_u32::<LittleEndian>());
-            debug!("ignoring block checksum {}", cksum);
+            if expected.is_some_and(|e| e != cksum) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "lz4 block checksum mismatch"))
+            }
+        }
+        if let Some(ref mut content) = self.content {
+            content.feed(&self.output[self.start..self.end]);
+        }
+        Ok(Some(discarded))
+    }
+
+    // Legacy streams have no end mark and no stored blocks, every block is
+    // compressed. They end with the input, or carry on as another legacy
+    // stream when the magic number shows up again.
+    fn decode_legacy_block(&mut self) -> io::Result<Option<u64>> {
+        loop {
+            match try!(self.read_u32_or_eof()) {
+                None => return Ok(None),
+                Some(LEGACY_MAGIC) => continue,
+                Some(n) if n as u64 > compression_bound(LEGACY_BLOCK_SIZE as u32).unwrap() as u64 => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "invalid legacy lz4 block size"))
+                }
+                Some(n) => {
+                    try!(self.decompress_block(n as usize));
+                    return Ok(Some(0))
+                }
+            }
+        }
+    }
+
+    // Drops the decoded output except, for linked blocks, the last 64KB
+    // which the next block may refer to. Independent blocks all start from
+    // the dictionary, if any. Returns how much was kept.
+    fn keep_history(&mut self) -> usize {
+        if !self.linked && !self.preset.is_empty() {
+            self.output.clear();
+            self.output.extend_from_slice(&self.preset);
+            return self.preset.len()
+        }
+        let keep = if self.linked { cmp::min(self.end, HISTORY) } else { 0 };
+        self.output.truncate(self.end);
+        self.output.drain(..self.end - keep);
+        keep
+    }
+
+    fn decompress_block(&mut self, n: usize) -> io::Result<()> {
+        self.temp.truncate(0);
+        self.temp.reserve(n);
+        try!(self.r.push_exactly(n as u64, &mut self.temp));
+
+        let keep = self.keep_history();
+        let target = cmp::min(self.max_block_size, 4 * n / 3);
+        self.output.truncate(keep);
+        self.output.reserve(target);
+        let mut decoder = BlockDecoder {
+            input: &self.temp[..n],
+            output: &mut self.output,
+            cur: 0,
+            limit: keep + self.max_block_size,
+        };
+        // nothing of a corrupt block is left to be read
+        self.start = keep;
+        self.end = keep;
+        self.end = try!(decoder.decode());
+        Ok(())
+    }
+
+    /// Decodes and throws away the next `n` bytes of output, returning how
+    /// many were skipped, which is less than `n` only at the end of the
+    /// stream. Stored blocks which are skipped entirely are never buffered.
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        if self.eof { return Ok(0) }
+        if !self.header {
+            try!(self.read_header());
+            self.header = true;
+        }
+        let mut left = n;
+        while left > 0 {
+            if self.start == self.end {
+                match try!(self.decode_block(left)) {
+                    Some(k) => left -= k,
+                    None => {
+                        self.eof = true;
+                        break;
+                    }
+                }
+            }
+            let k = cmp::min(left, (self.end - self.start) as u64);
+            self.start += k as usize;
+            left -= k;
         }
-        return Ok(true);
+        Ok(n - left)
     }
 
     /// Tests whether the end of this LZ4 stream has been reached
     pub fn eof(&mut self) -> bool { self.eof }
+
+    /// Returns the last `n` bytes of output already read from this decoder,
+    /// or fewer if it no longer holds that many. Linked blocks keep the last
+    /// 64KB of output around for the next block to refer to, but independent
+    /// blocks start afresh, so then only what was read of the current block
+    /// is held. The dictionary of a frame counts as output read before it.
+    /// Useful to see what a protocol referring back to earlier messages on
+    /// its own can count on, like `flate::Decoder::window`.
+    pub fn window(&self, n: usize) -> &[u8] {
+        let read = &self.output[..self.start];
+        &read[read.len().saturating_sub(n)..]
+    }
+
+    /// Snapshots the frame parameters and the decoded but not yet consumed
+    /// output into a byte vector which can later be handed to `import_state`.
+    /// For linked blocks the history the next block refers to is included.
+    ///
+    /// The position of the wrapped reader is not part of the snapshot, the
+    /// caller is responsible for resuming the input at the same offset.
+    /// Neither is the dictionary of the frame, only its id: the decoder
+    /// importing the snapshot looks it up in its own dictionaries.
+    pub fn export_state(&self) -> Vec<u8> {
+        let pending = &self.output[self.start..self.end];
+        let mut state = Vec::with_capacity(16 + pending.len());
+        state.push(STATE_VERSION);
+        state.push((self.header as u8) | (self.eof as u8) << 1 |
+                   (self.blk_checksum as u8) << 2 |
+                   (self.stream_checksum as u8) << 3 |
+                   (self.legacy as u8) << 4 |
+                   (self.linked as u8) << 5 |
+                   (self.dict_id.is_some() as u8) << 6);
+        state.write_u32::<LittleEndian>(self.max_block_size as u32).unwrap();
+        state.write_u32::<LittleEndian>(pending.len() as u32).unwrap();
+        state.extend_from_slice(pending);
+        if self.linked {
+            let history = &self.output[self.start - cmp::min(self.start, HISTORY)..self.start];
+            state.write_u32::<LittleEndian>(history.len() as u32).unwrap();
+            state.extend_from_slice(history);
+        }
+        if let Some(id) = self.dict_id {
+            state.write_u32::<LittleEndian>(id).unwrap();
+        }
+        state
+    }
+
+    /// Restores a snapshot previously produced by `export_state`, replacing
+    /// the current state of this decoder. A snapshot taken in a frame with
+    /// a dictionary needs a decoder created by `with_dictionaries` which
+    /// still has it.
+    pub fn import_state(&mut self, mut state: &[u8]) -> io::Result<()> {
+        if try!(state.read_u8()) != STATE_VERSION {
+            return Err(invalid_state())
+        }
+        let flags = try!(state.read_u8());
+        let max_block_size = try!(state.read_u32::<LittleEndian>()) as usize;
+        let len = try!(state.read_u32::<LittleEndian>()) as u64;
+        // a block size no frame could have declared, or pending output
+        // larger than a block, means the state is corrupt
+        let valid = match max_block_size {
+            0 => flags & 0x1 == 0,
+            LEGACY_BLOCK_SIZE => flags & 0x10 != 0,
+            size => MAX_SIZES.contains(&size),
+        };
+        if !valid || len > max_block_size as u64 {
+            return Err(invalid_state())
+        }
+        let mut pending = Vec::new();
+        try!(state.push_exactly(len, &mut pending));
+        let linked = flags & 0x20 != 0;
+        let mut output = Vec::new();
+        if linked {
+            let history = try!(state.read_u32::<LittleEndian>()) as u64;
+            if history > HISTORY as u64 {
+                return Err(invalid_state())
+            }
+            try!(state.push_exactly(history, &mut output));
+        }
+        let (dict_id, preset) = if flags & 0x40 != 0 {
+            let id = try!(state.read_u32::<LittleEndian>());
+            (Some(id), try!(self.preset(id, "state")))
+        } else {
+            (None, Vec::new())
+        };
+
+        self.header = flags & 0x1 != 0;
+        self.eof = flags & 0x2 != 0;
+        self.blk_checksum = flags & 0x4 != 0;
+        self.stream_checksum = flags & 0x8 != 0;
+        // the content checksum can't be checked from the middle of a frame
+        self.content = None;
+        self.legacy = flags & 0x10 != 0;
+        self.linked = linked;
+        self.dict_id = dict_id;
+        self.preset = preset;
+        self.max_block_size = max_block_size;
+        self.start = output.len();
+        output.extend_from_slice(&pending);
+        self.end = output.len();
+        self.output = output;
+        Ok(())
+    }
 }
 
 impl<R: Read> Read for Decoder<R> {
@@ -479,18 +908,15 @@ impl<R: Read> Read for Decoder<R> {
 
         while amt > 0 {
             if self.start == self.end {
-                let keep_going = try!(self.decode_block());
-                if !keep_going {
+                if try!(self.decode_block(0)).is_none() {
                     self.eof = true;
                     break;
                 }
+                // blocks may well decode to nothing
+                continue
             }
             let n = cmp::min(amt, self.end - self.start);
-            unsafe { copy_nonoverlapping(
-                &self.output[self.start],
-                &mut dst[len - amt],
-                n
-            )};
+            dst[len - amt..len - amt + n].copy_from_slice(&self.output[self.start..self.start + n]);
             self.start += n;
             amt -= n;
         }
@@ -505,91 +931,222 @@ impl<R: Read> Read for Decoder<R> {
 pub struct Encoder<W> {
     w: W,
     buf: Vec<u8>,
+    dict: usize,
+    linked: bool,
+    dict_id: Option<u32>,
+    // the tail of the preset dictionary, the history of the first block
+    preset: Vec<u8>,
     tmp: Vec<u8>,
     wrote_header: bool,
     limit: usize,
+    store_threshold: usize,
+    mode: CompressionMode,
+    timer: Timer,
 }
 
 impl<W: Write> Encoder<W> {
     /// Creates a new encoder which will have its output written to the given
     /// output stream. The output stream can be re-acquired by calling
     /// `finish()`
-    ///
-    /// NOTE: compression isn't actually implemented just yet, this is just a
-    /// skeleton of a future implementation.
     pub fn new(w: W) -> Encoder<W> {
         Encoder {
-            w: w,
+            w,
             wrote_header: false,
             buf: Vec::with_capacity(1024),
+            dict: 0,
+            linked: false,
+            dict_id: None,
+            preset: Vec::new(),
             tmp: Vec::new(),
-            limit: 256 * 1024,
+            limit: BLOCK_SIZE,
+            store_threshold: DEFAULT_STORE_THRESHOLD,
+            mode: CompressionMode::Fast,
+            timer: Timer::default(),
         }
     }
 
+    /// Creates an encoder compressing against a preset dictionary, whose id
+    /// is recorded in the frame for the decoder to look it up
+    pub fn with_dictionary(w: W, dict: &Dictionary) -> Encoder<W> {
+        let data = dict.data();
+        let preset = data[data.len() - cmp::min(data.len(), HISTORY)..].to_vec();
+        let mut buf = Vec::with_capacity(preset.len() + 1024);
+        buf.extend_from_slice(&preset);
+        Encoder {
+            dict: preset.len(),
+            dict_id: Some(dict.id()),
+            preset,
+            buf,
+            .. Encoder::new(w)
+        }
+    }
+
+    /// Starts timing the match finding, see `Stats`. LZ4 has no entropy
+    /// coding stage, writing the sequences is part of the match finding.
+    pub fn enable_stats(&mut self) {
+        self.timer.enable();
+    }
+
+    /// Sets the ratio guardrail, in percent of the input size. Blocks which
+    /// don't compress below this size are stored raw instead, which bounds
+    /// the overhead on incompressible data to the 4-byte block header.
+    /// 100 only stores blocks that would expand, 0 stores everything.
+    pub fn set_store_threshold(&mut self, percent: usize) {
+        self.store_threshold = cmp::min(percent, 100);
+    }
+
+    /// Chooses between the fast match finder and the high compression one
+    pub fn set_mode(&mut self, mode: CompressionMode) {
+        self.mode = mode;
+    }
+
+    /// Turns on linked blocks, whose matches may reach back into the last
+    /// 64KB of the previous blocks. This improves the ratio a lot for small
+    /// blocks, such as those cut by frequent flushes, but a block can then
+    /// only be decoded after the ones before it. Has to be called before
+    /// anything is written.
+    pub fn set_block_linked(&mut self, linked: bool) {
+        assert!(!self.wrote_header, "block linking can't change within a frame");
+        self.linked = linked;
+    }
+
     fn encode_block(&mut self) -> io::Result<()> {
-        self.tmp.truncate(0);
-        if self.compress() {
-            try!(self.w.write_u32::<LittleEndian>(self.tmp.len() as u32));
-            try!(self.w.write(&self.tmp));
+        let start = self.timer.start();
+        let compressed = compress_block(&self.buf, self.dict, &mut self.tmp,
+                                        self.store_threshold, self.mode);
+        self.timer.stop(start, Stage::MatchFinding);
+        try!(write_block(&mut self.w, &self.buf[self.dict..], &self.tmp, compressed));
+        if self.linked {
+            let keep = cmp::min(self.buf.len(), HISTORY);
+            let drop = self.buf.len() - keep;
+            self.buf.drain(..drop);
+            self.dict = keep;
         } else {
-            try!(self.w.write_u32::<LittleEndian>((self.buf.len() as u32) | 0x80000000));
-            try!(self.w.write(&self.buf));
+            // independent blocks all start from the dictionary
+            self.buf.clear();
+            self.buf.extend_from_slice(&self.preset);
+            self.dict = self.preset.len();
         }
-        self.buf.truncate(0);
         Ok(())
     }
 
-    fn compress(&mut self) -> bool {
-        false
+    /// Snapshots the buffered, not yet encoded input into a byte vector which
+    /// can later be handed to `import_state` of an encoder writing into the
+    /// same output stream. The dictionary isn't part of the snapshot, only
+    /// its id: an encoder created with the same one has to import it.
+    pub fn export_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(18 + self.buf.len());
+        state.push(STATE_VERSION);
+        state.push(self.wrote_header as u8 | (self.linked as u8) << 1 |
+                   (self.dict_id.is_some() as u8) << 2);
+        state.write_u32::<LittleEndian>(self.limit as u32).unwrap();
+        state.write_u32::<LittleEndian>((self.buf.len() - self.dict) as u32).unwrap();
+        state.extend_from_slice(&self.buf[self.dict..]);
+        if self.linked {
+            state.write_u32::<LittleEndian>(self.dict as u32).unwrap();
+            state.extend_from_slice(&self.buf[..self.dict]);
+        }
+        if let Some(id) = self.dict_id {
+            state.write_u32::<LittleEndian>(id).unwrap();
+        }
+        state
+    }
+
+    /// Restores a snapshot previously produced by `export_state`, replacing
+    /// the current state of this encoder.
+    pub fn import_state(&mut self, mut state: &[u8]) -> io::Result<()> {
+        if try!(state.read_u8()) != STATE_VERSION {
+            return Err(invalid_state())
+        }
+        let flags = try!(state.read_u8());
+        let limit = try!(state.read_u32::<LittleEndian>()) as usize;
+        let len = try!(state.read_u32::<LittleEndian>()) as u64;
+        // the frame header declares blocks of BLOCK_SIZE
+        if limit == 0 || limit > BLOCK_SIZE || len > limit as u64 {
+            return Err(invalid_state())
+        }
+        let mut pending = Vec::new();
+        try!(state.push_exactly(len, &mut pending));
+        let linked = flags & 0x2 != 0;
+        let mut buf = Vec::with_capacity(limit);
+        if linked {
+            let dict = try!(state.read_u32::<LittleEndian>()) as u64;
+            if dict > HISTORY as u64 {
+                return Err(invalid_state())
+            }
+            try!(state.push_exactly(dict, &mut buf));
+        } else {
+            buf.extend_from_slice(&self.preset);
+        }
+        let dict_id = if flags & 0x4 != 0 {
+            Some(try!(state.read_u32::<LittleEndian>()))
+        } else {
+            None
+        };
+        // the blocks to come have to be compressed against the dictionary
+        // the frame header names
+        if dict_id != self.dict_id {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "lz4 state was exported with another dictionary"))
+        }
+
+        self.wrote_header = flags & 0x1 != 0;
+        self.linked = linked;
+        self.limit = limit;
+        self.dict = buf.len();
+        buf.extend_from_slice(&pending);
+        self.buf = buf;
+        Ok(())
     }
 
     /// This function is used to flag that this session of compression is done
     /// with. The stream is finished up (final bytes are written), and then the
     /// wrapped writer is returned.
-    pub fn finish(mut self) -> (W, io::Result<()>) {
-        let mut result = self.flush();
+    pub fn finish(self) -> (W, io::Result<()>) {
+        let (w, _, result) = self.finish_with_stats();
+        (w, result)
+    }
 
-        for _ in 0..2 {
-            let tmp = self.w.write_u32::<LittleEndian>(0)
-                            .map_err(byteorder_err_to_io);
+    /// Same as `finish`, also returning the time spent in each stage if
+    /// `enable_stats` was called
+    pub fn finish_with_stats(mut self) -> (W, Stats, io::Result<()>) {
+        // even an empty frame gets its header
+        let result = self.write_header().and_then(|_| self.flush()).and_then(|_| {
+            self.w.write_u32::<LittleEndian>(0).map_err(byteorder_err_to_io)
+        });
 
-            result = result.and_then(|_| tmp);
-        }
+        (self.w, self.timer.stats(), result)
+    }
 
-        (self.w, result)
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.wrote_header {
+            try!(write_frame_header(&mut self.w, self.linked, self.dict_id));
+            self.wrote_header = true;
+        }
+        Ok(())
     }
 }
 
 impl<W: Write> Write for Encoder<W> {
     fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
-        if !self.wrote_header {
-            try!(self.w.write_u32::<LittleEndian>(MAGIC));
-            // version 01, turn on block independence, but turn off
-            // everything else (we have no checksums right now).
-            try!(self.w.write_u8(0b01_100000));
-            // Maximum block size is 256KB
-            try!(self.w.write_u8(0b0_101_0000));
-            // XXX: this checksum is just plain wrong.
-            try!(self.w.write_u8(0));
-            self.wrote_header = true;
-        }
+        let len = buf.len();
+        try!(self.write_header());
 
         while buf.len() > 0 {
-            let amt = cmp::min(self.limit - self.buf.len(), buf.len());
+            let amt = cmp::min(self.limit - (self.buf.len() - self.dict), buf.len());
             self.buf.extend(buf[..amt].iter().map(|b| *b));
 
-            if self.buf.len() == self.limit {
+            if self.buf.len() - self.dict == self.limit {
                 try!(self.encode_block());
             }
             buf = &buf[amt..];
         }
 
-        Ok(buf.len())
+        Ok(len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if self.buf.len() > 0 {
+        if self.buf.len() > self.dict {
             try!(self.encode_block());
         }
         self.w.flush()
@@ -597,67 +1154,849 @@ impl<W: Write> Write for Encoder<W> {
 }
 
 
-/// Decodes pure LZ4 block into output. Returns count of bytes
-/// processed.
-pub fn decode_block(input: &[u8], output: &mut Vec<u8>) -> usize {
-    let mut b = BlockDecoder {
-        input: input,
-        output: output,
-        cur: 0,
-        start: 0,
-        end: 0
-    };
-    b.decode()
+const FRAME_HEADER_SIZE: u64 = 7;
+const BLOCK_HEADER_SIZE: u64 = 4;
+const END_MARK_SIZE: u64 = 4;
+
+/// The smallest frame size accepted by `SplitEncoder`: a header, a single
+/// one-byte block and the end mark.
+pub const MIN_FRAME_SIZE: u64 = FRAME_HEADER_SIZE + BLOCK_HEADER_SIZE + 1 + END_MARK_SIZE;
+
+// The second byte of the xxHash32 of the frame descriptor
+fn header_checksum(descriptor: &[u8]) -> u8 {
+    (xxhash::xxh32(descriptor, 0) >> 8) as u8
 }
 
+fn write_frame_header<W: Write>(w: &mut W, linked: bool, dict_id: Option<u32>) -> io::Result<()> {
+    try!(w.write_u32::<LittleEndian>(MAGIC));
+    // version 01, block independence unless blocks are linked, a preset
+    // dictionary if any, but no block or content checksums.
+    let flg = if linked { 0b01_000000 } else { 0b01_100000 };
+    // Maximum block size is 256KB
+    let mut descriptor = vec![flg | dict_id.is_some() as u8, 0b0_101_0000];
+    if let Some(id) = dict_id {
+        descriptor.extend_from_slice(&id.to_le_bytes());
+    }
+    descriptor.push(header_checksum(&descriptor));
+    w.write_all(&descriptor)
+}
 
-/// Encodes input into pure LZ4 block. Return count of bytes
-/// processed.
-pub fn encode_block(input: &[u8], output: &mut Vec<u8>) -> usize {
-    let mut encoder = BlockEncoder {
-        input: input,
-        output: output,
-        hash_table: repeat(0).take(HASH_TABLE_SIZE as usize).collect(),
-        pos: 0,
-        anchor: 0,
-        dest_pos: 0
+// Compresses `input[dict..]` into `tmp`, the `dict` bytes before being the
+// history of linked blocks. Returns whether the result is worth keeping over
+// storing the block raw.
+fn compress_block(input: &[u8], dict: usize, tmp: &mut Vec<u8>,
+                  store_threshold: usize, mode: CompressionMode) -> bool {
+    tmp.truncate(0);
+    if store_threshold == 0 {
+        return false
+    }
+    let n = match mode {
+        CompressionMode::Fast => {
+            let mut encoder = BlockEncoder::new(input, tmp, dict);
+            encoder.index_dict();
+            encoder.encode() as usize
+        }
+        CompressionMode::HighCompression(level) => encode_hc(input, tmp, dict, level),
     };
+    n > 0 && n * 100 < (input.len() - dict) * store_threshold
+}
 
-    encoder.encode() as usize
+fn write_block<W: Write>(w: &mut W, raw: &[u8], compressed: &[u8],
+                         use_compressed: bool) -> io::Result<()> {
+    if use_compressed {
+        try!(w.write_u32::<LittleEndian>(compressed.len() as u32));
+        w.write_all(compressed)
+    } else {
+        try!(w.write_u32::<LittleEndian>((raw.len() as u32) | 0x80000000));
+        w.write_all(raw)
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use std::io::{BufReader, BufWriter, Read, Write};
-    use super::super::rand;
-    use super::{Decoder, Encoder};
-    #[cfg(feature="unstable")]
-    use test;
+/// This structure compresses a stream of bytes into a sequence of LZ4 frames,
+/// none of which exceeds a given size. Every frame goes to its own writer,
+/// obtained from a callback as soon as the previous frame is full, which is
+/// handy for storage systems with a cap on the object size.
+///
+/// Writers of completed frames are flushed and dropped, the last one is
+/// returned from `finish()`.
+pub struct SplitEncoder<W, F> {
+    open: F,
+    w: Option<W>,
+    parts: usize,
+    frame_size: u64,
+    max_frame_size: u64,
+    buf: Vec<u8>,
+    tmp: Vec<u8>,
+    limit: usize,
+    store_threshold: usize,
+    mode: CompressionMode,
+}
 
-    use super::super::byteorder::ReadBytesExt;
+impl<W: Write, F: FnMut(usize) -> io::Result<W>> SplitEncoder<W, F> {
+    /// Creates a new splitting encoder. `open` is called with the index of
+    /// each new part and returns the writer receiving it. Every part is a
+    /// complete LZ4 frame of at most `max_frame_size` bytes.
+    ///
+    /// Panics if `max_frame_size` is less than `MIN_FRAME_SIZE`.
+    pub fn new(max_frame_size: u64, open: F) -> SplitEncoder<W, F> {
+        assert!(max_frame_size >= MIN_FRAME_SIZE,
+            "LZ4 frames can't be smaller than {} bytes", MIN_FRAME_SIZE);
+        // a stored block is the worst case, make sure one fits in a frame
+        let room = max_frame_size - FRAME_HEADER_SIZE - BLOCK_HEADER_SIZE - END_MARK_SIZE;
+        SplitEncoder {
+            open,
+            w: None,
+            parts: 0,
+            frame_size: 0,
+            max_frame_size,
+            buf: Vec::with_capacity(1024),
+            tmp: Vec::new(),
+            limit: cmp::min(room, BLOCK_SIZE as u64) as usize,
+            store_threshold: DEFAULT_STORE_THRESHOLD,
+            mode: CompressionMode::Fast,
+        }
+    }
 
-    fn test_decode(input: &[u8], output: &[u8]) {
-        let mut d = Decoder::new(BufReader::new(input));
-        let mut buf = Vec::new();
+    /// Sets the ratio guardrail, see `Encoder::set_store_threshold`
+    pub fn set_store_threshold(&mut self, percent: usize) {
+        self.store_threshold = cmp::min(percent, 100);
+    }
 
-        d.read_to_end(&mut buf).unwrap();
-        assert!(&buf[..] == output);
+    /// Chooses the match finder, see `Encoder::set_mode`
+    pub fn set_mode(&mut self, mode: CompressionMode) {
+        self.mode = mode;
     }
 
-    #[test]
-    fn decode() {
-        let reference = include_bytes!("data/test.txt");
-        test_decode(include_bytes!("data/test.lz4.1"), reference);
-        test_decode(include_bytes!("data/test.lz4.2"), reference);
-        test_decode(include_bytes!("data/test.lz4.3"), reference);
-        test_decode(include_bytes!("data/test.lz4.4"), reference);
-        test_decode(include_bytes!("data/test.lz4.5"), reference);
-        test_decode(include_bytes!("data/test.lz4.6"), reference);
-        test_decode(include_bytes!("data/test.lz4.7"), reference);
-        test_decode(include_bytes!("data/test.lz4.8"), reference);
+    /// Returns the number of parts started so far
+    pub fn parts(&self) -> usize {
+        self.parts
+    }
+
+    fn start_frame(&mut self) -> io::Result<()> {
+        if self.w.is_none() {
+            let mut w = try!((self.open)(self.parts));
+            try!(write_frame_header(&mut w, false, None));
+            self.parts += 1;
+            self.frame_size = FRAME_HEADER_SIZE;
+            self.w = Some(w);
+        }
+        Ok(())
+    }
+
+    fn end_frame(&mut self) -> io::Result<Option<W>> {
+        match self.w.take() {
+            Some(mut w) => {
+                try!(w.write_u32::<LittleEndian>(0));
+                try!(w.flush());
+                Ok(Some(w))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn encode_block(&mut self) -> io::Result<()> {
+        let compressed = compress_block(&self.buf, 0, &mut self.tmp,
+                                        self.store_threshold, self.mode);
+        let size = BLOCK_HEADER_SIZE + if compressed {
+            self.tmp.len()
+        } else {
+            self.buf.len()
+        } as u64;
+        if self.frame_size + size + END_MARK_SIZE > self.max_frame_size {
+            try!(self.end_frame());
+        }
+        try!(self.start_frame());
+        try!(write_block(self.w.as_mut().unwrap(), &self.buf, &self.tmp, compressed));
+        self.frame_size += size;
+        self.buf.truncate(0);
+        Ok(())
+    }
+
+    /// Writes out the pending data and closes the last frame, returning the
+    /// writer of the last part.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.buf.is_empty() {
+            try!(self.encode_block());
+        }
+        // an empty input still produces a (single, empty) frame
+        try!(self.start_frame());
+        self.end_frame().map(|w| w.unwrap())
+    }
+}
+
+impl<W: Write, F: FnMut(usize) -> io::Result<W>> Write for SplitEncoder<W, F> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while !buf.is_empty() {
+            let amt = cmp::min(self.limit - self.buf.len(), buf.len());
+            self.buf.extend_from_slice(&buf[..amt]);
+
+            if self.buf.len() == self.limit {
+                try!(self.encode_block());
+            }
+            buf = &buf[amt..];
+        }
+        Ok(len)
+    }
+
+    /// Encodes the pending data as a block of the current frame. Note that
+    /// frequent flushes result in small blocks and a worse ratio.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            try!(self.encode_block());
+        }
+        match self.w {
+            Some(ref mut w) => w.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// This structure compresses a stream like `Encoder`, spreading the blocks
+/// over several threads. Blocks are cut at the same fixed offsets and
+/// written in input order, so the output is byte-identical to what `Encoder`
+/// produces for the same sequence of writes and flushes, whatever the number
+/// of threads.
+pub struct ParallelEncoder<W> {
+    w: W,
+    wrote_header: bool,
+    buf: Vec<u8>,
+    threads: usize,
+    store_threshold: usize,
+    mode: CompressionMode,
+}
+
+impl<W: Write> ParallelEncoder<W> {
+    /// Creates a new encoder compressing up to `threads` blocks at once.
+    /// Up to that many blocks of input are buffered.
+    pub fn new(w: W, threads: usize) -> ParallelEncoder<W> {
+        let threads = cmp::max(threads, 1);
+        ParallelEncoder {
+            w,
+            wrote_header: false,
+            buf: Vec::with_capacity(threads * BLOCK_SIZE),
+            threads,
+            store_threshold: DEFAULT_STORE_THRESHOLD,
+            mode: CompressionMode::Fast,
+        }
+    }
+
+    /// Sets the ratio guardrail, see `Encoder::set_store_threshold`
+    pub fn set_store_threshold(&mut self, percent: usize) {
+        self.store_threshold = cmp::min(percent, 100);
+    }
+
+    /// Chooses the match finder, see `Encoder::set_mode`
+    pub fn set_mode(&mut self, mode: CompressionMode) {
+        self.mode = mode;
+    }
+
+    fn encode_blocks(&mut self) -> io::Result<()> {
+        let (store_threshold, mode) = (self.store_threshold, self.mode);
+        let blocks = thread::scope(|s| {
+            let handles = self.buf.chunks(BLOCK_SIZE).map(|block| {
+                s.spawn(move || {
+                    let mut tmp = Vec::new();
+                    let compressed = compress_block(block, 0, &mut tmp, store_threshold, mode);
+                    (tmp, compressed)
+                })
+            }).collect::<Vec<_>>();
+            // all of them are joined, the scope would panic itself over a
+            // panicked thread left unjoined
+            handles.into_iter().map(|h| h.join()).collect::<Vec<_>>()
+        });
+        let blocks = try!(blocks.into_iter().collect::<Result<Vec<_>, _>>().map_err(|_| {
+            io::Error::other("lz4 compression thread panicked")
+        }));
+        for (raw, (tmp, compressed)) in self.buf.chunks(BLOCK_SIZE).zip(blocks.iter()) {
+            try!(write_block(&mut self.w, raw, tmp, *compressed));
+        }
+        self.buf.truncate(0);
+        Ok(())
+    }
+
+    /// This function is used to flag that this session of compression is done
+    /// with. The stream is finished up (final bytes are written), and then the
+    /// wrapped writer is returned.
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.write_header().and_then(|_| self.flush()).and_then(|_| {
+            self.w.write_u32::<LittleEndian>(0).map_err(byteorder_err_to_io)
+        });
+
+        (self.w, result)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if !self.wrote_header {
+            try!(write_frame_header(&mut self.w, false, None));
+            self.wrote_header = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelEncoder<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        try!(self.write_header());
+
+        while !buf.is_empty() {
+            let amt = cmp::min(self.threads * BLOCK_SIZE - self.buf.len(), buf.len());
+            self.buf.extend_from_slice(&buf[..amt]);
+
+            if self.buf.len() == self.threads * BLOCK_SIZE {
+                try!(self.encode_blocks());
+            }
+            buf = &buf[amt..];
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            try!(self.encode_blocks());
+        }
+        self.w.flush()
+    }
+}
+
+/// This structure decodes a sequence of LZ4 frames spread over several
+/// streams, such as the parts produced by a `SplitEncoder`, as one
+/// continuous stream. Every stream may hold any number of concatenated frames.
+pub struct SequenceDecoder<I: Iterator> {
+    parts: I,
+    current: Option<Decoder<I::Item>>,
+}
+
+impl<R: Read, I: Iterator<Item = R>> SequenceDecoder<I> {
+    /// Creates a new decoder reading the streams yielded by `parts` in order
+    pub fn new(parts: I) -> SequenceDecoder<I> {
+        SequenceDecoder {
+            parts,
+            current: None,
+        }
+    }
+}
+
+impl<R: Read, I: Iterator<Item = R>> SequenceDecoder<I> {
+    /// Decodes and throws away the next `n` bytes of output, see
+    /// `Decoder::skip`
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let mut left = n;
+        while left > 0 {
+            let more = match self.current {
+                Some(ref mut d) => {
+                    left -= try!(d.skip(left));
+                    left == 0 || try!(d.next_frame())
+                }
+                None => match self.parts.next() {
+                    Some(r) => {
+                        self.current = Some(Decoder::new(r));
+                        true
+                    }
+                    None => break,
+                },
+            };
+            if !more {
+                self.current = None;
+            }
+        }
+        Ok(n - left)
+    }
+}
+
+impl<R: Read, I: Iterator<Item = R>> Read for SequenceDecoder<I> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if dst.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            let more = match self.current {
+                Some(ref mut d) => {
+                    let n = try!(d.read(dst));
+                    if n > 0 {
+                        return Ok(n)
+                    }
+                    try!(d.next_frame())
+                }
+                None => match self.parts.next() {
+                    Some(r) => {
+                        self.current = Some(Decoder::new(r));
+                        true
+                    }
+                    None => return Ok(0),
+                },
+            };
+            if !more {
+                self.current = None;
+            }
+        }
+    }
+}
+
+
+/// Splices LZ4 files into one, copying them to `w` one after another and
+/// returning the number of bytes written. Concatenated frames form a valid
+/// stream, so nothing but the bytes of the parts gets copied and the result
+/// decompresses to the contents of the parts in order. Each part is checked
+/// as `validation` says while it is copied; with `Validation::Full` all of
+/// its frames are decoded, so frames compressed against a dictionary are
+/// rejected. Legacy frames have no end mark and can't be followed by
+/// anything, so they are rejected as well, just like empty parts. The error
+/// then carries a `SpliceFailed` saying how many bytes of `w` are made of
+/// whole parts, the rest coming from the failing one.
+pub fn concat<I, W>(parts: I, w: W, validation: Validation) -> io::Result<u64>
+    where I: IntoIterator, I::Item: Read, W: Write
+{
+    let legacy = || io::Error::new(io::ErrorKind::InvalidInput,
+                                   "legacy lz4 frames can't be spliced");
+    ::io::splice(parts, w, |r| {
+        match validation {
+            Validation::Header => {
+                match try!(r.read_u32::<LittleEndian>()) {
+                    MAGIC => {}
+                    LEGACY_MAGIC => return Err(legacy()),
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                                   "not an lz4 frame")),
+                }
+                try!(io::copy(r, &mut io::sink()));
+            }
+            Validation::Full => {
+                let mut d = Decoder::new(r);
+                loop {
+                    try!(io::copy(&mut d, &mut io::sink()));
+                    if d.legacy {
+                        return Err(legacy())
+                    }
+                    if !try!(d.next_frame()) {
+                        break
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Decodes pure LZ4 block into output, replacing what it held. Returns
+/// count of bytes decoded, or an error if the block is corrupt.
+pub fn decode_block(input: &[u8], output: &mut Vec<u8>) -> io::Result<usize> {
+    output.clear();
+    let mut b = BlockDecoder {
+        input: input,
+        output: output,
+        cur: 0,
+        limit: usize::MAX,
+    };
+    b.decode()
+}
+
+fn corrupt_block() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "corrupt lz4 block")
+}
+
+fn block_length(input: &[u8], cur: &mut usize, code: u8) -> io::Result<usize> {
+    let mut ret = code as usize;
+    if code == 0xf {
+        loop {
+            let tmp = *try!(input.get(*cur).ok_or_else(corrupt_block));
+            *cur += 1;
+            ret += tmp as usize;
+            if tmp != 0xff { break }
+        }
+    }
+    Ok(ret)
+}
+
+/// Decodes pure LZ4 block into an output buffer of exactly the decoded
+/// size, without allocating. Unlike `decode_block`, it is an error for the
+/// decoded data to be shorter or longer than `output`.
+pub fn decode_exact_into(input: &[u8], output: &mut [u8]) -> io::Result<()> {
+    let too_long = || io::Error::new(io::ErrorKind::InvalidData,
+                                     "lz4 block is longer than the output");
+    let mut cur = 0;
+    let mut end = 0;
+    while cur < input.len() {
+        let code = input[cur];
+        cur += 1;
+
+        let len = try!(block_length(input, &mut cur, code >> 4));
+        if len > input.len() - cur {
+            return Err(corrupt_block())
+        }
+        if len > output.len() - end {
+            return Err(too_long())
+        }
+        output[end..end + len].copy_from_slice(&input[cur..cur + len]);
+        cur += len;
+        end += len;
+        if cur == input.len() { break }
+
+        if input.len() - cur < 2 {
+            return Err(corrupt_block())
+        }
+        let back = (input[cur] as usize) | ((input[cur + 1] as usize) << 8);
+        cur += 2;
+        if back == 0 || back > end {
+            return Err(corrupt_block())
+        }
+
+        let len = try!(block_length(input, &mut cur, code & 0xf)) + 4;
+        if len > output.len() - end {
+            return Err(too_long())
+        }
+        // the match may overlap the bytes it produces
+        for i in end..end + len {
+            output[i] = output[i - back];
+        }
+        end += len;
+    }
+    if end != output.len() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                  "lz4 block is shorter than the output"))
+    }
+    Ok(())
+}
+
+
+/// Encodes input into pure LZ4 block. Return count of bytes
+/// processed.
+pub fn encode_block(input: &[u8], output: &mut Vec<u8>) -> usize {
+    BlockEncoder::new(input, output, 0).encode() as usize
+}
+
+/// Encodes a block like `encode_block`, searching the hash chains of the
+/// high compression mode at the given level, from 1 to 12
+pub fn encode_block_hc(input: &[u8], output: &mut Vec<u8>, level: u8) -> usize {
+    encode_hc(input, output, 0, level)
+}
+
+fn encode_hc(input: &[u8], output: &mut Vec<u8>, dict: usize, level: u8) -> usize {
+    let level = level.clamp(1, 12) as u32;
+    let attempts = match level {
+        1..=9 => 1 << (level - 1),
+        10 => 512,
+        11 => 1024,
+        _ => 4096,
+    };
+    BlockEncoder::new(input, output, dict).encode_hc(attempts, level >= 3) as usize
+}
+
+
+/// Compresses `data` into an LZ4 frame in one go
+pub fn compress_to_vec(data: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.write_all(data).expect("writing to a Vec can't fail");
+    let (out, result) = e.finish();
+    result.expect("writing to a Vec can't fail");
+    out
+}
+
+/// Compresses `data` into an LZ4 frame in `dst`, such as a page of a fixed
+/// size, returning the size of the frame. The first block that doesn't fit
+/// fails it with `LimitExceeded`.
+pub fn compress_into_limit(data: &[u8], dst: &mut [u8]) -> Result<usize, LimitExceeded> {
+    codecs::into_limit(dst, |w| {
+        let mut e = Encoder::new(w);
+        try!(e.write_all(data));
+        e.finish().1
+    })
+}
+
+/// Decompresses LZ4 frames held in memory in one go
+pub fn decompress_to_vec(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    try!(Decoder::new(data).read_to_end(&mut out));
+    Ok(out)
+}
+
+/// LZ4 frames as a runtime selectable `Codec`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Lz4 {
+    /// The match finder encoders use
+    pub mode: CompressionMode,
+}
+
+impl Codec for Lz4 {
+    fn name(&self) -> &'static str { "lz4" }
+
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>> {
+        metrics::encoder("lz4", w, |w| {
+            let mut e = Encoder::new(w);
+            e.set_mode(self.mode);
+            Ok(Box::new(e))
+        })
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        metrics::decoder("lz4", r, |r| Ok(Box::new(Decoder::new(r))))
+    }
+}
+
+impl<W: Write> Finish for Encoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (*self).finish().1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, BufReader, BufWriter, Read, Write};
+    use super::super::rand;
+    use checksum::xxhash;
+    use super::{CompressionMode, Decoder, Encoder};
+    #[cfg(feature="unstable")]
+    use test;
+
+    use super::super::byteorder::ReadBytesExt;
+
+    fn test_decode(input: &[u8], output: &[u8]) {
+        let mut d = Decoder::new(BufReader::new(input));
+        let mut buf = Vec::new();
+
+        d.read_to_end(&mut buf).unwrap();
+        assert!(&buf[..] == output);
+    }
+
+    #[test]
+    fn decode() {
+        let reference = include_bytes!("data/test.txt");
+        test_decode(include_bytes!("data/test.lz4.1"), reference);
+        test_decode(include_bytes!("data/test.lz4.2"), reference);
+        test_decode(include_bytes!("data/test.lz4.3"), reference);
+        test_decode(include_bytes!("data/test.lz4.4"), reference);
+        test_decode(include_bytes!("data/test.lz4.5"), reference);
+        test_decode(include_bytes!("data/test.lz4.6"), reference);
+        test_decode(include_bytes!("data/test.lz4.7"), reference);
+        test_decode(include_bytes!("data/test.lz4.8"), reference);
         test_decode(include_bytes!("data/test.lz4.9"), reference);
     }
 
+    #[test]
+    fn checksums() {
+        fn decode(input: &[u8]) -> io::Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            try!(Decoder::new(input).read_to_end(&mut buf));
+            Ok(buf)
+        }
+
+        // the reference files carry header and content checksums
+        let mut input = include_bytes!("data/test.lz4.1").to_vec();
+        let last = input.len() - 1;
+        input[last] ^= 1;
+        assert!(decode(&input).is_err());
+        input[last] ^= 1;
+        input[6] ^= 1;
+        assert!(decode(&input).is_err());
+
+        // a stored block with a block checksum
+        let mut frame = vec![0x04, 0x22, 0x4d, 0x18, 0x70, 0x40];
+        let hc = super::header_checksum(&frame[4..]);
+        frame.push(hc);
+        frame.extend_from_slice(&(5 | 0x80000000u32).to_le_bytes());
+        frame.extend_from_slice(b"hello");
+        frame.extend_from_slice(&xxhash::xxh32(b"hello", 0).to_le_bytes());
+        frame.extend_from_slice(&[0; 4]);
+        assert_eq!(&decode(&frame).unwrap()[..], b"hello");
+        frame[15] ^= 1;
+        assert!(decode(&frame).is_err());
+
+        let (encoded, _) = Encoder::new(Vec::new()).finish();
+        assert_eq!(encoded[6], super::header_checksum(&encoded[4..6]));
+    }
+
+    #[test]
+    fn decode_legacy() {
+        let input = include_bytes!("data/test.lz4.legacy");
+        let reference = include_bytes!("data/test.txt");
+        test_decode(input, reference);
+
+        // concatenated legacy streams read as one
+        let twice = [&input[..], &input[..]].concat();
+        let double = [&reference[..], &reference[..]].concat();
+        test_decode(&twice, &double);
+
+        let mut d = Decoder::new(&input[..input.len() - 1]);
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn decode_block_corrupt() {
+        let cases: [&[u8]; 6] = [
+            // literals run past the end of the block
+            &[0x50, b'a', b'b'],
+            // the literal length is cut off
+            &[0xf0],
+            // so is the offset
+            &[0x10, b'a', 0x01],
+            // an offset of zero
+            &[0x10, b'a', 0x00, 0x00],
+            // an offset before the start of the output
+            &[0x10, b'a', 0x02, 0x00],
+            // the match length is cut off
+            &[0x1f, b'a', 0x01, 0x00],
+        ];
+        let mut out = Vec::new();
+        for case in cases.iter() {
+            let err = super::decode_block(case, &mut out).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // a match overlapping its own output repeats it
+        assert_eq!(super::decode_block(&[0x10, b'a', 0x01, 0x00], &mut out).unwrap(), 5);
+        assert_eq!(&out[..], b"aaaaa");
+    }
+
+    #[test]
+    fn decode_block_over_size() {
+        // a 64KB block whose match goes on past 64KB
+        let mut block = vec![0x1f, b'a', 0x01, 0x00];
+        block.extend_from_slice(&[0xff; 300]);
+        block.push(0);
+        let mut frame = vec![0x04, 0x22, 0x4d, 0x18, 0x60, 0x40];
+        let hc = super::header_checksum(&frame[4..]);
+        frame.push(hc);
+        frame.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&block);
+        frame.extend_from_slice(&[0; 4]);
+
+        let err = Decoder::new(&frame[..]).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_corrupt() {
+        // no corrupted byte of a frame may do worse than fail the read. The
+        // top bit turns sizes into stored blocks, and lengths and offsets
+        // into ones running past the data.
+        let inputs: [&[u8]; 3] = [
+            include_bytes!("data/test.lz4.1"),
+            include_bytes!("data/test.lz4.legacy"),
+            include_bytes!("data/test.lz4.linked"),
+        ];
+        for input in inputs.iter() {
+            let mut input = input.to_vec();
+            for i in 0..input.len() {
+                input[i] ^= 0x80;
+                let _ = Decoder::new(&input[..]).read_to_end(&mut Vec::new());
+                input[i] ^= 0x80;
+            }
+        }
+
+        // an empty stored block in place of the end mark
+        let mut input = include_bytes!("data/test.lz4.1").to_vec();
+        let at = input.len() - 5;
+        input[at] ^= 0x80;
+        assert!(Decoder::new(&input[..]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    fn repeated_text() -> Vec<u8> {
+        (0..30).flat_map(|_| include_bytes!("data/test.txt").iter().cloned()).collect()
+    }
+
+    #[test]
+    fn decode_linked() {
+        // lz4 -BD -B4 -9, two 64KB blocks with the second one referring back
+        let input = include_bytes!("data/test.lz4.linked");
+        assert_eq!(input[4] & 0x20, 0);
+        test_decode(input, &repeated_text());
+    }
+
+    #[test]
+    fn decode_history_bounds() {
+        fn frame(flg: u8, back: u8) -> Vec<u8> {
+            let mut frame = vec![0x04, 0x22, 0x4d, 0x18, flg, 0x40];
+            let hc = super::header_checksum(&frame[4..]);
+            frame.push(hc);
+            // a stored block, then a match of four bytes with no literals
+            frame.extend_from_slice(&(4 | 0x80000000u32).to_le_bytes());
+            frame.extend_from_slice(b"abcd");
+            frame.extend_from_slice(&3u32.to_le_bytes());
+            frame.extend_from_slice(&[0x00, back, 0x00]);
+            frame.extend_from_slice(&[0; 4]);
+            frame
+        }
+        fn decode(input: &[u8]) -> io::Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            try!(Decoder::new(input).read_to_end(&mut buf));
+            Ok(buf)
+        }
+
+        // linked blocks reach into the history, but no further
+        assert_eq!(&decode(&frame(0x40, 4)).unwrap()[..], b"abcdabcd");
+        let err = decode(&frame(0x40, 5)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // independent blocks have no history at all
+        let err = decode(&frame(0x60, 4)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // legacy blocks are checked the same
+        let mut legacy = super::LEGACY_MAGIC.to_le_bytes().to_vec();
+        legacy.extend_from_slice(&4u32.to_le_bytes());
+        legacy.extend_from_slice(&[0x10, b'a', 0x02, 0x00]);
+        let err = decode(&legacy).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn window() {
+        let text = repeated_text();
+        for &linked in [false, true].iter() {
+            let encoded = encode_small_blocks(&text, linked, CompressionMode::Fast);
+            let mut d = Decoder::new(&encoded[..]);
+            let mut read = Vec::new();
+            let mut buf = [0; 700];
+            loop {
+                let window = d.window(100_000);
+                assert_eq!(window, &read[read.len() - window.len()..]);
+                // what was read of the current block, and the history of
+                // linked blocks before it
+                let block = match read.len() % 1000 {
+                    0 if !read.is_empty() => 1000,
+                    n => n,
+                };
+                let history = if linked { (read.len() - block).min(64 * 1024) } else { 0 };
+                assert_eq!(window.len(), block + history);
+                match d.read(&mut buf).unwrap() {
+                    0 => break,
+                    n => read.extend_from_slice(&buf[..n]),
+                }
+            }
+            assert!(read == text);
+        }
+    }
+
+    fn encode_small_blocks(bytes: &[u8], linked: bool, mode: CompressionMode) -> Vec<u8> {
+        let mut e = Encoder::new(Vec::new());
+        e.set_block_linked(linked);
+        e.set_mode(mode);
+        for chunk in bytes.chunks(1000) {
+            e.write_all(chunk).unwrap();
+            e.flush().unwrap();
+        }
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        encoded
+    }
+
+    #[test]
+    fn linked_roundtrip() {
+        let text = repeated_text();
+        for &mode in [CompressionMode::Fast, CompressionMode::HighCompression(9)].iter() {
+            let independent = encode_small_blocks(&text, false, mode);
+            let linked = encode_small_blocks(&text, true, mode);
+            assert!(linked.len() * 4 < independent.len());
+            test_decode(&linked, &text);
+        }
+
+        let mut e = Encoder::new(Vec::new());
+        e.set_block_linked(true);
+        e.write_all(&text).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        test_decode(&encoded, &text);
+    }
+
     #[test]
     fn raw_encode_block() {
         let data = include_bytes!("data/test.txt");
@@ -666,11 +2005,30 @@ mod test {
         super::encode_block(data, &mut encoded);
         let mut decoded = Vec::new();
 
-        super::decode_block(&encoded[..], &mut decoded);
+        super::decode_block(&encoded[..], &mut decoded).unwrap();
 
         assert_eq!(&data[..], &decoded[..]);
     }
 
+    #[test]
+    fn decode_exact_into() {
+        let input = include_bytes!("data/test.txt");
+        let mut block = Vec::new();
+        super::encode_block(&input[..], &mut block);
+
+        let mut output = vec![0u8; input.len()];
+        super::decode_exact_into(&block, &mut output).unwrap();
+        assert_eq!(&output[..], &input[..]);
+
+        let mut short = vec![0u8; input.len() - 1];
+        assert!(super::decode_exact_into(&block, &mut short).is_err());
+        let mut long = vec![0u8; input.len() + 1];
+        assert!(super::decode_exact_into(&block, &mut long).is_err());
+        for cut in 1..block.len() {
+            let _ = super::decode_exact_into(&block[..cut], &mut output);
+        }
+    }
+
     #[test]
     fn one_byte_at_a_time() {
         let input = include_bytes!("data/test.lz4.1");
@@ -725,6 +2083,240 @@ mod test {
         roundtrip(include_bytes!("data/test.txt"));
     }
 
+    fn encode(bytes: &[u8], store_threshold: usize) -> Vec<u8> {
+        let mut e = Encoder::new(Vec::new());
+        e.set_store_threshold(store_threshold);
+        e.write_all(bytes).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        encoded
+    }
+
+    #[test]
+    fn store_threshold() {
+        let text = include_bytes!("data/test.txt");
+        let compressed = encode(text, 98);
+        assert!(compressed.len() < text.len());
+
+        // nothing compresses below 0% so every block is stored
+        let stored = encode(text, 0);
+        assert_eq!(stored.len(), 7 + 4 + text.len() + 4);
+
+        let mut random = [0u8; 10000];
+        for b in random.iter_mut() { *b = rand::random() }
+        let encoded = encode(&random, 98);
+        assert_eq!(encoded.len(), 7 + 4 + random.len() + 4);
+
+        let mut decoded = Vec::new();
+        Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], &random[..]);
+    }
+
+    #[test]
+    fn high_compression() {
+        let text = &include_bytes!("data/test.large")[..300_000];
+        let fast = encode(text, 98).len();
+        let mut last = fast;
+        for &level in [1, 4, 9, 12].iter() {
+            let mut e = Encoder::new(Vec::new());
+            e.set_mode(CompressionMode::HighCompression(level));
+            e.write_all(text).unwrap();
+            let (encoded, err) = e.finish();
+            err.unwrap();
+
+            let mut decoded = Vec::new();
+            Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+            assert!(&decoded[..] == text);
+            if level > 1 {
+                assert!(encoded.len() <= last, "level {} is worse", level);
+            }
+            last = encoded.len();
+        }
+        assert!(last < fast);
+
+        // short blocks are all literals
+        let mut out = Vec::new();
+        for len in 0..16 {
+            super::encode_block_hc(&text[..len], &mut out, 9);
+            let mut decoded = Vec::new();
+            super::decode_block(&out, &mut decoded).unwrap();
+            assert_eq!(&decoded[..], &text[..len]);
+        }
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        use super::ParallelEncoder;
+
+        let text = &include_bytes!("data/test.large")[..1_500_000];
+        let mut serial = Encoder::new(Vec::new());
+        serial.write_all(&text[..700_000]).unwrap();
+        serial.flush().unwrap();
+        serial.write_all(&text[700_000..]).unwrap();
+        let (expected, err) = serial.finish();
+        err.unwrap();
+
+        for &threads in [1, 3, 8].iter() {
+            let mut e = ParallelEncoder::new(Vec::new(), threads);
+            for chunk in text[..700_000].chunks(99_999) {
+                e.write_all(chunk).unwrap();
+            }
+            e.flush().unwrap();
+            e.write_all(&text[700_000..]).unwrap();
+            let (encoded, err) = e.finish();
+            err.unwrap();
+            assert!(encoded == expected, "differs with {} threads", threads);
+        }
+
+        // no input is still a whole frame
+        let (empty, err) = ParallelEncoder::new(Vec::new(), 2).finish();
+        err.unwrap();
+        assert_eq!(empty, Encoder::new(Vec::new()).finish().0);
+        let mut decoded = Vec::new();
+        Decoder::new(&empty[..]).read_to_end(&mut decoded).unwrap();
+        assert!(decoded.is_empty());
+    }
+
+    #[test]
+    fn split_frames() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        use super::SplitEncoder;
+
+        let text = include_bytes!("data/test.large");
+        let parts = Rc::new(RefCell::new(Vec::new()));
+        let sink = parts.clone();
+        let mut e = SplitEncoder::new(100_000, |i| {
+            assert_eq!(i, sink.borrow().len());
+            sink.borrow_mut().push(Vec::new());
+            Ok(PartWriter(sink.clone(), i))
+        });
+        e.write_all(&text[..]).unwrap();
+        e.finish().unwrap();
+
+        let parts = parts.borrow();
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|p| p.len() <= 100_000));
+
+        let mut decoded = Vec::new();
+        super::SequenceDecoder::new(parts.iter().map(|p| &p[..]))
+            .read_to_end(&mut decoded).unwrap();
+        assert!(decoded[..] == text[..]);
+
+        // concatenated frames in a single stream decode just the same
+        let joined: Vec<u8> = parts.iter().flat_map(|p| p.iter().cloned()).collect();
+        let mut decoded = Vec::new();
+        super::SequenceDecoder::new(Some(&joined[..]).into_iter())
+            .read_to_end(&mut decoded).unwrap();
+        assert!(decoded[..] == text[..]);
+    }
+
+    #[test]
+    fn skip() {
+        let text = &include_bytes!("data/test.large")[..];
+        for &threshold in [0, 98].iter() {
+            let encoded = encode(text, threshold);
+            let mut d = Decoder::new(&encoded[..]);
+            assert_eq!(d.skip(10).unwrap(), 10);
+            assert_eq!(d.skip(600_000).unwrap(), 600_000);
+            let mut rest = Vec::new();
+            d.read_to_end(&mut rest).unwrap();
+            assert!(rest[..] == text[600_010..]);
+            assert_eq!(d.skip(1).unwrap(), 0);
+        }
+    }
+
+    struct PartWriter(::std::rc::Rc<::std::cell::RefCell<Vec<Vec<u8>>>>, usize);
+
+    impl Write for PartWriter {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut()[self.1].extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn export_import_state() {
+        use std::io::Cursor;
+        let input = &include_bytes!("data/test.lz4.1")[..];
+        let reference = &include_bytes!("data/test.txt")[..];
+
+        let mut d = Decoder::new(Cursor::new(input));
+        let mut out = vec![0u8; 1000];
+        d.read_exact(&mut out).unwrap();
+        let state = d.export_state();
+        let offset = d.r.position();
+
+        let mut d = Decoder::new(Cursor::new(input));
+        d.r.set_position(offset);
+        d.import_state(&state).unwrap();
+        d.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], reference);
+
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(&reference[..100]).unwrap();
+        let state = e.export_state();
+        let mut e = Encoder::new(e.w);
+        e.import_state(&state).unwrap();
+        e.write_all(&reference[100..]).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        let mut decoded = Vec::new();
+        Decoder::new(&encoded[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], reference);
+    }
+
+    #[test]
+    fn import_invalid_state() {
+        let mut d = Decoder::new(&include_bytes!("data/test.lz4.1")[..]);
+        d.read_exact(&mut [0; 1000]).unwrap();
+        let state = d.export_state();
+        // block sizes are at bytes 2 to 5, a frame can't declare these
+        for &size in [5u32 << 20, super::LEGACY_BLOCK_SIZE as u32, u32::MAX].iter() {
+            let mut bad = state.clone();
+            bad[2..6].copy_from_slice(&size.to_le_bytes());
+            assert!(Decoder::new(&b""[..]).import_state(&bad).is_err());
+        }
+
+        let mut e = Encoder::new(Vec::new());
+        e.write_all(b"pending").unwrap();
+        let mut bad = e.export_state();
+        bad[2..6].copy_from_slice(&(1u32 << 30).to_le_bytes());
+        assert!(Encoder::new(Vec::new()).import_state(&bad).is_err());
+    }
+
+    #[test]
+    fn export_import_linked_state() {
+        use std::io::Cursor;
+        let text = repeated_text();
+        let input = &include_bytes!("data/test.lz4.linked")[..];
+
+        // stop inside the second block, which needs the first as history
+        let mut d = Decoder::new(Cursor::new(input));
+        let mut out = vec![0u8; 70000];
+        d.read_exact(&mut out).unwrap();
+        let state = d.export_state();
+        let mut d2 = Decoder::new(Cursor::new(input));
+        d2.r.set_position(d.r.position());
+        d2.import_state(&state).unwrap();
+        d2.read_to_end(&mut out).unwrap();
+        assert!(out == text);
+
+        let mut e = Encoder::new(Vec::new());
+        e.set_block_linked(true);
+        e.write_all(&text[..5000]).unwrap();
+        e.flush().unwrap();
+        e.write_all(&text[5000..5100]).unwrap();
+        let state = e.export_state();
+        let mut e = Encoder::new(e.w);
+        e.import_state(&state).unwrap();
+        e.write_all(&text[5100..]).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        test_decode(&encoded, &text);
+    }
+
     #[cfg(feature="unstable")]
     #[bench]
     fn decompress_speed(bh: &mut test::Bencher) {
@@ -739,4 +2331,124 @@ mod test {
         });
         bh.bytes = output_size as u64;
     }
+
+    #[test]
+    fn one_shot() {
+        ::codecs::check_one_shot(super::compress_to_vec, super::decompress_to_vec,
+                                 super::compress_into_limit);
+    }
+
+    #[test]
+    fn dictionaries() {
+        use dict::{DictHandle, Dictionary};
+        let text = include_bytes!("data/test.txt");
+        let handle = DictHandle::new(Dictionary::new(7, text[..2000].to_vec()));
+        let input = &text[2000..2600];
+        let plain = super::compress_to_vec(input);
+        for &linked in [false, true].iter() {
+            let mut e = Encoder::with_dictionary(Vec::new(), &handle.current());
+            e.set_block_linked(linked);
+            // several blocks, each one able to use the dictionary
+            for chunk in input.chunks(200) {
+                e.write_all(chunk).unwrap();
+                e.flush().unwrap();
+            }
+            let (encoded, result) = e.finish();
+            result.unwrap();
+            let mut decoded = Vec::new();
+            Decoder::with_dictionaries(&encoded[..], handle.clone()).read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], input);
+            assert!(encoded.len() < plain.len());
+            // the id is needed to decode
+            assert!(Decoder::new(&encoded[..]).read_to_end(&mut Vec::new()).is_err());
+        }
+
+        let mut e = Encoder::with_dictionary(Vec::new(), &handle.current());
+        e.write_all(input).unwrap();
+        let old = e.finish().0;
+        handle.swap(Dictionary::new(8, text[2600..].to_vec()));
+        let mut decoded = Vec::new();
+        Decoder::with_dictionaries(&old[..], handle.clone()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], input);
+        handle.retire(7);
+        assert!(Decoder::with_dictionaries(&old[..], handle.clone()).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn dictionary_state() {
+        use std::io::Cursor;
+        use dict::{DictHandle, Dictionary};
+        let text = include_bytes!("data/test.txt");
+        let handle = DictHandle::new(Dictionary::new(7, text[..2000].to_vec()));
+        let input = &text[2000..2600];
+
+        // independent blocks, each one needs the dictionary again
+        let mut e = Encoder::with_dictionary(Vec::new(), &handle.current());
+        for chunk in input.chunks(200) {
+            e.write_all(chunk).unwrap();
+            e.flush().unwrap();
+        }
+        let encoded = e.finish().0;
+        let mut d = Decoder::with_dictionaries(Cursor::new(&encoded[..]), handle.clone());
+        let mut out = vec![0u8; 300];
+        d.read_exact(&mut out).unwrap();
+        let state = d.export_state();
+        let mut d2 = Decoder::with_dictionaries(Cursor::new(&encoded[..]), handle.clone());
+        d2.r.set_position(d.r.position());
+        d2.import_state(&state).unwrap();
+        d2.read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], input);
+        assert!(Decoder::new(&b""[..]).import_state(&state).is_err());
+
+        let mut e = Encoder::with_dictionary(Vec::new(), &handle.current());
+        e.write_all(&input[..200]).unwrap();
+        e.flush().unwrap();
+        e.write_all(&input[200..300]).unwrap();
+        let state = e.export_state();
+        assert!(Encoder::new(Vec::new()).import_state(&state).is_err());
+        let mut e = Encoder::with_dictionary(e.w, &handle.current());
+        e.import_state(&state).unwrap();
+        e.write_all(&input[300..]).unwrap();
+        let (encoded, err) = e.finish();
+        err.unwrap();
+        let mut decoded = Vec::new();
+        Decoder::with_dictionaries(&encoded[..], handle).read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], input);
+    }
+
+    #[test]
+    fn concat() {
+        use io::{SpliceFailed, Validation};
+        let text = include_bytes!("data/test.txt");
+        let parts = [super::compress_to_vec(&text[..1000]),
+                     super::compress_to_vec(&text[1000..]),
+                     super::compress_to_vec(b"")];
+        for &validation in [Validation::Header, Validation::Full].iter() {
+            let mut out = Vec::new();
+            let n = super::concat(parts.iter().map(|p| &p[..]), &mut out, validation).unwrap();
+            assert_eq!(n as usize, out.len());
+            assert!(out == parts.concat());
+            let mut decoded = Vec::new();
+            super::SequenceDecoder::new(Some(&out[..]).into_iter())
+                .read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], &text[..]);
+
+            let legacy = &include_bytes!("data/test.lz4.legacy")[..];
+            assert!(super::concat([legacy, &parts[0][..]], &mut Vec::new(), validation).is_err());
+            assert!(super::concat([&parts[0][..], b""], &mut Vec::new(), validation).is_err());
+        }
+
+        // a truncated part only shows when decoding
+        let cut = &parts[0][..parts[0].len() - 1];
+        assert!(super::concat(Some(cut), &mut Vec::new(), Validation::Header).is_ok());
+        assert!(super::concat(Some(cut), &mut Vec::new(), Validation::Full).is_err());
+
+        // the output is good up to the failing part
+        let mut out = Vec::new();
+        let err = super::concat([&parts[0][..], &parts[1][..], cut], &mut out,
+                                Validation::Full).unwrap_err();
+        let failed = SpliceFailed::find(&err).unwrap();
+        assert_eq!((failed.part, failed.valid), (2, (parts[0].len() + parts[1].len()) as u64));
+        assert_eq!(out.len(), failed.valid as usize + cut.len());
+    }
 }
@@ -0,0 +1,848 @@
+/*!
+
+LZMA decompression. Requires `lzma` feature, enabled by default
+
+LZMA is an LZ77 compressor whose literals, match lengths and distances are
+all coded bit by bit with an adaptive binary range coder, each bit with a
+probability picked by what came before. This module decodes the legacy
+`.lzma` files written by `xz --format=lzma` and LZMA Utils, raw LZMA streams
+given their properties, and LZMA2, the chunked variant used inside `.xz`
+files. The `xz` module reads the `.xz` container around it.
+
+LZMA uses its own range coder, which propagates carries into the bytes
+already written; the carry-less coder of `entropy::range` cannot read it, so
+the decoder here has its own.
+
+The dictionary a stream declares is allocated as the data is decoded, up to
+the declared size. Streams declaring more than `DEFAULT_DICT_LIMIT` are
+rejected with an error carrying `io::LimitExceeded`, unless the limit is
+raised with `set_dict_limit`.
+
+# Example
+
+```rust,no_run
+use std::fs::File;
+use std::io::Read;
+use compress::lzma;
+
+let file = File::open("archive.lzma").unwrap();
+let mut d = lzma::Decoder::new(file);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+```
+
+*/
+
+use std::cmp;
+use std::io::{self, Read};
+
+use io::LimitExceeded;
+
+pub mod xz;
+
+/// Largest dictionary the decoders allocate unless told otherwise, 256 MiB.
+/// `xz -9` uses 64 MiB.
+pub const DEFAULT_DICT_LIMIT: usize = 256 << 20;
+
+// smaller dictionaries are rounded up, like the reference decoder does
+const MIN_DICT_SIZE: usize = 1 << 12;
+const MIN_MATCH_LEN: usize = 2;
+const PROB_INIT: u16 = 1 << 10;
+const PROB_BITS: u32 = 11;
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+const STATES: usize = 12;
+const POS_STATES: usize = 1 << 4;
+const END_MARKER: u32 = 0xFFFF_FFFF;
+
+fn error<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn check_dict_size(size: u64, limit: usize) -> io::Result<usize> {
+    if size > limit as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, LimitExceeded { limit }))
+    }
+    Ok(cmp::max(size as usize, MIN_DICT_SIZE))
+}
+
+/// The literal context and position bits of an LZMA stream, stored in one
+/// byte in front of it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Props {
+    /// High bits of the previous byte used as literal context, 0 to 8
+    pub lc: u32,
+    /// Low bits of the position used as literal context, 0 to 4
+    pub lp: u32,
+    /// Low bits of the position used as context for everything else, 0 to 4
+    pub pb: u32,
+}
+
+impl Props {
+    /// Decodes the properties byte, `(pb * 5 + lp) * 9 + lc`
+    pub fn from_byte(b: u8) -> Option<Props> {
+        if b >= 9 * 5 * 5 {
+            return None
+        }
+        let b = b as u32;
+        Some(Props { lc: b % 9, lp: b / 9 % 5, pb: b / 45 })
+    }
+
+    /// Encodes the properties into a byte
+    pub fn to_byte(&self) -> u8 {
+        ((self.pb * 5 + self.lp) * 9 + self.lc) as u8
+    }
+
+    fn is_valid(&self) -> bool {
+        self.lc <= 8 && self.lp <= 4 && self.pb <= 4
+    }
+}
+
+impl Default for Props {
+    /// The properties `xz` uses unless told otherwise
+    fn default() -> Props {
+        Props { lc: 3, lp: 0, pb: 2 }
+    }
+}
+
+// Buffers the compressed input, counting the bytes consumed. The decoders
+// read it a byte at a time.
+struct Input<R> {
+    r: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    consumed: u64,
+}
+
+impl<R: Read> Input<R> {
+    fn new(r: R) -> Input<R> {
+        Input { r, buf: vec![0; 32 * 1024].into_boxed_slice(), pos: 0, len: 0, consumed: 0 }
+    }
+
+    // Refills the buffer if it is empty, returning whether input remains
+    fn fill(&mut self) -> io::Result<bool> {
+        while self.pos == self.len {
+            match self.r.read(&mut self.buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => { self.pos = 0; self.len = n; }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        if self.pos == self.len && !try!(self.fill()) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated LZMA stream"))
+        }
+        let b = self.buf[self.pos];
+        self.pos += 1;
+        self.consumed += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, out: &mut [u8]) -> io::Result<()> {
+        for b in out.iter_mut() {
+            *b = try!(self.byte());
+        }
+        Ok(())
+    }
+
+    // Copies up to `out.len()` bytes, at least one
+    fn copy(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.len && !try!(self.fill()) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated LZMA stream"))
+        }
+        let n = cmp::min(out.len(), self.len - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        self.consumed += n as u64;
+        Ok(n)
+    }
+
+    fn u16_be(&mut self) -> io::Result<u16> {
+        let hi = try!(self.byte());
+        Ok(u16::from_be_bytes([hi, try!(self.byte())]))
+    }
+}
+
+struct RangeDecoder {
+    range: u32,
+    code: u32,
+}
+
+impl RangeDecoder {
+    fn new<R: Read>(input: &mut Input<R>) -> io::Result<RangeDecoder> {
+        let mut init = [0; 5];
+        try!(input.bytes(&mut init));
+        let code = u32::from_be_bytes([init[1], init[2], init[3], init[4]]);
+        if init[0] != 0 || code == 0xFFFF_FFFF {
+            return error("invalid LZMA range coder state")
+        }
+        Ok(RangeDecoder { range: 0xFFFF_FFFF, code })
+    }
+
+    // A stream ends with the code at zero, as the encoder flushes its low end
+    fn is_finished(&self) -> bool {
+        self.code == 0
+    }
+
+    // Done after every bit, so that a stream is consumed up to its last byte
+    // once its last bit is decoded
+    fn normalize<R: Read>(&mut self, input: &mut Input<R>) -> io::Result<()> {
+        if self.range < TOP {
+            self.range <<= 8;
+            self.code = self.code << 8 | try!(input.byte()) as u32;
+        }
+        Ok(())
+    }
+
+    fn bit<R: Read>(&mut self, input: &mut Input<R>, prob: &mut u16) -> io::Result<usize> {
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = if self.code < bound {
+            self.range = bound;
+            *prob += ((1 << PROB_BITS) - *prob) >> MOVE_BITS;
+            0
+        } else {
+            self.range -= bound;
+            self.code -= bound;
+            *prob -= *prob >> MOVE_BITS;
+            1
+        };
+        try!(self.normalize(input));
+        Ok(bit)
+    }
+
+    // Bits with a fixed probability of one half, most significant first
+    fn direct<R: Read>(&mut self, input: &mut Input<R>, count: u32) -> io::Result<u32> {
+        let mut result = 0;
+        for _ in 0..count {
+            self.range >>= 1;
+            let bit = (self.code >= self.range) as u32;
+            if bit == 1 {
+                self.code -= self.range;
+            }
+            result = result << 1 | bit;
+            try!(self.normalize(input));
+        }
+        Ok(result)
+    }
+
+    // A `bits` wide number, most significant bit first, with the
+    // probabilities laid out as a binary tree rooted at index 1
+    fn tree<R: Read>(&mut self, input: &mut Input<R>, probs: &mut [u16], bits: u32)
+                     -> io::Result<usize> {
+        let mut m = 1;
+        for _ in 0..bits {
+            m = m << 1 | try!(self.bit(input, &mut probs[m]));
+        }
+        Ok(m - (1 << bits))
+    }
+
+    // Like `tree`, least significant bit first
+    fn reverse<R: Read>(&mut self, input: &mut Input<R>, probs: &mut [u16], bits: u32)
+                        -> io::Result<u32> {
+        let mut m = 1;
+        let mut result = 0;
+        for i in 0..bits {
+            let bit = try!(self.bit(input, &mut probs[m]));
+            m = m << 1 | bit;
+            result |= (bit as u32) << i;
+        }
+        Ok(result)
+    }
+}
+
+struct LenDecoder {
+    choice: u16,
+    choice2: u16,
+    low: [[u16; 8]; POS_STATES],
+    mid: [[u16; 8]; POS_STATES],
+    high: [u16; 256],
+}
+
+impl LenDecoder {
+    fn new() -> LenDecoder {
+        LenDecoder {
+            choice: PROB_INIT,
+            choice2: PROB_INIT,
+            low: [[PROB_INIT; 8]; POS_STATES],
+            mid: [[PROB_INIT; 8]; POS_STATES],
+            high: [PROB_INIT; 256],
+        }
+    }
+
+    // The match length minus `MIN_MATCH_LEN`
+    fn decode<R: Read>(&mut self, rc: &mut RangeDecoder, input: &mut Input<R>, pos_state: usize)
+                       -> io::Result<usize> {
+        if try!(rc.bit(input, &mut self.choice)) == 0 {
+            rc.tree(input, &mut self.low[pos_state], 3)
+        } else if try!(rc.bit(input, &mut self.choice2)) == 0 {
+            rc.tree(input, &mut self.mid[pos_state], 3).map(|len| len + 8)
+        } else {
+            rc.tree(input, &mut self.high, 8).map(|len| len + 16)
+        }
+    }
+}
+
+// The sliding window, allocated as it fills up
+struct Dict {
+    buf: Vec<u8>,
+    size: usize,
+    pos: usize,
+    full: bool,
+    // bytes since the last reset, the position the contexts are taken from
+    total: u64,
+}
+
+impl Dict {
+    fn new(size: usize) -> Dict {
+        Dict { buf: Vec::new(), size, pos: 0, full: false, total: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.buf.clear();
+        self.pos = 0;
+        self.full = false;
+        self.total = 0;
+    }
+
+    // Whether the byte `dist` back, counting from 1, is in the window
+    fn has(&self, dist: usize) -> bool {
+        dist <= if self.full { self.size } else { self.pos }
+    }
+
+    fn get(&self, dist: usize) -> u8 {
+        if self.pos >= dist {
+            self.buf[self.pos - dist]
+        } else {
+            self.buf[self.pos + self.size - dist]
+        }
+    }
+
+    fn put(&mut self, b: u8) {
+        if self.full {
+            self.buf[self.pos] = b;
+        } else {
+            if self.buf.len() == self.buf.capacity() {
+                let more = cmp::max(self.buf.len(), MIN_DICT_SIZE);
+                self.buf.reserve_exact(cmp::min(more, self.size - self.buf.len()));
+            }
+            self.buf.push(b);
+        }
+        self.pos += 1;
+        if self.pos == self.size {
+            self.pos = 0;
+            self.full = true;
+        }
+        self.total += 1;
+    }
+}
+
+// The decoding state of LZMA, shared by the LZMA and LZMA2 decoders
+struct Lzma {
+    props: Props,
+    dict: Dict,
+    rc: RangeDecoder,
+    state: usize,
+    // the last four distances, minus one
+    reps: [u32; 4],
+    // bytes of the current match still to copy
+    pending: usize,
+    literal: Vec<u16>,
+    is_match: [u16; STATES * POS_STATES],
+    is_rep: [u16; STATES],
+    is_rep0: [u16; STATES],
+    is_rep1: [u16; STATES],
+    is_rep2: [u16; STATES],
+    is_rep0_long: [u16; STATES * POS_STATES],
+    pos_slot: [[u16; 64]; 4],
+    pos_special: [u16; 115],
+    align: [u16; 16],
+    len: LenDecoder,
+    rep_len: LenDecoder,
+}
+
+impl Lzma {
+    fn new(props: Props, dict_size: usize) -> Lzma {
+        Lzma {
+            props,
+            dict: Dict::new(dict_size),
+            rc: RangeDecoder { range: 0xFFFF_FFFF, code: 0 },
+            state: 0,
+            reps: [0; 4],
+            pending: 0,
+            literal: vec![PROB_INIT; 0x300 << (props.lc + props.lp)],
+            is_match: [PROB_INIT; STATES * POS_STATES],
+            is_rep: [PROB_INIT; STATES],
+            is_rep0: [PROB_INIT; STATES],
+            is_rep1: [PROB_INIT; STATES],
+            is_rep2: [PROB_INIT; STATES],
+            is_rep0_long: [PROB_INIT; STATES * POS_STATES],
+            pos_slot: [[PROB_INIT; 64]; 4],
+            pos_special: [PROB_INIT; 115],
+            align: [PROB_INIT; 16],
+            len: LenDecoder::new(),
+            rep_len: LenDecoder::new(),
+        }
+    }
+
+    // Starts over with fresh probabilities, keeping the dictionary
+    fn reset_state(&mut self, props: Props) {
+        let dict = ::std::mem::replace(&mut self.dict, Dict::new(0));
+        *self = Lzma::new(props, 0);
+        self.dict = dict;
+    }
+
+    fn put(&mut self, out: &mut [u8], n: &mut usize, b: u8) {
+        self.dict.put(b);
+        out[*n] = b;
+        *n += 1;
+    }
+
+    fn literal<R: Read>(&mut self, input: &mut Input<R>) -> io::Result<u8> {
+        let Props { lc, lp, .. } = self.props;
+        let prev = if self.dict.has(1) { self.dict.get(1) as usize } else { 0 };
+        let ctx = ((self.dict.total as usize & ((1 << lp) - 1)) << lc) + (prev >> (8 - lc));
+        let probs = &mut self.literal[0x300 * ctx..0x300 * (ctx + 1)];
+        let mut sym = 1;
+        if self.state >= 7 {
+            // after a match, the byte following the match guides the coding
+            // until the first bit that differs from it
+            let mut match_byte = self.dict.get(self.reps[0] as usize + 1) as usize;
+            while sym < 0x100 {
+                let match_bit = (match_byte >> 7) & 1;
+                match_byte <<= 1;
+                let bit = try!(self.rc.bit(input, &mut probs[((1 + match_bit) << 8) + sym]));
+                sym = sym << 1 | bit;
+                if bit != match_bit {
+                    break
+                }
+            }
+        }
+        while sym < 0x100 {
+            sym = sym << 1 | try!(self.rc.bit(input, &mut probs[sym]));
+        }
+        Ok(sym as u8)
+    }
+
+    fn distance<R: Read>(&mut self, input: &mut Input<R>, len: usize) -> io::Result<u32> {
+        let len_state = cmp::min(len, 3);
+        let slot = try!(self.rc.tree(input, &mut self.pos_slot[len_state], 6)) as u32;
+        if slot < 4 {
+            return Ok(slot)
+        }
+        let bits = (slot >> 1) - 1;
+        let base = (2 | (slot & 1)) << bits;
+        if slot < 14 {
+            let probs = &mut self.pos_special[(base - slot) as usize..];
+            self.rc.reverse(input, probs, bits).map(|low| base + low)
+        } else {
+            let middle = try!(self.rc.direct(input, bits - 4)) << 4;
+            let low = try!(self.rc.reverse(input, &mut self.align, 4));
+            Ok(base + middle + low)
+        }
+    }
+
+    // Decodes into `out`, producing no more than `*left` bytes, and returns
+    // the number of bytes produced and whether the end marker was found.
+    // A match running past `*left` stays pending.
+    fn decode<R: Read>(&mut self, input: &mut Input<R>, out: &mut [u8], left: &mut u64)
+                       -> io::Result<(usize, bool)> {
+        let limit = cmp::min(out.len() as u64, *left) as usize;
+        let out = &mut out[..limit];
+        let pb_mask = (1 << self.props.pb) - 1;
+        let mut n = 0;
+        let end = loop {
+            while self.pending > 0 && n < out.len() {
+                let b = self.dict.get(self.reps[0] as usize + 1);
+                self.put(out, &mut n, b);
+                self.pending -= 1;
+            }
+            if n == out.len() {
+                break false
+            }
+
+            let s = self.state;
+            let pos_state = self.dict.total as usize & pb_mask;
+            if try!(self.rc.bit(input, &mut self.is_match[s * POS_STATES + pos_state])) == 0 {
+                let b = try!(self.literal(input));
+                self.put(out, &mut n, b);
+                self.state = if s < 4 { 0 } else if s < 10 { s - 3 } else { s - 6 };
+                continue
+            }
+
+            let len = if try!(self.rc.bit(input, &mut self.is_rep[s])) == 0 {
+                let len = try!(self.len.decode(&mut self.rc, input, pos_state));
+                let dist = try!(self.distance(input, len));
+                if dist == END_MARKER {
+                    break true
+                }
+                self.state = if s < 7 { 7 } else { 10 };
+                self.reps = [dist, self.reps[0], self.reps[1], self.reps[2]];
+                len
+            } else {
+                if try!(self.rc.bit(input, &mut self.is_rep0[s])) == 0 {
+                    if try!(self.rc.bit(input, &mut self.is_rep0_long[s * POS_STATES + pos_state])) == 0 {
+                        // a single byte at the last distance
+                        if !self.dict.has(self.reps[0] as usize + 1) {
+                            return error("LZMA match distance beyond the data")
+                        }
+                        self.state = if s < 7 { 9 } else { 11 };
+                        let b = self.dict.get(self.reps[0] as usize + 1);
+                        self.put(out, &mut n, b);
+                        continue
+                    }
+                } else {
+                    let dist;
+                    if try!(self.rc.bit(input, &mut self.is_rep1[s])) == 0 {
+                        dist = self.reps[1];
+                    } else {
+                        if try!(self.rc.bit(input, &mut self.is_rep2[s])) == 0 {
+                            dist = self.reps[2];
+                        } else {
+                            dist = self.reps[3];
+                            self.reps[3] = self.reps[2];
+                        }
+                        self.reps[2] = self.reps[1];
+                    }
+                    self.reps[1] = self.reps[0];
+                    self.reps[0] = dist;
+                }
+                self.state = if s < 7 { 8 } else { 11 };
+                try!(self.rep_len.decode(&mut self.rc, input, pos_state))
+            };
+            if !self.dict.has(self.reps[0] as usize + 1) {
+                return error("LZMA match distance beyond the data")
+            }
+            self.pending = len + MIN_MATCH_LEN;
+        };
+        *left -= n as u64;
+        Ok((n, end))
+    }
+}
+
+/// Decompresses a `.lzma` file, or a raw LZMA stream with `raw`. The
+/// header is read on the first call to `read`.
+pub struct Decoder<R> {
+    input: Input<R>,
+    // the properties, dictionary size and length of a raw stream, or those
+    // still to be read from the header
+    setup: Option<(Props, u32, Option<u64>)>,
+    lzma: Option<Lzma>,
+    left: u64,
+    known_size: bool,
+    dict_limit: usize,
+    done: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder of a `.lzma` file, starting with the 13 byte header
+    /// giving the properties, dictionary size and decompressed size
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            input: Input::new(r),
+            setup: None,
+            lzma: None,
+            left: 0,
+            known_size: false,
+            dict_limit: DEFAULT_DICT_LIMIT,
+            done: false,
+        }
+    }
+
+    /// Creates a decoder of a raw LZMA stream, without a header. Without a
+    /// `size`, the stream has to end with the end marker.
+    pub fn raw(r: R, props: Props, dict_size: u32, size: Option<u64>) -> Decoder<R> {
+        let mut d = Decoder::new(r);
+        d.setup = Some((props, dict_size, size));
+        d
+    }
+
+    /// Sets the largest dictionary the stream may declare, see the module
+    /// documentation
+    pub fn set_dict_limit(&mut self, limit: usize) {
+        self.dict_limit = limit;
+    }
+
+    /// Returns the underlying reader. Input read ahead of the end of the
+    /// stream is lost.
+    pub fn into_inner(self) -> R {
+        self.input.r
+    }
+
+    fn start(&mut self) -> io::Result<Lzma> {
+        let (props, dict_size, size) = match self.setup {
+            Some(setup) => setup,
+            None => {
+                let mut header = [0; 13];
+                try!(self.input.bytes(&mut header));
+                let props = match Props::from_byte(header[0]) {
+                    Some(props) => props,
+                    None => return error("invalid LZMA properties"),
+                };
+                let dict_size = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+                let mut size = [0; 8];
+                size.copy_from_slice(&header[5..]);
+                let size = match u64::from_le_bytes(size) {
+                    0xFFFF_FFFF_FFFF_FFFF => None,
+                    size => Some(size),
+                };
+                (props, dict_size, size)
+            }
+        };
+        if !props.is_valid() {
+            return error("invalid LZMA properties")
+        }
+        let dict_size = try!(check_dict_size(dict_size as u64, self.dict_limit));
+        self.known_size = size.is_some();
+        self.left = size.unwrap_or(u64::MAX);
+        let mut lzma = Lzma::new(props, dict_size);
+        lzma.rc = try!(RangeDecoder::new(&mut self.input));
+        Ok(lzma)
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.lzma.is_none() {
+            self.lzma = Some(try!(self.start()));
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0)
+        }
+        if self.left == 0 {
+            // an empty stream of known size
+            self.done = true;
+            return Ok(0)
+        }
+        let lzma = self.lzma.as_mut().unwrap();
+        let (n, end) = try!(lzma.decode(&mut self.input, buf, &mut self.left));
+        if end {
+            if self.known_size && self.left > 0 {
+                return error("LZMA end marker before the end of the data")
+            }
+            if !lzma.rc.is_finished() {
+                return error("corrupt LZMA end marker")
+            }
+            self.done = true;
+        } else if self.left == 0 {
+            if lzma.pending > 0 {
+                return error("LZMA match past the end of the data")
+            }
+            self.done = true;
+        }
+        Ok(n)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Chunk {
+    // expecting a control byte
+    Start,
+    Uncompressed { left: u64 },
+    Lzma { left: u64, end: u64 },
+    End,
+}
+
+// LZMA2 on top of a shared input, for `Lzma2Decoder` and the xz blocks
+struct Lzma2 {
+    lzma: Lzma,
+    chunk: Chunk,
+    need_dict_reset: bool,
+    need_props: bool,
+}
+
+impl Lzma2 {
+    fn new(dict_size: usize) -> Lzma2 {
+        Lzma2 {
+            lzma: Lzma::new(Props::default(), dict_size),
+            chunk: Chunk::Start,
+            need_dict_reset: true,
+            need_props: true,
+        }
+    }
+
+    fn start_chunk<R: Read>(&mut self, input: &mut Input<R>) -> io::Result<Chunk> {
+        let control = try!(input.byte());
+        if control == 0 {
+            return Ok(Chunk::End)
+        }
+        if control >= 0xE0 || control == 1 {
+            self.lzma.dict.reset();
+            self.need_dict_reset = false;
+            self.need_props = true;
+        } else if self.need_dict_reset {
+            return error("LZMA2 stream doesn't start with a dictionary reset")
+        }
+        if control < 0x80 {
+            if control > 2 {
+                return error("invalid LZMA2 control byte")
+            }
+            let size = try!(input.u16_be()) as u64 + 1;
+            return Ok(Chunk::Uncompressed { left: size })
+        }
+
+        let unpacked = ((control as u64 & 0x1F) << 16) + try!(input.u16_be()) as u64 + 1;
+        let packed = try!(input.u16_be()) as u64 + 1;
+        if control >= 0xC0 {
+            let props = match Props::from_byte(try!(input.byte())) {
+                Some(props) if props.lc + props.lp <= 4 => props,
+                _ => return error("invalid LZMA2 properties"),
+            };
+            self.need_props = false;
+            self.lzma.reset_state(props);
+        } else if self.need_props {
+            return error("LZMA2 chunk without properties")
+        } else if control >= 0xA0 {
+            let props = self.lzma.props;
+            self.lzma.reset_state(props);
+        }
+        let end = input.consumed + packed;
+        self.lzma.rc = try!(RangeDecoder::new(input));
+        Ok(Chunk::Lzma { left: unpacked, end })
+    }
+
+    // Returns 0 at the end of the LZMA2 data only
+    fn read<R: Read>(&mut self, input: &mut Input<R>, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            match self.chunk {
+                Chunk::Start => self.chunk = try!(self.start_chunk(input)),
+                Chunk::End => return Ok(0),
+                Chunk::Uncompressed { left: 0 } => self.chunk = Chunk::Start,
+                Chunk::Uncompressed { left } => {
+                    let len = cmp::min(buf.len() as u64, left) as usize;
+                    let n = try!(input.copy(&mut buf[..len]));
+                    for &b in &buf[..n] {
+                        self.lzma.dict.put(b);
+                    }
+                    self.chunk = Chunk::Uncompressed { left: left - n as u64 };
+                    return Ok(n)
+                }
+                Chunk::Lzma { left: 0, end } => {
+                    if self.lzma.pending > 0 || !self.lzma.rc.is_finished() ||
+                       input.consumed != end {
+                        return error("corrupt LZMA2 chunk")
+                    }
+                    self.chunk = Chunk::Start;
+                }
+                Chunk::Lzma { mut left, end } => {
+                    let (n, marker) = try!(self.lzma.decode(input, buf, &mut left));
+                    if marker || input.consumed > end {
+                        return error("corrupt LZMA2 chunk")
+                    }
+                    self.chunk = Chunk::Lzma { left, end };
+                    return Ok(n)
+                }
+            }
+        }
+    }
+}
+
+/// Decompresses raw LZMA2 data, the format of the `.xz` blocks, given the
+/// dictionary size from its filter properties
+pub struct Lzma2Decoder<R> {
+    input: Input<R>,
+    lzma2: Lzma2,
+}
+
+impl<R: Read> Lzma2Decoder<R> {
+    /// Creates a decoder with a dictionary of `dict_size` bytes
+    pub fn new(r: R, dict_size: usize) -> Lzma2Decoder<R> {
+        Lzma2Decoder {
+            input: Input::new(r),
+            lzma2: Lzma2::new(cmp::max(dict_size, MIN_DICT_SIZE)),
+        }
+    }
+
+    /// Decodes the dictionary size from the one byte of LZMA2 filter
+    /// properties
+    pub fn dict_size(props: u8) -> Option<u64> {
+        match props {
+            0..=39 => Some((2 | (props as u64 & 1)) << (props / 2 + 11)),
+            40 => Some(0xFFFF_FFFF),
+            _ => None,
+        }
+    }
+
+    /// Returns the underlying reader. Input read ahead of the end of the
+    /// data is lost.
+    pub fn into_inner(self) -> R {
+        self.input.r
+    }
+}
+
+impl<R: Read> Read for Lzma2Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lzma2.read(&mut self.input, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use io::LimitExceeded;
+    use super::{Decoder, Lzma2Decoder, Props};
+
+    fn decode(data: &[u8]) -> ::std::io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Decoder::new(data).read_to_end(&mut out).map(|_| out)
+    }
+
+    #[test]
+    fn props() {
+        assert_eq!(Props::from_byte(0x5d), Some(Props::default()));
+        assert_eq!(Props::default().to_byte(), 0x5d);
+        assert_eq!(Props::from_byte(225), None);
+        assert_eq!(Lzma2Decoder::<&[u8]>::dict_size(0), Some(4096));
+        assert_eq!(Lzma2Decoder::<&[u8]>::dict_size(19), Some(3 << 20));
+        assert_eq!(Lzma2Decoder::<&[u8]>::dict_size(41), None);
+    }
+
+    #[test]
+    fn decode_file() {
+        let data = include_bytes!("../data/test.txt.lzma");
+        assert_eq!(&decode(data).unwrap()[..], &include_bytes!("../data/test.txt")[..]);
+
+        // small reads resume in the middle of matches
+        let mut d = Decoder::new(&data[..]);
+        let mut out = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match d.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(&out[..], &include_bytes!("../data/test.txt")[..]);
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = include_bytes!("../data/test.txt.lzma");
+        assert!(decode(&data[..data.len() - 10]).is_err());
+        for &at in &[0, 13, 100, 800, data.len() - 3] {
+            let mut data = data.to_vec();
+            data[at] ^= 0x10;
+            // whatever the damage, no panic and no silent success with
+            // the original output
+            if let Ok(out) = decode(&data) {
+                assert!(out[..] != include_bytes!("../data/test.txt")[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn dict_limit() {
+        let data = include_bytes!("../data/test.txt.lzma");
+        let mut d = Decoder::new(&data[..]);
+        d.set_dict_limit(1 << 16);
+        let err = d.read(&mut [0; 16]).unwrap_err();
+        assert_eq!(LimitExceeded::find(&err), Some(&LimitExceeded { limit: 1 << 16 }));
+    }
+}
@@ -0,0 +1,467 @@
+/*!
+
+`.xz` decompression
+
+An `.xz` file is made of streams, each holding blocks of compressed data,
+an index listing the sizes of the blocks and a footer repeating the stream
+flags. Every block is checked with the integrity check the stream declares:
+CRC-32 and CRC-64 are verified, while streams using SHA-256 are rejected.
+The headers, the index and the footer carry CRC-32s of their own, and the
+index has to agree with the blocks decoded. Concatenated streams, with or
+without stream padding between them, decode to the concatenation of their
+data, like `xz -d` does.
+
+Only blocks compressed with LZMA2 alone can be decoded, which is what `xz`
+writes unless given a filter chain; blocks using the BCJ or delta filters
+fail with an error.
+
+# Example
+
+```rust,no_run
+use std::fs::File;
+use std::io::Read;
+use compress::lzma::xz;
+
+let file = File::open("archive.xz").unwrap();
+let mut d = xz::Decoder::new(file);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+```
+
+*/
+
+use std::io::{self, Read};
+
+use checksum::crc;
+use super::{check_dict_size, error, Input, Lzma2, Lzma2Decoder, DEFAULT_DICT_LIMIT};
+
+/// The bytes every `.xz` stream starts with
+pub const MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+
+const FOOTER_MAGIC: [u8; 2] = *b"YZ";
+const FILTER_LZMA2: u64 = 0x21;
+
+// Variable length integer of up to 63 bits, 7 bits per byte, least
+// significant first
+fn varint<F: FnMut() -> io::Result<u8>>(mut next: F) -> io::Result<u64> {
+    let mut value = 0;
+    for i in 0..9 {
+        let b = try!(next());
+        value |= (b as u64 & 0x7F) << (7 * i);
+        if b & 0x80 == 0 {
+            if i > 0 && b == 0 {
+                return error("xz integer not minimally encoded")
+            }
+            return Ok(value)
+        }
+    }
+    error("xz integer too large")
+}
+
+fn crc32(buf: &[u8]) -> u32 {
+    let mut state = crc::State32::new();
+    state.feed(buf);
+    state.result()
+}
+
+fn le32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+enum Check {
+    None,
+    Crc32(crc::State32),
+    Crc64(crc::State<'static>),
+}
+
+impl Check {
+    fn new(id: u8) -> io::Result<Check> {
+        match id {
+            0x00 => Ok(Check::None),
+            0x01 => Ok(Check::Crc32(crc::State32::new())),
+            0x04 => Ok(Check::Crc64(crc::CRC_64_XZ.state())),
+            _ => error("unsupported xz integrity check"),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Check::None => 0,
+            Check::Crc32(_) => 4,
+            Check::Crc64(_) => 8,
+        }
+    }
+
+    fn feed(&mut self, buf: &[u8]) {
+        match *self {
+            Check::None => {}
+            Check::Crc32(ref mut state) => state.feed(buf),
+            Check::Crc64(ref mut state) => state.feed(buf),
+        }
+    }
+
+    fn reset(&mut self) {
+        match *self {
+            Check::None => {}
+            Check::Crc32(ref mut state) => state.reset(),
+            Check::Crc64(ref mut state) => state.reset(),
+        }
+    }
+
+    fn matches(&self, stored: &[u8]) -> bool {
+        let mut value = [0; 8];
+        value[..stored.len()].copy_from_slice(stored);
+        let value = u64::from_le_bytes(value);
+        match *self {
+            Check::None => true,
+            Check::Crc32(ref state) => value == state.result() as u64,
+            Check::Crc64(ref state) => value == state.result(),
+        }
+    }
+}
+
+// Input covered by a CRC-32, for the index
+struct Hashed<'a, R: 'a> {
+    input: &'a mut Input<R>,
+    crc: crc::State32,
+    size: u64,
+}
+
+impl<'a, R: Read> Hashed<'a, R> {
+    fn byte(&mut self) -> io::Result<u8> {
+        let b = try!(self.input.byte());
+        self.crc.feed(&[b]);
+        self.size += 1;
+        Ok(b)
+    }
+}
+
+// The block being decoded, and what its header says about it
+struct Block {
+    header_size: u64,
+    compressed: Option<u64>,
+    uncompressed: Option<u64>,
+    start: u64,
+    produced: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Step {
+    StreamHeader,
+    BlockHeader,
+    Block,
+    Index,
+    Footer,
+    Padding,
+    Done,
+}
+
+/// Decompresses an `.xz` file, see the module documentation
+pub struct Decoder<R> {
+    input: Input<R>,
+    step: Step,
+    flags: [u8; 2],
+    check: Check,
+    lzma2: Lzma2,
+    block: Block,
+    // unpadded and uncompressed size of the blocks of the current stream
+    records: Vec<(u64, u64)>,
+    index_size: u64,
+    dict_limit: usize,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading an `.xz` file from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            input: Input::new(r),
+            step: Step::StreamHeader,
+            flags: [0; 2],
+            check: Check::None,
+            lzma2: Lzma2::new(0),
+            block: Block { header_size: 0, compressed: None, uncompressed: None, start: 0, produced: 0 },
+            records: Vec::new(),
+            index_size: 0,
+            dict_limit: DEFAULT_DICT_LIMIT,
+        }
+    }
+
+    /// Sets the largest dictionary a block may declare, `DEFAULT_DICT_LIMIT`
+    /// unless changed. Blocks declaring more fail with an error carrying
+    /// `io::LimitExceeded`.
+    pub fn set_dict_limit(&mut self, limit: usize) {
+        self.dict_limit = limit;
+    }
+
+    /// Returns the underlying reader. Input read ahead of the end of the
+    /// file is lost.
+    pub fn into_inner(self) -> R {
+        self.input.r
+    }
+
+    fn stream_header(&mut self) -> io::Result<()> {
+        let mut header = [0; 12];
+        try!(self.input.bytes(&mut header));
+        if header[..6] != MAGIC {
+            return error("not an xz stream")
+        }
+        if le32(&header[8..]) != crc32(&header[6..8]) {
+            return error("xz stream header checksum mismatch")
+        }
+        if header[6] != 0 || header[7] & 0xF0 != 0 {
+            return error("unsupported xz stream flags")
+        }
+        self.check = try!(Check::new(header[7]));
+        self.flags = [header[6], header[7]];
+        self.records.clear();
+        Ok(())
+    }
+
+    // Reads a block header, or the index indicator, returning whether a
+    // block follows
+    fn block_header(&mut self) -> io::Result<bool> {
+        let first = try!(self.input.byte());
+        if first == 0 {
+            return Ok(false)
+        }
+        let size = (first as usize + 1) * 4;
+        let mut header = vec![0; size];
+        header[0] = first;
+        try!(self.input.bytes(&mut header[1..]));
+        if le32(&header[size - 4..]) != crc32(&header[..size - 4]) {
+            return error("xz block header checksum mismatch")
+        }
+
+        let flags = header[1];
+        if flags & 0x3C != 0 {
+            return error("unsupported xz block flags")
+        }
+        let mut fields = header[2..size - 4].iter().cloned();
+        let mut next = || fields.next().map_or(error("invalid xz block header"), Ok);
+        let compressed = if flags & 0x40 != 0 { Some(try!(varint(&mut next))) } else { None };
+        let uncompressed = if flags & 0x80 != 0 { Some(try!(varint(&mut next))) } else { None };
+        if compressed == Some(0) {
+            return error("invalid xz block header")
+        }
+        let filters = (flags & 3) + 1;
+        let id = try!(varint(&mut next));
+        let props_size = try!(varint(&mut next));
+        if filters != 1 || id != FILTER_LZMA2 {
+            return error("unsupported xz filter")
+        }
+        if props_size != 1 {
+            return error("invalid LZMA2 filter properties")
+        }
+        let dict_size = match Lzma2Decoder::<R>::dict_size(try!(next())) {
+            Some(size) => size,
+            None => return error("invalid LZMA2 filter properties"),
+        };
+        if fields.any(|b| b != 0) {
+            return error("invalid xz block header padding")
+        }
+
+        self.lzma2 = Lzma2::new(try!(check_dict_size(dict_size, self.dict_limit)));
+        self.check.reset();
+        self.block = Block {
+            header_size: size as u64,
+            compressed,
+            uncompressed,
+            start: self.input.consumed,
+            produced: 0,
+        };
+        Ok(true)
+    }
+
+    fn block_end(&mut self) -> io::Result<()> {
+        let compressed = self.input.consumed - self.block.start;
+        if self.block.compressed.is_some_and(|size| size != compressed) ||
+           self.block.uncompressed.is_some_and(|size| size != self.block.produced) {
+            return error("xz block size mismatch")
+        }
+        for _ in 0..(4 - compressed % 4) % 4 {
+            if try!(self.input.byte()) != 0 {
+                return error("invalid xz block padding")
+            }
+        }
+        let mut stored = [0; 8];
+        let stored = &mut stored[..self.check.size()];
+        try!(self.input.bytes(stored));
+        if !self.check.matches(stored) {
+            return error("xz block check mismatch")
+        }
+        let unpadded = self.block.header_size + compressed + stored.len() as u64;
+        self.records.push((unpadded, self.block.produced));
+        Ok(())
+    }
+
+    // Reads the index after its indicator byte
+    fn index(&mut self) -> io::Result<()> {
+        let mut index = Hashed { input: &mut self.input, crc: crc::State32::new(), size: 0 };
+        index.crc.feed(&[0]);
+        index.size = 1;
+        if try!(varint(|| index.byte())) != self.records.len() as u64 {
+            return error("xz index doesn't match the blocks")
+        }
+        for &(unpadded, uncompressed) in &self.records {
+            if try!(varint(|| index.byte())) != unpadded ||
+               try!(varint(|| index.byte())) != uncompressed {
+                return error("xz index doesn't match the blocks")
+            }
+        }
+        while !index.size.is_multiple_of(4) {
+            if try!(index.byte()) != 0 {
+                return error("invalid xz index padding")
+            }
+        }
+        let mut stored = [0; 4];
+        try!(index.input.bytes(&mut stored));
+        if le32(&stored) != index.crc.result() {
+            return error("xz index checksum mismatch")
+        }
+        self.index_size = index.size + 4;
+        Ok(())
+    }
+
+    fn footer(&mut self) -> io::Result<()> {
+        let mut footer = [0; 12];
+        try!(self.input.bytes(&mut footer));
+        if footer[10..] != FOOTER_MAGIC {
+            return error("invalid xz stream footer")
+        }
+        if le32(&footer) != crc32(&footer[4..10]) {
+            return error("xz stream footer checksum mismatch")
+        }
+        if (le32(&footer[4..]) as u64 + 1) * 4 != self.index_size || footer[8..10] != self.flags {
+            return error("xz stream footer doesn't match the stream")
+        }
+        Ok(())
+    }
+
+    // Skips stream padding, returning whether another stream follows
+    fn padding(&mut self) -> io::Result<bool> {
+        loop {
+            if !try!(self.input.fill()) {
+                return Ok(false)
+            }
+            if self.input.buf[self.input.pos] != 0 {
+                return Ok(true)
+            }
+            let mut padding = [0; 4];
+            try!(self.input.bytes(&mut padding));
+            if padding != [0; 4] {
+                return error("invalid xz stream padding")
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            self.step = match self.step {
+                Step::StreamHeader => {
+                    try!(self.stream_header());
+                    Step::BlockHeader
+                }
+                Step::BlockHeader => {
+                    if try!(self.block_header()) { Step::Block } else { Step::Index }
+                }
+                Step::Block => {
+                    let n = try!(self.lzma2.read(&mut self.input, buf));
+                    if n == 0 {
+                        try!(self.block_end());
+                        Step::BlockHeader
+                    } else {
+                        self.check.feed(&buf[..n]);
+                        self.block.produced += n as u64;
+                        let consumed = self.input.consumed - self.block.start;
+                        if self.block.compressed.is_some_and(|size| consumed > size) ||
+                           self.block.uncompressed.is_some_and(|size| self.block.produced > size) {
+                            return error("xz block size mismatch")
+                        }
+                        return Ok(n)
+                    }
+                }
+                Step::Index => {
+                    try!(self.index());
+                    Step::Footer
+                }
+                Step::Footer => {
+                    try!(self.footer());
+                    Step::Padding
+                }
+                Step::Padding => {
+                    if try!(self.padding()) { Step::StreamHeader } else { Step::Done }
+                }
+                Step::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+    use io::LimitExceeded;
+    use super::Decoder;
+
+    fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Decoder::new(data).read_to_end(&mut out).map(|_| out)
+    }
+
+    #[test]
+    fn decode_files() {
+        let text = &include_bytes!("../data/test.txt")[..];
+        assert_eq!(&decode(include_bytes!("../data/test.txt.xz")).unwrap()[..], text);
+        // four blocks with their sizes in the headers, checked with CRC-32
+        assert_eq!(&decode(include_bytes!("../data/test.blocks.xz")).unwrap()[..], text);
+        // two streams with padding between, the first unchecked and
+        // holding an uncompressed chunk
+        assert_eq!(&decode(include_bytes!("../data/mixed.xz")).unwrap()[..],
+                   &include_bytes!("../data/mixed.plain")[..]);
+    }
+
+    #[test]
+    fn small_reads() {
+        let mut d = Decoder::new(&include_bytes!("../data/mixed.xz")[..]);
+        let mut out = Vec::new();
+        let mut buf = [0; 13];
+        loop {
+            match d.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(&out[..], &include_bytes!("../data/mixed.plain")[..]);
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = include_bytes!("../data/test.txt.xz");
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        assert!(decode(&[]).is_err());
+        let mut padded = data.to_vec();
+        padded.extend_from_slice(&[0; 3]);
+        assert!(decode(&padded).is_err());
+        padded.push(0);
+        assert!(decode(&padded).is_ok());
+        // every byte is covered by a check
+        for at in 0..data.len() {
+            let mut data = data.to_vec();
+            data[at] ^= 0x04;
+            assert!(decode(&data).is_err(), "flipped byte {}", at);
+        }
+    }
+
+    #[test]
+    fn dict_limit() {
+        let mut d = Decoder::new(&include_bytes!("../data/test.txt.xz")[..]);
+        d.set_dict_limit(1 << 20);
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(LimitExceeded::find(&err), Some(&LimitExceeded { limit: 1 << 20 }));
+    }
+}
@@ -0,0 +1,219 @@
+/*!
+
+LZW coded image data of GIF files
+
+The pixels of a GIF image are stored as a byte giving the bits per pixel the codes start from, the
+"LZW minimum code size", followed by the LZW codes cut into sub-blocks of up to 255 bytes, each
+preceded by its length, and an empty sub-block. This module reads and writes that part of a GIF file;
+the headers and color tables around it are left to the caller.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::lzw::gif;
+
+// a 4x4 picture with four colors
+let pixels = [0u8, 0, 1, 1, 0, 0, 1, 1, 2, 2, 3, 3, 2, 2, 3, 3];
+let mut e = gif::Encoder::new(Vec::new(), 2);
+e.write_all(&pixels).unwrap();
+let (data, result) = e.finish();
+result.unwrap();
+
+let mut d = gif::Decoder::new(&data[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], &pixels[..]);
+```
+
+*/
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use super::{error, Options};
+
+const MAX_SUB_BLOCK: usize = 255;
+
+// Cuts what is written into sub-blocks, after the minimum code size
+struct SubBlocks<W> {
+    w: W,
+    code_size: Option<u8>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SubBlocks<W> {
+    fn write_blocks(&mut self, all: bool) -> io::Result<()> {
+        if let Some(size) = self.code_size.take() {
+            try!(self.w.write_all(&[size]));
+        }
+        let mut start = 0;
+        while self.buf.len() - start >= MAX_SUB_BLOCK || (all && start < self.buf.len()) {
+            let n = cmp::min(self.buf.len() - start, MAX_SUB_BLOCK);
+            try!(self.w.write_all(&[n as u8]));
+            try!(self.w.write_all(&self.buf[start..start + n]));
+            start += n;
+        }
+        self.buf.drain(..start);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for SubBlocks<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        try!(self.write_blocks(false));
+        Ok(buf.len())
+    }
+
+    // only full sub-blocks, so that flushing doesn't change the output
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_blocks(false));
+        self.w.flush()
+    }
+}
+
+/// GIF image data encoder
+pub struct Encoder<W> {
+    inner: super::Encoder<SubBlocks<W>>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder of pixels of `bits` bits, 2 to 8. Images of one bit
+    /// per pixel are coded with 2 bits.
+    pub fn new(w: W, bits: u8) -> Encoder<W> {
+        let blocks = SubBlocks { w, code_size: Some(bits), buf: Vec::new() };
+        Encoder { inner: super::Encoder::new(blocks, Options::gif(bits)) }
+    }
+
+    /// Writes the end code and the empty sub-block, and returns the wrapped
+    /// writer
+    pub fn finish(self) -> (W, io::Result<()>) {
+        let (mut blocks, result) = self.inner.finish();
+        let result = result.and_then(|_| blocks.write_blocks(true))
+                           .and_then(|_| blocks.w.write_all(&[0]))
+                           .and_then(|_| blocks.w.flush());
+        (blocks.w, result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Joins the sub-blocks up to the empty one
+struct Blocks<R> {
+    r: R,
+    left: usize,
+    done: bool,
+}
+
+impl<R: Read> Read for Blocks<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.left == 0 && !self.done {
+            let mut size = [0];
+            try!(self.r.read_exact(&mut size));
+            self.left = size[0] as usize;
+            self.done = self.left == 0;
+        }
+        let n = cmp::min(buf.len(), self.left);
+        let n = try!(self.r.read(&mut buf[..n]));
+        if n == 0 && self.left > 0 && !buf.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GIF sub-block cut short"))
+        }
+        self.left -= n;
+        Ok(n)
+    }
+}
+
+/// GIF image data decoder
+pub struct Decoder<R> {
+    r: Option<R>,
+    inner: Option<super::Decoder<Blocks<R>>>,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading image data from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder { r: Some(r), inner: None }
+    }
+
+    /// Returns the wrapped reader, which is positioned after the empty
+    /// sub-block once all the pixels have been read
+    pub fn into_inner(self) -> R {
+        match self.inner {
+            Some(inner) => inner.into_inner().r,
+            None => self.r.unwrap(),
+        }
+    }
+
+    fn read_code_size(&mut self) -> io::Result<()> {
+        let mut size = [0];
+        try!(self.r.as_mut().unwrap().read_exact(&mut size));
+        if !(2..=8).contains(&size[0]) {
+            return error("GIF LZW minimum code size out of range")
+        }
+        let blocks = Blocks { r: self.r.take().unwrap(), left: 0, done: false };
+        self.inner = Some(super::Decoder::new(blocks, Options::gif(size[0])));
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.inner.is_none() {
+            try!(self.read_code_size());
+        }
+        let inner = self.inner.as_mut().unwrap();
+        let n = try!(inner.read(buf));
+        if n == 0 && !buf.is_empty() {
+            // skip whatever follows the end code
+            try!(io::copy(&mut inner.r.r, &mut io::sink()));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Encoder, Decoder};
+
+    #[test]
+    fn roundtrip() {
+        let pixels: Vec<u8> = (0..50_000u32).map(|i| (i / 7 % 13 + i % 3) as u8).collect();
+        let mut e = Encoder::new(Vec::new(), 4);
+        e.write_all(&pixels).unwrap();
+        let (mut data, result) = e.finish();
+        result.unwrap();
+        assert_eq!(data[0], 4);
+        assert_eq!(data[1], 255);
+        assert_eq!(data[data.len() - 1], 0);
+
+        // the decoder stops after the empty sub-block
+        data.push(0x3B);
+        let mut d = Decoder::new(&data[..]);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert!(decoded == pixels);
+        assert_eq!(d.into_inner(), &[0x3B][..]);
+
+        assert!(Decoder::new(&data[..data.len() - 10]).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn reference() {
+        // the image data of the sample image of Wikipedia's GIF article
+        let data = [0x08, 0x0B, 0x00, 0x51, 0xFC, 0x1B, 0x28, 0x70, 0xA0, 0xC1, 0x83, 0x01, 0x01, 0x00];
+        let mut decoded = Vec::new();
+        Decoder::new(&data[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded.len(), 15);
+        assert_eq!((decoded[0], decoded[1], decoded[4]), (0x28, 0xFF, 0x28));
+    }
+}
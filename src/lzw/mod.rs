@@ -0,0 +1,669 @@
+/*!
+
+LZW compression, as used by the Unix `compress` tool and GIF. Requires `lzw` feature, enabled by default
+
+LZW replaces strings seen before by codes into a table built along the way: every code written adds
+the string it stands for plus the byte after it to the table, so the decoder rebuilds the same table
+from the codes alone. Codes start one bit wider than the literals and grow a bit whenever the table
+needs it, up to 16 bits. A clear code empties the table again.
+
+The formats using LZW differ in the details, which `Options` describes: `Options::compress` is the
+variant of `.Z` files, whose codes are packed least significant bit first in groups of eight, padded
+out whenever the width changes, `Options::gif` the one of GIF images and `Options::tiff` the one of
+TIFF and PDF, packing the codes most significant bit first. The `z` module reads and writes the `.Z`
+container around the codes, and `gif` the sub-blocks GIF image data is cut into.
+
+The encoder clears the table as soon as it is full when the format has an end code, like GIF
+encoders do, and otherwise keeps using the full table until the compression ratio drops, like
+`compress` does.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::lzw::{self, Options};
+
+let text = b"TOBEORNOTTOBEORTOBEORNOT";
+let mut e = lzw::Encoder::new(Vec::new(), Options::gif(8));
+e.write_all(text).unwrap();
+let (encoded, result) = e.finish();
+result.unwrap();
+
+let mut d = lzw::Decoder::new(&encoded[..], Options::gif(8));
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], &text[..]);
+```
+
+# Credit
+
+This is an original implementation. The code groups and the clearing heuristic follow `compress`
+4.x by Spencer Thomas, Jim McKie, Steve Davies, Ken Turkowski, James A. Woods and Joe Orost.
+
+*/
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use super::byteorder::ReadBytesExt;
+
+pub mod gif;
+pub mod z;
+
+/// Widest code of any LZW variant
+pub const MAX_BITS: u8 = 16;
+
+// input bytes between two looks at the compression ratio of a full table
+const CHECK_GAP: u64 = 10000;
+
+fn error<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Order in which the bits of the codes are packed into bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    /// Least significant bit first, as in `.Z` files and GIF
+    Lsb,
+    /// Most significant bit first, as in TIFF and PDF
+    Msb,
+}
+
+/// The variant of LZW to code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Bits of a literal, 2 to 8: the bytes coded have to be below
+    /// `1 << literal_bits`
+    pub literal_bits: u8,
+    /// Width of the widest code, from `literal_bits + 1` to `MAX_BITS`
+    pub max_bits: u8,
+    /// How codes are packed into bytes
+    pub order: BitOrder,
+    /// Whether code `1 << literal_bits` clears the table
+    pub clear_code: bool,
+    /// Whether the code after the clear code ends the data. The encoder then
+    /// starts with a clear code and clears the table as soon as it is full.
+    pub end_code: bool,
+    /// Whether codes widen one code earlier than the table needs, as in TIFF
+    pub early_change: bool,
+    /// Whether codes come in groups of eight, padded out when the width
+    /// changes or the table is cleared, as in `.Z` files
+    pub grouped: bool,
+}
+
+impl Options {
+    /// The variant of `.Z` files written by `compress -b max_bits`, 9 to 16
+    pub fn compress(max_bits: u8) -> Options {
+        Options {
+            literal_bits: 8,
+            max_bits,
+            order: BitOrder::Lsb,
+            clear_code: true,
+            end_code: false,
+            early_change: false,
+            grouped: true,
+        }
+    }
+
+    /// The variant of GIF image data of `literal_bits` bits per pixel,
+    /// the "LZW minimum code size" of GIF, 2 to 8
+    pub fn gif(literal_bits: u8) -> Options {
+        Options {
+            literal_bits,
+            max_bits: 12,
+            order: BitOrder::Lsb,
+            clear_code: true,
+            end_code: true,
+            early_change: false,
+            grouped: false,
+        }
+    }
+
+    /// The variant of TIFF and of PDF's `LZWDecode` filter
+    pub fn tiff() -> Options {
+        Options {
+            literal_bits: 8,
+            max_bits: 12,
+            order: BitOrder::Msb,
+            clear_code: true,
+            end_code: true,
+            early_change: true,
+            grouped: false,
+        }
+    }
+
+    fn check(&self) {
+        assert!(self.literal_bits >= 2 && self.literal_bits <= 8,
+                "LZW literals have 2 to 8 bits, not {}", self.literal_bits);
+        assert!(self.max_bits > self.literal_bits && self.max_bits <= MAX_BITS,
+                "LZW codes can't be {} bits wide", self.max_bits);
+        assert!(self.clear_code || !self.end_code, "the LZW end code comes after a clear code");
+    }
+
+    fn clear(&self) -> u32 {
+        1 << self.literal_bits
+    }
+
+    fn end(&self) -> u32 {
+        self.clear() + 1
+    }
+
+    // the first code of the table
+    fn first(&self) -> u32 {
+        self.clear() + self.clear_code as u32 + self.end_code as u32
+    }
+
+    fn table_size(&self) -> u32 {
+        1 << self.max_bits
+    }
+}
+
+// The width of the codes, which the encoder and the decoder change at the
+// same points: after the code which brings the table as the decoder sees it
+// to the next power of two
+struct Width {
+    options: Options,
+    bits: u8,
+    // the table size the decoder reaches after the last code, one code
+    // behind the encoder
+    seen: u32,
+}
+
+impl Width {
+    fn new(options: Options) -> Width {
+        Width {
+            options,
+            bits: options.literal_bits + 1,
+            seen: options.first() - 1,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Width::new(self.options);
+    }
+
+    // Counts a code other than the clear and end codes, returns whether the
+    // codes after it are wider
+    fn step(&mut self) -> bool {
+        self.seen = cmp::min(self.seen + 1, self.options.table_size());
+        let widen = self.bits < self.options.max_bits &&
+            self.seen + self.options.early_change as u32 >= 1 << self.bits;
+        self.bits += widen as u8;
+        widen
+    }
+}
+
+struct BitWriter<W> {
+    w: W,
+    order: BitOrder,
+    acc: u64,
+    bits: u32,
+    // bits written since the start of the current code group
+    group: u64,
+    buf: Vec<u8>,
+    // bytes written in all
+    written: u64,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn put(&mut self, code: u32, width: u8) -> io::Result<()> {
+        let width = width as u32;
+        match self.order {
+            BitOrder::Lsb => {
+                self.acc |= (code as u64) << self.bits;
+                self.bits += width;
+                while self.bits >= 8 {
+                    self.buf.push(self.acc as u8);
+                    self.acc >>= 8;
+                    self.bits -= 8;
+                }
+            }
+            BitOrder::Msb => {
+                self.acc = (self.acc << width) | code as u64;
+                self.bits += width;
+                while self.bits >= 8 {
+                    self.bits -= 8;
+                    self.buf.push((self.acc >> self.bits) as u8);
+                }
+                self.acc &= (1 << self.bits) - 1;
+            }
+        }
+        self.group += width as u64;
+        if self.buf.len() >= 4096 {
+            self.written += self.buf.len() as u64;
+            try!(self.w.write_all(&self.buf));
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    // Pads the current group of eight `width` bit codes out
+    fn pad(&mut self, width: u8) -> io::Result<()> {
+        let size = 8 * width as u64;
+        let mut left = (size - self.group % size) % size;
+        while left > 0 {
+            let n = cmp::min(left, 16);
+            try!(self.put(0, n as u8));
+            left -= n;
+        }
+        self.group = 0;
+        Ok(())
+    }
+
+    // Writes out the last partial byte and everything buffered
+    fn flush(&mut self) -> io::Result<()> {
+        if self.bits > 0 {
+            let last = match self.order {
+                BitOrder::Lsb => self.acc,
+                BitOrder::Msb => self.acc << (8 - self.bits),
+            };
+            self.buf.push(last as u8);
+            self.acc = 0;
+            self.bits = 0;
+        }
+        self.written += self.buf.len() as u64;
+        try!(self.w.write_all(&self.buf));
+        self.buf.clear();
+        self.w.flush()
+    }
+}
+
+/// LZW encoder
+pub struct Encoder<W> {
+    w: BitWriter<W>,
+    options: Options,
+    width: Width,
+    // (code << 8 | byte) -> code of the string plus the byte
+    table: HashMap<u32, u16>,
+    free: u32,
+    // code of the string matched so far
+    current: Option<u16>,
+    started: bool,
+    // what `compress` looks at to decide when to clear a full table
+    read: u64,
+    checkpoint: u64,
+    ratio: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder of the given variant writing into `w`
+    pub fn new(w: W, options: Options) -> Encoder<W> {
+        options.check();
+        Encoder {
+            w: BitWriter {
+                w,
+                order: options.order,
+                acc: 0,
+                bits: 0,
+                group: 0,
+                buf: Vec::new(),
+                written: 0,
+            },
+            options,
+            width: Width::new(options),
+            table: HashMap::new(),
+            free: options.first(),
+            current: None,
+            started: false,
+            read: 0,
+            checkpoint: CHECK_GAP,
+            ratio: 0,
+        }
+    }
+
+    fn put(&mut self, code: u32) -> io::Result<()> {
+        try!(self.w.put(code, self.width.bits));
+        if self.width.step() && self.options.grouped {
+            try!(self.w.pad(self.width.bits - 1));
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        try!(self.w.put(self.options.clear(), self.width.bits));
+        if self.options.grouped {
+            try!(self.w.pad(self.width.bits));
+        }
+        self.width.reset();
+        self.table.clear();
+        self.free = self.options.first();
+        self.ratio = 0;
+        Ok(())
+    }
+
+    // Clears a full table if the compression ratio has dropped since the
+    // last checkpoint
+    fn check_ratio(&mut self) -> io::Result<()> {
+        if self.read < self.checkpoint {
+            return Ok(())
+        }
+        self.checkpoint = self.read + CHECK_GAP;
+        let written = self.w.written + self.w.buf.len() as u64;
+        let ratio = (self.read << 8) / cmp::max(written, 1);
+        if ratio > self.ratio {
+            self.ratio = ratio;
+            Ok(())
+        } else {
+            self.clear()
+        }
+    }
+
+    /// Writes the last code and returns the wrapped writer
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        let result = self.finish_codes();
+        (self.w.w, result)
+    }
+
+    fn finish_codes(&mut self) -> io::Result<()> {
+        try!(self.start());
+        if let Some(code) = self.current.take() {
+            try!(self.put(code as u32));
+        }
+        if self.options.end_code {
+            try!(self.w.put(self.options.end(), self.width.bits));
+        }
+        self.w.flush()
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if !self.started && self.options.end_code {
+            try!(self.w.put(self.options.clear(), self.width.bits));
+        }
+        self.started = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.start());
+        let limit = self.options.table_size() - self.options.early_change as u32;
+        for &byte in buf.iter() {
+            if byte as u32 >= self.options.clear() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "byte too large for the LZW literals"))
+            }
+            self.read += 1;
+            let code = match self.current {
+                Some(code) => code,
+                None => {
+                    self.current = Some(byte as u16);
+                    continue
+                }
+            };
+            let key = (code as u32) << 8 | byte as u32;
+            if let Some(&next) = self.table.get(&key) {
+                self.current = Some(next);
+                continue
+            }
+            try!(self.put(code as u32));
+            self.current = Some(byte as u16);
+            if self.free < limit {
+                self.table.insert(key, self.free as u16);
+                self.free += 1;
+                if self.free == limit && self.options.end_code {
+                    try!(self.clear());
+                }
+            } else if self.options.clear_code {
+                try!(self.check_ratio());
+            }
+        }
+        Ok(buf.len())
+    }
+
+    /// Writes out the complete bytes of the codes so far. The string being
+    /// matched stays open, so flushing doesn't change the output.
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.written += self.w.buf.len() as u64;
+        try!(self.w.w.write_all(&self.w.buf));
+        self.w.buf.clear();
+        self.w.w.flush()
+    }
+}
+
+struct BitReader<R> {
+    r: R,
+    order: BitOrder,
+    acc: u64,
+    bits: u32,
+    // bits read since the start of the current code group
+    group: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    // Reads a code, `None` at the end of the input
+    fn get(&mut self, width: u8) -> io::Result<Option<u32>> {
+        let width = width as u32;
+        while self.bits < width {
+            let byte = match self.r.read_u8() {
+                Ok(byte) => byte as u64,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            match self.order {
+                BitOrder::Lsb => self.acc |= byte << self.bits,
+                BitOrder::Msb => self.acc = (self.acc << 8) | byte,
+            }
+            self.bits += 8;
+        }
+        let mask = (1 << width) - 1;
+        let code = match self.order {
+            BitOrder::Lsb => {
+                let code = self.acc & mask;
+                self.acc >>= width;
+                code
+            }
+            BitOrder::Msb => (self.acc >> (self.bits - width)) & mask,
+        };
+        self.bits -= width;
+        if self.order == BitOrder::Msb {
+            self.acc &= (1 << self.bits) - 1;
+        }
+        self.group += width as u64;
+        Ok(Some(code as u32))
+    }
+
+    // Skips to the end of the current group of eight `width` bit codes
+    fn pad(&mut self, width: u8) -> io::Result<()> {
+        let size = 8 * width as u64;
+        let mut left = (size - self.group % size) % size;
+        while left > 0 {
+            let n = cmp::min(left, 16);
+            if try!(self.get(n as u8)).is_none() {
+                break
+            }
+            left -= n;
+        }
+        self.group = 0;
+        Ok(())
+    }
+}
+
+/// LZW decoder
+pub struct Decoder<R> {
+    r: BitReader<R>,
+    options: Options,
+    width: Width,
+    // the strings of the table, as the code of their prefix and their last byte
+    prefix: Vec<u16>,
+    suffix: Vec<u8>,
+    previous: Option<u16>,
+    string: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder of the given variant reading from `r`
+    pub fn new(r: R, options: Options) -> Decoder<R> {
+        options.check();
+        Decoder {
+            r: BitReader {
+                r,
+                order: options.order,
+                acc: 0,
+                bits: 0,
+                group: 0,
+            },
+            options,
+            width: Width::new(options),
+            prefix: Vec::new(),
+            suffix: Vec::new(),
+            previous: None,
+            string: Vec::new(),
+            out: Vec::new(),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    /// Returns the wrapped reader. After the end code this is positioned
+    /// right after the byte holding it.
+    pub fn into_inner(self) -> R {
+        self.r.r
+    }
+
+    // Decodes the next code into `out`, or finds the end
+    fn next(&mut self) -> io::Result<()> {
+        let code = match try!(self.r.get(self.width.bits)) {
+            Some(code) => code,
+            None => {
+                self.eof = true;
+                return Ok(())
+            }
+        };
+        let options = self.options;
+        if options.clear_code && code == options.clear() {
+            if options.grouped {
+                try!(self.r.pad(self.width.bits));
+            }
+            self.width.reset();
+            self.prefix.clear();
+            self.suffix.clear();
+            self.previous = None;
+            return Ok(())
+        }
+        if options.end_code && code == options.end() {
+            self.eof = true;
+            return Ok(())
+        }
+
+        let first = options.first();
+        let free = first + self.prefix.len() as u32;
+        let known = code < options.clear() || (code >= first && code < free);
+        let mut c = match self.previous {
+            _ if known => code,
+            // the string about to be added, its last byte being its first
+            Some(previous) if code == free && free < options.table_size() => previous as u32,
+            _ => return error("invalid LZW code"),
+        };
+        self.string.clear();
+        while c >= first {
+            let i = (c - first) as usize;
+            self.string.push(self.suffix[i]);
+            c = self.prefix[i] as u32;
+        }
+        let head = c as u8;
+        self.string.push(head);
+        self.out.clear();
+        self.pos = 0;
+        self.out.extend(self.string.iter().rev());
+        if !known {
+            self.out.push(head);
+        }
+        if let Some(previous) = self.previous {
+            if free < options.table_size() {
+                self.prefix.push(previous);
+                self.suffix.push(head);
+            }
+        }
+        self.previous = Some(code as u16);
+        if self.width.step() && options.grouped {
+            try!(self.r.pad(self.width.bits - 1));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() && !self.eof && !buf.is_empty() {
+            try!(self.next());
+        }
+        let n = cmp::min(buf.len(), self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Encoder, Decoder, Options, BitOrder};
+
+    fn encode(input: &[u8], options: Options) -> Vec<u8> {
+        let mut e = Encoder::new(Vec::new(), options);
+        e.write_all(input).unwrap();
+        let (encoded, result) = e.finish();
+        result.unwrap();
+        encoded
+    }
+
+    fn decode(encoded: &[u8], options: Options) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        Decoder::new(encoded, options).read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        let text = include_bytes!("../data/test.txt");
+        let large = &include_bytes!("../data/test.large")[..1 << 20];
+        let msb = Options { order: BitOrder::Msb, ..Options::compress(12) };
+        let plain = Options { clear_code: false, ..Options::compress(10) };
+        for &options in [Options::compress(16), Options::compress(9), Options::tiff(), Options::gif(8), msb,
+                         plain].iter() {
+            for input in [&b""[..], b"a", b"TOBEORNOTTOBEORTOBEORNOT", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaa", text,
+                          large].iter() {
+                let encoded = encode(input, options);
+                assert!(decode(&encoded, options)[..] == input[..], "{:?} failed", options);
+            }
+        }
+    }
+
+    #[test]
+    fn gif_pixels() {
+        // two bits per pixel, runs and noise
+        let pixels: Vec<u8> = (0..100_000u32).map(|i| {
+            if i % 1000 < 500 { (i / 1000 % 4) as u8 } else { (i.wrapping_mul(2654435761) >> 30) as u8 }
+        }).collect();
+        let encoded = encode(&pixels, Options::gif(2));
+        assert!(decode(&encoded, Options::gif(2)) == pixels);
+
+        let mut e = Encoder::new(Vec::new(), Options::gif(2));
+        assert!(e.write_all(&[4]).is_err());
+    }
+
+    #[test]
+    fn reference() {
+        // the sample image of Wikipedia's GIF article, 3x5 pixels
+        let encoded = [0x00, 0x51, 0xFC, 0x1B, 0x28, 0x70, 0xA0, 0xC1, 0x83, 0x01, 0x01];
+        let mut pixels = vec![0xFF; 15];
+        pixels[0] = 0x28;
+        pixels[4] = 0x28;
+        assert_eq!(decode(&encoded, Options::gif(8)), pixels);
+    }
+
+    #[test]
+    fn corrupt() {
+        let encoded = encode(include_bytes!("../data/test.txt"), Options::gif(8));
+        let mut bad = encoded.clone();
+        // a code past the end of the table
+        bad[3] = 0xFF;
+        bad[4] = 0xFF;
+        let mut decoded = Vec::new();
+        assert!(Decoder::new(&bad[..], Options::gif(8)).read_to_end(&mut decoded).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+/*!
+
+`.Z` files of the Unix `compress` tool
+
+A `.Z` file is the `MAGIC` bytes, a byte holding the width of the widest code in its low five bits and
+`BLOCK_MODE`, then the LZW codes as `Options::compress` packs them. Files without `BLOCK_MODE`, from
+the oldest versions of `compress`, have no clear code. `gzip -d` reads the files written here.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::lzw::z;
+
+let mut e = z::Encoder::new(Vec::new());
+e.write_all(b"some text, some text").unwrap();
+let (file, result) = e.finish();
+result.unwrap();
+
+let mut d = z::Decoder::new(&file[..]);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], b"some text, some text");
+```
+
+*/
+
+use std::io::{self, Read, Write};
+
+use super::{error, Options, MAX_BITS};
+
+/// The bytes every `.Z` file starts with
+pub const MAGIC: [u8; 2] = [0x1F, 0x9D];
+
+/// Flag of the third header byte telling that code 256 clears the table
+pub const BLOCK_MODE: u8 = 0x80;
+
+const BITS_MASK: u8 = 0x1F;
+
+/// `.Z` file encoder
+pub struct Encoder<W> {
+    inner: super::Encoder<W>,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder using codes of up to 16 bits, like `compress`
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder::with_max_bits(w, MAX_BITS)
+    }
+
+    /// Creates an encoder using codes of up to `max_bits` bits, 10 to 16,
+    /// like `compress -b max_bits`. Files of 9 bit codes aren't written, as
+    /// `gzip -d` widens their codes to 10 bits once the table is full.
+    pub fn with_max_bits(w: W, max_bits: u8) -> Encoder<W> {
+        assert!((10..=MAX_BITS).contains(&max_bits), ".Z codes can't be {} bits wide", max_bits);
+        let mut inner = super::Encoder::new(w, Options::compress(max_bits));
+        inner.w.buf.extend_from_slice(&MAGIC);
+        inner.w.buf.push(max_bits | BLOCK_MODE);
+        Encoder { inner }
+    }
+
+    /// Writes the last code and returns the wrapped writer
+    pub fn finish(self) -> (W, io::Result<()>) {
+        self.inner.finish()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// `.Z` file decoder
+pub struct Decoder<R> {
+    r: Option<R>,
+    inner: Option<super::Decoder<R>>,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading a `.Z` file from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder { r: Some(r), inner: None }
+    }
+
+    /// Returns the wrapped reader
+    pub fn into_inner(self) -> R {
+        match self.inner {
+            Some(inner) => inner.into_inner(),
+            None => self.r.unwrap(),
+        }
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let mut header = [0; 3];
+        try!(self.r.as_mut().unwrap().read_exact(&mut header));
+        if header[..2] != MAGIC {
+            return error("not a .Z file")
+        }
+        let max_bits = header[2] & BITS_MASK;
+        if header[2] & !(BITS_MASK | BLOCK_MODE) != 0 {
+            return error("unknown .Z header flags")
+        }
+        if !(9..=MAX_BITS).contains(&max_bits) {
+            return error(".Z code width out of range")
+        }
+        let options = Options {
+            clear_code: header[2] & BLOCK_MODE != 0,
+            ..Options::compress(max_bits)
+        };
+        self.inner = Some(super::Decoder::new(self.r.take().unwrap(), options));
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.inner.is_none() {
+            try!(self.read_header());
+        }
+        self.inner.as_mut().unwrap().read(buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use super::{Encoder, Decoder};
+
+    fn decode(file: &[u8]) -> Vec<u8> {
+        let mut decoded = Vec::new();
+        Decoder::new(file).read_to_end(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn roundtrips() {
+        let input = &include_bytes!("../data/test.large")[..1 << 20];
+        for &bits in [10, 12, 16].iter() {
+            let mut e = Encoder::with_max_bits(Vec::new(), bits);
+            e.write_all(input).unwrap();
+            let (file, result) = e.finish();
+            result.unwrap();
+            assert!(decode(&file)[..] == input[..], "{} bits failed", bits);
+        }
+    }
+
+    #[test]
+    fn decode_file() {
+        // `gzip -d` decodes it as well
+        let file = include_bytes!("../data/test.txt.Z");
+        assert!(decode(file)[..] == include_bytes!("../data/test.txt")[..]);
+    }
+
+    #[test]
+    fn bad_headers() {
+        for bad in [&b""[..], b"\x1F\x9D", b"\x1F\x8B\x90", b"\x1F\x9D\x98", b"\x1F\x9D\xB0"].iter() {
+            assert!(Decoder::new(*bad).read_to_end(&mut Vec::new()).is_err());
+        }
+        assert!(decode(b"\x1F\x9D\x90").is_empty());
+    }
+}
@@ -5,15 +5,20 @@
 //! Example invocations:
 //! echo -n "abracadabra" | ./app bwt | xxd
 //! echo "banana" | ./app bwt | ./app -d
+//! ./app -roundtrip bwt mtf ari <file
 
-#[macro_use] extern crate log;
 extern crate compress;
 extern crate byteorder;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs::File;
 use std::io::{self, Read, Write};
-use std::{env, str};
-use compress::{bwt, lz4, ReadExact};
+use std::rc::Rc;
+use std::{cmp, env, panic, process, str};
+use compress::{bwt, lz4, scramble, ReadExact};
+use compress::io::SparseWriter;
+use compress::pipeline::Pipeline;
 use compress::entropy::ari;
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
 
@@ -22,8 +27,12 @@ static MAGIC    : u32   = 0x73632172;   //=r!cs
 struct Config {
     exe_name: String,
     methods: Vec<String>,
+    filters: Option<Pipeline>,
     block_size: usize,
+    key: [u8; scramble::KEY_BYTES],
     decompress: bool,
+    roundtrip: bool,
+    output: Option<String>,
 }
 
 impl Config {
@@ -31,21 +40,41 @@ impl Config {
         let mut cfg = Config {
             exe_name: args.next().unwrap().clone(),
             methods: Vec::new(),
+            filters: None,
             block_size: 1<<16,
+            key: [0; scramble::KEY_BYTES],
             decompress: false,
+            roundtrip: false,
+            output: None,
         };
         let mut handlers: HashMap<&str, Box<dyn FnMut(&str, &mut Config)>> =
             HashMap::new();
         handlers.insert("d", Box::new(|_, cfg| { cfg.decompress = true; }));
+        handlers.insert("roundtrip", Box::new(|_, cfg| { cfg.roundtrip = true; }));
+        handlers.insert("out", Box::new(|path, cfg| {
+            cfg.output = Some(path.trim_start_matches('=').to_string());
+        }));
         handlers.insert("block", Box::new(|b, cfg| {
             cfg.block_size = b.parse().unwrap();
         }));
+        handlers.insert("filters", Box::new(|f, cfg| {
+            match f.trim_start_matches('=').parse() {
+                Ok(p) => cfg.filters = Some(p),
+                Err(e) => panic!("{}", e),
+            }
+        }));
+        handlers.insert("key", Box::new(|k, cfg| {
+            let n = std::cmp::min(k.len(), scramble::KEY_BYTES);
+            cfg.key = [0; scramble::KEY_BYTES];
+            cfg.key[..n].copy_from_slice(&k.as_bytes()[..n]);
+        }));
 
         for arg in args {
 			let slice = &arg[..];
             if slice.starts_with("-") {
-                match handlers.iter_mut().find(|&(&k,_)| slice[1..].starts_with(k)) {
-                    Some((k,h)) => (*h)(&slice[1+k.len()..], &mut cfg),
+                let opt = slice.trim_start_matches('-');
+                match handlers.iter_mut().find(|&(&k,_)| opt.starts_with(k)) {
+                    Some((k,h)) => (*h)(&opt[k.len()..], &mut cfg),
                     None => println!("Warning: unrecognized option: {}", &arg[..]),
                 }
             }else {
@@ -64,6 +93,99 @@ struct Pass {
     info: String,
 }
 
+/// Writer handing its output back after the pass chain owning it is gone
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+fn hex_dump(label: &str, data: &[u8], start: usize, mark: usize) {
+    let end = cmp::min(data.len(), start + 32);
+    let bytes: Vec<String> = (start..end).map(|i| if i == mark {
+        format!("[{:02x}]", data[i])
+    } else {
+        format!("{:02x}", data[i])
+    }).collect();
+    println!("\t{:<9} @{:08x}: {}", label, start, bytes.join(" "));
+}
+
+/// Compresses the input with the given passes, decompresses it again and
+/// reports where the result first differs from the input.
+fn debug_roundtrip(passes: &mut HashMap<String,Pass>, config: &Config,
+                   original: &[u8]) -> bool {
+    let sink = SharedBuf::default();
+    {
+        let mut wsum: Box<dyn Write> = Box::new(sink.clone());
+        for met in config.methods.iter() {
+            match passes.get_mut(met) {
+                Some(pa) => wsum = (pa.encode)(wsum, config),
+                None => panic!("Pass {} is not implemented", *met)
+            }
+        }
+        if let Err(e) = wsum.write_all(original).and_then(|_| wsum.flush()) {
+            println!("Encoding failed: {}", e);
+            return false
+        }
+    }
+    let encoded = sink.0.borrow().clone();
+    let ratio = encoded.len() * 10000 / cmp::max(original.len(), 1);
+    println!("Encoded {} bytes into {} ({}.{:02}%) with {}", original.len(), encoded.len(),
+             ratio / 100, ratio % 100, config.methods.join(" "));
+
+    let mut rsum: Box<dyn Read> = Box::new(io::Cursor::new(encoded));
+    for met in config.methods.iter() {
+        rsum = (passes.get_mut(met).unwrap().decode)(rsum, config);
+    }
+    // a panicking decoder still leaves behind what it produced so far
+    let mut decoded = Vec::new();
+    let result = match panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        rsum.read_to_end(&mut decoded)
+    })) {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "decoder panicked")),
+    };
+
+    let mismatch = original.iter().zip(decoded.iter()).position(|(a, b)| a != b);
+    let offset = match mismatch {
+        Some(offset) => offset,
+        None if decoded.len() != original.len() => cmp::min(decoded.len(), original.len()),
+        None => match result {
+            Ok(_) => {
+                println!("Roundtrip OK");
+                return true
+            }
+            Err(e) => {
+                println!("Output matches but decoding failed at the end: {}", e);
+                return false
+            }
+        },
+    };
+    println!("Mismatch at offset {} (0x{:x}), decoded {} of {} bytes",
+             offset, offset, decoded.len(), original.len());
+    if let Err(e) = result {
+        println!("Decoding stopped with: {}", e);
+    }
+    let start = offset - cmp::min(offset, 16);
+    hex_dump("expected", original, start, offset);
+    hex_dump("decoded", &decoded, start, offset);
+    false
+}
+
+/// Returns `len` as a length byte of the archive header, giving up when
+/// it doesn't fit rather than writing a header which reads back wrong.
+fn header_len(what: &str, len: usize) -> u8 {
+    if len > u8::MAX as usize {
+        eprintln!("{} is {} long, the archive header holds at most {}", what, len, u8::MAX);
+        process::exit(1);
+    }
+    len as u8
+}
 
 /// main entry point
 pub fn main() {
@@ -84,10 +206,11 @@ pub fn main() {
     });
     passes.insert("bwt".to_string(), Pass {
         encode: Box::new(|w,c| {
-            Box::new(bwt::Encoder::new(w, c.block_size)) as Box<dyn Write + 'static>
+            Box::new(bwt::Encoder::new(w, c.block_size).unwrap()) as Box<dyn Write + 'static>
         }),
         decode: Box::new(|r,_c| {
-            Box::new(bwt::Decoder::new(r, true)) as Box<dyn Read + 'static>
+            // archives of earlier versions hold headerless streams
+            Box::new(bwt::Decoder::legacy(r, true)) as Box<dyn Read + 'static>
         }),
         info: "Burrows-Wheeler Transformation".to_string(),
     });
@@ -100,6 +223,16 @@ pub fn main() {
         }),
         info: "Move-To-Front Transformation".to_string(),
     });
+    passes.insert("scramble".to_string(), Pass {
+        encode: Box::new(|w,c| {
+            // a fresh nonce for every stream, stored ahead of it
+            Box::new(scramble::Encoder::with_random_nonce(w, &c.key).unwrap()) as Box<dyn Write + 'static>
+        }),
+        decode: Box::new(|r,c| {
+            Box::new(scramble::Decoder::with_header(r, &c.key)) as Box<dyn Read + 'static>
+        }),
+        info: "Keyed ChaCha20 obfuscation (not encryption)".to_string(),
+    });
     /* // looks like we are missing the encoder implementation
     passes.insert(~"flate", Pass {
         encode: |w,_c| {
@@ -127,12 +260,12 @@ pub fn main() {
         assert!(config.methods.is_empty(), "Decompression methods are set in stone");
         match input.read_u32::<LittleEndian>() {
             Ok(magic) if magic != MAGIC => {
-                error!("Input is not a rust-compress archive");
-                return
+                eprintln!("Input is not a rust-compress archive");
+                process::exit(1);
             },
             Err(e) => {
-                error!("Unable to read input: {:?}", e);
-                return
+                eprintln!("Unable to read input: {}", e);
+                process::exit(1);
             },
             _ => () //OK
         }
@@ -144,27 +277,67 @@ pub fn main() {
         }).collect();
         let mut rsum: Box<dyn Read> = Box::new(input);
         for met in methods.iter() {
-            info!("Found pass {}", *met);
             match passes.get_mut(met) {
                 Some(pa) => rsum = (pa.decode)(rsum, &config),
-                None => panic!("Pass is not implemented"),
+                // a whole pipeline given by -filters
+                None => match met.parse::<Pipeline>() {
+                    Ok(p) => rsum = p.with_key(config.key).decoder(rsum).unwrap(),
+                    Err(_) => panic!("Pass is not implemented"),
+                },
+            }
+        }
+        match config.output {
+            // zeros are seeked over, leaving holes in the file
+            Some(ref path) => {
+                let mut sparse = SparseWriter::new(File::create(path).unwrap());
+                io::copy(&mut rsum, &mut sparse).unwrap();
+                sparse.finish().1.unwrap();
             }
+            None => { io::copy(&mut rsum, &mut output).unwrap(); }
+        }
+    }else if config.roundtrip && !config.methods.is_empty() {
+        let mut original = Vec::new();
+        input.read_to_end(&mut original).unwrap();
+        if !debug_roundtrip(&mut passes, &config, &original) {
+            process::exit(1);
+        }
+    }else if let Some(ref pipeline) = config.filters {
+        assert!(config.methods.is_empty(), "Passes can't be combined with -filters");
+        let spec = pipeline.to_string();
+        let len = header_len("The filter spec", spec.len());
+        output.write_u32::<LittleEndian>(MAGIC).unwrap();
+        output.write_u8(1).unwrap();
+        output.write_u8(len).unwrap();
+        output.write_all(spec.as_bytes()).unwrap();
+        let mut e = pipeline.clone().with_key(config.key).encoder(output).unwrap();
+        let copied = io::copy(&mut input, &mut e).map(|_| ());
+        // the error names the failing stage
+        if let Err(e) = copied.and(e.finish().1) {
+            eprintln!("Compression failed: {}", e);
+            process::exit(1);
         }
-        io::copy(&mut rsum, &mut output).unwrap();
     }else if config.methods.is_empty() {
         println!("rust-compress test application");
         println!("Usage:");
         println!("\t{} <options> <method1> .. <methodN> <input >output", config.exe_name);
         println!("Options:");
         println!("\t-d (to decompress)");
+        println!("\t-out=<file> (decompress into a sparse file instead of stdout)");
+        println!("\t-roundtrip (compress and decompress, report the first mismatch)");
         println!("\t-block<N> (BWT block size)");
+        println!("\t-key<K> (scramble key, up to 32 bytes)");
+        println!("\t--filters=<spec> (pipeline instead of passes, e.g. bwt:4m|mtf|rle|ari)");
         println!("Passes:");
         for (name,pa) in passes.iter() {
             println!("\t{} = {}", *name, pa.info);
         }
     }else {
+        let count = header_len("The list of passes", config.methods.len());
+        for met in config.methods.iter() {
+            header_len("A pass name", met.len());
+        }
         output.write_u32::<LittleEndian>(MAGIC).unwrap();
-        output.write_u8(config.methods.len() as u8).unwrap();
+        output.write_u8(count).unwrap();
         for met in config.methods.iter() {
             output.write_u8(met.len() as u8).unwrap();
             output.write_all(met.as_bytes()).unwrap();
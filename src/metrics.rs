@@ -0,0 +1,343 @@
+/*!
+
+Counters for monitoring the codecs in a service
+
+An application installs a `Sink` once, and the streams created through the
+`Codec` trait and `DecoderAny` report to it: when they are opened and
+closed, how many bytes go in and come out, and the kind of every error.
+Bridging these calls to Prometheus, statsd or a log is up to the sink, the
+crate only makes the calls. Each one names the codec, as listed by
+`capabilities`, and whether the stream compresses or decompresses.
+
+Streams pick up the sink installed when they are created, and without one
+they aren't touched at all. Sink methods are called from the threads doing
+the work, for every read and write, so they should be cheap, like bumping
+an atomic counter.
+
+# Example
+
+```rust
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use compress::metrics::{self, Direction, Sink};
+
+#[derive(Default)]
+struct Opened(AtomicUsize);
+
+impl Sink for Opened {
+    fn opened(&self, _codec: &'static str, _dir: Direction) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+let opened = Arc::new(Opened::default());
+metrics::set_sink(opened.clone());
+let codec = compress::codec_by_name("flate").unwrap();
+let mut out = Vec::new();
+let mut e = codec.encoder(Box::new(&mut out)).unwrap();
+e.write_all(b"hello").unwrap();
+e.finish().unwrap();
+metrics::clear_sink();
+assert!(opened.0.load(Ordering::Relaxed) >= 1);
+```
+
+*/
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, RwLock};
+
+use codecs::Finish;
+
+/// Whether a stream compresses or decompresses
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Compressing, the input is plain and the output compressed
+    Encode,
+    /// Decompressing, the input is compressed and the output plain
+    Decode,
+}
+
+/// Receives the counters of the streams, see the module documentation.
+/// Every method does nothing by default.
+pub trait Sink: Send + Sync {
+    /// A stream was created
+    fn opened(&self, _codec: &'static str, _dir: Direction) {}
+
+    /// A stream was finished or dropped
+    fn closed(&self, _codec: &'static str, _dir: Direction) {}
+
+    /// `n` bytes were consumed, plain ones for encoders and compressed ones
+    /// for decoders
+    fn bytes_in(&self, _codec: &'static str, _dir: Direction, _n: u64) {}
+
+    /// `n` bytes were produced, compressed ones for encoders and plain ones
+    /// for decoders
+    fn bytes_out(&self, _codec: &'static str, _dir: Direction, _n: u64) {}
+
+    /// A read, write or finish failed
+    fn error(&self, _codec: &'static str, _dir: Direction, _kind: io::ErrorKind) {}
+}
+
+static SINK: RwLock<Option<Arc<dyn Sink>>> = RwLock::new(None);
+
+/// Installs the sink streams created from now on report to, replacing any
+/// previous one
+pub fn set_sink(sink: Arc<dyn Sink>) {
+    *SINK.write().unwrap() = Some(sink);
+}
+
+/// Removes the sink, streams created from now on aren't metered
+pub fn clear_sink() {
+    *SINK.write().unwrap() = None;
+}
+
+fn sink() -> Option<Arc<dyn Sink>> {
+    SINK.read().unwrap().clone()
+}
+
+// The state shared by the two halves of a metered stream
+struct Meter {
+    sink: Arc<dyn Sink>,
+    codec: &'static str,
+    dir: Direction,
+}
+
+impl Meter {
+    fn new(sink: Arc<dyn Sink>, codec: &'static str, dir: Direction) -> Arc<Meter> {
+        sink.opened(codec, dir);
+        Arc::new(Meter { sink, codec, dir })
+    }
+
+    fn bytes_in<T>(&self, result: &io::Result<T>, n: impl Fn(&T) -> usize) {
+        if let Ok(ref t) = *result {
+            self.sink.bytes_in(self.codec, self.dir, n(t) as u64);
+        }
+    }
+
+    fn bytes_out<T>(&self, result: &io::Result<T>, n: impl Fn(&T) -> usize) {
+        if let Ok(ref t) = *result {
+            self.sink.bytes_out(self.codec, self.dir, n(t) as u64);
+        }
+    }
+
+    // Errors are only counted here, as the outer half hands them to the
+    // caller, so that one coming from the wrapped stream isn't counted twice
+    fn error<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(ref e) = result {
+            self.sink.error(self.codec, self.dir, e.kind());
+        }
+        result
+    }
+}
+
+// Reports the stream as closed when the outer half goes away
+struct Closer(Arc<Meter>);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        self.0.sink.closed(self.0.codec, self.0.dir);
+    }
+}
+
+// Counts the compressed bytes an encoder writes
+struct CountingWriter<'a> {
+    inner: Box<dyn Write + 'a>,
+    meter: Arc<Meter>,
+}
+
+impl<'a> Write for CountingWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.meter.bytes_out(&result, |&n| n);
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct MeteredEncoder<'a> {
+    inner: Box<dyn Finish + 'a>,
+    closer: Closer,
+}
+
+impl<'a> Write for MeteredEncoder<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.closer.0.bytes_in(&result, |&n| n);
+        self.closer.0.error(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.closer.0.error(self.inner.flush())
+    }
+}
+
+impl<'a> Finish for MeteredEncoder<'a> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.closer.0.error(self.inner.finish())
+    }
+}
+
+// Counts the compressed bytes a decoder reads
+struct CountingReader<'a> {
+    inner: Box<dyn Read + 'a>,
+    meter: Arc<Meter>,
+}
+
+impl<'a> Read for CountingReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.meter.bytes_in(&result, |&n| n);
+        result
+    }
+}
+
+struct MeteredDecoder<'a> {
+    inner: Box<dyn Read + 'a>,
+    closer: Closer,
+}
+
+impl<'a> Read for MeteredDecoder<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        self.closer.0.bytes_out(&result, |&n| n);
+        self.closer.0.error(result)
+    }
+}
+
+/// Creates an encoder with `make` around `w`, metered as `codec` if a sink
+/// is installed. This is how the `Codec` implementations of the crate
+/// report, and can be used by others as well.
+pub fn encoder<'a, F>(codec: &'static str, w: Box<dyn Write + 'a>, make: F)
+                      -> io::Result<Box<dyn Finish + 'a>>
+    where F: FnOnce(Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>>
+{
+    let sink = match sink() {
+        Some(sink) => sink,
+        None => return make(w),
+    };
+    let meter = Meter::new(sink, codec, Direction::Encode);
+    let w = Box::new(CountingWriter { inner: w, meter: meter.clone() });
+    let closer = Closer(meter);
+    let inner = try!(closer.0.error(make(w)));
+    Ok(Box::new(MeteredEncoder { inner, closer }))
+}
+
+/// Creates a decoder with `make` around `r`, metered as `codec` if a sink
+/// is installed, the counterpart of `encoder`
+pub fn decoder<'a, F>(codec: &'static str, r: Box<dyn Read + 'a>, make: F)
+                      -> io::Result<Box<dyn Read + 'a>>
+    where F: FnOnce(Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>>
+{
+    let sink = match sink() {
+        Some(sink) => sink,
+        None => return make(r),
+    };
+    let meter = Meter::new(sink, codec, Direction::Decode);
+    let r = Box::new(CountingReader { inner: r, meter: meter.clone() });
+    let closer = Closer(meter);
+    let inner = try!(closer.0.error(make(r)));
+    Ok(Box::new(MeteredDecoder { inner, closer }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::{self, Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, ThreadId};
+    use super::{Direction, Sink};
+    use codecs::Finish;
+
+    // other tests run codecs in parallel, only the calls made on the test's
+    // own thread are counted
+    #[derive(Default)]
+    struct Totals {
+        thread: Option<ThreadId>,
+        counts: Mutex<HashMap<(&'static str, Direction, &'static str), u64>>,
+    }
+
+    impl Totals {
+        fn add(&self, codec: &'static str, dir: Direction, what: &'static str, n: u64) {
+            if Some(thread::current().id()) == self.thread {
+                *self.counts.lock().unwrap().entry((codec, dir, what)).or_insert(0) += n;
+            }
+        }
+
+        fn get(&self, codec: &'static str, dir: Direction, what: &'static str) -> u64 {
+            self.counts.lock().unwrap().get(&(codec, dir, what)).cloned().unwrap_or(0)
+        }
+    }
+
+    impl Sink for Totals {
+        fn opened(&self, codec: &'static str, dir: Direction) { self.add(codec, dir, "opened", 1) }
+        fn closed(&self, codec: &'static str, dir: Direction) { self.add(codec, dir, "closed", 1) }
+        fn bytes_in(&self, codec: &'static str, dir: Direction, n: u64) { self.add(codec, dir, "in", n) }
+        fn bytes_out(&self, codec: &'static str, dir: Direction, n: u64) { self.add(codec, dir, "out", n) }
+        fn error(&self, codec: &'static str, dir: Direction, _: io::ErrorKind) {
+            self.add(codec, dir, "errors", 1)
+        }
+    }
+
+    struct Passthrough<'a>(Box<dyn Write + 'a>);
+
+    impl<'a> Write for Passthrough<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+    }
+
+    impl<'a> Finish for Passthrough<'a> {
+        fn finish(self: Box<Self>) -> io::Result<()> { Ok(()) }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn counts() {
+        let totals = Arc::new(Totals { thread: Some(thread::current().id()), ..Totals::default() });
+        super::set_sink(totals.clone());
+
+        let mut out = Vec::new();
+        {
+            let mut e = super::encoder("test", Box::new(&mut out), |w| Ok(Box::new(Passthrough(w))))
+                .unwrap();
+            e.write_all(b"hello").unwrap();
+            e.finish().unwrap();
+        }
+        let mut d = super::decoder("test", Box::new(&b"abc"[..]), |r| Ok(Box::new(r.take(2))))
+            .unwrap();
+        let mut plain = Vec::new();
+        d.read_to_end(&mut plain).unwrap();
+        drop(d);
+        let failing = super::decoder("test", Box::new(&b""[..]), |_| {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "bad header"))
+        });
+        assert!(failing.is_err());
+        // an error of the wrapped writer passing through the encoder
+        let mut e = super::encoder("test", Box::new(Broken), |w| Ok(Box::new(Passthrough(w))))
+            .unwrap();
+        assert!(e.write(b"hello").is_err());
+        drop(e);
+        super::clear_sink();
+
+        let enc = Direction::Encode;
+        let dec = Direction::Decode;
+        assert_eq!(&out[..], b"hello");
+        assert_eq!((totals.get("test", enc, "opened"), totals.get("test", enc, "closed")), (2, 2));
+        assert_eq!((totals.get("test", enc, "in"), totals.get("test", enc, "out")), (5, 5));
+        assert_eq!(totals.get("test", enc, "errors"), 1);
+        assert_eq!((totals.get("test", dec, "opened"), totals.get("test", dec, "closed")), (2, 2));
+        assert_eq!((totals.get("test", dec, "in"), totals.get("test", dec, "out")), (2, 2));
+        assert_eq!(totals.get("test", dec, "errors"), 1);
+    }
+}
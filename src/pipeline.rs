@@ -0,0 +1,971 @@
+/*!
+
+Chains of compression stages described by spec strings
+
+A `Pipeline` is a list of stages the data goes through one after the other,
+written as their names separated by `|`. A stage taking a parameter has it
+after a colon:
+
+* `bwt[:size]` Burrows-Wheeler transform with the given block size, in
+  bytes or with a `k`/`m` suffix, 1m by default
+* `mtf` move-to-front transform
+* `dc[:size]` distance coding, an alternative to `mtf`, in blocks of the
+  given size like `bwt`
+* `rle[:scheme]` run-length encoding, without a header by default, or
+  with the scheme in a header byte: `escape` bounds the expansion, a
+  number from 2 to 127 is the shortest run encoded
+* `rle0` zero runs of MTF ranks with the RUNA/RUNB digits of bzip2, ahead
+  of an entropy coder
+* `zrle` zero runs of MTF ranks and an adaptive arithmetic coder, ending
+  a `bwt|mtf` pipeline
+* `ari` adaptive arithmetic byte coder
+* `lz4` LZ4 frames
+* `deflate[:level]` raw DEFLATE, level 0 to 9, 6 by default
+* `bzip2[:level]` bzip2, level 1 to 9, 9 by default
+* `delta[:word]` differences of successive values, bytes by default or
+  16 and 32 bit words with `16le`, `16be`, `32le` or `32be`, ahead of a
+  compressor for numeric data
+* `xor[:word]` successive values XORed with each other, like `delta`
+* `x86`, `arm` and `arm64` branch converters, ahead of a compressor for
+  executables
+* `scramble` keyed ChaCha20 obfuscation, see the `scramble` module, with
+  a random nonce written ahead of its output. The key is not part of the
+  spec and is given with `Pipeline::with_key`
+* `identity`, or `store`, passes the data through untouched, so that a
+  spec can ask for no compression at all
+
+For instance `bwt:4m|mtf|rle|ari` is a classic BWT compressor. A pipeline
+prints back as its spec in a normalized form, with every parameter spelled
+out, so it can be stored next to the data and parsed again to decode it.
+Stages whose feature is not compiled in can be parsed, but building an
+encoder or decoder with them fails with an `UnsupportedFormat` error.
+
+`Pipeline::threaded_encoder` runs every stage on a thread of its own, the
+stages handing the data on in chunks through bounded queues. A `bwt` stage
+and the coders after it then work on different blocks at the same time,
+and the output is the same as with `encoder`.
+
+Errors coming out of a pipeline's encoder or decoder are attributed to the
+stage which raised them: the `io::Error` keeps the original kind and
+carries a `StageError` telling the stage and how far into its input it got.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::pipeline::Pipeline;
+
+let pipeline: Pipeline = "bwt:64k|mtf|ari".parse().unwrap();
+let mut e = pipeline.encoder(Vec::new()).unwrap();
+e.write_all(b"abracadabra abracadabra").unwrap();
+let (compressed, result) = e.finish();
+result.unwrap();
+
+let mut d = pipeline.decoder(&compressed[..]).unwrap();
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+assert_eq!(&decoded[..], b"abracadabra abracadabra");
+assert_eq!(pipeline.to_string(), "bwt:64k|mtf|ari");
+```
+
+*/
+
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use filters::{self, Filter, Word};
+use UnsupportedFormat;
+
+#[cfg(feature="bwt")]
+use bwt::MAX_BLOCK_SIZE;
+// the limit is only known to the BWT encoder itself
+#[cfg(not(feature="bwt"))]
+const MAX_BLOCK_SIZE: usize = !0;
+
+/// Block size of the `bwt` stage when the spec doesn't give one
+pub const DEFAULT_BWT_BLOCK: usize = 1 << 20;
+
+/// A stage of a pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// Burrows-Wheeler transform with the given block size
+    Bwt(usize),
+    /// Move-to-front transform
+    Mtf,
+    /// Distance coding with the given block size
+    Dc(usize),
+    /// Run-length encoding without a header
+    Rle,
+    /// Run-length encoding of runs at least this long, with a header
+    RleThreshold(u8),
+    /// Run-length encoding with escape bytes, with a header
+    RleEscape,
+    /// Zero run coding of MTF ranks
+    Rle0,
+    /// Zero run coding of MTF ranks with an arithmetic coder
+    Zrle,
+    /// Adaptive arithmetic byte coder
+    Ari,
+    /// LZ4 frames
+    Lz4,
+    /// Raw DEFLATE at the given level
+    Deflate(u8),
+    /// bzip2 at the given level
+    Bzip2(u8),
+    /// Delta filter on the given values
+    Delta(Word),
+    /// XOR filter on the given values
+    Xor(Word),
+    /// x86 branch converter
+    X86,
+    /// ARM branch converter
+    Arm,
+    /// ARM64 branch converter
+    Arm64,
+    /// Keyed scrambling with the pipeline's key
+    Scramble,
+    /// The data as it is
+    Identity,
+}
+
+impl Stage {
+    /// Returns the name of the stage in specs
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Stage::Bwt(_) => "bwt",
+            Stage::Mtf => "mtf",
+            Stage::Dc(_) => "dc",
+            Stage::Rle | Stage::RleThreshold(_) | Stage::RleEscape => "rle",
+            Stage::Rle0 => "rle0",
+            Stage::Zrle => "zrle",
+            Stage::Ari => "ari",
+            Stage::Lz4 => "lz4",
+            Stage::Deflate(_) => "deflate",
+            Stage::Bzip2(_) => "bzip2",
+            Stage::Delta(_) => "delta",
+            Stage::Xor(_) => "xor",
+            Stage::X86 => "x86",
+            Stage::Arm => "arm",
+            Stage::Arm64 => "arm64",
+            Stage::Scramble => "scramble",
+            Stage::Identity => "identity",
+        }
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(f.write_str(self.name()));
+        match *self {
+            Stage::Bwt(size) | Stage::Dc(size) if size.is_multiple_of(1 << 20) => write!(f, ":{}m", size >> 20),
+            Stage::Bwt(size) | Stage::Dc(size) if size.is_multiple_of(1 << 10) => write!(f, ":{}k", size >> 10),
+            Stage::Bwt(size) | Stage::Dc(size) => write!(f, ":{}", size),
+            Stage::Deflate(level) | Stage::Bzip2(level) | Stage::RleThreshold(level) => write!(f, ":{}", level),
+            Stage::RleEscape => f.write_str(":escape"),
+            Stage::Delta(word) | Stage::Xor(word) => write!(f, ":{}", word),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why a spec couldn't be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    stage: String,
+    reason: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid pipeline stage `{}`: {}", self.stage, self.reason)
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(e: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+fn parse_size(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(&b'k') | Some(&b'K') => (&s[..s.len() - 1], 10),
+        Some(&b'm') | Some(&b'M') => (&s[..s.len() - 1], 20),
+        _ => (s, 0),
+    };
+    digits.parse::<usize>().ok().and_then(|n| n.checked_mul(1 << shift))
+}
+
+impl FromStr for Stage {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Stage, ParseError> {
+        let fail = |reason| Err(ParseError { stage: s.to_string(), reason });
+        let mut parts = s.trim().splitn(2, ':');
+        let name = parts.next().unwrap();
+        let param = parts.next();
+        let stage = match (name, param) {
+            ("bwt", None) => Stage::Bwt(DEFAULT_BWT_BLOCK),
+            ("dc", None) => Stage::Dc(DEFAULT_BWT_BLOCK),
+            ("bwt", Some(size)) | ("dc", Some(size)) => match parse_size(size) {
+                Some(size) if size > 0 && size <= MAX_BLOCK_SIZE => {
+                    if name == "bwt" { Stage::Bwt(size) } else { Stage::Dc(size) }
+                }
+                Some(_) => return fail("block size out of range"),
+                None => return fail("expected a block size"),
+            },
+            ("deflate", None) => Stage::Deflate(6),
+            ("bzip2", None) => Stage::Bzip2(9),
+            ("deflate", Some(level)) | ("bzip2", Some(level)) => {
+                let range = if name == "deflate" { 0..=9 } else { 1..=9 };
+                match level.parse::<u8>() {
+                    Ok(level) if range.contains(&level) => {
+                        if name == "deflate" { Stage::Deflate(level) } else { Stage::Bzip2(level) }
+                    }
+                    _ => return fail("level out of range"),
+                }
+            }
+            ("delta", None) => Stage::Delta(Word::U8),
+            ("xor", None) => Stage::Xor(Word::U8),
+            ("delta", Some(word)) | ("xor", Some(word)) => match word.parse() {
+                Ok(word) => if name == "delta" { Stage::Delta(word) } else { Stage::Xor(word) },
+                Err(_) => return fail("expected 8, 16le, 16be, 32le or 32be"),
+            },
+            ("mtf", None) => Stage::Mtf,
+            ("rle", None) => Stage::Rle,
+            ("rle", Some("escape")) => Stage::RleEscape,
+            // up to rle::MAX_THRESHOLD
+            ("rle", Some(threshold)) => match threshold.parse::<u8>() {
+                Ok(threshold) if (2..=0x7f).contains(&threshold) => Stage::RleThreshold(threshold),
+                _ => return fail("expected escape or a threshold from 2 to 127"),
+            },
+            ("rle0", None) => Stage::Rle0,
+            ("zrle", None) => Stage::Zrle,
+            ("ari", None) => Stage::Ari,
+            ("lz4", None) => Stage::Lz4,
+            ("x86", None) => Stage::X86,
+            ("arm", None) => Stage::Arm,
+            ("arm64", None) => Stage::Arm64,
+            ("scramble", None) => Stage::Scramble,
+            ("identity", None) | ("store", None) => Stage::Identity,
+            ("mtf", _) | ("rle0", _) | ("zrle", _) | ("ari", _) | ("lz4", _) | ("x86", _) | ("arm", _) |
+            ("arm64", _) | ("scramble", _) | ("identity", _) | ("store", _) => {
+                return fail("takes no parameter")
+            }
+            _ => return fail("unknown stage"),
+        };
+        Ok(stage)
+    }
+}
+
+/// A list of stages, see the module documentation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    key: Option<[u8; 32]>,
+}
+
+impl Pipeline {
+    /// Creates a pipeline from its stages, in the order the data goes
+    /// through them when compressing
+    pub fn new(stages: Vec<Stage>) -> Pipeline {
+        Pipeline { stages, key: None }
+    }
+
+    /// Sets the key of the `scramble` stages. Without one, building an
+    /// encoder or decoder with such a stage fails with an `InvalidInput`
+    /// error.
+    pub fn with_key(mut self, key: [u8; 32]) -> Pipeline {
+        self.key = Some(key);
+        self
+    }
+
+    /// Returns the stages
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// Creates an encoder compressing through all the stages into `w`
+    pub fn encoder<'a, W: Write + 'a>(&self, w: W) -> io::Result<Encoder<'a, W>> {
+        let bottom: Box<dyn Link<W> + 'a> = Box::new(Bottom(w));
+        let mut link: Box<dyn Link<W> + 'a> =
+            Box::new(Attributed::new(bottom, self.stages.len(), None, Rc::new(Cell::new(0))));
+        for (i, &stage) in self.stages.iter().enumerate().rev() {
+            let encoder = try!(encode_stage(stage, self.key, link));
+            link = Box::new(Attributed::new(encoder, i, Some(stage), Rc::new(Cell::new(0))));
+        }
+        Ok(Encoder { link })
+    }
+
+    /// Creates an encoder like `encoder`, with every stage running on a
+    /// thread of its own. Stages receive their input in chunks of
+    /// `THREAD_CHUNK` bytes, and `flush` waits until the data written so far
+    /// went through every stage and the writer was flushed.
+    pub fn threaded_encoder<W: Write + Send + 'static>(&self, w: W)
+                                                       -> io::Result<Encoder<'static, W>> {
+        // a stage which can't be created fails here rather than on its
+        // thread
+        for &stage in self.stages.iter() {
+            try!(encode_stage(stage, self.key, Box::new(Bottom(io::sink()))));
+        }
+        let mut lower: Box<dyn Link<W> + Send> = Box::new(Bottom(w));
+        for (i, &stage) in self.stages.iter().enumerate().rev() {
+            let below = self.stages.get(i + 1).cloned();
+            let key = self.key;
+            let (tx, rx) = sync_channel(THREAD_QUEUE);
+            let thread = try!(thread::Builder::new()
+                .name(format!("pipeline stage {}", stage))
+                .spawn(move || run_stage(i, stage, key, below, lower, rx)));
+            lower = Box::new(Channel {
+                tx: Some(tx),
+                thread: Some(thread),
+                stopped: None,
+                buf: Vec::new(),
+            });
+        }
+        let top = self.stages.first().cloned();
+        let link = Attributed::new(lower as Box<dyn Link<W>>, 0, top, Rc::new(Cell::new(0)));
+        Ok(Encoder { link: Box::new(link) })
+    }
+
+    /// Creates a decoder reading data compressed by this pipeline from `r`
+    pub fn decoder<'a, R: Read + 'a>(&self, r: R) -> io::Result<Box<dyn Read + 'a>> {
+        let count = Rc::new(Cell::new(0));
+        let input = Counted { inner: r, count: count.clone() };
+        let mut rsum: Box<dyn Read + 'a> =
+            Box::new(Attributed::new(input, self.stages.len(), None, count));
+        for (i, &stage) in self.stages.iter().enumerate().rev() {
+            let count = Rc::new(Cell::new(0));
+            let input = Counted { inner: rsum, count: count.clone() };
+            let decoder = try!(decode_stage(stage, self.key, Box::new(input)));
+            rsum = Box::new(Attributed::new(decoder, i, Some(stage), count));
+        }
+        Ok(rsum)
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, stage) in self.stages.iter().enumerate() {
+            if i > 0 {
+                try!(f.write_str("|"));
+            }
+            try!(write!(f, "{}", stage));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Pipeline, ParseError> {
+        if s.trim().is_empty() {
+            return Err(ParseError { stage: String::new(), reason: "empty pipeline" })
+        }
+        let stages = try!(s.split('|').map(str::parse).collect());
+        Ok(Pipeline::new(stages))
+    }
+}
+
+/// The stage of a pipeline an error came from, found inside the `io::Error`
+/// returned by the pipeline's encoder or decoder
+#[derive(Debug)]
+pub struct StageError {
+    /// Position of the stage in the pipeline, or the number of stages for
+    /// the writer or reader the pipeline runs on
+    pub index: usize,
+    /// The stage, `None` for the writer or reader the pipeline runs on
+    pub stage: Option<Stage>,
+    /// How far into its input the stage got: the number of bytes written
+    /// into it when encoding, or read by it from the stage below when
+    /// decoding
+    pub offset: u64,
+    /// The error raised by the stage
+    pub error: io::Error,
+}
+
+impl StageError {
+    /// Returns the stage error carried by `e`, if it came from a pipeline
+    pub fn find(e: &io::Error) -> Option<&StageError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<StageError>())
+    }
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.stage {
+            Some(stage) => write!(f, "pipeline stage {} ({}) failed at offset {}: {}",
+                                  self.index, stage, self.offset, self.error),
+            None => write!(f, "stream under the pipeline failed at offset {}: {}",
+                           self.offset, self.error),
+        }
+    }
+}
+
+impl Error for StageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// Counts the bytes read through it
+struct Counted<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+// Tags the errors raised by a stage with its position. Errors already
+// tagged by a stage further down pass through unchanged.
+struct Attributed<T> {
+    inner: T,
+    index: usize,
+    stage: Option<Stage>,
+    offset: Rc<Cell<u64>>,
+}
+
+impl<T> Attributed<T> {
+    fn new(inner: T, index: usize, stage: Option<Stage>, offset: Rc<Cell<u64>>) -> Attributed<T> {
+        Attributed { inner, index, stage, offset }
+    }
+
+    fn attribute(&self, e: io::Error) -> io::Error {
+        if StageError::find(&e).is_some() {
+            return e
+        }
+        io::Error::new(e.kind(), StageError {
+            index: self.index,
+            stage: self.stage,
+            offset: self.offset.get(),
+            error: e,
+        })
+    }
+}
+
+impl<T: Write> Write for Attributed<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            // caught here, as `write_all` further up would blame its caller
+            Ok(0) if !buf.is_empty() => {
+                Err(self.attribute(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")))
+            }
+            Ok(n) => {
+                self.offset.set(self.offset.get() + n as u64);
+                Ok(n)
+            }
+            Err(e) => Err(self.attribute(e)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().map_err(|e| self.attribute(e))
+    }
+}
+
+impl<'a, W> Link<W> for Attributed<Box<dyn Link<W> + 'a>> {
+    fn finish(self: Box<Self>) -> (W, io::Result<()>) {
+        let this = *self;
+        let tag = Attributed::new((), this.index, this.stage, this.offset);
+        let (w, result) = this.inner.finish();
+        (w, result.map_err(|e| tag.attribute(e)))
+    }
+}
+
+impl<T: Read> Read for Attributed<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(|e| self.attribute(e))
+    }
+}
+
+// An encoder of the chain, finishing it finishes the ones below it
+trait Link<W>: Write {
+    fn finish(self: Box<Self>) -> (W, io::Result<()>);
+}
+
+// The writer at the bottom of the chain
+struct Bottom<W>(W);
+
+impl<W: Write> Write for Bottom<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+}
+
+impl<W: Write> Link<W> for Bottom<W> {
+    fn finish(mut self: Box<Self>) -> (W, io::Result<()>) {
+        let result = self.0.flush();
+        (self.0, result)
+    }
+}
+
+macro_rules! link {
+    ($encoder:ty, $finish:expr) => (
+        impl<'a, W> Link<W> for $encoder {
+            fn finish(self: Box<Self>) -> (W, io::Result<()>) {
+                let (inner, result): (Box<dyn Link<W> + 'a>, io::Result<()>) = $finish(*self);
+                let (w, rest) = inner.finish();
+                (w, result.and(rest))
+            }
+        }
+    )
+}
+
+link!(filters::Encoder<Box<dyn Link<W> + 'a>>, filters::Encoder::finish);
+#[cfg(feature="bwt")]
+link!(::bwt::Encoder<Box<dyn Link<W> + 'a>>, ::bwt::Encoder::finish);
+#[cfg(feature="bwt")]
+link!(::bwt::mtf::Encoder<Box<dyn Link<W> + 'a>>, |e: ::bwt::mtf::Encoder<_>| (e.finish(), Ok(())));
+#[cfg(feature="bwt")]
+link!(::bwt::dc::Encoder<Box<dyn Link<W> + 'a>>, ::bwt::dc::Encoder::finish);
+#[cfg(feature="bwt")]
+link!(::bwt::rle0::Encoder<Box<dyn Link<W> + 'a>>, ::bwt::rle0::Encoder::finish);
+#[cfg(all(feature="bwt", feature="entropy"))]
+link!(::bwt::zrle::Encoder<Box<dyn Link<W> + 'a>>, ::bwt::zrle::Encoder::finish);
+#[cfg(feature="rle")]
+link!(::rle::Encoder<Box<dyn Link<W> + 'a>>, ::rle::Encoder::finish);
+#[cfg(feature="entropy")]
+link!(::entropy::ari::ByteEncoder<Box<dyn Link<W> + 'a>>, ::entropy::ari::ByteEncoder::finish);
+#[cfg(feature="lz4")]
+link!(::lz4::Encoder<Box<dyn Link<W> + 'a>>, ::lz4::Encoder::finish);
+#[cfg(feature="flate")]
+link!(::flate::Encoder<Box<dyn Link<W> + 'a>>, ::flate::Encoder::finish);
+#[cfg(feature="bzip2")]
+link!(::bzip2::Encoder<Box<dyn Link<W> + 'a>>, ::bzip2::Encoder::finish);
+#[cfg(feature="scramble")]
+link!(::scramble::Encoder<Box<dyn Link<W> + 'a>>, |e: ::scramble::Encoder<_>| (e.finish(), Ok(())));
+
+/// Size of the chunks the stages of `Pipeline::threaded_encoder` pass on
+pub const THREAD_CHUNK: usize = 64 * 1024;
+
+// chunks queued between two stage threads
+const THREAD_QUEUE: usize = 4;
+
+enum Message {
+    Data(Vec<u8>),
+    // answered once the stages below have flushed as well
+    Flush(SyncSender<()>),
+}
+
+fn stopped() -> io::Error {
+    io::Error::other("a lower pipeline stage has failed")
+}
+
+fn join<T>(thread: JoinHandle<T>) -> T {
+    thread.join().unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+// Feeds the stage thread below, which sits on top of the writer `W`
+struct Channel<W> {
+    tx: Option<SyncSender<Message>>,
+    thread: Option<JoinHandle<(W, io::Result<()>)>>,
+    // the writer given back by a stage thread which quit on an error
+    stopped: Option<W>,
+    buf: Vec<u8>,
+}
+
+impl<W> Channel<W> {
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        match (self.tx.as_ref(), self.stopped.is_some()) {
+            (Some(tx), false) if tx.send(message).is_ok() => return Ok(()),
+            (_, true) => return Err(stopped()),
+            _ => {}
+        }
+        Err(self.stop())
+    }
+
+    // Joins the thread, which has quit on an error, returning that error
+    fn stop(&mut self) -> io::Error {
+        let (w, result) = join(self.thread.take().unwrap());
+        self.stopped = Some(w);
+        result.err().unwrap_or_else(stopped)
+    }
+
+    fn send_buffered(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(())
+        }
+        let data = mem::replace(&mut self.buf, Vec::with_capacity(THREAD_CHUNK));
+        self.send(Message::Data(data))
+    }
+}
+
+impl<W> Write for Channel<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= THREAD_CHUNK {
+            try!(self.send_buffered());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.send_buffered());
+        let (ack, done) = sync_channel(1);
+        try!(self.send(Message::Flush(ack)));
+        match done.recv() {
+            Ok(()) => Ok(()),
+            // the flush has failed, or an earlier write
+            Err(_) => Err(self.stop()),
+        }
+    }
+}
+
+impl<W> Link<W> for Channel<W> {
+    fn finish(mut self: Box<Self>) -> (W, io::Result<()>) {
+        let result = self.send_buffered();
+        // the thread finishes its stage once the queue is closed
+        self.tx = None;
+        match self.thread.take() {
+            Some(thread) => {
+                let (w, rest) = join(thread);
+                (w, result.and(rest))
+            }
+            None => (self.stopped.take().unwrap(), result.and(Err(stopped()))),
+        }
+    }
+}
+
+// The body of a stage thread: encodes what comes down the queue into the
+// stage below until the queue is closed, then finishes the stages below
+fn run_stage<W: Write>(index: usize, stage: Stage, key: Option<[u8; 32]>, below: Option<Stage>,
+                lower: Box<dyn Link<W> + Send>, rx: Receiver<Message>)
+                -> (W, io::Result<()>) {
+    let lower = Attributed::new(lower as Box<dyn Link<W>>, index + 1, below, Rc::new(Cell::new(0)));
+    let encoder = match encode_stage(stage, key, Box::new(lower)) {
+        Ok(encoder) => encoder,
+        Err(_) => unreachable!("stage {} was checked to be available", stage),
+    };
+    let mut link = Box::new(Attributed::new(encoder, index, Some(stage), Rc::new(Cell::new(0))));
+    let mut failed = None;
+    for message in rx.iter() {
+        let result = match message {
+            Message::Data(data) => link.write_all(&data),
+            Message::Flush(ack) => link.flush().map(|_| { let _ = ack.send(()); }),
+        };
+        if let Err(e) = result {
+            failed = Some(e);
+            break
+        }
+    }
+    // stop the stage above before finishing, which may take a while
+    drop(rx);
+    let (w, result) = link.finish();
+    match failed {
+        Some(e) => (w, Err(e)),
+        None => (w, result),
+    }
+}
+
+fn unavailable<T>(stage: Stage) -> io::Result<T> {
+    let feature = match stage {
+        Stage::Bwt(_) | Stage::Mtf | Stage::Dc(_) | Stage::Rle0 => "bwt",
+        Stage::Rle | Stage::RleThreshold(_) | Stage::RleEscape => "rle",
+        Stage::Zrle if !cfg!(feature="bwt") => "bwt",
+        Stage::Zrle | Stage::Ari => "entropy",
+        Stage::Lz4 => "lz4",
+        Stage::Deflate(_) => "flate",
+        Stage::Bzip2(_) => "bzip2",
+        Stage::Scramble => "scramble",
+        Stage::Delta(_) | Stage::Xor(_) | Stage::X86 | Stage::Arm | Stage::Arm64 | Stage::Identity => "",
+    };
+    Err(UnsupportedFormat { name: stage.name(), feature }.into())
+}
+
+fn no_key<T>() -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, "the scramble stage needs a key, see Pipeline::with_key"))
+}
+
+#[allow(unreachable_patterns)]
+#[cfg_attr(not(feature="scramble"), allow(unused_variables))]
+fn encode_stage<'a, W: Write + 'a>(stage: Stage, key: Option<[u8; 32]>, w: Box<dyn Link<W> + 'a>)
+                                   -> io::Result<Box<dyn Link<W> + 'a>> {
+    Ok(match stage {
+        #[cfg(feature="bwt")]
+        Stage::Bwt(size) => Box::new(try!(::bwt::Encoder::new(w, size))),
+        #[cfg(feature="bwt")]
+        Stage::Mtf => Box::new(::bwt::mtf::Encoder::new(w)),
+        #[cfg(feature="bwt")]
+        Stage::Dc(size) => Box::new(try!(::bwt::dc::Encoder::new(w, size))),
+        #[cfg(feature="bwt")]
+        Stage::Rle0 => Box::new(::bwt::rle0::Encoder::new(w)),
+        #[cfg(feature="rle")]
+        Stage::Rle => Box::new(::rle::Encoder::new(w)),
+        #[cfg(feature="rle")]
+        Stage::RleThreshold(threshold) => Box::new(::rle::Encoder::with_mode(w, ::rle::Mode::Threshold(threshold))),
+        #[cfg(feature="rle")]
+        Stage::RleEscape => Box::new(::rle::Encoder::with_mode(w, ::rle::Mode::Escape)),
+        #[cfg(all(feature="bwt", feature="entropy"))]
+        Stage::Zrle => Box::new(::bwt::zrle::Encoder::new(w)),
+        #[cfg(feature="entropy")]
+        Stage::Ari => Box::new(::entropy::ari::ByteEncoder::new(w)),
+        #[cfg(feature="lz4")]
+        Stage::Lz4 => Box::new(::lz4::Encoder::new(w)),
+        #[cfg(feature="flate")]
+        Stage::Deflate(level) => Box::new(::flate::Encoder::with_level(w, ::flate::CompressionLevel::Level(level))),
+        #[cfg(feature="bzip2")]
+        Stage::Bzip2(level) => Box::new(::bzip2::Encoder::with_level(w, level)),
+        #[cfg(feature="scramble")]
+        Stage::Scramble => match key {
+            Some(key) => Box::new(try!(::scramble::Encoder::with_random_nonce(w, &key))),
+            None => return no_key(),
+        },
+        Stage::Delta(word) => Box::new(filters::Encoder::new(w, Filter::Delta(word))),
+        Stage::Xor(word) => Box::new(filters::Encoder::new(w, Filter::Xor(word))),
+        Stage::X86 => Box::new(filters::Encoder::new(w, Filter::X86)),
+        Stage::Arm => Box::new(filters::Encoder::new(w, Filter::Arm)),
+        Stage::Arm64 => Box::new(filters::Encoder::new(w, Filter::Arm64)),
+        Stage::Identity => w,
+        _ => return unavailable(stage),
+    })
+}
+
+#[allow(unreachable_patterns)]
+#[cfg_attr(not(feature="scramble"), allow(unused_variables))]
+fn decode_stage<'a>(stage: Stage, key: Option<[u8; 32]>, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match stage {
+        #[cfg(feature="bwt")]
+        Stage::Bwt(_) => Box::new(::bwt::Decoder::new(r, true)),
+        #[cfg(feature="bwt")]
+        Stage::Mtf => Box::new(::bwt::mtf::Decoder::new(r)),
+        #[cfg(feature="bwt")]
+        Stage::Dc(_) => Box::new(::bwt::dc::Decoder::new(r)),
+        #[cfg(feature="bwt")]
+        Stage::Rle0 => Box::new(::bwt::rle0::Decoder::new(r)),
+        #[cfg(feature="rle")]
+        Stage::Rle => Box::new(::rle::Decoder::new(r)),
+        #[cfg(feature="rle")]
+        Stage::RleThreshold(_) | Stage::RleEscape => Box::new(::rle::Decoder::with_header(r)),
+        #[cfg(all(feature="bwt", feature="entropy"))]
+        Stage::Zrle => Box::new(::bwt::zrle::Decoder::new(r)),
+        #[cfg(feature="entropy")]
+        Stage::Ari => Box::new(::entropy::ari::ByteDecoder::new(r)),
+        #[cfg(feature="lz4")]
+        Stage::Lz4 => Box::new(::lz4::Decoder::new(r)),
+        #[cfg(feature="flate")]
+        Stage::Deflate(_) => Box::new(::flate::Decoder::new(r)),
+        #[cfg(feature="bzip2")]
+        Stage::Bzip2(_) => Box::new(::bzip2::Decoder::new(r)),
+        #[cfg(feature="scramble")]
+        Stage::Scramble => match key {
+            Some(key) => Box::new(::scramble::Decoder::with_header(r, &key)),
+            None => return no_key(),
+        },
+        Stage::Delta(word) => Box::new(filters::Decoder::new(r, Filter::Delta(word))),
+        Stage::Xor(word) => Box::new(filters::Decoder::new(r, Filter::Xor(word))),
+        Stage::X86 => Box::new(filters::Decoder::new(r, Filter::X86)),
+        Stage::Arm => Box::new(filters::Decoder::new(r, Filter::Arm)),
+        Stage::Arm64 => Box::new(filters::Decoder::new(r, Filter::Arm64)),
+        Stage::Identity => r,
+        _ => return unavailable(stage),
+    })
+}
+
+/// An encoder running data through the stages of a pipeline
+pub struct Encoder<'a, W> {
+    link: Box<dyn Link<W> + 'a>,
+}
+
+impl<'a, W: Write> Encoder<'a, W> {
+    /// Finishes every stage, from the first to the last, and returns the
+    /// wrapped writer
+    pub fn finish(self) -> (W, io::Result<()>) {
+        self.link.finish()
+    }
+}
+
+impl<'a, W: Write> Write for Encoder<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.link.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.link.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use super::{Pipeline, Stage, StageError};
+
+    #[test]
+    fn parse_and_print() {
+        let p: Pipeline = "bwt:4m|mtf|rle|ari".parse().unwrap();
+        assert_eq!(p.stages(), &[Stage::Bwt(4 << 20), Stage::Mtf, Stage::Rle, Stage::Ari]);
+        assert_eq!(p.to_string(), "bwt:4m|mtf|rle|ari");
+        assert_eq!("bwt | deflate".parse::<Pipeline>().unwrap().to_string(), "bwt:1m|deflate:6");
+        assert_eq!("bwt:1000|bzip2:1".parse::<Pipeline>().unwrap().to_string(), "bwt:1000|bzip2:1");
+        assert_eq!("store".parse::<Pipeline>().unwrap().to_string(), "identity");
+        assert_eq!("bwt:900k | mtf | zrle".parse::<Pipeline>().unwrap().to_string(), "bwt:900k|mtf|zrle");
+        assert_eq!("delta|xor:32be|lz4".parse::<Pipeline>().unwrap().to_string(), "delta:8|xor:32be|lz4");
+        assert_eq!("rle:escape|rle:4|rle".parse::<Pipeline>().unwrap().stages(),
+                   &[Stage::RleEscape, Stage::RleThreshold(4), Stage::Rle]);
+        for bad in ["", "bwt:0", "dc:x", "bwt:1g", "mtf:2", "deflate:10", "bzip2:0", "zip", "bwt||ari",
+                    "store:1", "zrle:1", "rle0:1", "delta:16", "xor:", "x86:1",
+                    "rle:1", "rle:128", "rle:esc", "scramble:key"].iter() {
+            assert!(bad.parse::<Pipeline>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature="bwt", feature="rle", feature="entropy", feature="lz4",
+              feature="flate", feature="bzip2"))]
+    fn roundtrips() {
+        let input = include_bytes!("data/test.txt");
+        for spec in ["bwt:4k|mtf|rle|ari", "bwt:4k|dc:4k|ari", "bwt:4k|mtf|zrle", "bwt:4k|mtf|rle0|ari", "lz4|ari", "deflate:9|rle",
+                     "rle|bzip2:1", "mtf", "identity", "lz4|store", "rle:escape|rle:3",
+                     "delta:16le|deflate:6", "xor:32be|delta|lz4", "x86|arm|arm64|lz4"].iter() {
+            let p: Pipeline = spec.parse().unwrap();
+            let mut e = p.encoder(Vec::new()).unwrap();
+            e.write_all(input).unwrap();
+            let (compressed, result) = e.finish();
+            result.unwrap();
+            let mut decoded = Vec::new();
+            p.decoder(&compressed[..]).unwrap().read_to_end(&mut decoded).unwrap();
+            assert!(decoded[..] == input[..], "{} failed", spec);
+        }
+    }
+
+    #[test]
+    #[cfg(all(feature="scramble", feature="lz4"))]
+    fn scramble_roundtrips() {
+        let input = include_bytes!("data/test.txt");
+        let p: Pipeline = "lz4|scramble".parse().unwrap();
+        assert_eq!(p.to_string(), "lz4|scramble");
+        assert_eq!(p.encoder(Vec::new()).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        assert!(p.decoder(&b""[..]).is_err());
+
+        let p = p.with_key([0x5a; 32]);
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut e = p.encoder(Vec::new()).unwrap();
+            e.write_all(input).unwrap();
+            let (compressed, result) = e.finish();
+            result.unwrap();
+            let mut decoded = Vec::new();
+            p.decoder(&compressed[..]).unwrap().read_to_end(&mut decoded).unwrap();
+            assert!(decoded[..] == input[..]);
+            streams.push(compressed);
+        }
+        // every stream gets a nonce of its own
+        assert!(streams[0] != streams[1]);
+
+        // a wrong key garbles the LZ4 frame under it
+        let p = p.with_key([0xa5; 32]);
+        assert!(p.decoder(&streams[0][..]).unwrap().read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    #[cfg(all(feature="rle", feature="lz4"))]
+    fn attributes_errors() {
+        let p: Pipeline = "rle|lz4".parse().unwrap();
+        let input = include_bytes!("data/test.txt");
+        let mut e = p.encoder(Vec::new()).unwrap();
+        e.write_all(input).unwrap();
+        let (compressed, _) = e.finish();
+
+        // cut in the middle of the LZ4 frame
+        let mut d = p.decoder(&compressed[..100]).unwrap();
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        let stage = StageError::find(&err).unwrap();
+        assert_eq!((stage.index, stage.stage, stage.offset), (1, Some(Stage::Lz4), 100));
+        assert!(err.to_string().contains("stage 1 (lz4)"));
+
+        // the output can't take it all
+        let mut out = [0u8; 50];
+        let mut e = p.encoder(&mut out[..]).unwrap();
+        e.write_all(input).unwrap();
+        let err = e.finish().1.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        let stage = StageError::find(&err).unwrap();
+        assert_eq!((stage.index, stage.stage, stage.offset), (2, None, 50));
+
+        // the same from the threads of a threaded encoder
+        let mut e = p.threaded_encoder(io::Cursor::new(vec![0; 50].into_boxed_slice())).unwrap();
+        e.write_all(input).unwrap();
+        let err = e.finish().1.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        let stage = StageError::find(&err).unwrap();
+        assert_eq!((stage.index, stage.stage, stage.offset), (2, None, 50));
+    }
+
+    #[test]
+    #[cfg(all(feature="bwt", feature="rle", feature="entropy", feature="lz4"))]
+    fn threaded_matches_serial() {
+        let input = &include_bytes!("data/test.large")[..1 << 20];
+        for spec in ["bwt:256k|mtf|rle|ari", "lz4|ari", "rle"].iter() {
+            let p: Pipeline = spec.parse().unwrap();
+            // flushes land at the same places in both
+            let mut e = p.encoder(Vec::new()).unwrap();
+            for chunk in input.chunks(100_000) {
+                e.write_all(chunk).unwrap();
+                e.flush().unwrap();
+            }
+            let (serial, result) = e.finish();
+            result.unwrap();
+
+            let mut e = p.threaded_encoder(Vec::new()).unwrap();
+            for chunk in input.chunks(100_000) {
+                e.write_all(chunk).unwrap();
+                e.flush().unwrap();
+            }
+            let (threaded, result) = e.finish();
+            result.unwrap();
+            assert!(threaded == serial, "{} differs", spec);
+        }
+    }
+
+    #[test]
+    fn threaded_flush_waits() {
+        use std::sync::{Arc, Mutex};
+
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let input = &include_bytes!("data/test.txt")[..];
+        let p: Pipeline = "delta|rle".parse().unwrap();
+        let mut e = p.encoder(Vec::new()).unwrap();
+        e.write_all(input).unwrap();
+        e.flush().unwrap();
+        let serial = e.finish().0;
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut e = p.threaded_encoder(Shared(out.clone())).unwrap();
+        e.write_all(input).unwrap();
+        e.flush().unwrap();
+        assert!(*out.lock().unwrap() == serial);
+    }
+
+    #[test]
+    #[cfg(not(feature="bzip2"))]
+    fn missing_stages() {
+        use UnsupportedFormat;
+
+        let p: Pipeline = "delta|bzip2".parse().unwrap();
+        let e = p.encoder(Vec::new()).err().unwrap();
+        assert_eq!(UnsupportedFormat::find(&e).map(|u| (u.name, u.feature)), Some(("bzip2", "bzip2")));
+        assert!(p.decoder(&b""[..]).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+/*!
+
+Compressed record logs. Requires `serde` feature
+
+A `Writer` serializes records with bincode, collects them into batches and
+writes every batch compressed with the chosen codec and protected by a
+CRC-32 of its serialized contents. A `Reader` gives the records back in
+order. Batching lets the codec see enough data to find redundancy between
+records while keeping the log appendable.
+
+# Format
+
+The log starts with the magic bytes `RCRL`, followed by any number of
+batches, each made of
+
+* the codec, as a `Format` identifier or 0 for stored batches. bzip2,
+  DEFLATE, GZIP, LZ4 frames and RLE are supported
+* the number of records, u32 little endian
+* the compressed length, u32 little endian
+* the CRC-32 of the serialized records, u32 little endian
+* the compressed records
+
+A `Reader` decompresses a whole batch at once, so it refuses batches whose
+serialized records take more than `DEFAULT_MAX_BATCH` bytes, or the limit
+given to `Reader::with_max_batch`, rather than let a corrupt or hostile log
+make it allocate without bound.
+
+# Example
+
+The example needs the `lz4` feature as well.
+
+```rust
+# #[cfg(feature = "lz4")] {
+use compress::records::{Reader, Writer};
+use compress::Format;
+
+let mut w = Writer::new(Vec::new(), Some(Format::Lz4Frame), 100).unwrap();
+for i in 0..1000u32 {
+    w.write(&(i, format!("event {}", i))).unwrap();
+}
+let log = w.finish().unwrap();
+
+let r = Reader::<_, (u32, String)>::new(&log[..]).unwrap();
+let records = r.collect::<Result<Vec<_>, _>>().unwrap();
+assert_eq!(records[42], (42, "event 42".to_string()));
+# }
+```
+
+*/
+
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+
+use bincode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use super::ReadExact;
+use codecs::{compress, decompress};
+use Crc32;
+use Format;
+
+/// The bytes every record log starts with
+pub const MAGIC: [u8; 4] = *b"RCRL";
+
+/// The largest batch `Reader::new` accepts, in bytes of serialized records
+pub const DEFAULT_MAX_BATCH: usize = 64 << 20;
+
+const STORED: u8 = 0;
+
+fn invalid<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+}
+
+fn bincode_err_to_io(e: bincode::ErrorKind) -> io::Error {
+    match e {
+        bincode::ErrorKind::Io(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Writes a record log, see the module documentation
+pub struct Writer<W: Write, T> {
+    w: W,
+    codec: Option<Format>,
+    batch_records: usize,
+    count: usize,
+    buf: Vec<u8>,
+    _marker: PhantomData<fn(&T)>,
+}
+
+impl<W: Write, T: Serialize> Writer<W, T> {
+    /// Starts a log in `w` whose batches hold up to `batch_records` records
+    /// compressed with `codec`, or stored when `codec` is `None`. Fails if
+    /// the codec cannot compress or isn't compiled in.
+    pub fn new(mut w: W, codec: Option<Format>, batch_records: usize) -> io::Result<Writer<W, T>> {
+        try!(compress(codec, &[]));
+        try!(w.write_all(&MAGIC));
+        Ok(Writer {
+            w,
+            codec,
+            batch_records: if batch_records == 0 { 1 } else { batch_records },
+            count: 0,
+            buf: Vec::new(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Appends a record, writing out the batch once it is full
+    pub fn write(&mut self, record: &T) -> io::Result<()> {
+        try!(bincode::serialize_into(&mut self.buf, record).map_err(|e| bincode_err_to_io(*e)));
+        self.count += 1;
+        if self.count == self.batch_records {
+            try!(self.write_batch());
+        }
+        Ok(())
+    }
+
+    /// Writes out the pending records as a batch, even if it isn't full
+    pub fn write_batch(&mut self) -> io::Result<()> {
+        if self.count == 0 {
+            return Ok(())
+        }
+        let compressed = try!(compress(self.codec, &self.buf));
+        if compressed.len() > u32::MAX as usize {
+            return invalid("record batch is too large")
+        }
+        let mut crc = Crc32::new();
+        crc.feed(&self.buf);
+
+        try!(self.w.write_u8(self.codec.map_or(STORED, |f| f as u8)));
+        try!(self.w.write_u32::<LittleEndian>(self.count as u32));
+        try!(self.w.write_u32::<LittleEndian>(compressed.len() as u32));
+        try!(self.w.write_u32::<LittleEndian>(crc.result()));
+        try!(self.w.write_all(&compressed));
+        self.count = 0;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Writes out the last batch and returns the wrapped writer
+    pub fn finish(mut self) -> io::Result<W> {
+        try!(self.write_batch());
+        try!(self.w.flush());
+        Ok(self.w)
+    }
+}
+
+/// Reads a record log, see the module documentation. Records are returned
+/// by `read` or by iterating.
+pub struct Reader<R, T> {
+    r: R,
+    compressed: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+    left: u32,
+    max_batch: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<R: Read, T: DeserializeOwned> Reader<R, T> {
+    /// Starts reading a log, checking its magic bytes
+    pub fn new(r: R) -> io::Result<Reader<R, T>> {
+        Reader::with_max_batch(r, DEFAULT_MAX_BATCH)
+    }
+
+    /// Starts reading a log whose batches take up to `max_batch` bytes once
+    /// decompressed. A larger batch fails with an `InvalidData` error
+    /// carrying `LimitExceeded`.
+    pub fn with_max_batch(mut r: R, max_batch: usize) -> io::Result<Reader<R, T>> {
+        let mut magic = [0; 4];
+        try!(r.read_exact(&mut magic));
+        if magic != MAGIC {
+            return invalid("not a record log")
+        }
+        Ok(Reader {
+            r,
+            compressed: Vec::new(),
+            buf: Vec::new(),
+            pos: 0,
+            left: 0,
+            max_batch,
+            _marker: PhantomData,
+        })
+    }
+
+    fn read_batch(&mut self) -> io::Result<bool> {
+        let mut codec = [0];
+        if try!(self.r.read(&mut codec)) == 0 {
+            return Ok(false)
+        }
+        let count = try!(self.r.read_u32::<LittleEndian>());
+        let len = try!(self.r.read_u32::<LittleEndian>());
+        let crc = try!(self.r.read_u32::<LittleEndian>());
+        self.compressed.clear();
+        try!(self.r.push_exactly(len as u64, &mut self.compressed));
+        try!(decompress(codec[0], &self.compressed, &mut self.buf, self.max_batch));
+
+        let mut actual = Crc32::new();
+        actual.feed(&self.buf);
+        if actual.result() != crc {
+            return invalid("invalid checksum on record batch")
+        }
+        self.pos = 0;
+        self.left = count;
+        Ok(true)
+    }
+
+    /// Returns the next record, or `None` at the end of the log
+    pub fn read(&mut self) -> io::Result<Option<T>> {
+        while self.left == 0 {
+            if self.pos != self.buf.len() {
+                return invalid("trailing bytes in record batch")
+            }
+            if !try!(self.read_batch()) {
+                return Ok(None)
+            }
+        }
+        let mut rest = &self.buf[self.pos..];
+        let before = rest.len();
+        let record = try!(bincode::deserialize_from(&mut rest).map_err(|e| bincode_err_to_io(*e)));
+        self.pos += before - rest.len();
+        self.left -= 1;
+        Ok(Some(record))
+    }
+
+    /// Returns the wrapped reader
+    pub fn unwrap(self) -> R {
+        self.r
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for Reader<R, T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<io::Result<T>> {
+        match self.read() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::ErrorKind;
+    use super::{Reader, Writer};
+    use {Format, LimitExceeded};
+
+    // a codec that is compiled in, the batch is stored otherwise
+    fn codec() -> Option<Format> {
+        if cfg!(feature="lz4") {
+            Some(Format::Lz4Frame)
+        } else if cfg!(feature="rle") {
+            Some(Format::Rle)
+        } else {
+            None
+        }
+    }
+
+    fn roundtrip(codec: Option<Format>) {
+        let records = (0..500u32).map(|i| (i, format!("{:x}", i * i), vec![i as u8; i as usize % 7]))
+                                 .collect::<Vec<_>>();
+        let mut w = Writer::new(Vec::new(), codec, 64).unwrap();
+        for record in records.iter() {
+            w.write(record).unwrap();
+        }
+        let log = w.finish().unwrap();
+
+        let r = Reader::<_, (u32, String, Vec<u8>)>::new(&log[..]).unwrap();
+        assert_eq!(r.collect::<Result<Vec<_>, _>>().unwrap(), records);
+    }
+
+    #[test]
+    fn roundtrips() {
+        roundtrip(None);
+        if cfg!(feature="lz4") {
+            roundtrip(Some(Format::Lz4Frame));
+        }
+        if cfg!(feature="rle") {
+            roundtrip(Some(Format::Rle));
+        }
+    }
+
+    #[test]
+    fn corrupt_batch() {
+        let mut w = Writer::new(Vec::new(), None, 10).unwrap();
+        for i in 0..25u64 {
+            w.write(&i).unwrap();
+        }
+        let mut log = w.finish().unwrap();
+        // flip a bit of the second record of the first batch
+        log[4 + 13 + 8] ^= 1;
+        let mut r = Reader::<_, u64>::new(&log[..]).unwrap();
+        assert!(r.read().is_err());
+    }
+
+    #[test]
+    fn max_batch() {
+        let mut w = Writer::new(Vec::new(), codec(), 1000).unwrap();
+        for _ in 0..1000 {
+            w.write(&0u64).unwrap();
+        }
+        let log = w.finish().unwrap();
+        let mut r = Reader::<_, u64>::with_max_batch(&log[..], 7999).unwrap();
+        let err = r.read().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(LimitExceeded::find(&err).unwrap().limit, 7999);
+
+        let r = Reader::<_, u64>::with_max_batch(&log[..], 8000).unwrap();
+        assert_eq!(r.count(), 1000);
+    }
+
+    #[test]
+    fn unsupported_codec() {
+        assert!(Writer::<_, u8>::new(Vec::new(), Some(Format::BwtPipe), 10).is_err());
+    }
+}
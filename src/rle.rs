@@ -11,6 +11,23 @@ The most significat bit of each byte in this string indicates if the byte is
 the last byte in the string. The rest of the bits are concatenated using 
 the Little Endian convention.
 
+Doubling every pair costs a length byte, which expands data full of natural
+pairs. `Encoder::with_mode` raises the number of repetitions a run needs
+before it is encoded, so shorter runs pass through unchanged. The chosen
+scheme is stored in a one byte header, and such streams are read back with
+`Decoder::with_header`. `Mode::Escape` marks runs with a per-block escape
+byte instead, which bounds the expansion of any input to one byte in 255.
+
+Headerless streams have no framing at all: empty input encodes to no bytes,
+and `Decoder::new` reads no bytes as empty data. Where a missing stream must
+not pass for an empty one, use `with_mode`, whose streams always hold at
+least the header byte, and `Decoder::with_header`, which fails with
+`UnexpectedEof` when even that is missing. The `Rle` codec does so.
+
+`Encoder` and `Decoder` are thin adapters around `EncoderCore` and
+`DecoderCore`, which do the work on slices without any I/O, see the `io`
+module.
+
 # Example
 
 ```rust
@@ -32,113 +49,319 @@ assert_eq!(&input[..], &decoder_buf[..]);
 
 !*/
 
-use std::io::{self, Write, Read, Bytes};
+use std::cmp;
+use std::io::{self, Write, Read};
+use std::iter;
+use codecs::{Codec, Finish};
+use io::{Core, CoreReader, CoreWriter, Status};
+use metrics;
+
+/// Longest run prefix a stream header can ask for.
+pub const MAX_THRESHOLD: u8 = 0x7f;
+
+/// Number of input bytes sharing one escape byte in `Mode::Escape`.
+pub const ESCAPE_BLOCK: usize = 255;
+
+// shortest run worth the three byte escape sequence
+const ESCAPE_MIN_RUN: usize = 4;
+const ESCAPE_HEADER: u8 = 0x80;
+
+/// Run encoding schemes. `Encoder::new` always uses `Threshold(2)` without a
+/// header, `Encoder::with_mode` records the scheme in a 1-byte header that
+/// `Decoder::with_header` picks up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Runs of at least this many bytes (2 to `MAX_THRESHOLD`) are written
+    /// as that many copies followed by the length of the rest of the run,
+    /// shorter runs are passed through unchanged. Raising it above 2 keeps
+    /// natural pairs from growing by a length byte.
+    Threshold(u8),
+    /// Every block of `ESCAPE_BLOCK` bytes starts with an escape byte that
+    /// does not occur in the block. Runs of four or more bytes become the
+    /// escape byte, the run length and the repeated byte, everything else is
+    /// passed through, so no input grows by more than one byte in 255.
+    Escape,
+}
 
-/// This structure is used to compress a stream of bytes using a RLE
-/// compression algorithm. This is a wrapper around an internal writer which
-/// bytes will be written to.
-pub struct Encoder<W> {
-    w: W,
+impl Mode {
+    fn header(&self) -> u8 {
+        match *self {
+            Mode::Threshold(t) => t,
+            Mode::Escape => ESCAPE_HEADER,
+        }
+    }
+
+    fn from_header(byte: u8) -> Option<Mode> {
+        match byte {
+            2 ..= MAX_THRESHOLD => Some(Mode::Threshold(byte)),
+            ESCAPE_HEADER => Some(Mode::Escape),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Mode {
+    fn default() -> Mode {
+        Mode::Threshold(2)
+    }
+}
+
+/// The I/O free core of `Encoder`, see `io::Core`
+pub struct EncoderCore {
     reps: u64,
+    // copies of the run written out by a flush already
+    emitted: u64,
     byte: u8,
-    in_run: bool
+    threshold: u64,
+    escape: bool,
+    block: Vec<u8>,
+    // the escape byte and length of the part of the block written out by a
+    // flush already
+    block_escape: Option<u8>,
+    block_done: usize,
+    // encoded bytes the output had no room for yet
+    out: Vec<u8>,
+    out_pos: usize,
+    finished: bool,
 }
 
-impl<W: Write> Encoder<W> {
-    /// Creates a new encoder which will have its output written to the given
-    /// output stream.
-    pub fn new(w: W) -> Encoder<W> {
-        Encoder {
-            w: w,
+impl EncoderCore {
+    /// Creates a core encoding like `Encoder::new`
+    pub fn new() -> EncoderCore {
+        EncoderCore {
             reps: 0,
+            emitted: 0,
             byte: 0,
-            in_run: false
+            threshold: 2,
+            escape: false,
+            block: Vec::new(),
+            block_escape: None,
+            block_done: 0,
+            out: Vec::new(),
+            out_pos: 0,
+            finished: false,
         }
     }
 
-    /// This function is used to flag that this session of compression is done
-    /// with. The stream is finished up (final bytes are written), and then the
-    /// wrapped writer is returned.
-    pub fn finish(mut self) -> (W, io::Result<()>) {
-        let result = self.flush();
+    /// Creates a core encoding like `Encoder::with_mode`
+    pub fn with_mode(mode: Mode) -> EncoderCore {
+        let threshold = match mode {
+            Mode::Threshold(t) => {
+                assert!((2..=MAX_THRESHOLD).contains(&t),
+                        "run threshold {} is out of range", t);
+                t as u64
+            }
+            Mode::Escape => 0,
+        };
+        EncoderCore {
+            threshold,
+            escape: mode == Mode::Escape,
+            block: Vec::with_capacity(if mode == Mode::Escape { ESCAPE_BLOCK } else { 0 }),
+            out: vec![mode.header()],
+            .. EncoderCore::new()
+        }
+    }
+
+    fn drain(&mut self, output: &mut [u8]) -> usize {
+        let n = cmp::min(self.out.len() - self.out_pos, output.len());
+        output[..n].copy_from_slice(&self.out[self.out_pos..self.out_pos + n]);
+        self.out_pos += n;
+        if self.out_pos == self.out.len() {
+            self.out.clear();
+            self.out_pos = 0;
+        }
+        n
+    }
 
-        (self.w, result)
+    fn write_run(&mut self) {
+        let copies = cmp::min(self.reps, self.threshold);
+        self.out.extend(iter::repeat_n(self.byte, (copies - self.emitted) as usize));
+        if self.reps >= self.threshold {
+            let mut reps_encode = self.reps - self.threshold;
+            loop {
+                let byte = (reps_encode & 0b0111_1111) as u8;
+                reps_encode = reps_encode >> 7;
+
+                if reps_encode == 0 {
+                    self.out.push(byte | 0b1000_0000);
+                    break;
+                }
+                self.out.push(byte);
+            }
+        }
+        self.reps = 0;
+        self.emitted = 0;
     }
 
-    fn process_byte(&mut self, byte: u8) -> io::Result<()> {
-        if self.byte == byte {
-            self.reps += 1;
-        } else if self.byte != byte {
-            try!(self.flush());
-            self.reps = 1;
-            self.byte = byte;
+    // Writes out the buffered part of the block, leaving the block open to
+    // more bytes if `partial`
+    fn write_escape_block(&mut self, partial: bool) {
+        if self.block.is_empty() {
+            return
         }
+        let escape = match self.block_escape {
+            Some(escape) => escape,
+            None => {
+                // a block is too short to contain all 256 byte values
+                let mut seen = [false; 256];
+                for &b in self.block.iter() {
+                    seen[b as usize] = true;
+                }
+                let escape = seen.iter().position(|&s| !s).unwrap() as u8;
+                self.out.push(escape);
+                escape
+            }
+        };
 
-        Ok(())
+        // after a flush the escape byte may turn up in the rest of the
+        // block, it is then written as a run of its own
+        for run in self.block.chunk_by(|a, b| a == b) {
+            if run.len() >= ESCAPE_MIN_RUN || run[0] == escape {
+                self.out.extend_from_slice(&[escape, run.len() as u8, run[0]]);
+            } else {
+                self.out.extend_from_slice(run);
+            }
+        }
+        self.block_done += self.block.len();
+        self.block.clear();
+        if partial && self.block_done < ESCAPE_BLOCK {
+            self.block_escape = Some(escape);
+        } else {
+            self.block_escape = None;
+            self.block_done = 0;
+        }
     }
 }
 
-impl<W: Write> Write for Encoder<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if ! self.in_run && buf.len() > 0 {
-            self.byte = buf[0];
-            self.reps = 1;
-            self.in_run = true;
+impl Default for EncoderCore {
+    fn default() -> EncoderCore {
+        EncoderCore::new()
+    }
+}
+
+impl Core for EncoderCore {
+    fn process(&mut self, input: &[u8], output: &mut [u8], finish: bool)
+               -> io::Result<(usize, usize, Status)> {
+        let mut produced = self.drain(output);
+        let mut used = 0;
+        while used < input.len() && self.out.is_empty() {
+            if self.escape {
+                let left = ESCAPE_BLOCK - self.block_done - self.block.len();
+                let amt = cmp::min(left, input.len() - used);
+                self.block.extend_from_slice(&input[used..used + amt]);
+                used += amt;
+                if amt == left {
+                    self.write_escape_block(false);
+                }
+            } else {
+                let byte = input[used];
+                used += 1;
+                if self.reps > 0 && self.byte == byte {
+                    self.reps += 1;
+                    continue
+                }
+                self.write_run();
+                self.reps = 1;
+                self.byte = byte;
+            }
+            produced += self.drain(&mut output[produced..]);
         }
 
-        for byte in &buf[1..] {
-            try!(self.process_byte(*byte));
+        if finish && used == input.len() && !self.finished {
+            if self.escape {
+                self.write_escape_block(false);
+            } else {
+                self.write_run();
+            }
+            self.finished = true;
+            produced += self.drain(&mut output[produced..]);
         }
+        let status = if self.finished && self.out.is_empty() { Status::StreamEnd } else { Status::Ok };
+        Ok((used, produced, status))
+    }
 
-        Ok(buf.len())
+    // A run which may still grow is written as far as the decoder can take
+    // it: up to the threshold the copies are a prefix of the full run, past
+    // it the run is ended and the next byte starts a new one. In
+    // `Mode::Escape` the part of the block seen so far is written, and the
+    // rest of the block goes on with the same escape byte.
+    fn flush(&mut self) {
+        if self.finished {
+            return
+        }
+        if self.escape {
+            self.write_escape_block(true);
+        } else if self.reps >= self.threshold {
+            self.write_run();
+        } else {
+            let n = self.reps - self.emitted;
+            self.out.extend(iter::repeat_n(self.byte, n as usize));
+            self.emitted = self.reps;
+        }
     }
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        if self.reps == 1 {
-            try!(self.w.write(&[self.byte]));
-        } else if self.reps > 1 {
-            let mut buf = [0; 11];
-            let mut reps_encode = self.reps - 2;
-            let mut index = 2;
-            buf[0] = self.byte;
-            buf[1] = self.byte;
+/// This structure is used to compress a stream of bytes using a RLE
+/// compression algorithm. This is a wrapper around an internal writer which
+/// bytes will be written to.
+pub struct Encoder<W> {
+    inner: CoreWriter<W, EncoderCore>,
+}
 
-            loop {
-                buf[index] = (reps_encode & 0b0111_1111) as u8;
-                reps_encode = reps_encode >> 7;
+impl<W: Write> Encoder<W> {
+    /// Creates a new encoder which will have its output written to the given
+    /// output stream.
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder { inner: CoreWriter::new(w, EncoderCore::new()) }
+    }
 
-                if reps_encode == 0 {
-                    buf[index] = buf[index] | 0b1000_0000;
-                    break;
-                }
+    /// Creates a new encoder using the given scheme, which is recorded in a
+    /// header byte at the start of the stream. Such streams have to be read
+    /// back with `Decoder::with_header`.
+    pub fn with_mode(w: W, mode: Mode) -> Encoder<W> {
+        Encoder { inner: CoreWriter::new(w, EncoderCore::with_mode(mode)) }
+    }
 
-                index += 1;
-            }
+    /// This function is used to flag that this session of compression is done
+    /// with. The stream is finished up (final bytes are written), and then the
+    /// wrapped writer is returned.
+    pub fn finish(self) -> (W, io::Result<()>) {
+        self.inner.finish()
+    }
+}
 
-            try!(self.w.write(&buf[..(index + 1)]));
-        }
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
 
-        Ok(())
+    /// Writes out the run in progress as far as the format allows, so that
+    /// everything written so far can be decoded, and flushes the wrapped
+    /// writer. Frequent flushes make the output a little larger.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
 struct RunBuilder {
     byte: u8,
+    base: u64,
     slice: [u8; 9],
     byte_count: u8
 }
 
 impl RunBuilder {
-    fn new(byte: u8) -> RunBuilder {
+    fn new(byte: u8, base: u64) -> RunBuilder {
         RunBuilder {
             byte: byte,
+            base,
             slice: [0; 9],
             byte_count: 0
         }
     }
 
     fn to_run(&mut self) -> Run {
-        let reps = 2 + self.slice.iter().enumerate().fold(0u64, |reps, (i, &byte)| {
+        let reps = self.base + self.slice.iter().enumerate().fold(0u64, |reps, (i, &byte)| {
             reps | (((byte & 0b0111_1111) as u64) << (i as u32 * 7))
         });
 
@@ -166,126 +389,328 @@ struct Run {
 
 enum DecoderState {
     Clean,
-    Single(u8),
-    Run(RunBuilder)
+    Repeat(u8, u64),
+    Run(RunBuilder),
+    // after an escape byte and after its run length
+    Escaped,
+    EscapedReps(u8),
 }
 
-/// This structure is used to decode a run length encoded stream. This wraps
-/// an internal reader which is read from when this decoder's read method is
-/// called.
-pub struct Decoder<R> {
-    buf: Bytes<R>,
+/// The I/O free core of `Decoder`, see `io::Core`
+pub struct DecoderCore {
     state: DecoderState,
-    run: Option<Run>
+    run: Option<Run>,
+    mode: Mode,
+    threshold: u64,
+    read_header: bool,
+    escape: u8,
+    block_left: u64,
+    ended: bool,
 }
 
-impl<R: Read> Decoder<R> {
-    /// Creates a new decoder which will read data from the given stream. The
-    /// inner stream can be re-acquired by moving out of the `r` field of this
-    /// structure.
-    pub fn new(r: R) -> Decoder<R> {
-        Decoder {
-            buf: r.bytes(),
+impl DecoderCore {
+    /// Creates a core decoding like `Decoder::new`
+    pub fn new() -> DecoderCore {
+        DecoderCore {
             state: DecoderState::Clean,
-            run: None
+            run: None,
+            mode: Mode::default(),
+            threshold: 2,
+            read_header: true,
+            escape: 0,
+            block_left: 0,
+            ended: false,
         }
     }
 
-    fn read_byte(&mut self) -> io::Result<Option<u8>> {
-        if let None = self.run {
-            try!(self.read_run());
+    /// Creates a core decoding like `Decoder::with_header`
+    pub fn with_header() -> DecoderCore {
+        DecoderCore {
+            read_header: false,
+            .. DecoderCore::new()
         }
+    }
 
-        if let Some(Run { byte: b, reps: r }) = self.run {
-            if r <= 1 {
-                self.run = None;
-            } else {
-                self.run = Some(Run { byte: b, reps: r - 1 });
+    /// Returns the scheme of the stream, or `None` while its header hasn't
+    /// been seen
+    pub fn mode(&self) -> Option<Mode> {
+        if self.read_header { Some(self.mode) } else { None }
+    }
+
+    fn header(&mut self, byte: u8) -> io::Result<()> {
+        self.mode = match Mode::from_header(byte) {
+            Some(mode) => mode,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "unknown rle header")),
+        };
+        if let Mode::Threshold(t) = self.mode {
+            self.threshold = t as u64;
+        }
+        self.read_header = true;
+        Ok(())
+    }
+
+    fn feed(&mut self, byte: u8) -> io::Result<()> {
+        if !self.read_header {
+            return self.header(byte)
+        }
+        if self.mode == Mode::Escape {
+            return self.feed_escaped(byte)
+        }
+
+        match self.state {
+            DecoderState::Repeat(current, count) => {
+                if byte != current {
+                    self.run = Some(Run { byte: current, reps: count });
+                    self.state = DecoderState::Repeat(byte, 1);
+                } else if count + 1 == self.threshold {
+                    self.state = DecoderState::Run(RunBuilder::new(byte, self.threshold));
+                } else {
+                    self.state = DecoderState::Repeat(byte, count + 1);
+                }
+            },
+            DecoderState::Run(ref mut run_builder) => {
+                try!(run_builder.add_byte(byte));
+
+                if Self::is_final_run_byte(byte) {
+                    self.run = Some(run_builder.to_run());
+                    self.state = DecoderState::Clean;
+                }
             }
+            _ => {
+                self.state = DecoderState::Repeat(byte, 1);
+            },
+        }
+        Ok(())
+    }
 
-            return Ok(Some(b))
+    fn feed_escaped(&mut self, byte: u8) -> io::Result<()> {
+        match self.state {
+            DecoderState::Escaped => {
+                if byte == 0 || byte as u64 > self.block_left {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              "invalid rle escape sequence"))
+                }
+                self.state = DecoderState::EscapedReps(byte);
+            }
+            DecoderState::EscapedReps(reps) => {
+                self.run = Some(Run { byte, reps: reps as u64 });
+                self.block_left -= reps as u64;
+                self.state = DecoderState::Clean;
+            }
+            _ if self.block_left == 0 => {
+                self.escape = byte;
+                self.block_left = ESCAPE_BLOCK as u64;
+            }
+            _ if byte == self.escape => self.state = DecoderState::Escaped,
+            _ => {
+                self.run = Some(Run { byte, reps: 1 });
+                self.block_left -= 1;
+            }
         }
+        Ok(())
+    }
 
-        Ok(None)
+    // input exhausted -- flush remaining state into run
+    fn end(&mut self) -> io::Result<()> {
+        if !self.read_header {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing rle header"))
+        }
+        self.run = match self.state {
+            DecoderState::Clean => None,
+            DecoderState::Repeat(byte, count) => Some(Run { byte: byte, reps: count }),
+            DecoderState::Run(ref mut run_builder) => Some(run_builder.to_run()),
+            DecoderState::Escaped | DecoderState::EscapedReps(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "invalid rle escape sequence"))
+            }
+        };
+        self.state = DecoderState::Clean;
+        Ok(())
     }
 
-    fn read_run(&mut self) -> io::Result<()> {
-        let mut reset = false;
+    // drops up to `n` bytes of the decoded run without expanding them
+    fn skip_run(&mut self, n: u64) -> u64 {
+        match self.run {
+            Some(Run { byte, reps }) => {
+                let k = cmp::min(n, reps);
+                self.run = if k == reps { None } else { Some(Run { byte, reps: reps - k }) };
+                k
+            }
+            None => 0,
+        }
+    }
 
-        while let Some(result) = self.buf.next() {
-            let byte = try!(result);
+    fn is_final_run_byte(byte: u8) -> bool {
+        0b1000_0000 & byte != 0
+    }
+}
 
-            match self.state {
-                DecoderState::Clean => {
-                    self.state = DecoderState::Single(byte);
-                },
-                DecoderState::Single(current) => {
-                    if byte == current {
-                        self.state = DecoderState::Run(RunBuilder::new(byte));
-                    } else {
-                        self.run = Some(Run { byte: current, reps: 1 });
-                        self.state = DecoderState::Single(byte);
-                        break;
-                    }
-                },
-                DecoderState::Run(ref mut run_builder) => {
-                    try!(run_builder.add_byte(byte));
+impl Default for DecoderCore {
+    fn default() -> DecoderCore {
+        DecoderCore::new()
+    }
+}
 
-                    if Self::is_final_run_byte(byte) {
-                        self.run = Some(run_builder.to_run());
-                        reset = true;
-                        break;
-                    }
+impl Core for DecoderCore {
+    fn process(&mut self, input: &[u8], output: &mut [u8], finish: bool)
+               -> io::Result<(usize, usize, Status)> {
+        let (mut used, mut produced) = (0, 0);
+        loop {
+            if let Some(Run { byte, reps }) = self.run {
+                let k = cmp::min(reps, (output.len() - produced) as u64) as usize;
+                for slot in output[produced..produced + k].iter_mut() {
+                    *slot = byte;
+                }
+                produced += k;
+                self.skip_run(k as u64);
+                if self.run.is_some() {
+                    break
+                }
+            }
+            if used == input.len() {
+                if finish && !self.ended {
+                    try!(self.end());
+                    self.ended = true;
+                    continue
                 }
+                break
             }
+            try!(self.feed(input[used]));
+            used += 1;
         }
+        let status = if self.ended && self.run.is_none() { Status::StreamEnd } else { Status::Ok };
+        Ok((used, produced, status))
+    }
+}
 
-        if reset {
-            self.state = DecoderState::Clean;
-        }
+/// This structure is used to decode a run length encoded stream. This wraps
+/// an internal reader which is read from when this decoder's read method is
+/// called.
+pub struct Decoder<R> {
+    inner: CoreReader<R, DecoderCore>,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a new decoder which will read data from the given stream.
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder { inner: CoreReader::new(r, DecoderCore::new()) }
+    }
 
-        // internal read object exhausted -- flush remaining state into run
-        if let None = self.run {
-            self.run = match self.state {
-                DecoderState::Clean => None,
-                DecoderState::Single(byte) => Some(Run { byte: byte, reps: 1 }),
-                DecoderState::Run(ref mut run_builder) => Some(run_builder.to_run())
-            };
+    /// Creates a new decoder for a stream written by `Encoder::with_mode`,
+    /// which starts with a header byte describing the scheme.
+    pub fn with_header(r: R) -> Decoder<R> {
+        Decoder { inner: CoreReader::new(r, DecoderCore::with_header()) }
+    }
 
-            self.state = DecoderState::Clean;
+    /// Returns the scheme of the stream, reading its header if it has not
+    /// been seen yet.
+    pub fn mode(&mut self) -> io::Result<Mode> {
+        loop {
+            if let Some(mode) = self.inner.core().mode() {
+                return Ok(mode)
+            }
+            try!(self.inner.read(&mut []));
         }
+    }
 
-        Ok(())
+    /// Decodes and throws away the next `n` bytes of output, returning how
+    /// many were skipped, which is less than `n` only at the end of the
+    /// stream. Runs are skipped without being expanded.
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        let mut left = n;
+        while left > 0 {
+            left -= self.inner.core_mut().skip_run(left);
+            // decoding one byte leaves the rest of its run in the core
+            if left > 0 {
+                if try!(self.inner.read(&mut [0])) == 0 {
+                    break
+                }
+                left -= 1;
+            }
+        }
+        Ok(n - left)
     }
 
-    fn is_final_run_byte(byte: u8) -> bool {
-        0b1000_0000 & byte != 0
+    /// Decodes the rest of the stream into `output`, which has to be exactly
+    /// as long as the decoded data. Beyond the input buffer allocated with
+    /// the decoder, this needs no allocation.
+    pub fn decode_exact_into(&mut self, output: &mut [u8]) -> io::Result<()> {
+        let mut pos = 0;
+        while pos < output.len() {
+            match try!(self.inner.read(&mut output[pos..])) {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                               "rle stream is shorter than the output")),
+                n => pos += n,
+            }
+        }
+        if try!(self.inner.read(&mut [0])) > 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "rle stream is longer than the output"))
+        }
+        Ok(())
     }
 }
 
 impl<R: Read> Read for Decoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut bytes_read = 0;
+        self.inner.read(buf)
+    }
+}
 
-        for slot in buf {
-            match try!(self.read_byte()) {
-                Some(b) => *slot = b,
-                None => break
-            }
 
-            bytes_read += 1;
-        }
+/// Run-length encodes `data` in one go, in the threshold mode
+pub fn compress_to_vec(data: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.write_all(data).expect("writing to a Vec can't fail");
+    let (out, result) = e.finish();
+    result.expect("writing to a Vec can't fail");
+    out
+}
+
+/// Decodes run-length encoded data held in memory in one go
+pub fn decompress_to_vec(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    try!(Decoder::new(data).read_to_end(&mut out));
+    Ok(out)
+}
 
-        Ok(bytes_read)
+/// Run-length encoding as a runtime selectable `Codec`. The scheme is
+/// recorded in a header byte, see `Encoder::with_mode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rle {
+    /// The scheme encoders use
+    pub mode: Mode,
+}
+
+impl Codec for Rle {
+    fn name(&self) -> &'static str { "rle" }
+
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>> {
+        metrics::encoder("rle", w, |w| Ok(Box::new(Encoder::with_mode(w, self.mode))))
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        metrics::decoder("rle", r, |r| Ok(Box::new(Decoder::with_header(r))))
+    }
+}
+
+impl<W: Write> Finish for Encoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (*self).finish().1
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Decoder, Encoder};
+    use super::{Decoder, DecoderCore, Encoder, EncoderCore, Mode, ESCAPE_BLOCK};
+    use io::{Core, Status};
     use super::super::rand::{RngCore,rngs::OsRng};
-    use std::io::{Write, Read};
+    use std::cell::RefCell;
+    use std::cmp;
+    use std::io::{self, Write, Read};
     use std::iter::{Iterator, repeat};
+    use std::rc::Rc;
     #[cfg(feature="unstable")]
     use test;
 
@@ -351,6 +776,183 @@ mod test {
         test_decode(&[1, 3, 4, 4, 0 + 128, 100, 100, 52, 1 + 128], &data[..]);
     }
 
+    fn test_mode_roundtrip(mode: Mode, input: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::with_mode(Vec::new(), mode);
+        // split writes must not lose or break up runs
+        for chunk in input.chunks(7) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let (buf, result) = encoder.finish();
+        result.unwrap();
+
+        let mut decoder = Decoder::with_header(&buf[..]);
+        let mut decoder_buf = Vec::new();
+        decoder.read_to_end(&mut decoder_buf).unwrap();
+        assert_eq!(decoder.mode().unwrap(), mode);
+        assert_eq!(input, &decoder_buf[..]);
+        buf
+    }
+
+    #[test]
+    fn threshold_encoding() {
+        let buf = test_mode_roundtrip(Mode::Threshold(3), &[7, 7, 1, 1, 1, 1, 1, 2]);
+        assert_eq!(&buf[..], &[3, 7, 7, 1, 1, 1, 2 + 128, 2]);
+
+        // with the default threshold the header is the only difference
+        let buf = test_mode_roundtrip(Mode::Threshold(2), &[20, 20, 20, 20, 20, 15]);
+        assert_eq!(&buf[..], &[2, 20, 20, 3 + 128, 15]);
+
+        test_mode_roundtrip(Mode::Threshold(4), b"");
+        test_mode_roundtrip(Mode::Threshold(4), b"aaabbbbccccccccccccccccccccd");
+        test_mode_roundtrip(Mode::Threshold(127), &[9; 1000]);
+        test_mode_roundtrip(Mode::Threshold(5), include_bytes!("data/test.txt"));
+    }
+
+    #[test]
+    fn threshold_pairs_do_not_expand() {
+        let input = b"aabbccddeeff";
+        let buf = test_mode_roundtrip(Mode::Threshold(3), input);
+        assert_eq!(&buf[1..], &input[..]);
+    }
+
+    #[test]
+    fn escape_encoding() {
+        // 0 is taken, so the escape byte is 1
+        let buf = test_mode_roundtrip(Mode::Escape, &[0, 0, 5, 5, 5, 5, 5, 2]);
+        assert_eq!(&buf[..], &[0x80, 1, 0, 0, 1, 5, 5, 2]);
+
+        test_mode_roundtrip(Mode::Escape, b"");
+        test_mode_roundtrip(Mode::Escape, &[3; 1000]);
+        test_mode_roundtrip(Mode::Escape, include_bytes!("data/test.txt"));
+    }
+
+    #[test]
+    fn escape_worst_case() {
+        let mut input = [0; 13579];
+        OsRng.fill_bytes(&mut input[..]);
+        for (i, b) in input.iter_mut().enumerate().filter(|&(i, _)| i % 3 == 0) {
+            *b = (i / 3) as u8;
+        }
+        let buf = test_mode_roundtrip(Mode::Escape, &input);
+        let blocks = input.len().div_ceil(ESCAPE_BLOCK);
+        assert!(buf.len() <= 1 + input.len() + blocks);
+
+        // pairs everywhere still cost only the escape bytes
+        let pairs = input.iter().flat_map(|&b| vec![b, b]).collect::<Vec<_>>();
+        let buf = test_mode_roundtrip(Mode::Escape, &pairs);
+        let blocks = pairs.len().div_ceil(ESCAPE_BLOCK);
+        assert!(buf.len() <= 1 + pairs.len() + blocks);
+    }
+
+    // feeds the core one byte at a time with one byte of room for output
+    fn trickle<C: Core>(core: &mut C, input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut byte = [0];
+        for i in 0..input.len() + 1 {
+            loop {
+                let (used, produced, status) =
+                    core.process(&input[i..cmp::min(i + 1, input.len())], &mut byte,
+                                 i == input.len()).unwrap();
+                out.extend_from_slice(&byte[..produced]);
+                if used == 1 || status == Status::StreamEnd {
+                    break
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn cores_byte_at_a_time() {
+        let input = b"aaaaaaaaaabcdddddddddddddddddddddddddde";
+        for &mode in &[Mode::Threshold(2), Mode::Threshold(4), Mode::Escape] {
+            let encoded = test_mode_roundtrip(mode, input);
+            assert_eq!(trickle(&mut EncoderCore::with_mode(mode), input), encoded);
+            assert_eq!(&trickle(&mut DecoderCore::with_header(), &encoded)[..], &input[..]);
+        }
+    }
+
+    // a writer whose contents stay readable while an encoder holds it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_makes_output_decodable() {
+        // after a flush the escape byte of the block may still turn up
+        let escapes = [5, 0, 1, 0, 0, 0, 0, 2, 0, 0];
+        for (&mode, input) in [None, Some(Mode::Threshold(2)), Some(Mode::Threshold(4)), Some(Mode::Escape)]
+                .iter().flat_map(|mode| vec![(mode, &b"aaaaaaaaaabcdddddddddddddddddddddddde"[..]),
+                                             (mode, &escapes[..])]) {
+            for &step in &[1, 3, 7] {
+                let out = Shared::default();
+                let mut encoder = match mode {
+                    Some(mode) => Encoder::with_mode(out.clone(), mode),
+                    None => Encoder::new(out.clone()),
+                };
+                for (i, chunk) in input.chunks(step).enumerate() {
+                    encoder.write_all(chunk).unwrap();
+                    encoder.flush().unwrap();
+                    let encoded = out.0.borrow().clone();
+                    let mut decoded = Vec::new();
+                    match mode {
+                        Some(_) => Decoder::with_header(&encoded[..]).read_to_end(&mut decoded),
+                        None => Decoder::new(&encoded[..]).read_to_end(&mut decoded),
+                    }.unwrap();
+                    let len = cmp::min((i + 1) * step, input.len());
+                    assert_eq!(&decoded[..], &input[..len]);
+                }
+                encoder.finish().1.unwrap();
+                let encoded = out.0.borrow().clone();
+                let mut decoded = Vec::new();
+                match mode {
+                    Some(_) => Decoder::with_header(&encoded[..]).read_to_end(&mut decoded),
+                    None => Decoder::new(&encoded[..]).read_to_end(&mut decoded),
+                }.unwrap();
+                assert_eq!(&decoded[..], &input[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut buf = Vec::new();
+        assert!(Decoder::with_header(&[1, 2, 3][..]).read_to_end(&mut buf).is_err());
+        assert!(Decoder::with_header(&[][..]).read_to_end(&mut buf).is_err());
+    }
+
+    #[test]
+    fn skip() {
+        let mut decoder = Decoder::new(&[1, 3, 4, 4, 128, 100, 100, 52, 129][..]);
+        assert_eq!(decoder.skip(3).unwrap(), 3);
+        assert_eq!(decoder.skip(150).unwrap(), 150);
+        let mut rest = Vec::new();
+        decoder.read_to_end(&mut rest).unwrap();
+        assert_eq!(&rest[..], &[100; 33][..]);
+        assert_eq!(decoder.skip(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn decode_exact_into() {
+        let input = [1, 3, 4, 4, 128, 100, 100, 52, 129];
+        let mut output = [0; 186];
+        Decoder::new(&input[..]).decode_exact_into(&mut output).unwrap();
+        assert_eq!(&output[..4], &[1, 3, 4, 4]);
+        assert_eq!(&output[4..], &[100; 182][..]);
+
+        assert!(Decoder::new(&input[..]).decode_exact_into(&mut [0; 185]).is_err());
+        assert!(Decoder::new(&input[..]).decode_exact_into(&mut [0; 187]).is_err());
+    }
+
     #[test]
     fn random_roundtrips() {
         for _ in 0..100 {
@@ -400,4 +1002,12 @@ mod test {
 
         bh.bytes = output_size as u64;
     }
+
+    #[test]
+    fn one_shot() {
+        let input = b"aaaaaaaaaabcdddddddddddddddddddddddddde";
+        let compressed = super::compress_to_vec(input);
+        assert!(compressed.len() < input.len());
+        assert_eq!(&super::decompress_to_vec(&compressed).unwrap()[..], &input[..]);
+    }
 }
@@ -0,0 +1,310 @@
+/*!
+
+Keyed stream scrambling. Requires `scramble` feature, enabled by default
+
+This module XORs a stream with the ChaCha20 keystream derived from a 256-bit
+key and a 64-bit nonce. Applying the same key and nonce again restores the
+original data, so encoding and decoding are the same operation.
+
+`Encoder::new` and `Decoder::new` take the nonce from the caller and write
+nothing but the scrambled data. `Encoder::with_random_nonce` picks a random
+nonce instead and writes it ahead of the data, in `NONCE_BYTES` little-endian
+bytes, for `Decoder::with_header` to read back, so that a key can be reused
+for any number of streams.
+
+This is obfuscation, not encryption: there is no authentication of any kind,
+a flipped bit in the scrambled stream silently flips the same bit of the
+output, and reusing a key/nonce pair for two streams leaks their XOR. Use it
+to hide payloads from casual inspection, never to protect secrets.
+
+# Example
+
+```rust
+use std::io::{Read, Write};
+use compress::scramble;
+
+let key = [7u8; 32];
+let mut e = scramble::Encoder::new(Vec::new(), &key, 1);
+e.write_all(b"hello world").unwrap();
+let scrambled = e.finish();
+assert!(&scrambled[..] != b"hello world");
+
+let mut d = scramble::Decoder::new(&scrambled[..], &key, 1);
+let mut plain = Vec::new();
+d.read_to_end(&mut plain).unwrap();
+assert_eq!(&plain[..], b"hello world");
+```
+
+# Links
+
+https://cr.yp.to/chacha.html
+
+*/
+
+use std::io::{self, Read, Write};
+
+use rand;
+
+/// Size of the key in bytes
+pub const KEY_BYTES: usize = 32;
+
+/// Size of the nonce header written by `Encoder::with_random_nonce`
+pub const NONCE_BYTES: usize = 8;
+
+const BLOCK_BYTES: usize = 64;
+const ROUNDS: usize = 20;
+
+/// ChaCha20 keystream generator, 64-bit nonce and 64-bit block counter
+pub struct Keystream {
+    state: [u32; 16],
+    block: [u8; BLOCK_BYTES],
+    pos: usize,
+}
+
+impl Keystream {
+    /// Create a keystream for the given key and nonce
+    pub fn new(key: &[u8; KEY_BYTES], nonce: u64) -> Keystream {
+        let mut state = [0u32; 16];
+        // "expand 32-byte k"
+        state[0] = 0x61707865;
+        state[1] = 0x3320646e;
+        state[2] = 0x79622d32;
+        state[3] = 0x6b206574;
+        for (i, word) in key.chunks(4).enumerate() {
+            state[4 + i] = read_u32(word);
+        }
+        state[14] = nonce as u32;
+        state[15] = (nonce >> 32) as u32;
+        Keystream {
+            state,
+            block: [0; BLOCK_BYTES],
+            pos: BLOCK_BYTES,
+        }
+    }
+
+    /// XOR the next keystream bytes into the buffer
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            if self.pos == BLOCK_BYTES {
+                self.next_block();
+            }
+            *byte ^= self.block[self.pos];
+            self.pos += 1;
+        }
+    }
+
+    fn next_block(&mut self) {
+        let mut x = self.state;
+        for _ in 0..ROUNDS / 2 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+        for (i, out) in self.block.chunks_mut(4).enumerate() {
+            let word = x[i].wrapping_add(self.state[i]);
+            out[0] = word as u8;
+            out[1] = (word >> 8) as u8;
+            out[2] = (word >> 16) as u8;
+            out[3] = (word >> 24) as u8;
+        }
+        // 64-bit block counter in words 12 and 13
+        self.state[12] = self.state[12].wrapping_add(1);
+        if self.state[12] == 0 {
+            self.state[13] = self.state[13].wrapping_add(1);
+        }
+        self.pos = 0;
+    }
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+#[inline]
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]); x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]); x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+
+/// A scrambling stream encoder
+pub struct Encoder<W> {
+    w: W,
+    keystream: Keystream,
+    buf: Vec<u8>,
+}
+
+impl<W> Encoder<W> {
+    /// start scrambling into the given writer
+    pub fn new(w: W, key: &[u8; KEY_BYTES], nonce: u64) -> Encoder<W> {
+        Encoder {
+            w,
+            keystream: Keystream::new(key, nonce),
+            buf: Vec::new(),
+        }
+    }
+
+    /// finish scrambling and return the wrapped writer
+    pub fn finish(self) -> W {
+        self.w
+    }
+}
+
+impl<W: Write> Encoder<W> {
+    /// start scrambling into the given writer with a random nonce, which is
+    /// written to it right away
+    pub fn with_random_nonce(mut w: W, key: &[u8; KEY_BYTES]) -> io::Result<Encoder<W>> {
+        let nonce = rand::random::<u64>();
+        try!(w.write_all(&nonce.to_le_bytes()));
+        Ok(Encoder::new(w, key, nonce))
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        self.buf.extend_from_slice(buf);
+        self.keystream.apply(&mut self.buf);
+        try!(self.w.write_all(&self.buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+
+/// A descrambling stream decoder
+pub struct Decoder<R> {
+    r: R,
+    keystream: Keystream,
+    // the key until the nonce header has been read
+    key: Option<[u8; KEY_BYTES]>,
+}
+
+impl<R> Decoder<R> {
+    /// start descrambling the given reader
+    pub fn new(r: R, key: &[u8; KEY_BYTES], nonce: u64) -> Decoder<R> {
+        Decoder {
+            r,
+            keystream: Keystream::new(key, nonce),
+            key: None,
+        }
+    }
+
+    /// start descrambling a stream written by `Encoder::with_random_nonce`,
+    /// the nonce is read on the first call to `read`, which fails with
+    /// `UnexpectedEof` if the stream is too short to hold it
+    pub fn with_header(r: R, key: &[u8; KEY_BYTES]) -> Decoder<R> {
+        Decoder {
+            r,
+            keystream: Keystream::new(key, 0),
+            key: Some(*key),
+        }
+    }
+
+    /// finish descrambling and return the wrapped reader
+    pub fn finish(self) -> R {
+        self.r
+    }
+}
+
+impl<R: Read> Decoder<R> {
+    /// descramble and throw away the next `n` bytes, return how many were skipped
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        io::copy(&mut self.by_ref().take(n), &mut io::sink())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        if let Some(key) = self.key {
+            let mut nonce = [0; NONCE_BYTES];
+            try!(self.r.read_exact(&mut nonce));
+            self.keystream = Keystream::new(&key, u64::from_le_bytes(nonce));
+            self.key = None;
+        }
+        let n = try!(self.r.read(dst));
+        self.keystream.apply(&mut dst[..n]);
+        Ok(n)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use super::{Decoder, Encoder, Keystream, NONCE_BYTES};
+
+    #[test]
+    fn keystream_reference() {
+        // first keystream block for the all-zero key and nonce
+        let mut ks = Keystream::new(&[0; 32], 0);
+        let mut block = [0u8; 32];
+        ks.apply(&mut block);
+        assert_eq!(&block[..], &[
+            0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90,
+            0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86, 0xbd, 0x28,
+            0xbd, 0xd2, 0x19, 0xb8, 0xa0, 0x8d, 0xed, 0x1a,
+            0xa8, 0x36, 0xef, 0xcc, 0x8b, 0x77, 0x0d, 0xc7][..]);
+    }
+
+    fn roundtrip(bytes: &[u8]) {
+        let key = [0x5a; 32];
+        let mut e = Encoder::new(Vec::new(), &key, 42);
+        // uneven writes must not disturb the keystream position
+        for chunk in bytes.chunks(77) {
+            e.write_all(chunk).unwrap();
+        }
+        let encoded = e.finish();
+        assert_eq!(encoded.len(), bytes.len());
+
+        let mut d = Decoder::new(&encoded[..], &key, 42);
+        let mut decoded = Vec::new();
+        d.read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], bytes);
+    }
+
+    #[test]
+    fn some_roundtrips() {
+        roundtrip(b"");
+        roundtrip(b"scramble");
+        roundtrip(include_bytes!("data/test.txt"));
+    }
+
+    #[test]
+    fn random_nonce() {
+        let key = [0x5a; 32];
+        let input = include_bytes!("data/test.txt");
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut e = Encoder::with_random_nonce(Vec::new(), &key).unwrap();
+            e.write_all(input).unwrap();
+            let encoded = e.finish();
+            assert_eq!(encoded.len(), NONCE_BYTES + input.len());
+
+            let mut decoded = Vec::new();
+            Decoder::with_header(&encoded[..], &key).read_to_end(&mut decoded).unwrap();
+            assert_eq!(&decoded[..], &input[..]);
+            streams.push(encoded);
+        }
+        // the same key and data give different streams
+        assert!(streams[0] != streams[1]);
+
+        let e = Encoder::with_random_nonce(Vec::new(), &key).unwrap();
+        let encoded = e.finish();
+        let mut decoded = Vec::new();
+        Decoder::with_header(&encoded[..], &key).read_to_end(&mut decoded).unwrap();
+        assert!(decoded.is_empty());
+        let err = Decoder::with_header(&encoded[..NONCE_BYTES - 1], &key).read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,206 @@
+/*!
+
+Whole messages compressed and decompressed into shared `Bytes` buffers.
+Requires `bytes` feature, not enabled by default
+
+Network code built on the `bytes` crate passes messages around as `Bytes`,
+reference counted slices of a shared allocation. `Buffers` compresses or
+decompresses a message read from any `Buf` with a `Codec`, writing the
+output straight into a `BytesMut` and handing it out as `Bytes` without
+copying it again. Fresh room for decoded output is zeroed once before the
+decoder writes into it, as safe code can't hand out uninitialized memory.
+Decoding stops at a limit on the size of the message. Once the messages
+handed out are dropped, the allocation is reused for the next one, so a
+connection decoding message after message doesn't allocate for each of
+them. Messages stored by the `Identity` codec
+are returned as a slice of the input itself.
+
+# Example
+
+```rust
+# extern crate bytes;
+# extern crate compress;
+# fn main() {
+use bytes::Bytes;
+use compress::shared::Buffers;
+
+let codec = compress::codec_by_name("lz4").unwrap();
+let mut buffers = Buffers::new();
+let compressed = buffers.encode(&*codec, &b"a message, a message"[..]).unwrap();
+let decoded = buffers.decode(&*codec, compressed, 1 << 20).unwrap();
+assert_eq!(decoded, Bytes::from_static(b"a message, a message"));
+# }
+```
+
+*/
+
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use {Codec, LimitExceeded};
+
+// how much room is made in the output buffer at a time
+const CHUNK: usize = 32 << 10;
+
+/// An output buffer reused from one message to the next
+#[derive(Debug, Default)]
+pub struct Buffers {
+    out: BytesMut,
+}
+
+impl Buffers {
+    /// Creates buffers which allocate once the first message is coded
+    pub fn new() -> Buffers {
+        Buffers { out: BytesMut::new() }
+    }
+
+    /// Creates buffers with room for `capacity` bytes of output
+    pub fn with_capacity(capacity: usize) -> Buffers {
+        Buffers { out: BytesMut::with_capacity(capacity) }
+    }
+
+    // Makes room for a message, at the start of the allocation if nothing
+    // refers to it any more
+    fn start(&mut self) {
+        self.out.clear();
+        self.out.reserve(CHUNK);
+    }
+
+    /// Compresses all of `input` into a complete stream of `codec`. The
+    /// chunks of `input` are fed to the encoder as they are.
+    pub fn encode<B: Buf>(&mut self, codec: &dyn Codec, mut input: B) -> io::Result<Bytes> {
+        self.start();
+        {
+            let mut e = try!(codec.encoder(Box::new((&mut self.out).writer())));
+            while input.has_remaining() {
+                let n = {
+                    let chunk = input.chunk();
+                    try!(e.write_all(chunk));
+                    chunk.len()
+                };
+                input.advance(n);
+            }
+            try!(e.finish());
+        }
+        Ok(self.out.split().freeze())
+    }
+
+    /// Decompresses the stream of `codec` in `input`, which is only copied
+    /// for `Identity` if `input` isn't `Bytes` already. Messages coming from
+    /// the network may be crafted to decompress to far more than they take,
+    /// so a message longer than `limit` fails with an `InvalidData` error
+    /// carrying `LimitExceeded`, once `limit` and one more byte were decoded.
+    pub fn decode<B: Buf>(&mut self, codec: &dyn Codec, mut input: B, limit: usize)
+                          -> io::Result<Bytes> {
+        if codec.is_identity() {
+            let len = input.remaining();
+            if len > limit {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, LimitExceeded { limit }))
+            }
+            return Ok(input.copy_to_bytes(len))
+        }
+        self.start();
+        let mut d = try!(codec.decoder(Box::new(input.reader())));
+        // `out` is kept at its capacity, so that the room for output is
+        // zeroed once rather than before every read, `len` is the end of
+        // the message
+        let mut len = 0;
+        loop {
+            if len == self.out.len() {
+                if len > limit {
+                    self.out.clear();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, LimitExceeded { limit }))
+                }
+                if self.out.capacity() == len {
+                    self.out.reserve(CHUNK);
+                }
+                let capacity = self.out.capacity();
+                self.out.resize(capacity, 0);
+            }
+            // one byte past the limit tells whether more remain
+            let end = cmp::min(self.out.len(), limit.saturating_add(1));
+            match d.read(&mut self.out[len..end]) {
+                Ok(0) if len < end => break,
+                Ok(0) => {
+                    self.out.clear();
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, LimitExceeded { limit }))
+                }
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.out.clear();
+                    return Err(e)
+                }
+            }
+        }
+        self.out.truncate(len);
+        Ok(self.out.split().freeze())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{Buf, Bytes};
+    use super::{Buffers, CHUNK};
+    use {codec_by_name, Identity, LimitExceeded};
+
+    #[test]
+    fn roundtrips() {
+        let input = &include_bytes!("data/test.large")[..100_000];
+        let mut buffers = Buffers::new();
+        for name in ["flate", "lz4", "identity"].iter() {
+            let codec = match codec_by_name(name) {
+                Some(codec) => codec,
+                None => continue,
+            };
+            // a message arriving in two pieces
+            let (a, b) = input.split_at(12_345);
+            let compressed = buffers.encode(&*codec, a.chain(b)).unwrap();
+            let decoded = buffers.decode(&*codec, compressed, input.len()).unwrap();
+            assert!(decoded[..] == input[..], "{} failed", name);
+        }
+    }
+
+    #[test]
+    fn reuses_buffers() {
+        let mut buffers = Buffers::new();
+        let stored = Bytes::from_static(include_bytes!("data/test.txt"));
+        let decoded = buffers.decode(&Identity, stored.clone(), stored.len()).unwrap();
+        assert_eq!(decoded.as_ptr(), stored.as_ptr());
+
+        let codec = match codec_by_name("lz4") {
+            Some(codec) => codec,
+            None => return,
+        };
+        let compressed = Buffers::new().encode(&*codec, stored.clone()).unwrap();
+        let first = buffers.decode(&*codec, &compressed[..], stored.len()).unwrap();
+        let ptr = first.as_ptr() as usize;
+        drop(first);
+        // the messages take turns in the first allocation
+        for _ in 0..50 {
+            let next = buffers.decode(&*codec, &compressed[..], stored.len()).unwrap();
+            assert_eq!(next, stored);
+            assert!((next.as_ptr() as usize).wrapping_sub(ptr) < CHUNK);
+        }
+        assert!(buffers.decode(&*codec, &b"not lz4"[..], stored.len()).is_err());
+    }
+
+    #[test]
+    fn limit() {
+        let mut buffers = Buffers::new();
+        let bomb = Bytes::from(vec![0; 100_000]);
+        for name in ["flate", "lz4", "identity"].iter() {
+            let codec = match codec_by_name(name) {
+                Some(codec) => codec,
+                None => continue,
+            };
+            let compressed = buffers.encode(&*codec, bomb.clone()).unwrap();
+            let err = buffers.decode(&*codec, compressed.clone(), 99_999).unwrap_err();
+            assert_eq!(LimitExceeded::find(&err).unwrap().limit, 99_999);
+            // the limit itself is fine
+            assert_eq!(buffers.decode(&*codec, compressed, 100_000).unwrap(), bomb);
+        }
+    }
+}
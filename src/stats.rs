@@ -0,0 +1,69 @@
+//! Wall time spent in the stages of an encoder
+
+use std::time::{Duration, Instant};
+
+/// Time an encoder spent in each of its stages. Timing is off by default and
+/// turned on by the `enable_stats` method of the encoders supporting it; the
+/// totals are returned by their `finish_with_stats`. Stages an encoder
+/// doesn't have stay at zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Searching the history for matches
+    pub match_finding: Duration,
+    /// Turning literals and matches into the output bits
+    pub entropy_coding: Duration,
+    /// Computing checksums of the data
+    pub checksumming: Duration,
+}
+
+impl Stats {
+    /// Returns the time spent in all stages together
+    pub fn total(&self) -> Duration {
+        self.match_finding + self.entropy_coding + self.checksumming
+    }
+}
+
+/// A stage of encoding
+#[derive(Clone, Copy)]
+pub enum Stage {
+    MatchFinding,
+    #[cfg(feature="flate")]
+    EntropyCoding,
+    #[cfg(feature="gzip")]
+    Checksumming,
+}
+
+/// Adds up the time of stages, doing nothing until enabled
+#[derive(Default)]
+pub struct Timer {
+    enabled: bool,
+    stats: Stats,
+}
+
+impl Timer {
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    /// Starts timing a stage, to be passed to `stop`
+    pub fn start(&self) -> Option<Instant> {
+        if self.enabled { Some(Instant::now()) } else { None }
+    }
+
+    pub fn stop(&mut self, start: Option<Instant>, stage: Stage) {
+        if let Some(start) = start {
+            let elapsed = start.elapsed();
+            *match stage {
+                Stage::MatchFinding => &mut self.stats.match_finding,
+                #[cfg(feature="flate")]
+                Stage::EntropyCoding => &mut self.stats.entropy_coding,
+                #[cfg(feature="gzip")]
+                Stage::Checksumming => &mut self.stats.checksumming,
+            } += elapsed;
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
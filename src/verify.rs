@@ -0,0 +1,311 @@
+/*!
+
+Verification of compressed output after writing. Requires `verify` feature,
+enabled by default
+
+An `Encoder` wraps one of the crate's encoders writing into a seekable
+stream, such as a file. It keeps a CRC-32 and the length of everything
+written and, once the wrapped encoder is finished, seeks back, decodes the
+output with the matching decoder and checks that the same data comes out.
+Decoding is streaming, so the memory needed doesn't grow with the data.
+
+A failed check is reported as an `io::Error` of kind `InvalidData` whose
+inner error is a `VerifyError`.
+
+# Example
+
+```rust
+use std::io::{Cursor, Write};
+use compress::{lz4, verify};
+
+let mut e = verify::Encoder::new(Cursor::new(Vec::new()), lz4::Encoder::new).unwrap();
+e.write_all(b"archived for a long time").unwrap();
+let (out, result) = e.finish();
+result.unwrap();
+assert!(!out.into_inner().is_empty());
+```
+
+*/
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use codecs;
+use Crc32;
+use Format;
+
+/// An encoder whose output can be verified
+pub trait Verifiable: Write {
+    /// The writer the compressed data goes to
+    type Writer;
+
+    /// The format written, selecting the decoder used to check it
+    const FORMAT: Format;
+
+    /// Finishes the stream and returns the wrapped writer
+    fn finish(self) -> (Self::Writer, io::Result<()>);
+}
+
+#[cfg(feature="bzip2")]
+impl<W: Write> Verifiable for ::bzip2::Encoder<W> {
+    type Writer = W;
+    const FORMAT: Format = Format::Bzip2;
+    fn finish(self) -> (W, io::Result<()>) { ::bzip2::Encoder::finish(self) }
+}
+
+#[cfg(feature="flate")]
+impl<W: Write> Verifiable for ::flate::Encoder<W> {
+    type Writer = W;
+    const FORMAT: Format = Format::Deflate;
+    fn finish(self) -> (W, io::Result<()>) { ::flate::Encoder::finish(self) }
+}
+
+#[cfg(all(feature="gzip", feature="zlib"))]
+impl<W: Write> Verifiable for ::gzip::Encoder<W> {
+    type Writer = W;
+    const FORMAT: Format = Format::Gzip;
+    fn finish(self) -> (W, io::Result<()>) { ::gzip::Encoder::finish(self) }
+}
+
+#[cfg(feature="lz4")]
+impl<W: Write> Verifiable for ::lz4::Encoder<W> {
+    type Writer = W;
+    const FORMAT: Format = Format::Lz4Frame;
+    fn finish(self) -> (W, io::Result<()>) { ::lz4::Encoder::finish(self) }
+}
+
+#[cfg(feature="lz4")]
+impl<W: Write + Send> Verifiable for ::lz4::ParallelEncoder<W> {
+    type Writer = W;
+    const FORMAT: Format = Format::Lz4Frame;
+    fn finish(self) -> (W, io::Result<()>) { ::lz4::ParallelEncoder::finish(self) }
+}
+
+#[cfg(feature="rle")]
+impl<W: Write> Verifiable for ::rle::Encoder<W> {
+    type Writer = W;
+    const FORMAT: Format = Format::Rle;
+    fn finish(self) -> (W, io::Result<()>) { ::rle::Encoder::finish(self) }
+}
+
+/// The reason verification failed
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The output could not be decoded
+    Decode(io::Error),
+    /// The output decoded to different data
+    Mismatch,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VerifyError::Decode(ref e) => write!(f, "compressed output does not decode: {}", e),
+            VerifyError::Mismatch => f.write_str("compressed output decodes to different data"),
+        }
+    }
+}
+
+impl Error for VerifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            VerifyError::Decode(ref e) => Some(e),
+            VerifyError::Mismatch => None,
+        }
+    }
+}
+
+fn failed(e: VerifyError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Checksums everything written to it
+struct Digest {
+    crc: Crc32,
+    len: u64,
+}
+
+impl Digest {
+    fn new() -> Digest {
+        Digest { crc: Crc32::new(), len: 0 }
+    }
+}
+
+impl Write for Digest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.crc.feed(buf);
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An encoder checking its output on `finish`, see the module documentation
+pub struct Encoder<E> {
+    inner: E,
+    digest: Digest,
+    start: u64,
+}
+
+impl<W: Read + Write + Seek, E: Verifiable<Writer = W>> Encoder<E> {
+    /// Creates the wrapped encoder with `make`, which writes into `w` from its
+    /// current position on. Fails if the format can't be decoded by this
+    /// build of the crate or the position can't be read, handing `w` back
+    /// untouched.
+    pub fn new<F: FnOnce(W) -> E>(mut w: W, make: F) -> Result<Encoder<E>, (W, io::Error)> {
+        if let Err(e) = codecs::decoder(E::FORMAT, io::empty()) {
+            return Err((w, e))
+        }
+        let start = match w.stream_position() {
+            Ok(start) => start,
+            Err(e) => return Err((w, e)),
+        };
+        Ok(Encoder {
+            inner: make(w),
+            digest: Digest::new(),
+            start,
+        })
+    }
+
+    /// Returns a mutable reference to the wrapped encoder, for instance to
+    /// change its settings. Data written directly to it isn't accounted
+    /// for and fails the verification.
+    pub fn get_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    /// Finishes the wrapped encoder, then decodes its output and compares it
+    /// with what was written. The writer is returned positioned at the end
+    /// of the output.
+    pub fn finish(self) -> (W, io::Result<()>) {
+        let Encoder { inner, digest, start } = self;
+        let (mut w, result) = inner.finish();
+        let result = result.and_then(|_| check(&mut w, E::FORMAT, start, &digest));
+        (w, result)
+    }
+}
+
+fn check<W: Read + Seek>(w: &mut W, format: Format, start: u64, expected: &Digest) -> io::Result<()> {
+    let end = try!(w.stream_position());
+    try!(w.seek(SeekFrom::Start(start)));
+    let mut actual = Digest::new();
+    let decoded = codecs::decoder(format, (&mut *w).take(end - start))
+        .and_then(|mut d| io::copy(&mut d, &mut actual));
+    try!(w.seek(SeekFrom::Start(end)));
+    if let Err(e) = decoded {
+        return Err(failed(VerifyError::Decode(e)))
+    }
+    if actual.len != expected.len || actual.crc.result() != expected.crc.result() {
+        return Err(failed(VerifyError::Mismatch))
+    }
+    Ok(())
+}
+
+impl<E: Verifiable> Write for Encoder<E> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        try!(self.digest.write(&buf[..n]));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+#[cfg(all(feature="lz4", feature="gzip", feature="zlib", feature="rle"))]
+mod test {
+    use std::io::{self, Cursor, Seek, SeekFrom, Write};
+    use super::{Encoder, Verifiable, VerifyError};
+    use Format;
+
+    #[test]
+    fn verifies_codecs() {
+        let input = include_bytes!("data/test.txt");
+        let mut prefixed = Cursor::new(b"prefix".to_vec());
+        prefixed.seek(SeekFrom::End(0)).unwrap();
+        let mut e = Encoder::new(prefixed, ::lz4::Encoder::new).unwrap();
+        e.write_all(input).unwrap();
+        let (out, result) = e.finish();
+        result.unwrap();
+        assert_eq!(out.position(), out.get_ref().len() as u64);
+        assert_eq!(&out.get_ref()[..6], b"prefix");
+
+        let mut e = Encoder::new(Cursor::new(Vec::new()), ::flate::Encoder::new).unwrap();
+        e.write_all(input).unwrap();
+        e.finish().1.unwrap();
+        let mut e = Encoder::new(Cursor::new(Vec::new()), ::gzip::Encoder::new).unwrap();
+        e.write_all(input).unwrap();
+        e.finish().1.unwrap();
+        let mut e = Encoder::new(Cursor::new(Vec::new()), ::rle::Encoder::new).unwrap();
+        e.write_all(input).unwrap();
+        e.finish().1.unwrap();
+    }
+
+    // Corrupts the last byte of the data it wraps on finish
+    struct Broken(::lz4::Encoder<Cursor<Vec<u8>>>);
+
+    impl Write for Broken {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { self.0.flush() }
+    }
+
+    impl Verifiable for Broken {
+        type Writer = Cursor<Vec<u8>>;
+        const FORMAT: Format = Format::Lz4Frame;
+        fn finish(self) -> (Cursor<Vec<u8>>, io::Result<()>) {
+            let (mut w, result) = self.0.finish();
+            // the last byte of the block, before the 4-byte end mark
+            let n = w.get_ref().len();
+            w.get_mut()[n - 5] ^= 0x20;
+            (w, result)
+        }
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut e = Encoder::new(Cursor::new(Vec::new()), |w| Broken(::lz4::Encoder::new(w))).unwrap();
+        e.write_all(b"some text that stays literal").unwrap();
+        let err = e.finish().1.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match err.get_ref().and_then(|e| e.downcast_ref::<VerifyError>()) {
+            Some(&VerifyError::Mismatch) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    // A stream whose position can't be read
+    struct Unseekable(Vec<u8>);
+
+    impl io::Read for Unseekable {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> { Ok(0) }
+    }
+
+    impl Write for Unseekable {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Seek for Unseekable {
+        fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+            Err(io::Error::new(io::ErrorKind::Other, "not seekable"))
+        }
+    }
+
+    #[test]
+    fn returns_writer() {
+        let w = Unseekable(b"kept".to_vec());
+        match Encoder::new(w, ::lz4::Encoder::new) {
+            Err((w, e)) => {
+                assert_eq!(&w.0[..], b"kept");
+                assert_eq!(e.kind(), io::ErrorKind::Other);
+            }
+            Ok(_) => panic!("the position can't be read"),
+        }
+    }
+}
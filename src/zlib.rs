@@ -20,19 +20,68 @@
 //!
 //! * http://tools.ietf.org/html/rfc1950 - RFC that this implementation is based
 //!   on
+//! * http://zlib.net/manual.html#Advanced - the `windowBits` convention of
+//!   `inflateInit2` which `Wrapper` mirrors
 
-use std::io::{self, Read};
-use super::byteorder::{BigEndian, ReadBytesExt};
+use std::io::{self, Read, Write};
+use super::byteorder::{BigEndian, LittleEndian, ReadBytesExt};
 
-use Adler32;
+use {Adler32, Crc32};
+use codecs::{Codec, Finish};
 use flate;
+use metrics;
+#[cfg(feature="gzip")]
+use gzip;
+
+/// The framing around the DEFLATE data of a stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrapper {
+    /// ZLIB header and Adler-32 trailer
+    Zlib,
+    /// GZIP member header and CRC-32/length trailer, needs the `gzip`
+    /// feature
+    Gzip,
+    /// Bare DEFLATE data
+    Raw,
+    /// Looks at the first two bytes to pick one of the above, for servers
+    /// which do not label their data correctly
+    Auto,
+}
+
+/// Returns the two byte ZLIB header for a stream compressed at `level` with
+/// the full 32K window, for encoders writing the DEFLATE data themselves.
+pub fn header(level: flate::CompressionLevel) -> [u8; 2] {
+    header_with(level, false)
+}
+
+// The header, with the FDICT flag announcing a preset dictionary if `dict`
+fn header_with(level: flate::CompressionLevel, dict: bool) -> [u8; 2] {
+    let cmf = 0x78;
+    let flevel = match level.level() {
+        0 | 1 => 0,
+        2..=5 => 1,
+        6 => 2,
+        _ => 3,
+    };
+    let flg = flevel << 6 | (dict as u8) << 5;
+    // FCHECK makes the header a multiple of 31
+    let check = 31 - u16::from_be_bytes([cmf, flg]) % 31;
+    [cmf, flg | (check % 31) as u8]
+}
 
 /// Structure used to decode a ZLIB-encoded stream. The wrapped stream can be
 /// re-acquired through the unwrap() method.
 pub struct Decoder<R> {
     hash: Adler32,
+    crc: Crc32,
+    size: u32,
+    wrapper: Wrapper,
     inner: flate::Decoder<R>,
+    dictionary: Option<Vec<u8>>,
+    #[cfg(feature="gzip")]
+    limits: gzip::Limits,
     read_header: bool,
+    read_trailer: bool,
 }
 
 impl<R: Read> Decoder<R> {
@@ -40,21 +89,99 @@ impl<R: Read> Decoder<R> {
     /// This decoder also implements the `Reader` trait, and the underlying
     /// reader can be re-acquired through the `unwrap` method.
     pub fn new(r: R) -> Decoder<R> {
+        Decoder::with_wrapper(r, Wrapper::Zlib)
+    }
+
+    /// Creates a decoder for DEFLATE data in the given framing.
+    pub fn with_wrapper(r: R, wrapper: Wrapper) -> Decoder<R> {
         Decoder {
             hash: Adler32::new(),
+            crc: Crc32::new(),
+            size: 0,
+            wrapper,
             inner: flate::Decoder::new(r),
+            dictionary: None,
+            #[cfg(feature="gzip")]
+            limits: gzip::Limits::default(),
             read_header: false,
+            read_trailer: false,
         }
     }
 
+    /// Creates a ZLIB-stream decoder for streams compressed with the preset
+    /// dictionary `dict`. Streams announcing a dictionary fail to decode
+    /// unless its Adler-32 matches `dict`, streams without one decode as
+    /// usual.
+    pub fn with_dictionary(r: R, dict: &[u8]) -> Decoder<R> {
+        Decoder {
+            dictionary: Some(dict.to_vec()),
+            .. Decoder::new(r)
+        }
+    }
+
+    /// Returns the framing of the stream. With `Wrapper::Auto` this is the
+    /// detected one once the header has been read.
+    pub fn wrapper(&self) -> Wrapper {
+        self.wrapper
+    }
+
     /// Destroys this decoder, returning the underlying reader.
     pub fn unwrap(self) -> R {
         self.inner.r
     }
 
-    fn validate_header(&mut self) -> io::Result<()> {
-        let cmf = try!(self.inner.r.read_u8());
-        let flg = try!(self.inner.r.read_u8());
+    fn read_header(&mut self) -> io::Result<()> {
+        match self.wrapper {
+            Wrapper::Zlib => {
+                let cmf = try!(self.inner.r.read_u8());
+                let flg = try!(self.inner.r.read_u8());
+                try!(self.validate_header(cmf, flg));
+                self.read_dictionary(flg)
+            }
+            Wrapper::Gzip => self.gzip_header(&[]),
+            Wrapper::Raw => Ok(()),
+            Wrapper::Auto => {
+                let cmf = try!(self.inner.r.read_u8());
+                let flg = try!(self.inner.r.read_u8());
+                if [cmf, flg] == [0x1f, 0x8b] {
+                    self.wrapper = Wrapper::Gzip;
+                    self.gzip_header(&[cmf, flg])
+                } else if cmf & 0xf == 0x8 && cmf >> 4 <= 7 &&
+                          u16::from_be_bytes([cmf, flg]).is_multiple_of(31) {
+                    self.wrapper = Wrapper::Zlib;
+                    try!(self.validate_header(cmf, flg));
+                    self.read_dictionary(flg)
+                } else {
+                    self.wrapper = Wrapper::Raw;
+                    self.inner.unread(&[cmf, flg]);
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Sets the limits on the field lengths of a GZIP header, the defaults
+    /// of `gzip::Limits` otherwise. Must be called before reading.
+    #[cfg(feature="gzip")]
+    pub fn set_gzip_limits(&mut self, limits: gzip::Limits) {
+        self.limits = limits;
+    }
+
+    #[cfg(feature="gzip")]
+    fn gzip_header(&mut self, magic: &[u8]) -> io::Result<()> {
+        try!(gzip::Header::read_with_limits(&mut magic.chain(&mut self.inner.r), &self.limits));
+        Ok(())
+    }
+
+    #[cfg(not(feature="gzip"))]
+    fn gzip_header(&mut self, _magic: &[u8]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "gzip support requires the gzip feature"
+        ))
+    }
+
+    fn validate_header(&mut self, cmf: u8, flg: u8) -> io::Result<()> {
         if cmf & 0xf != 0x8 {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -62,63 +189,116 @@ impl<R: Read> Decoder<R> {
             ))
         }
 
-        if cmf & 0xf0 != 0x70 {
+        // smaller windows are fine with a decoder keeping the full 32K
+        if cmf & 0xf0 > 0x70 {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "unsupported zlib window size"
             ))
         }
 
-        if flg & 0x20 != 0 {
+        if ((cmf as u16) * 256 + (flg as u16)) % 31 != 0 {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "unsupported initial dictionary in the output stream"
+                "invalid zlib header checksum"
             ))
         }
+        Ok(())
+    }
 
-        if ((cmf as u16) * 256 + (flg as u16)) % 31 != 0 {
+    // Reads the DICTID following a header with the FDICT flag and primes the
+    // window with the matching dictionary
+    fn read_dictionary(&mut self, flg: u8) -> io::Result<()> {
+        if flg & 0x20 == 0 {
+            return Ok(())
+        }
+        let id = try!(self.inner.r.read_u32::<BigEndian>());
+        let dict = match self.dictionary {
+            Some(ref dict) => dict,
+            None => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "zlib stream needs a preset dictionary"
+            )),
+        };
+        let mut hash = Adler32::new();
+        hash.feed(dict);
+        if hash.result() != id {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "invalid zlib header checksum"
+                "zlib stream needs a different preset dictionary"
             ))
         }
+        self.inner.prime_window(dict);
         Ok(())
     }
 
     /// Tests if this stream has reached the EOF point yet.
     pub fn eof(&self) -> bool { self.inner.eof() }
 
+    /// Decodes and throws away the next `n` bytes of output, returning how
+    /// many were skipped. The skipped data still goes into the checksum.
+    pub fn skip(&mut self, n: u64) -> io::Result<u64> {
+        io::copy(&mut self.by_ref().take(n), &mut io::sink())
+    }
+
     #[allow(dead_code)]
     fn reset(&mut self) {
         self.inner.reset();
         self.hash.reset();
+        self.crc.reset();
+        self.size = 0;
         self.read_header = false;
+        self.read_trailer = false;
+    }
+
+    fn check_trailer(&mut self) -> io::Result<usize> {
+        if self.read_trailer {
+            return Ok(0)
+        }
+        self.read_trailer = true;
+        let valid = match self.wrapper {
+            Wrapper::Zlib => {
+                try!(self.inner.r.read_u32::<BigEndian>()) == self.hash.result()
+            }
+            Wrapper::Gzip => {
+                let crc = try!(self.inner.r.read_u32::<LittleEndian>());
+                let size = try!(self.inner.r.read_u32::<LittleEndian>());
+                crc == self.crc.result() && size == self.size
+            }
+            Wrapper::Raw | Wrapper::Auto => true,
+        };
+        if valid {
+            Ok(0)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid checksum on zlib stream"
+            ))
+        }
     }
 }
 
 impl<R: Read> Read for Decoder<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if !self.read_header {
-            try!(self.validate_header());
+            try!(self.read_header());
             self.read_header = true;
-        } else if self.inner.eof() {
-            return Ok(0);
+        }
+        // the trailer is checked as soon as the last byte has gone out
+        if self.inner.eof() {
+            return self.check_trailer();
         }
         match self.inner.read(buf) {
-            Ok(0) => {
-                let cksum = try!(self.inner.r.read_u32::<BigEndian>());
-                if cksum != self.hash.result() {
-                    Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "invalid checksum on zlib stream"
-                    ))
-                }
-                else {
-                    Ok(0)
-                }
-            }
+            Ok(0) => self.check_trailer(),
             Ok(n) => {
-                self.hash.feed(&buf[..n]);
+                match self.wrapper {
+                    Wrapper::Zlib => self.hash.feed(&buf[..n]),
+                    Wrapper::Gzip => {
+                        self.crc.feed(&buf[..n]);
+                        self.size = self.size.wrapping_add(n as u32);
+                    }
+                    Wrapper::Raw | Wrapper::Auto => {}
+                }
                 Ok(n)
             }
             Err(e) => Err(e)
@@ -126,6 +306,137 @@ impl<R: Read> Read for Decoder<R> {
     }
 }
 
+
+/// Writes a ZLIB stream, compressing the data with `flate::Encoder`. The
+/// header goes out with the first write and the Adler-32 trailer on
+/// `finish`.
+pub struct Encoder<W> {
+    inner: flate::Encoder<W>,
+    header: Option<Vec<u8>>,
+    hash: Adler32,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder compressing at the default level
+    pub fn new(w: W) -> Encoder<W> {
+        Encoder::with_level(w, flate::CompressionLevel::Default)
+    }
+
+    /// Creates an encoder compressing at `level`, which is also recorded in
+    /// the header
+    pub fn with_level(w: W, level: flate::CompressionLevel) -> Encoder<W> {
+        Encoder {
+            inner: flate::Encoder::with_level(w, level),
+            header: Some(header(level).to_vec()),
+            hash: Adler32::new(),
+        }
+    }
+
+    /// Creates an encoder compressing at `level` against the preset
+    /// dictionary `dict`, whose Adler-32 is recorded in the header. The
+    /// stream has to be decoded with `Decoder::with_dictionary` and the same
+    /// dictionary. Matches only reach 1K back, so only the end of the
+    /// dictionary is of use.
+    pub fn with_dictionary(w: W, level: flate::CompressionLevel, dict: &[u8]) -> Encoder<W> {
+        let mut hash = Adler32::new();
+        hash.feed(dict);
+        let mut header = header_with(level, true).to_vec();
+        header.extend_from_slice(&hash.result().to_be_bytes());
+        let mut inner = flate::Encoder::with_level(w, level);
+        inner.prime_window(dict);
+        Encoder { inner, header: Some(header), hash: Adler32::new() }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        match self.header.take() {
+            Some(header) => self.inner.get_mut().write_all(&header),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the ratio guardrail of the DEFLATE blocks, see
+    /// `flate::Encoder::set_store_threshold`
+    pub fn set_store_threshold(&mut self, percent: usize) {
+        self.inner.set_store_threshold(percent);
+    }
+
+    /// Makes everything written so far decodable from the output, see
+    /// `flate::Encoder::flush_with`
+    pub fn flush_with(&mut self, mode: flate::FlushMode) -> io::Result<()> {
+        try!(self.write_header());
+        self.inner.flush_with(mode)
+    }
+
+    /// This function is used to flag that this session of compression is done
+    /// with. The stream is finished up (final bytes are written), and then the
+    /// wrapped writer is returned.
+    pub fn finish(mut self) -> (W, io::Result<()>) {
+        if let Err(e) = self.write_header() {
+            return (self.inner.finish().0, Err(e))
+        }
+        let (mut w, result) = self.inner.finish();
+        let hash = self.hash.result();
+        let result = result.and_then(|_| w.write_all(&hash.to_be_bytes()))
+                           .and_then(|_| w.flush());
+        (w, result)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.write_header());
+        let n = try!(self.inner.write(buf));
+        self.hash.feed(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.write_header());
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Finish for Encoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        (*self).finish().1
+    }
+}
+
+/// Compresses `data` into a ZLIB stream in one go, at the default level
+pub fn compress_to_vec(data: &[u8]) -> Vec<u8> {
+    let mut e = Encoder::new(Vec::new());
+    e.write_all(data).expect("writing to a Vec can't fail");
+    let (out, result) = e.finish();
+    result.expect("writing to a Vec can't fail");
+    out
+}
+
+/// Decompresses a ZLIB stream held in memory in one go
+pub fn decompress_to_vec(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    try!(Decoder::new(data).read_to_end(&mut out));
+    Ok(out)
+}
+
+/// ZLIB streams as a runtime selectable `Codec`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Zlib {
+    /// The level encoders compress at
+    pub level: flate::CompressionLevel,
+}
+
+impl Codec for Zlib {
+    fn name(&self) -> &'static str { "zlib" }
+
+    fn encoder<'a>(&self, w: Box<dyn Write + 'a>) -> io::Result<Box<dyn Finish + 'a>> {
+        metrics::encoder("zlib", w, |w| Ok(Box::new(Encoder::with_level(w, self.level))))
+    }
+
+    fn decoder<'a>(&self, r: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        metrics::decoder("zlib", r, |r| Ok(Box::new(Decoder::new(r))))
+    }
+}
+
 #[cfg(test)]
 #[allow(warnings)]
 mod test {
@@ -133,7 +444,8 @@ mod test {
     use super::super::rand::{random};
     use super::super::byteorder::{LittleEndian, BigEndian, WriteBytesExt, ReadBytesExt};
     use std::str;
-    use super::{Decoder};
+    use super::{Decoder, Wrapper};
+    use flate::CompressionLevel;
     #[cfg(feature="unstable")]
     use test;
 
@@ -202,6 +514,53 @@ mod test {
         assert!(&out[..] == &include_bytes!("data/test.txt")[..]);
     }
 
+    fn decode_auto(input: &[u8]) -> (Wrapper, Vec<u8>) {
+        let mut d = Decoder::with_wrapper(input, Wrapper::Auto);
+        let mut buf = Vec::new();
+        d.read_to_end(&mut buf).unwrap();
+        (d.wrapper(), buf)
+    }
+
+    #[test]
+    fn auto_wrapper() {
+        let reference = &include_bytes!("data/test.txt")[..];
+        for input in [&include_bytes!("data/test.z.0")[..],
+                      &include_bytes!("data/test.z.1")[..],
+                      &include_bytes!("data/test.z.9")[..]].iter() {
+            assert_eq!(decode_auto(input), (Wrapper::Zlib, reference.to_vec()));
+            // the same DEFLATE data without the zlib framing
+            let raw = &input[2..input.len() - 4];
+            assert_eq!(decode_auto(raw), (Wrapper::Raw, reference.to_vec()));
+        }
+        if cfg!(feature="gzip") {
+            let (wrapper, buf) = decode_auto(include_bytes!("data/test.gz"));
+            assert_eq!(wrapper, Wrapper::Gzip);
+            assert!(&buf[..] == reference);
+        }
+    }
+
+    #[test]
+    fn auto_wrapper_bad_trailer() {
+        let mut input = include_bytes!("data/test.z.1").to_vec();
+        let last = input.len() - 1;
+        input[last] ^= 1;
+        let mut d = Decoder::with_wrapper(&input[..], Wrapper::Auto);
+        assert!(d.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn level_header() {
+        assert_eq!(super::header(CompressionLevel::Default), [0x78, 0x9c]);
+        assert_eq!(super::header(CompressionLevel::Best), [0x78, 0xda]);
+        assert_eq!(super::header(CompressionLevel::Fastest), [0x78, 0x01]);
+        for level in 0..12 {
+            let header = super::header(CompressionLevel::Level(level));
+            let mut input = header.to_vec();
+            input.extend_from_slice(&include_bytes!("data/test.z.0")[2..]);
+            test_decode(&input, include_bytes!("data/test.txt"));
+        }
+    }
+
     //fn roundtrip(bytes: &[u8]) {
     //    let mut e = Encoder::new(MemWriter::new());
     //    e.write(bytes);
@@ -233,4 +592,37 @@ mod test {
         });
         bh.bytes = output_size as u64;
     }
+
+    #[test]
+    fn dictionary() {
+        let reference = &include_bytes!("data/test.txt")[..];
+        let dict = &reference[2000..];
+        // written by zlib's deflateSetDictionary
+        let input = &include_bytes!("data/test.z.dict")[..];
+        let mut out = Vec::new();
+        Decoder::with_dictionary(input, dict).read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], reference);
+        assert!(Decoder::new(input).read_to_end(&mut Vec::new()).is_err());
+        assert!(Decoder::with_dictionary(input, &dict[1..]).read_to_end(&mut Vec::new()).is_err());
+
+        let mut e = super::Encoder::with_dictionary(Vec::new(), CompressionLevel::Default, dict);
+        e.write_all(&reference[2500..]).unwrap();
+        let (compressed, result) = e.finish();
+        result.unwrap();
+        assert!(compressed.len() < 100);
+        let mut out = Vec::new();
+        Decoder::with_dictionary(&compressed[..], dict).read_to_end(&mut out).unwrap();
+        assert_eq!(&out[..], &reference[2500..]);
+    }
+
+    #[test]
+    fn one_shot() {
+        let input = include_bytes!("data/test.txt");
+        let compressed = super::compress_to_vec(input);
+        assert_eq!(&super::decompress_to_vec(&compressed).unwrap()[..], &input[..]);
+        let mut bad = compressed.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        assert!(super::decompress_to_vec(&bad).is_err());
+    }
 }
@@ -0,0 +1,897 @@
+/*!
+
+Zstandard decompression. Requires `zstd` feature, enabled by default
+
+Zstandard is an LZ77 compressor whose blocks keep their literals apart from
+the sequences telling how to interleave them with matches. Literals are
+Huffman coded, while the literal lengths, match lengths and offsets of the
+sequences are coded with FSE, the table-based ANS coder also found in
+`entropy::ans`. This module decodes `.zst` files as described by RFC 8878:
+any number of frames, each optionally followed by the low 32 bits of its
+XXH64 checksum, with skippable frames in between passed over. Frames
+compressed against a dictionary are not supported.
+
+Zstandard has its own take on FSE: symbols with a probability below one get
+a single state at the end of the table, and the bits are read backwards from
+the end of each stream. The tables are therefore built here rather than with
+`entropy::ans::Table`.
+
+A frame declares the window its matches reach back into, which the decoder
+has to keep around. Frames declaring more than `DEFAULT_WINDOW_LIMIT` are
+rejected with an error carrying `io::LimitExceeded`, unless the limit is
+raised with `set_window_limit`.
+
+# Example
+
+```rust,no_run
+use std::fs::File;
+use std::io::Read;
+use compress::zstd;
+
+let file = File::open("archive.zst").unwrap();
+let mut d = zstd::Decoder::new(file);
+let mut decoded = Vec::new();
+d.read_to_end(&mut decoded).unwrap();
+```
+
+# Related links
+
+* https://tools.ietf.org/html/rfc8878 - Zstandard Compression and the
+  application/zstd Media Type
+
+*/
+
+use std::cmp;
+use std::io::{self, Read};
+use std::mem;
+
+use super::byteorder::{LittleEndian, ReadBytesExt};
+use checksum::xxhash;
+use io::LimitExceeded;
+
+/// Largest window the decoder keeps unless told otherwise, 128 MiB, the
+/// default limit of the reference decoder too. `zstd -19` uses 8 MiB.
+pub const DEFAULT_WINDOW_LIMIT: usize = 128 << 20;
+
+const MAGIC: u32 = 0xFD2F_B528;
+// the low four bits are free for the application
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const BLOCK_MAX: usize = 128 << 10;
+const HUFFMAN_MAX_BITS: u32 = 11;
+
+const LL_MAX: usize = 35;
+const ML_MAX: usize = 52;
+const OF_MAX: usize = 31;
+
+// the distributions used by the "predefined" mode, -1 standing for a
+// probability below one
+const LL_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1,
+    2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const ML_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1,
+    -1, -1, -1, -1, -1,
+];
+const OF_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+// base values and extra bits of the literal and match length codes
+const LL_BASE: [u32; 36] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 18, 20, 22, 24, 28, 32, 40, 48, 64, 128, 256, 512, 1024, 2048, 4096,
+    8192, 16384, 32768, 65536,
+];
+const LL_BITS: [u8; 36] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15, 16,
+];
+const ML_BASE: [u32; 53] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18,
+    19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34,
+    35, 37, 39, 41, 43, 47, 51, 59, 67, 83, 99, 131, 259, 515, 1027, 2051,
+    4099, 8195, 16387, 32771, 65539,
+];
+const ML_BITS: [u8; 53] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    1, 1, 1, 1, 2, 2, 3, 3, 4, 4, 5, 7, 8, 9, 10, 11,
+    12, 13, 14, 15, 16,
+];
+
+fn error<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn highbit(x: u32) -> u32 {
+    31 - x.leading_zeros()
+}
+
+// Reads `n` bits, at most 32, starting at bit `start` of `data` taken as a
+// little endian number
+fn bits_at(data: &[u8], start: usize, n: u32) -> u64 {
+    let mut word = 0u64;
+    for (i, &b) in data[start / 8..].iter().take(8).enumerate() {
+        word |= (b as u64) << (8 * i);
+    }
+    (word >> (start % 8)) & ((1 << n) - 1)
+}
+
+// The bitstreams of the entropy coded parts, read from the end backwards.
+// The highest set bit of the last byte marks where they start. Reading past
+// the beginning yields zeros and leaves `left` negative.
+struct Backward<'a> {
+    data: &'a [u8],
+    left: isize,
+}
+
+impl<'a> Backward<'a> {
+    fn new(data: &'a [u8]) -> io::Result<Backward<'a>> {
+        match data.last() {
+            Some(&last) if last != 0 => Ok(Backward {
+                data,
+                left: (8 * (data.len() - 1) + highbit(last as u32) as usize) as isize,
+            }),
+            _ => error("corrupt zstd bitstream"),
+        }
+    }
+
+    fn peek(&self, n: u32) -> u64 {
+        let n = n as isize;
+        if self.left >= n {
+            bits_at(self.data, (self.left - n) as usize, n as u32)
+        } else if self.left > 0 {
+            bits_at(self.data, 0, self.left as u32) << (n - self.left)
+        } else {
+            0
+        }
+    }
+
+    fn consume(&mut self, n: u32) {
+        self.left -= n as isize;
+    }
+
+    fn bits(&mut self, n: u32) -> u64 {
+        let value = self.peek(n);
+        self.consume(n);
+        value
+    }
+}
+
+// A decoding table entry: the symbol of a state, and how to get the next one
+#[derive(Clone, Copy)]
+struct Entry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+// An FSE decoding table
+struct Fse {
+    log: u32,
+    entries: Vec<Entry>,
+}
+
+impl Fse {
+    fn new(norm: &[i16], log: u32) -> io::Result<Fse> {
+        let size = 1usize << log;
+        let mut symbols = vec![0u8; size];
+        let mut next = vec![0u32; norm.len()];
+        // symbols below one get a state each at the very end
+        let mut high = size;
+        for (s, &n) in norm.iter().enumerate() {
+            if n == -1 {
+                if high == 0 {
+                    return error("corrupt zstd FSE table")
+                }
+                high -= 1;
+                symbols[high] = s as u8;
+                next[s] = 1;
+            } else {
+                next[s] = n as u32;
+            }
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut pos = 0;
+        for (s, &n) in norm.iter().enumerate() {
+            for _ in 0..cmp::max(n, 0) {
+                symbols[pos] = s as u8;
+                pos = (pos + step) & (size - 1);
+                while pos >= high {
+                    pos = (pos + step) & (size - 1);
+                }
+            }
+        }
+        if pos != 0 {
+            return error("corrupt zstd FSE table")
+        }
+        let entries = symbols.iter().map(|&s| {
+            let x = next[s as usize];
+            next[s as usize] += 1;
+            let bits = log - highbit(x);
+            Entry { symbol: s, bits: bits as u8, base: ((x << bits) - size as u32) as u16 }
+        }).collect();
+        Ok(Fse { log, entries })
+    }
+
+    // A table for a single symbol, which takes no bits at all
+    fn rle(symbol: u8) -> Fse {
+        Fse { log: 0, entries: vec![Entry { symbol, bits: 0, base: 0 }] }
+    }
+
+    fn predefined(norm: &[i16], log: u32) -> Fse {
+        Fse::new(norm, log).expect("the predefined distributions are valid")
+    }
+
+    // Reads a table description, returning the table and the number of
+    // bytes it took
+    fn read(data: &[u8], max_symbol: usize, max_log: u32) -> io::Result<(Fse, usize)> {
+        let mut pos = 0;
+        let mut bits = |n: u32, consume: u32| -> u32 {
+            let value = bits_at(data, cmp::min(pos, 8 * data.len()), n) as u32;
+            pos += consume as usize;
+            value
+        };
+        let log = bits(4, 4) + 5;
+        if log > max_log {
+            return error("zstd FSE table too large")
+        }
+        let mut remaining = (1i32 << log) + 1;
+        let mut threshold = 1i32 << log;
+        let mut nbits = log + 1;
+        let mut norm = Vec::new();
+        let mut previous_zero = false;
+        while remaining > 1 && norm.len() <= max_symbol {
+            if previous_zero {
+                // the symbols which follow a zero probability are skipped
+                // over three at a time
+                let mut zeros = 0;
+                loop {
+                    let repeat = bits(2, 2);
+                    zeros += repeat as usize;
+                    if repeat != 3 {
+                        break
+                    }
+                }
+                if norm.len() + zeros > max_symbol {
+                    return error("corrupt zstd FSE table")
+                }
+                norm.resize(norm.len() + zeros, 0);
+            }
+            let max = 2 * threshold - 1 - remaining;
+            let value = bits(nbits, 0) as i32;
+            let count = if value & (threshold - 1) < max {
+                bits(0, nbits - 1);
+                value & (threshold - 1)
+            } else {
+                bits(0, nbits);
+                let count = value & (2 * threshold - 1);
+                if count >= threshold { count - max } else { count }
+            } - 1;
+            if count.abs() >= remaining {
+                return error("corrupt zstd FSE table")
+            }
+            remaining -= count.abs();
+            norm.push(count as i16);
+            previous_zero = count == 0;
+            while remaining < threshold {
+                nbits -= 1;
+                threshold >>= 1;
+            }
+        }
+        let used = pos.div_ceil(8);
+        if remaining != 1 || used > data.len() {
+            return error("corrupt zstd FSE table")
+        }
+        Ok((try!(Fse::new(&norm, log)), used))
+    }
+}
+
+// A Huffman decoding table indexed by the next `max_bits` bits
+struct Huffman {
+    max_bits: u32,
+    entries: Vec<(u8, u8)>,
+}
+
+impl Huffman {
+    // Reads a tree description, returning the table and the number of
+    // bytes it took
+    fn read(data: &[u8]) -> io::Result<(Huffman, usize)> {
+        let header = match data.first() {
+            Some(&header) => header as usize,
+            None => return error("truncated zstd literals"),
+        };
+        let mut weights = Vec::new();
+        let used = if header < 128 {
+            // weights coded with FSE, two states taking turns
+            let data = match data.get(1..1 + header) {
+                Some(data) => data,
+                None => return error("truncated zstd literals"),
+            };
+            let (table, n) = try!(Fse::read(data, 12, 6));
+            let mut bits = try!(Backward::new(&data[n..]));
+            let mut states = [bits.bits(table.log) as usize, bits.bits(table.log) as usize];
+            let mut turn = 0;
+            loop {
+                if weights.len() > 255 {
+                    return error("corrupt zstd Huffman tree")
+                }
+                let entry = table.entries[states[turn]];
+                weights.push(entry.symbol);
+                states[turn] = entry.base as usize + bits.bits(entry.bits as u32) as usize;
+                turn ^= 1;
+                if bits.left < 0 {
+                    weights.push(table.entries[states[turn]].symbol);
+                    break
+                }
+            }
+            1 + header
+        } else {
+            // four bits each
+            let n = header - 127;
+            let data = match data.get(1..1 + n.div_ceil(2)) {
+                Some(data) => data,
+                None => return error("truncated zstd literals"),
+            };
+            weights.extend((0..n).map(|i| data[i / 2] >> (4 - 4 * (i % 2)) & 15));
+            1 + data.len()
+        };
+        if weights.len() > 255 {
+            return error("corrupt zstd Huffman tree")
+        }
+
+        // the weight of the last symbol is implied, it brings the total to
+        // a power of two
+        let mut total = 0u32;
+        for &w in weights.iter() {
+            if w as u32 > HUFFMAN_MAX_BITS {
+                return error("corrupt zstd Huffman tree")
+            }
+            if w > 0 {
+                total += 1 << (w - 1);
+            }
+        }
+        if total == 0 {
+            return error("corrupt zstd Huffman tree")
+        }
+        let max_bits = highbit(total) + 1;
+        let rest = (1 << max_bits) - total;
+        if max_bits > HUFFMAN_MAX_BITS || !rest.is_power_of_two() {
+            return error("corrupt zstd Huffman tree")
+        }
+        weights.push(highbit(rest) as u8 + 1);
+
+        // longer codes come first, and within a length lower symbols
+        let mut entries = Vec::with_capacity(1 << max_bits);
+        for w in 1..max_bits as u8 + 1 {
+            for (symbol, _) in weights.iter().enumerate().filter(|&(_, &x)| x == w) {
+                let n = 1 << (w - 1);
+                let bits = (max_bits + 1 - w as u32) as u8;
+                entries.extend((0..n).map(|_| (symbol as u8, bits)));
+            }
+        }
+        Ok((Huffman { max_bits, entries }, used))
+    }
+
+    fn decode(&self, data: &[u8], n: usize, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut bits = try!(Backward::new(data));
+        for _ in 0..n {
+            let (symbol, len) = self.entries[bits.peek(self.max_bits) as usize];
+            out.push(symbol);
+            bits.consume(len as u32);
+        }
+        if bits.left != 0 {
+            return error("corrupt zstd literals")
+        }
+        Ok(())
+    }
+}
+
+/// A decoder of Zstandard files, reading all of their frames as one stream.
+/// Content sizes and checksums are verified as frames end.
+pub struct Decoder<R> {
+    r: R,
+    window_limit: usize,
+    started: bool,
+    in_frame: bool,
+    // the output of the current frame which matches may still refer to,
+    // and how much of it was read
+    buf: Vec<u8>,
+    pos: usize,
+    window: usize,
+    frame_len: u64,
+    content_size: Option<u64>,
+    checksum: Option<xxhash::State64>,
+    // what the blocks of a frame carry over to the next one
+    block: Vec<u8>,
+    literals: Vec<u8>,
+    huffman: Option<Huffman>,
+    tables: [Option<Fse>; 3],
+    reps: [usize; 3],
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a decoder reading a Zstandard file from `r`
+    pub fn new(r: R) -> Decoder<R> {
+        Decoder {
+            r,
+            window_limit: DEFAULT_WINDOW_LIMIT,
+            started: false,
+            in_frame: false,
+            buf: Vec::new(),
+            pos: 0,
+            window: 0,
+            frame_len: 0,
+            content_size: None,
+            checksum: None,
+            block: Vec::new(),
+            literals: Vec::new(),
+            huffman: None,
+            tables: [None, None, None],
+            reps: [1, 4, 8],
+        }
+    }
+
+    /// Sets the largest window the decoder agrees to keep. Frames declaring
+    /// a larger one fail with an error carrying `io::LimitExceeded`.
+    pub fn set_window_limit(&mut self, limit: usize) {
+        self.window_limit = limit;
+    }
+
+    /// Destroys this decoder, returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    // Reads the header of the next frame, skipping skippable frames, and
+    // returns false at the end of the input
+    fn frame(&mut self) -> io::Result<bool> {
+        let magic = loop {
+            let mut first = [0];
+            if try!(self.r.read(&mut first)) == 0 {
+                if self.started {
+                    return Ok(false)
+                }
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty zstd file"))
+            }
+            let mut rest = [0; 3];
+            try!(self.r.read_exact(&mut rest));
+            let magic = u32::from_le_bytes([first[0], rest[0], rest[1], rest[2]]);
+            if magic & !0xf != SKIPPABLE_MAGIC {
+                break magic
+            }
+            let size = try!(self.r.read_u32::<LittleEndian>()) as u64;
+            if try!(io::copy(&mut (&mut self.r).take(size), &mut io::sink())) != size {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated zstd file"))
+            }
+            self.started = true;
+        };
+        if magic != MAGIC {
+            return error("not a zstd frame")
+        }
+
+        let descriptor = try!(self.r.read_u8());
+        if descriptor & 0x08 != 0 {
+            return error("reserved bit set in zstd frame header")
+        }
+        let single_segment = descriptor & 0x20 != 0;
+        let mut window = 0;
+        if !single_segment {
+            let byte = try!(self.r.read_u8());
+            let base = 1u64 << (10 + (byte >> 3));
+            window = base + (base / 8) * (byte & 7) as u64;
+        }
+        let dict_id = match descriptor & 3 {
+            0 => 0,
+            1 => try!(self.r.read_u8()) as u32,
+            2 => try!(self.r.read_u16::<LittleEndian>()) as u32,
+            _ => try!(self.r.read_u32::<LittleEndian>()),
+        };
+        if dict_id != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "zstd dictionaries are not supported"))
+        }
+        self.content_size = match descriptor >> 6 {
+            0 if single_segment => Some(try!(self.r.read_u8()) as u64),
+            0 => None,
+            1 => Some(try!(self.r.read_u16::<LittleEndian>()) as u64 + 256),
+            2 => Some(try!(self.r.read_u32::<LittleEndian>()) as u64),
+            _ => Some(try!(self.r.read_u64::<LittleEndian>())),
+        };
+        if single_segment {
+            window = self.content_size.unwrap();
+        }
+        if window > self.window_limit as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      LimitExceeded { limit: self.window_limit }))
+        }
+
+        self.window = window as usize;
+        self.checksum = if descriptor & 0x04 != 0 { Some(xxhash::State64::new()) } else { None };
+        self.frame_len = 0;
+        self.huffman = None;
+        self.tables = [None, None, None];
+        self.reps = [1, 4, 8];
+        // matches don't reach back into earlier frames
+        self.buf.clear();
+        self.pos = 0;
+        self.started = true;
+        self.in_frame = true;
+        Ok(true)
+    }
+
+    // Decodes the next block of the current frame, and checks the frame
+    // once its last block is done
+    fn next_block(&mut self) -> io::Result<()> {
+        let header = try!(self.r.read_u24::<LittleEndian>());
+        let last = header & 1 != 0;
+        let size = (header >> 3) as usize;
+        if size > cmp::min(self.window, BLOCK_MAX) {
+            return error("zstd block too large")
+        }
+
+        // drop the history which is out of the window's reach
+        if self.buf.len() > 2 * cmp::max(self.window, BLOCK_MAX) {
+            let excess = self.buf.len() - self.window;
+            self.buf.drain(..excess);
+            self.pos -= excess;
+        }
+        let start = self.buf.len();
+        match (header >> 1) & 3 {
+            0 => {
+                self.buf.resize(start + size, 0);
+                try!(self.r.read_exact(&mut self.buf[start..]));
+            }
+            1 => {
+                let byte = try!(self.r.read_u8());
+                self.buf.resize(start + size, byte);
+            }
+            2 => {
+                let mut block = mem::take(&mut self.block);
+                block.resize(size, 0);
+                let result = self.r.read_exact(&mut block).and_then(|_| self.compressed(&block, start));
+                self.block = block;
+                try!(result);
+            }
+            _ => return error("reserved zstd block type"),
+        }
+        if self.buf.len() - start > BLOCK_MAX {
+            return error("zstd block too large")
+        }
+        self.frame_len += (self.buf.len() - start) as u64;
+        if let Some(ref mut checksum) = self.checksum {
+            checksum.feed(&self.buf[start..]);
+        }
+
+        if last {
+            if self.content_size.is_some_and(|size| size != self.frame_len) {
+                return error("zstd frame has the wrong content size")
+            }
+            if let Some(ref checksum) = self.checksum {
+                if try!(self.r.read_u32::<LittleEndian>()) != checksum.result() as u32 {
+                    return error("zstd content checksum mismatch")
+                }
+            }
+            self.in_frame = false;
+        }
+        Ok(())
+    }
+
+    // Decodes a compressed block, whose output starts at `start` in `buf`
+    fn compressed(&mut self, block: &[u8], start: usize) -> io::Result<()> {
+        let used = try!(self.read_literals(block));
+        self.sequences(&block[used..], start)
+    }
+
+    // Decodes the literals section into `literals`, returning its size
+    fn read_literals(&mut self, block: &[u8]) -> io::Result<usize> {
+        let byte = |i: usize| match block.get(i) {
+            Some(&b) => Ok(b as usize),
+            None => error("truncated zstd literals"),
+        };
+        let b0 = try!(byte(0));
+        self.literals.clear();
+        if b0 & 3 < 2 {
+            // raw or a single byte repeated
+            let (size, header) = match (b0 >> 2) & 3 {
+                1 => ((b0 >> 4) | try!(byte(1)) << 4, 2),
+                3 => ((b0 >> 4) | try!(byte(1)) << 4 | try!(byte(2)) << 12, 3),
+                _ => (b0 >> 3, 1),
+            };
+            if size > BLOCK_MAX {
+                return error("corrupt zstd literals")
+            }
+            if b0 & 3 == 1 {
+                let b = try!(byte(header));
+                self.literals.resize(size, b as u8);
+                return Ok(header + 1)
+            }
+            return match block.get(header..header + size) {
+                Some(raw) => {
+                    self.literals.extend_from_slice(raw);
+                    Ok(header + size)
+                }
+                None => error("truncated zstd literals"),
+            }
+        }
+
+        let (streams, header, bits) = match (b0 >> 2) & 3 {
+            0 => (1, 3, 10),
+            1 => (4, 3, 10),
+            2 => (4, 4, 14),
+            _ => (4, 5, 18),
+        };
+        let mut value = 0u64;
+        for i in 0..header {
+            value |= (try!(byte(i)) as u64) << (8 * i);
+        }
+        let mask = (1 << bits) - 1;
+        let size = ((value >> 4) & mask) as usize;
+        let compressed = ((value >> (4 + bits)) & mask) as usize;
+        if size > BLOCK_MAX {
+            return error("corrupt zstd literals")
+        }
+        let mut data = match block.get(header..header + compressed) {
+            Some(data) => data,
+            None => return error("truncated zstd literals"),
+        };
+        if b0 & 3 == 2 {
+            let (huffman, used) = try!(Huffman::read(data));
+            self.huffman = Some(huffman);
+            data = &data[used..];
+        }
+        let huffman = match self.huffman {
+            Some(ref huffman) => huffman,
+            None => return error("zstd literals refer to a missing Huffman tree"),
+        };
+        if streams == 1 {
+            try!(huffman.decode(data, size, &mut self.literals));
+        } else {
+            // a jump table gives the sizes of the first three streams, all
+            // but the last decode to a quarter of the literals rounded up
+            if data.len() < 6 {
+                return error("truncated zstd literals")
+            }
+            let quarter = size.div_ceil(4);
+            let mut streams = &data[6..];
+            for i in 0..4 {
+                let len = match i {
+                    3 => streams.len(),
+                    _ => u16::from_le_bytes([data[2 * i], data[2 * i + 1]]) as usize,
+                };
+                if len > streams.len() || (i == 3 && 3 * quarter > size) {
+                    return error("corrupt zstd literals")
+                }
+                let n = if i == 3 { size - 3 * quarter } else { quarter };
+                try!(huffman.decode(&streams[..len], n, &mut self.literals));
+                streams = &streams[len..];
+            }
+        }
+        Ok(header + compressed)
+    }
+
+    // Decodes the sequences section and carries the sequences out
+    fn sequences(&mut self, data: &[u8], start: usize) -> io::Result<()> {
+        let byte = |i: usize| match data.get(i) {
+            Some(&b) => Ok(b as usize),
+            None => error("truncated zstd sequences"),
+        };
+        let b0 = try!(byte(0));
+        let (count, mut pos) = match b0 {
+            0..=127 => (b0, 1),
+            128..=254 => (((b0 - 128) << 8) + try!(byte(1)), 2),
+            _ => (try!(byte(1)) + (try!(byte(2)) << 8) + 0x7f00, 3),
+        };
+        if count == 0 {
+            if pos != data.len() {
+                return error("corrupt zstd sequences")
+            }
+            self.buf.extend_from_slice(&self.literals);
+            return Ok(())
+        }
+
+        let modes = try!(byte(pos));
+        pos += 1;
+        if modes & 3 != 0 {
+            return error("reserved bits set in zstd sequences")
+        }
+        let kinds: [(&[i16], u32, usize, u32); 3] = [
+            (&LL_DEFAULT, 6, LL_MAX, 9),
+            (&OF_DEFAULT, 5, OF_MAX, 8),
+            (&ML_DEFAULT, 6, ML_MAX, 9),
+        ];
+        for (i, &(default, log, max_symbol, max_log)) in kinds.iter().enumerate() {
+            match (modes >> (6 - 2 * i)) & 3 {
+                0 => self.tables[i] = Some(Fse::predefined(default, log)),
+                1 => {
+                    let symbol = try!(byte(pos));
+                    if symbol > max_symbol {
+                        return error("corrupt zstd sequences")
+                    }
+                    self.tables[i] = Some(Fse::rle(symbol as u8));
+                    pos += 1;
+                }
+                2 => {
+                    let (table, used) = try!(Fse::read(&data[pos..], max_symbol, max_log));
+                    self.tables[i] = Some(table);
+                    pos += used;
+                }
+                _ => if self.tables[i].is_none() {
+                    return error("zstd sequences refer to a missing table")
+                },
+            }
+        }
+        let (ll, of, ml) = match self.tables {
+            [Some(ref ll), Some(ref of), Some(ref ml)] => (ll, of, ml),
+            _ => unreachable!(),
+        };
+
+        let mut bits = try!(Backward::new(&data[pos..]));
+        let mut ll_state = bits.bits(ll.log) as usize;
+        let mut of_state = bits.bits(of.log) as usize;
+        let mut ml_state = bits.bits(ml.log) as usize;
+        let mut literals = &self.literals[..];
+        for i in 0..count {
+            let (ll_entry, of_entry, ml_entry) =
+                (ll.entries[ll_state], of.entries[of_state], ml.entries[ml_state]);
+            let code = of_entry.symbol as u32;
+            let offset = (1u64 << code) + bits.bits(code);
+            let code = ml_entry.symbol as usize;
+            let match_len = (ML_BASE[code] as u64 + bits.bits(ML_BITS[code] as u32)) as usize;
+            let code = ll_entry.symbol as usize;
+            let literal_len = (LL_BASE[code] as u64 + bits.bits(LL_BITS[code] as u32)) as usize;
+            if bits.left < 0 {
+                return error("corrupt zstd sequences")
+            }
+
+            // the three most recent offsets can be repeated, shifted by
+            // one when there are no literals in between
+            let offset = if offset > 3 {
+                self.reps = [offset as usize - 3, self.reps[0], self.reps[1]];
+                self.reps[0]
+            } else {
+                let index = offset as usize - 1 + (literal_len == 0) as usize;
+                if index > 0 {
+                    let offset = match index {
+                        3 => self.reps[0] - 1,
+                        _ => self.reps[index],
+                    };
+                    if index != 1 {
+                        self.reps[2] = self.reps[1];
+                    }
+                    self.reps[1] = self.reps[0];
+                    self.reps[0] = offset;
+                }
+                self.reps[0]
+            };
+
+            if literal_len > literals.len() {
+                return error("zstd sequence runs out of literals")
+            }
+            self.buf.extend_from_slice(&literals[..literal_len]);
+            literals = &literals[literal_len..];
+            let reach = self.frame_len as usize + self.buf.len() - start;
+            if offset == 0 || offset > cmp::min(reach, self.window) {
+                return error("zstd match reaches too far back")
+            }
+            let from = self.buf.len() - offset;
+            if offset >= match_len {
+                self.buf.extend_from_within(from..from + match_len);
+            } else {
+                for j in from..from + match_len {
+                    let b = self.buf[j];
+                    self.buf.push(b);
+                }
+            }
+            if self.buf.len() - start > BLOCK_MAX {
+                return error("zstd block too large")
+            }
+
+            if i + 1 < count {
+                ll_state = ll_entry.base as usize + bits.bits(ll_entry.bits as u32) as usize;
+                ml_state = ml_entry.base as usize + bits.bits(ml_entry.bits as u32) as usize;
+                of_state = of_entry.base as usize + bits.bits(of_entry.bits as u32) as usize;
+            }
+        }
+        if bits.left != 0 {
+            return error("corrupt zstd sequences")
+        }
+        self.buf.extend_from_slice(literals);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() && !buf.is_empty() {
+            if self.in_frame {
+                try!(self.next_block());
+            } else if !try!(self.frame()) {
+                return Ok(0)
+            }
+        }
+        let n = cmp::min(buf.len(), self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+    use io::LimitExceeded;
+    use super::Decoder;
+
+    fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Decoder::new(data).read_to_end(&mut out).map(|_| out)
+    }
+
+    fn repeated_text() -> Vec<u8> {
+        (0..60).flat_map(|_| include_bytes!("data/test.txt").iter().cloned()).collect()
+    }
+
+    #[test]
+    fn decode_files() {
+        let text = &include_bytes!("data/test.txt")[..];
+        // zstd -19, a single segment with its checksum
+        assert_eq!(&decode(include_bytes!("data/test.txt.zst")).unwrap()[..], text);
+        // zstd -3 from a pipe, two blocks in a 2 MiB window
+        assert_eq!(decode(include_bytes!("data/test.repeated.zst")).unwrap(), repeated_text());
+    }
+
+    #[test]
+    fn frames() {
+        // an empty frame, a skippable one and a single byte without checksum
+        let empty = [0x28, 0xb5, 0x2f, 0xfd, 0x24, 0x00, 0x01, 0x00, 0x00, 0x99, 0xe9, 0xd8, 0x51];
+        let skippable = [0x5a, 0x2a, 0x4d, 0x18, 0x02, 0x00, 0x00, 0x00, 0xaa, 0xbb];
+        let one = [0x28, 0xb5, 0x2f, 0xfd, 0x20, 0x01, 0x09, 0x00, 0x00, 0x61];
+        assert_eq!(decode(&empty).unwrap(), b"");
+        assert_eq!(decode(&[&empty[..], &skippable, &one, &one].concat()).unwrap(), b"aa");
+        assert!(decode(&skippable[..9]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn small_reads() {
+        let mut d = Decoder::new(&include_bytes!("data/test.repeated.zst")[..]);
+        let mut out = Vec::new();
+        let mut buf = [0; 13];
+        loop {
+            match d.read(&mut buf).unwrap() {
+                0 => break,
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+        assert_eq!(out, repeated_text());
+    }
+
+    #[test]
+    fn corrupt() {
+        let data = include_bytes!("data/test.txt.zst");
+        assert!(decode(&data[..data.len() - 1]).is_err());
+        let mut padded = data.to_vec();
+        padded.push(0);
+        assert!(decode(&padded).is_err());
+        for &at in [5, 20, data.len() / 2, data.len() - 8, data.len() - 1].iter() {
+            let mut data = data.to_vec();
+            data[at] ^= 0x10;
+            assert!(decode(&data).is_err(), "flipped byte {}", at);
+        }
+    }
+
+    #[test]
+    fn window_limit() {
+        let mut d = Decoder::new(&include_bytes!("data/test.repeated.zst")[..]);
+        d.set_window_limit(1 << 20);
+        let err = d.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(LimitExceeded::find(&err), Some(&LimitExceeded { limit: 1 << 20 }));
+    }
+}
@@ -0,0 +1,513 @@
+/*!
+
+Compression ratio regression checks on standard corpora
+
+A `Corpus` is a set of named files, read from a directory, a tar archive
+(possibly gzipped) or a zip archive, or downloaded from one of the known
+`Source`s, like the Canterbury and Silesia corpora. `measure` compresses
+every file with a `Codec`, checking that it decodes back, and `regressions`
+compares the ratios with ones recorded earlier by `write_baseline`, so that
+a change to a codec can be checked against the same yardstick by everybody.
+
+This needs the `corpus` feature, which is only meant for development and is
+not enabled by default. Downloading runs `curl`.
+
+The ignored `regressions_on_corpus` test runs the whole check on the corpus
+named by `COMPRESS_CORPUS`, either the name of a `Source` or the path of a
+directory or archive holding the files:
+
+```text
+COMPRESS_CORPUS=canterbury cargo test --features corpus --test corpus -- --ignored
+```
+
+The ratios are compared with `target/corpus/<name>.baseline`, or the file
+given by `COMPRESS_CORPUS_BASELINE`, which is written when it doesn't exist
+yet. `COMPRESS_CORPUS_CODECS` takes a comma separated list of codec names,
+and `COMPRESS_CORPUS_TOLERANCE` the relative growth of a ratio that is still
+accepted, 0.01 by default.
+
+*/
+
+#![allow(deprecated)]
+
+use std::cmp;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+extern crate byteorder;
+extern crate compress;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use compress::{codec_by_name, flate, gzip, Codec};
+
+/// Names of the codecs measured when none are given, those of them that
+/// are compiled in being returned by `codec_by_name`
+const CODECS: &[&str] = &["bwt", "flate", "gzip", "lz4", "rle", "zlib"];
+
+/// A corpus which can be downloaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Source {
+    /// Short name of the corpus, used to name the downloaded archive
+    name: &'static str,
+    /// Where the archive of the corpus is downloaded from
+    url: &'static str,
+}
+
+/// The Canterbury corpus, eleven files of up to a megabyte
+const CANTERBURY: Source = Source {
+    name: "canterbury",
+    url: "https://corpus.canterbury.ac.nz/resources/cantrbry.tar.gz",
+};
+
+/// The large Canterbury corpus: the bible, E. coli and the CIA world fact book
+const CANTERBURY_LARGE: Source = Source {
+    name: "canterbury-large",
+    url: "https://corpus.canterbury.ac.nz/resources/large.tar.gz",
+};
+
+/// The Silesia corpus, twelve files of 6 to 50 MB
+const SILESIA: Source = Source {
+    name: "silesia",
+    url: "https://sun.aei.polsl.pl/~sdeor/corpus/silesia.zip",
+};
+
+const SOURCES: [Source; 3] = [CANTERBURY, CANTERBURY_LARGE, SILESIA];
+
+/// Returns the known corpus of the given name
+fn source_by_name(name: &str) -> Option<Source> {
+    SOURCES.iter().find(|s| s.name == name).cloned()
+}
+
+fn error<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// One file of a corpus
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct File {
+    /// Path of the file within the corpus
+    name: String,
+    /// Contents of the file
+    data: Vec<u8>,
+}
+
+/// Files to measure the codecs on
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Corpus {
+    /// Name of the corpus
+    name: String,
+    /// The files, sorted by name
+    files: Vec<File>,
+}
+
+impl Corpus {
+    /// Reads the corpus at `path`: the regular files of a directory, or
+    /// those of a `.tar`, `.tar.gz`, `.tgz` or `.zip` archive. The corpus is
+    /// named after the file or directory.
+    fn open(path: &Path) -> io::Result<Corpus> {
+        let file_name = path.file_name().map(|n| n.to_string_lossy().into_owned())
+                            .unwrap_or_default();
+        let mut files = Vec::new();
+        let name = if path.is_dir() {
+            try!(read_dir(path, "", &mut files));
+            file_name
+        } else {
+            let data = try!(fs::read(path));
+            let stem = |ext: &str| file_name.strip_suffix(ext).map(|s| s.to_string());
+            if let Some(name) = stem(".tar.gz").or_else(|| stem(".tgz")) {
+                let mut tar = Vec::new();
+                try!(gzip::MultiDecoder::new(&data[..]).read_to_end(&mut tar));
+                try!(read_tar(&tar, &mut files));
+                name
+            } else if let Some(name) = stem(".tar") {
+                try!(read_tar(&data, &mut files));
+                name
+            } else if let Some(name) = stem(".zip") {
+                try!(read_zip(&data, &mut files));
+                name
+            } else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "corpus isn't a directory, tar or zip archive"))
+            }
+        };
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Corpus { name, files })
+    }
+
+    /// Reads the corpus of `source`, first downloading its archive into the
+    /// `cache` directory unless it is there already
+    fn fetch(source: &Source, cache: &Path) -> io::Result<Corpus> {
+        let ext = if source.url.ends_with(".zip") { ".zip" } else { ".tar.gz" };
+        let path = cache.join(format!("{}{}", source.name, ext));
+        if !path.exists() {
+            try!(fs::create_dir_all(cache));
+            let partial = path.with_extension("part");
+            let status = try!(Command::new("curl").arg("-fsSL").arg("-o").arg(&partial)
+                                                   .arg(source.url).status());
+            if !status.success() {
+                return Err(io::Error::other(format!("downloading {} failed", source.url)))
+            }
+            try!(fs::rename(&partial, &path));
+        }
+        Corpus::open(&path)
+    }
+}
+
+fn read_dir(dir: &Path, prefix: &str, files: &mut Vec<File>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let kind = try!(entry.file_type());
+        if kind.is_dir() {
+            try!(read_dir(&entry.path(), &format!("{}/", name), files));
+        } else if kind.is_file() {
+            files.push(File { name, data: try!(fs::read(entry.path())) });
+        }
+    }
+    Ok(())
+}
+
+const TAR_BLOCK: usize = 512;
+
+fn tar_field(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// The regular files of a ustar archive
+fn read_tar(mut tar: &[u8], files: &mut Vec<File>) -> io::Result<()> {
+    while !tar.is_empty() {
+        if tar.len() < TAR_BLOCK {
+            return error("tar archive cut short")
+        }
+        let header = &tar[..TAR_BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            break
+        }
+        let size = tar_field(&header[124..136]);
+        let size = match usize::from_str_radix(size.trim(), 8) {
+            Ok(size) => size,
+            Err(_) => return error("invalid tar entry size"),
+        };
+        let data_end = TAR_BLOCK + size;
+        if tar.len() < data_end {
+            return error("tar archive cut short")
+        }
+        if header[156] == b'0' || header[156] == 0 {
+            let mut name = tar_field(&header[..100]);
+            if &header[257..262] == b"ustar" && header[345] != 0 {
+                name = format!("{}/{}", tar_field(&header[345..500]), name);
+            }
+            files.push(File { name, data: tar[TAR_BLOCK..data_end].to_vec() });
+        }
+        tar = &tar[cmp::min(tar.len(), data_end.div_ceil(TAR_BLOCK) * TAR_BLOCK)..];
+    }
+    Ok(())
+}
+
+const ZIP_END: u32 = 0x0605_4b50;
+const ZIP_CENTRAL: u32 = 0x0201_4b50;
+const ZIP_LOCAL: u32 = 0x0403_4b50;
+// Most memory reserved up front for a zip entry, whatever its header says
+const ZIP_RESERVE: usize = 64 << 20;
+
+// The stored and deflated files of a zip archive, as listed by its central
+// directory
+fn read_zip(zip: &[u8], files: &mut Vec<File>) -> io::Result<()> {
+    let end = match (0..zip.len().saturating_sub(21)).rev()
+                        .find(|&i| LittleEndian::read_u32(&zip[i..]) == ZIP_END) {
+        Some(end) => &zip[end..],
+        None => return error("zip archive without a central directory"),
+    };
+    let count = LittleEndian::read_u16(&end[10..]) as usize;
+    let mut pos = LittleEndian::read_u32(&end[16..]) as usize;
+    for _ in 0..count {
+        let entry = match zip.get(pos..pos + 46) {
+            Some(entry) if LittleEndian::read_u32(entry) == ZIP_CENTRAL => entry,
+            _ => return error("invalid zip central directory"),
+        };
+        let method = LittleEndian::read_u16(&entry[10..]);
+        let packed = LittleEndian::read_u32(&entry[20..]) as usize;
+        let size = LittleEndian::read_u32(&entry[24..]) as usize;
+        let name_len = LittleEndian::read_u16(&entry[28..]) as usize;
+        let extra_len = LittleEndian::read_u16(&entry[30..]) as usize;
+        let comment_len = LittleEndian::read_u16(&entry[32..]) as usize;
+        let local = LittleEndian::read_u32(&entry[42..]) as usize;
+        let name = match zip.get(pos + 46..pos + 46 + name_len) {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None => return error("zip archive cut short"),
+        };
+        pos += 46 + name_len + extra_len + comment_len;
+        if name.ends_with('/') {
+            continue
+        }
+
+        let header = match zip.get(local..local + 30) {
+            Some(header) if LittleEndian::read_u32(header) == ZIP_LOCAL => header,
+            _ => return error("invalid zip local header"),
+        };
+        let start = local + 30 + LittleEndian::read_u16(&header[26..]) as usize
+                               + LittleEndian::read_u16(&header[28..]) as usize;
+        let packed = match zip.get(start..start + packed) {
+            Some(packed) => packed,
+            None => return error("zip archive cut short"),
+        };
+        let data = match method {
+            0 => packed.to_vec(),
+            8 => {
+                // the size is only a hint until the entry is decoded, and
+                // decoding stops just past it
+                let mut data = Vec::with_capacity(cmp::min(size, ZIP_RESERVE));
+                try!(flate::Decoder::new(packed).take(size as u64 + 1).read_to_end(&mut data));
+                data
+            }
+            _ => return error("unsupported zip compression method"),
+        };
+        if data.len() != size {
+            return error("zip entry size mismatch")
+        }
+        files.push(File { name, data });
+    }
+    Ok(())
+}
+
+/// How well a codec compressed one file
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Ratio {
+    /// Name of the codec
+    codec: String,
+    /// Name of the file
+    file: String,
+    /// Size of the file
+    original: u64,
+    /// Size of the compressed file
+    compressed: u64,
+}
+
+impl Ratio {
+    /// Compressed size relative to the original one, 1.0 for empty files
+    fn ratio(&self) -> f64 {
+        if self.original == 0 {
+            return 1.0
+        }
+        self.compressed as f64 / self.original as f64
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {} -> {} ({:.4})", self.codec, self.file, self.original,
+               self.compressed, self.ratio())
+    }
+}
+
+/// Compresses every file of the corpus with `codec`, failing if one of them
+/// doesn't decode back to the same data
+fn measure(codec: &dyn Codec, corpus: &Corpus) -> io::Result<Vec<Ratio>> {
+    let mut ratios = Vec::with_capacity(corpus.files.len());
+    for file in corpus.files.iter() {
+        let mut compressed = Vec::new();
+        {
+            let mut e = try!(codec.encoder(Box::new(&mut compressed)));
+            try!(e.write_all(&file.data));
+            try!(e.finish());
+        }
+        let mut decoded = Vec::with_capacity(file.data.len());
+        try!(try!(codec.decoder(Box::new(&compressed[..]))).read_to_end(&mut decoded));
+        if decoded != file.data {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{} doesn't roundtrip {}", codec.name(), file.name)))
+        }
+        ratios.push(Ratio {
+            codec: codec.name().to_string(),
+            file: file.name.clone(),
+            original: file.data.len() as u64,
+            compressed: compressed.len() as u64,
+        });
+    }
+    Ok(ratios)
+}
+
+/// Writes the ratios one per line, as the codec, the file and both sizes
+/// separated by tabs
+fn write_baseline<W: Write>(w: &mut W, ratios: &[Ratio]) -> io::Result<()> {
+    for r in ratios.iter() {
+        try!(writeln!(w, "{}\t{}\t{}\t{}", r.codec, r.file, r.original, r.compressed));
+    }
+    Ok(())
+}
+
+/// Reads ratios written by `write_baseline`, skipping empty lines and ones
+/// starting with `#`
+fn read_baseline<R: BufRead>(r: R) -> io::Result<Vec<Ratio>> {
+    let mut ratios = Vec::new();
+    for line in r.lines() {
+        let line = try!(line);
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let ratio = match fields[..] {
+            [codec, file, original, compressed] => match (original.parse(), compressed.parse()) {
+                (Ok(original), Ok(compressed)) => Ratio {
+                    codec: codec.to_string(),
+                    file: file.to_string(),
+                    original,
+                    compressed,
+                },
+                _ => return error("invalid size in baseline"),
+            },
+            _ => return error("baseline lines need four fields"),
+        };
+        ratios.push(ratio);
+    }
+    Ok(ratios)
+}
+
+/// A file which a codec compresses worse than it used to
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Regression {
+    /// The recorded ratio
+    baseline: Ratio,
+    /// The measured ratio
+    current: Ratio,
+}
+
+impl fmt::Display for Regression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: ratio {:.4} was {:.4}", self.current.codec, self.current.file,
+               self.current.ratio(), self.baseline.ratio())
+    }
+}
+
+/// Returns the measured ratios more than `tolerance` times worse than the
+/// baseline ones of the same codec and file. Files or codecs missing from
+/// the baseline are not compared.
+fn regressions(baseline: &[Ratio], current: &[Ratio], tolerance: f64) -> Vec<Regression> {
+    current.iter().filter_map(|c| {
+        baseline.iter().find(|b| b.codec == c.codec && b.file == c.file)
+                .filter(|b| c.ratio() > b.ratio() * (1.0 + tolerance))
+                .map(|b| Regression { baseline: b.clone(), current: c.clone() })
+    }).collect()
+}
+
+fn tar_entry(tar: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    tar.extend_from_slice(&header);
+    tar.extend_from_slice(data);
+    tar.resize(tar.len().div_ceil(512) * 512, 0);
+}
+
+#[test]
+fn tar_archives() {
+    let mut tar = Vec::new();
+    tar_entry(&mut tar, "corpus/", b'5', b"");
+    tar_entry(&mut tar, "corpus/text", b'0', include_bytes!("../src/data/test.txt"));
+    tar_entry(&mut tar, "corpus/empty", b'0', b"");
+    tar.resize(tar.len() + 1024, 0);
+
+    let mut files = Vec::new();
+    read_tar(&tar, &mut files).unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].name, "corpus/text");
+    assert!(files[0].data[..] == include_bytes!("../src/data/test.txt")[..]);
+    assert!(files[1].data.is_empty());
+
+    let path = env::temp_dir().join(format!("compress-corpus-{}.tar.gz", ::std::process::id()));
+    let mut e = gzip::Encoder::new(Vec::new());
+    e.write_all(&tar).unwrap();
+    let (gz, result) = e.finish();
+    result.unwrap();
+    fs::write(&path, gz).unwrap();
+    let corpus = Corpus::open(&path);
+    fs::remove_file(&path).unwrap();
+    let corpus = corpus.unwrap();
+    assert!(corpus.name.starts_with("compress-corpus-"));
+    assert_eq!(corpus.files[0].name, "corpus/empty");
+
+    assert!(read_tar(&tar[..1000], &mut Vec::new()).is_err());
+}
+
+#[test]
+fn baselines() {
+    let corpus = Corpus {
+        name: "test".to_string(),
+        files: vec![File { name: "test.txt".to_string(),
+                           data: include_bytes!("../src/data/test.txt").to_vec() }],
+    };
+    let ratios = measure(&*codec_by_name("lz4").unwrap(), &corpus).unwrap();
+    let mut written = Vec::new();
+    write_baseline(&mut written, &ratios).unwrap();
+    let mut baseline = read_baseline(&written[..]).unwrap();
+    assert_eq!(baseline, ratios);
+    assert!(regressions(&baseline, &ratios, 0.0).is_empty());
+
+    baseline[0].compressed -= 10;
+    let found = regressions(&baseline, &ratios, 0.0);
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].current, ratios[0]);
+    assert!(regressions(&baseline, &ratios, 0.1).is_empty());
+
+    assert!(read_baseline(&b"lz4\ttest.txt\t10\n"[..]).is_err());
+}
+
+fn var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+#[test]
+#[ignore]
+fn regressions_on_corpus() {
+    let target = PathBuf::from(var("CARGO_TARGET_DIR").unwrap_or_else(|| "target".to_string()));
+    let cache = target.join("corpus");
+    let name = var("COMPRESS_CORPUS").expect("COMPRESS_CORPUS names the corpus to measure");
+    let corpus = match source_by_name(&name) {
+        Some(source) => Corpus::fetch(&source, &cache),
+        None => Corpus::open(Path::new(&name)),
+    }.unwrap();
+    let tolerance = var("COMPRESS_CORPUS_TOLERANCE").map_or(0.01, |t| t.parse().unwrap());
+    let codecs = var("COMPRESS_CORPUS_CODECS")
+        .map_or_else(|| CODECS.iter().map(|c| c.to_string()).collect(),
+                     |c| c.split(',').map(|c| c.trim().to_string()).collect::<Vec<_>>());
+
+    let mut ratios = Vec::new();
+    for name in codecs.iter() {
+        let codec = match codec_by_name(name) {
+            Some(codec) => codec,
+            None => continue,
+        };
+        let measured = measure(&*codec, &corpus).unwrap();
+        for r in measured.iter() {
+            println!("{}", r);
+        }
+        ratios.extend(measured);
+    }
+
+    let path = var("COMPRESS_CORPUS_BASELINE").map_or_else(
+        || cache.join(format!("{}.baseline", corpus.name)), PathBuf::from);
+    match fs::File::open(&path) {
+        Ok(file) => {
+            let baseline = read_baseline(BufReader::new(file)).unwrap();
+            let found = regressions(&baseline, &ratios, tolerance);
+            for r in found.iter() {
+                println!("regression: {}", r);
+            }
+            assert!(found.is_empty(), "{} ratios regressed beyond {}", found.len(), tolerance);
+        }
+        Err(_) => {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let mut file = fs::File::create(&path).unwrap();
+            write_baseline(&mut file, &ratios).unwrap();
+            println!("baseline written to {}", path.display());
+        }
+    }
+}